@@ -1,10 +1,55 @@
+pub mod query;
+#[cfg(feature = "wallet")]
+pub mod ratelimit;
+pub mod signing;
+pub mod stream;
+pub mod ws;
+
+use ring::signature::Ed25519KeyPair;
 use serde::Serialize;
-use crate::blockchain::Blockchain;
+use crate::blockchain::{Blockchain, Mempool, MMR_COMMITMENT_INTERVAL};
+use crate::blockchain::invariants;
+use self::query::ListSpec;
+use self::signing::ResponseSigner;
+use self::stream::{ChainExport, ExportFormat, ExportKind};
+use crate::diagnostics::{self, ChainSummary};
+use crate::memory::MemoryReport;
+use crate::metrics::{Marker, MetricsHistory};
+use crate::types::address::Address;
+use crate::types::amount::Amount;
+#[cfg(feature = "wallet")]
+use crate::wallet::{ledger, SendCondition, Wallet};
+#[cfg(feature = "wallet")]
+use crate::network::ratelimit::RateLimit;
+#[cfg(feature = "wallet")]
+use self::ratelimit::ClientLimiter;
+#[cfg(feature = "miner")]
 use crate::miner::Handle as MinerHandle;
 use crate::txgen::Handle as TransactionHandle;
 use crate::network::server::Handle as NetworkServerHandle;
-use crate::network::message::Message;
-use crate::types::hash::Hashable;
+use crate::network::shared::Shared;
+use crate::network::banlist::{self, BanList};
+use crate::network::clock::NetworkClock;
+use crate::network::features::{Features, PeerFeatures};
+use crate::network::markers;
+use crate::types::mmr::MmrProof;
+use crate::network::message::{InvItem, Message};
+use crate::network::orphan::{OrphanLimits, OrphanResolver, PeerOrphanStats};
+use crate::network::addrbook::AddressBook;
+use crate::network::peer::PeerStates;
+use crate::network::ratelimit::RateLimiter;
+use crate::network::sync::{Monitor as SyncMonitor, PeerTips, SyncGate};
+use crate::network::download::DownloadScheduler;
+use crate::network::routing::RequestRouter;
+use crate::network::topology::{self, Topology, REPORT_MAX_AGE};
+use crate::network::chain_proofs::ChainProofRequests;
+use crate::metrics::validation::BlockMetricsLog;
+use crate::network::attribution::FirstSeen;
+use crate::threads::{PoolKind, ThreadCount, ThreadPools};
+use crate::types::chain_proof::MAX_CHAIN_PROOF_DRAWS;
+use crate::types::hash::{H256, Hashable};
+use crate::types::transaction::SignedTransaction;
+use crate::watchdog::{AlarmLevel, BlockWatch};
 
 use log::info;
 use std::collections::HashMap;
@@ -15,12 +60,67 @@ use tiny_http::Response;
 use tiny_http::Server as HTTPServer;
 use url::Url;
 
+// blocks compared by /diagnostics/compare unless asked otherwise
+const DEFAULT_RECENT: usize = 20;
+// length of a ban when /network/ban is not told, a day
+const DEFAULT_BAN_SECONDS: u64 = 24 * 60 * 60;
+// how long /network/topology waits for the peers to report their links unless asked otherwise, and at most
+const DEFAULT_TOPOLOGY_WAIT_MS: u64 = 1000;
+const MAX_TOPOLOGY_WAIT_MS: u64 = 10000;
+// headers /network/chain-proof samples unless asked otherwise, and how long it waits for the proof
+const DEFAULT_CHAIN_PROOF_DRAWS: u32 = 64;
+const DEFAULT_CHAIN_PROOF_WAIT_MS: u64 = 2000;
+const MAX_CHAIN_PROOF_WAIT_MS: u64 = 10000;
+
+// what /network/topology is built from: the reports of the peers, their round trip times and
+// the connections we dialed
+type TopologySources = (Arc<Mutex<Topology>>, Arc<Mutex<RequestRouter>>, Arc<Mutex<AddressBook>>);
+
+/// The HTTP API of a node. The miner and wallet endpoints are only served when the node runs
+/// them, see `with_miner` and `with_wallet`.
 pub struct Server {
+    addr: std::net::SocketAddr,
     handle: HTTPServer,
-    miner: MinerHandle,
+    #[cfg(feature = "miner")]
+    miner: Option<MinerHandle>,
     network: NetworkServerHandle,
     blockchain: Arc<Mutex<Blockchain>>,
     tx_generator: TransactionHandle,
+    metrics: Arc<Mutex<MetricsHistory>>,
+    #[cfg(feature = "wallet")]
+    wallet: Option<Arc<Mutex<Wallet>>>,
+    #[cfg(feature = "wallet")]
+    send_limiter: Arc<Mutex<ClientLimiter>>,
+    orphans: Arc<Mutex<OrphanResolver>>,
+    clock: Arc<Mutex<NetworkClock>>,
+    peer_features: Arc<Mutex<PeerFeatures>>,
+    memory: Arc<Mutex<MemoryReport>>,
+    peer_tips: Arc<Mutex<PeerTips>>,
+    sync_gate: Arc<Mutex<SyncGate>>,
+    downloads: Arc<Mutex<DownloadScheduler>>,
+    rate_limiter: Arc<Mutex<RateLimiter>>,
+    block_watch: Arc<Mutex<BlockWatch>>,
+    peer_states: Arc<Mutex<PeerStates>>,
+    mempool: Arc<Mutex<Mempool>>,
+    ban_list: Arc<Mutex<BanList>>,
+    topology: Option<TopologySources>,
+    chain_proofs: Option<Arc<Mutex<ChainProofRequests>>>,
+    thread_pools: Option<Arc<Mutex<ThreadPools>>>,
+    router: Option<Arc<Mutex<RequestRouter>>>,
+    block_metrics: Option<Arc<Mutex<BlockMetricsLog>>>,
+    first_seen: Option<Arc<Mutex<FirstSeen>>>,
+    signer: Option<Arc<ResponseSigner>>,
+    test_mode: bool,
+}
+
+/// The node state the API server reports on besides the state it shares with the P2P workers
+pub struct Reports {
+    pub metrics: Arc<Mutex<MetricsHistory>>,
+    pub memory: Arc<Mutex<MemoryReport>>,
+    pub sync_gate: Arc<Mutex<SyncGate>>,
+    pub downloads: Arc<Mutex<DownloadScheduler>>,
+    pub block_watch: Arc<Mutex<BlockWatch>>,
+    pub ban_list: Arc<Mutex<BanList>>,
 }
 
 #[derive(Serialize)]
@@ -29,6 +129,222 @@ struct ApiResponse {
     message: String,
 }
 
+#[cfg(feature = "wallet")]
+#[derive(Serialize)]
+struct ScheduledSendEntry {
+    id: usize,
+    to: String,
+    value: Amount,
+    height: Option<u32>,
+    time: Option<u128>,
+    signed: bool,
+}
+
+#[cfg(feature = "wallet")]
+#[derive(Serialize)]
+struct AddressBalanceEntry {
+    index: usize,
+    address: String,
+    nonce: usize,
+    balance: Amount,
+}
+
+#[cfg(feature = "wallet")]
+#[derive(Serialize)]
+struct BalancesReport {
+    total: Amount,
+    addresses: Vec<AddressBalanceEntry>,
+}
+
+#[derive(Serialize)]
+struct CheckpointReport {
+    height: u32,
+    hash: String,
+    state_root: String,
+    /// the value to pass to --checkpoint
+    checkpoint: String,
+}
+
+#[derive(Serialize)]
+struct ChainProofReport {
+    peer: String,
+    height: u32,
+    tip: String,
+    root: String,
+    estimated_work: f64,
+    sampled_headers: usize,
+}
+
+#[derive(Serialize)]
+struct OrphanReport {
+    abandoned: usize,
+    buffered: usize,
+    evicted: usize,
+    limits: OrphanLimits,
+    peers: HashMap<String, PeerOrphanStats>,
+}
+
+#[derive(Serialize)]
+struct ReorgStatus {
+    tip: String,
+    height: u32,
+    pinned: Option<String>,
+    invalidated: Vec<String>,
+    fork_events: usize,
+}
+
+#[derive(Serialize)]
+struct MmrCommitment {
+    height: u32,
+    root: String,
+}
+
+#[derive(Serialize)]
+struct MmrStatus {
+    leaves: usize,
+    root: String,
+    commitment_interval: u32,
+    commitments: Vec<MmrCommitment>,
+}
+
+#[derive(Serialize)]
+struct HeaderProof {
+    block: String,
+    /// root of the header MMR the proof is checked against
+    root: String,
+    leaf_index: usize,
+    leaf_count: usize,
+    siblings: Vec<String>,
+    peaks: Vec<String>,
+}
+
+impl HeaderProof {
+    fn new(block: H256, root: H256, proof: MmrProof) -> Self {
+        HeaderProof {
+            block: block.to_string(),
+            root: root.to_string(),
+            leaf_index: proof.leaf_index,
+            leaf_count: proof.leaf_count,
+            siblings: proof.siblings.iter().map(|h| h.to_string()).collect(),
+            peaks: proof.peaks.iter().map(|h| h.to_string()).collect(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct BlockEntry {
+    hash: String,
+    height: u32,
+    parent: String,
+    // list items go through serde_json::Value, which has no u128
+    timestamp: u64,
+    /// None if the body was pruned
+    transactions: Option<usize>,
+    /// serialized size in bytes, None if the body was pruned
+    size: Option<u64>,
+    /// what validating the block took, None unless it was received from a peer recently
+    signature_ops: Option<usize>,
+    validation_us: Option<u64>,
+    /// when the block arrived, in ms since the unix epoch
+    received_at: Option<u64>,
+    /// the peer that delivered the block first, None if it was not received from a peer recently
+    first_from: Option<String>,
+}
+
+const BLOCKS: ListSpec = ListSpec {
+    fields: &["hash", "height", "parent", "timestamp", "transactions", "size", "signature_ops", "validation_us", "received_at", "first_from"],
+    id: "hash",
+    sort_keys: &["height", "timestamp", "transactions", "size", "validation_us"],
+};
+
+#[derive(Serialize)]
+struct TransactionEntry {
+    hash: String,
+    /// block and height of the longest chain the transaction is in, None if it is in the mempool
+    block: Option<String>,
+    height: Option<u32>,
+    sender: String,
+    receiver: String,
+    value: Amount,
+    nonce: usize,
+}
+
+impl TransactionEntry {
+    fn new(transaction: &SignedTransaction, block: Option<(H256, u32)>) -> Self {
+        TransactionEntry {
+            hash: transaction.hash().to_string(),
+            block: block.map(|(hash, _)| hash.to_string()),
+            height: block.map(|(_, height)| height),
+            sender: Address::from_public_key_bytes(&transaction.signer_public_key).to_string(),
+            receiver: transaction.t.receiver.to_string(),
+            value: transaction.t.value,
+            nonce: transaction.t.account_nonce,
+        }
+    }
+}
+
+const TRANSACTIONS: ListSpec = ListSpec {
+    fields: &["hash", "block", "height", "sender", "receiver", "value", "nonce"],
+    id: "hash",
+    sort_keys: &["height", "value", "nonce"],
+};
+
+const MEMPOOL: ListSpec = ListSpec {
+    fields: &["hash", "block", "height", "sender", "receiver", "value", "nonce"],
+    id: "hash",
+    sort_keys: &["nonce", "value"],
+};
+
+#[derive(Serialize)]
+struct PeerEntry {
+    addr: String,
+    state: String,
+    /// tip the peer last reported, None until it did
+    height: Option<u32>,
+    tip: Option<String>,
+    /// features negotiated with the peer, None until the handshake completed
+    features: Option<Features>,
+    /// protocol version messages to the peer are written in, None until the handshake completed
+    version: Option<u32>,
+    /// smoothed round trip time of pings and requests, in ms, None until one was answered
+    rtt_ms: Option<f64>,
+}
+
+const PEERS: ListSpec = ListSpec {
+    fields: &["addr", "state", "height", "tip", "features", "version", "rtt_ms"],
+    id: "addr",
+    sort_keys: &["addr", "height", "rtt_ms"],
+};
+
+#[derive(Serialize)]
+struct BanEntry {
+    addr: String,
+    reason: String,
+    /// when the ban ends, in ms since the unix epoch
+    until: u64,
+}
+
+#[derive(Serialize)]
+struct ContributionEntry {
+    addr: String,
+    /// accepted blocks and transactions this peer delivered before any other peer
+    blocks: u64,
+    transactions: u64,
+}
+
+const CONTRIBUTIONS: ListSpec = ListSpec { fields: &["addr", "blocks", "transactions"], id: "addr", sort_keys: &["addr", "blocks", "transactions"] };
+
+const BANS: ListSpec = ListSpec { fields: &["addr", "reason", "until"], id: "addr", sort_keys: &["addr", "until"] };
+
+#[derive(Serialize)]
+struct ChainWorkEntry {
+    height: u32,
+    hash: String,
+    difficulty: String,
+    work: f64,
+    cumulative_work: f64,
+}
+
 macro_rules! respond_result {
     ( $req:expr, $success:expr, $message:expr ) => {{
         let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
@@ -49,29 +365,174 @@ macro_rules! respond_json {
         $req.respond(resp).unwrap();
     }};
 }
+// respond like `respond_json`, with the signature of `signer`, if any, over the request, the
+// body and the tip of `blockchain` in headers, see `signing`
+macro_rules! respond_signed {
+    ( $req:expr, $signer:expr, $blockchain:expr, $message:expr ) => {{
+        let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
+        let body = serde_json::to_string(&$message).unwrap();
+        let headers = $signer.as_ref().map(|signer: &Arc<ResponseSigner>| {
+            let (height, tip) = {
+                let blockchain = $blockchain.lock().unwrap();
+                (blockchain.height(), blockchain.tip())
+            };
+            let signed_at = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis();
+            signer.headers($req.url(), body.as_bytes(), height, &tip, signed_at)
+        });
+        let mut resp = Response::from_string(body).with_header(content_type);
+        for (name, value) in headers.into_iter().flatten() {
+            resp = resp.with_header(Header::from_bytes(name.as_bytes(), value.as_bytes()).unwrap());
+        }
+        $req.respond(resp).unwrap();
+    }};
+}
 
 impl Server {
-    pub fn start(
+    pub fn new(
         addr: std::net::SocketAddr,
-        miner: &MinerHandle,
         network: &NetworkServerHandle,
-        blockchain: &Arc<Mutex<Blockchain>>,
+        shared: &Shared,
         tx_generator: &TransactionHandle, 
-    ) {
+        reports: Reports,
+        test_mode: bool,
+    ) -> Self {
         let handle = HTTPServer::http(&addr).unwrap();
-        let server = Self {
+        Self {
+            addr,
             handle,
-            miner: miner.clone(),
+            #[cfg(feature = "miner")]
+            miner: None,
             network: network.clone(),
-            blockchain: Arc::clone(blockchain),
+            blockchain: Arc::clone(&shared.blockchain),
             tx_generator: tx_generator.clone(),
-        };
+            metrics: reports.metrics,
+            #[cfg(feature = "wallet")]
+            wallet: None,
+            #[cfg(feature = "wallet")]
+            send_limiter: Arc::new(Mutex::new(ClientLimiter::new(None))),
+            orphans: Arc::clone(&shared.orphans),
+            clock: Arc::clone(&shared.clock),
+            peer_features: Arc::clone(&shared.peer_features),
+            memory: reports.memory,
+            peer_tips: Arc::clone(&shared.peer_tips),
+            sync_gate: reports.sync_gate,
+            downloads: reports.downloads,
+            rate_limiter: Arc::clone(&shared.rate_limiter),
+            block_watch: reports.block_watch,
+            peer_states: Arc::clone(&shared.peer_states),
+            mempool: Arc::clone(&shared.mempool),
+            ban_list: reports.ban_list,
+            topology: None,
+            chain_proofs: None,
+            thread_pools: None,
+            router: None,
+            block_metrics: None,
+            first_seen: None,
+            signer: None,
+            test_mode,
+        }
+    }
+
+    /// Serve the topology of the network around this node, as its peers report it in
+    /// `topology`, with the round trip times of `router` and our outgoing connections in `book`
+    pub fn with_topology(mut self, topology: &Arc<Mutex<Topology>>, router: &Arc<Mutex<RequestRouter>>, book: &Arc<Mutex<AddressBook>>) -> Self {
+        self.topology = Some((Arc::clone(topology), Arc::clone(router), Arc::clone(book)));
+        self
+    }
+
+    /// Ask peers to prove their chain, the workers check the answers to the requests in
+    /// `chain_proofs`
+    pub fn with_chain_proofs(mut self, chain_proofs: &Arc<Mutex<ChainProofRequests>>) -> Self {
+        self.chain_proofs = Some(Arc::clone(chain_proofs));
+        self
+    }
+
+    /// Serve the sizes of the thread pools in `thread_pools` and resize them
+    pub fn with_thread_pools(mut self, thread_pools: &Arc<Mutex<ThreadPools>>) -> Self {
+        self.thread_pools = Some(Arc::clone(thread_pools));
+        self
+    }
+
+    /// List the peers with the round trip times `router` measures
+    pub fn with_router(mut self, router: &Arc<Mutex<RequestRouter>>) -> Self {
+        self.router = Some(Arc::clone(router));
+        self
+    }
+
+    /// List the validation metrics the workers record in `block_metrics` with the blocks
+    pub fn with_block_metrics(mut self, block_metrics: &Arc<Mutex<BlockMetricsLog>>) -> Self {
+        self.block_metrics = Some(Arc::clone(block_metrics));
+        self
+    }
+
+    /// List which peer first delivered each block, as the workers record it in `first_seen`,
+    /// and what each peer delivered first
+    pub fn with_first_seen(mut self, first_seen: &Arc<Mutex<FirstSeen>>) -> Self {
+        self.first_seen = Some(Arc::clone(first_seen));
+        self
+    }
+
+    /// Sign the responses carrying chain data or balances with the node key `key`
+    pub fn with_signer(mut self, key: Ed25519KeyPair) -> Self {
+        self.signer = Some(Arc::new(ResponseSigner::new(key)));
+        self
+    }
+
+    /// Serve the miner endpoints with `miner`
+    #[cfg(feature = "miner")]
+    pub fn with_miner(mut self, miner: &MinerHandle) -> Self {
+        self.miner = Some(miner.clone());
+        self
+    }
+
+    /// Serve the wallet endpoints with `wallet`
+    #[cfg(feature = "wallet")]
+    pub fn with_wallet(mut self, wallet: &Arc<Mutex<Wallet>>) -> Self {
+        self.wallet = Some(Arc::clone(wallet));
+        self
+    }
+
+    /// Hold each client of /wallet/send to `limit`, none by default
+    #[cfg(feature = "wallet")]
+    pub fn with_send_limit(mut self, limit: Option<RateLimit>) -> Self {
+        self.send_limiter = Arc::new(Mutex::new(ClientLimiter::new(limit)));
+        self
+    }
+
+    pub fn start(self) {
+        let addr = self.addr;
         thread::spawn(move || {
-            for req in server.handle.incoming_requests() {
-                let miner = server.miner.clone();
-                let network = server.network.clone();
-                let tx_generator = server.tx_generator.clone();
-                let blockchain = Arc::clone(&server.blockchain);
+            for req in self.handle.incoming_requests() {
+                #[cfg(feature = "miner")]
+                let miner = self.miner.clone();
+                let network = self.network.clone();
+                let tx_generator = self.tx_generator.clone();
+                let blockchain = Arc::clone(&self.blockchain);
+                let metrics = Arc::clone(&self.metrics);
+                #[cfg(feature = "wallet")]
+                let wallet = self.wallet.clone();
+                #[cfg(feature = "wallet")]
+                let send_limiter = Arc::clone(&self.send_limiter);
+                let orphans = Arc::clone(&self.orphans);
+                let clock = Arc::clone(&self.clock);
+                let peer_features = Arc::clone(&self.peer_features);
+                let memory = Arc::clone(&self.memory);
+                let peer_tips = Arc::clone(&self.peer_tips);
+                let sync_gate = Arc::clone(&self.sync_gate);
+                let downloads = Arc::clone(&self.downloads);
+                let rate_limiter = Arc::clone(&self.rate_limiter);
+                let block_watch = Arc::clone(&self.block_watch);
+                let peer_states = Arc::clone(&self.peer_states);
+                let mempool = Arc::clone(&self.mempool);
+                let ban_list = Arc::clone(&self.ban_list);
+                let topology = self.topology.clone();
+                let chain_proofs = self.chain_proofs.clone();
+                let thread_pools = self.thread_pools.clone();
+                let router = self.router.clone();
+                let block_metrics = self.block_metrics.clone();
+                let first_seen = self.first_seen.clone();
+                let signer = self.signer.clone();
+                let test_mode = self.test_mode;
                 thread::spawn(move || {
                     // a valid url requires a base
                     let base_url = Url::parse(&format!("http://{}/", &addr)).unwrap();
@@ -82,8 +543,188 @@ impl Server {
                             return;
                         }
                     };
+                    // the admin endpoints rewrite the chain, they must not be reachable on a real
+                    // network; sizing the thread pools does not touch the chain
+                    if url.path().starts_with("/admin/") && !url.path().starts_with("/admin/threads") && !test_mode {
+                        respond_result!(req, false, "admin endpoints are only available with --test-mode");
+                        return;
+                    }
+                    // until the initial sync completes the chain is partial, do not serve it as if it were complete
+                    if url.path().starts_with("/blockchain/") && !sync_gate.lock().unwrap().is_complete() {
+                        let height = {blockchain.lock().unwrap().height()};
+                        respond_result!(req, false, format!("syncing: at height {}, see /sync/status", height));
+                        return;
+                    }
                     match url.path() {
+                        "/sync/status" => {
+                            let status = {
+                                let blockchain = blockchain.lock().unwrap();
+                                let peer_tips = peer_tips.lock().unwrap();
+                                let sync_gate = sync_gate.lock().unwrap();
+                                SyncMonitor::status(&blockchain, &peer_tips, &sync_gate)
+                            };
+                            respond_json!(req, status);
+                        }
+                        "/sync/downloads" => {
+                            // blocks queued and requested from each peer during the initial sync
+                            let status = {downloads.lock().unwrap().status()};
+                            respond_json!(req, status);
+                        }
+                        "/admin/reorg" => {
+                            // make the branch ending at `block` canonical, whatever its length
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let hash = match params.get("block") {
+                                Some(v) => v,
+                                None => {
+                                    respond_result!(req, false, "missing block");
+                                    return;
+                                }
+                            };
+                            let hash = match hash.parse::<H256>() {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, format!("error parsing block: {}", e));
+                                    return;
+                                }
+                            };
+                            let forced = {
+                                let mut blockchain = blockchain.lock().unwrap();
+                                let forced = blockchain.force_canonical(&hash);
+                                invariants::enforce(&blockchain, None, &format!("forcing block {} onto the longest chain", hash));
+                                forced
+                            };
+                            if forced {
+                                info!("Forced block {} onto the longest chain", hash);
+                                respond_result!(req, true, "ok");
+                            } else {
+                                respond_result!(req, false, format!("unknown block {}", hash));
+                            }
+                        }
+                        "/admin/invalidate" | "/admin/reconsider" => {
+                            // mark the branch from `block` on invalid, or valid again
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let hash = match params.get("block") {
+                                Some(v) => v,
+                                None => {
+                                    respond_result!(req, false, "missing block");
+                                    return;
+                                }
+                            };
+                            let hash = match hash.parse::<H256>() {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, format!("error parsing block: {}", e));
+                                    return;
+                                }
+                            };
+                            let invalidate = url.path() == "/admin/invalidate";
+                            let done = {
+                                let mut blockchain = blockchain.lock().unwrap();
+                                let done = if invalidate {
+                                    blockchain.invalidate_block(&hash)
+                                } else {
+                                    blockchain.reconsider_block(&hash)
+                                };
+                                invariants::enforce(&blockchain, None, &format!("{} block {}", url.path(), hash));
+                                done
+                            };
+                            if done {
+                                info!("{} block {}", if invalidate { "Invalidated" } else { "Reconsidered" }, hash);
+                                respond_result!(req, true, "ok");
+                            } else {
+                                respond_result!(req, false, format!("unknown or genesis block {}", hash));
+                            }
+                        }
+                        "/admin/reorg/release" => {
+                            {
+                                let mut blockchain = blockchain.lock().unwrap();
+                                blockchain.release_canonical();
+                                invariants::enforce(&blockchain, None, "releasing the forced block");
+                            }
+                            respond_result!(req, true, "ok");
+                        }
+                        "/admin/marker" => {
+                            // place an experiment marker now, every node supporting markers records it too
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let name = match params.get("name") {
+                                Some(name) => name,
+                                None => {
+                                    respond_result!(req, false, "missing name");
+                                    return;
+                                }
+                            };
+                            if let Err(e) = Marker::check_name(name) {
+                                respond_result!(req, false, e);
+                                return;
+                            }
+                            let marker = Marker::new(name, {clock.lock().unwrap().now()} as u64);
+                            markers::record_and_relay(&marker, None, &metrics, &network, &peer_features);
+                            respond_json!(req, marker);
+                        }
+                        "/admin/reorg/status" => {
+                            let status = {
+                                let blockchain = blockchain.lock().unwrap();
+                                ReorgStatus {
+                                    tip: blockchain.tip().to_string(),
+                                    height: blockchain.height(),
+                                    pinned: blockchain.pinned().map(|hash| hash.to_string()),
+                                    invalidated: blockchain.invalidated().iter().map(|hash| hash.to_string()).collect(),
+                                    fork_events: blockchain.fork_events(),
+                                }
+                            };
+                            respond_json!(req, status);
+                        }
+                        "/admin/threads" | "/admin/threads/set" => {
+                            let thread_pools = match &thread_pools {
+                                Some(thread_pools) => thread_pools,
+                                None => {
+                                    respond_result!(req, false, "this node does not size its thread pools");
+                                    return;
+                                }
+                            };
+                            if url.path() == "/admin/threads" {
+                                let status = {thread_pools.lock().unwrap().status()};
+                                respond_json!(req, status);
+                                return;
+                            }
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let pool = match params.get("pool").map(|v| v.parse::<PoolKind>()) {
+                                Some(Ok(v)) => v,
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                                None => {
+                                    respond_result!(req, false, "missing pool");
+                                    return;
+                                }
+                            };
+                            let count = match params.get("threads").map(|v| v.parse::<ThreadCount>()) {
+                                Some(Ok(v)) => v,
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing threads: {}", e));
+                                    return;
+                                }
+                                None => {
+                                    respond_result!(req, false, "missing threads");
+                                    return;
+                                }
+                            };
+                            let result = {thread_pools.lock().unwrap().resize(pool, count)};
+                            match result {
+                                Ok(threads) => respond_result!(req, true, format!("{} pool resized to {} threads", pool.name(), threads)),
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
+                        #[cfg(feature = "miner")]
                         "/miner/start" => {
+                            let miner = match miner {
+                                Some(miner) => miner,
+                                None => {
+                                    respond_result!(req, false, "this node does not run a miner");
+                                    return;
+                                }
+                            };
                             let params = url.query_pairs();
                             let params: HashMap<_, _> = params.into_owned().collect();
                             let lambda = match params.get("lambda") {
@@ -107,6 +748,11 @@ impl Server {
                             miner.start(lambda);
                             respond_result!(req, true, "ok");
                         }
+                        #[cfg(feature = "miner")]
+                        "/miner/efficiency" => match miner {
+                            Some(miner) => respond_json!(req, miner.efficiency()),
+                            None => respond_result!(req, false, "this node does not run a miner"),
+                        },
                         "/txgen/start" => {
                             print!("txgen start");
                             let params = url.query_pairs();
@@ -132,15 +778,470 @@ impl Server {
                             tx_generator.start(theta); //
                             respond_result!(req, true, "ok");
                         }
+                        "/network/orphans" => {
+                            let report = {
+                                let orphans = orphans.lock().unwrap();
+                                OrphanReport {
+                                    abandoned: orphans.abandoned(),
+                                    buffered: orphans.buffered_count(),
+                                    evicted: orphans.evicted(),
+                                    limits: orphans.limits(),
+                                    peers: orphans.stats().iter().map(|(addr, s)| (addr.to_string(), s.clone())).collect(),
+                                }
+                            };
+                            respond_json!(req, report);
+                        }
+                        "/network/rate-limits" => {
+                            let report = {rate_limiter.lock().unwrap().report()};
+                            respond_json!(req, report);
+                        }
+                        "/health" => {
+                            let status = {block_watch.lock().unwrap().status(std::time::Instant::now())};
+                            // load balancers and monitors only look at the status code
+                            let code = if status.status == AlarmLevel::Critical { 503 } else { 200 };
+                            let content_type =
+                                "Content-Type: application/json".parse::<Header>().unwrap();
+                            let resp = Response::from_string(serde_json::to_string(&status).unwrap())
+                                .with_header(content_type)
+                                .with_status_code(code);
+                            req.respond(resp).unwrap();
+                        }
+                        "/network/clock" => {
+                            let report = {clock.lock().unwrap().report()};
+                            respond_json!(req, report);
+                        }
                         "/network/ping" => {
                             network.broadcast(Message::Ping(String::from("Test ping")));
                             respond_result!(req, true, "ok");
                         }
+                        "/blockchain/blocks" => {
+                            // the blocks of the longest chain, a page at a time
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let entries: Vec<BlockEntry> = {
+                                let blockchain = blockchain.lock().unwrap();
+                                let block_metrics = block_metrics.as_ref().map(|block_metrics| block_metrics.lock().unwrap());
+                                let first_seen = first_seen.as_ref().map(|first_seen| first_seen.lock().unwrap());
+                                blockchain
+                                    .all_blocks_in_longest_chain()
+                                    .into_iter()
+                                    .filter_map(|hash| {
+                                        let header = blockchain.header(&hash)?;
+                                        let block = blockchain.block(&hash);
+                                        let metrics = block_metrics.as_ref().and_then(|block_metrics| block_metrics.get(&hash));
+                                        Some(BlockEntry {
+                                            hash: hash.to_string(),
+                                            height: header.length,
+                                            parent: header.parent.to_string(),
+                                            timestamp: header.timestamp as u64,
+                                            transactions: block.as_ref().map(|block| block.content.transactions.len()),
+                                            size: block.as_ref().map(|block| bincode::serialized_size(block).unwrap()),
+                                            signature_ops: metrics.map(|metrics| metrics.signature_ops),
+                                            validation_us: metrics.map(|metrics| metrics.validation_us),
+                                            received_at: metrics.map(|metrics| metrics.received_at),
+                                            first_from: first_seen
+                                                .as_ref()
+                                                .and_then(|first_seen| first_seen.source(&InvItem::Block(hash)))
+                                                .map(|addr| addr.to_string()),
+                                        })
+                                    })
+                                    .collect()
+                            };
+                            match query::list(&params, &BLOCKS, &entries) {
+                                Ok(page) => respond_signed!(req, signer, blockchain, page),
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
+                        "/blockchain/transactions" => {
+                            // the transactions of the longest chain, but those of pruned bodies
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let entries: Vec<TransactionEntry> = {
+                                let blockchain = blockchain.lock().unwrap();
+                                let mut entries = Vec::new();
+                                for (height, hash) in blockchain.all_blocks_in_longest_chain().into_iter().enumerate() {
+                                    if let Some(block) = blockchain.block(&hash) {
+                                        entries.extend(block.content.transactions.iter().map(|transaction| TransactionEntry::new(transaction, Some((hash, height as u32)))));
+                                    }
+                                }
+                                entries
+                            };
+                            match query::list(&params, &TRANSACTIONS, &entries) {
+                                Ok(page) => respond_signed!(req, signer, blockchain, page),
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
+                        "/mempool" => {
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let entries: Vec<TransactionEntry> =
+                                {mempool.lock().unwrap().hash_map.values().map(|transaction| TransactionEntry::new(transaction, None)).collect()};
+                            match query::list(&params, &MEMPOOL, &entries) {
+                                Ok(page) => respond_json!(req, page),
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
+                        "/network/peers" => {
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let connected = {peer_states.lock().unwrap().connected()};
+                            let entries: Vec<PeerEntry> = {
+                                let peer_tips = peer_tips.lock().unwrap();
+                                let peer_features = peer_features.lock().unwrap();
+                                let rtt = router.as_ref().map(|router| router.lock().unwrap().rtts()).unwrap_or_default();
+                                connected
+                                    .into_iter()
+                                    .map(|(addr, state)| {
+                                        let tip = peer_tips.get(&addr);
+                                        PeerEntry {
+                                            addr: addr.to_string(),
+                                            state: format!("{:?}", state),
+                                            height: tip.map(|(height, _)| height),
+                                            tip: tip.map(|(_, hash)| hash.to_string()),
+                                            features: peer_features.negotiated(&addr),
+                                            version: peer_features.version(&addr),
+                                            rtt_ms: rtt.get(&addr).map(|rtt| rtt.as_secs_f64() * 1000.0),
+                                        }
+                                    })
+                                    .collect()
+                            };
+                            match query::list(&params, &PEERS, &entries) {
+                                Ok(page) => respond_json!(req, page),
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
+                        "/network/topology" => {
+                            let (topology, router, book) = match &topology {
+                                Some(topology) => topology,
+                                None => {
+                                    respond_result!(req, false, "this node does not map the network");
+                                    return;
+                                }
+                            };
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let wait = match params.get("wait").map(|wait| wait.parse::<u64>()) {
+                                None => DEFAULT_TOPOLOGY_WAIT_MS,
+                                Some(Ok(wait)) if wait <= MAX_TOPOLOGY_WAIT_MS => wait,
+                                Some(Ok(_)) => {
+                                    respond_result!(req, false, format!("wait must be at most {} ms", MAX_TOPOLOGY_WAIT_MS));
+                                    return;
+                                }
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing wait: {}", e));
+                                    return;
+                                }
+                            };
+                            // ask the peers for their links again, the workers record the answers
+                            let peers = {peer_features.lock().unwrap().peers_supporting(Features::TOPOLOGY)};
+                            for peer in peers {
+                                network.send_to(peer, Message::GetLinks);
+                            }
+                            thread::sleep(std::time::Duration::from_millis(wait));
+                            let connected = {peer_states.lock().unwrap().connected()}.into_iter().map(|(addr, _)| addr).collect();
+                            let connections = {
+                                let book = book.lock().unwrap();
+                                let router = router.lock().unwrap();
+                                topology::connections(connected, Some(&book), &router)
+                            };
+                            let graph = {
+                                let mut topology = topology.lock().unwrap();
+                                let own_links = topology.local_links(connections);
+                                topology.graph(&own_links, std::time::Instant::now(), REPORT_MAX_AGE)
+                            };
+                            respond_json!(req, graph);
+                        }
+                        "/network/chain-proof" => {
+                            // check the longest chain of `peer` from a few sampled headers, as a light client would
+                            let chain_proofs = match &chain_proofs {
+                                Some(chain_proofs) => chain_proofs,
+                                None => {
+                                    respond_result!(req, false, "this node does not check chain proofs");
+                                    return;
+                                }
+                            };
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let peer = match params.get("peer").map(|v| v.parse::<std::net::SocketAddr>()) {
+                                Some(Ok(v)) => v,
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing peer: {}", e));
+                                    return;
+                                }
+                                None => {
+                                    respond_result!(req, false, "missing peer");
+                                    return;
+                                }
+                            };
+                            let draws = match params.get("draws").map(|v| v.parse::<u32>()) {
+                                None => DEFAULT_CHAIN_PROOF_DRAWS,
+                                Some(Ok(v)) if v <= MAX_CHAIN_PROOF_DRAWS => v,
+                                Some(Ok(_)) => {
+                                    respond_result!(req, false, format!("draws must be at most {}", MAX_CHAIN_PROOF_DRAWS));
+                                    return;
+                                }
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing draws: {}", e));
+                                    return;
+                                }
+                            };
+                            let wait = match params.get("wait").map(|wait| wait.parse::<u64>()) {
+                                None => DEFAULT_CHAIN_PROOF_WAIT_MS,
+                                Some(Ok(wait)) if wait <= MAX_CHAIN_PROOF_WAIT_MS => wait,
+                                Some(Ok(_)) => {
+                                    respond_result!(req, false, format!("wait must be at most {} ms", MAX_CHAIN_PROOF_WAIT_MS));
+                                    return;
+                                }
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing wait: {}", e));
+                                    return;
+                                }
+                            };
+                            let supported = {peer_features.lock().unwrap().supports(&peer, Features::CHAIN_PROOFS)};
+                            if !supported {
+                                respond_result!(req, false, format!("peer {} is not connected or does not serve chain proofs", peer));
+                                return;
+                            }
+                            let seed = {chain_proofs.lock().unwrap().request(peer, draws)};
+                            network.send_to(peer, Message::GetChainProof(seed, draws));
+                            let deadline = std::time::Instant::now() + std::time::Duration::from_millis(wait);
+                            let result = loop {
+                                let result = {chain_proofs.lock().unwrap().result(&peer)};
+                                if result.is_some() || std::time::Instant::now() >= deadline {
+                                    break result;
+                                }
+                                thread::sleep(std::time::Duration::from_millis(20));
+                            };
+                            match result {
+                                Some(Ok(summary)) => respond_json!(req, ChainProofReport {
+                                    peer: peer.to_string(),
+                                    height: summary.height,
+                                    tip: summary.tip.to_string(),
+                                    root: summary.root.to_string(),
+                                    estimated_work: summary.estimated_work,
+                                    sampled_headers: summary.sampled_headers,
+                                }),
+                                Some(Err(e)) => respond_result!(req, false, format!("invalid chain proof from {}: {}", peer, e)),
+                                None => respond_result!(req, false, format!("no chain proof from {} within {} ms", peer, wait)),
+                            }
+                        }
+                        "/network/bans" => {
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let entries: Vec<BanEntry> = {
+                                ban_list
+                                    .lock()
+                                    .unwrap()
+                                    .bans(banlist::now())
+                                    .into_iter()
+                                    .map(|(ip, ban)| BanEntry { addr: ip.to_string(), reason: ban.reason, until: ban.until })
+                                    .collect()
+                            };
+                            match query::list(&params, &BANS, &entries) {
+                                Ok(page) => respond_json!(req, page),
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
+                        "/network/first-seen" => {
+                            let first_seen = match &first_seen {
+                                Some(first_seen) => first_seen,
+                                None => {
+                                    respond_result!(req, false, "this node does not record who delivers blocks first");
+                                    return;
+                                }
+                            };
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let entries: Vec<ContributionEntry> = {
+                                first_seen
+                                    .lock()
+                                    .unwrap()
+                                    .contributions()
+                                    .iter()
+                                    .map(|(addr, contribution)| ContributionEntry {
+                                        addr: addr.to_string(),
+                                        blocks: contribution.blocks,
+                                        transactions: contribution.transactions,
+                                    })
+                                    .collect()
+                            };
+                            match query::list(&params, &CONTRIBUTIONS, &entries) {
+                                Ok(page) => respond_json!(req, page),
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
+                        "/network/ban" | "/network/unban" => {
+                            // bans are by address, a port given with it is ignored
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let addr = match params.get("addr") {
+                                Some(addr) => addr,
+                                None => {
+                                    respond_result!(req, false, "missing addr");
+                                    return;
+                                }
+                            };
+                            let ip = match addr.parse::<std::net::IpAddr>() {
+                                Ok(ip) => ip,
+                                Err(e) => match addr.parse::<std::net::SocketAddr>() {
+                                    Ok(addr) => addr.ip(),
+                                    Err(_) => {
+                                        respond_result!(req, false, format!("error parsing addr: {}", e));
+                                        return;
+                                    }
+                                },
+                            };
+                            if url.path() == "/network/unban" {
+                                let unbanned = {ban_list.lock().unwrap().unban(&ip)};
+                                match unbanned {
+                                    Ok(true) => respond_result!(req, true, "ok"),
+                                    Ok(false) => respond_result!(req, false, format!("{} is not banned", ip)),
+                                    Err(e) => respond_result!(req, false, format!("error saving the ban list: {}", e)),
+                                }
+                                return;
+                            }
+                            let seconds = match params.get("seconds").map(|seconds| seconds.parse::<u64>()) {
+                                None => DEFAULT_BAN_SECONDS,
+                                Some(Ok(seconds)) if seconds > 0 => seconds,
+                                Some(Ok(_)) => {
+                                    respond_result!(req, false, "seconds must be over 0");
+                                    return;
+                                }
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing seconds: {}", e));
+                                    return;
+                                }
+                            };
+                            let reason = params.get("reason").map(String::as_str).unwrap_or("banned through the API");
+                            let until = banlist::now().saturating_add(seconds.saturating_mul(1000));
+                            let banned = {ban_list.lock().unwrap().ban(ip, reason, until)};
+                            if let Err(e) = banned {
+                                respond_result!(req, false, format!("error saving the ban list: {}", e));
+                                return;
+                            }
+                            info!("Banned {} for {}s: {}", ip, seconds, reason);
+                            network.disconnect_ip(ip);
+                            respond_json!(req, BanEntry { addr: ip.to_string(), reason: reason.to_string(), until });
+                        }
                         "/blockchain/longest-chain" => {
                             print!("longest chain length {}", {blockchain.lock().unwrap().all_blocks_in_longest_chain().len()});
                             let v = {blockchain.lock().unwrap().all_blocks_in_longest_chain()};
                             let v_string: Vec<String> = v.into_iter().map(|h|h.to_string()).collect();
-                            respond_json!(req, v_string);
+                            respond_signed!(req, signer, blockchain, v_string);
+                        }
+                        "/blockchain/mmr" => {
+                            let status = {
+                                let blockchain = blockchain.lock().unwrap();
+                                MmrStatus {
+                                    leaves: blockchain.height() as usize + 1,
+                                    root: blockchain.header_mmr_root().to_string(),
+                                    commitment_interval: MMR_COMMITMENT_INTERVAL,
+                                    commitments: blockchain
+                                        .mmr_commitments()
+                                        .into_iter()
+                                        .map(|(height, root)| MmrCommitment { height, root: root.to_string() })
+                                        .collect(),
+                                }
+                            };
+                            respond_signed!(req, signer, blockchain, status);
+                        }
+                        "/blockchain/mmr/proof" => {
+                            // prove `block` is on the longest chain, up to the tip or up to height `at`
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let hash = match params.get("block").map(|v| v.parse::<H256>()) {
+                                Some(Ok(v)) => v,
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing block: {}", e));
+                                    return;
+                                }
+                                None => {
+                                    respond_result!(req, false, "missing block");
+                                    return;
+                                }
+                            };
+                            let at = match params.get("at").map(|v| v.parse::<u32>()) {
+                                None => None,
+                                Some(Ok(v)) => Some(v),
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing at: {}", e));
+                                    return;
+                                }
+                            };
+                            let proof = {
+                                let blockchain = blockchain.lock().unwrap();
+                                blockchain.prove_header(&hash, at).map(|proof| {
+                                    let root = blockchain.header_mmr_root_at(proof.leaf_count);
+                                    HeaderProof::new(hash, root, proof)
+                                })
+                            };
+                            match proof {
+                                Some(proof) => respond_signed!(req, signer, blockchain, proof),
+                                None => respond_result!(req, false, format!("block {} is not on the longest chain up to that height", hash)),
+                            }
+                        }
+                        "/blockchain/checkpoint" => {
+                            // a trusted block of the longest chain for new nodes to sync up to, the tip by default
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let height = match params.get("height").map(|v| v.parse::<u32>()) {
+                                None => None,
+                                Some(Ok(v)) => Some(v),
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing height: {}", e));
+                                    return;
+                                }
+                            };
+                            let checkpoint = {
+                                let blockchain = blockchain.lock().unwrap();
+                                blockchain.checkpoint_at(height.unwrap_or_else(|| blockchain.height()))
+                            };
+                            match checkpoint {
+                                Some(checkpoint) => respond_signed!(req, signer, blockchain, CheckpointReport {
+                                    height: checkpoint.height,
+                                    hash: checkpoint.hash.to_string(),
+                                    state_root: checkpoint.state_root.to_string(),
+                                    checkpoint: checkpoint.to_string(),
+                                }),
+                                None => respond_result!(req, false, "height above the tip"),
+                            }
+                        }
+                        "/blockchain/summary" => {
+                            // the tip and the last `recent` blocks, compared by /diagnostics/compare
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let recent = match params.get("recent").map(|v| v.parse::<usize>()) {
+                                None => DEFAULT_RECENT,
+                                Some(Ok(v)) => v,
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing recent: {}", e));
+                                    return;
+                                }
+                            };
+                            let summary = ChainSummary::of(&blockchain.lock().unwrap(), recent);
+                            respond_signed!(req, signer, blockchain, summary);
+                        }
+                        "/diagnostics/compare" => {
+                            // compare our chain with the one of the node whose API server is at `peer`
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let peer = match params.get("peer").map(|v| v.parse::<std::net::SocketAddr>()) {
+                                Some(Ok(v)) => v,
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing peer: {}", e));
+                                    return;
+                                }
+                                None => {
+                                    respond_result!(req, false, "missing peer");
+                                    return;
+                                }
+                            };
+                            let recent = match params.get("recent").map(|v| v.parse::<usize>()) {
+                                None => DEFAULT_RECENT,
+                                Some(Ok(v)) => v,
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing recent: {}", e));
+                                    return;
+                                }
+                            };
+                            let peer_summary = match diagnostics::fetch_summary(peer, recent) {
+                                Ok(summary) => summary,
+                                Err(e) => {
+                                    respond_result!(req, false, format!("error asking {} for its chain: {}", peer, e));
+                                    return;
+                                }
+                            };
+                            let local_summary = ChainSummary::of(&blockchain.lock().unwrap(), recent);
+                            respond_json!(req, diagnostics::compare(&local_summary, &peer_summary));
                         }
                         "/blockchain/longest-chain-tx" => {
                             // get the transaction hashes of all the blocks in the longest chain
@@ -151,7 +1252,86 @@ impl Server {
                                 let v_string: Vec<String> = block_tx.into_iter().map(|h|h.to_string()).collect();
                                 all_txs.push(v_string);
                             }
-                            respond_json!(req, all_txs);
+                            respond_signed!(req, signer, blockchain, all_txs);
+                        }
+                        "/blockchain/difficulty" => {
+                            // difficulty and work of the longest chain between heights `from` and `to`
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let from = match params.get("from").map(|v| v.parse::<u32>()) {
+                                None => 0,
+                                Some(Ok(v)) => v,
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing from: {}", e));
+                                    return;
+                                }
+                            };
+                            let to = match params.get("to").map(|v| v.parse::<u32>()) {
+                                None => u32::MAX,
+                                Some(Ok(v)) => v,
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing to: {}", e));
+                                    return;
+                                }
+                            };
+                            let history = {blockchain.lock().unwrap().chain_work(from, to)};
+                            let entries: Vec<ChainWorkEntry> = history
+                                .into_iter()
+                                .map(|w| ChainWorkEntry {
+                                    height: w.height,
+                                    hash: w.hash.to_string(),
+                                    difficulty: w.difficulty.to_string(),
+                                    work: w.work,
+                                    cumulative_work: w.cumulative_work,
+                                })
+                                .collect();
+                            respond_signed!(req, signer, blockchain, entries);
+                        }
+                        "/blockchain/export/params" => {
+                            // chain parameters of a new network starting from the balances at our tip
+                            let params = {blockchain.lock().unwrap().export_params()};
+                            respond_signed!(req, signer, blockchain, params);
+                        }
+                        "/blockchain/export/blocks" | "/blockchain/export/transactions" => {
+                            // stream the blocks or transactions of the longest chain between heights `from` and `to`
+                            let kind = if url.path() == "/blockchain/export/blocks" {
+                                ExportKind::Blocks
+                            } else {
+                                ExportKind::Transactions
+                            };
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let from = match params.get("from").map(|v| v.parse::<u32>()) {
+                                None => 0,
+                                Some(Ok(v)) => v,
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing from: {}", e));
+                                    return;
+                                }
+                            };
+                            let to = match params.get("to").map(|v| v.parse::<u32>()) {
+                                None => u32::MAX,
+                                Some(Ok(v)) => v,
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing to: {}", e));
+                                    return;
+                                }
+                            };
+                            let format = match params.get("format").map(|v| v.parse::<ExportFormat>()) {
+                                None => ExportFormat::Ndjson,
+                                Some(Ok(v)) => v,
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
+                            let content_type = format!("Content-Type: {}", format.content_type()).parse::<Header>().unwrap();
+                            let export = ChainExport::new(&blockchain, from, to, kind, format);
+                            // no length is given, so the response is sent chunked as the export is read
+                            let resp = Response::new(200.into(), vec![content_type], export, None, None);
+                            if let Err(e) = req.respond(resp) {
+                                info!("Export stream ended early: {}", e);
+                            }
                         }
                         "/blockchain/state" => {
                             let params = url.query_pairs();
@@ -188,9 +1368,248 @@ impl Server {
                             for (address, (nonce, balance)) in state.state {
                                 all_addresses.push((address, nonce, balance));
                             }
-                            respond_json!(req, all_addresses);
+                            respond_signed!(req, signer, blockchain, all_addresses);
                         }
 
+                        "/metrics/storage" => {
+                            let metrics = {blockchain.lock().unwrap().storage_metrics()};
+                            match metrics {
+                                Ok(metrics) => respond_json!(req, metrics),
+                                Err(e) => respond_result!(req, false, format!("error reading store size: {}", e)),
+                            }
+                        }
+                        "/metrics/memory" => {
+                            let report = {memory.lock().unwrap().clone()};
+                            respond_json!(req, report);
+                        }
+                        "/metrics/export" => {
+                            // export the metrics history between `from` and `to` (ms since epoch) as CSV
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let from = match params.get("from").map(|v| v.parse::<u64>()) {
+                                None => 0,
+                                Some(Ok(v)) => v,
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing from: {}", e));
+                                    return;
+                                }
+                            };
+                            let to = match params.get("to").map(|v| v.parse::<u64>()) {
+                                None => u64::MAX,
+                                Some(Ok(v)) => v,
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing to: {}", e));
+                                    return;
+                                }
+                            };
+                            let csv = match metrics.lock().unwrap().export_csv(from, to) {
+                                Ok(csv) => csv,
+                                Err(e) => {
+                                    respond_result!(req, false, format!("error reading metrics history: {}", e));
+                                    return;
+                                }
+                            };
+                            let content_type = "Content-Type: text/csv".parse::<Header>().unwrap();
+                            req.respond(Response::from_string(csv).with_header(content_type)).unwrap();
+                        }
+                        "/metrics/markers" => {
+                            // the experiment markers placed between `from` and `to` (ms since epoch)
+                            let params: HashMap<_, _> = url.query_pairs().into_owned().collect();
+                            let from = match params.get("from").map(|v| v.parse::<u64>()) {
+                                None => 0,
+                                Some(Ok(v)) => v,
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing from: {}", e));
+                                    return;
+                                }
+                            };
+                            let to = match params.get("to").map(|v| v.parse::<u64>()) {
+                                None => u64::MAX,
+                                Some(Ok(v)) => v,
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing to: {}", e));
+                                    return;
+                                }
+                            };
+                            let markers = {metrics.lock().unwrap().markers(from, to)};
+                            respond_json!(req, markers);
+                        }
+                        #[cfg(feature = "wallet")]
+                        "/wallet/send" => {
+                            let wallet = match wallet {
+                                Some(wallet) => wallet,
+                                None => {
+                                    respond_result!(req, false, "this node does not run a wallet");
+                                    return;
+                                }
+                            };
+                            // send now, or hold the send until a `height` or `time` (ms since epoch) is reached
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let receiver = match params.get("to").map(|v| v.parse::<Address>()) {
+                                Some(Ok(v)) => v,
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing to: {}", e));
+                                    return;
+                                }
+                                None => {
+                                    respond_result!(req, false, "missing to");
+                                    return;
+                                }
+                            };
+                            let value = match params.get("value").map(|v| v.parse::<Amount>()) {
+                                Some(Ok(v)) => v,
+                                Some(Err(e)) => {
+                                    respond_result!(req, false, format!("error parsing value: {}", e));
+                                    return;
+                                }
+                                None => {
+                                    respond_result!(req, false, "missing value");
+                                    return;
+                                }
+                            };
+                            let condition = match (params.get("height"), params.get("time")) {
+                                (None, None) => None,
+                                (Some(h), None) => match h.parse::<u32>() {
+                                    Ok(v) => Some(SendCondition::Height(v)),
+                                    Err(e) => {
+                                        respond_result!(req, false, format!("error parsing height: {}", e));
+                                        return;
+                                    }
+                                },
+                                (None, Some(t)) => match t.parse::<u128>() {
+                                    Ok(v) => Some(SendCondition::Timestamp(v)),
+                                    Err(e) => {
+                                        respond_result!(req, false, format!("error parsing time: {}", e));
+                                        return;
+                                    }
+                                },
+                                (Some(_), Some(_)) => {
+                                    respond_result!(req, false, "only one of height and time may be given");
+                                    return;
+                                }
+                            };
+                            // a malformed request does not count against the limit, only the sends it would make
+                            let client = match params.get("token") {
+                                Some(token) => format!("token {}", token),
+                                None => req.remote_addr().ip().to_string(),
+                            };
+                            let admitted = {send_limiter.lock().unwrap().admit(&client, std::time::Instant::now())};
+                            if let Err(retry_after) = admitted {
+                                let message = match retry_after {
+                                    Some(wait) => format!("too many sends, retry in {:.1}s", wait.as_secs_f64()),
+                                    None => "too many sends, the limit of this node does not refill".to_string(),
+                                };
+                                info!("Refusing a send of API client {}: {}", client, message);
+                                let payload = ApiResponse { success: false, message };
+                                let mut resp = Response::from_string(serde_json::to_string_pretty(&payload).unwrap())
+                                    .with_header("Content-Type: application/json".parse::<Header>().unwrap())
+                                    .with_status_code(429);
+                                if let Some(wait) = retry_after {
+                                    // in whole seconds, rounded up so a client retrying then is admitted
+                                    let seconds = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+                                    resp = resp.with_header(format!("Retry-After: {}", seconds).parse::<Header>().unwrap());
+                                }
+                                req.respond(resp).unwrap();
+                                return;
+                            }
+                            let sign_now = params.get("presign").map(|v| v == "true").unwrap_or(false);
+                            let result = match condition {
+                                None => wallet.lock().unwrap().send(receiver, value).map(|hash| hash.to_string()),
+                                Some(c) => wallet.lock().unwrap().schedule(receiver, value, c, sign_now).map(|id| format!("scheduled {}", id)),
+                            };
+                            match result {
+                                Ok(message) => respond_result!(req, true, message),
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
+                        #[cfg(feature = "wallet")]
+                        "/wallet/scheduled" => {
+                            let wallet = match wallet {
+                                Some(wallet) => wallet,
+                                None => {
+                                    respond_result!(req, false, "this node does not run a wallet");
+                                    return;
+                                }
+                            };
+                            let entries: Vec<ScheduledSendEntry> = wallet
+                                .lock()
+                                .unwrap()
+                                .scheduled()
+                                .iter()
+                                .map(|s| ScheduledSendEntry {
+                                    id: s.id,
+                                    to: s.receiver.to_string(),
+                                    value: s.value,
+                                    height: match s.condition {
+                                        SendCondition::Height(h) => Some(h),
+                                        _ => None,
+                                    },
+                                    time: match s.condition {
+                                        SendCondition::Timestamp(t) => Some(t),
+                                        _ => None,
+                                    },
+                                    signed: s.signed.is_some(),
+                                })
+                                .collect();
+                            respond_json!(req, entries);
+                        }
+                        #[cfg(feature = "wallet")]
+                        "/wallet/ledger" => {
+                            let wallet = match wallet {
+                                Some(wallet) => wallet,
+                                None => {
+                                    respond_result!(req, false, "this node does not run a wallet");
+                                    return;
+                                }
+                            };
+                            // the wallet's activity as a ledger-cli journal, or as CSV with format=csv
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let entries = {wallet.lock().unwrap().ledger()};
+                            let (body, content_type) = match params.get("format").map(|v| v.as_str()) {
+                                None | Some("ledger") => (ledger::to_journal(&entries), "Content-Type: text/plain"),
+                                Some("csv") => (ledger::to_csv(&entries), "Content-Type: text/csv"),
+                                Some(other) => {
+                                    respond_result!(req, false, format!("unknown format {}, expected ledger or csv", other));
+                                    return;
+                                }
+                            };
+                            let content_type = content_type.parse::<Header>().unwrap();
+                            req.respond(Response::from_string(body).with_header(content_type)).unwrap();
+                        }
+                        #[cfg(feature = "wallet")]
+                        "/wallet/receive" => {
+                            let wallet = match wallet {
+                                Some(wallet) => wallet,
+                                None => {
+                                    respond_result!(req, false, "this node does not run a wallet");
+                                    return;
+                                }
+                            };
+                            // a fresh address for each payment, so payments are not linked by it
+                            let address = {wallet.lock().unwrap().receive_address()};
+                            respond_result!(req, true, address);
+                        }
+                        #[cfg(feature = "wallet")]
+                        "/wallet/balances" => {
+                            let wallet = match wallet {
+                                Some(wallet) => wallet,
+                                None => {
+                                    respond_result!(req, false, "this node does not run a wallet");
+                                    return;
+                                }
+                            };
+                            let balances = {wallet.lock().unwrap().balances()};
+                            respond_signed!(req, signer, blockchain, BalancesReport {
+                                total: balances.total,
+                                addresses: balances
+                                    .addresses
+                                    .into_iter()
+                                    .map(|a| AddressBalanceEntry { index: a.index, address: a.address.to_string(), nonce: a.nonce, balance: a.balance })
+                                    .collect(),
+                            });
+                        }
                         "/blockchain/longest-chain-tx-count" => {
                             respond_result!(req, false, "unimplemented!");
                         }
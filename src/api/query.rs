@@ -0,0 +1,240 @@
+use serde::Serialize;
+use serde_json::{json, Map, Value};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fmt;
+
+/// Items on a page unless a limit is asked for
+pub const DEFAULT_LIMIT: usize = 100;
+/// Most items on a page
+pub const MAX_LIMIT: usize = 1000;
+
+/// The items a list endpoint serves: every field of an item, the one telling items apart and
+/// the ones they can be sorted by, the first of which is the default.
+pub struct ListSpec {
+    pub fields: &'static [&'static str],
+    pub id: &'static str,
+    pub sort_keys: &'static [&'static str],
+}
+
+/// Reason the query parameters of a list endpoint were refused.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QueryError {
+    Limit(String),
+    Sort { key: String, allowed: Vec<&'static str> },
+    Fields { field: String, allowed: Vec<&'static str> },
+    /// not a cursor this endpoint gave out with the same sort
+    Cursor,
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QueryError::Limit(limit) => write!(f, "invalid limit {}, expected 1 to {}", limit, MAX_LIMIT),
+            QueryError::Sort { key, allowed } => write!(f, "cannot sort by {}, expected one of {}", key, allowed.join(", ")),
+            QueryError::Fields { field, allowed } => write!(f, "unknown field {}, expected some of {}", field, allowed.join(", ")),
+            QueryError::Cursor => write!(f, "invalid cursor, it must come from a page with the same sort"),
+        }
+    }
+}
+
+/// One page of a list, `next` being the cursor of the following page if there is one.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Page {
+    pub items: Vec<Value>,
+    pub next: Option<String>,
+}
+
+/// Query parameters shared by the list endpoints: `limit` items per page, the page after
+/// `cursor`, sorted by `sort`, descending if prefixed with -, keeping only the comma
+/// separated `fields`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ListQuery {
+    limit: usize,
+    sort: String,
+    descending: bool,
+    // sort value and id of the last item of the previous page
+    after: Option<(Value, Value)>,
+    fields: Option<Vec<String>>,
+}
+
+impl ListQuery {
+    pub fn parse(params: &HashMap<String, String>, spec: &ListSpec) -> Result<Self, QueryError> {
+        let limit = match params.get("limit") {
+            None => DEFAULT_LIMIT,
+            Some(limit) => match limit.parse::<usize>() {
+                Ok(parsed) if (1..=MAX_LIMIT).contains(&parsed) => parsed,
+                _ => return Err(QueryError::Limit(limit.clone())),
+            },
+        };
+        let (sort, descending) = match params.get("sort") {
+            None => (spec.sort_keys[0].to_string(), false),
+            Some(sort) => match sort.strip_prefix('-') {
+                Some(key) => (key.to_string(), true),
+                None => (sort.clone(), false),
+            },
+        };
+        if !spec.sort_keys.contains(&sort.as_str()) {
+            return Err(QueryError::Sort { key: sort, allowed: spec.sort_keys.to_vec() });
+        }
+        let fields = match params.get("fields") {
+            None => None,
+            Some(fields) => {
+                let fields: Vec<String> = fields.split(',').map(|field| field.to_string()).collect();
+                if let Some(field) = fields.iter().find(|field| !spec.fields.contains(&field.as_str())) {
+                    return Err(QueryError::Fields { field: field.clone(), allowed: spec.fields.to_vec() });
+                }
+                Some(fields)
+            }
+        };
+        let mut query = ListQuery { limit, sort, descending, after: None, fields };
+        if let Some(cursor) = params.get("cursor") {
+            query.after = Some(query.decode_cursor(cursor).ok_or(QueryError::Cursor)?);
+        }
+        Ok(query)
+    }
+
+    // a cursor names the sort it was made for, so it is not used with another
+    fn sort_name(&self) -> String {
+        format!("{}{}", if self.descending { "-" } else { "" }, self.sort)
+    }
+
+    fn encode_cursor(&self, key: &Value, id: &Value) -> String {
+        hex::encode(json!([self.sort_name(), key, id]).to_string())
+    }
+
+    fn decode_cursor(&self, cursor: &str) -> Option<(Value, Value)> {
+        let bytes = hex::decode(cursor).ok()?;
+        match serde_json::from_slice::<Value>(&bytes).ok()? {
+            Value::Array(parts) if parts.len() == 3 && parts[0] == Value::String(self.sort_name()) => {
+                Some((parts[1].clone(), parts[2].clone()))
+            }
+            _ => None,
+        }
+    }
+
+    // order of two (sort value, id) pairs in this query's sort
+    fn compare(&self, a: (&Value, &Value), b: (&Value, &Value)) -> Ordering {
+        let order = compare_values(a.0, b.0).then_with(|| compare_values(a.1, b.1));
+        if self.descending {
+            order.reverse()
+        } else {
+            order
+        }
+    }
+
+    /// The page of `items` this query asks for. Items are ordered by the sort key and then by
+    /// id, and a page starts after the item its cursor names, so pages neither repeat nor skip
+    /// items that stay in the list while it is paged through. Items must serialize as objects
+    /// without u128 fields.
+    pub fn page<T: Serialize>(&self, spec: &ListSpec, items: &[T]) -> Page {
+        let mut items: Vec<Map<String, Value>> = items
+            .iter()
+            .map(|item| match serde_json::to_value(item).unwrap() {
+                Value::Object(fields) => fields,
+                other => panic!("list items must serialize as objects, not {}", other),
+            })
+            .collect();
+        let key = |item: &Map<String, Value>| (item.get(&self.sort).cloned().unwrap_or(Value::Null), item.get(spec.id).cloned().unwrap_or(Value::Null));
+        items.sort_by(|a, b| {
+            let (a, b) = (key(a), key(b));
+            self.compare((&a.0, &a.1), (&b.0, &b.1))
+        });
+        if let Some((after_key, after_id)) = &self.after {
+            items.retain(|item| {
+                let (item_key, item_id) = key(item);
+                self.compare((&item_key, &item_id), (after_key, after_id)) == Ordering::Greater
+            });
+        }
+        let next = if items.len() > self.limit {
+            let (last_key, last_id) = key(&items[self.limit - 1]);
+            Some(self.encode_cursor(&last_key, &last_id))
+        } else {
+            None
+        };
+        items.truncate(self.limit);
+        let items = items
+            .into_iter()
+            .map(|item| match &self.fields {
+                Some(fields) => Value::Object(item.into_iter().filter(|(field, _)| fields.contains(field)).collect()),
+                None => Value::Object(item),
+            })
+            .collect();
+        Page { items, next }
+    }
+}
+
+/// The page of `items` the query parameters `params` ask for, the way every list endpoint
+/// serves its items
+pub fn list<T: Serialize>(params: &HashMap<String, String>, spec: &ListSpec, items: &[T]) -> Result<Page, QueryError> {
+    Ok(ListQuery::parse(params, spec)?.page(spec, items))
+}
+
+// null, then booleans, numbers and strings, each in their natural order
+fn compare_values(a: &Value, b: &Value) -> Ordering {
+    fn rank(value: &Value) -> u8 {
+        match value {
+            Value::Null => 0,
+            Value::Bool(_) => 1,
+            Value::Number(_) => 2,
+            Value::String(_) => 3,
+            Value::Array(_) => 4,
+            Value::Object(_) => 5,
+        }
+    }
+    match (a, b) {
+        (Value::Bool(a), Value::Bool(b)) => a.cmp(b),
+        (Value::Number(a), Value::Number(b)) => match (a.as_u64(), b.as_u64()) {
+            (Some(a), Some(b)) => a.cmp(&b),
+            _ => a.as_f64().unwrap_or(0.0).partial_cmp(&b.as_f64().unwrap_or(0.0)).unwrap_or(Ordering::Equal),
+        },
+        (Value::String(a), Value::String(b)) => a.cmp(b),
+        _ => rank(a).cmp(&rank(b)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Serialize)]
+    struct Item {
+        hash: String,
+        value: u64,
+        note: &'static str,
+    }
+
+    const ITEMS: ListSpec = ListSpec { fields: &["hash", "value", "note"], id: "hash", sort_keys: &["hash", "value"] };
+
+    fn params(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn pages_follow_cursors() {
+        // two items share a value, the hash breaks the tie
+        let items: Vec<Item> = [(5, "e"), (10, "a"), (1, "c"), (5, "b"), (7, "d")]
+            .iter()
+            .map(|(value, hash)| Item { hash: hash.to_string(), value: *value, note: "x" })
+            .collect();
+        let hashes = |page: &Page| page.items.iter().map(|item| item["hash"].as_str().unwrap().to_string()).collect::<Vec<_>>();
+
+        let first = list(&params(&[("sort", "-value"), ("limit", "2")]), &ITEMS, &items).unwrap();
+        assert_eq!(hashes(&first), ["a", "d"]);
+        let cursor = first.next.clone().unwrap();
+        let second = list(&params(&[("sort", "-value"), ("limit", "2"), ("cursor", &cursor)]), &ITEMS, &items).unwrap();
+        assert_eq!(hashes(&second), ["e", "b"]);
+        let last = list(&params(&[("sort", "-value"), ("limit", "2"), ("cursor", &second.next.unwrap())]), &ITEMS, &items).unwrap();
+        assert_eq!((hashes(&last), last.next), (vec!["c".to_string()], None));
+
+        // the default sort, with some fields only
+        let page = list(&params(&[("fields", "hash,value")]), &ITEMS, &items).unwrap();
+        assert_eq!(hashes(&page), ["a", "b", "c", "d", "e"]);
+        assert_eq!(page.items[0], json!({"hash": "a", "value": 10}));
+
+        assert_eq!(list(&params(&[("cursor", &cursor)]), &ITEMS, &items), Err(QueryError::Cursor));
+        assert_eq!(list(&params(&[("limit", "0")]), &ITEMS, &items), Err(QueryError::Limit("0".to_string())));
+        assert!(matches!(list(&params(&[("sort", "note")]), &ITEMS, &items), Err(QueryError::Sort { .. })));
+        assert!(matches!(list(&params(&[("fields", "hash,size")]), &ITEMS, &items), Err(QueryError::Fields { .. })));
+    }
+}
@@ -0,0 +1,78 @@
+use crate::network::ratelimit::RateLimit;
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+// clients tracked before those whose bucket refilled are forgotten
+const MAX_CLIENTS: usize = 10000;
+
+// tokens left in the bucket of a client, as of `updated`
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Limits the transactions each API client may submit, so one script cannot fill the mempool
+/// with its sends while others wait. A client is named by the token it passes, or its address
+/// if it passes none; tokens are not checked, they only tell apart clients sharing an address.
+pub struct ClientLimiter {
+    limit: Option<RateLimit>,
+    buckets: HashMap<String, Bucket>,
+}
+
+impl ClientLimiter {
+    /// A limiter holding every client to `limit`, or no limiter if None
+    pub fn new(limit: Option<RateLimit>) -> Self {
+        ClientLimiter { limit, buckets: HashMap::new() }
+    }
+
+    /// Whether `client` may submit a transaction at `now`, takes a token if so. If not, how
+    /// long until it may, None if it never will as the limit does not refill.
+    pub fn admit(&mut self, client: &str, now: Instant) -> Result<(), Option<Duration>> {
+        let limit = match self.limit {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+        if self.buckets.len() >= MAX_CLIENTS && !self.buckets.contains_key(client) {
+            // a full bucket is the same as none
+            self.buckets
+                .retain(|_, bucket| bucket.tokens + now.saturating_duration_since(bucket.updated).as_secs_f64() * limit.rate < limit.burst);
+        }
+        let bucket = self.buckets.entry(client.to_string()).or_insert(Bucket { tokens: limit.burst, updated: now });
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * limit.rate).min(limit.burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else if limit.rate > 0.0 {
+            Err(Some(Duration::from_secs_f64((1.0 - bucket.tokens) / limit.rate)))
+        } else {
+            Err(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clients_wait_their_turn() {
+        // a send every 2 s after a burst of 2
+        let mut limiter = ClientLimiter::new(Some(RateLimit::new(0.5, 2.0)));
+        let start = Instant::now();
+        assert_eq!(limiter.admit("demo", start), Ok(()));
+        assert_eq!(limiter.admit("demo", start), Ok(()));
+        assert_eq!(limiter.admit("demo", start), Err(Some(Duration::from_secs(2))));
+        // other clients are not affected
+        assert_eq!(limiter.admit("127.0.0.1", start), Ok(()));
+        assert_eq!(limiter.admit("demo", start + Duration::from_secs(1)), Err(Some(Duration::from_secs(1))));
+        assert_eq!(limiter.admit("demo", start + Duration::from_secs(2)), Ok(()));
+
+        let mut once = ClientLimiter::new(Some(RateLimit::new(0.0, 1.0)));
+        assert_eq!(once.admit("demo", start), Ok(()));
+        assert_eq!(once.admit("demo", start + Duration::from_secs(60)), Err(None));
+        assert_eq!(ClientLimiter::new(None).admit("demo", start), Ok(()));
+    }
+}
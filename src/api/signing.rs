@@ -0,0 +1,78 @@
+use crate::types::hash::H256;
+
+use ring::digest;
+use ring::signature::{self, Ed25519KeyPair, KeyPair};
+
+/// Header carrying the public key of the node, hex encoded
+pub const NODE_KEY_HEADER: &str = "X-Node-Key";
+/// Headers carrying the tip of the node when it signed, and its height
+pub const TIP_HEADER: &str = "X-Tip";
+pub const HEIGHT_HEADER: &str = "X-Height";
+/// Header carrying when the node signed, in ms since the unix epoch
+pub const SIGNED_AT_HEADER: &str = "X-Signed-At";
+/// Header carrying the Ed25519 signature, hex encoded
+pub const SIGNATURE_HEADER: &str = "X-Signature";
+
+/// What a signature covers: the request `target`, i.e. its path and query, the tip the node was
+/// at, when it signed, and the SHA-256 of the `body`. As text, so tooling in any language can
+/// rebuild it from the response.
+pub fn signed_bytes(target: &str, body: &[u8], height: u32, tip: &H256, signed_at: u128) -> Vec<u8> {
+    let body_hash = digest::digest(&digest::SHA256, body);
+    format!("api-response\n{}\n{}\n{}\n{}\n{}", target, height, tip, signed_at, hex::encode(body_hash)).into_bytes()
+}
+
+/// Signs the API responses carrying consensus data with the node key, so an experiment can keep
+/// evidence of what a node claimed, and at which tip. The JSON bodies are unchanged, the
+/// signature and its context travel in headers.
+pub struct ResponseSigner {
+    key: Ed25519KeyPair,
+}
+
+impl ResponseSigner {
+    pub fn new(key: Ed25519KeyPair) -> Self {
+        ResponseSigner { key }
+    }
+
+    /// The headers signing `body`, served for `target` at the tip `tip` of height `height`
+    pub fn headers(&self, target: &str, body: &[u8], height: u32, tip: &H256, signed_at: u128) -> Vec<(&'static str, String)> {
+        let signature = self.key.sign(&signed_bytes(target, body, height, tip, signed_at));
+        vec![
+            (NODE_KEY_HEADER, hex::encode(self.key.public_key())),
+            (TIP_HEADER, tip.to_string()),
+            (HEIGHT_HEADER, height.to_string()),
+            (SIGNED_AT_HEADER, signed_at.to_string()),
+            (SIGNATURE_HEADER, hex::encode(signature)),
+        ]
+    }
+}
+
+/// Check the `signature` of `public_key` over a response, with the context from its headers
+pub fn verify(public_key: &[u8], signature: &[u8], target: &str, body: &[u8], height: u32, tip: &H256, signed_at: u128) -> bool {
+    signature::UnparsedPublicKey::new(&signature::ED25519, public_key)
+        .verify(&signed_bytes(target, body, height, tip, signed_at), signature)
+        .is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::key_pair;
+
+    #[test]
+    fn signatures_cover_body_and_context() {
+        let signer = ResponseSigner::new(key_pair::random());
+        let tip = H256::from([3; 32]);
+        let body = br#"{"leaves":4}"#;
+        let headers = signer.headers("/blockchain/mmr", body, 3, &tip, 1000);
+        let header = |name: &str| headers.iter().find(|(n, _)| *n == name).unwrap().1.clone();
+        assert_eq!(header(TIP_HEADER), tip.to_string());
+        let key = hex::decode(header(NODE_KEY_HEADER)).unwrap();
+        let signature = hex::decode(header(SIGNATURE_HEADER)).unwrap();
+
+        assert!(verify(&key, &signature, "/blockchain/mmr", body, 3, &tip, 1000));
+        assert!(!verify(&key, &signature, "/blockchain/mmr", br#"{"leaves":5}"#, 3, &tip, 1000));
+        assert!(!verify(&key, &signature, "/blockchain/mmr?at=2", body, 3, &tip, 1000));
+        assert!(!verify(&key, &signature, "/blockchain/mmr", body, 4, &tip, 1000));
+        assert!(!verify(&key, &signature, "/blockchain/mmr", body, 3, &tip, 1001));
+    }
+}
@@ -0,0 +1,235 @@
+use crate::blockchain::Blockchain;
+use crate::types::block::Block;
+use crate::types::amount::Amount;
+use crate::types::hash::{H256, Hashable};
+use crate::types::transaction::SignedTransaction;
+
+use serde::Serialize;
+use std::io::Read;
+use std::sync::{Arc, Mutex};
+
+/// Which records of the chain an export stream carries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportKind {
+    Blocks,
+    Transactions,
+}
+
+/// Encoding of the records of an export stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// one JSON object per line
+    Ndjson,
+    /// each record as bincode, the encoding of the p2p messages, prefixed by its length as a big-endian u32
+    Binary,
+}
+
+impl std::str::FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ndjson" => Ok(ExportFormat::Ndjson),
+            "binary" => Ok(ExportFormat::Binary),
+            _ => Err(format!("unknown export format {}, expected ndjson or binary", s)),
+        }
+    }
+}
+
+impl ExportFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Ndjson => "application/x-ndjson",
+            ExportFormat::Binary => "application/octet-stream",
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct TransactionEntry {
+    height: u32,
+    block: String,
+    hash: String,
+    receiver: String,
+    value: Amount,
+    account_nonce: usize,
+    signer_public_key: String,
+    signature: String,
+}
+
+#[derive(Serialize)]
+struct BlockEntry {
+    height: u32,
+    hash: String,
+    parent: String,
+    nonce: usize,
+    difficulty: String,
+    timestamp: u128,
+    merkle_root: String,
+    transactions: Vec<String>,
+}
+
+fn transaction_entry(height: u32, block: &H256, tx: &SignedTransaction) -> TransactionEntry {
+    TransactionEntry {
+        height,
+        block: block.to_string(),
+        hash: tx.hash().to_string(),
+        receiver: tx.t.receiver.to_string(),
+        value: tx.t.value,
+        account_nonce: tx.t.account_nonce,
+        signer_public_key: hex::encode(&tx.signer_public_key),
+        signature: hex::encode(&tx.signature_vector),
+    }
+}
+
+fn block_entry(height: u32, block: &Block) -> BlockEntry {
+    BlockEntry {
+        height,
+        hash: block.hash().to_string(),
+        parent: block.header.parent.to_string(),
+        nonce: block.header.nonce,
+        difficulty: block.header.difficulty.to_string(),
+        timestamp: block.header.timestamp,
+        merkle_root: block.header.merkle_root.to_string(),
+        transactions: block.content.transactions.iter().map(|tx| tx.hash().to_string()).collect(),
+    }
+}
+
+/// Reader over the blocks or transactions of a range of the longest chain. Only the block hashes
+/// of the range are taken up front; each block is looked up and encoded when the reader gets to
+/// it, so a whole chain can be sent without holding its encoding in memory.
+pub struct ChainExport {
+    blockchain: Arc<Mutex<Blockchain>>,
+    kind: ExportKind,
+    format: ExportFormat,
+    // (height, hash) of the blocks still to be encoded
+    blocks: std::vec::IntoIter<(u32, H256)>,
+    buffer: Vec<u8>,
+    position: usize,
+}
+
+impl ChainExport {
+    /// Export the longest chain between heights `from` and `to` (inclusive)
+    pub fn new(blockchain: &Arc<Mutex<Blockchain>>, from: u32, to: u32, kind: ExportKind, format: ExportFormat) -> Self {
+        let blocks: Vec<(u32, H256)> = {blockchain.lock().unwrap().all_blocks_in_longest_chain()}
+            .into_iter()
+            .enumerate()
+            .map(|(height, hash)| (height as u32, hash))
+            .filter(|(height, _)| *height >= from && *height <= to)
+            .collect();
+        ChainExport {
+            blockchain: Arc::clone(blockchain),
+            kind,
+            format,
+            blocks: blocks.into_iter(),
+            buffer: Vec::new(),
+            position: 0,
+        }
+    }
+
+    fn push_record<T: Serialize>(&mut self, record: &T) {
+        match self.format {
+            ExportFormat::Ndjson => {
+                serde_json::to_writer(&mut self.buffer, record).unwrap();
+                self.buffer.push(b'\n');
+            }
+            ExportFormat::Binary => {
+                let bytes = bincode::serialize(record).unwrap();
+                self.buffer.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+                self.buffer.extend_from_slice(&bytes);
+            }
+        }
+    }
+
+    // encode the records of the next block into the buffer, false once the range is exhausted
+    fn fill(&mut self) -> bool {
+        // blocks whose body was pruned are left out
+        let (height, hash, block) = loop {
+            let (height, hash) = match self.blocks.next() {
+                Some(next) => next,
+                None => return false,
+            };
+            if let Some(block) = {self.blockchain.lock().unwrap().block(&hash)} {
+                break (height, hash, block);
+            }
+        };
+        self.buffer.clear();
+        self.position = 0;
+        match (self.kind, self.format) {
+            (ExportKind::Blocks, ExportFormat::Ndjson) => self.push_record(&block_entry(height, &block)),
+            (ExportKind::Blocks, ExportFormat::Binary) => self.push_record(&block),
+            (ExportKind::Transactions, ExportFormat::Ndjson) => {
+                for tx in block.content.transactions.iter() {
+                    self.push_record(&transaction_entry(height, &hash, tx));
+                }
+            }
+            (ExportKind::Transactions, ExportFormat::Binary) => {
+                for tx in block.content.transactions.iter() {
+                    self.push_record(tx);
+                }
+            }
+        }
+        true
+    }
+}
+
+impl Read for ChainExport {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        // a block without transactions encodes to nothing in a transaction export, keep going
+        while self.position == self.buffer.len() {
+            if !self.fill() {
+                return Ok(0);
+            }
+        }
+        let n = buf.len().min(self.buffer.len() - self.position);
+        buf[..n].copy_from_slice(&self.buffer[self.position..self.position + n]);
+        self.position += n;
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::block::TestBlockBuilder;
+
+    fn test_chain(length: u32) -> Arc<Mutex<Blockchain>> {
+        let mut blockchain = Blockchain::new(0);
+        for i in 1..=length {
+            let block = TestBlockBuilder::new(&blockchain.tip()).length(i).build();
+            blockchain.insert(&block);
+        }
+        Arc::new(Mutex::new(blockchain))
+    }
+
+    #[test]
+    fn ndjson_blocks_in_range() {
+        let blockchain = test_chain(4);
+        let mut export = ChainExport::new(&blockchain, 1, 3, ExportKind::Blocks, ExportFormat::Ndjson);
+        let mut ndjson = String::new();
+        export.read_to_string(&mut ndjson).unwrap();
+        let heights: Vec<u64> = ndjson
+            .lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap()["height"].as_u64().unwrap())
+            .collect();
+        assert_eq!(heights, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn binary_blocks_are_length_prefixed() {
+        let blockchain = test_chain(2);
+        let mut export = ChainExport::new(&blockchain, 0, u32::MAX, ExportKind::Blocks, ExportFormat::Binary);
+        let mut bytes = Vec::new();
+        export.read_to_end(&mut bytes).unwrap();
+        let expected = {blockchain.lock().unwrap().all_blocks_in_longest_chain()};
+        let mut hashes = Vec::new();
+        let mut rest = bytes.as_slice();
+        while !rest.is_empty() {
+            let length = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+            let block: Block = bincode::deserialize(&rest[4..4 + length]).unwrap();
+            hashes.push(block.hash());
+            rest = &rest[4 + length..];
+        }
+        assert_eq!(hashes, expected);
+    }
+}
@@ -0,0 +1,81 @@
+use crate::events::{EventBus, EventFilter};
+
+use log::{debug, info, warn};
+use std::io;
+use std::net::TcpListener;
+use std::thread;
+use tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tungstenite::http::StatusCode;
+use tungstenite::Message;
+
+// events waiting to be written to a client, a client falling further behind is disconnected
+const CLIENT_QUEUE_CAPACITY: usize = 1000;
+
+/// WebSocket server pushing node events to its clients as one JSON text message per event. A
+/// client only gets the events matching the filter in the query of the URL it connects to, e.g.
+/// `ws://127.0.0.1:8000/?events=transaction&address=<hex>`, see `EventFilter::parse`.
+pub struct Server {
+    listener: TcpListener,
+    events: EventBus,
+}
+
+impl Server {
+    /// Listen at `addr`, failing if it cannot be bound
+    pub fn start(addr: std::net::SocketAddr, events: &EventBus) -> io::Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        let server = Self {
+            listener,
+            events: events.clone(),
+        };
+        thread::spawn(move || {
+            for stream in server.listener.incoming() {
+                let stream = match stream {
+                    Ok(s) => s,
+                    Err(e) => {
+                        warn!("Error accepting WebSocket client: {}", e);
+                        continue;
+                    }
+                };
+                // subscribe before the handshake so no event is missed in between
+                let subscription = server.events.subscribe_bounded(CLIENT_QUEUE_CAPACITY);
+                thread::spawn(move || {
+                    let mut filter = EventFilter::default();
+                    // the error response type is set by tungstenite
+                    #[allow(clippy::result_large_err)]
+                    let read_filter = |request: &Request, response: Response| -> Result<Response, ErrorResponse> {
+                        match EventFilter::parse(request.uri().query().unwrap_or("")) {
+                            Ok(parsed) => {
+                                filter = parsed;
+                                Ok(response)
+                            }
+                            Err(e) => {
+                                let mut error = ErrorResponse::new(Some(e));
+                                *error.status_mut() = StatusCode::BAD_REQUEST;
+                                Err(error)
+                            }
+                        }
+                    };
+                    let mut socket = match tungstenite::accept_hdr(stream, read_filter) {
+                        Ok(s) => s,
+                        Err(e) => {
+                            warn!("WebSocket handshake failed: {}", e);
+                            return;
+                        }
+                    };
+                    for event in subscription.iter().filter(|event| filter.matches(event)) {
+                        let text = serde_json::to_string(&event).unwrap();
+                        if let Err(e) = socket.write_message(Message::Text(text)) {
+                            debug!("WebSocket client went away: {}", e);
+                            return;
+                        }
+                    }
+                    // the bus dropped the subscription as the client fell behind
+                    warn!("WebSocket client fell behind, disconnecting it");
+                    let _ = socket.close(None);
+                });
+            }
+        });
+        info!("WebSocket event server listening at {}", &addr);
+        Ok(())
+    }
+}
@@ -0,0 +1,77 @@
+use super::State;
+use crate::types::hash::H256;
+use crate::types::state_proof::state_root;
+
+/// A block of the longest chain the operator trusts, with the root of the state after it. Blocks
+/// up to its height are synced without verifying the signatures of their transactions, and the
+/// block at its height must be this one and lead to this state.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Checkpoint {
+    pub height: u32,
+    pub hash: H256,
+    pub state_root: H256,
+}
+
+impl Checkpoint {
+    /// The checkpoint of the block `hash` at `height`, followed by `state`
+    pub fn new(height: u32, hash: H256, state: &State) -> Self {
+        Checkpoint { height, hash, state_root: state_root(&state.state) }
+    }
+
+    /// Whether the signatures of a block at `height` go unverified
+    pub fn covers(&self, height: u32) -> bool {
+        height <= self.height
+    }
+
+    /// Whether the block `hash` at `height`, followed by `state`, agrees with the checkpoint;
+    /// blocks at other heights always do
+    pub fn admits(&self, height: u32, hash: &H256, state: &State) -> bool {
+        height != self.height || (*hash == self.hash && state_root(&state.state) == self.state_root)
+    }
+}
+
+impl std::str::FromStr for Checkpoint {
+    type Err = String;
+
+    /// Parse a checkpoint written as HEIGHT:HASH:STATE_ROOT
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split(':').collect();
+        if parts.len() != 3 {
+            return Err(format!("expected HEIGHT:HASH:STATE_ROOT, got {}", s));
+        }
+        let height = parts[0].parse::<u32>().map_err(|e| format!("bad height: {}", e))?;
+        let hash = parts[1].parse::<H256>().map_err(|e| format!("bad hash: {}", e))?;
+        let state_root = parts[2].parse::<H256>().map_err(|e| format!("bad state root: {}", e))?;
+        Ok(Checkpoint { height, hash, state_root })
+    }
+}
+
+impl std::fmt::Display for Checkpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}:{}:{}", self.height, self.hash, self.state_root)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::address::Address;
+    use crate::types::amount::Amount;
+
+    #[test]
+    fn block_at_height_must_match() {
+        let mut state = State::default();
+        state.state.insert(Address::from([1; 20]), (1, Amount::from_sub_units(10)));
+        let hash = H256::from([7; 32]);
+        let checkpoint = Checkpoint::new(5, hash, &state);
+        assert_eq!(checkpoint.to_string().parse::<Checkpoint>(), Ok(checkpoint));
+        assert!(checkpoint.covers(5) && !checkpoint.covers(6));
+        assert!(checkpoint.admits(5, &hash, &state));
+        // another block, or the same one leading to another state
+        assert!(!checkpoint.admits(5, &H256::from([8; 32]), &state));
+        let other = State::default();
+        assert!(!checkpoint.admits(5, &hash, &other));
+        assert!(checkpoint.admits(4, &H256::from([8; 32]), &other));
+        assert!("5:00".parse::<Checkpoint>().is_err());
+    }
+}
@@ -0,0 +1,142 @@
+use super::params::ChainParams;
+use super::store::BlockStore;
+use super::Blockchain;
+use crate::types::block::Block;
+use crate::types::hash::Hashable;
+
+use serde::Serialize;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// What compacting a block store kept of it.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct CompactionReport {
+    pub blocks_before: usize,
+    pub blocks_after: usize,
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+    /// tip of the longest chain, the same before and after
+    pub tip: String,
+    pub height: u32,
+}
+
+// where the compacted copy of the file at `path` is written before it replaces it
+fn compacted_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".compact");
+    PathBuf::from(name)
+}
+
+// the blockchain a node started on `blocks` restores
+fn replay(params: &ChainParams, blocks: &[Block]) -> Blockchain {
+    let mut blockchain = Blockchain::with_params(0, params);
+    for block in blocks {
+        blockchain.insert(block);
+    }
+    blockchain
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Rewrite the block store at `headers_path` and `bodies_path` of a node started with `params`,
+/// keeping only the blocks of the longest chain and of the side chains whose tip is less than
+/// `fork_depth` blocks below its tip, in their original order. The kept blocks are written to
+/// new files next to the old ones, which they replace only after reading back exactly as written
+/// and restoring the same tip. The node must be stopped, as it appends to the files.
+pub fn compact<P: AsRef<Path>>(headers_path: P, bodies_path: P, params: &ChainParams, fork_depth: u32) -> io::Result<CompactionReport> {
+    let (headers_path, bodies_path) = (headers_path.as_ref(), bodies_path.as_ref());
+    let (store, blocks) = BlockStore::open(headers_path, bodies_path)?;
+    let bytes_before = store.disk_size()?;
+    drop(store);
+    let blockchain = replay(params, &blocks);
+    let live = blockchain.live_blocks(fork_depth);
+    let kept: Vec<Block> = blocks.iter().filter(|block| live.contains(&block.hash())).cloned().collect();
+
+    let (new_headers, new_bodies) = (compacted_path(headers_path), compacted_path(bodies_path));
+    for path in [&new_headers, &new_bodies] {
+        // left over from a compaction that failed
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+    }
+    {
+        let (mut store, _) = BlockStore::open(&new_headers, &new_bodies)?;
+        for block in kept.iter() {
+            store.append(block)?;
+        }
+        store.sync()?;
+    }
+
+    let (store, read_back) = BlockStore::open(&new_headers, &new_bodies)?;
+    let bytes_after = store.disk_size()?;
+    drop(store);
+    let intact = read_back.len() == kept.len()
+        && read_back.iter().zip(kept.iter()).all(|(read, written)| bincode::serialize(read).unwrap() == bincode::serialize(written).unwrap());
+    if !intact {
+        return Err(invalid(format!("{} does not read back as the {} blocks written to it", new_headers.display(), kept.len())));
+    }
+    let compacted = replay(params, &read_back);
+    if compacted.tip() != blockchain.tip() {
+        return Err(invalid(format!("the compacted store restores tip {} instead of {}", compacted.tip(), blockchain.tip())));
+    }
+
+    // headers first: a crash in between leaves the compacted headers with every old body, which
+    // still opens as the compacted store
+    fs::rename(&new_headers, headers_path)?;
+    fs::rename(&new_bodies, bodies_path)?;
+    Ok(CompactionReport {
+        blocks_before: blocks.len(),
+        blocks_after: kept.len(),
+        bytes_before,
+        bytes_after,
+        tip: blockchain.tip().to_string(),
+        height: blockchain.height(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::block::TestBlockBuilder;
+    use crate::types::hash::H256;
+
+    #[test]
+    fn stale_forks_are_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        let headers = dir.path().join("compact.headers");
+        let bodies = dir.path().join("compact.bodies");
+        let params = ChainParams::default();
+        let genesis = Blockchain::with_params(0, &params).genesis();
+        let mut main = vec![genesis];
+        let mut blocks = Vec::new();
+        for height in 1..=5 {
+            let block = TestBlockBuilder::new(main.last().unwrap()).length(height).build();
+            main.push(block.hash());
+            blocks.push(block);
+        }
+        // a fork that ended 3 blocks below the tip, and one a block below it
+        let stale = TestBlockBuilder::new(&main[1]).length(2).build();
+        let live = TestBlockBuilder::new(&main[3]).length(4).build();
+        let live_child = TestBlockBuilder::new(&live.hash()).length(5).build();
+        blocks.extend(vec![stale.clone(), live.clone(), live_child.clone()]);
+        {
+            let (mut store, _) = BlockStore::open(&headers, &bodies).unwrap();
+            for block in blocks.iter() {
+                store.append(block).unwrap();
+            }
+        }
+
+        let report = compact(&headers, &bodies, &params, 2).unwrap();
+        assert_eq!((report.blocks_before, report.blocks_after), (8, 7));
+        assert!(report.bytes_after < report.bytes_before);
+        assert_eq!((report.tip, report.height), (main[5].to_string(), 5));
+        let (store, restored) = BlockStore::open(&headers, &bodies).unwrap();
+        let restored: Vec<H256> = restored.iter().map(|block| block.hash()).collect();
+        assert_eq!(restored, [&main[1..], &[live.hash(), live_child.hash()]].concat());
+        assert_eq!(store.disk_size().unwrap(), report.bytes_after);
+        assert!(!compacted_path(&headers).exists());
+    }
+}
@@ -0,0 +1,99 @@
+use log::info;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+/// Bytes of the envelope opening a file
+pub const ENVELOPE_SIZE: u64 = 8;
+
+/// The opening of a file this node persists: four bytes naming what it holds, then the version
+/// of its format as a big-endian u32. It lets a node tell the files of an older build, which it
+/// migrates, from those of a newer one, which it refuses rather than misread and truncate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Envelope {
+    pub magic: [u8; 4],
+    pub version: u32,
+}
+
+impl Envelope {
+    pub fn to_bytes(self) -> [u8; ENVELOPE_SIZE as usize] {
+        let mut bytes = [0; ENVELOPE_SIZE as usize];
+        bytes[..4].copy_from_slice(&self.magic);
+        bytes[4..].copy_from_slice(&self.version.to_be_bytes());
+        bytes
+    }
+
+    /// The envelope `bytes` start with, None if they do not start with `magic`
+    pub fn parse(bytes: &[u8], magic: [u8; 4]) -> Option<Envelope> {
+        if bytes.len() < ENVELOPE_SIZE as usize || bytes[..4] != magic {
+            return None;
+        }
+        let mut version = [0; 4];
+        version.copy_from_slice(&bytes[4..ENVELOPE_SIZE as usize]);
+        Some(Envelope { magic, version: u32::from_be_bytes(version) })
+    }
+}
+
+// where the migrated copy of the file at `path` is written before it replaces it
+fn migrated_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".migrate");
+    PathBuf::from(name)
+}
+
+/// Open the file at `path` holding `magic` in format `version` for reading and writing,
+/// positioned after its envelope, and create it if needed. Files written before envelopes are
+/// version 0. A file of an older version is migrated first: `migrate` is called with each
+/// version from the file's up to `version` and what follows the envelope in that version, and
+/// returns it in the next one. The migrated file is written next to the old one, which it
+/// replaces only once complete. A file of a newer version is left as it is.
+pub fn open<P, F>(path: P, magic: [u8; 4], version: u32, migrate: F) -> io::Result<File>
+where
+    P: AsRef<Path>,
+    F: Fn(u32, Vec<u8>) -> io::Result<Vec<u8>>,
+{
+    let path = path.as_ref();
+    let mut file = OpenOptions::new().create(true).truncate(false).read(true).write(true).open(path)?;
+    let mut opening = Vec::new();
+    (&mut file).take(ENVELOPE_SIZE).read_to_end(&mut opening)?;
+    // too short for a whole record of any version, nothing to keep
+    if opening.len() < ENVELOPE_SIZE as usize {
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&Envelope { magic, version }.to_bytes())?;
+        file.flush()?;
+        return Ok(file);
+    }
+    let found = Envelope::parse(&opening, magic).map_or(0, |envelope| envelope.version);
+    if found == version {
+        return Ok(file);
+    }
+    if found > version {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} is in format version {}, newer than version {} of this build, run a newer build", path.display(), found, version),
+        ));
+    }
+
+    let mut content = Vec::new();
+    file.seek(SeekFrom::Start(0))?;
+    file.read_to_end(&mut content)?;
+    if found > 0 {
+        content.drain(..ENVELOPE_SIZE as usize);
+    }
+    for from in found..version {
+        content = migrate(from, content)?;
+    }
+    let migrated = migrated_path(path);
+    {
+        let mut copy = File::create(&migrated)?;
+        copy.write_all(&Envelope { magic, version }.to_bytes())?;
+        copy.write_all(&content)?;
+        copy.sync_all()?;
+    }
+    fs::rename(&migrated, path)?;
+    info!("Migrated {} from format version {} to {}", path.display(), found, version);
+    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+    file.seek(SeekFrom::Start(ENVELOPE_SIZE))?;
+    Ok(file)
+}
@@ -0,0 +1,153 @@
+use super::{ApplyError, Blockchain, Mempool};
+use crate::types::hash::{H256, Hashable};
+use crate::types::state_proof::state_root;
+
+use log::error;
+use std::collections::HashMap;
+use std::fmt;
+
+/// An invariant of the blockchain or the mempool that does not hold.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Violation {
+    /// the height index of the longest chain does not end at the tip
+    TipNotIndexed { tip: H256, height: u32, indexed_tip: Option<H256>, indexed_height: Option<u32> },
+    /// the state stored for the tip differs from the one replaying the longest chain gives
+    StateRoot { tip: H256, stored: H256, recomputed: H256 },
+    /// a block of the longest chain has a transaction the state before it cannot take
+    BadBlock { block: H256, error: ApplyError },
+    /// a transaction of a block of the longest chain is still in the mempool
+    ConfirmedInMempool { transaction: H256, block: H256 },
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Violation::TipNotIndexed { tip, height, indexed_tip, indexed_height } => write!(
+                f,
+                "tip {} at height {} but the height index ends at {:?} at height {:?}",
+                tip, height, indexed_tip, indexed_height
+            ),
+            Violation::StateRoot { tip, stored, recomputed } => write!(
+                f,
+                "state of tip {} has root {} but replaying the longest chain gives {}",
+                tip, stored, recomputed
+            ),
+            Violation::BadBlock { block, error } => write!(f, "block {} of the longest chain cannot be applied: {}", block, error),
+            Violation::ConfirmedInMempool { transaction, block } => {
+                write!(f, "transaction {} confirmed in block {} is still in the mempool", transaction, block)
+            }
+        }
+    }
+}
+
+/// Check the invariants of `blockchain`, and those between it and `mempool` if given. Replays
+/// the whole longest chain, so it costs as much as a restart; the state is not checked if a
+/// body on the longest chain was pruned without a store to read it back from.
+pub fn check(blockchain: &Blockchain, mempool: Option<&Mempool>) -> Vec<Violation> {
+    let mut violations = Vec::new();
+    let (tip, height) = (blockchain.tip(), blockchain.height());
+    let indexed_height = blockchain.header_mmr.len().checked_sub(1);
+    let indexed_tip = indexed_height.and_then(|height| blockchain.header_mmr.leaf(height));
+    if indexed_height != Some(height as usize) || indexed_tip != Some(tip) {
+        violations.push(Violation::TipNotIndexed { tip, height, indexed_tip, indexed_height: indexed_height.map(|height| height as u32) });
+    }
+
+    let mut state = blockchain.state_map[&blockchain.genesis()].clone();
+    let mut confirmed = HashMap::new();
+    let mut replayed = true;
+    for hash in blockchain.all_blocks_in_longest_chain().into_iter().skip(1) {
+        let block = match blockchain.block(&hash) {
+            Some(block) => block,
+            None => {
+                replayed = false;
+                break;
+            }
+        };
+        if let Err(error) = state.apply(&block.content.transactions) {
+            violations.push(Violation::BadBlock { block: hash, error });
+            replayed = false;
+            break;
+        }
+        confirmed.extend(block.content.transactions.iter().map(|transaction| (transaction.hash(), hash)));
+    }
+    if replayed {
+        let stored = state_root(&blockchain.state_map[&tip].state);
+        let recomputed = state_root(&state.state);
+        if stored != recomputed {
+            violations.push(Violation::StateRoot { tip, stored, recomputed });
+        }
+    }
+
+    if let Some(mempool) = mempool {
+        for transaction in mempool.hash_map.keys() {
+            if let Some(block) = confirmed.get(transaction) {
+                violations.push(Violation::ConfirmedInMempool { transaction: *transaction, block: *block });
+            }
+        }
+    }
+    violations
+}
+
+/// In paranoid mode, check the invariants right after `change` and abort the process with a
+/// report of every violation, so a bug stops the node close to its cause instead of spreading
+/// through later blocks. Call it holding the lock of the blockchain, and of the mempool if
+/// given, so no other thread is halfway through a change.
+pub fn enforce(blockchain: &Blockchain, mempool: Option<&Mempool>, change: &str) {
+    if !blockchain.is_paranoid() {
+        return;
+    }
+    let violations = check(blockchain, mempool);
+    if violations.is_empty() {
+        return;
+    }
+    error!("Invariants broken after {}, at tip {} at height {}:", change, blockchain.tip(), blockchain.height());
+    for violation in violations.iter() {
+        error!("  {}", violation);
+    }
+    if let Some(mempool) = mempool {
+        error!("  mempool of {} transactions", mempool.hash_map.len());
+    }
+    error!("  chain of {} blocks with {} fork events", blockchain.headers.len(), blockchain.fork_events());
+    std::process::abort();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::address::Address;
+    use crate::types::amount::Amount;
+    use crate::types::block::TestBlockBuilder;
+    use crate::types::transaction::{sign, SignedTransaction, Transaction};
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    #[test]
+    fn violations_are_found() {
+        let mut blockchain = Blockchain::new(0);
+        let payer = Ed25519KeyPair::from_seed_unchecked(&[0; 32]).unwrap();
+        let t = Transaction { receiver: Address::from([1; 20]), value: Amount::from_sub_units(10), account_nonce: 1 };
+        let transaction = SignedTransaction {
+            signature_vector: sign(&t, &payer).as_ref().to_vec(),
+            signer_public_key: payer.public_key().as_ref().to_vec(),
+            t,
+        };
+        let block = TestBlockBuilder::new(&blockchain.tip()).length(1).transactions(vec![transaction.clone()]).build();
+        blockchain.insert(&block);
+        let mut mempool = Mempool::new();
+        assert!(check(&blockchain, Some(&mempool)).is_empty());
+
+        mempool.hash_map.insert(transaction.hash(), transaction.clone());
+        blockchain.state_map.get_mut(&block.hash()).unwrap().state.insert(Address::from([2; 20]), (0, Amount::from_sub_units(1)));
+        let violations = check(&blockchain, Some(&mempool));
+        assert_eq!(violations.len(), 2);
+        assert!(matches!(violations[0], Violation::StateRoot { tip, .. } if tip == block.hash()));
+        assert_eq!(violations[1], Violation::ConfirmedInMempool { transaction: transaction.hash(), block: block.hash() });
+        // a body no state could take, as a corrupt store would give back, stops the replay
+        blockchain.bodies.get_mut(&block.hash()).unwrap().transactions.push(transaction.clone());
+        let error = ApplyError::CannotSend(transaction.hash());
+        assert!(check(&blockchain, None).contains(&Violation::BadBlock { block: block.hash(), error }));
+        blockchain.header_mmr.truncate(1);
+        assert!(matches!(check(&blockchain, None)[0], Violation::TipNotIndexed { indexed_height: Some(0), .. }));
+        // off unless asked for
+        enforce(&blockchain, Some(&mempool), "the test block");
+    }
+}
@@ -1,24 +1,247 @@
+pub mod params;
+pub mod validators;
+pub mod store;
+pub mod envelope;
+pub mod compact;
+pub mod invariants;
+pub mod checkpoint;
+
 use crate::types::address::Address;
-use crate::types::block::{Block,generate_random_block, generate_genesis_block};
+use crate::types::amount::Amount;
+use crate::types::block::{Block, Content, Header, generate_genesis_block};
 use crate::types::hash::{H256, Hashable};
 use crate::types::key_pair;
 use crate::types::transaction::SignedTransaction;
-use std::collections::HashMap;
+use crate::types::state_proof::{self, StateProof};
+use crate::types::chain_proof::{self, ChainProof};
+use crate::types::mmr::{MerkleMountainRange, MmrProof};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
+use params::ChainParams;
+use validators::Validators;
+use checkpoint::Checkpoint;
+use crate::types::pow::PowAlgorithm;
+use store::{BlockStore, StoreStats};
+use serde::Serialize;
+use crate::events::{Event, EventBus};
+use crate::memory;
 use std::sync::Arc;
 use std::thread::current;
 use hex_literal::hex;
+use log::{debug, trace, warn};
 use ring::signature::{Ed25519KeyPair, KeyPair};
 use url::quirks::port;
 
 pub struct Blockchain {
-    pub hash_map: HashMap<H256, Block>,
+    headers: HashMap<H256, Header>, // hot, every header is kept in memory for fork choice and difficulty
+    bodies: HashMap<H256, Content>, // bodies still in memory, the others are read from the store if there is one
+    prune_depth: Option<u32>, // bodies of blocks this far below the tip are dropped from memory
+    pruned: (u64, u64), // number and bincode size of the bodies dropped from memory so far
     tip: H256,
-    pub state_map:HashMap<H256, State> // state per block
+    genesis: H256,
+    pub state_map:HashMap<H256, State>, // state per block
+    fork_events: usize, // number of inserted blocks that did not extend the previous tip
+    params: ChainParams,
+    store: Option<BlockStore>,
+    pinned: Option<H256>, // block forced onto the longest chain, the tip only follows blocks extending it
+    invalidated: HashSet<H256>, // blocks marked invalid by hand, their descendants are invalid too
+    invalid: HashSet<H256>, // the invalidated blocks and all their descendants
+    events: Option<EventBus>, // told about the blocks joining the longest chain and about reorgs
+    header_mmr: MerkleMountainRange, // hashes of the headers of the longest chain, leaf i at height i
+    validators: Validators, // extra consensus rules on blocks and transactions
+    paranoid: bool, // check the invariants after every change of the longest chain
+    checkpoint: Option<Checkpoint>, // trusted block the chain is synced up to without verifying signatures
 }
 
-#[derive(Clone)]
+/// The root of the header MMR is committed to every this many blocks, light clients keep these
+/// commitments and check proofs of older blocks against them.
+pub const MMR_COMMITMENT_INTERVAL: u32 = 100;
+
+/// Difficulty and work of one block of the longest chain.
+#[derive(Debug, Clone)]
+pub struct ChainWork {
+    pub height: u32,
+    pub hash: H256,
+    pub difficulty: H256,
+    pub work: f64,
+    /// work of all blocks from genesis up to and including this one
+    pub cumulative_work: f64,
+}
+
+/// Memory and disk used by the blockchain, for tuning the prune depth on small disks.
+#[derive(Serialize, Debug, Clone)]
+pub struct StorageMetrics {
+    pub headers: usize,
+    pub bodies_in_memory: usize,
+    pub pruned_bodies: u64,
+    /// bytes of the bodies dropped from memory, as bincode
+    pub pruned_bytes: u64,
+    /// None without a store
+    pub disk: Option<DiskMetrics>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct DiskMetrics {
+    pub size_bytes: u64,
+    pub write_amplification: f64,
+    /// bytes of stored bodies off the longest chain, which compacting the append-only store would free
+    pub stale_body_bytes: u64,
+    pub store: StoreStats,
+}
+
+/// Number of blocks back from the parent whose median timestamp a new block must be later than
+pub const MEDIAN_TIME_SPAN: usize = 11;
+
+/// Milliseconds a block timestamp may be ahead of the network time of the node checking it
+pub const MAX_FUTURE_BLOCK_TIME_MS: u128 = 2 * 60 * 60 * 1000;
+
+/// Blocks an empty account is kept for once `ChainParams::empty_accounts_from` is reached,
+/// so a fork off a recent block still finds the accounts it left empty
+pub const FINALITY_WINDOW: u32 = 100;
+
+/// A transaction the state it is applied to cannot take, which makes its block invalid.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApplyError {
+    /// the sender does not exist, cannot afford the transaction, or its nonce does not follow
+    CannotSend(H256),
+    /// the balance of the receiver would overflow
+    Overflow(H256),
+}
+
+impl std::fmt::Display for ApplyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ApplyError::CannotSend(hash) => write!(f, "sender of transaction {} cannot send it", hash),
+            ApplyError::Overflow(hash) => write!(f, "transaction {} overflows the balance of its receiver", hash),
+        }
+    }
+}
+
+#[derive(Clone, Default)]
 pub struct State {
-    pub state: HashMap<Address, (usize, usize)> // mapping from account address to (account nonce, balance)
+    pub state: HashMap<Address, (usize, Amount)>, // mapping from account address to (account nonce, balance)
+    /// length of the block this is the state after
+    pub height: u32,
+    /// height of the first block leaving empty accounts out, from `ChainParams`
+    pub empty_accounts_from: Option<u32>,
+    // accounts without balance that never sent, and the height they were found empty at, pruned a
+    // finality window later. An account that sent keeps its nonce for good, so its transactions
+    // cannot be replayed once it is paid again.
+    emptied: HashMap<Address, u32>,
+}
+
+impl State {
+    /// The genesis state of the network described by `params`, holding `accounts`
+    pub fn genesis(accounts: HashMap<Address, (usize, Amount)>, params: &ChainParams) -> Self {
+        State { state: accounts, empty_accounts_from: params.empty_accounts_from, ..Default::default() }
+    }
+
+    /// Nonce and balance of an account, (0, 0) for one that does not exist
+    pub fn account(&self, address: &Address) -> (usize, Amount) {
+        self.state.get(address).copied().unwrap_or((0, Amount::ZERO))
+    }
+
+    /// Whether `sender` can send a transaction moving `value` with `nonce`. An account that is
+    /// not stored cannot send, while a stored empty one, as blocks before
+    /// `ChainParams::empty_accounts_from` create, can still send nothing with nonce 1.
+    pub fn can_send(&self, sender: &Address, value: Amount, nonce: usize) -> bool {
+        match self.state.get(sender) {
+            Some(&(account_nonce, balance)) => value <= balance && nonce == account_nonce + 1,
+            None => false,
+        }
+    }
+
+    /// Whether the sender of `transaction` already used its nonce, so it is confirmed, or
+    /// conflicts with a confirmed transaction, and can never be mined on top of this state
+    pub fn is_stale(&self, transaction: &SignedTransaction) -> bool {
+        let sender = Address::from_public_key_bytes(&transaction.signer_public_key);
+        transaction.t.account_nonce <= self.account(&sender).0
+    }
+
+    /// Apply the transactions of the block after this state, in order, or tell which one this
+    /// state cannot take, leaving it half applied. From `ChainParams::empty_accounts_from` on, a
+    /// transaction moving nothing to a new address does not create it, and accounts that never sent
+    /// and are left without balance for `FINALITY_WINDOW` blocks are pruned.
+    pub fn apply(&mut self, transactions: &[SignedTransaction]) -> Result<(), ApplyError> {
+        self.start_block();
+        for transaction in transactions {
+            self.apply_transaction(transaction)?;
+        }
+        self.finish_block();
+        Ok(())
+    }
+
+    /// Move on to the block after this state, whose transactions `apply_transaction` applies one
+    /// by one before `finish_block`
+    pub fn start_block(&mut self) {
+        self.height += 1;
+        // the accounts emptied before the activation start waiting at it
+        if self.pruning() && Some(self.height) == self.empty_accounts_from.map(|from| from.max(1)) {
+            let height = self.height;
+            self.emptied = self.state.iter().filter(|(_, (nonce, balance))| *nonce == 0 && balance.is_zero()).map(|(address, _)| (*address, height)).collect();
+        }
+    }
+
+    /// Apply a transaction of the block started by `start_block`, on top of the ones before it.
+    /// The state is left unchanged if it cannot take the transaction.
+    pub fn apply_transaction(&mut self, transaction: &SignedTransaction) -> Result<(), ApplyError> {
+        let receiver = transaction.t.receiver;
+        let sender = Address::from_public_key_bytes(transaction.signer_public_key.as_slice());
+        let tx_amount = transaction.t.value;
+        if !self.can_send(&sender, tx_amount, transaction.t.account_nonce) {
+            return Err(ApplyError::CannotSend(transaction.hash()));
+        }
+        let (sender_nonce, sender_balance) = self.account(&sender);
+        let new_balance = sender_balance.checked_sub(tx_amount).ok_or_else(|| ApplyError::CannotSend(transaction.hash()))?;
+        // what the receiver holds once the sender paid, to find an overflow before changing anything
+        let receiver_balance = if receiver == sender { new_balance } else { self.account(&receiver).1 };
+        let rec_balance = receiver_balance.checked_add(tx_amount).ok_or_else(|| ApplyError::Overflow(transaction.hash()))?;
+
+        // update the state of the sender
+        self.state.insert(sender, (sender_nonce + 1, new_balance));
+        // a sender has a nonce to keep and is never pruned
+        self.emptied.remove(&sender);
+
+        if self.state.contains_key(&receiver) {
+            let rec_nonce = self.state.get(&receiver).unwrap().0;
+            self.state.insert(receiver, (rec_nonce, rec_balance));
+            if !rec_balance.is_zero() {
+                self.emptied.remove(&receiver);
+            }
+            trace!("Receiver {} of transaction {} updated", receiver, transaction.hash());
+        }
+        // create a new entry for the receiver if it does not exist, unless it would be empty
+        // under the empty-account rules: sending nothing to fresh addresses must not grow the state
+        else if !self.pruning() || !tx_amount.is_zero() {
+            self.state.insert(receiver, (0, tx_amount));
+            trace!("Receiver {} of transaction {} created", receiver, transaction.hash());
+        }
+        Ok(())
+    }
+
+    /// Prune the accounts that never sent and were left without balance for `FINALITY_WINDOW`
+    /// blocks at the end of the block started by `start_block`
+    pub fn finish_block(&mut self) {
+        if !self.pruning() {
+            return;
+        }
+        let (height, state) = (self.height, &mut self.state);
+        self.emptied.retain(|address, emptied| {
+            let kept = *emptied + FINALITY_WINDOW > height;
+            if !kept {
+                state.remove(address);
+            }
+            kept
+        });
+    }
+
+    // whether the current block follows the empty-account rules
+    fn pruning(&self) -> bool {
+        match self.empty_accounts_from {
+            Some(from) => self.height >= from,
+            None => false,
+        }
+    }
 }
 
 
@@ -30,69 +253,216 @@ pub struct Mempool {
 impl Blockchain {
     /// Create a new blockchain, only containing the genesis block
     pub fn new(seed: u8) -> Self {
-        let parent: [u8; 32] = [0; 32];
-        let parent_hash = H256::from(parent);
-        let genesis_block: Block = generate_genesis_block(&parent_hash);
+        Self::with_params(seed, &ChainParams::default())
+    }
+
+    /// Create a new blockchain for the network described by `params`, only containing the genesis block
+    pub fn with_params(seed: u8, params: &ChainParams) -> Self {
+        let parent_hash = params.genesis_parent();
+        let genesis_block: Block = generate_genesis_block(&parent_hash, &params.initial_difficulty);
         let genesis_hash = genesis_block.hash();
-        let mut hash_map: HashMap <H256, Block> = HashMap::new();
-        hash_map.insert(genesis_hash, genesis_block);
+        let mut headers = HashMap::new();
+        headers.insert(genesis_hash, genesis_block.header);
+        let mut bodies = HashMap::new();
+        bodies.insert(genesis_hash, genesis_block.content);
 
         // create 3 determinstic key pairs based on the seed fed by port IP
         let first_address = Address::from_public_key_bytes(Ed25519KeyPair::from_seed_unchecked(&[0; 32]).unwrap().public_key().as_ref());
         let second_address = Address::from_public_key_bytes(Ed25519KeyPair::from_seed_unchecked(&[1; 32]).unwrap().public_key().as_ref());
         let third_address = Address::from_public_key_bytes(Ed25519KeyPair::from_seed_unchecked(&[2; 32]).unwrap().public_key().as_ref());
         
-        // initialize a new state that contains 3 address, nonce, and balance
-        let mut state = HashMap::new();
-        state.insert(first_address, (0, 100));
-        state.insert(second_address, (0, 0));
-        state.insert(third_address, (0, 0));
+        // initialize a new state that contains 3 address, nonce, and balance, unless the
+        // parameters allocate their own
+        let state = params.genesis_state().unwrap_or_else(|| {
+            let mut state = HashMap::new();
+            state.insert(first_address, (0, Amount::from_sub_units(100)));
+            state.insert(second_address, (0, Amount::ZERO));
+            state.insert(third_address, (0, Amount::ZERO));
+            state
+        });
 
         // insert the new state into the state map per genesis block
         let mut state_map = HashMap::new();
-        state_map.insert(genesis_hash, State {state});
+        state_map.insert(genesis_hash, State::genesis(state, params));
 
-        Blockchain { hash_map, tip: genesis_hash, state_map }
+        let mut header_mmr = MerkleMountainRange::new();
+        header_mmr.push(genesis_hash);
+
+        Blockchain { headers, bodies, prune_depth: None, pruned: (0, 0), tip: genesis_hash, genesis: genesis_hash, state_map, fork_events: 0, params: params.clone(), store: None, pinned: None, invalidated: HashSet::new(), invalid: HashSet::new(), events: None, header_mmr, validators: Validators::new(), paranoid: false, checkpoint: None }
     }
 
     /// Insert a block into blockchain
     pub fn insert(&mut self, block: &Block) {
+        // the declared length is a consensus rule, a block must sit exactly one above its parent
+        match self.headers.get(&block.get_parent()) {
+            Some(parent) if block.header.length == parent.length + 1 => {}
+            _ => return,
+        }
+        // a block whose transactions its parent state cannot take is invalid
+        let mut state_copy = self.state_map.get(&block.get_parent()).unwrap().clone();
+        if let Err(e) = state_copy.apply(&block.content.transactions) {
+            warn!("Rejecting block {}: {}", block.hash(), e);
+            return;
+        }
+        let old_tip = self.tip;
         let new_block = block.clone(); 
-        println!(" pass the length test?: {}", new_block.header.length > self.hash_map.get(&self.tip()).unwrap().header.length);
-        if new_block.header.length > self.hash_map.get(&self.tip()).unwrap().header.length {
-            println!("is tip same as parent?: {}", new_block.get_parent() == self.tip());
-            
-            self.hash_map.insert(block.hash(), new_block);
+        // blocks of a side chain are kept too, so the chain can switch over once the side chain gets longer
+        let extends_tip = match self.pinned {
+            Some(_) => new_block.get_parent() == self.tip(),
+            None => new_block.header.length > self.height(),
+        };
+        // children of an invalid block are invalid as well and never become the tip
+        let invalid = self.invalid.contains(&new_block.get_parent());
+        if invalid {
+            self.invalid.insert(block.hash());
+        }
+        if extends_tip && !invalid {
+            if new_block.get_parent() != self.tip() {
+                self.fork_events += 1;
+            }
             self.tip = block.hash();
-            let mut state_copy = self.state_map.get(&block.get_parent()).unwrap().clone();
+        }
+        self.headers.insert(block.hash(), new_block.header);
+        self.bodies.insert(block.hash(), new_block.content);
+        self.state_map.insert(block.hash(), state_copy);
+        if let Some(store) = self.store.as_mut() {
+            if let Err(e) = store.append(block) {
+                warn!("Error storing block {}: {}", block.hash(), e);
+            }
+        }
+        self.prune_bodies();
+        self.follow_tip_in_mmr();
+        self.publish_tip_change(old_tip);
+        debug!("Inserted block {} at height {}", block.hash(), block.header.length);
+    }
 
-            for transaction in &block.content.transactions {
-                // update the state of the sender
-                let receiver = transaction.t.receiver;
-                let sender = Address::from_public_key_bytes(transaction.signer_public_key.as_slice());
-                let tx_amount = transaction.t.value;
+    /// Check blocks and transactions against `validators` on top of the built-in rules
+    pub fn set_validators(&mut self, validators: Validators) {
+        self.validators = validators;
+    }
 
-                let new_nonce = state_copy.state.get(&sender).unwrap().0 + 1;
-                let new_balance = state_copy.state.get(&sender).unwrap().1 - tx_amount;
+    /// Get the extra rules blocks and transactions of this network must follow
+    pub fn validators(&self) -> Validators {
+        self.validators.clone()
+    }
 
-                state_copy.state.insert(sender, (new_nonce, new_balance));
+    /// Publish to `events` the blocks and transactions joining the longest chain from now on,
+    /// and the reorgs
+    pub fn set_events(&mut self, events: &EventBus) {
+        self.events = Some(events.clone());
+    }
 
-                if state_copy.state.contains_key(&receiver) {
-                    let rec_nonce = state_copy.state.get(&receiver).unwrap().0;
-                    let rec_balance = state_copy.state.get(&receiver).unwrap().1 + tx_amount;
-                    state_copy.state.insert(receiver, (rec_nonce, rec_balance));
-                    println!("receiver state updated");
-                }
-                // create a new entry for the receiver if it does not exist
-                else {
-                    state_copy.state.insert(receiver, (0, tx_amount));
-                    println!("new receiver state created");
-                }
+    // drop the headers no longer on the longest chain from the MMR and append the new ones
+    fn follow_tip_in_mmr(&mut self) {
+        let mut adopted = Vec::new();
+        let mut hash = self.tip;
+        let keep = loop {
+            let height = self.headers[&hash].length as usize;
+            if self.header_mmr.leaf(height) == Some(hash) {
+                break height + 1;
+            }
+            adopted.push(hash);
+            if height == 0 {
+                break 0;
             }
-            self.state_map.insert(block.hash(), state_copy);
+            hash = self.headers[&hash].parent;
+        };
+        self.header_mmr.truncate(keep);
+        for hash in adopted.into_iter().rev() {
+            self.header_mmr.push(hash);
+        }
+    }
+
+    /// Root of the MMR over the headers of the longest chain
+    pub fn header_mmr_root(&self) -> H256 {
+        self.header_mmr.root()
+    }
+
+    /// Root of the header MMR over the first `leaf_count` blocks of the longest chain
+    pub fn header_mmr_root_at(&self, leaf_count: usize) -> H256 {
+        self.header_mmr.root_at(leaf_count).unwrap()
+    }
+
+    /// Roots of the header MMR committed every `MMR_COMMITMENT_INTERVAL` blocks of the longest
+    /// chain, with the height of the last header each covers
+    pub fn mmr_commitments(&self) -> Vec<(u32, H256)> {
+        (MMR_COMMITMENT_INTERVAL..=self.height())
+            .step_by(MMR_COMMITMENT_INTERVAL as usize)
+            .map(|height| (height, self.header_mmr.root_at(height as usize + 1).unwrap()))
+            .collect()
+    }
+
+    /// Prove that the block `hash` is on the longest chain, against the root of the header MMR
+    /// covering the longest chain up to height `at`, or up to the tip if None. None if the block is
+    /// not on the longest chain or above `at`.
+    pub fn prove_header(&self, hash: &H256, at: Option<u32>) -> Option<MmrProof> {
+        let height = self.headers.get(hash)?.length as usize;
+        if self.header_mmr.leaf(height) != Some(*hash) {
+            return None;
         }
-        println!("block is inserted in the blockchain insert() function");
-        println!("does blockchain contain the parent in the blockchain mod {}", self.hash_map.contains_key(&block.get_parent()));
+        let leaf_count = match at {
+            Some(at) => at as usize + 1,
+            None => self.header_mmr.len(),
+        };
+        self.header_mmr.prove(height, leaf_count)
+    }
+
+    /// Prove the work of the longest chain with the headers drawn from `seed`, see `ChainProof`
+    pub fn chain_proof(&self, seed: &H256, draws: u32) -> ChainProof {
+        chain_proof::prove(&self.header_mmr, |hash| self.headers[hash].clone(), seed, draws)
+    }
+
+    // publish the blocks between the last common ancestor of `old_tip` and the tip, after a reorg
+    fn publish_tip_change(&self, old_tip: H256) {
+        let events = match self.events.as_ref() {
+            Some(events) if old_tip != self.tip => events,
+            _ => return,
+        };
+        let mut adopted = Vec::new();
+        let (mut old, mut new) = (old_tip, self.tip);
+        let mut depth = 0;
+        while old != new {
+            if self.headers[&old].length >= self.headers[&new].length {
+                old = self.headers[&old].parent;
+                depth += 1;
+            } else {
+                adopted.push(new);
+                new = self.headers[&new].parent;
+            }
+        }
+        if depth > 0 {
+            events.publish(Event::Reorg { depth, old_tip: old_tip.to_string(), new_tip: self.tip.to_string() });
+        }
+        for hash in adopted.iter().rev() {
+            let header = &self.headers[hash];
+            // a body pruned without a store is announced without its transactions
+            let transactions = self.block(hash).map(|block| block.content.transactions).unwrap_or_default();
+            events.publish(Event::Block {
+                hash: hash.to_string(),
+                height: header.length,
+                parent: header.parent.to_string(),
+                transactions: transactions.len(),
+            });
+            for tx in transactions.iter() {
+                events.publish(Event::Transaction {
+                    hash: tx.hash().to_string(),
+                    from: Address::from_public_key_bytes(&tx.signer_public_key).to_string(),
+                    to: tx.t.receiver.to_string(),
+                    value: tx.t.value,
+                    block: hash.to_string(),
+                    height: header.length,
+                });
+            }
+        }
+    }
+
+    /// Replay the blocks read from `store`, then keep every block inserted from now on in it
+    pub fn attach_store(&mut self, store: BlockStore, blocks: Vec<Block>) {
+        self.store = None;
+        for block in blocks.iter() {
+            self.insert(block);
+        }
+        self.store = Some(store);
     }
 
     /// Get the last block's hash of the longest chain
@@ -100,16 +470,393 @@ impl Blockchain {
         self.tip
     }
 
+    /// Get the hash of the genesis block, which tells apart the networks a node may connect to
+    pub fn genesis(&self) -> H256 {
+        self.genesis
+    }
+
+    /// Parameters of a new network that starts from the state at our tip: ours, with every
+    /// non-empty account allocated in its genesis
+    pub fn export_params(&self) -> ChainParams {
+        let state = &self.state_map[&self.tip].state;
+        let mut allocation: Vec<_> = state
+            .iter()
+            .filter(|(_, (nonce, balance))| *nonce > 0 || !balance.is_zero())
+            .map(|(address, (nonce, balance))| params::Allocation { address: *address, nonce: *nonce, balance: *balance })
+            .collect();
+        allocation.sort_by_key(|a| a.address);
+        ChainParams { allocation: Some(allocation), ..self.params.clone() }
+    }
+
+    /// Whether the block `hash` is in the blockchain, even if its body was pruned
+    pub fn contains(&self, hash: &H256) -> bool {
+        self.headers.contains_key(hash)
+    }
+
+    /// Get the header of the block `hash`, without touching the disk
+    pub fn header(&self, hash: &H256) -> Option<&Header> {
+        self.headers.get(hash)
+    }
+
+    /// Get the block `hash`, reading its body back from the store if it is no longer in memory.
+    /// None if the block is unknown or its body was pruned.
+    pub fn block(&self, hash: &H256) -> Option<Block> {
+        let header = self.headers.get(hash)?.clone();
+        let content = match self.bodies.get(hash) {
+            Some(content) => content.clone(),
+            None => match self.store.as_ref()?.read_body(hash) {
+                Ok(content) => content?,
+                Err(e) => {
+                    warn!("Error reading the body of block {}: {}", hash, e);
+                    return None;
+                }
+            },
+        };
+        Some(Block { header, content })
+    }
+
+    /// Check the invariants of the blockchain and the mempool after every block that joins or
+    /// leaves the longest chain, aborting on the first violation, see `invariants::enforce`
+    pub fn set_paranoid(&mut self, paranoid: bool) {
+        self.paranoid = paranoid;
+    }
+
+    pub fn is_paranoid(&self) -> bool {
+        self.paranoid
+    }
+
+    /// Sync the blocks up to `checkpoint` without verifying their signatures, the block at its
+    /// height has to match it
+    pub fn set_checkpoint(&mut self, checkpoint: Checkpoint) {
+        self.checkpoint = Some(checkpoint);
+    }
+
+    pub fn checkpoint(&self) -> Option<Checkpoint> {
+        self.checkpoint
+    }
+
+    /// The checkpoint of the block of the longest chain at `height`, for other nodes to sync
+    /// up to it, None above the tip
+    pub fn checkpoint_at(&self, height: u32) -> Option<Checkpoint> {
+        let hash = *self.all_blocks_in_longest_chain().get(height as usize)?;
+        Some(Checkpoint::new(height, hash, &self.state_map[&hash]))
+    }
+
+    /// Keep in memory only the bodies of blocks at most `depth` below the tip. Older bodies are
+    /// read back from the store when asked for, or are gone if there is no store.
+    pub fn set_prune_depth(&mut self, depth: u32) {
+        self.prune_depth = Some(depth);
+        self.prune_bodies();
+    }
+
+    fn prune_bodies(&mut self) {
+        let depth = match self.prune_depth {
+            Some(depth) => depth,
+            None => return,
+        };
+        let keep_from = self.height().saturating_sub(depth);
+        let headers = &self.headers;
+        let pruned = &mut self.pruned;
+        // the genesis block is not in the store, and its body is empty anyway
+        self.bodies.retain(|hash, content| {
+            let keep = headers[hash].length >= keep_from || headers[hash].length == 0;
+            if !keep {
+                pruned.0 += 1;
+                pruned.1 += bincode::serialized_size(content).unwrap();
+            }
+            keep
+        });
+    }
+
+    /// Get what the blockchain keeps in memory and on disk, and what pruning saved
+    pub fn storage_metrics(&self) -> io::Result<StorageMetrics> {
+        let disk = match self.store.as_ref() {
+            Some(store) => {
+                let canonical: HashSet<H256> = self.all_blocks_in_longest_chain().into_iter().collect();
+                let stats = store.stats();
+                Some(DiskMetrics {
+                    size_bytes: store.disk_size()?,
+                    write_amplification: stats.write_amplification(),
+                    stale_body_bytes: self.headers.keys().filter(|hash| !canonical.contains(hash)).map(|hash| store.body_size(hash)).sum(),
+                    store: stats,
+                })
+            }
+            None => None,
+        };
+        Ok(StorageMetrics {
+            headers: self.headers.len(),
+            bodies_in_memory: self.bodies.len(),
+            pruned_bodies: self.pruned.0,
+            pruned_bytes: self.pruned.1,
+            disk,
+        })
+    }
+
+    /// Approximate bytes of memory taken by the headers
+    pub fn header_memory(&self) -> usize {
+        self.headers.len() * memory::map_entry_size::<H256, Header>()
+    }
+
+    /// Approximate bytes of memory taken by the block bodies kept in memory
+    pub fn body_memory(&self) -> usize {
+        self.bodies.values().map(|content| memory::map_entry_size::<H256, Content>() + memory::content_size(content)).sum()
+    }
+
+    /// Approximate bytes of memory taken by the state of every block
+    pub fn state_memory(&self) -> usize {
+        self.state_map
+            .values()
+            .map(|state| memory::map_entry_size::<H256, State>() + state.state.len() * memory::map_entry_size::<Address, (usize, Amount)>())
+            .sum()
+    }
+
+    /// Drop bodies from memory, oldest first, until the remaining ones take at most `max_bytes`,
+    /// returns how many were dropped. As with pruning, dropped bodies are read back from the
+    /// store if there is one.
+    pub fn evict_bodies(&mut self, max_bytes: usize) -> usize {
+        let mut used = self.body_memory();
+        if used <= max_bytes {
+            return 0;
+        }
+        let mut by_age: Vec<(u32, H256)> = self
+            .bodies
+            .keys()
+            .map(|hash| (self.headers[hash].length, *hash))
+            // the genesis block is not in the store
+            .filter(|(height, _)| *height > 0)
+            .collect();
+        by_age.sort();
+        let mut evicted = 0;
+        for (_, hash) in by_age {
+            if used <= max_bytes {
+                break;
+            }
+            let content = self.bodies.remove(&hash).unwrap();
+            used -= memory::map_entry_size::<H256, Content>() + memory::content_size(&content);
+            self.pruned.0 += 1;
+            self.pruned.1 += bincode::serialized_size(&content).unwrap();
+            evicted += 1;
+        }
+        evicted
+    }
+
+    /// Make the branch ending at `hash` the longest chain regardless of its length, until
+    /// `release_canonical` is called. Blocks extending it still advance the tip, blocks of other
+    /// branches are only stored. Returns false if the block is unknown.
+    pub fn force_canonical(&mut self, hash: &H256) -> bool {
+        if !self.headers.contains_key(hash) {
+            return false;
+        }
+        let old_tip = self.tip;
+        if *hash != self.tip {
+            self.fork_events += 1;
+        }
+        self.tip = *hash;
+        self.pinned = Some(*hash);
+        self.follow_tip_in_mmr();
+        self.publish_tip_change(old_tip);
+        true
+    }
+
+    /// Go back to following the longest branch after `force_canonical`
+    pub fn release_canonical(&mut self) {
+        self.pinned = None;
+        self.select_tip();
+    }
+
+    /// Get the block forced onto the longest chain by `force_canonical`, if any
+    pub fn pinned(&self) -> Option<H256> {
+        self.pinned
+    }
+
+    /// Mark `hash` and all its descendants invalid, e.g. a branch mined by a buggy miner, and
+    /// move the tip to the longest valid branch. Returns false if the block is unknown or is the
+    /// genesis block.
+    pub fn invalidate_block(&mut self, hash: &H256) -> bool {
+        match self.headers.get(hash) {
+            Some(header) if header.length > 0 => {}
+            _ => return false,
+        }
+        self.invalidated.insert(*hash);
+        self.mark_invalid();
+        if matches!(self.pinned, Some(pinned) if self.invalid.contains(&pinned)) {
+            self.pinned = None;
+        }
+        self.select_tip();
+        true
+    }
+
+    /// Undo `invalidate_block` on `hash`, its ancestors and its descendants, and move the tip to
+    /// the longest valid branch. Returns false if the block is unknown.
+    pub fn reconsider_block(&mut self, hash: &H256) -> bool {
+        if !self.headers.contains_key(hash) {
+            return false;
+        }
+        let reconsidered: Vec<H256> = self
+            .invalidated
+            .iter()
+            .filter(|marked| self.is_ancestor(marked, hash) || self.is_ancestor(hash, marked))
+            .cloned()
+            .collect();
+        for marked in reconsidered.iter() {
+            self.invalidated.remove(marked);
+        }
+        self.mark_invalid();
+        self.select_tip();
+        true
+    }
+
+    /// Get the blocks marked invalid by `invalidate_block`
+    pub fn invalidated(&self) -> Vec<H256> {
+        self.invalidated.iter().cloned().collect()
+    }
+
+    /// Whether `hash` is invalidated, directly or through one of its ancestors
+    pub fn is_invalid(&self, hash: &H256) -> bool {
+        self.invalid.contains(hash)
+    }
+
+    // whether `ancestor` is `hash` or one of its ancestors
+    fn is_ancestor(&self, ancestor: &H256, hash: &H256) -> bool {
+        let ancestor_length = match self.headers.get(ancestor) {
+            Some(header) => header.length,
+            None => return false,
+        };
+        let mut current = *hash;
+        loop {
+            let header = match self.headers.get(&current) {
+                Some(header) => header,
+                None => return false,
+            };
+            if header.length <= ancestor_length {
+                return current == *ancestor;
+            }
+            current = header.parent;
+        }
+    }
+
+    // recompute the invalid blocks from the invalidated ones
+    fn mark_invalid(&mut self) {
+        let mut children: HashMap<H256, Vec<H256>> = HashMap::new();
+        for (hash, header) in self.headers.iter() {
+            children.entry(header.parent).or_default().push(*hash);
+        }
+        let mut invalid = HashSet::new();
+        let mut queue: VecDeque<H256> =
+            self.invalidated.iter().filter(|hash| self.headers.contains_key(hash)).cloned().collect();
+        while let Some(hash) = queue.pop_front() {
+            if invalid.insert(hash) {
+                queue.extend(children.get(&hash).into_iter().flatten());
+            }
+        }
+        self.invalid = invalid;
+    }
+
+    // move the tip to the longest valid branch, which must extend the forced block if there is one
+    fn select_tip(&mut self) {
+        let current_valid = !self.invalid.contains(&self.tip)
+            && self.pinned.is_none_or(|pinned| self.is_ancestor(&pinned, &self.tip));
+        let best = self
+            .headers
+            .iter()
+            .filter(|(hash, _)| !self.invalid.contains(*hash))
+            .filter(|(hash, _)| self.pinned.is_none_or(|pinned| self.is_ancestor(&pinned, hash)))
+            // the current tip wins ties, so the chain does not switch between equally long branches
+            .max_by_key(|(hash, header)| (header.length, current_valid && **hash == self.tip))
+            .map(|(hash, _)| *hash)
+            .unwrap();
+        if best != self.tip {
+            let old_tip = self.tip;
+            if !self.is_ancestor(&self.tip, &best) {
+                self.fork_events += 1;
+            }
+            self.tip = best;
+            self.follow_tip_in_mmr();
+            self.publish_tip_change(old_tip);
+        }
+    }
+
+    /// Prove the nonce and balance of an account in the state after the given block
+    pub fn state_proof(&self, address: &Address, block_hash: &H256) -> Option<StateProof> {
+        let state = self.state_map.get(block_hash)?;
+        state_proof::prove(&state.state, *block_hash, address)
+    }
+
+    /// Get the difficulty a block extending `parent` must declare. It is the parent's difficulty,
+    /// except every `retarget_interval` blocks where it is adjusted to the last window's block time.
+    pub fn next_difficulty(&self, parent: &H256) -> H256 {
+        let parent_header = self.headers.get(parent).unwrap();
+        let height = parent_header.length + 1;
+        let interval = self.params.retarget_interval;
+        if interval == 0 || height % interval != 0 {
+            return parent_header.difficulty;
+        }
+        // measure the window back from the parent; the genesis timestamp is not a mining time
+        let mut first = parent_header;
+        let mut intervals = 0;
+        while intervals < interval - 1 && first.length > 1 {
+            first = self.headers.get(&first.parent).unwrap();
+            intervals += 1;
+        }
+        if intervals == 0 {
+            return parent_header.difficulty;
+        }
+        let elapsed = parent_header.timestamp.saturating_sub(first.timestamp);
+        self.params.retarget(&parent_header.difficulty, elapsed, intervals)
+    }
+
+    /// Get the median timestamp of `parent` and the blocks before it, up to `MEDIAN_TIME_SPAN`
+    /// of them. A block extending `parent` must be later, so a miner cannot backdate it to
+    /// lower the difficulty of the next retarget.
+    pub fn median_time_past(&self, parent: &H256) -> u128 {
+        let mut timestamps = Vec::with_capacity(MEDIAN_TIME_SPAN);
+        let mut header = self.headers.get(parent);
+        while let Some(current) = header {
+            timestamps.push(current.timestamp);
+            if timestamps.len() == MEDIAN_TIME_SPAN || current.length == 0 {
+                break;
+            }
+            header = self.headers.get(&current.parent);
+        }
+        timestamps.sort_unstable();
+        timestamps.get(timestamps.len() / 2).copied().unwrap_or(0)
+    }
+
+    /// Whether a block extending `parent` may carry `timestamp`, checked at the network time `now`:
+    /// later than the median time past, and at most `MAX_FUTURE_BLOCK_TIME_MS` ahead of `now`
+    pub fn timestamp_is_valid(&self, parent: &H256, timestamp: u128, now: u128) -> bool {
+        timestamp > self.median_time_past(parent) && timestamp <= now.saturating_add(MAX_FUTURE_BLOCK_TIME_MS)
+    }
+
+    /// Get the proof of work hash blocks of this network must meet their difficulty with
+    pub fn pow(&self) -> PowAlgorithm {
+        self.params.pow
+    }
+
+    /// Get the smallest transaction value blocks of this network may contain
+    pub fn min_tx_value(&self) -> Amount {
+        self.params.min_tx_value()
+    }
+
+    /// Get the height of the tip, the genesis block being at height 0
+    pub fn height(&self) -> u32 {
+        self.headers.get(&self.tip).unwrap().length
+    }
+
+    /// Get the number of inserted blocks that switched the tip to a different branch
+    pub fn fork_events(&self) -> usize {
+        self.fork_events
+    }
+
     /// Get all blocks' hashes of the longest chain, ordered from genesis to the tip
     pub fn all_blocks_in_longest_chain(&self) -> Vec<H256> {
         let mut blocks = Vec::new();
         let mut current_hash = self.tip;
-        let mut current_length = self.hash_map.get(&self.tip).unwrap().header.length;
-        print!("{}", current_length);
+        let mut current_length = self.headers.get(&self.tip).unwrap().length;
         while current_length > 0 {
             blocks.push(current_hash);
-            current_hash = self.hash_map.get(&current_hash).unwrap().get_parent(); // update the hash
-            current_length = self.hash_map.get(&current_hash).unwrap().header.length; // update the length
+            current_hash = self.headers.get(&current_hash).unwrap().parent; // update the hash
+            current_length = self.headers.get(&current_hash).unwrap().length; // update the length
         }
         blocks.push(current_hash);
 
@@ -121,6 +868,85 @@ impl Blockchain {
         reversed_blocks
     }
 
+    /// Get the hashes of the blocks worth keeping: the longest chain, and the side chains whose
+    /// tip is less than `fork_depth` blocks below ours, which may still overtake it
+    pub fn live_blocks(&self, fork_depth: u32) -> HashSet<H256> {
+        let mut live: HashSet<H256> = self.all_blocks_in_longest_chain().into_iter().collect();
+        let parents: HashSet<H256> = self.headers.values().map(|header| header.parent).collect();
+        let height = self.height();
+        let fork_tips: Vec<H256> = self
+            .headers
+            .iter()
+            .filter(|(hash, header)| !parents.contains(*hash) && header.length + fork_depth > height)
+            .map(|(hash, _)| *hash)
+            .collect();
+        for mut hash in fork_tips {
+            // down to where the side chain forks off the longest chain
+            while live.insert(hash) {
+                hash = self.headers[&hash].parent;
+            }
+        }
+        live
+    }
+
+    /// Get a block locator of the longest chain: hashes from the tip back to genesis, the first
+    /// ten one block apart and then twice as far apart each, so a peer finds where its chain
+    /// forks from ours in a few dozen hashes however long the chains are
+    pub fn locator(&self) -> Vec<H256> {
+        let mut locator = Vec::new();
+        let mut height = self.height() as usize;
+        let mut step = 1;
+        loop {
+            locator.push(self.header_mmr.leaf(height).unwrap());
+            if height == 0 {
+                break;
+            }
+            if locator.len() >= 10 {
+                step *= 2;
+            }
+            height = height.saturating_sub(step);
+        }
+        locator
+    }
+
+    /// Get up to `max` blocks of the longest chain following the first hash of `locator` that is
+    /// on it, from genesis if none is. Stops early at a block whose body was pruned.
+    pub fn blocks_after(&self, locator: &[H256], max: usize) -> Vec<Block> {
+        let fork_height = locator
+            .iter()
+            .filter_map(|hash| self.headers.get(hash).map(|header| (hash, header.length as usize)))
+            .find(|(hash, height)| self.header_mmr.leaf(*height) == Some(**hash))
+            .map_or(0, |(_, height)| height);
+        (fork_height + 1..self.header_mmr.len())
+            .take(max)
+            .map_while(|height| self.block(&self.header_mmr.leaf(height).unwrap()))
+            .collect()
+    }
+
+    /// Get the difficulty and work of the blocks of the longest chain with height between `from` and `to` (inclusive)
+    pub fn chain_work(&self, from: u32, to: u32) -> Vec<ChainWork> {
+        let mut history = Vec::new();
+        let mut cumulative_work = 0.0;
+        for (height, hash) in self.all_blocks_in_longest_chain().into_iter().enumerate() {
+            let height = height as u32;
+            if height > to {
+                break;
+            }
+            let header = self.headers.get(&hash).unwrap();
+            cumulative_work += header.difficulty.work();
+            if height >= from {
+                history.push(ChainWork {
+                    height,
+                    hash,
+                    difficulty: header.difficulty,
+                    work: header.difficulty.work(),
+                    cumulative_work,
+                });
+            }
+        }
+        history
+    }
+
     /// Get all blocks' hashes of the longest chain, ordered from genesis to the tip
     pub fn all_tx_in_longest_chain(&self) -> Vec<Vec<H256>> {
         let longest_chain = self.all_blocks_in_longest_chain();
@@ -128,9 +954,11 @@ impl Blockchain {
         let mut all_txs = Vec::new();
         for n in 0..longest_chain.len() {
             let mut this_block_tx = Vec::new();
-            let block = self.hash_map.get(&longest_chain[n]).unwrap();
-            for m in 0..block.content.transactions.len() {
-                this_block_tx.push(block.content.transactions[m].hash());
+            // a pruned block is listed without its transactions
+            if let Some(block) = self.block(&longest_chain[n]) {
+                for m in 0..block.content.transactions.len() {
+                    this_block_tx.push(block.content.transactions[m].hash());
+                }
             }
             all_txs.push(this_block_tx);
         }
@@ -154,6 +982,34 @@ impl Mempool {
         }
         transactions
     }
+
+    /// Approximate bytes of memory taken by the transactions
+    pub fn memory_usage(&self) -> usize {
+        self.hash_map.values().map(|tx| memory::map_entry_size::<H256, SignedTransaction>() + memory::transaction_size(tx)).sum()
+    }
+
+    /// Drop transactions until the remaining ones take at most `max_bytes`, returns how many were
+    /// dropped. Transactions with the highest account nonce go first, since they are the last
+    /// to become valid and may depend on the others.
+    pub fn evict(&mut self, max_bytes: usize) -> usize {
+        let mut used = self.memory_usage();
+        if used <= max_bytes {
+            return 0;
+        }
+        let mut by_nonce: Vec<(usize, H256)> = self.hash_map.iter().map(|(hash, tx)| (tx.t.account_nonce, *hash)).collect();
+        by_nonce.sort();
+        let mut evicted = 0;
+        while used > max_bytes {
+            let (_, hash) = match by_nonce.pop() {
+                Some(last) => last,
+                None => break,
+            };
+            let tx = self.hash_map.remove(&hash).unwrap();
+            used -= memory::map_entry_size::<H256, SignedTransaction>() + memory::transaction_size(&tx);
+            evicted += 1;
+        }
+        evicted
+    }
 }
 
 
@@ -176,4 +1032,362 @@ mod tests {
     }
 }
 
-// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
\ No newline at end of file
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
+
+#[cfg(test)]
+mod chain_tests {
+    use super::*;
+    use crate::types::block::TestBlockBuilder;
+    use crate::types::hash::Hashable;
+    use crate::types::transaction::Transaction;
+
+    #[test]
+    fn wrong_length_is_rejected() {
+        let mut blockchain = Blockchain::new(0);
+        let genesis_hash = blockchain.tip();
+        let block = TestBlockBuilder::new(&genesis_hash).length(5).build();
+        blockchain.insert(&block);
+        assert_eq!(blockchain.tip(), genesis_hash);
+        assert!(!blockchain.contains(&block.hash()));
+
+        let block = TestBlockBuilder::new(&genesis_hash).length(1).build();
+        blockchain.insert(&block);
+        assert_eq!(blockchain.tip(), block.hash());
+        assert_eq!(blockchain.height(), 1);
+    }
+
+    #[test]
+    fn longer_side_chain_takes_over() {
+        let mut blockchain = Blockchain::new(0);
+        let genesis_hash = blockchain.tip();
+        let main = TestBlockBuilder::new(&genesis_hash).length(1).build();
+        blockchain.insert(&main);
+        let side = TestBlockBuilder::new(&genesis_hash).length(1).build();
+        blockchain.insert(&side);
+        assert_eq!(blockchain.tip(), main.hash());
+        let side_child = TestBlockBuilder::new(&side.hash()).length(2).build();
+        blockchain.insert(&side_child);
+        assert_eq!(blockchain.tip(), side_child.hash());
+        assert_eq!(blockchain.fork_events(), 1);
+    }
+
+    #[test]
+    fn side_chain_blocks_are_kept_with_their_state() {
+        let mut blockchain = Blockchain::new(0);
+        let genesis_hash = blockchain.tip();
+        let main = TestBlockBuilder::new(&genesis_hash).length(1).build();
+        blockchain.insert(&main);
+        let side = TestBlockBuilder::new(&genesis_hash).length(1).build();
+        blockchain.insert(&side);
+        assert_eq!(blockchain.tip(), main.hash());
+        assert!(blockchain.state_map.contains_key(&side.hash()));
+        assert_eq!(blockchain.fork_events(), 0);
+    }
+
+    fn transfer(key: &Ed25519KeyPair, receiver: Address, value: u64, account_nonce: usize) -> SignedTransaction {
+        let t = Transaction { receiver, value: Amount::from_sub_units(value), account_nonce };
+        let signature_vector = crate::types::transaction::sign(&t, key).as_ref().to_vec();
+        SignedTransaction { t, signature_vector, signer_public_key: key.public_key().as_ref().to_vec() }
+    }
+
+    #[test]
+    fn blocks_the_parent_state_cannot_take_are_rejected() {
+        let mut blockchain = Blockchain::new(0);
+        let genesis = blockchain.tip();
+        let key = Ed25519KeyPair::from_seed_unchecked(&[0; 32]).unwrap();
+        let same_nonce = vec![transfer(&key, Address::from([8; 20]), 10, 1), transfer(&key, Address::from([9; 20]), 10, 1)];
+        let overspend = vec![transfer(&key, Address::from([8; 20]), 60, 1), transfer(&key, Address::from([9; 20]), 60, 2)];
+        for transactions in [same_nonce.clone(), overspend.clone()] {
+            let block = TestBlockBuilder::new(&genesis).length(1).transactions(transactions).build();
+            blockchain.insert(&block);
+            assert!(!blockchain.contains(&block.hash()));
+            assert_eq!(blockchain.tip(), genesis);
+        }
+        let mut state = blockchain.state_map[&genesis].clone();
+        assert_eq!(state.apply(&same_nonce), Err(ApplyError::CannotSend(same_nonce[1].hash())));
+        // a transaction that cannot be applied leaves the state as it was
+        let mut state = blockchain.state_map[&genesis].clone();
+        state.start_block();
+        state.apply_transaction(&overspend[0]).unwrap();
+        let before = state.state.clone();
+        assert_eq!(state.apply_transaction(&overspend[1]), Err(ApplyError::CannotSend(overspend[1].hash())));
+        assert_eq!(state.state, before);
+    }
+
+    #[test]
+    fn empty_accounts_follow_the_activation_height() {
+        let params = ChainParams { empty_accounts_from: Some(2), ..Default::default() };
+        let mut blockchain = Blockchain::with_params(0, &params);
+        let key = Ed25519KeyPair::from_seed_unchecked(&[0; 32]).unwrap();
+        let sender = Address::from_public_key_bytes(key.public_key().as_ref());
+        // before the activation sending nothing creates the receiver, which can send nothing back
+        let block = TestBlockBuilder::new(&blockchain.tip()).length(1).transactions(vec![transfer(&key, Address::from([7; 20]), 0, 1)]).build();
+        blockchain.insert(&block);
+        let state = &blockchain.state_map[&block.hash()];
+        assert_eq!(state.account(&Address::from([7; 20])), (0, Amount::ZERO));
+        assert!(state.can_send(&Address::from([7; 20]), Amount::ZERO, 1));
+        assert!(!state.can_send(&Address::from([9; 20]), Amount::ZERO, 1));
+
+        let transfers = vec![transfer(&key, Address::from([9; 20]), 0, 2), transfer(&key, Address::from([8; 20]), 5, 3)];
+        let block = TestBlockBuilder::new(&block.hash()).length(2).transactions(transfers).build();
+        blockchain.insert(&block);
+        let state = &blockchain.state_map[&block.hash()];
+        assert!(!state.state.contains_key(&Address::from([9; 20])));
+        assert_eq!(state.account(&Address::from([8; 20])), (0, Amount::from_sub_units(5)));
+        assert_eq!(state.account(&sender), (3, Amount::from_sub_units(95)));
+        assert!(state.can_send(&Address::from([8; 20]), Amount::from_sub_units(5), 1));
+
+        // the built-in parameters keep the rules chains were started with
+        let mut old = Blockchain::new(0);
+        let block = TestBlockBuilder::new(&old.tip()).length(1).transactions(vec![transfer(&key, Address::from([9; 20]), 0, 1)]).build();
+        old.insert(&block);
+        assert_eq!(old.state_map[&block.hash()].account(&Address::from([9; 20])), (0, Amount::ZERO));
+        assert!(old.state_map[&block.hash()].state.contains_key(&Address::from([9; 20])));
+    }
+
+    #[test]
+    fn empty_accounts_that_never_sent_are_pruned_after_the_finality_window() {
+        let key = Ed25519KeyPair::from_seed_unchecked(&[0; 32]).unwrap();
+        let other = Ed25519KeyPair::from_seed_unchecked(&[1; 32]).unwrap();
+        let sender = Address::from_public_key_bytes(key.public_key().as_ref());
+        let receiver = Address::from_public_key_bytes(other.public_key().as_ref());
+        let mut accounts = HashMap::new();
+        accounts.insert(sender, (0, Amount::from_sub_units(100)));
+        accounts.insert(Address::from([7; 20]), (0, Amount::ZERO));
+        accounts.insert(Address::from([8; 20]), (0, Amount::ZERO));
+        let params = ChainParams { empty_accounts_from: Some(0), ..Default::default() };
+        let mut state = State::genesis(accounts, &params);
+
+        // the sender empties its account, but its used nonce keeps it
+        let everything = transfer(&key, receiver, 100, 1);
+        state.apply(std::slice::from_ref(&everything)).unwrap();
+        // an empty account paid within the window is kept
+        state.apply(&[transfer(&other, Address::from([8; 20]), 1, 1)]).unwrap();
+        for _ in 2..FINALITY_WINDOW {
+            state.apply(&[]).unwrap();
+        }
+        assert!(state.state.contains_key(&Address::from([7; 20])));
+        state.apply(&[]).unwrap();
+        assert!(!state.state.contains_key(&Address::from([7; 20])));
+        assert_eq!(state.account(&Address::from([8; 20])), (0, Amount::from_sub_units(1)));
+        for _ in 0..FINALITY_WINDOW {
+            state.apply(&[]).unwrap();
+        }
+        assert_eq!(state.account(&sender), (1, Amount::ZERO));
+        assert_eq!(state.state.len(), 3);
+
+        // paid again, the account goes on from its nonce, so its old transfer cannot be replayed
+        state.apply(&[transfer(&other, sender, 40, 2)]).unwrap();
+        assert_eq!(state.account(&sender), (1, Amount::from_sub_units(40)));
+        assert!(!state.can_send(&sender, Amount::from_sub_units(40), everything.t.account_nonce));
+        assert!(state.can_send(&sender, Amount::from_sub_units(40), 2));
+    }
+
+    #[test]
+    fn exported_state_starts_a_new_network() {
+        let mut old = Blockchain::new(0);
+        let key = Ed25519KeyPair::from_seed_unchecked(&[0; 32]).unwrap();
+        let sender = Address::from_public_key_bytes(key.public_key().as_ref());
+        let t = Transaction { receiver: Address::from([8; 20]), value: Amount::from_sub_units(30), account_nonce: 1 };
+        let signature_vector = crate::types::transaction::sign(&t, &key).as_ref().to_vec();
+        let transfer = SignedTransaction { t, signature_vector, signer_public_key: key.public_key().as_ref().to_vec() };
+        let block = TestBlockBuilder::new(&old.tip()).length(1).transactions(vec![transfer]).build();
+        old.insert(&block);
+
+        let params = old.export_params();
+        // the empty built-in accounts are left out
+        assert_eq!(params.allocation.as_ref().unwrap().len(), 2);
+        let new = Blockchain::with_params(0, &params);
+        assert_eq!(new.height(), 0);
+        assert_ne!(new.genesis(), old.genesis());
+        let state = &new.state_map[&new.tip()];
+        assert_eq!(state.account(&sender), (1, Amount::from_sub_units(70)));
+        assert_eq!(state.account(&Address::from([8; 20])), (0, Amount::from_sub_units(30)));
+        // the same allocation is the same network
+        assert_eq!(Blockchain::with_params(0, &params).genesis(), new.genesis());
+        assert_eq!(Blockchain::with_params(0, &ChainParams::default()).genesis(), old.genesis());
+    }
+
+    #[test]
+    fn tip_changes_are_published() {
+        let mut blockchain = Blockchain::new(0);
+        let events = EventBus::new();
+        let subscription = events.subscribe();
+        blockchain.set_events(&events);
+        let genesis = blockchain.tip();
+        let a1 = TestBlockBuilder::new(&genesis).length(1).build();
+        let b1 = TestBlockBuilder::new(&genesis).length(1).build();
+        let b2 = TestBlockBuilder::new(&b1.hash()).length(2).build();
+        blockchain.insert(&a1);
+        blockchain.insert(&b1);
+        blockchain.insert(&b2);
+        let names: Vec<&str> = subscription.try_iter().map(|event| event.name()).collect();
+        // the side branch is announced once it becomes the longest chain
+        assert_eq!(names, vec!["block", "reorg", "block", "block"]);
+    }
+
+    #[test]
+    fn header_mmr_follows_longest_chain() {
+        use crate::types::mmr;
+        let mut blockchain = Blockchain::new(0);
+        let genesis = blockchain.tip();
+        let a1 = TestBlockBuilder::new(&genesis).length(1).build();
+        blockchain.insert(&a1);
+        let root_at_a1 = blockchain.header_mmr_root();
+        let proof = blockchain.prove_header(&a1.hash(), None).unwrap();
+        assert!(mmr::verify(&root_at_a1, &a1.hash(), &proof));
+
+        // after a reorg the replaced block is no longer provable, the adopted ones are
+        let b1 = TestBlockBuilder::new(&genesis).length(1).build();
+        let b2 = TestBlockBuilder::new(&b1.hash()).length(2).build();
+        blockchain.insert(&b1);
+        blockchain.insert(&b2);
+        assert!(blockchain.prove_header(&a1.hash(), None).is_none());
+        let root = blockchain.header_mmr_root();
+        assert_ne!(root, root_at_a1);
+        let proof = blockchain.prove_header(&b1.hash(), None).unwrap();
+        assert!(mmr::verify(&root, &b1.hash(), &proof));
+        // against the root of an older height
+        let proof = blockchain.prove_header(&genesis, Some(1)).unwrap();
+        let mut only_b1 = Blockchain::new(0);
+        only_b1.insert(&b1);
+        assert!(mmr::verify(&only_b1.header_mmr_root(), &genesis, &proof));
+        assert!(blockchain.prove_header(&b2.hash(), Some(1)).is_none());
+    }
+
+    #[test]
+    fn forced_branch_stays_canonical() {
+        let mut blockchain = Blockchain::new(0);
+        let genesis_hash = blockchain.tip();
+        let main = TestBlockBuilder::new(&genesis_hash).length(1).build();
+        let main_child = TestBlockBuilder::new(&main.hash()).length(2).build();
+        let side = TestBlockBuilder::new(&genesis_hash).length(1).build();
+        blockchain.insert(&main);
+        blockchain.insert(&main_child);
+        blockchain.insert(&side);
+        assert!(!blockchain.force_canonical(&H256::from([7; 32])));
+
+        assert!(blockchain.force_canonical(&side.hash()));
+        assert_eq!(blockchain.tip(), side.hash());
+        // the longer branch grows but the forced one is kept
+        let main_grandchild = TestBlockBuilder::new(&main_child.hash()).length(3).build();
+        blockchain.insert(&main_grandchild);
+        assert_eq!(blockchain.tip(), side.hash());
+        let side_child = TestBlockBuilder::new(&side.hash()).length(2).build();
+        blockchain.insert(&side_child);
+        assert_eq!(blockchain.tip(), side_child.hash());
+        assert_eq!(blockchain.pinned(), Some(side.hash()));
+
+        blockchain.release_canonical();
+        assert_eq!(blockchain.tip(), main_grandchild.hash());
+        assert_eq!(blockchain.pinned(), None);
+        assert_eq!(blockchain.fork_events(), 2);
+    }
+
+    #[test]
+    fn invalidated_branch_is_abandoned() {
+        let mut blockchain = Blockchain::new(0);
+        let genesis_hash = blockchain.tip();
+        let bad = TestBlockBuilder::new(&genesis_hash).length(1).build();
+        let bad_child = TestBlockBuilder::new(&bad.hash()).length(2).build();
+        let good = TestBlockBuilder::new(&genesis_hash).length(1).build();
+        blockchain.insert(&bad);
+        blockchain.insert(&bad_child);
+        blockchain.insert(&good);
+        assert!(!blockchain.invalidate_block(&genesis_hash));
+
+        assert!(blockchain.invalidate_block(&bad.hash()));
+        assert_eq!(blockchain.tip(), good.hash());
+        assert!(blockchain.is_invalid(&bad_child.hash()));
+        // the invalid branch no longer takes over, however long it gets
+        let bad_grandchild = TestBlockBuilder::new(&bad_child.hash()).length(3).build();
+        blockchain.insert(&bad_grandchild);
+        assert_eq!(blockchain.tip(), good.hash());
+        assert!(blockchain.is_invalid(&bad_grandchild.hash()));
+
+        // reconsidering a descendant clears the mark on its ancestor
+        assert!(blockchain.reconsider_block(&bad_child.hash()));
+        assert_eq!(blockchain.tip(), bad_grandchild.hash());
+        assert!(blockchain.invalidated().is_empty());
+        assert_eq!(blockchain.fork_events(), 2);
+    }
+
+    #[test]
+    fn invalidating_marks_every_descendant() {
+        let mut blockchain = Blockchain::new(0);
+        let genesis_hash = blockchain.tip();
+        let mut hashes = vec![];
+        for i in 1..=300 {
+            let block = TestBlockBuilder::new(&blockchain.tip()).length(i).build();
+            blockchain.insert(&block);
+            hashes.push(block.hash());
+        }
+        assert!(blockchain.invalidate_block(&hashes[0]));
+        assert_eq!(blockchain.tip(), genesis_hash);
+        assert!(hashes.iter().all(|hash| blockchain.is_invalid(hash)));
+        assert!(!blockchain.is_invalid(&genesis_hash));
+    }
+
+    #[test]
+    fn pruned_bodies_keep_headers() {
+        let mut blockchain = Blockchain::new(0);
+        blockchain.set_prune_depth(1);
+        let mut hashes = vec![blockchain.tip()];
+        for i in 1..=3 {
+            let block = TestBlockBuilder::new(&blockchain.tip()).length(i).build();
+            blockchain.insert(&block);
+            hashes.push(block.hash());
+        }
+        assert!(blockchain.block(&hashes[0]).is_some());
+        assert!(blockchain.block(&hashes[1]).is_none());
+        assert!(blockchain.block(&hashes[2]).is_some());
+        // the header chain is still complete
+        assert!(blockchain.contains(&hashes[1]));
+        assert_eq!(blockchain.all_blocks_in_longest_chain(), hashes);
+        assert_eq!(blockchain.next_difficulty(&hashes[1]), blockchain.header(&hashes[1]).unwrap().difficulty);
+        let metrics = blockchain.storage_metrics().unwrap();
+        assert_eq!((metrics.headers, metrics.bodies_in_memory, metrics.pruned_bodies), (4, 3, 1));
+        assert!(metrics.pruned_bytes > 0 && metrics.disk.is_none());
+    }
+
+    #[test]
+    fn chain_work_of_genesis() {
+        let blockchain = Blockchain::new(0);
+        let history = blockchain.chain_work(0, u32::MAX);
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[0].hash, blockchain.tip());
+        // the genesis difficulty 000eff.. takes 2^256 / 0x000f00..00 hashes on average
+        let expected = 2f64.powi(256) / (15.0 * 2f64.powi(240));
+        assert!((history[0].work - expected).abs() < 1e-6);
+        assert_eq!(history[0].cumulative_work, history[0].work);
+        assert!(blockchain.chain_work(1, u32::MAX).is_empty());
+    }
+
+    #[test]
+    fn locator_finds_the_fork_point() {
+        let mut blockchain = Blockchain::new(0);
+        let mut hashes = vec![blockchain.tip()];
+        for length in 1..=40 {
+            let block = TestBlockBuilder::new(hashes.last().unwrap()).length(length).build();
+            blockchain.insert(&block);
+            hashes.push(block.hash());
+        }
+        let locator = blockchain.locator();
+        // ten steps of one, then 2, 4, 8 and 16 blocks apart, down to genesis
+        let heights: Vec<usize> = vec![40, 39, 38, 37, 36, 35, 34, 33, 32, 31, 29, 25, 17, 1, 0];
+        assert_eq!(locator, heights.iter().map(|height| hashes[*height]).collect::<Vec<_>>());
+
+        // a node that stayed on a side branch off height 20 gets the blocks after it
+        let mut side = Blockchain::new(0);
+        for hash in &hashes[1..=20] {
+            side.insert(&blockchain.block(hash).unwrap());
+        }
+        let fork = TestBlockBuilder::new(&hashes[20]).length(21).build();
+        side.insert(&fork);
+        let blocks = blockchain.blocks_after(&side.locator(), 5);
+        assert_eq!(blocks.iter().map(|block| block.hash()).collect::<Vec<_>>(), hashes[21..26].to_vec());
+        // an unknown locator starts from genesis, one at the tip gets nothing
+        assert_eq!(blockchain.blocks_after(&[H256::default()], 1)[0].hash(), hashes[1]);
+        assert!(blockchain.blocks_after(&locator, 5).is_empty());
+    }
+}
@@ -0,0 +1,208 @@
+use crate::types::address::Address;
+use crate::types::amount::Amount;
+use crate::types::hash::{digest_serialized, H256};
+use crate::types::pow::PowAlgorithm;
+
+use hex_literal::hex;
+use serde::{Serialize, Serializer, Deserialize, Deserializer};
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+/// Parameters of a network every node of it must agree on, written once by `genesis init` and
+/// handed to each node with `--chain-params`, so a private network can pick its own difficulty
+/// without recompiling.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ChainParams {
+    /// difficulty of the genesis block, and of every block until the first retarget
+    #[serde(serialize_with = "to_hex", deserialize_with = "from_hex")]
+    pub initial_difficulty: H256,
+    /// number of blocks between difficulty adjustments, 0 keeps the difficulty fixed
+    pub retarget_interval: u32,
+    /// block interval in milliseconds the difficulty adjustments aim for
+    pub target_block_time: u64,
+    /// proof of work hash, SHA-256 for files written before it could be chosen
+    #[serde(default)]
+    pub pow: PowAlgorithm,
+    /// smallest transaction value nodes relay unless started with their own --dust-threshold,
+    /// 0 for none
+    #[serde(default)]
+    pub dust_threshold: Amount,
+    /// whether a block with a transaction below `dust_threshold` is invalid, rather than the
+    /// threshold being relay policy only
+    #[serde(default)]
+    pub consensus_dust: bool,
+    /// height of the first block whose transactions moving nothing to a new address do not
+    /// create it, and from which empty accounts that never sent are pruned after the finality
+    /// window; None keeps every account, as chains did before
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub empty_accounts_from: Option<u32>,
+    /// accounts of the genesis state, the built-in ones when absent; written by `genesis export`
+    /// to start a new network from the balances of an old one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub allocation: Option<Vec<Allocation>>,
+}
+
+/// An account of the genesis state.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Allocation {
+    #[serde(serialize_with = "to_hex", deserialize_with = "from_hex")]
+    pub address: Address,
+    /// kept from the old network, so its signed transactions cannot be replayed on the new one
+    #[serde(default)]
+    pub nonce: usize,
+    pub balance: Amount,
+}
+
+// keep the difficulty and addresses readable and editable in the parameters file
+fn to_hex<T: std::fmt::Display, S: Serializer>(value: &T, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&value.to_string())
+}
+
+fn from_hex<'de, T, D>(deserializer: D) -> Result<T, D::Error>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+    D: Deserializer<'de>,
+{
+    let hex = String::deserialize(deserializer)?;
+    hex.parse().map_err(serde::de::Error::custom)
+}
+
+impl Default for ChainParams {
+    fn default() -> Self {
+        ChainParams {
+            initial_difficulty: hex!("000effffffffffffffffffffffffffffffffffffffffffffffffffffffffffff").into(),
+            retarget_interval: 0,
+            target_block_time: 10_000,
+            pow: PowAlgorithm::Sha256,
+            dust_threshold: Amount::ZERO,
+            consensus_dust: false,
+            empty_accounts_from: None,
+            allocation: None,
+        }
+    }
+}
+
+impl ChainParams {
+    /// Read the parameters from a file written by `save`
+    pub fn load<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        let json = std::fs::read_to_string(path)?;
+        let params: Self = serde_json::from_str(&json).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        params.check().map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        Ok(params)
+    }
+
+    /// Refuse parameters no node could run a chain with
+    pub fn check(&self) -> Result<(), String> {
+        if self.retarget_interval > 0 && self.target_block_time == 0 {
+            return Err("difficulty adjustments need a target block time above 0".to_string());
+        }
+        if let Some(allocation) = &self.allocation {
+            let mut addresses = HashSet::new();
+            if let Some(twice) = allocation.iter().find(|a| !addresses.insert(a.address)) {
+                return Err(format!("address {} is allocated twice", twice.address));
+            }
+        }
+        self.pow.check()
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
+        std::fs::write(path, serde_json::to_string_pretty(self).unwrap())
+    }
+
+    /// Smallest transaction value a valid block may contain, 0 unless dust is a consensus rule
+    pub fn min_tx_value(&self) -> Amount {
+        if self.consensus_dust {
+            self.dust_threshold
+        } else {
+            Amount::ZERO
+        }
+    }
+
+    /// Parent of the genesis block: zero for the built-in genesis state, otherwise a hash of the
+    /// allocation, so networks started from different balances have different genesis blocks
+    pub fn genesis_parent(&self) -> H256 {
+        match &self.allocation {
+            None => H256::default(),
+            Some(allocation) => {
+                let mut accounts: Vec<_> = allocation.iter().map(|a| (a.address, a.nonce as u64, a.balance)).collect();
+                accounts.sort();
+                digest_serialized(&accounts)
+            }
+        }
+    }
+
+    /// Accounts of the genesis state as (nonce, balance), None for the built-in ones
+    pub fn genesis_state(&self) -> Option<HashMap<Address, (usize, Amount)>> {
+        self.allocation.as_ref().map(|allocation| allocation.iter().map(|a| (a.address, (a.nonce, a.balance))).collect())
+    }
+
+    /// Difficulty after a retarget, given the difficulty of the last window and how long the
+    /// window took to mine `intervals` blocks. A single adjustment is capped at a factor of 4.
+    pub fn retarget(&self, difficulty: &H256, elapsed: u128, intervals: u32) -> H256 {
+        let expected = self.target_block_time as u128 * intervals as u128;
+        let elapsed = elapsed.clamp(expected / 4, expected * 4) as u64;
+        // a higher difficulty value is an easier target
+        difficulty.scale(elapsed, expected as u64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn save_and_load() {
+        let params = ChainParams {
+            retarget_interval: 10,
+            pow: PowAlgorithm::Argon2id { memory_kib: 64, passes: 1 },
+            dust_threshold: Amount::from_sub_units(5),
+            consensus_dust: true,
+            empty_accounts_from: Some(100),
+            allocation: Some(vec![Allocation { address: Address::from([1; 20]), nonce: 3, balance: Amount::from_sub_units(7) }]),
+            ..Default::default()
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("chain-params.json");
+        params.save(&path).unwrap();
+        assert_eq!(ChainParams::load(&path).unwrap(), params);
+        // files written before the proof of work, dust threshold or empty accounts could be chosen
+        // use SHA-256, allow any value and keep every account
+        let old = r#"{"initial_difficulty":"000effffffffffffffffffffffffffffffffffffffffffffffffffffffffffff","retarget_interval":0,"target_block_time":10000}"#;
+        std::fs::write(&path, old).unwrap();
+        assert_eq!(ChainParams::load(&path).unwrap(), ChainParams::default());
+        // an address allocated twice is refused
+        let twice = ChainParams { allocation: Some(vec![params.allocation.as_ref().unwrap()[0]; 2]), ..Default::default() };
+        twice.save(&path).unwrap();
+        assert!(ChainParams::load(&path).is_err());
+        // so is retargeting towards blocks without any interval
+        let instant = ChainParams { retarget_interval: 10, target_block_time: 0, ..Default::default() };
+        instant.save(&path).unwrap();
+        assert!(ChainParams::load(&path).is_err());
+        assert!(ChainParams { retarget_interval: 0, ..instant }.check().is_ok());
+        // and a proof of work no node could afford, which the command line never produces
+        let huge = ChainParams { pow: PowAlgorithm::Argon2id { memory_kib: u32::MAX, passes: 1 }, ..Default::default() };
+        huge.save(&path).unwrap();
+        assert!(ChainParams::load(&path).is_err());
+    }
+
+    #[test]
+    fn dust_is_only_consensus_when_asked() {
+        let mut params = ChainParams { dust_threshold: Amount::from_sub_units(10), ..Default::default() };
+        assert_eq!(params.min_tx_value(), Amount::ZERO);
+        params.consensus_dust = true;
+        assert_eq!(params.min_tx_value(), Amount::from_sub_units(10));
+    }
+
+    #[test]
+    fn retarget_follows_block_time() {
+        let params = ChainParams { retarget_interval: 10, target_block_time: 1000, ..Default::default() };
+        let difficulty: H256 = hex!("0000ffff00000000000000000000000000000000000000000000000000000000").into();
+        // twice as slow as intended, so the target doubles
+        let easier: H256 = hex!("0001fffe00000000000000000000000000000000000000000000000000000000").into();
+        assert_eq!(params.retarget(&difficulty, 18_000, 9), easier);
+        // way too fast, capped at a quarter
+        let harder: H256 = hex!("00003fffc0000000000000000000000000000000000000000000000000000000").into();
+        assert_eq!(params.retarget(&difficulty, 1, 9), harder);
+    }
+}
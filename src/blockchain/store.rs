@@ -0,0 +1,275 @@
+use super::envelope::{self, ENVELOPE_SIZE};
+use crate::metrics::Histogram;
+use crate::types::block::{Block, Content, Header};
+use crate::types::hash::{H256, Hashable};
+
+use serde::Serialize;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::Instant;
+
+/// Append-only files of the blocks inserted into the blockchain, in insertion order so every
+/// parent comes before its children. Headers and bodies go to separate files: the headers are
+/// all loaded on startup, while a body is only read when it is asked for, so bodies can stay on
+/// disk once the blockchain drops them from memory. Each record is stored as bincode prefixed by
+/// its length as a big-endian u32, a body record being the block hash followed by the content.
+/// Both files open with an envelope naming their format version, see `FORMAT_VERSION`.
+pub struct BlockStore {
+    headers: File,
+    bodies: File,
+    // position and length of the content of each body record
+    index: HashMap<H256, (u64, usize)>,
+    stats: StoreStats,
+    // bodies are read through a shared reference
+    read_latency: RefCell<Histogram>,
+}
+
+/// What the store wrote and read since it was opened.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct StoreStats {
+    pub blocks_appended: u64,
+    /// bytes of the blocks appended, as bincode
+    pub block_bytes: u64,
+    /// bytes written to the files for them, with hashes and length prefixes
+    pub bytes_written: u64,
+    /// bytes of a partly written record cut off when the store was opened
+    pub truncated_bytes: u64,
+    pub write_latency: Histogram,
+    pub read_latency: Histogram,
+}
+
+impl StoreStats {
+    /// Bytes written to disk per byte of block
+    pub fn write_amplification(&self) -> f64 {
+        if self.block_bytes == 0 {
+            return 0.0;
+        }
+        self.bytes_written as f64 / self.block_bytes as f64
+    }
+}
+
+/// Version of the format of the header and body files. Files of an older version are migrated
+/// when the store is opened, by a step from each version to the next in `migrate`; bump this and
+/// add a step whenever the records change.
+pub const FORMAT_VERSION: u32 = 1;
+
+const HEADERS_MAGIC: [u8; 4] = *b"HDRS";
+const BODIES_MAGIC: [u8; 4] = *b"BODS";
+
+// the records following the envelope of a file in format `version`, in format `version + 1`
+fn migrate(version: u32, records: Vec<u8>) -> io::Result<Vec<u8>> {
+    match version {
+        // version 0 had no envelope, the records are unchanged
+        0 => Ok(records),
+        _ => unreachable!("no migration from format version {}", version),
+    }
+}
+
+// position and bytes of each record of a file
+type Records = Vec<(u64, Vec<u8>)>;
+
+// read the length-prefixed records of `file` from its position on and drop a record cut short by
+// an interrupted write, returning the number of bytes dropped
+fn read_records(file: &mut File) -> io::Result<(Records, u64)> {
+    let start = file.stream_position()?;
+    let mut bytes = Vec::new();
+    file.read_to_end(&mut bytes)?;
+    let mut records = Vec::new();
+    let mut valid = 0;
+    while bytes.len() >= valid + 4 {
+        let length = u32::from_be_bytes(bytes[valid..valid + 4].try_into().unwrap()) as usize;
+        match bytes.get(valid + 4..valid + 4 + length) {
+            Some(record) => records.push((start + (valid + 4) as u64, record.to_vec())),
+            None => break,
+        }
+        valid += 4 + length;
+    }
+    file.set_len(start + valid as u64)?;
+    file.seek(SeekFrom::Start(start + valid as u64))?;
+    Ok((records, (bytes.len() - valid) as u64))
+}
+
+// a single write, so a crash leaves at most one partial record at the end; returns the position of the record.
+// Reads move the cursor, so it goes back to the end first.
+fn append_record(file: &mut File, bytes: &[u8]) -> io::Result<u64> {
+    let position = file.seek(SeekFrom::End(0))? + 4;
+    let mut record = (bytes.len() as u32).to_be_bytes().to_vec();
+    record.extend_from_slice(bytes);
+    file.write_all(&record)?;
+    file.flush()?;
+    Ok(position)
+}
+
+impl BlockStore {
+    /// Open the store with its headers at `headers_path` and bodies at `bodies_path`, creating
+    /// the files if needed, and read back the blocks it holds. Blocks whose header or body was cut
+    /// short by an interrupted write are dropped. Files of an older format are migrated, files of
+    /// a newer one are an error and left untouched.
+    pub fn open<P: AsRef<Path>>(headers_path: P, bodies_path: P) -> io::Result<(Self, Vec<Block>)> {
+        let mut headers = envelope::open(headers_path, HEADERS_MAGIC, FORMAT_VERSION, migrate)?;
+        let mut bodies = envelope::open(bodies_path, BODIES_MAGIC, FORMAT_VERSION, migrate)?;
+        let mut index = HashMap::new();
+        let mut contents = HashMap::new();
+        let (records, mut truncated_bytes) = read_records(&mut bodies)?;
+        for (position, record) in records {
+            let (hash, content): (H256, Content) = match bincode::deserialize(&record) {
+                Ok(body) => body,
+                Err(_) => break,
+            };
+            let hash_length = bincode::serialized_size(&hash).unwrap();
+            index.insert(hash, (position + hash_length, record.len() - hash_length as usize));
+            contents.insert(hash, content);
+        }
+        let mut blocks = Vec::new();
+        let mut valid = ENVELOPE_SIZE;
+        let (records, _) = read_records(&mut headers)?;
+        for (position, record) in records {
+            let header: Header = match bincode::deserialize(&record) {
+                Ok(header) => header,
+                Err(_) => break,
+            };
+            // the body is written first, a header without one was not stored completely
+            let content = match contents.remove(&header.hash()) {
+                Some(content) => content,
+                None => break,
+            };
+            blocks.push(Block { header, content });
+            valid = position + record.len() as u64;
+        }
+        truncated_bytes += headers.metadata()?.len() - valid;
+        headers.set_len(valid)?;
+        headers.seek(SeekFrom::Start(valid))?;
+        let stats = StoreStats { truncated_bytes, ..Default::default() };
+        Ok((BlockStore { headers, bodies, index, stats, read_latency: RefCell::new(Histogram::default()) }, blocks))
+    }
+
+    pub fn append(&mut self, block: &Block) -> io::Result<()> {
+        let start = Instant::now();
+        let hash = block.hash();
+        let body = bincode::serialize(&(hash, &block.content)).unwrap();
+        let header = bincode::serialize(&block.header).unwrap();
+        let position = append_record(&mut self.bodies, &body)?;
+        let hash_length = bincode::serialized_size(&hash).unwrap();
+        self.index.insert(hash, (position + hash_length, body.len() - hash_length as usize));
+        append_record(&mut self.headers, &header)?;
+
+        self.stats.write_latency.record(start.elapsed());
+        self.stats.blocks_appended += 1;
+        self.stats.block_bytes += bincode::serialized_size(block).unwrap();
+        self.stats.bytes_written += (body.len() + header.len() + 8) as u64;
+        Ok(())
+    }
+
+    /// Write the appended blocks through to the disk
+    pub fn sync(&self) -> io::Result<()> {
+        self.bodies.sync_all()?;
+        self.headers.sync_all()
+    }
+
+    /// Size in bytes of the header and body files
+    pub fn disk_size(&self) -> io::Result<u64> {
+        Ok(self.headers.metadata()?.len() + self.bodies.metadata()?.len())
+    }
+
+    /// Size in bytes of the stored body of block `hash`, 0 if it is not stored
+    pub fn body_size(&self, hash: &H256) -> u64 {
+        self.index.get(hash).map_or(0, |(_, length)| *length as u64)
+    }
+
+    pub fn stats(&self) -> StoreStats {
+        StoreStats { read_latency: self.read_latency.borrow().clone(), ..self.stats.clone() }
+    }
+
+    /// Read the body of the block `hash` back from disk, None if it was never stored
+    pub fn read_body(&self, hash: &H256) -> io::Result<Option<Content>> {
+        let (position, length) = match self.index.get(hash) {
+            Some(location) => *location,
+            None => return Ok(None),
+        };
+        let start = Instant::now();
+        let mut bytes = vec![0; length];
+        let mut bodies = &self.bodies;
+        bodies.seek(SeekFrom::Start(position))?;
+        bodies.read_exact(&mut bytes)?;
+        self.read_latency.borrow_mut().record(start.elapsed());
+        bincode::deserialize(&bytes)
+            .map(Some)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::block::TestBlockBuilder;
+
+    #[test]
+    fn partial_record_is_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        let headers = dir.path().join("block-store.headers");
+        let bodies = dir.path().join("block-store.bodies");
+        let first = TestBlockBuilder::new(&H256::default()).length(1).build();
+        let second = TestBlockBuilder::new(&first.hash()).length(2).build();
+        {
+            let (mut store, blocks) = BlockStore::open(&headers, &bodies).unwrap();
+            assert!(blocks.is_empty());
+            store.append(&first).unwrap();
+            // a read in between does not move where the next block goes
+            assert!(store.read_body(&first.hash()).unwrap().is_some());
+            store.append(&second).unwrap();
+            assert!(store.read_body(&second.hash()).unwrap().is_some());
+            // the write of a third block was interrupted
+            store.bodies.write_all(&[0, 0, 1, 0, 7]).unwrap();
+        }
+        let (mut store, blocks) = BlockStore::open(&headers, &bodies).unwrap();
+        assert_eq!(store.stats().truncated_bytes, 5);
+        let hashes: Vec<H256> = blocks.iter().map(|b| b.hash()).collect();
+        assert_eq!(hashes, vec![first.hash(), second.hash()]);
+        // appending continues right after the last whole block
+        let third = TestBlockBuilder::new(&second.hash()).length(3).build();
+        store.append(&third).unwrap();
+        let stats = store.stats();
+        assert_eq!(stats.blocks_appended, 1);
+        assert_eq!(stats.write_latency.count, 1);
+        assert!(stats.write_amplification() > 1.0);
+        let (store, blocks) = BlockStore::open(&headers, &bodies).unwrap();
+        assert_eq!(blocks.len(), 3);
+        assert_eq!(store.read_body(&second.hash()).unwrap().unwrap().transactions.len(), second.content.transactions.len());
+        assert!(store.read_body(&H256::from([7; 32])).unwrap().is_none());
+        assert_eq!(store.stats().read_latency.count, 1);
+        assert_eq!(store.disk_size().unwrap(), std::fs::metadata(&headers).unwrap().len() + std::fs::metadata(&bodies).unwrap().len());
+    }
+
+    #[test]
+    fn old_formats_are_migrated_newer_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let headers = dir.path().join("block-store-legacy.headers");
+        let bodies = dir.path().join("block-store-legacy.bodies");
+        let block = TestBlockBuilder::new(&H256::default()).length(2).build();
+        // the files as written before they had an envelope
+        let record = |bytes: Vec<u8>| [(bytes.len() as u32).to_be_bytes().to_vec(), bytes].concat();
+        std::fs::write(&headers, record(bincode::serialize(&block.header).unwrap())).unwrap();
+        std::fs::write(&bodies, record(bincode::serialize(&(block.hash(), &block.content)).unwrap())).unwrap();
+        let legacy_size = std::fs::metadata(&headers).unwrap().len() + std::fs::metadata(&bodies).unwrap().len();
+        {
+            let (store, blocks) = BlockStore::open(&headers, &bodies).unwrap();
+            assert_eq!(blocks.len(), 1);
+            assert_eq!(store.stats().truncated_bytes, 0);
+            assert_eq!(store.disk_size().unwrap(), legacy_size + 2 * ENVELOPE_SIZE);
+            assert_eq!(store.read_body(&block.hash()).unwrap().unwrap().transactions.len(), block.content.transactions.len());
+        }
+        let opening = std::fs::read(&bodies).unwrap();
+        assert_eq!(envelope::Envelope::parse(&opening, BODIES_MAGIC).unwrap().version, FORMAT_VERSION);
+        assert_eq!(BlockStore::open(&headers, &bodies).unwrap().1.len(), 1);
+
+        // files of a later build are not read, nor cut down to what this one understands
+        let newer = envelope::Envelope { magic: HEADERS_MAGIC, version: FORMAT_VERSION + 1 };
+        std::fs::write(&headers, [newer.to_bytes().to_vec(), vec![1, 2, 3]].concat()).unwrap();
+        assert_eq!(BlockStore::open(&headers, &bodies).err().unwrap().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(std::fs::metadata(&headers).unwrap().len(), ENVELOPE_SIZE + 3);
+    }
+}
@@ -0,0 +1,167 @@
+use super::State;
+use crate::types::block::Block;
+use crate::types::transaction::SignedTransaction;
+
+use std::error::Error;
+use std::sync::Arc;
+
+/// An extra consensus rule on top of the built-in ones, for projects building on the chain, e.g.
+/// a name registry or voting. Every node of a network must register the same validators in
+/// `network_rules`, or they will disagree on which blocks are valid.
+pub trait Validator: Send + Sync {
+    /// Why a block or transaction breaks the rule
+    type Error: Error + Send + Sync + 'static;
+
+    /// Short name of the rule, for logs
+    fn name(&self) -> &'static str;
+
+    /// Check a transaction against `state`: the state after the parent of its block, or after
+    /// the tip and the earlier transactions of its package when it is admitted to the mempool.
+    /// Runs after the built-in signature, nonce and balance checks passed.
+    fn check_transaction(&self, _tx: &SignedTransaction, _state: &State) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Check a block as a whole against the state after its parent, before its transactions
+    fn check_block(&self, _block: &Block, _state: &State) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+/// A rule of a validator a block or transaction broke. The error of the validator can be had
+/// back with `error.downcast_ref`.
+#[derive(Debug)]
+pub struct RuleViolation {
+    pub rule: &'static str,
+    pub error: Box<dyn Error + Send + Sync>,
+}
+
+impl std::fmt::Display for RuleViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}: {}", self.rule, self.error)
+    }
+}
+
+impl Error for RuleViolation {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(self.error.as_ref())
+    }
+}
+
+// validators with their error type erased, so different ones can be kept together
+trait AnyValidator: Send + Sync {
+    fn check_transaction(&self, tx: &SignedTransaction, state: &State) -> Result<(), RuleViolation>;
+    fn check_block(&self, block: &Block, state: &State) -> Result<(), RuleViolation>;
+}
+
+impl<V: Validator> AnyValidator for V {
+    fn check_transaction(&self, tx: &SignedTransaction, state: &State) -> Result<(), RuleViolation> {
+        Validator::check_transaction(self, tx, state).map_err(|e| RuleViolation { rule: self.name(), error: Box::new(e) })
+    }
+
+    fn check_block(&self, block: &Block, state: &State) -> Result<(), RuleViolation> {
+        Validator::check_block(self, block, state).map_err(|e| RuleViolation { rule: self.name(), error: Box::new(e) })
+    }
+}
+
+/// The validators of a network, checked in the order they were registered.
+#[derive(Clone, Default)]
+pub struct Validators {
+    validators: Vec<Arc<dyn AnyValidator>>,
+}
+
+impl Validators {
+    pub fn new() -> Self {
+        Validators { validators: Vec::new() }
+    }
+
+    pub fn register<V: Validator + 'static>(&mut self, validator: V) {
+        self.validators.push(Arc::new(validator));
+    }
+
+    pub fn len(&self) -> usize {
+        self.validators.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.validators.is_empty()
+    }
+
+    /// Check a transaction against every validator, returns the first rule it breaks
+    pub fn check_transaction(&self, tx: &SignedTransaction, state: &State) -> Result<(), RuleViolation> {
+        self.validators.iter().try_for_each(|validator| validator.check_transaction(tx, state))
+    }
+
+    /// Check a block against every validator, returns the first rule it breaks
+    pub fn check_block(&self, block: &Block, state: &State) -> Result<(), RuleViolation> {
+        self.validators.iter().try_for_each(|validator| validator.check_block(block, state))
+    }
+}
+
+/// The validators of this network, used by the node and when replaying a trace. Register the
+/// rules of an extension project here.
+pub fn network_rules() -> Validators {
+    Validators::new()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::amount::Amount;
+    use crate::types::block::TestBlockBuilder;
+    use crate::types::hash::H256;
+    use crate::types::transaction::Transaction;
+
+    #[derive(Debug, PartialEq)]
+    struct TooLarge(Amount);
+
+    impl std::fmt::Display for TooLarge {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            write!(f, "value {} is too large", self.0)
+        }
+    }
+
+    impl Error for TooLarge {}
+
+    struct MaxValue(Amount);
+
+    impl Validator for MaxValue {
+        type Error = TooLarge;
+
+        fn name(&self) -> &'static str {
+            "max-value"
+        }
+
+        fn check_transaction(&self, tx: &SignedTransaction, _state: &State) -> Result<(), TooLarge> {
+            if tx.t.value > self.0 {
+                return Err(TooLarge(tx.t.value));
+            }
+            Ok(())
+        }
+
+        fn check_block(&self, block: &Block, _state: &State) -> Result<(), TooLarge> {
+            let total = Amount::checked_sum(block.content.transactions.iter().map(|tx| tx.t.value)).unwrap();
+            if total > self.0 {
+                return Err(TooLarge(total));
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn violations_keep_their_error() {
+        let mut validators = Validators::new();
+        assert!(validators.is_empty());
+        validators.register(MaxValue(Amount::from_sub_units(10)));
+        let state = State::default();
+        let tx = |value| SignedTransaction { t: Transaction { value: Amount::from_sub_units(value), ..Default::default() }, ..Default::default() };
+        assert!(validators.check_transaction(&tx(10), &state).is_ok());
+        let violation = validators.check_transaction(&tx(11), &state).unwrap_err();
+        assert_eq!(violation.rule, "max-value");
+        assert_eq!(violation.error.downcast_ref::<TooLarge>(), Some(&TooLarge(Amount::from_sub_units(11))));
+        assert_eq!(violation.to_string(), "max-value: value 11 is too large");
+        // each transaction passes, but not the block as a whole
+        let block = TestBlockBuilder::new(&H256::default()).transactions(vec![tx(6), tx(6)]).build();
+        assert!(validators.check_block(&block, &state).is_err());
+    }
+}
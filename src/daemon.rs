@@ -0,0 +1,169 @@
+use serde::{Serialize, Deserialize};
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+// set on the background process so it knows it is the daemon rather than the launcher
+const DAEMON_ENV: &str = "BITCOIN_DAEMON";
+
+/// Files of a node running in the background, named after its P2P port so many nodes can share
+/// a data directory.
+#[derive(Debug, Clone)]
+pub struct DaemonFiles {
+    data_dir: PathBuf,
+    port: u16,
+}
+
+impl DaemonFiles {
+    pub fn new<P: AsRef<Path>>(data_dir: P, port: u16) -> Self {
+        DaemonFiles { data_dir: data_dir.as_ref().to_path_buf(), port }
+    }
+
+    pub fn pid(&self) -> PathBuf {
+        self.data_dir.join(format!("bitcoin-{}.pid", self.port))
+    }
+
+    pub fn status(&self) -> PathBuf {
+        self.data_dir.join(format!("bitcoin-{}.status.json", self.port))
+    }
+
+    /// Output of the node, which has no terminal once in the background
+    pub fn log(&self) -> PathBuf {
+        self.data_dir.join(format!("bitcoin-{}.log", self.port))
+    }
+}
+
+/// What lab automation needs to know about a running node.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Status {
+    pub pid: u32,
+    pub p2p_addr: String,
+    pub api_addr: String,
+    /// None if the WebSocket event server is off
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ws_addr: Option<String>,
+    pub data_dir: String,
+    /// hash of the genesis block, nodes of one network share it
+    pub chain_id: String,
+}
+
+impl Status {
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let json = fs::read_to_string(path)?;
+        serde_json::from_str(&json).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, serde_json::to_string_pretty(self).unwrap())
+    }
+}
+
+/// Whether this process is the background copy started by `spawn`
+pub fn is_daemon() -> bool {
+    std::env::var_os(DAEMON_ENV).is_some()
+}
+
+/// Start this executable again with the same arguments in the background, its output going to
+/// the log file. The new process gets its own process group, and on Windows no console, so it
+/// outlives the terminal. Returns the pid of the background process.
+pub fn spawn(files: &DaemonFiles) -> io::Result<u32> {
+    fs::create_dir_all(&files.data_dir)?;
+    let log = OpenOptions::new().create(true).append(true).open(files.log())?;
+    let mut command = Command::new(std::env::current_exe()?);
+    command
+        .args(std::env::args_os().skip(1))
+        .env(DAEMON_ENV, "1")
+        .stdin(Stdio::null())
+        .stdout(log.try_clone()?)
+        .stderr(log);
+    detach(&mut command);
+    let child = command.spawn()?;
+    Ok(child.id())
+}
+
+#[cfg(unix)]
+fn detach(command: &mut Command) {
+    use std::os::unix::process::CommandExt;
+    command.process_group(0);
+}
+
+#[cfg(windows)]
+fn detach(command: &mut Command) {
+    use std::os::windows::process::CommandExt;
+    const DETACHED_PROCESS: u32 = 0x0000_0008;
+    const CREATE_NEW_PROCESS_GROUP: u32 = 0x0000_0200;
+    command.creation_flags(DETACHED_PROCESS | CREATE_NEW_PROCESS_GROUP);
+}
+
+#[cfg(not(any(unix, windows)))]
+fn detach(_command: &mut Command) {}
+
+// ask the system to end the process `pid`
+#[cfg(unix)]
+fn terminate(pid: u32) -> io::Result<std::process::ExitStatus> {
+    Command::new("kill").arg(pid.to_string()).status()
+}
+
+#[cfg(windows)]
+fn terminate(pid: u32) -> io::Result<std::process::ExitStatus> {
+    // a detached node has no console to be sent Ctrl+C through
+    Command::new("taskkill").args(["/F", "/PID"]).arg(pid.to_string()).status()
+}
+
+#[cfg(not(any(unix, windows)))]
+fn terminate(pid: u32) -> io::Result<std::process::ExitStatus> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, format!("cannot stop process {} on this platform", pid)))
+}
+
+/// Record the pid of this process and the status of the node
+pub fn write_files(files: &DaemonFiles, status: &Status) -> io::Result<()> {
+    fs::write(files.pid(), format!("{}\n", status.pid))?;
+    status.save(files.status())
+}
+
+/// Terminate the node whose pid file is in `files` and remove its files. Returns its pid.
+pub fn stop(files: &DaemonFiles) -> io::Result<u32> {
+    let pid = fs::read_to_string(files.pid())?;
+    let pid = pid
+        .trim()
+        .parse::<u32>()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let killed = terminate(pid)?;
+    if !killed.success() {
+        return Err(io::Error::other(format!("kill {} failed with {}", pid, killed)));
+    }
+    fs::remove_file(files.pid())?;
+    // an old node may not have written its status yet
+    let _ = fs::remove_file(files.status());
+    Ok(pid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(unix)]
+    fn stop_removes_files() {
+        let temp = tempfile::tempdir().unwrap();
+        let data_dir = temp.path();
+        let files = DaemonFiles::new(data_dir, 6000);
+        let mut sleeper = Command::new("sleep").arg("60").spawn().unwrap();
+        let status = Status {
+            pid: sleeper.id(),
+            p2p_addr: "127.0.0.1:6000".to_string(),
+            api_addr: "127.0.0.1:7000".to_string(),
+            ws_addr: Some("127.0.0.1:8000".to_string()),
+            data_dir: data_dir.display().to_string(),
+            chain_id: "00".repeat(32),
+        };
+        write_files(&files, &status).unwrap();
+        assert_eq!(Status::load(files.status()).unwrap(), status);
+
+        assert_eq!(stop(&files).unwrap(), sleeper.id());
+        assert!(!sleeper.wait().unwrap().success());
+        assert!(!files.pid().exists());
+        assert!(!files.status().exists());
+    }
+}
@@ -0,0 +1,189 @@
+use crate::blockchain::Blockchain;
+use crate::blockchain::params::ChainParams;
+use crate::types::state_proof;
+
+use serde::de::DeserializeOwned;
+use serde::{Serialize, Deserialize};
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+
+/// One block of the longest chain, with the root of the state after it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SummaryBlock {
+    pub height: u32,
+    pub hash: String,
+    pub state_root: String,
+}
+
+/// The tip of a node's longest chain and its last blocks, which two nodes compare to find out
+/// whether and where they disagree.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ChainSummary {
+    pub height: u32,
+    pub tip: String,
+    pub state_root: String,
+    /// the last blocks of the longest chain, oldest first
+    pub recent: Vec<SummaryBlock>,
+}
+
+impl ChainSummary {
+    /// Summarize the longest chain of `blockchain` with its last `recent` blocks
+    pub fn of(blockchain: &Blockchain, recent: usize) -> Self {
+        let chain = blockchain.all_blocks_in_longest_chain();
+        let recent: Vec<SummaryBlock> = chain
+            .iter()
+            .enumerate()
+            .skip(chain.len().saturating_sub(recent))
+            .map(|(height, hash)| SummaryBlock {
+                height: height as u32,
+                hash: hash.to_string(),
+                state_root: state_proof::state_root(&blockchain.state_map[hash].state).to_string(),
+            })
+            .collect();
+        let tip = blockchain.tip();
+        ChainSummary {
+            height: blockchain.height(),
+            tip: tip.to_string(),
+            state_root: state_proof::state_root(&blockchain.state_map[&tip].state).to_string(),
+            recent,
+        }
+    }
+}
+
+/// First height at which two nodes disagree.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    pub height: u32,
+    /// block hashes of the two nodes at that height
+    pub local: String,
+    pub peer: String,
+    /// the nodes already disagree on the oldest block both summaries hold, so the chains may
+    /// have split below `height`, compare more recent blocks to find out
+    pub before_window: bool,
+}
+
+/// Outcome of comparing the summaries of two nodes.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct AgreementReport {
+    pub local_height: u32,
+    pub peer_height: u32,
+    pub local_tip: String,
+    pub peer_tip: String,
+    /// same tip and same state
+    pub agree: bool,
+    /// first height with different blocks, None if the compared blocks all match and one node is
+    /// at most behind the other
+    pub divergence: Option<Divergence>,
+    /// first height whose block both nodes have but with a different state after it, a bug in
+    /// how one of them applies transactions
+    pub state_mismatch: Option<u32>,
+}
+
+/// Compare the summary of this node with the one of a peer
+pub fn compare(local: &ChainSummary, peer: &ChainSummary) -> AgreementReport {
+    let mut divergence = None;
+    let mut state_mismatch = None;
+    let mut first_common = true;
+    for ours in local.recent.iter() {
+        let theirs = match peer.recent.iter().find(|b| b.height == ours.height) {
+            Some(theirs) => theirs,
+            None => continue,
+        };
+        if ours.hash != theirs.hash {
+            divergence = Some(Divergence {
+                height: ours.height,
+                local: ours.hash.clone(),
+                peer: theirs.hash.clone(),
+                before_window: first_common && ours.height > 0,
+            });
+            break;
+        }
+        if ours.state_root != theirs.state_root && state_mismatch.is_none() {
+            state_mismatch = Some(ours.height);
+        }
+        first_common = false;
+    }
+    AgreementReport {
+        local_height: local.height,
+        peer_height: peer.height,
+        local_tip: local.tip.clone(),
+        peer_tip: peer.tip.clone(),
+        agree: local.tip == peer.tip && local.state_root == peer.state_root,
+        divergence,
+        state_mismatch,
+    }
+}
+
+/// Get the chain summary of the node whose API server is at `api`, with its last `recent` blocks
+pub fn fetch_summary(api: SocketAddr, recent: usize) -> io::Result<ChainSummary> {
+    fetch(api, &format!("/blockchain/summary?recent={}", recent))
+}
+
+/// Get the chain parameters of a new network starting from the balances at the tip of the node
+/// whose API server is at `api`
+pub fn fetch_export(api: SocketAddr) -> io::Result<ChainParams> {
+    fetch(api, "/blockchain/export/params")
+}
+
+fn fetch<T: DeserializeOwned>(api: SocketAddr, path: &str) -> io::Result<T> {
+    let mut stream = TcpStream::connect_timeout(&api, Duration::from_secs(5))?;
+    stream.set_read_timeout(Some(Duration::from_secs(30)))?;
+    // HTTP/1.0 so the body is not chunked and ends with the connection
+    write!(stream, "GET {} HTTP/1.0\r\nHost: {}\r\n\r\n", path, api)?;
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response)?;
+    let response = String::from_utf8_lossy(&response);
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed HTTP response"))?;
+    let status = head.lines().next().unwrap_or("");
+    if status.split_whitespace().nth(1) != Some("200") {
+        return Err(io::Error::other(format!("{} answered {}", api, status)));
+    }
+    serde_json::from_str(body).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", e, body)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::block::TestBlockBuilder;
+    use crate::types::hash::Hashable;
+
+    #[test]
+    fn first_divergence_is_reported() {
+        let mut local = Blockchain::new(0);
+        let mut peer = Blockchain::new(0);
+        let shared = TestBlockBuilder::new(&local.tip()).length(1).build();
+        local.insert(&shared);
+        peer.insert(&shared);
+        assert!(compare(&ChainSummary::of(&local, 10), &ChainSummary::of(&peer, 10)).agree);
+
+        let ours = TestBlockBuilder::new(&shared.hash()).length(2).build();
+        let theirs = TestBlockBuilder::new(&shared.hash()).length(2).build();
+        local.insert(&ours);
+        peer.insert(&theirs);
+        peer.insert(&TestBlockBuilder::new(&theirs.hash()).length(3).build());
+        let report = compare(&ChainSummary::of(&local, 10), &ChainSummary::of(&peer, 10));
+        assert!(!report.agree);
+        assert_eq!((report.local_height, report.peer_height), (2, 3));
+        let divergence = report.divergence.unwrap();
+        assert_eq!((divergence.height, divergence.before_window), (2, false));
+        assert_eq!(divergence.local, ours.hash().to_string());
+        assert_eq!(report.state_mismatch, None);
+
+        // with a window of one block the split may be older than what was compared
+        let report = compare(&ChainSummary::of(&local, 1), &ChainSummary::of(&peer, 2));
+        assert!(report.divergence.unwrap().before_window);
+    }
+
+    #[test]
+    fn behind_is_not_divergence() {
+        let mut local = Blockchain::new(0);
+        let peer = Blockchain::new(0);
+        local.insert(&TestBlockBuilder::new(&local.tip()).length(1).build());
+        let report = compare(&ChainSummary::of(&local, 10), &ChainSummary::of(&peer, 10));
+        assert!(!report.agree);
+        assert_eq!(report.divergence, None);
+    }
+}
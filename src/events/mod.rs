@@ -0,0 +1,270 @@
+pub mod webhook;
+
+use crate::types::address::Address;
+use crate::types::amount::Amount;
+use crate::watchdog::AlarmLevel;
+
+use crossbeam::channel::{bounded, unbounded, Receiver, Sender, TrySendError};
+use log::debug;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+/// Structured events a node emits about itself, serialized as JSON for subscribers.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    PeerConnected { addr: std::net::SocketAddr, direction: String },
+    /// `peers` are still connected
+    PeerDisconnected { addr: std::net::SocketAddr, peers: usize },
+    /// we could not establish a connection to the peer
+    HandshakeFailed { addr: std::net::SocketAddr, error: String },
+    /// our tip stopped advancing while peers report a higher one, a resync was triggered
+    SyncStalled { height: u32, peer_height: u32 },
+    /// the initial sync completed, the node serves blocks from now on
+    SyncComplete { height: u32 },
+    /// the block interval alarm changed level, no block arrived for `since_last_block_ms`
+    BlockAlarm { level: AlarmLevel, since_last_block_ms: u64 },
+    /// a block joined the longest chain
+    Block { hash: String, height: u32, parent: String, transactions: usize },
+    /// a transaction was confirmed by a block joining the longest chain
+    Transaction { hash: String, from: String, to: String, value: Amount, block: String, height: u32 },
+    /// the longest chain switched to another branch, abandoning `depth` blocks
+    Reorg { depth: u32, old_tip: String, new_tip: String },
+}
+
+impl Event {
+    /// Name of the event, as in its JSON `event` field
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::PeerConnected { .. } => "peer_connected",
+            Event::PeerDisconnected { .. } => "peer_disconnected",
+            Event::HandshakeFailed { .. } => "handshake_failed",
+            Event::SyncStalled { .. } => "sync_stalled",
+            Event::SyncComplete { .. } => "sync_complete",
+            Event::BlockAlarm { .. } => "block_alarm",
+            Event::Block { .. } => "block",
+            Event::Transaction { .. } => "transaction",
+            Event::Reorg { .. } => "reorg",
+        }
+    }
+}
+
+/// Events a subscriber wants, so it is not sent the others. Every condition left unset lets all
+/// events through.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct EventFilter {
+    /// names of the events to send
+    pub events: Option<HashSet<String>>,
+    /// send only the transactions from or to one of these addresses
+    pub addresses: Option<HashSet<Address>>,
+    /// send only the transactions to one of these addresses, e.g. payments to our wallet
+    pub to: Option<HashSet<Address>>,
+    /// send only the transactions of at least this value
+    pub min_value: Amount,
+    /// send only the reorgs abandoning at least this many blocks
+    pub min_reorg_depth: u32,
+    /// send only the disconnects leaving fewer peers than this
+    pub peers_below: Option<usize>,
+}
+
+impl EventFilter {
+    /// Parse a filter from a query string such as
+    /// `events=block,transaction&address=<hex>,<hex>&min_reorg_depth=2`, or
+    /// `events=transaction&to=<hex>&min_value=1000` for large payments to an address
+    pub fn parse(query: &str) -> Result<Self, String> {
+        let mut filter = EventFilter::default();
+        for (key, value) in url::form_urlencoded::parse(query.as_bytes()) {
+            let values = value.split(',').filter(|v| !v.is_empty());
+            match key.as_ref() {
+                "events" => filter.events = Some(values.map(|v| v.to_string()).collect()),
+                "address" => {
+                    let addresses = values.map(|v| v.parse::<Address>()).collect::<Result<HashSet<_>, _>>()?;
+                    filter.addresses = Some(addresses);
+                }
+                "to" => {
+                    let addresses = values.map(|v| v.parse::<Address>()).collect::<Result<HashSet<_>, _>>()?;
+                    filter.to = Some(addresses);
+                }
+                "min_value" => {
+                    filter.min_value = value.parse::<Amount>().map_err(|e| format!("bad min_value: {}", e))?;
+                }
+                "min_reorg_depth" => {
+                    filter.min_reorg_depth = value.parse::<u32>().map_err(|e| format!("bad min_reorg_depth: {}", e))?;
+                }
+                "peers_below" => {
+                    filter.peers_below = Some(value.parse::<usize>().map_err(|e| format!("bad peers_below: {}", e))?);
+                }
+                other => return Err(format!("unknown filter {}", other)),
+            }
+        }
+        Ok(filter)
+    }
+
+    pub fn matches(&self, event: &Event) -> bool {
+        if let Some(events) = &self.events {
+            if !events.contains(event.name()) {
+                return false;
+            }
+        }
+        match event {
+            Event::Transaction { from, to, value, .. } => {
+                let involved = match &self.addresses {
+                    Some(addresses) => addresses.iter().any(|a| a.to_string() == *from || a.to_string() == *to),
+                    None => true,
+                };
+                let received = match &self.to {
+                    Some(addresses) => addresses.iter().any(|a| a.to_string() == *to),
+                    None => true,
+                };
+                involved && received && *value >= self.min_value
+            }
+            Event::Reorg { depth, .. } => *depth >= self.min_reorg_depth,
+            Event::PeerDisconnected { peers, .. } => match self.peers_below {
+                Some(below) => *peers < below,
+                None => true,
+            },
+            _ => true,
+        }
+    }
+}
+
+/// Fan-out of events to every subscriber. Subscribers that went away, or whose bounded channel is
+/// full, are dropped on the next publish.
+#[derive(Clone)]
+pub struct EventBus {
+    subscribers: Arc<Mutex<Vec<Sender<Event>>>>,
+}
+
+impl EventBus {
+    pub fn new() -> Self {
+        EventBus { subscribers: Arc::new(Mutex::new(Vec::new())) }
+    }
+
+    /// Get a channel receiving every event published from now on
+    pub fn subscribe(&self) -> Receiver<Event> {
+        let (sender, receiver) = unbounded();
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    /// Like `subscribe`, but holding at most `capacity` events: a subscriber falling further
+    /// behind is dropped, and its channel disconnects once it read the events held
+    pub fn subscribe_bounded(&self, capacity: usize) -> Receiver<Event> {
+        let (sender, receiver) = bounded(capacity);
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    /// Send an event to all current subscribers
+    pub fn publish(&self, event: Event) {
+        self.subscribers.lock().unwrap().retain(|s| match s.try_send(event.clone()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                debug!("Dropping an event subscriber that fell behind");
+                false
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        });
+    }
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn publish_reaches_live_subscribers() {
+        let bus = EventBus::new();
+        let first = bus.subscribe();
+        let second = bus.subscribe();
+        drop(second);
+        let addr = "127.0.0.1:6001".parse().unwrap();
+        bus.publish(Event::PeerDisconnected { addr, peers: 0 });
+        assert_eq!(first.try_recv(), Ok(Event::PeerDisconnected { addr, peers: 0 }));
+        assert_eq!(bus.subscribers.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn lagging_bounded_subscribers_are_dropped() {
+        let bus = EventBus::new();
+        let lagging = bus.subscribe_bounded(1);
+        let keeping_up = bus.subscribe_bounded(1);
+        let addr = "127.0.0.1:6001".parse().unwrap();
+        bus.publish(Event::PeerDisconnected { addr, peers: 0 });
+        assert!(keeping_up.try_recv().is_ok());
+        bus.publish(Event::PeerDisconnected { addr, peers: 1 });
+        assert_eq!(bus.subscribers.lock().unwrap().len(), 1);
+        // the lagging subscriber reads what it was sent, then finds its channel closed
+        assert_eq!(lagging.recv(), Ok(Event::PeerDisconnected { addr, peers: 0 }));
+        assert!(lagging.recv().is_err());
+        assert_eq!(keeping_up.try_recv(), Ok(Event::PeerDisconnected { addr, peers: 1 }));
+    }
+
+    #[test]
+    fn filter_events() {
+        let ours = Address::from([1; 20]);
+        let filter = EventFilter::parse(&format!("events=transaction,reorg&address={}&min_reorg_depth=2", ours)).unwrap();
+        let transaction = |from: Address, to: Address| Event::Transaction {
+            hash: String::new(),
+            from: from.to_string(),
+            to: to.to_string(),
+            value: Amount::from_sub_units(1),
+            block: String::new(),
+            height: 1,
+        };
+        let reorg = |depth| Event::Reorg { depth, old_tip: String::new(), new_tip: String::new() };
+        assert!(filter.matches(&transaction(Address::from([2; 20]), ours)));
+        assert!(!filter.matches(&transaction(Address::from([2; 20]), Address::from([3; 20]))));
+        assert!(filter.matches(&reorg(2)));
+        assert!(!filter.matches(&reorg(1)));
+        assert!(!filter.matches(&Event::PeerDisconnected { addr: "127.0.0.1:6001".parse().unwrap(), peers: 0 }));
+        // no filter lets everything through
+        assert!(EventFilter::parse("").unwrap().matches(&reorg(0)));
+        assert!(EventFilter::parse("address=zz").is_err());
+        assert!(EventFilter::parse("colour=red").is_err());
+    }
+
+    #[test]
+    fn filter_large_payments_and_peer_drops() {
+        let wallet = Address::from([1; 20]);
+        let other = Address::from([2; 20]);
+        let payment = |from: Address, to: Address, value| Event::Transaction {
+            hash: String::new(),
+            from: from.to_string(),
+            to: to.to_string(),
+            value: Amount::from_sub_units(value),
+            block: String::new(),
+            height: 1,
+        };
+        let filter = EventFilter::parse(&format!("to={}&min_value=100", wallet)).unwrap();
+        assert!(filter.matches(&payment(other, wallet, 100)));
+        assert!(!filter.matches(&payment(other, wallet, 99)));
+        // payments made by the wallet are not incoming
+        assert!(!filter.matches(&payment(wallet, other, 500)));
+
+        let filter = EventFilter::parse("events=peer_disconnected&peers_below=2").unwrap();
+        let addr = "127.0.0.1:6001".parse().unwrap();
+        assert!(filter.matches(&Event::PeerDisconnected { addr, peers: 1 }));
+        assert!(!filter.matches(&Event::PeerDisconnected { addr, peers: 2 }));
+        assert!(EventFilter::parse("min_value=lots").is_err());
+    }
+
+    #[test]
+    fn event_json_is_tagged() {
+        let event = Event::PeerConnected {
+            addr: "127.0.0.1:6001".parse().unwrap(),
+            direction: "outgoing".to_string(),
+        };
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"event":"peer_connected","addr":"127.0.0.1:6001","direction":"outgoing"}"#
+        );
+    }
+}
@@ -0,0 +1,209 @@
+use super::{Event, EventBus, EventFilter};
+
+use crossbeam::channel::{bounded, TrySendError};
+use log::{debug, info, warn};
+use ring::hmac;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+use url::Url;
+
+// events waiting for a webhook that is down, newer ones are dropped past this
+const QUEUE_CAPACITY: usize = 1000;
+// the wait before the first retry, doubled for each further one
+const FIRST_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// An http:// endpoint events are posted to, as JSON. With a secret each post carries the
+/// HMAC-SHA256 of its body in an `X-Signature-256: sha256=<hex>` header, so the receiver can
+/// check it comes from this node.
+#[derive(Debug, Clone)]
+pub struct Webhook {
+    pub url: Url,
+    /// events posted by `Notifier`
+    pub filter: EventFilter,
+    secret: Option<Vec<u8>>,
+}
+
+impl Webhook {
+    /// Parse a webhook given on the command line, an http:// url whose fragment, if any, is the
+    /// filter of the events to post, e.g. `http://127.0.0.1:9000/hook#events=block,reorg`. The
+    /// fragment is not sent.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let mut url = Url::parse(spec).map_err(|e| e.to_string())?;
+        if url.scheme() != "http" || url.host_str().is_none() {
+            return Err(format!("{} is not an http:// url", url));
+        }
+        let filter = EventFilter::parse(url.fragment().unwrap_or(""))?;
+        url.set_fragment(None);
+        Ok(Webhook { url, filter, secret: None })
+    }
+
+    /// Sign the posts with `secret`
+    pub fn with_secret(mut self, secret: &[u8]) -> Self {
+        self.secret = Some(secret.to_vec());
+        self
+    }
+
+    /// POST `body`, the JSON of an event of type `kind`, and wait for a 2xx answer
+    pub fn post(&self, kind: &str, body: &str) -> io::Result<()> {
+        let addr = self
+            .url
+            .socket_addrs(|| Some(80))?
+            .into_iter()
+            .next()
+            .ok_or_else(|| io::Error::other(format!("cannot resolve {}", self.url)))?;
+        let mut stream = TcpStream::connect_timeout(&addr, Duration::from_secs(5))?;
+        stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+        let path = match self.url.query() {
+            Some(query) => format!("{}?{}", self.url.path(), query),
+            None => self.url.path().to_string(),
+        };
+        let signature = match &self.secret {
+            Some(secret) => format!("X-Signature-256: sha256={}\r\n", sign(secret, body)),
+            None => String::new(),
+        };
+        let request = format!(
+            "POST {} HTTP/1.0\r\nHost: {}\r\nContent-Type: application/json\r\nX-Event: {}\r\n{}Content-Length: {}\r\n\r\n{}",
+            path,
+            self.url.host_str().unwrap(),
+            kind,
+            signature,
+            body.len(),
+            body
+        );
+        stream.write_all(request.as_bytes())?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response)?;
+        let response = String::from_utf8_lossy(&response);
+        let status = response.lines().next().unwrap_or("");
+        match status.split_whitespace().nth(1) {
+            Some(code) if code.starts_with('2') => Ok(()),
+            _ => Err(io::Error::other(format!("{} answered {}", self.url, status))),
+        }
+    }
+
+    /// Post `body`, trying again up to `retries` times with a doubling delay
+    pub fn post_with_retries(&self, kind: &str, body: &str, retries: u32) -> io::Result<()> {
+        let mut delay = FIRST_RETRY_DELAY;
+        let mut attempt = 0;
+        loop {
+            match self.post(kind, body) {
+                Err(e) if attempt < retries => {
+                    debug!("Error posting {} to {}, retrying in {:?}: {}", kind, self.url, delay, e);
+                    thread::sleep(delay);
+                    delay *= 2;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+/// Hex HMAC-SHA256 of `body` with `secret`
+pub fn sign(secret: &[u8], body: &str) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret);
+    hex::encode(hmac::sign(&key, body.as_bytes()).as_ref())
+}
+
+/// Posts the events of this node to webhooks, each only those matching its filter. Every
+/// webhook is posted to from a thread of its own in the order of the events, so one that is
+/// slow or down only holds up its own events.
+pub struct Notifier {
+    webhooks: Vec<Webhook>,
+    retries: u32,
+    events: EventBus,
+}
+
+impl Notifier {
+    pub fn new(webhooks: Vec<Webhook>, retries: u32, events: &EventBus) -> Self {
+        Notifier { webhooks, retries, events: events.clone() }
+    }
+
+    pub fn start(self) {
+        if self.webhooks.is_empty() {
+            return;
+        }
+        let subscription = self.events.subscribe();
+        let mut queues = Vec::new();
+        for (i, webhook) in self.webhooks.into_iter().enumerate() {
+            let (sender, receiver) = bounded::<Event>(QUEUE_CAPACITY);
+            let retries = self.retries;
+            info!("Posting events to {}", webhook.url);
+            queues.push((webhook.url.clone(), webhook.filter.clone(), sender));
+            thread::Builder::new()
+                .name(format!("webhook-{}", i))
+                .spawn(move || {
+                    for event in receiver.iter() {
+                        let body = serde_json::to_string(&event).unwrap();
+                        if let Err(e) = webhook.post_with_retries(event.name(), &body, retries) {
+                            warn!("Giving up posting {} to {}: {}", event.name(), webhook.url, e);
+                        }
+                    }
+                })
+                .unwrap();
+        }
+        thread::Builder::new()
+            .name("webhook-dispatch".to_string())
+            .spawn(move || {
+                for event in subscription.iter() {
+                    for (url, filter, queue) in queues.iter() {
+                        if !filter.matches(&event) {
+                            continue;
+                        }
+                        if let Err(TrySendError::Full(_)) = queue.try_send(event.clone()) {
+                            warn!("Webhook {} is falling behind, dropping {}", url, event.name());
+                        }
+                    }
+                }
+            })
+            .unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn parse_reads_the_filter_from_the_fragment() {
+        assert!(Webhook::parse("https://example.com/hook").is_err());
+        assert!(Webhook::parse("http://example.com/hook#colour=red").is_err());
+        let webhook = Webhook::parse("http://example.com/hook?node=1#events=reorg&min_reorg_depth=3").unwrap();
+        assert_eq!(webhook.url.as_str(), "http://example.com/hook?node=1");
+        assert_eq!(webhook.filter.min_reorg_depth, 3);
+        assert!(!webhook.filter.matches(&Event::SyncComplete { height: 1 }));
+    }
+
+    #[test]
+    fn post_is_signed() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook?node=1", listener.local_addr().unwrap());
+        let webhook = Webhook::parse(&url).unwrap().with_secret(b"secret");
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buffer = [0; 1024];
+            while !request.ends_with(b"}") {
+                let n = stream.read(&mut buffer).unwrap();
+                request.extend_from_slice(&buffer[..n]);
+            }
+            stream.write_all(b"HTTP/1.0 204 No Content\r\n\r\n").unwrap();
+            String::from_utf8_lossy(&request).to_string()
+        });
+        let body = "{\"event\":\"sync_complete\",\"height\":1}";
+        webhook.post("sync_complete", body).unwrap();
+        let request = server.join().unwrap();
+        assert!(request.starts_with("POST /hook?node=1 HTTP/1.0"));
+        assert!(request.contains("X-Event: sync_complete\r\n"));
+        assert!(request.contains(&format!("X-Signature-256: sha256={}\r\n", sign(b"secret", body))));
+        assert!(request.ends_with(body));
+        // HMAC-SHA256 test vector of RFC 4231, case 2
+        assert_eq!(
+            sign(b"Jefe", "what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+}
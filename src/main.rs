@@ -2,24 +2,87 @@
 #[macro_use]
 extern crate hex_literal;
 
+#[cfg(feature = "api")]
 pub mod api;
 pub mod blockchain;
+pub mod daemon;
+pub mod diagnostics;
+pub mod events;
 pub mod types;
+pub mod memory;
+pub mod metrics;
+#[cfg(feature = "miner")]
 pub mod miner;
 pub mod network;
+pub mod policy;
+pub mod selftest;
+pub mod snapshot;
+pub mod threads;
 pub mod txgen;
+pub mod watchdog;
+#[cfg(feature = "wallet")]
+pub mod wallet;
 
 use blockchain::{Blockchain, Mempool};
+use blockchain::params::ChainParams;
+use blockchain::validators;
+use blockchain::store::BlockStore;
+use blockchain::compact;
+use blockchain::checkpoint::Checkpoint;
+use daemon::{DaemonFiles, Status as DaemonStatus};
 use clap::clap_app;
 use smol::channel;
-use log::{error, info};
+use log::{error, info, warn};
+#[cfg(feature = "api")]
 use api::Server as ApiServer;
-use std::net;
+#[cfg(feature = "api")]
+use api::ws::Server as EventServer;
+use std::net::{self, ToSocketAddrs};
+use std::path::Path;
 use std::process;
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time;
+#[cfg(feature = "miner")]
 use miner::worker::Worker;
+use policy::RelayPolicy;
+use events::EventBus;
+use events::webhook::{Notifier, Webhook};
+use watchdog::{AlarmThresholds, BlockWatch};
+use network::catchup::TxGossipPause;
+use network::magic::NetworkMagic;
+use network::eviction::{ConnectionLimits, LastUseful};
+use network::sync::{PeerTips, SyncGate};
+use network::features::PeerFeatures;
+use network::peer::PeerStates;
+use network::gossip::{Fanout, RecentlyAnnounced, RelayDelay};
+use network::ratelimit::{self, RateLimiter};
+use network::banlist::BanList;
+use network::dustfilter::DustFilters;
+use network::reconcile::Reconciler;
+use network::clock::NetworkClock;
+use network::keepalive::KeepAlive;
+use network::chain_proofs::ChainProofRequests;
+use network::trace::TraceWriter;
+use network::verifier::VerifierPool;
+use network::orphan::{OrphanLimits, OrphanResolver, OrphanStrategy};
+use network::download::DownloadScheduler;
+use network::vnet::VirtualNetwork;
+use network::routing::RequestRouter;
+use network::inflight::InFlightBlocks;
+use network::topology::Topology;
+use memory::{MemoryCaps, MemoryReport};
+use threads::{PoolKind, ThreadCount, ThreadPools};
+use metrics::MetricsHistory;
+use metrics::validation::BlockMetricsLog;
+use network::attribution::FirstSeen;
+#[cfg(feature = "wallet")]
+use wallet::Wallet;
+use types::amount::Amount;
+use types::hash::H256;
+use types::key_pair;
+use types::pow::PowAlgorithm;
+use ring::signature::KeyPair;
 
 fn main() {
     // parse command line arguments
@@ -27,10 +90,135 @@ fn main() {
      (version: "0.1")
      (about: "Bitcoin client")
      (@arg verbose: -v ... "Increases the verbosity of logging")
-     (@arg peer_addr: --p2p [ADDR] default_value("127.0.0.1:6000") "Sets the IP address and the port of the P2P server")
+     (@arg peer_addr: --p2p [ADDR] default_value("127.0.0.1:6000") "Sets the IP address and the port of the P2P server, [::]:<port> listening for both IPv6 and IPv4 peers")
+     (@arg p2p_ws_addr: --("p2p-ws") [ADDR] "Sets the IP address and the port where peers may connect over WebSocket as well, one binary message per P2P message, e.g. from a browser")
      (@arg api_addr: --api [ADDR] default_value("127.0.0.1:7000") "Sets the IP address and the port of the API server")
-     (@arg known_peer: -c --connect ... [PEER] "Sets the peers to connect to at start")
-     (@arg p2p_workers: --("p2p-workers") [INT] default_value("4") "Sets the number of worker threads for P2P server")
+     (@arg ws_addr: --ws [ADDR] "Sets the IP address and the port of the WebSocket event server, which only runs if given")
+     (@arg known_peer: -c --connect ... [PEER] "Sets the peers to connect to at start, they are added to the address book in peers-<p2p port>.json of the data directory and redialed when dropped")
+     (@arg push_blocks_to: --("push-blocks-to") ... [PEER] "Sends new blocks whole to these peers, by the address of the connection, instead of announcing them, e.g. between the nodes of miners")
+     (@arg block_fanout: --("block-fanout") [N] default_value("all") "Sets how many peers, drawn at random, each new block is announced to, or all")
+     (@arg tx_fanout: --("tx-fanout") [N] default_value("all") "Sets how many peers, drawn at random, each new transaction is announced to, or all")
+     (@arg p2p_ws_json: --("p2p-ws-json") "Lets WebSocket peers connecting to /json exchange P2P messages as JSON text, for nodes written in other languages")
+     (@arg network_magic: --("network-magic") [HEX] "Starts every P2P frame sent over TCP with these 4 bytes, as 8 hex digits, and disconnects peers whose frames do not, to keep separate networks from connecting by accident")
+     (@arg no_compression: --("no-compression") "Does not offer peers to compress large P2P messages, for links where CPU is scarcer than bandwidth")
+     (@arg tx_relay_delay: --("tx-relay-delay") [MS] default_value("0") "Sets the longest random time in milliseconds a new transaction is held before it is announced, 0 announces right away")
+     (@arg tx_pause_behind: --("tx-pause-behind") [BLOCKS] default_value("10") "Ignores the transactions peers relay while more than BLOCKS blocks behind their best tip, so catching up is not slowed down by transaction floods, or off")
+     (@arg seed: --seed ... [HOST] "Sets the seed nodes, as a host name or IP address and port, asked for the addresses of other peers at start")
+     (@arg addr_interval: --("addr-interval") [INT] default_value("60000") "Sets how often in milliseconds a peer is asked for the addresses it knows")
+     (@arg keepalive_interval: --("keepalive-interval") [INT] default_value("10000") "Sets how often in milliseconds every peer is pinged")
+     (@arg keepalive_misses: --("keepalive-misses") [INT] default_value("3") "Sets how many pings in a row a peer may leave unanswered before it is disconnected, 0 never disconnects")
+     (@arg max_outbound: --("max-outbound") [INT] default_value("8") "Sets how many peers of the address book this node stays connected to, and how many outgoing connections the P2P server keeps")
+     (@arg max_inbound: --("max-inbound") [INT] default_value("117") "Sets how many incoming peers the P2P server keeps, WebSocket peers included, a new one over the limit evicts the peer of the lowest value")
+     (@arg max_redial_backoff: --("max-redial-backoff") [INT] default_value("300000") "Sets the longest wait in milliseconds between attempts to redial a peer of the address book")
+     (@arg webhook: --webhook ... [URL] "Posts the events matching the filter in the URL fragment to this http:// URL, e.g. http://127.0.0.1:9000/hook#events=block,reorg&min_reorg_depth=3, all events without a fragment")
+     (@arg webhook_secret: --("webhook-secret") [SECRET] "Signs the posts to the webhooks and the alarm webhook with the HMAC-SHA256 of this secret, in the X-Signature-256 header")
+     (@arg webhook_retries: --("webhook-retries") [INT] default_value("3") "Sets how many times a failed post to a webhook is retried, waiting twice as long each time")
+     (@arg rate_limit: --("rate-limit") ... [LIMIT] "Sets how many messages of a type each peer may send, as <message>=<rate per second>[/<burst>], or <message>=none to lift the default limit")
+     (@arg p2p_workers: --("p2p-workers") [INT] default_value("4") "Sets the number of worker threads for P2P server, or auto for half the cores")
+     (@arg verify_threads: --("verify-threads") [INT] default_value("auto") "Sets the number of threads verifying the signatures of gossiped transactions, or auto for one per core")
+     (@arg miner_threads: --("miner-threads") [INT] default_value("1") "Sets the number of threads trying nonces, or auto for half the cores")
+     (@arg dust_threshold: --("dust-threshold") [INT] "Sets the minimum transaction value this node relays, defaults to the dust threshold of the chain parameters")
+     (@arg vnet: --vnet ... [PARAMS] "Hosts another network on the P2P port as well, given by its chain parameters file and optionally =<peer>,<peer> to connect to; connections are told apart by the genesis they are tagged with")
+     (@arg max_tx_size: --("max-tx-size") [INT] default_value("100000") "Sets the maximum serialized transaction size this node relays")
+     (@arg orphan_resolution: --("orphan-resolution") [STRATEGY] default_value("same-peer") "Sets whom to ask for missing parents of orphan blocks, same-peer or all-peers")
+     (@arg orphan_depth: --("orphan-depth") [INT] default_value("100") "Sets how many missing ancestors of an orphan block are requested before giving up")
+     (@arg max_orphans: --("max-orphans") [INT] default_value("1000") "Sets how many orphan blocks are kept waiting for their parent, the oldest are dropped first")
+     (@arg max_orphans_per_peer: --("max-orphans-per-peer") [INT] default_value("200") "Sets how many of the kept orphan blocks can come from a single peer")
+     (@arg chain_params: --("chain-params") [PATH] "Sets the chain parameters file written by genesis init, defaults to the built-in parameters")
+     (@arg encryption: --encryption [MODE] default_value("off") "Sets whether P2P connections are encrypted and authenticated with the key in node-<p2p port>.key of the data directory: off, prefer (plaintext with peers that do not support it) or require")
+     (@arg peer_key: --("peer-key") ... [PIN] "Pins a node key, in hex; with pins, a peer we connect to must prove one of them")
+     (@arg gossip_window: --("gossip-window") [INT] default_value("60000") "Sets how long in milliseconds a transaction is not announced again to a peer that sent it or was told about it")
+     (@arg reconcile_interval: --("reconcile-interval") [INT] default_value("2000") "Sets the interval in milliseconds between transaction set reconciliations with peers we connected to that support them, 0 floods every transaction announcement instead")
+     (@arg clock_skew_threshold: --("clock-skew-threshold") [INT] default_value("5000") "Sets how far in milliseconds the local clock may be from the median of the peers' clocks before this node warns")
+     (@arg adjust_clock: --("adjust-clock") "Timestamps mined blocks with the local time corrected by the median offset of the peers' clocks")
+     (@arg block_interval: --("block-interval") [INT] default_value("10000") "Sets the expected interval between blocks in milliseconds")
+     (@arg min_chain_work: --("min-chain-work") [WORK] default_value("0") "Sets the work the longest chain needs before this node serves blocks, 0 serves them right away")
+     (@arg alarm_warning: --("alarm-warning") [INT] default_value("3") "Sets the number of block intervals without a new tip before this node warns that block production stalled")
+     (@arg alarm_critical: --("alarm-critical") [INT] default_value("6") "Sets the number of block intervals without a new tip before this node reports itself unhealthy")
+     (@arg alarm_webhook: --("alarm-webhook") [URL] "Posts the health of block production to this http:// URL when it becomes or stops being critical")
+     (@arg stall_intervals: --("stall-intervals") [INT] default_value("6") "Sets the number of block intervals without a new tip before this node resyncs")
+     (@arg max_blocks_in_flight: --("max-blocks-in-flight") [INT] default_value("16") "Sets the number of blocks requested from one peer at a time during the initial sync")
+     (@arg block_stall_timeout: --("block-stall-timeout") [MS] default_value("5000") "Sets the time in milliseconds a peer has to send a requested block before another peer is asked during the initial sync")
+     (@arg block_request_timeout: --("block-request-timeout") [MS] default_value("5000") "Sets the time in milliseconds before a block already requested from one peer is requested again from another peer reporting it")
+     (@arg data_dir: --("data-dir") [PATH] default_value(".") "Sets the directory of the files this node writes")
+     (@arg resume_sync: --("resume-sync") "Keeps blocks and sync progress in the data directory, so a restarted node resumes syncing where it stopped")
+     (@arg send_rate_limit: --("send-rate-limit") [LIMIT] default_value("0.5/10") "Sets how many transactions each API client may submit through /wallet/send, as <rate per second>[/<burst>], or none; a client is named by its token parameter, or its IP address without one")
+     (@arg sign_responses: --("sign-responses") "Signs the API responses carrying chain data or balances with the key in node-<p2p port>.key of the data directory, with the tip they were served at, in X-Signature and related headers")
+     (@arg test_mode: --("test-mode") "Enables the /admin API endpoints that rewrite the chain, for test networks only")
+     (@arg checkpoint: --checkpoint [CHECKPOINT] "Syncs the blocks up to a trusted block of the longest chain without verifying their signatures, given as HEIGHT:HASH:STATE_ROOT as /blockchain/checkpoint of a trusted node shows it")
+     (@arg paranoid: --paranoid "Checks the chain, the state and the mempool after every change of the longest chain and aborts on the first inconsistency, replaying the whole chain each time")
+     (@arg prune_depth: --("prune-depth") [INT] "Keeps in memory only the block bodies at most INT blocks below the tip, older ones are read from disk with --resume-sync and dropped otherwise")
+     (@arg snapshot_dir: --("snapshot-dir") [PATH] "Publishes a snapshot of the chain and state, signed with the key in node-<p2p port>.key of the data directory, to this directory")
+     (@arg snapshot_interval: --("snapshot-interval") [INT] default_value("600000") "Sets the interval between snapshots in milliseconds")
+     (@arg bootstrap_from: --("bootstrap-from") [PATH] "Loads the chain from the snapshot in this directory at start, after checking its manifest")
+     (@arg snapshot_publisher: --("snapshot-publisher") [KEY] "Only bootstraps from a snapshot signed by this hex public key")
+     (@arg daemon: --daemon "Runs the node in the background, writing bitcoin-<p2p port>.pid, .status.json and .log to the data directory")
+     (@arg capture: --capture [PATH] "Records every inbound P2P message with its time and peer to this file, for the replay subcommand")
+     (@arg metrics_file: --("metrics-file") [PATH] "Sets the ring file recording the metrics history, defaults to metrics-<p2p port>.ring in the data directory")
+     (@arg metrics_capacity: --("metrics-capacity") [INT] default_value("86400") "Sets the number of samples kept in the metrics history")
+     (@arg metrics_interval: --("metrics-interval") [INT] default_value("1000") "Sets the interval between metrics samples in milliseconds")
+     (@arg max_block_memory: --("max-block-memory") [MIB] "Caps the memory of the block bodies, the oldest are dropped from memory over it and read back from disk with --resume-sync")
+     (@arg max_mempool_memory: --("max-mempool-memory") [MIB] "Caps the memory of the mempool, the transactions with the highest account nonce are dropped over it")
+     (@arg max_orphan_memory: --("max-orphan-memory") [MIB] "Caps the memory of the orphan blocks waiting for their parent")
+     (@arg max_cache_memory: --("max-cache-memory") [MIB] "Caps the memory of the record of which transactions each peer has seen, the oldest records are forgotten over it")
+     (@subcommand genesis =>
+        (about: "Manages the genesis of a private network")
+        (@subcommand init =>
+            (about: "Writes the chain parameters every node of a private network is started with")
+            (@arg difficulty: --difficulty [TARGET] default_value("000effffffffffffffffffffffffffffffffffffffffffffffffffffffffffff") "Sets the initial difficulty as 64 hex digits, zeros:<bits>, compact:<nBits> or a decimal difficulty")
+            (@arg retarget_interval: --("retarget-interval") [INT] default_value("0") "Sets the number of blocks between difficulty adjustments, 0 disables them")
+            (@arg target_block_time: --("target-block-time") [INT] default_value("10000") "Sets the block interval in milliseconds difficulty adjustments aim for")
+            (@arg pow: --pow [ALGORITHM] default_value("sha256") "Sets the proof of work hash, sha256 or the memory-hard argon2id:<KiB>:<passes>")
+            (@arg dust_threshold: --("dust-threshold") [INT] default_value("0") "Sets the minimum transaction value nodes relay unless started with their own threshold")
+            (@arg consensus_dust: --("consensus-dust") "Makes blocks with a transaction below the dust threshold invalid")
+            (@arg empty_accounts_from: --("empty-accounts-from") [HEIGHT] default_value("0") "Sets the height from which blocks leave empty accounts out and prune the empty ones that never sent, never to keep them all")
+            (@arg out: -o --out [PATH] default_value("chain_params.json") "Sets the file the chain parameters are written to")
+        )
+        (@subcommand export =>
+            (about: "Writes the chain parameters of a new network whose genesis holds the balances at the tip of a running node")
+            (@arg api_addr: --api [ADDR] default_value("127.0.0.1:7000") "Sets the API server address of the node")
+            (@arg out: -o --out [PATH] default_value("chain_params.json") "Sets the file the chain parameters are written to")
+        )
+     )
+     (@subcommand calibrate =>
+        (about: "Measures the hashrate of the miner on this machine and suggests the initial difficulty for a block interval")
+        (@arg target_block_time: --("target-block-time") [INT] default_value("10000") "Sets the block interval in milliseconds the difficulty aims for")
+        (@arg miners: --miners [INT] default_value("1") "Sets the number of nodes expected to mine, each as fast as this one")
+        (@arg lambda: --lambda [INT] default_value("0") "Sets the lambda in µs the miners will be started with")
+        (@arg threads: --threads [INT] default_value("1") "Sets the number of threads trying nonces the miners will be started with")
+        (@arg pow: --pow [ALGORITHM] default_value("sha256") "Sets the proof of work hash, sha256 or the memory-hard argon2id:<KiB>:<passes>")
+        (@arg seconds: --seconds [INT] default_value("5") "Sets how long the miner is measured")
+        (@arg out: -o --out [PATH] "Writes the chain parameters of a network with the suggested difficulty to PATH")
+     )
+     (@subcommand replay =>
+        (about: "Feeds the messages of a trace recorded with --capture through a fresh blockchain, with the default relay policy and orphan settings")
+        (@arg trace: --trace <PATH> "Sets the trace file to replay")
+        (@arg chain_params: --("chain-params") [PATH] "Sets the chain parameters file the capturing node was started with")
+        (@arg until: --until [INT] "Replays only the first INT messages, to bisect the message that triggers a bug")
+     )
+     (@subcommand compare =>
+        (about: "Compares the chains of two running nodes and reports the first height where they disagree")
+        (@arg api_addr: --api [ADDR] default_value("127.0.0.1:7000") "Sets the API server address of the first node")
+        (@arg with: --with <ADDR> "Sets the API server address of the node to compare with")
+        (@arg recent: --recent [INT] default_value("20") "Sets the number of most recent blocks compared")
+     )
+     (@subcommand compact =>
+        (about: "Rewrites the block store of a stopped node started with --resume-sync, dropping the side chains that can no longer become the longest chain")
+        (@arg peer_addr: --p2p [ADDR] default_value("127.0.0.1:6000") "Sets the P2P server address the node was started with")
+        (@arg data_dir: --("data-dir") [PATH] default_value(".") "Sets the data directory the node was started with")
+        (@arg chain_params: --("chain-params") [PATH] "Sets the chain parameters file the node was started with")
+        (@arg keep_forks: --("keep-forks") [INT] default_value("100") "Keeps the side chains whose tip is less than INT blocks below the tip")
+     )
+     (@subcommand selftest =>
+        (about: "Checks key generation, signatures, Merkle proofs, block validation, storage and the API server in-process and reports what passed")
+        (@arg dir: --dir [PATH] "Sets the directory the storage checks write to, in a subdirectory removed afterwards; defaults to the system temporary directory")
+        (@arg json: --json "Prints the report as JSON")
+     )
+     (@subcommand stop =>
+        (about: "Stops a node started with --daemon")
+        (@arg peer_addr: --p2p [ADDR] default_value("127.0.0.1:6000") "Sets the P2P server address the node was started with")
+        (@arg data_dir: --("data-dir") [PATH] default_value(".") "Sets the data directory the node was started with")
+     )
     )
     .get_matches();
 
@@ -38,6 +226,282 @@ fn main() {
     let verbosity = matches.occurrences_of("verbose") as usize;
     stderrlog::new().verbosity(verbosity).init().unwrap();
 
+    // write the chain parameters of a new private network and exit
+    if let Some(init) = matches.subcommand_matches("genesis").and_then(|g| g.subcommand_matches("init")) {
+        let initial_difficulty = H256::parse_target(init.value_of("difficulty").unwrap()).unwrap_or_else(|e| {
+            error!("Error parsing difficulty: {}", e);
+            process::exit(1);
+        });
+        let retarget_interval = init
+            .value_of("retarget_interval")
+            .unwrap()
+            .parse::<u32>()
+            .unwrap_or_else(|e| {
+                error!("Error parsing retarget interval: {}", e);
+                process::exit(1);
+            });
+        let target_block_time = init
+            .value_of("target_block_time")
+            .unwrap()
+            .parse::<u64>()
+            .unwrap_or_else(|e| {
+                error!("Error parsing target block time: {}", e);
+                process::exit(1);
+            });
+        let pow = init.value_of("pow").unwrap().parse::<PowAlgorithm>().unwrap_or_else(|e| {
+            error!("Error parsing proof of work: {}", e);
+            process::exit(1);
+        });
+        let dust_threshold = init
+            .value_of("dust_threshold")
+            .unwrap()
+            .parse::<Amount>()
+            .unwrap_or_else(|e| {
+                error!("Error parsing dust threshold: {}", e);
+                process::exit(1);
+            });
+        let consensus_dust = init.is_present("consensus_dust");
+        if consensus_dust && dust_threshold.is_zero() {
+            error!("--consensus-dust needs a --dust-threshold above 0");
+            process::exit(1);
+        }
+        let empty_accounts_from = match init.value_of("empty_accounts_from").unwrap() {
+            "never" => None,
+            height => Some(height.parse::<u32>().unwrap_or_else(|e| {
+                error!("Error parsing empty accounts height: {}", e);
+                process::exit(1);
+            })),
+        };
+        let params = ChainParams { initial_difficulty, retarget_interval, target_block_time, pow, dust_threshold, consensus_dust, empty_accounts_from, allocation: None };
+        if let Err(e) = params.check() {
+            error!("Error in chain parameters: {}", e);
+            process::exit(1);
+        }
+        let out = init.value_of("out").unwrap();
+        params.save(out).unwrap_or_else(|e| {
+            error!("Error writing chain parameters to {}: {}", out, e);
+            process::exit(1);
+        });
+        info!("Chain parameters written to {}", out);
+        return;
+    }
+
+    // snapshot the balances of a running node into the chain parameters of a new network and exit
+    if let Some(export) = matches.subcommand_matches("genesis").and_then(|g| g.subcommand_matches("export")) {
+        let api_addr = export.value_of("api_addr").unwrap().parse::<net::SocketAddr>().unwrap_or_else(|e| {
+            error!("Error parsing API server address: {}", e);
+            process::exit(1);
+        });
+        let params = diagnostics::fetch_export(api_addr).unwrap_or_else(|e| {
+            error!("Error asking {} for its state: {}", api_addr, e);
+            process::exit(1);
+        });
+        let out = export.value_of("out").unwrap();
+        params.save(out).unwrap_or_else(|e| {
+            error!("Error writing chain parameters to {}: {}", out, e);
+            process::exit(1);
+        });
+        info!("Chain parameters with {} accounts written to {}", params.allocation.map_or(0, |a| a.len()), out);
+        return;
+    }
+
+    // measure the hashrate of the miner, suggest an initial difficulty and exit
+    if let Some(calibrate) = matches.subcommand_matches("calibrate") {
+        let parse_u64 = |name: &str, what: &str| {
+            calibrate.value_of(name).unwrap().parse::<u64>().unwrap_or_else(|e| {
+                error!("Error parsing {}: {}", what, e);
+                process::exit(1);
+            })
+        };
+        let target_block_time = parse_u64("target_block_time", "target block time");
+        let miners = parse_u64("miners", "miners") as u32;
+        let lambda = parse_u64("lambda", "lambda");
+        let threads = parse_u64("threads", "threads") as usize;
+        let seconds = parse_u64("seconds", "seconds");
+        let pow = calibrate.value_of("pow").unwrap().parse::<PowAlgorithm>().unwrap_or_else(|e| {
+            error!("Error parsing proof of work: {}", e);
+            process::exit(1);
+        });
+        if target_block_time == 0 || miners == 0 || seconds == 0 {
+            error!("The target block time, miners and seconds must be above 0");
+            process::exit(1);
+        }
+        #[cfg(feature = "miner")]
+        {
+            let params = ChainParams { target_block_time, pow, ..Default::default() };
+            info!("Measuring the miner for {} s", seconds);
+            let hashrate = miner::calibrate::benchmark(&params, lambda, threads, time::Duration::from_secs(seconds));
+            let calibration = miner::calibrate::Calibration::new(hashrate, miners, target_block_time);
+            if let Some(out) = calibrate.value_of("out") {
+                let params = ChainParams { initial_difficulty: calibration.target(), ..params };
+                params.save(out).unwrap_or_else(|e| {
+                    error!("Error writing chain parameters to {}: {}", out, e);
+                    process::exit(1);
+                });
+                info!("Chain parameters written to {}", out);
+            }
+            println!("{}", serde_json::to_string_pretty(&calibration).unwrap());
+        }
+        #[cfg(not(feature = "miner"))]
+        {
+            let _ = (lambda, threads, pow);
+            error!("Calibrating needs the miner, build with the miner feature");
+            process::exit(1);
+        }
+        return;
+    }
+
+    // replay a captured trace offline and exit
+    if let Some(replay) = matches.subcommand_matches("replay") {
+        let path = replay.value_of("trace").unwrap();
+        let mut records = network::trace::read(path).unwrap_or_else(|e| {
+            error!("Error reading trace {}: {}", path, e);
+            process::exit(1);
+        });
+        if let Some(until) = replay.value_of("until") {
+            let until = until.parse::<usize>().unwrap_or_else(|e| {
+                error!("Error parsing until: {}", e);
+                process::exit(1);
+            });
+            records.truncate(until);
+        }
+        let chain_params = match replay.value_of("chain_params") {
+            Some(path) => ChainParams::load(path).unwrap_or_else(|e| {
+                error!("Error loading chain parameters from {}: {}", path, e);
+                process::exit(1);
+            }),
+            None => ChainParams::default(),
+        };
+        let mut blockchain = Blockchain::with_params(0, &chain_params);
+        blockchain.set_validators(validators::network_rules());
+        let blockchain = Arc::new(Mutex::new(blockchain));
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        // the workers are not started, the messages are handled one by one on this thread
+        let worker = network::worker::Worker::offline(&blockchain, &mempool);
+        network::trace::replay(&records, &worker);
+        let blockchain = blockchain.lock().unwrap();
+        info!(
+            "Replayed {} messages: tip {} at height {}, {} transactions in the mempool",
+            records.len(),
+            blockchain.tip(),
+            blockchain.height(),
+            mempool.lock().unwrap().hash_map.len()
+        );
+        return;
+    }
+
+    // compare the chains of two running nodes and exit
+    if let Some(compare) = matches.subcommand_matches("compare") {
+        let parse_addr = |name: &str| {
+            compare.value_of(name).unwrap().parse::<net::SocketAddr>().unwrap_or_else(|e| {
+                error!("Error parsing API server address: {}", e);
+                process::exit(1);
+            })
+        };
+        let (local, peer) = (parse_addr("api_addr"), parse_addr("with"));
+        let recent = compare.value_of("recent").unwrap().parse::<usize>().unwrap_or_else(|e| {
+            error!("Error parsing recent: {}", e);
+            process::exit(1);
+        });
+        let fetch = |addr| {
+            diagnostics::fetch_summary(addr, recent).unwrap_or_else(|e| {
+                error!("Error asking {} for its chain: {}", addr, e);
+                process::exit(1);
+            })
+        };
+        let report = diagnostics::compare(&fetch(local), &fetch(peer));
+        println!("{}", serde_json::to_string_pretty(&report).unwrap());
+        return;
+    }
+
+    // drop stale forks from the block store of a stopped node and exit
+    if let Some(compact) = matches.subcommand_matches("compact") {
+        let p2p_addr = compact
+            .value_of("peer_addr")
+            .unwrap()
+            .parse::<net::SocketAddr>()
+            .unwrap_or_else(|e| {
+                error!("Error parsing P2P server address: {}", e);
+                process::exit(1);
+            });
+        let keep_forks = compact.value_of("keep_forks").unwrap().parse::<u32>().unwrap_or_else(|e| {
+            error!("Error parsing keep forks: {}", e);
+            process::exit(1);
+        });
+        let chain_params = match compact.value_of("chain_params") {
+            Some(path) => ChainParams::load(path).unwrap_or_else(|e| {
+                error!("Error loading chain parameters from {}: {}", path, e);
+                process::exit(1);
+            }),
+            None => ChainParams::default(),
+        };
+        let data_dir = Path::new(compact.value_of("data_dir").unwrap());
+        if DaemonFiles::new(data_dir, p2p_addr.port()).pid().exists() {
+            error!("Node {} is running in the background, stop it first", p2p_addr);
+            process::exit(1);
+        }
+        let headers_path = data_dir.join(format!("headers-{}.dat", p2p_addr.port()));
+        let bodies_path = data_dir.join(format!("bodies-{}.dat", p2p_addr.port()));
+        if !headers_path.exists() {
+            error!("No block store at {}", headers_path.display());
+            process::exit(1);
+        }
+        let report = compact::compact(&headers_path, &bodies_path, &chain_params, keep_forks).unwrap_or_else(|e| {
+            error!("Error compacting block store {}: {}", headers_path.display(), e);
+            process::exit(1);
+        });
+        info!(
+            "Compacted {} from {} to {} blocks, {} to {} bytes",
+            headers_path.display(),
+            report.blocks_before,
+            report.blocks_after,
+            report.bytes_before,
+            report.bytes_after
+        );
+        println!("{}", serde_json::to_string_pretty(&report).unwrap());
+        return;
+    }
+
+    // check that this build works on this machine and exit
+    if let Some(selftest) = matches.subcommand_matches("selftest") {
+        let dir = selftest.value_of("dir").map(std::path::PathBuf::from).unwrap_or_else(std::env::temp_dir);
+        let report = selftest::run_in(&dir).unwrap_or_else(|e| {
+            error!("Error preparing the self-test in {}: {}", dir.display(), e);
+            process::exit(1);
+        });
+        if selftest.is_present("json") {
+            println!("{}", serde_json::to_string_pretty(&report).unwrap());
+        } else {
+            // the blockchain prints as it validates, start the report on a line of its own
+            println!("\n{}", report);
+        }
+        if !report.passed() {
+            process::exit(1);
+        }
+        return;
+    }
+
+    // stop a node running in the background and exit
+    if let Some(stop) = matches.subcommand_matches("stop") {
+        let p2p_addr = stop
+            .value_of("peer_addr")
+            .unwrap()
+            .parse::<net::SocketAddr>()
+            .unwrap_or_else(|e| {
+                error!("Error parsing P2P server address: {}", e);
+                process::exit(1);
+            });
+        let files = DaemonFiles::new(stop.value_of("data_dir").unwrap(), p2p_addr.port());
+        match daemon::stop(&files) {
+            Ok(pid) => info!("Stopped node {} with pid {}", p2p_addr, pid),
+            Err(e) => {
+                error!("Error stopping node with pid file {}: {}", files.pid().display(), e);
+                process::exit(1);
+            }
+        }
+        return;
+    }
+
     // parse p2p server address
     let p2p_addr = matches
         .value_of("peer_addr")
@@ -47,6 +511,27 @@ fn main() {
             error!("Error parsing P2P server address: {}", e);
             process::exit(1);
         });
+
+    // move to the background, the copy started there runs the node
+    let data_dir = Path::new(matches.value_of("data_dir").unwrap());
+    std::fs::create_dir_all(data_dir).unwrap_or_else(|e| {
+        error!("Error creating data directory {}: {}", data_dir.display(), e);
+        process::exit(1);
+    });
+    let daemon_files = DaemonFiles::new(data_dir, p2p_addr.port());
+    if matches.is_present("daemon") && !daemon::is_daemon() {
+        match daemon::spawn(&daemon_files) {
+            Ok(pid) => {
+                info!("Node running in the background with pid {}, logging to {}", pid, daemon_files.log().display());
+                return;
+            }
+            Err(e) => {
+                error!("Error starting the node in the background: {}", e);
+                process::exit(1);
+            }
+        }
+    }
+
     let mut seed = 0;
     if p2p_addr == "127.0.0.1:6000".parse::<net::SocketAddr>().unwrap() {
         seed = 0;
@@ -59,7 +544,58 @@ fn main() {
     }
     print!("{}", seed);
 
-    let blockchain = Blockchain::new(seed);
+    // load the parameters shared by every node of this network
+    let chain_params = match matches.value_of("chain_params") {
+        Some(path) => ChainParams::load(path).unwrap_or_else(|e| {
+            error!("Error loading chain parameters from {}: {}", path, e);
+            process::exit(1);
+        }),
+        None => ChainParams::default(),
+    };
+
+    let mut blockchain = Blockchain::with_params(seed, &chain_params);
+    // extension projects add their own consensus rules in network_rules
+    blockchain.set_validators(validators::network_rules());
+    blockchain.set_paranoid(matches.is_present("paranoid"));
+    if let Some(checkpoint) = matches.value_of("checkpoint") {
+        let checkpoint = checkpoint.parse::<Checkpoint>().unwrap_or_else(|e| {
+            error!("Error parsing checkpoint: {}", e);
+            process::exit(1);
+        });
+        info!("Syncing up to block {} at height {} without verifying signatures", checkpoint.hash, checkpoint.height);
+        blockchain.set_checkpoint(checkpoint);
+    }
+    // the genesis block identifies the network
+    let chain_id = blockchain.tip();
+    let resume_sync = matches.is_present("resume_sync");
+    if resume_sync {
+        let headers_path = data_dir.join(format!("headers-{}.dat", p2p_addr.port()));
+        let bodies_path = data_dir.join(format!("bodies-{}.dat", p2p_addr.port()));
+        let (store, blocks) = BlockStore::open(&headers_path, &bodies_path).unwrap_or_else(|e| {
+            error!("Error opening block store {}: {}", headers_path.display(), e);
+            process::exit(1);
+        });
+        info!("Restoring {} blocks from {}", blocks.len(), headers_path.display());
+        blockchain.attach_store(store, blocks);
+    }
+    if let Some(dir) = matches.value_of("bootstrap_from") {
+        let (manifest, blocks) = snapshot::load(dir, matches.value_of("snapshot_publisher")).unwrap_or_else(|e| {
+            error!("Error loading snapshot from {}: {}", dir, e);
+            process::exit(1);
+        });
+        snapshot::restore(&mut blockchain, &manifest, &blocks).unwrap_or_else(|e| {
+            error!("Error bootstrapping from snapshot {}: {}", dir, e);
+            process::exit(1);
+        });
+        info!("Bootstrapped to height {} from snapshot published by {}", manifest.height, manifest.publisher);
+    }
+    if let Some(depth) = matches.value_of("prune_depth") {
+        let depth = depth.parse::<u32>().unwrap_or_else(|e| {
+            error!("Error parsing prune depth: {}", e);
+            process::exit(1);
+        });
+        blockchain.set_prune_depth(depth);
+    }
     let mempool = Mempool::new();
     let blockchain = Arc::new(Mutex::new(blockchain));
     let mempool = Arc::new(Mutex::new(mempool));
@@ -74,85 +610,799 @@ fn main() {
             process::exit(1);
         });
 
+    // parse websocket server address
+    let ws_addr = matches.value_of("ws_addr").map(|addr| {
+        addr.parse::<net::SocketAddr>().unwrap_or_else(|e| {
+            error!("Error parsing WebSocket server address: {}", e);
+            process::exit(1);
+        })
+    });
+
     // create channels between server and worker
     let (msg_tx, msg_rx) = channel::bounded(10000);
+    // the workers read the inbound messages after they are recorded
+    let msg_rx = match matches.value_of("capture") {
+        Some(path) => {
+            let writer = TraceWriter::create(path).unwrap_or_else(|e| {
+                error!("Error creating trace {}: {}", path, e);
+                process::exit(1);
+            });
+            info!("Capturing inbound messages to {}", path);
+            network::trace::capture(writer, msg_rx)
+        }
+        None => msg_rx,
+    };
 
-    // start the p2p server
-    let (server_ctx, server) = network::server::new(p2p_addr, msg_tx).unwrap();
-    server_ctx.start().unwrap();
+    // events of this node are fanned out to the websocket clients
+    let events = EventBus::new();
+    {blockchain.lock().unwrap().set_events(&events)};
+
+    // post events to the webhooks, from the start so no connection is missed
+    let webhook_secret = matches.value_of("webhook_secret");
+    let parse_webhook = |spec: &str| {
+        let webhook = Webhook::parse(spec).unwrap_or_else(|e| {
+            error!("Error parsing webhook {}: {}", spec, e);
+            process::exit(1);
+        });
+        match webhook_secret {
+            Some(secret) => webhook.with_secret(secret.as_bytes()),
+            None => webhook,
+        }
+    };
+    let webhooks: Vec<Webhook> = matches.values_of("webhook").into_iter().flatten().map(parse_webhook).collect();
+    let webhook_retries = matches
+        .value_of("webhook_retries")
+        .unwrap()
+        .parse::<u32>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing webhook retries: {}", e);
+            process::exit(1);
+        });
+    Notifier::new(webhooks, webhook_retries, &events).start();
+
+    // protocol state of every peer, advanced by the workers and drained by the server
+    let peer_states = Arc::new(Mutex::new(PeerStates::new()));
+
+    // transactions each peer has recently seen, so they are not announced to it again
+    let gossip_window = matches
+        .value_of("gossip_window")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing gossip window: {}", e);
+            process::exit(1);
+        });
+    let gossip = Arc::new(Mutex::new(RecentlyAnnounced::new(time::Duration::from_millis(gossip_window))));
+    let reconcile_interval = matches
+        .value_of("reconcile_interval")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing reconcile interval: {}", e);
+            process::exit(1);
+        });
+    let reconciler = Arc::new(Mutex::new(if reconcile_interval == 0 { Reconciler::disabled() } else { Reconciler::new() }));
+    let clock_skew_threshold = matches
+        .value_of("clock_skew_threshold")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing clock skew threshold: {}", e);
+            process::exit(1);
+        });
+    let clock = Arc::new(Mutex::new(NetworkClock::new(
+        time::Duration::from_millis(clock_skew_threshold),
+        matches.is_present("adjust_clock"),
+    )));
+
+    // limit the messages of each peer, defaults overridden by --rate-limit
+    let mut rate_limiter = RateLimiter::with_default_limits();
+    for spec in matches.values_of("rate_limit").into_iter().flatten() {
+        let (message, limit) = ratelimit::parse_limit(spec).unwrap_or_else(|e| {
+            error!("Error parsing rate limit: {}", e);
+            process::exit(1);
+        });
+        rate_limiter.set_limit(&message, limit);
+    }
+    let rate_limiter = Arc::new(Mutex::new(rate_limiter));
+
+    // parse how orphan blocks are resolved
+    let orphan_strategy = matches
+        .value_of("orphan_resolution")
+        .unwrap()
+        .parse::<OrphanStrategy>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing orphan resolution: {}", e);
+            process::exit(1);
+        });
+    let orphan_depth = matches
+        .value_of("orphan_depth")
+        .unwrap()
+        .parse::<u32>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing orphan depth: {}", e);
+            process::exit(1);
+        });
+    let orphan_limit = |name: &str, arg: &str| {
+        matches.value_of(arg).unwrap().parse::<usize>().unwrap_or_else(|e| {
+            error!("Error parsing {}: {}", name, e);
+            process::exit(1);
+        })
+    };
+    let orphan_limits = OrphanLimits {
+        max_count: orphan_limit("max orphans", "max_orphans"),
+        max_per_peer: orphan_limit("max orphans per peer", "max_orphans_per_peer"),
+        max_bytes: None,
+    };
+    let orphans = Arc::new(Mutex::new(OrphanResolver::new(orphan_strategy, orphan_depth)));
+    {orphans.lock().unwrap().set_limits(orphan_limits)};
+
+    let peer_tips = Arc::new(Mutex::new(PeerTips::new()));
+    let peer_features = Arc::new(Mutex::new(PeerFeatures::new()));
+
+    // the node state the P2P server, its workers and the API server share
+    let shared = network::shared::Shared {
+        blockchain: Arc::clone(&blockchain),
+        mempool: Arc::clone(&mempool),
+        peer_states: Arc::clone(&peer_states),
+        peer_tips: Arc::clone(&peer_tips),
+        peer_features: Arc::clone(&peer_features),
+        orphans: Arc::clone(&orphans),
+        gossip: Arc::clone(&gossip),
+        reconciler: Arc::clone(&reconciler),
+        clock: Arc::clone(&clock),
+        rate_limiter: Arc::clone(&rate_limiter),
+    };
+
+    // start the p2p server, encrypting its connections if asked to
+    let encryption = network::encryption::Mode::parse(matches.value_of("encryption").unwrap()).unwrap_or_else(|e| {
+        error!("Error parsing encryption: {}", e);
+        process::exit(1);
+    });
+    let (server_ctx, server) =
+        network::server::new(p2p_addr, msg_tx, &shared, &events).unwrap();
+    let server_ctx = if encryption == network::encryption::Mode::Off {
+        if matches.is_present("peer_key") {
+            error!("Error parsing peer key: pinned keys are only checked with --encryption prefer or require");
+            process::exit(1);
+        }
+        server_ctx
+    } else {
+        let key_path = data_dir.join(format!("node-{}.key", p2p_addr.port()));
+        let key = key_pair::load_or_generate(&key_path).unwrap_or_else(|e| {
+            error!("Error loading node key {}: {}", key_path.display(), e);
+            process::exit(1);
+        });
+        info!("Encrypting P2P connections as {}", hex::encode(key.public_key()));
+        let pins = matches
+            .values_of("peer_key")
+            .into_iter()
+            .flatten()
+            .map(network::encryption::parse_pin)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap_or_else(|e| {
+                error!("Error parsing peer key: {}", e);
+                process::exit(1);
+            });
+        server_ctx.with_encryption(encryption, key).with_key_pins(pins)
+    };
+    // the peers we dial, kept across restarts
+    let max_redial_backoff = matches
+        .value_of("max_redial_backoff")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing max redial backoff: {}", e);
+            process::exit(1);
+        });
+    let address_book_path = data_dir.join(format!("peers-{}.json", p2p_addr.port()));
+    let address_book = network::addrbook::AddressBook::load(&address_book_path, time::Duration::from_millis(max_redial_backoff))
+        .unwrap_or_else(|e| {
+            error!("Error loading address book {}: {}", address_book_path.display(), e);
+            process::exit(1);
+        });
+    let address_book = Arc::new(Mutex::new(address_book));
+    let server_ctx = server_ctx.with_address_book(&address_book);
+    let ban_list_path = data_dir.join(format!("bans-{}.json", p2p_addr.port()));
+    let ban_list = BanList::load(&ban_list_path, network::banlist::now()).unwrap_or_else(|e| {
+        error!("Error loading ban list {}: {}", ban_list_path.display(), e);
+        process::exit(1);
+    });
+    let ban_list = Arc::new(Mutex::new(ban_list));
+    let server_ctx = server_ctx.with_ban_list(&ban_list);
+    let dust_filters = Arc::new(Mutex::new(DustFilters::new()));
+    let server_ctx = server_ctx.with_dust_filters(&dust_filters, &mempool);
+    let server_ctx = match matches.value_of("p2p_ws_addr") {
+        Some(addr) => server_ctx.with_websocket(addr.parse::<net::SocketAddr>().unwrap_or_else(|e| {
+            error!("Error parsing P2P WebSocket address: {}", e);
+            process::exit(1);
+        })),
+        None => server_ctx,
+    };
+    let server_ctx = server_ctx.with_websocket_json(matches.is_present("p2p_ws_json"));
+    let push_blocks_to: Vec<net::SocketAddr> = matches
+        .values_of("push_blocks_to")
+        .map(|peers| {
+            peers
+                .map(|peer| {
+                    peer.parse::<net::SocketAddr>().unwrap_or_else(|e| {
+                        error!("Error parsing block push peer {}: {}", peer, e);
+                        process::exit(1);
+                    })
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    let server_ctx = server_ctx.with_block_push(push_blocks_to);
+    let parse_fanout = |name: &str, what: &str| match matches.value_of(name).unwrap() {
+        "all" => None,
+        fanout => Some(fanout.parse::<usize>().unwrap_or_else(|e| {
+            error!("Error parsing {} fanout: {}", what, e);
+            process::exit(1);
+        })),
+    };
+    let fanout = Fanout { blocks: parse_fanout("block_fanout", "block"), transactions: parse_fanout("tx_fanout", "transaction") };
+    let server_ctx = server_ctx.with_fanout(fanout);
+    let compression = !matches.is_present("no_compression");
+    let server_ctx = server_ctx.with_compression(compression);
+    let network_magic = matches.value_of("network_magic").map(|magic| {
+        magic.parse::<NetworkMagic>().unwrap_or_else(|e| {
+            error!("Error parsing network magic: {}", e);
+            process::exit(1);
+        })
+    });
+    let server_ctx = server_ctx.with_magic(network_magic);
+    // round trip times and the links peers report, for the topology map and the block downloads
+    let router = Arc::new(Mutex::new(RequestRouter::new()));
+    // when each peer last delivered something new, with the round trip times to pick whom to evict
+    let last_useful = Arc::new(Mutex::new(LastUseful::new()));
+    let parse_limit = |name: &str, what: &str| {
+        matches.value_of(name).unwrap().parse::<usize>().unwrap_or_else(|e| {
+            error!("Error parsing max {}: {}", what, e);
+            process::exit(1);
+        })
+    };
+    let max_outbound = parse_limit("max_outbound", "outbound");
+    let limits = ConnectionLimits { max_inbound: parse_limit("max_inbound", "inbound"), max_outbound };
+    let server_ctx = server_ctx.with_connection_limits(limits, &router, &last_useful);
+    let tx_relay_delay = matches
+        .value_of("tx_relay_delay")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing transaction relay delay: {}", e);
+            process::exit(1);
+        });
+    let server_ctx = if tx_relay_delay > 0 {
+        let relay_delay = Arc::new(Mutex::new(RelayDelay::new(time::Duration::from_millis(tx_relay_delay))));
+        network::gossip::start_relay(time::Duration::from_millis(50), &relay_delay, &server);
+        server_ctx.with_relay_delay(&relay_delay)
+    } else {
+        server_ctx
+    };
+
+    let tx_pause = match matches.value_of("tx_pause_behind").unwrap() {
+        "off" => None,
+        behind => Some(Arc::new(Mutex::new(TxGossipPause::new(behind.parse::<u32>().unwrap_or_else(|e| {
+            error!("Error parsing transaction pause: {}", e);
+            process::exit(1);
+        }))))),
+    };
+
+    // size the thread pools, they can be resized through the API while the node runs
+    let mut thread_pools = ThreadPools::new(threads::cores());
+    let parse_threads = |name: &str, what: &str| {
+        matches.value_of(name).unwrap().parse::<ThreadCount>().unwrap_or_else(|e| {
+            error!("Error parsing {}: {}", what, e);
+            process::exit(1);
+        })
+    };
+    let p2p_worker_count = parse_threads("p2p_workers", "P2P workers");
+    let verify_thread_count = parse_threads("verify_threads", "verify threads");
+    #[cfg(feature = "miner")]
+    let miner_thread_count = parse_threads("miner_threads", "miner threads");
 
     // start the worker
-    let p2p_workers = matches
-        .value_of("p2p_workers")
+    let p2p_workers = thread_pools.threads(PoolKind::P2pWorkers, p2p_worker_count);
+
+    // parse the relay policy of this node
+    let mut dust_threshold = match matches.value_of("dust_threshold") {
+        Some(threshold) => threshold.parse::<Amount>().unwrap_or_else(|e| {
+            error!("Error parsing dust threshold: {}", e);
+            process::exit(1);
+        }),
+        None => chain_params.dust_threshold,
+    };
+    // relaying what no block can contain would only fill mempools
+    if dust_threshold < chain_params.min_tx_value() {
+        warn!(
+            "Dust threshold {} is below the {} of the chain parameters, relaying from {}",
+            dust_threshold,
+            chain_params.min_tx_value(),
+            chain_params.min_tx_value()
+        );
+        dust_threshold = chain_params.min_tx_value();
+    }
+    let max_tx_size = matches
+        .value_of("max_tx_size")
         .unwrap()
         .parse::<usize>()
         .unwrap_or_else(|e| {
-            error!("Error parsing P2P workers: {}", e);
+            error!("Error parsing max transaction size: {}", e);
             process::exit(1);
         });
-    let worker_ctx = network::worker::Worker::new(
-        p2p_workers,
-        msg_rx,
+    let relay_policy = RelayPolicy {
+        dust_threshold,
+        max_tx_size,
+    };
+
+    // host the networks of --vnet next to ours, on the same P2P port
+    let mut server_ctx = server_ctx;
+    let mut vnets: Vec<(VirtualNetwork, Vec<net::SocketAddr>)> = Vec::new();
+    for spec in matches.values_of("vnet").into_iter().flatten() {
+        let (params_path, peers) = match spec.split_once('=') {
+            Some((params_path, peers)) => (params_path, peers.split(',').collect()),
+            None => (spec, Vec::new()),
+        };
+        let params = ChainParams::load(params_path).unwrap_or_else(|e| {
+            error!("Error loading chain parameters {}: {}", params_path, e);
+            process::exit(1);
+        });
+        let peers = peers
+            .into_iter()
+            .map(|peer| peer.parse::<net::SocketAddr>())
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap_or_else(|e| {
+                error!("Error parsing peer address of {}: {}", spec, e);
+                process::exit(1);
+            });
+        let key = if encryption == network::encryption::Mode::Off {
+            None
+        } else {
+            let key_path = data_dir.join(format!("node-{}.key", p2p_addr.port()));
+            let key = key_pair::load_or_generate(&key_path).unwrap_or_else(|e| {
+                error!("Error loading node key {}: {}", key_path.display(), e);
+                process::exit(1);
+            });
+            Some((encryption, key))
+        };
+        let policy = RelayPolicy { dust_threshold: params.dust_threshold.max(params.min_tx_value()), max_tx_size };
+        let vnet = VirtualNetwork::start(&params, p2p_addr, p2p_workers, &policy, &clock, key, &ban_list).unwrap_or_else(|e| {
+            error!("Error starting the network of {}: {}", params_path, e);
+            process::exit(1);
+        });
+        // the genesis tells the networks apart, it only depends on the initial difficulty and allocation
+        let genesis = {blockchain.lock().unwrap().genesis()};
+        if vnet.genesis == genesis || vnets.iter().any(|(other, _)| other.genesis == vnet.genesis) {
+            error!("Error starting the network of {}: another network has genesis {}, give it another initial difficulty or allocation", params_path, vnet.genesis);
+            process::exit(1);
+        }
+        server_ctx = server_ctx.with_route(vnet.genesis, &vnet.server);
+        vnets.push((vnet, peers));
+    }
+    server_ctx.start().unwrap();
+    if reconcile_interval > 0 {
+        network::reconcile::start_initiator(time::Duration::from_millis(reconcile_interval), &server, &reconciler);
+    }
+    for (vnet, peers) in vnets {
+        thread::Builder::new()
+            .name(format!("vnet-{:.8}", vnet.genesis))
+            .spawn(move || {
+                for peer in peers {
+                    if let Err(e) = vnet.server.connect(peer) {
+                        warn!("Error connecting to peer {} of the network with genesis {}: {}", peer, vnet.genesis, e);
+                    }
+                }
+            })
+            .unwrap();
+    }
+
+    // parse how much work the chain needs before the initial sync is complete
+    let min_chain_work = matches
+        .value_of("min_chain_work")
+        .unwrap()
+        .parse::<f64>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing min chain work: {}", e);
+            process::exit(1);
+        });
+    let sync_gate = Arc::new(Mutex::new(SyncGate::new(min_chain_work)));
+
+    // verify the signatures of gossiped transactions on threads of their own
+    let verifier = VerifierPool::start(thread_pools.threads(PoolKind::Verifiers, verify_thread_count), 10000);
+
+    // open the metrics history, the P2P workers record the experiment markers of peers in it
+    let metrics_file = match matches.value_of("metrics_file") {
+        Some(path) => path.to_owned(),
+        None => data_dir.join(format!("metrics-{}.ring", p2p_addr.port())).display().to_string(),
+    };
+    let metrics_capacity = matches
+        .value_of("metrics_capacity")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing metrics capacity: {}", e);
+            process::exit(1);
+        });
+    if metrics_capacity == 0 {
+        error!("The metrics capacity must be above 0");
+        process::exit(1);
+    }
+    let metrics_history = MetricsHistory::open(&metrics_file, metrics_capacity).unwrap_or_else(|e| {
+        error!("Error opening metrics history {}: {}", &metrics_file, e);
+        process::exit(1);
+    });
+    let metrics_history = Arc::new(Mutex::new(metrics_history));
+    // size and validation time of the blocks received from peers
+    let block_metrics = Arc::new(Mutex::new(BlockMetricsLog::default()));
+    // which peer delivered each block and transaction first
+    let first_seen = Arc::new(Mutex::new(FirstSeen::default()));
+
+    // spread the blocks missing during the initial sync over the ready peers
+    let max_blocks_in_flight = matches
+        .value_of("max_blocks_in_flight")
+        .unwrap()
+        .parse::<usize>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing max blocks in flight: {}", e);
+            process::exit(1);
+        });
+    let block_stall_timeout = matches
+        .value_of("block_stall_timeout")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing block stall timeout: {}", e);
+            process::exit(1);
+        });
+    let downloads = Arc::new(Mutex::new(DownloadScheduler::new(
+        max_blocks_in_flight,
+        time::Duration::from_millis(block_stall_timeout),
+    )));
+    let block_request_timeout = matches
+        .value_of("block_request_timeout")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing block request timeout: {}", e);
+            process::exit(1);
+        });
+    let block_requests = Arc::new(Mutex::new(InFlightBlocks::new(time::Duration::from_millis(block_request_timeout))));
+
+    network::routing::start(time::Duration::from_millis(500), &router, &peer_states, &server);
+    network::download::start(time::Duration::from_millis(500), &downloads, &peer_states, &router, &server);
+    let topology = Arc::new(Mutex::new(Topology::new(p2p_addr)));
+    // chain proofs asked of peers through the API
+    let chain_proofs = Arc::new(Mutex::new(ChainProofRequests::new()));
+
+    // ping every peer, dropping the ones that stopped answering
+    let keepalive_interval = matches
+        .value_of("keepalive_interval")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing keepalive interval: {}", e);
+            process::exit(1);
+        });
+    let keepalive_misses = matches
+        .value_of("keepalive_misses")
+        .unwrap()
+        .parse::<u32>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing keepalive misses: {}", e);
+            process::exit(1);
+        });
+    let keepalive = Arc::new(Mutex::new(KeepAlive::new(keepalive_misses)));
+    network::keepalive::start(time::Duration::from_millis(keepalive_interval), &keepalive, &peer_states, &server);
+    let worker_ctx = network::worker::Worker::new(p2p_workers, msg_rx, &server, &shared, &relay_policy)
+    .with_sync_gate(&sync_gate)
+    .with_verifier(&verifier)
+    .with_address_book(&address_book, p2p_addr)
+    .with_metrics(&metrics_history)
+    .with_block_metrics(&block_metrics)
+    .with_first_seen(&first_seen)
+    .with_last_useful(&last_useful)
+    .with_downloads(&downloads)
+    .with_dust_filters(&dust_filters)
+    .with_router(&router)
+    .with_block_requests(&block_requests)
+    .with_topology(&topology)
+    .with_keepalive(&keepalive)
+    .with_chain_proofs(&chain_proofs)
+    .with_compression(compression);
+    let worker_ctx = match &tx_pause {
+        Some(tx_pause) => worker_ctx.with_tx_pause(tx_pause),
+        None => worker_ctx,
+    };
+    let p2p_worker_pool = worker_ctx.start();
+    thread_pools.add(PoolKind::P2pWorkers, p2p_worker_count, &p2p_worker_pool);
+    thread_pools.add(PoolKind::Verifiers, verify_thread_count, &verifier.thread_pool());
+
+    // start watching for a stalled tip
+    let block_interval = matches
+        .value_of("block_interval")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing block interval: {}", e);
+            process::exit(1);
+        });
+    let stall_intervals = matches
+        .value_of("stall_intervals")
+        .unwrap()
+        .parse::<u32>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing stall intervals: {}", e);
+            process::exit(1);
+        });
+    let sync_monitor = network::sync::Monitor::new(
+        time::Duration::from_millis(block_interval),
+        stall_intervals,
         &server,
         &blockchain,
-        &mempool
-    );
-    worker_ctx.start();
+        &peer_tips,
+        &orphans,
+        &events,
+    )
+    .with_sync_gate(&sync_gate);
+    let sync_monitor = if resume_sync {
+        sync_monitor.with_checkpoint(data_dir.join(format!("sync-{}.json", p2p_addr.port())))
+    } else {
+        sync_monitor
+    };
+    sync_monitor.start();
+
+    // start raising alarms when no block arrives for several intervals
+    let alarm_warning = matches
+        .value_of("alarm_warning")
+        .unwrap()
+        .parse::<u32>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing alarm warning intervals: {}", e);
+            process::exit(1);
+        });
+    let alarm_critical = matches
+        .value_of("alarm_critical")
+        .unwrap()
+        .parse::<u32>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing alarm critical intervals: {}", e);
+            process::exit(1);
+        });
+    if alarm_warning == 0 || alarm_warning >= alarm_critical {
+        error!("Error parsing alarm intervals: the warning must come after at least one interval and before the critical alarm");
+        process::exit(1);
+    }
+    let alarm_webhook = matches.value_of("alarm_webhook").map(parse_webhook);
+    let block_watch = {
+        let blockchain = blockchain.lock().unwrap();
+        Arc::new(Mutex::new(BlockWatch::new(
+            time::Duration::from_millis(block_interval),
+            AlarmThresholds { warning: alarm_warning, critical: alarm_critical },
+            blockchain.tip(),
+            blockchain.height(),
+            time::Instant::now(),
+        )))
+    };
+    watchdog::worker::Worker::new(
+        &block_watch,
+        time::Duration::from_millis(block_interval.clamp(1, 1000)),
+        &blockchain,
+        &events,
+        alarm_webhook,
+    )
+    .start();
 
-    // start the transaction generator
-    let (tx_ctx, tx, finished_tx_chan) = txgen::new(&blockchain, &mempool, seed);
-    let tx_worker_ctx = txgen::worker::Worker::new(&server, finished_tx_chan, &blockchain, &mempool);
-    tx_ctx.start();
-    tx_worker_ctx.start();
+    // start the transaction generator, it is only ever started through the API
+    #[cfg(feature = "api")]
+    let (tx_ctx, tx, finished_tx_chan) = txgen::new(&blockchain, &mempool, seed, &relay_policy);
+    #[cfg(feature = "api")]
+    {
+        let tx_worker_ctx = txgen::worker::Worker::new(&server, finished_tx_chan, &blockchain, &mempool);
+        tx_ctx.start();
+        tx_worker_ctx.start();
+    }
 
     // start the miner
-    let (miner_ctx, miner, finished_block_chan) = miner::new(&blockchain, &mempool);
-    let miner_worker_ctx = Worker::new(&server, finished_block_chan, &blockchain, &mempool);
-    miner_ctx.start();
-    miner_worker_ctx.start();
+    #[cfg(feature = "miner")]
+    let (miner_ctx, miner, finished_block_chan) = miner::new(&blockchain, &mempool, &clock);
+    #[cfg(feature = "miner")]
+    {
+        let miner_worker_ctx = Worker::new(&server, finished_block_chan, &blockchain, &mempool);
+        miner_ctx.start();
+        miner_worker_ctx.start();
+        thread_pools.add(PoolKind::Miner, miner_thread_count, &miner.thread_pool());
+    }
+    let thread_pools = Arc::new(Mutex::new(thread_pools));
+    #[cfg(feature = "miner")]
+    let hash_count = miner.hash_counter();
+    #[cfg(not(feature = "miner"))]
+    let hash_count = Arc::new(std::sync::atomic::AtomicU64::new(0));
+
+    // start the wallet and the scheduler of its delayed sends
+    #[cfg(feature = "wallet")]
+    let wallet = Arc::new(Mutex::new(Wallet::new(seed, &blockchain, &mempool, &server, &relay_policy)));
+    #[cfg(feature = "wallet")]
+    wallet::worker::Worker::new(&wallet, time::Duration::from_millis(1000)).start();
+
+    // start recording the metrics history
+    let metrics_interval = matches
+        .value_of("metrics_interval")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing metrics interval: {}", e);
+            process::exit(1);
+        });
+    let metrics_worker_ctx = metrics::worker::Worker::new(
+        &metrics_history,
+        time::Duration::from_millis(metrics_interval),
+        &server,
+        &hash_count,
+        &blockchain,
+        &mempool,
+    );
+    metrics_worker_ctx.start();
 
-    // connect to known peers
+    // measure the memory of each subsystem and keep it within the caps, given in MiB
+    let memory_cap = |name: &str, arg: &str| {
+        matches.value_of(arg).map(|mib| {
+            memory::parse_cap(mib).unwrap_or_else(|e| {
+                error!("Error parsing {} memory cap: {}", name, e);
+                process::exit(1);
+            })
+        })
+    };
+    let memory_caps = MemoryCaps {
+        bodies: memory_cap("block", "max_block_memory"),
+        mempool: memory_cap("mempool", "max_mempool_memory"),
+        orphans: memory_cap("orphan", "max_orphan_memory"),
+        caches: memory_cap("cache", "max_cache_memory"),
+    };
+    let memory_report = Arc::new(Mutex::new(MemoryReport::default()));
+    let memory_worker_ctx = memory::worker::Worker::new(
+        &memory_report,
+        time::Duration::from_millis(metrics_interval),
+        memory_caps,
+        &blockchain,
+        &mempool,
+        &orphans,
+        &gossip,
+    );
+    memory_worker_ctx.start();
+
+    // publish snapshots for other nodes to bootstrap from
+    if let Some(dir) = matches.value_of("snapshot_dir") {
+        let key_path = data_dir.join(format!("node-{}.key", p2p_addr.port()));
+        let key = key_pair::load_or_generate(&key_path).unwrap_or_else(|e| {
+            error!("Error loading node key {}: {}", key_path.display(), e);
+            process::exit(1);
+        });
+        info!("Signing snapshots as {}", hex::encode(key.public_key()));
+        let snapshot_interval = matches
+            .value_of("snapshot_interval")
+            .unwrap()
+            .parse::<u64>()
+            .unwrap_or_else(|e| {
+                error!("Error parsing snapshot interval: {}", e);
+                process::exit(1);
+            });
+        let snapshot_worker_ctx = snapshot::worker::Worker::new(
+            dir.into(),
+            time::Duration::from_millis(snapshot_interval),
+            key,
+            &blockchain,
+        );
+        snapshot_worker_ctx.start();
+    }
+
+    // connect to known peers, seeds and the ones of the address book, and redial them when dropped
     if let Some(known_peers) = matches.values_of("known_peer") {
-        let known_peers: Vec<String> = known_peers.map(|x| x.to_owned()).collect();
-        let server = server.clone();
-        thread::spawn(move || {
-            for peer in known_peers {
-                loop {
-                    let addr = match peer.parse::<net::SocketAddr>() {
-                        Ok(x) => x,
-                        Err(e) => {
-                            error!("Error parsing peer address {}: {}", &peer, e);
-                            break;
-                        }
-                    };
-                    match server.connect(addr) {
-                        Ok(_) => {
-                            info!("Connected to outgoing peer {}", &addr);
-                            break;
-                        }
-                        Err(e) => {
-                            error!(
-                                "Error connecting to peer {}, retrying in one second: {}",
-                                addr, e
-                            );
-                            thread::sleep(time::Duration::from_millis(1000));
-                            continue;
-                        }
+        let mut book = address_book.lock().unwrap();
+        for peer in known_peers {
+            match peer.parse::<net::SocketAddr>() {
+                Ok(addr) => book.add(addr),
+                Err(e) => error!("Error parsing peer address {}: {}", peer, e),
+            }
+        }
+    }
+    // a seed may be a host name standing for several nodes, resolved anew at every start
+    if let Some(seeds) = matches.values_of("seed") {
+        let mut book = address_book.lock().unwrap();
+        for seed in seeds {
+            match seed.to_socket_addrs() {
+                Ok(addrs) => {
+                    for addr in addrs {
+                        info!("Seed {} is at {}", seed, addr);
+                        book.add(addr);
                     }
                 }
+                Err(e) => error!("Error resolving seed {}: {}", seed, e),
             }
-        });
+        }
     }
+    {
+        let book = address_book.lock().unwrap();
+        if !book.is_empty() {
+            info!("Dialing up to {} of the {} peers of the address book", max_outbound, book.len());
+        }
+    }
+    network::addrbook::Dialer::new(&address_book, &server, time::Duration::from_millis(500), max_outbound).start();
+    let addr_interval = matches
+        .value_of("addr_interval")
+        .unwrap()
+        .parse::<u64>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing address exchange interval: {}", e);
+            process::exit(1);
+        });
+    network::addrbook::start_exchange(time::Duration::from_millis(addr_interval), &server, &address_book, &peer_features);
 
 
-    // start the API server
-    ApiServer::start(
-        api_addr,
-        &miner,
-        &server,
-        &blockchain,
-        &tx,
-    );
+    // start the API server and the WebSocket event server
+    #[cfg(feature = "api")]
+    {
+        let reports = api::Reports {
+            metrics: Arc::clone(&metrics_history),
+            memory: Arc::clone(&memory_report),
+            sync_gate: Arc::clone(&sync_gate),
+            downloads: Arc::clone(&downloads),
+            block_watch: Arc::clone(&block_watch),
+            ban_list: Arc::clone(&ban_list),
+        };
+        let api = ApiServer::new(api_addr, &server, &shared, &tx, reports, matches.is_present("test_mode"));
+        let api = api
+            .with_topology(&topology, &router, &address_book)
+            .with_chain_proofs(&chain_proofs)
+            .with_router(&router)
+            .with_block_metrics(&block_metrics)
+            .with_first_seen(&first_seen)
+            .with_thread_pools(&thread_pools);
+        let api = if matches.is_present("sign_responses") {
+            let key_path = data_dir.join(format!("node-{}.key", p2p_addr.port()));
+            let key = key_pair::load_or_generate(&key_path).unwrap_or_else(|e| {
+                error!("Error loading node key {}: {}", key_path.display(), e);
+                process::exit(1);
+            });
+            info!("Signing API responses as {}", hex::encode(key.public_key()));
+            api.with_signer(key)
+        } else {
+            api
+        };
+        #[cfg(feature = "miner")]
+        let api = api.with_miner(&miner);
+        #[cfg(feature = "wallet")]
+        let api = {
+            let send_limit = network::ratelimit::parse_rate(matches.value_of("send_rate_limit").unwrap()).unwrap_or_else(|e| {
+                error!("Error parsing send rate limit: {}", e);
+                process::exit(1);
+            });
+            api.with_wallet(&wallet).with_send_limit(send_limit)
+        };
+        api.start();
+        if let Some(ws_addr) = ws_addr {
+            EventServer::start(ws_addr, &events).unwrap_or_else(|e| {
+                error!("Error starting the WebSocket event server at {}: {}", ws_addr, e);
+                process::exit(1);
+            });
+        }
+    }
+
+    // tell lab automation where to find this node
+    if daemon::is_daemon() {
+        let status = DaemonStatus {
+            pid: process::id(),
+            p2p_addr: p2p_addr.to_string(),
+            api_addr: api_addr.to_string(),
+            ws_addr: ws_addr.map(|addr| addr.to_string()),
+            data_dir: data_dir.canonicalize().unwrap_or_else(|_| data_dir.to_path_buf()).display().to_string(),
+            chain_id: chain_id.to_string(),
+        };
+        daemon::write_files(&daemon_files, &status).unwrap_or_else(|e| {
+            error!("Error writing {}: {}", daemon_files.status().display(), e);
+            process::exit(1);
+        });
+    }
 
     loop {
         std::thread::park();
@@ -0,0 +1,110 @@
+pub mod worker;
+
+use crate::types::block::{Block, Content, Header};
+use crate::types::transaction::SignedTransaction;
+
+use serde::Serialize;
+use std::mem::size_of;
+
+// Sizes are approximate: they count what a value holds inline and on the heap, plus a control
+// byte per hash map entry, and ignore allocator overhead and the unused capacity of the maps.
+
+/// Approximate bytes a transaction takes in memory
+pub fn transaction_size(tx: &SignedTransaction) -> usize {
+    size_of::<SignedTransaction>() + tx.signature_vector.capacity() + tx.signer_public_key.capacity()
+}
+
+/// Approximate bytes the body of a block takes in memory
+pub fn content_size(content: &Content) -> usize {
+    size_of::<Content>() + content.transactions.iter().map(transaction_size).sum::<usize>()
+}
+
+/// Approximate bytes a whole block takes in memory
+pub fn block_size(block: &Block) -> usize {
+    size_of::<Header>() + content_size(&block.content)
+}
+
+/// Approximate bytes of one entry of a `HashMap<K, V>`, not counting what `V` holds on the heap
+pub fn map_entry_size<K, V>() -> usize {
+    size_of::<K>() + size_of::<V>() + 1
+}
+
+/// Approximate memory used by each subsystem, in bytes.
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct MemoryUsage {
+    pub headers: usize,
+    /// block bodies kept in memory
+    pub bodies: usize,
+    /// account states of every block, never evicted since validating a block needs the state of
+    /// its parent
+    pub states: usize,
+    pub mempool: usize,
+    /// orphan blocks waiting for their parent
+    pub orphans: usize,
+    /// transactions each peer is known to have seen, to suppress announcements
+    pub caches: usize,
+}
+
+impl MemoryUsage {
+    pub fn total(&self) -> usize {
+        self.headers + self.bodies + self.states + self.mempool + self.orphans + self.caches
+    }
+}
+
+/// Caps in bytes on the memory of the subsystems that can give some up, None for no cap.
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryCaps {
+    /// bodies over the cap are dropped oldest first, and read back from the store if there is one
+    pub bodies: Option<usize>,
+    /// transactions over the cap are dropped, those with the highest account nonce first
+    pub mempool: Option<usize>,
+    /// orphans over the cap are dropped, their parent is requested again if they are resent
+    pub orphans: Option<usize>,
+    /// the oldest announcements are forgotten, so a peer may be told about a transaction twice
+    pub caches: Option<usize>,
+}
+
+/// What was dropped to stay within the caps since the node started.
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Evictions {
+    pub bodies: usize,
+    pub transactions: usize,
+    pub orphans: usize,
+    pub cache_entries: usize,
+}
+
+/// Memory figures served by the API.
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct MemoryReport {
+    pub usage: MemoryUsage,
+    pub total: usize,
+    pub caps: MemoryCaps,
+    pub evicted: Evictions,
+}
+
+/// Parse a cap given in MiB on the command line
+pub fn parse_cap(mib: &str) -> Result<usize, String> {
+    let mib = mib.parse::<usize>().map_err(|e| e.to_string())?;
+    mib.checked_mul(1 << 20).ok_or_else(|| format!("{} MiB does not fit in memory", mib))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::transaction::generate_random_transaction;
+
+    #[test]
+    fn sizes_grow_with_content() {
+        let tx = SignedTransaction {
+            t: generate_random_transaction(),
+            signature_vector: vec![0; 64],
+            signer_public_key: vec![0; 32],
+        };
+        assert_eq!(transaction_size(&tx), size_of::<SignedTransaction>() + 96);
+        let empty = Content { transactions: vec![] };
+        let full = Content { transactions: vec![tx.clone(), tx] };
+        assert_eq!(content_size(&full) - content_size(&empty), 2 * (size_of::<SignedTransaction>() + 96));
+        assert_eq!(parse_cap("2"), Ok(2 << 20));
+        assert!(parse_cap("two").is_err());
+    }
+}
@@ -0,0 +1,167 @@
+use log::{info, warn};
+use crate::blockchain::{Blockchain, Mempool};
+use crate::network::gossip::RecentlyAnnounced;
+use crate::network::orphan::OrphanResolver;
+use super::{Evictions, MemoryCaps, MemoryReport, MemoryUsage};
+use std::thread;
+use std::sync::{Arc, Mutex};
+use std::time;
+
+/// Measures the memory of each subsystem every `interval` and evicts what is over its cap. Orphan
+/// blocks are evicted by the `OrphanResolver` as soon as they go over the cap.
+pub struct Worker {
+    report: Arc<Mutex<MemoryReport>>,
+    interval: time::Duration,
+    caps: MemoryCaps,
+    blockchain: Arc<Mutex<Blockchain>>,
+    mempool: Arc<Mutex<Mempool>>,
+    orphans: Arc<Mutex<OrphanResolver>>,
+    gossip: Arc<Mutex<RecentlyAnnounced>>,
+}
+
+impl Worker {
+    pub fn new(
+        report: &Arc<Mutex<MemoryReport>>,
+        interval: time::Duration,
+        caps: MemoryCaps,
+        blockchain: &Arc<Mutex<Blockchain>>,
+        mempool: &Arc<Mutex<Mempool>>,
+        orphans: &Arc<Mutex<OrphanResolver>>,
+        gossip: &Arc<Mutex<RecentlyAnnounced>>,
+    ) -> Self {
+        Self {
+            report: Arc::clone(report),
+            interval,
+            caps,
+            blockchain: Arc::clone(blockchain),
+            mempool: Arc::clone(mempool),
+            orphans: Arc::clone(orphans),
+            gossip: Arc::clone(gossip),
+        }
+    }
+
+    pub fn start(self) {
+        let interval = self.interval;
+        let caps = self.caps;
+        if let Some(max) = self.caps.orphans {
+            {self.orphans.lock().unwrap().set_max_buffered(max)};
+        }
+        thread::Builder::new()
+            .name("memory-worker".to_string())
+            .spawn(move || loop {
+                self.enforce();
+                thread::sleep(self.interval);
+            })
+            .unwrap();
+        info!("Memory accounting every {:?} with caps {:?}", interval, caps);
+    }
+
+    /// Evict what is over the caps, then measure what is left
+    pub fn enforce(&self) {
+        let mut evicted = Evictions::default();
+        if let Some(max) = self.caps.bodies {
+            evicted.bodies = self.blockchain.lock().unwrap().evict_bodies(max);
+        }
+        if let Some(max) = self.caps.mempool {
+            evicted.transactions = self.mempool.lock().unwrap().evict(max);
+        }
+        if let Some(max) = self.caps.caches {
+            evicted.cache_entries = self.gossip.lock().unwrap().evict(max);
+        }
+        if evicted.bodies > 0 || evicted.transactions > 0 {
+            warn!(
+                "Over the memory caps, dropped {} block bodies and {} mempool transactions",
+                evicted.bodies, evicted.transactions
+            );
+        }
+
+        let usage = {
+            let blockchain = self.blockchain.lock().unwrap();
+            MemoryUsage {
+                headers: blockchain.header_memory(),
+                bodies: blockchain.body_memory(),
+                states: blockchain.state_memory(),
+                mempool: 0,
+                orphans: 0,
+                caches: 0,
+            }
+        };
+        let (orphans, evicted_orphans) = {
+            let orphans = self.orphans.lock().unwrap();
+            (orphans.buffered(), orphans.evicted())
+        };
+        let usage = MemoryUsage {
+            mempool: {self.mempool.lock().unwrap().memory_usage()},
+            orphans,
+            caches: {self.gossip.lock().unwrap().memory_usage()},
+            ..usage
+        };
+
+        let mut report = self.report.lock().unwrap();
+        report.total = usage.total();
+        report.usage = usage;
+        report.caps = self.caps;
+        report.evicted.bodies += evicted.bodies;
+        report.evicted.transactions += evicted.transactions;
+        report.evicted.cache_entries += evicted.cache_entries;
+        report.evicted.orphans = evicted_orphans;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::orphan::OrphanStrategy;
+    use crate::types::block::TestBlockBuilder;
+    use crate::types::hash::Hashable;
+    use crate::types::transaction::{generate_random_transaction, SignedTransaction};
+
+    fn transaction(account_nonce: usize) -> SignedTransaction {
+        let mut t = generate_random_transaction();
+        t.account_nonce = account_nonce;
+        SignedTransaction { t, signature_vector: vec![0; 64], signer_public_key: vec![0; 32] }
+    }
+
+    #[test]
+    fn caps_are_enforced() {
+        let blockchain = Arc::new(Mutex::new(Blockchain::new(0)));
+        let mut parent = {blockchain.lock().unwrap().tip()};
+        for length in 1..=5 {
+            let block = TestBlockBuilder::new(&parent).length(length).build();
+            parent = block.hash();
+            blockchain.lock().unwrap().insert(&block);
+        }
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        for nonce in 1..=20 {
+            let tx = transaction(nonce);
+            mempool.lock().unwrap().hash_map.insert(tx.hash(), tx);
+        }
+        // the bodies are empty, and all take the same memory
+        let body = {blockchain.lock().unwrap().body_memory()} / 6;
+        let tx = {mempool.lock().unwrap().memory_usage()} / 20;
+        let caps = MemoryCaps { bodies: Some(3 * body), mempool: Some(5 * tx), orphans: None, caches: None };
+        let report = Arc::new(Mutex::new(MemoryReport::default()));
+        let worker = Worker::new(
+            &report,
+            time::Duration::from_secs(1),
+            caps,
+            &blockchain,
+            &mempool,
+            &Arc::new(Mutex::new(OrphanResolver::new(OrphanStrategy::SamePeer, 100))),
+            &Arc::new(Mutex::new(RecentlyAnnounced::new(time::Duration::from_secs(60)))),
+        );
+        worker.enforce();
+
+        let report = report.lock().unwrap();
+        assert!(report.usage.bodies <= 3 * body);
+        assert!(report.usage.mempool <= 5 * tx);
+        assert_eq!(report.evicted.transactions, 15);
+        assert_eq!(report.total, report.usage.total());
+        // the newest blocks and the lowest nonces are kept
+        let blockchain = blockchain.lock().unwrap();
+        assert!(blockchain.block(&parent).is_some());
+        assert!(blockchain.block(&blockchain.all_blocks_in_longest_chain()[1]).is_none());
+        let mempool = mempool.lock().unwrap();
+        assert!(mempool.hash_map.values().all(|tx| tx.t.account_nonce <= 5));
+    }
+}
@@ -0,0 +1,318 @@
+pub mod validation;
+pub mod worker;
+
+use serde::{Serialize, Deserialize};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// One snapshot of the node's key time series.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Sample {
+    /// milliseconds since the unix epoch
+    pub timestamp: u64,
+    pub height: u64,
+    pub mempool_size: u64,
+    pub peer_count: u64,
+    /// nonces tried per second since the previous sample
+    pub hashrate: f64,
+    pub fork_events: u64,
+}
+
+/// A named point in time of an experiment, e.g. "partition start", placed through the API of one
+/// node and gossiped to the others, so the exported metrics of every node carry the same markers.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Marker {
+    /// milliseconds since the unix epoch, by the network clock of the node that placed it
+    pub timestamp: u64,
+    pub name: String,
+    /// tells apart markers placed with the same name at the same time
+    pub nonce: u64,
+}
+
+/// Longest marker name accepted
+pub const MAX_MARKER_NAME: usize = 100;
+
+/// Most markers kept, the oldest are forgotten first
+pub const MAX_MARKERS: usize = 10_000;
+
+impl Marker {
+    pub fn new(name: &str, timestamp: u64) -> Self {
+        Marker { timestamp, name: name.to_string(), nonce: rand::random() }
+    }
+
+    /// Check that a name can be exported as is: not empty, at most `MAX_MARKER_NAME` bytes of
+    /// letters, digits, spaces and `_.:-`
+    pub fn check_name(name: &str) -> Result<(), String> {
+        if name.is_empty() || name.len() > MAX_MARKER_NAME {
+            return Err(format!("marker name must be 1 to {} bytes long", MAX_MARKER_NAME));
+        }
+        match name.chars().find(|c| !(c.is_ascii_alphanumeric() || " _.:-".contains(*c))) {
+            Some(c) => Err(format!("marker name may not contain {:?}", c)),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Latency histogram with power of two buckets: bucket `i` counts the operations that took less
+/// than 2^i µs, and at least 2^(i-1) µs, the last one everything slower.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Histogram {
+    pub count: u64,
+    pub total_us: u64,
+    pub max_us: u64,
+    pub buckets: Vec<u64>,
+}
+
+// the last bucket starts at 2^21 µs, about 2 s
+const BUCKETS: usize = 23;
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram { count: 0, total_us: 0, max_us: 0, buckets: vec![0; BUCKETS] }
+    }
+}
+
+impl Histogram {
+    pub fn record(&mut self, latency: Duration) {
+        let us = latency.as_micros() as u64;
+        let bucket = (64 - us.leading_zeros() as usize).min(BUCKETS - 1);
+        self.buckets[bucket] += 1;
+        self.count += 1;
+        self.total_us += us;
+        self.max_us = self.max_us.max(us);
+    }
+
+    /// Upper bound in µs of the bucket holding the `q` quantile (0 to 1), 0 if nothing was recorded
+    pub fn quantile(&self, q: f64) -> u64 {
+        let rank = (q * self.count as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (i, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return 1 << i;
+            }
+        }
+        0
+    }
+}
+
+// a slot in the ring file, the sequence number tells the oldest sample from the newest one
+#[derive(Serialize, Deserialize)]
+struct Record {
+    seq: u64,
+    sample: Sample,
+}
+
+// every field is fixed width under bincode, so every record takes the same number of bytes
+const RECORD_SIZE: u64 = 56;
+
+/// Metrics history kept in a fixed-size ring file on disk. Once `capacity` samples have been
+/// written, each new sample overwrites the oldest one, so the file never grows past
+/// `capacity` records and survives node restarts. Markers are kept next to it, one JSON object
+/// per line in a file with the `markers` extension.
+pub struct MetricsHistory {
+    file: File,
+    capacity: u64,
+    next_seq: u64,
+    markers_path: PathBuf,
+    markers: Vec<Marker>, // oldest first
+}
+
+impl MetricsHistory {
+    /// Open the ring file at `path`, creating it if needed, and resume after its newest sample
+    pub fn open<P: AsRef<Path>>(path: P, capacity: u64) -> io::Result<Self> {
+        assert!(capacity > 0, "metrics history needs room for at least one sample");
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path)?;
+        let markers_path = path.as_ref().with_extension("markers");
+        let mut markers: Vec<Marker> = match std::fs::read_to_string(&markers_path) {
+            // a line cut short by a crash is skipped
+            Ok(lines) => lines.lines().filter_map(|line| serde_json::from_str(line).ok()).collect(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        markers.sort_by_key(|m| m.timestamp);
+        let mut history = MetricsHistory { file, capacity, next_seq: 0, markers_path, markers };
+        history.next_seq = match history.records()?.iter().map(|r| r.seq).max() {
+            Some(seq) => seq + 1,
+            None => 0,
+        };
+        Ok(history)
+    }
+
+    /// Record a marker, false if it was recorded before, e.g. when a peer relays it back to us
+    pub fn mark(&mut self, marker: &Marker) -> io::Result<bool> {
+        if self.markers.contains(marker) {
+            return Ok(false);
+        }
+        let index = self.markers.partition_point(|m| m.timestamp <= marker.timestamp);
+        self.markers.insert(index, marker.clone());
+        if self.markers.len() > MAX_MARKERS {
+            self.markers.remove(0);
+            let lines: String = self.markers.iter().map(|m| serde_json::to_string(m).unwrap() + "\n").collect();
+            std::fs::write(&self.markers_path, lines)?;
+        } else {
+            let mut file = OpenOptions::new().append(true).create(true).open(&self.markers_path)?;
+            file.write_all((serde_json::to_string(marker).unwrap() + "\n").as_bytes())?;
+        }
+        Ok(true)
+    }
+
+    /// Get the markers placed between `from` and `to` (inclusive, in ms), oldest first
+    pub fn markers(&self, from: u64, to: u64) -> Vec<Marker> {
+        self.markers.iter().filter(|m| m.timestamp >= from && m.timestamp <= to).cloned().collect()
+    }
+
+    /// Append a sample, overwriting the oldest one if the ring is full
+    pub fn record(&mut self, sample: &Sample) -> io::Result<()> {
+        let record = Record { seq: self.next_seq, sample: sample.clone() };
+        let bytes = bincode::serialize(&record).unwrap();
+        debug_assert_eq!(bytes.len() as u64, RECORD_SIZE);
+        let slot = self.next_seq % self.capacity;
+        self.file.seek(SeekFrom::Start(slot * RECORD_SIZE))?;
+        self.file.write_all(&bytes)?;
+        self.file.flush()?;
+        self.next_seq += 1;
+        Ok(())
+    }
+
+    /// Get the samples taken between `from` and `to` (inclusive, in ms), oldest first
+    pub fn samples(&mut self, from: u64, to: u64) -> io::Result<Vec<Sample>> {
+        let mut records = self.records()?;
+        records.sort_by_key(|r| r.seq);
+        Ok(records
+            .into_iter()
+            .map(|r| r.sample)
+            .filter(|s| s.timestamp >= from && s.timestamp <= to)
+            .collect())
+    }
+
+    /// Export the samples taken between `from` and `to` (inclusive, in ms) as CSV. The names of
+    /// the markers placed since the previous sample are listed with each sample, separated by
+    /// semicolons.
+    pub fn export_csv(&mut self, from: u64, to: u64) -> io::Result<String> {
+        let mut csv = String::from("timestamp,height,mempool_size,peer_count,hashrate,fork_events,markers\n");
+        let mut since = from;
+        for s in self.samples(from, to)? {
+            let markers: Vec<String> = self.markers(since, s.timestamp).into_iter().map(|m| m.name).collect();
+            since = s.timestamp + 1;
+            csv.push_str(&format!(
+                "{},{},{},{},{:.2},{},{}\n",
+                s.timestamp, s.height, s.mempool_size, s.peer_count, s.hashrate, s.fork_events, markers.join(";")
+            ));
+        }
+        Ok(csv)
+    }
+
+    // read every complete record currently in the ring file, in slot order
+    fn records(&mut self) -> io::Result<Vec<Record>> {
+        let mut bytes = Vec::new();
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.read_to_end(&mut bytes)?;
+        let slots = std::cmp::min(bytes.len() as u64 / RECORD_SIZE, self.capacity) as usize;
+        let mut records = Vec::new();
+        for chunk in bytes.chunks_exact(RECORD_SIZE as usize).take(slots) {
+            match bincode::deserialize::<Record>(chunk) {
+                Ok(record) => records.push(record),
+                Err(_) => break,
+            }
+        }
+        Ok(records)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_at(timestamp: u64) -> Sample {
+        Sample {
+            timestamp,
+            height: timestamp / 10,
+            mempool_size: 3,
+            peer_count: 2,
+            hashrate: 1.5,
+            fork_events: 0,
+        }
+    }
+
+    // the ring file `name` in a directory of its own, removed with it
+    fn temp_path(name: &str) -> (tempfile::TempDir, std::path::PathBuf) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join(format!("{}.ring", name));
+        (dir, path)
+    }
+
+    #[test]
+    fn ring_overwrites_oldest() {
+        let (_dir, path) = temp_path("metrics-ring");
+        let mut history = MetricsHistory::open(&path, 3).unwrap();
+        for t in 1..=5 {
+            history.record(&sample_at(t * 100)).unwrap();
+        }
+        let timestamps: Vec<u64> = history.samples(0, u64::MAX).unwrap().iter().map(|s| s.timestamp).collect();
+        assert_eq!(timestamps, vec![300, 400, 500]);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 3 * RECORD_SIZE);
+
+        // reopening resumes after the newest sample
+        let mut history = MetricsHistory::open(&path, 3).unwrap();
+        history.record(&sample_at(600)).unwrap();
+        let timestamps: Vec<u64> = history.samples(0, u64::MAX).unwrap().iter().map(|s| s.timestamp).collect();
+        assert_eq!(timestamps, vec![400, 500, 600]);
+    }
+
+    #[test]
+    fn histogram_quantiles() {
+        let mut histogram = Histogram::default();
+        assert_eq!(histogram.quantile(0.5), 0);
+        for us in [0, 3, 5, 6, 7, 100, 3_000_000] {
+            histogram.record(Duration::from_micros(us));
+        }
+        assert_eq!(histogram.count, 7);
+        assert_eq!(histogram.max_us, 3_000_000);
+        // 5, 6 and 7 µs are in the bucket below 8 µs
+        assert_eq!(histogram.quantile(0.5), 8);
+        assert_eq!(histogram.quantile(1.0), 1 << (BUCKETS - 1));
+    }
+
+    #[test]
+    fn export_csv_in_range() {
+        let (_dir, path) = temp_path("metrics-csv");
+        let mut history = MetricsHistory::open(&path, 10).unwrap();
+        for t in 1..=4 {
+            history.record(&sample_at(t * 100)).unwrap();
+        }
+        let csv = history.export_csv(200, 300).unwrap();
+        assert_eq!(
+            csv,
+            "timestamp,height,mempool_size,peer_count,hashrate,fork_events,markers\n\
+             200,20,3,2,1.50,0,\n\
+             300,30,3,2,1.50,0,\n"
+        );
+    }
+
+    #[test]
+    fn markers_are_exported_with_the_next_sample() {
+        let (_dir, path) = temp_path("metrics-markers");
+        let mut history = MetricsHistory::open(&path, 10).unwrap();
+        for t in 1..=3 {
+            history.record(&sample_at(t * 100)).unwrap();
+        }
+        let start = Marker::new("partition start", 150);
+        assert!(history.mark(&start).unwrap());
+        assert!(history.mark(&Marker::new("partition end", 200)).unwrap());
+        // relayed back by a peer
+        assert!(!history.mark(&start).unwrap());
+        let csv = history.export_csv(0, 300).unwrap();
+        assert!(csv.ends_with("100,10,3,2,1.50,0,\n200,20,3,2,1.50,0,partition start;partition end\n300,30,3,2,1.50,0,\n"));
+
+        // the markers survive a restart
+        let history = MetricsHistory::open(&path, 10).unwrap();
+        assert_eq!(history.markers(0, u64::MAX).len(), 2);
+        assert_eq!(history.markers(160, 300)[0].name, "partition end");
+        assert!(Marker::check_name("partition start").is_ok());
+        assert!(Marker::check_name("").is_err());
+        assert!(Marker::check_name("a,b").is_err());
+    }
+}
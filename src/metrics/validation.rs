@@ -0,0 +1,80 @@
+use crate::types::hash::H256;
+
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+
+/// Most blocks whose metrics are kept, the oldest are forgotten first
+pub const MAX_BLOCK_METRICS: usize = 10_000;
+
+/// What it took to validate a block received from a peer, for the analysis of block size
+/// against propagation delay.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BlockMetrics {
+    /// serialized size in bytes
+    pub size: u64,
+    pub transactions: usize,
+    /// transaction signatures verified
+    pub signature_ops: usize,
+    /// from the first check of the block until it was inserted, in µs
+    pub validation_us: u64,
+    /// when the block arrived, in ms since the unix epoch by the network clock
+    pub received_at: u64,
+}
+
+/// The metrics of the last `capacity` blocks validated.
+pub struct BlockMetricsLog {
+    capacity: usize,
+    blocks: HashMap<H256, BlockMetrics>,
+    order: VecDeque<H256>, // oldest first
+}
+
+impl BlockMetricsLog {
+    pub fn new(capacity: usize) -> Self {
+        BlockMetricsLog {
+            capacity,
+            blocks: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    pub fn record(&mut self, hash: H256, metrics: BlockMetrics) {
+        if self.blocks.insert(hash, metrics).is_none() {
+            self.order.push_back(hash);
+        }
+        while self.order.len() > self.capacity {
+            let oldest = self.order.pop_front().unwrap();
+            self.blocks.remove(&oldest);
+        }
+    }
+
+    /// Metrics of `hash`, None if it was not received from a peer or is too old
+    pub fn get(&self, hash: &H256) -> Option<BlockMetrics> {
+        self.blocks.get(hash).copied()
+    }
+}
+
+impl Default for BlockMetricsLog {
+    fn default() -> Self {
+        Self::new(MAX_BLOCK_METRICS)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn oldest_blocks_are_forgotten() {
+        let mut log = BlockMetricsLog::new(2);
+        let metrics = |size| BlockMetrics { size, transactions: 1, signature_ops: 1, validation_us: 10, received_at: 1000 };
+        for i in 0..3u8 {
+            log.record(H256::from([i; 32]), metrics(i as u64));
+        }
+        assert_eq!(log.get(&H256::from([0; 32])), None);
+        assert_eq!(log.get(&H256::from([2; 32])), Some(metrics(2)));
+        // a block validated again replaces its metrics without taking another slot
+        log.record(H256::from([1; 32]), metrics(5));
+        assert_eq!(log.get(&H256::from([1; 32])), Some(metrics(5)));
+        assert_eq!(log.get(&H256::from([2; 32])), Some(metrics(2)));
+    }
+}
@@ -0,0 +1,79 @@
+use log::{error, info};
+use crate::blockchain::{Blockchain, Mempool};
+use crate::network::server::Handle as ServerHandle;
+use super::{MetricsHistory, Sample};
+use std::thread;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{self, Instant, SystemTime, UNIX_EPOCH};
+
+pub struct Worker {
+    history: Arc<Mutex<MetricsHistory>>,
+    interval: time::Duration,
+    server: ServerHandle,
+    hash_count: Arc<AtomicU64>, // nonces tried by the miner, stays 0 on a node without one
+    blockchain: Arc<Mutex<Blockchain>>,
+    mempool: Arc<Mutex<Mempool>>,
+}
+
+impl Worker {
+    pub fn new(
+        history: &Arc<Mutex<MetricsHistory>>,
+        interval: time::Duration,
+        server: &ServerHandle,
+        hash_count: &Arc<AtomicU64>,
+        blockchain: &Arc<Mutex<Blockchain>>,
+        mempool: &Arc<Mutex<Mempool>>,
+    ) -> Self {
+        Self {
+            history: Arc::clone(history),
+            interval,
+            server: server.clone(),
+            hash_count: Arc::clone(hash_count),
+            blockchain: Arc::clone(blockchain),
+            mempool: Arc::clone(mempool),
+        }
+    }
+
+    pub fn start(self) {
+        let interval = self.interval;
+        thread::Builder::new()
+            .name("metrics-worker".to_string())
+            .spawn(move || {
+                self.sample_loop();
+            })
+            .unwrap();
+        info!("Metrics recorder sampling every {:?}", interval);
+    }
+
+    fn sample_loop(&self) {
+        let mut last_hash_count = self.hash_count.load(Ordering::Relaxed);
+        let mut last_instant = Instant::now();
+        loop {
+            thread::sleep(self.interval);
+
+            // the hashrate is averaged over the time since the previous sample
+            let hash_count = self.hash_count.load(Ordering::Relaxed);
+            let elapsed = last_instant.elapsed().as_secs_f64();
+            let hashrate = (hash_count - last_hash_count) as f64 / elapsed;
+            last_hash_count = hash_count;
+            last_instant = Instant::now();
+
+            let (height, fork_events) = {
+                let blockchain = self.blockchain.lock().unwrap();
+                (blockchain.height() as u64, blockchain.fork_events() as u64)
+            };
+            let sample = Sample {
+                timestamp: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64,
+                height,
+                mempool_size: {self.mempool.lock().unwrap().hash_map.len() as u64},
+                peer_count: self.server.peer_count() as u64,
+                hashrate,
+                fork_events,
+            };
+            if let Err(e) = self.history.lock().unwrap().record(&sample) {
+                error!("Error recording metrics sample: {}", e);
+            }
+        }
+    }
+}
@@ -0,0 +1,82 @@
+use crate::blockchain::{Blockchain, Mempool};
+use crate::blockchain::params::ChainParams;
+use crate::network::clock::NetworkClock;
+use crate::types::hash::H256;
+
+use serde::Serialize;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Initial difficulty suggested for a network from the hashrate measured on this machine.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Calibration {
+    /// nonces per second one miner tried during the benchmark
+    pub hashrate: f64,
+    pub miners: u32,
+    /// block interval in milliseconds the difficulty aims for
+    pub target_block_time: u64,
+    /// hashes the whole network is expected to try per block
+    pub expected_hashes: f64,
+    /// target to pass to `genesis init --difficulty`
+    pub difficulty: String,
+}
+
+impl Calibration {
+    /// Difficulty with which `miners` miners trying `hashrate` nonces per second each find a
+    /// block every `target_block_time` ms on average
+    pub fn new(hashrate: f64, miners: u32, target_block_time: u64) -> Self {
+        let expected_hashes = hashrate * miners as f64 * target_block_time as f64 / 1000.0;
+        // below one hash per block every nonce has to meet the target
+        let difficulty = H256::from_difficulty(expected_hashes.round().max(1.0) as u64).unwrap();
+        Calibration { hashrate, miners, target_block_time, expected_hashes, difficulty: difficulty.to_string() }
+    }
+
+    /// The difficulty as a target
+    pub fn target(&self) -> H256 {
+        self.difficulty.parse().unwrap()
+    }
+}
+
+/// Run the miner of this node offline for `duration` with `threads` threads and `lambda` µs
+/// between nonces, on the proof of work of `params`, and return the nonces it tried per second.
+/// The whole miner is measured, building each block on the tip and the lambda included, so the
+/// hashrate is close to the one it reaches when mining for real; the mempool is empty though,
+/// and a full one makes building blocks slower.
+pub fn benchmark(params: &ChainParams, lambda: u64, threads: usize, duration: Duration) -> f64 {
+    // only the zero hash meets the zero target, so no block is found while measuring
+    let params = ChainParams { initial_difficulty: H256::default(), retarget_interval: 0, ..params.clone() };
+    let blockchain = Arc::new(Mutex::new(Blockchain::with_params(0, &params)));
+    let mempool = Arc::new(Mutex::new(Mempool::new()));
+    let clock = Arc::new(Mutex::new(NetworkClock::new(Duration::from_secs(5), false)));
+    let (ctx, miner, _finished_blocks) = super::new(&blockchain, &mempool, &clock);
+    miner.thread_pool().lock().unwrap().resize(threads.max(1));
+    ctx.start();
+    let hash_count = miner.hash_counter();
+    miner.start(lambda);
+    let started = Instant::now();
+    thread::sleep(duration);
+    let hashes = hash_count.load(Ordering::Relaxed);
+    let elapsed = started.elapsed().as_secs_f64();
+    miner.exit();
+    hashes as f64 / elapsed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn difficulty_matches_expected_work() {
+        // 4 miners at 500 nonces/s and a block every 5 s: 10000 hashes per block
+        let calibration = Calibration::new(500.0, 4, 5000);
+        assert_eq!(calibration.expected_hashes, 10000.0);
+        assert!((calibration.target().work() - 10000.0).abs() < 1.0);
+        // a machine too slow for the interval gets the easiest target
+        assert_eq!(Calibration::new(0.1, 1, 1000).target(), H256::MAX);
+        // the miner does try nonces
+        let params = ChainParams::default();
+        assert!(benchmark(&params, 0, 1, Duration::from_millis(200)) > 0.0);
+    }
+}
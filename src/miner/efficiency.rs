@@ -0,0 +1,130 @@
+use crate::types::hash::H256;
+
+use serde::Serialize;
+
+/// How the work of the miner was spent, for comparing mining efficiency with network latency.
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct EfficiencyReport {
+    /// distinct templates the miner hashed
+    pub templates: u64,
+    /// templates dropped as a block of another node became the tip
+    pub new_tip_restarts: u64,
+    /// templates dropped as a block this miner found became the tip
+    pub own_block_restarts: u64,
+    /// templates replaced on the same parent, as the mempool changed
+    pub template_refreshes: u64,
+    pub hashes: u64,
+    /// hashes estimated to have been spent on a parent after another node found its child,
+    /// lost to the time the block took to reach this node
+    pub stale_hashes: u64,
+    /// mean ms between another node finding a block, by its timestamp, and this miner
+    /// switching to it
+    pub mean_switch_delay_ms: f64,
+    /// share of the hashes that were not stale
+    pub efficiency: f64,
+}
+
+/// Follows the templates the miner hashes to tell why its work restarts. A block of another
+/// node makes the hashes on the old parent since that block was found stale: they could at best
+/// have produced a competing block. Estimating them assumes the hashrate was steady while the
+/// miner worked on the old parent and the clocks of the nodes roughly agree.
+#[derive(Debug, Default)]
+pub struct EfficiencyTracker {
+    // parent and merkle root of the template hashed last
+    current: Option<(H256, H256)>,
+    last_mined: Option<H256>,
+    // when the miner started on the current parent, in ms, and the hashes on it since
+    parent_since: u128,
+    parent_hashes: u64,
+    switch_delays: u128,
+    report: EfficiencyReport,
+}
+
+impl EfficiencyTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Count a hash at `now` of the template on `parent` with `merkle_root`, `parent_timestamp`
+    /// being when the parent was found, both in ms since the unix epoch
+    pub fn hash(&mut self, parent: H256, merkle_root: H256, parent_timestamp: u128, now: u128) {
+        match self.current {
+            Some((current_parent, current_root)) if current_parent == parent => {
+                if current_root != merkle_root {
+                    self.report.template_refreshes += 1;
+                    self.report.templates += 1;
+                }
+            }
+            Some(_) => {
+                self.report.templates += 1;
+                if self.last_mined == Some(parent) {
+                    self.report.own_block_restarts += 1;
+                } else {
+                    self.report.new_tip_restarts += 1;
+                    let delay = now.saturating_sub(parent_timestamp);
+                    let worked = now.saturating_sub(self.parent_since);
+                    if let Some(stale) = (self.parent_hashes as u128 * delay.min(worked)).checked_div(worked) {
+                        self.report.stale_hashes += stale as u64;
+                    }
+                    self.switch_delays += delay;
+                }
+                self.parent_since = now;
+                self.parent_hashes = 0;
+            }
+            None => {
+                self.report.templates += 1;
+                self.parent_since = now;
+            }
+        }
+        self.current = Some((parent, merkle_root));
+        self.report.hashes += 1;
+        self.parent_hashes += 1;
+    }
+
+    /// Note that the miner found block `hash`, work on top of it is no restart to blame on others
+    pub fn mined(&mut self, hash: H256) {
+        self.last_mined = Some(hash);
+    }
+
+    pub fn report(&self) -> EfficiencyReport {
+        let mut report = self.report.clone();
+        if report.new_tip_restarts > 0 {
+            report.mean_switch_delay_ms = self.switch_delays as f64 / report.new_tip_restarts as f64;
+        }
+        report.efficiency = if report.hashes == 0 { 1.0 } else { 1.0 - report.stale_hashes as f64 / report.hashes as f64 };
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn late_blocks_make_work_stale() {
+        let (genesis, ours, theirs) = (H256::from([1; 32]), H256::from([2; 32]), H256::from([3; 32]));
+        let (empty, full) = (H256::from([0; 32]), H256::from([9; 32]));
+        let mut tracker = EfficiencyTracker::new();
+        // 100 hashes over 1 s on genesis, the mempool changing halfway
+        for i in 0..100 {
+            tracker.hash(genesis, if i < 50 { empty } else { full }, 0, 1000 + i * 10);
+        }
+        tracker.mined(ours);
+        tracker.hash(ours, empty, 2000, 2000);
+        // 100 more hashes until 3000, while another node found a block at 2750
+        for i in 1..100 {
+            tracker.hash(ours, empty, 2000, 2000 + i * 10);
+        }
+        tracker.hash(theirs, empty, 2750, 3000);
+
+        let report = tracker.report();
+        assert_eq!(report.templates, 4);
+        assert_eq!((report.template_refreshes, report.own_block_restarts, report.new_tip_restarts), (1, 1, 1));
+        assert_eq!(report.hashes, 201);
+        // the last quarter of the work on our block was lost
+        assert_eq!(report.stale_hashes, 25);
+        assert_eq!(report.mean_switch_delay_ms, 250.0);
+        assert!((report.efficiency - 176.0 / 201.0).abs() < 1e-9);
+        assert_eq!(EfficiencyTracker::new().report().efficiency, 1.0);
+    }
+}
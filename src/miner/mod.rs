@@ -1,18 +1,25 @@
+pub mod calibrate;
+pub mod efficiency;
 pub mod worker;
 
 use log::info;
 
-use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
+use crossbeam::channel::{unbounded, Receiver, Sender};
 use std::time;
 
 use std::thread;
 use crate::types::address::Address;
 use crate::types::block::{Block, Header, Content};
 use crate::blockchain::{Blockchain, Mempool};
+use crate::blockchain::invariants;
+use crate::network::clock::NetworkClock;
+use crate::threads::Pool;
 use crate::types::hash::Hashable;
-use std::sync::{Arc, Mutex};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use crate::types::merkle::MerkleTree;
+use crate::types::pow::PowHash;
+use efficiency::{EfficiencyReport, EfficiencyTracker};
 use rand::Rng;
 
 enum ControlSignal {
@@ -21,41 +28,91 @@ enum ControlSignal {
     Exit,
 }
 
-enum OperatingState {
-    Paused,
-    Run(u64),
-    ShutDown,
+// what the mining threads follow: None while paused, the lambda while running
+#[derive(Default)]
+struct RunState {
+    lambda: Mutex<Option<u64>>,
+    changed: Condvar,
 }
 
-pub struct Context {
-    /// Channel for receiving control signal
+impl RunState {
+    fn set(&self, lambda: Option<u64>) {
+        *self.lambda.lock().unwrap() = lambda;
+        self.changed.notify_all();
+    }
+
+    // the lambda to mine with, None if still paused after a while
+    fn wait(&self) -> Option<u64> {
+        let lambda = self.lambda.lock().unwrap();
+        if lambda.is_some() {
+            return *lambda;
+        }
+        let (lambda, _) = self.changed.wait_timeout(lambda, time::Duration::from_millis(100)).unwrap();
+        *lambda
+    }
+}
+
+/// What the mining threads share.
+#[derive(Clone)]
+struct Miner {
     arc_mutex: Arc<Mutex<Blockchain>>, 
-    control_chan: Receiver<ControlSignal>,
-    operating_state: OperatingState,
     finished_block_chan: Sender<Block>,
     mempool: Arc<Mutex<Mempool>>,
+    hash_count: Arc<AtomicU64>, // number of nonces tried since the miner was created
+    clock: Arc<Mutex<NetworkClock>>, // block timestamps, adjusted to the peers if enabled
+    efficiency: Arc<Mutex<EfficiencyTracker>>,
+    run: Arc<RunState>,
+}
+
+pub struct Context {
+    /// Channel for receiving control signal
+    control_chan: Receiver<ControlSignal>,
+    run: Arc<RunState>,
+    threads: Arc<Mutex<Pool>>,
 }
 
 #[derive(Clone)]
 pub struct Handle {
     /// Channel for sending signal to the miner thread
     control_chan: Sender<ControlSignal>,
+    hash_count: Arc<AtomicU64>,
+    efficiency: Arc<Mutex<EfficiencyTracker>>,
+    threads: Arc<Mutex<Pool>>,
 }
 
-pub fn new(blockchain: &Arc<Mutex<Blockchain>>, mempool: &Arc<Mutex<Mempool>>) -> (Context, Handle, Receiver<Block>) {
+pub fn new(
+    blockchain: &Arc<Mutex<Blockchain>>,
+    mempool: &Arc<Mutex<Mempool>>,
+    clock: &Arc<Mutex<NetworkClock>>,
+) -> (Context, Handle, Receiver<Block>) {
     let (signal_chan_sender, signal_chan_receiver) = unbounded();
     let (finished_block_sender, finished_block_receiver) = unbounded();
+    let hash_count = Arc::new(AtomicU64::new(0));
+    let efficiency = Arc::new(Mutex::new(EfficiencyTracker::new()));
+    let run = Arc::new(RunState::default());
 
-    let ctx = Context {
+    let miner = Miner {
         arc_mutex: Arc::clone(blockchain),
-        control_chan: signal_chan_receiver,
-        operating_state: OperatingState::Paused,
         finished_block_chan: finished_block_sender,
         mempool: Arc::clone(mempool),
+        hash_count: Arc::clone(&hash_count),
+        clock: Arc::clone(clock),
+        efficiency: Arc::clone(&efficiency),
+        run: Arc::clone(&run),
+    };
+    let threads = Arc::new(Mutex::new(Pool::new("miner", move |stop| miner.mine(stop))));
+
+    let ctx = Context {
+        control_chan: signal_chan_receiver,
+        run,
+        threads: Arc::clone(&threads),
     };
 
     let handle = Handle {
         control_chan: signal_chan_sender,
+        hash_count,
+        efficiency,
+        threads,
     };
 
     (ctx, handle, finished_block_receiver) 
@@ -65,7 +122,8 @@ pub fn new(blockchain: &Arc<Mutex<Blockchain>>, mempool: &Arc<Mutex<Mempool>>) -
 fn test_new() -> (Context, Handle, Receiver<Block>) {
     let new_blockchain= &Arc::new(Mutex::new(Blockchain::new(0)));
     let new_mempool = &Arc::new(Mutex::new(Mempool::new()));
-    new(new_blockchain, new_mempool)
+    let clock = &Arc::new(Mutex::new(NetworkClock::new(time::Duration::from_secs(5), false)));
+    new(new_blockchain, new_mempool, clock)
 }
 
 impl Handle {
@@ -82,157 +140,179 @@ impl Handle {
     pub fn update(&self) {
         self.control_chan.send(ControlSignal::Update).unwrap();
     }
+
+    /// Get the counter of the nonces the miner has tried so far, for subsystems that watch the
+    /// hashrate without depending on the miner
+    pub fn hash_counter(&self) -> Arc<AtomicU64> {
+        Arc::clone(&self.hash_count)
+    }
+
+    /// How much of the work of the miner was restarted or wasted, and why
+    pub fn efficiency(&self) -> EfficiencyReport {
+        self.efficiency.lock().unwrap().report()
+    }
+
+    /// The mining threads, to resize while the node runs
+    pub fn thread_pool(&self) -> Arc<Mutex<Pool>> {
+        Arc::clone(&self.threads)
+    }
 }
 
 impl Context {
-    pub fn start(mut self) {
+    /// Start the thread following the control signals, and one mining thread unless the pool
+    /// was sized already
+    pub fn start(self) {
+        {
+            let mut threads = self.threads.lock().unwrap();
+            if threads.size() == 0 {
+                threads.resize(1);
+            }
+        }
         thread::Builder::new()
-            .name("miner".to_string())
+            .name("miner-control".to_string())
             .spawn(move || {
-                self.miner_loop();
+                self.control_loop();
             })
             .unwrap();
         info!("Miner initialized into paused mode");
     }
 
-    fn miner_loop(&mut self) {
-        // main mining loop
+    fn control_loop(&self) {
         loop {
-            // check and react to control signals
-            match self.operating_state {
-                OperatingState::Paused => {
-                    let signal = self.control_chan.recv().unwrap();
-                    match signal {
-                        ControlSignal::Exit => {
-                            info!("Miner shutting down");
-                            self.operating_state = OperatingState::ShutDown;
-                        }
-                        ControlSignal::Start(i) => {
-                            info!("Miner starting in continuous mode with lambda {}", i);
-                            self.operating_state = OperatingState::Run(i);
-                        }
-                        ControlSignal::Update => {
-                            // in paused state, don't need to update
-                        }
-                    };
-                    continue;
-                }
-                OperatingState::ShutDown => {
+            match self.control_chan.recv() {
+                Ok(ControlSignal::Exit) | Err(_) => {
+                    info!("Miner shutting down");
+                    self.run.set(None);
+                    {self.threads.lock().unwrap().resize(0)};
                     return;
                 }
-                _ => match self.control_chan.try_recv() {
-                    Ok(signal) => {
-                        match signal {
-                            ControlSignal::Exit => {
-                                info!("Miner shutting down");
-                                self.operating_state = OperatingState::ShutDown;
-                            }
-                            ControlSignal::Start(i) => {
-                                info!("Miner starting in continuous mode with lambda {}", i);
-                                self.operating_state = OperatingState::Run(i);
-                            }
-                            ControlSignal::Update => {
-                                unimplemented!()
-                            }
-                        };
-                    }
-                    Err(TryRecvError::Empty) => {}
-                    Err(TryRecvError::Disconnected) => panic!("Miner control channel detached"),
-                },
-            }
-            if let OperatingState::ShutDown = self.operating_state {
-                return;
-            }
-            let tip = {self.arc_mutex.lock().unwrap().tip().clone()};
-            let state_copy = {self.arc_mutex.lock().unwrap().state_map.get(&tip).unwrap().clone()};
-
-            let mut this_block_transactions= Vec::new();
-            // if the block is consistent with the difficulty of the blockchain, insert the block into the blockchain
-            // update the block's transactions based on the mempool before inserting into the blockchain
-            let mut count = 0;
-            let mut included_senders = Vec::new();
-
-            for (hash, transaction) in self.mempool.lock().unwrap().hash_map.clone() {
-            
-                let mut transaction_is_valid = true;
-
-                let sender = Address::from_public_key_bytes(transaction.signer_public_key.as_slice());
-                if included_senders.contains(&sender) {
-                    continue;
-                }
-                let tx_amount = transaction.t.value;
-                let tx_nonce = transaction.t.account_nonce;
-                // update the state and append the transaction if the transaction is valid
-                if state_copy.state.contains_key(&sender) {
-                    if tx_amount > state_copy.state.get(&sender).unwrap().1 || tx_nonce != state_copy.state.get(&sender).unwrap().0 + 1{
-                        transaction_is_valid = false;
-                    }
+                Ok(ControlSignal::Start(i)) => {
+                    info!("Miner starting in continuous mode with lambda {}", i);
+                    self.run.set(Some(i));
                 }
-                else {
-                    transaction_is_valid = false;
+                Ok(ControlSignal::Update) => {
+                    // every nonce is tried on a block built from the current tip and mempool
                 }
-            
-                // only append transaction if it is valid
-                if transaction_is_valid {
-                    included_senders.push(sender);
-                    this_block_transactions.push(transaction);
-                    count += 1;
-                    if count >= 16 {
-                        break;
-                    }
-                } 
-            }                    
-
-            // After initializing the transactions, initialize timestap, difficulty, content, merkle root, and nonce
-            let parent = tip;
-            let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
-            let difficulty = {self.arc_mutex.lock().unwrap().hash_map.get(&parent).unwrap().get_difficulty()};
-
-            let merkle_tree = MerkleTree::new(&this_block_transactions);
-            let merkle_root = merkle_tree.root(); // hash of the block is the hash of the merkle root
-
-            let mut rng = rand::thread_rng();
-            let nonce: usize = rng.gen();
-
-            let length = {self.arc_mutex.lock().unwrap().hash_map.get(&parent).unwrap().header.length + 1};
-
-            let header = Header {
-                parent,
-                nonce,
-                difficulty,
-                timestamp,
-                merkle_root,
-                length,
-            };
+            }
+        }
+    }
+}
 
-            let content = Content {
-                transactions: this_block_transactions.to_vec(),
+impl Miner {
+    // main mining loop of one thread
+    fn mine(&self, stop: &AtomicBool) {
+        while !stop.load(Ordering::Relaxed) {
+            let lambda = match self.run.wait() {
+                Some(lambda) => lambda,
+                None => continue,
             };
-            
-            let block = Block {header, content};
-            
-
-            if block.hash() <= difficulty && count > 0 {            
-                println!("new block passing difficulty check with transaction length {}", block.content.transactions.len());
-                // only remove the transactions from the mempool after the block is passed through
-                for transaction in block.content.transactions.clone() {
-                    println!("calling lock in miner 6");
-                    {self.mempool.lock().unwrap().hash_map.remove(&transaction.hash())};
-                }
-
-                {self.arc_mutex.lock().unwrap().insert(&block)}; 
-                println!(" new block inserted");
-
-                self.finished_block_chan.send(block.clone()).expect("Send finished block error");
-
+            self.try_nonce();
+            if lambda != 0 {
+                let interval = time::Duration::from_micros(lambda);
+                thread::sleep(interval);
             }
+        }
+    }
 
-            if let OperatingState::Run(i) = self.operating_state {
-                if i != 0 {
-                    let interval = time::Duration::from_micros(i as u64);
-                    thread::sleep(interval);
+    // build a block on the tip from the mempool and try one random nonce on it
+    fn try_nonce(&self) {
+        let tip = {self.arc_mutex.lock().unwrap().tip().clone()};
+        // the transactions picked so far are applied, so each one is checked on top of them
+        let mut state_copy = {self.arc_mutex.lock().unwrap().state_map.get(&tip).unwrap().clone()};
+        state_copy.start_block();
+        let validators = {self.arc_mutex.lock().unwrap().validators()};
+
+        let mut this_block_transactions= Vec::new();
+        // if the block is consistent with the difficulty of the blockchain, insert the block into the blockchain
+        // update the block's transactions based on the mempool before inserting into the blockchain
+        let mut count = 0;
+        let mut included_senders = Vec::new();
+
+        for (hash, transaction) in self.mempool.lock().unwrap().hash_map.clone() {
+        
+            let mut transaction_is_valid = true;
+
+            let sender = Address::from_public_key_bytes(transaction.signer_public_key.as_slice());
+            if included_senders.contains(&sender) {
+                continue;
+            }
+            let tx_amount = transaction.t.value;
+            let tx_nonce = transaction.t.account_nonce;
+            // update the state and append the transaction if the transaction is valid
+            if !state_copy.can_send(&sender, tx_amount, tx_nonce)
+                || validators.check_transaction(&transaction, &state_copy).is_err()
+                || state_copy.apply_transaction(&transaction).is_err()
+            {
+                transaction_is_valid = false;
+            }
+        
+            // only append transaction if it is valid
+            if transaction_is_valid {
+                included_senders.push(sender);
+                this_block_transactions.push(transaction);
+                count += 1;
+                if count >= 16 {
+                    break;
                 }
+            } 
+        }                    
+
+        // After initializing the transactions, initialize timestap, difficulty, content, merkle root, and nonce
+        let parent = tip;
+        // a clock behind the last blocks still has to produce a valid timestamp
+        let median_time_past = {self.arc_mutex.lock().unwrap().median_time_past(&parent)};
+        let timestamp = {self.clock.lock().unwrap().now()}.max(median_time_past + 1);
+        let difficulty = {self.arc_mutex.lock().unwrap().next_difficulty(&parent)};
+        let pow = {self.arc_mutex.lock().unwrap().pow()};
+
+        let merkle_tree = MerkleTree::new(&this_block_transactions);
+        let merkle_root = merkle_tree.root(); // hash of the block is the hash of the merkle root
+
+        let mut rng = rand::thread_rng();
+        let nonce: usize = rng.gen();
+
+        let (length, parent_timestamp) = {
+            let blockchain = self.arc_mutex.lock().unwrap();
+            let parent_header = blockchain.header(&parent).unwrap();
+            (parent_header.length + 1, parent_header.timestamp)
+        };
+
+        let header = Header {
+            parent,
+            nonce,
+            difficulty,
+            timestamp,
+            merkle_root,
+            length,
+        };
+
+        let content = Content {
+            transactions: this_block_transactions.to_vec(),
+        };
+        
+        let block = Block {header, content};
+        self.hash_count.fetch_add(1, Ordering::Relaxed);
+        self.efficiency.lock().unwrap().hash(parent, merkle_root, parent_timestamp, timestamp);
+        
+
+        if pow.meets_target(&block.header) && count > 0 {            
+            println!("new block passing difficulty check with transaction length {}", block.content.transactions.len());
+            // only remove the transactions from the mempool after the block is passed through,
+            // with the blockchain locked so they are not admitted again before it is inserted
+            {
+                let mut blockchain = self.arc_mutex.lock().unwrap();
+                blockchain.insert(&block);
+                let mut mempool = self.mempool.lock().unwrap();
+                for transaction in block.content.transactions.iter() {
+                    mempool.hash_map.remove(&transaction.hash());
+                }
+                invariants::enforce(&blockchain, Some(&mempool), &format!("mined block {}", block.hash()));
             }
+            println!(" new block inserted");
+            self.efficiency.lock().unwrap().mined(block.hash());
+
+            self.finished_block_chan.send(block.clone()).expect("Send finished block error");
+
         }
     }
 }
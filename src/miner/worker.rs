@@ -1,6 +1,6 @@
 use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
 use log::{debug, info};
-use crate::network::message::Message::{NewBlockHashes, self};
+use crate::network::message::{InvItem, Message};
 use crate::types::block::Block;
 use crate::network::server::Handle as ServerHandle;
 use crate::blockchain::{Blockchain, Mempool};
@@ -46,8 +46,8 @@ impl Worker {
             let _block = self.finished_block_chan.recv().expect("Receive finished block error");
             // broadcast the block hash
             let _block_hash = _block.hash();
-            let block_vector = vec![_block_hash];
-            self.server.broadcast(NewBlockHashes(block_vector));
+            let block_vector = vec![InvItem::Block(_block_hash)];
+            self.server.broadcast(Message::Inv(block_vector));
         }
     }
 
@@ -0,0 +1,403 @@
+use super::features::{Features, PeerFeatures};
+use super::message::Message;
+use super::server::Handle as ServerHandle;
+
+use log::{debug, info, warn};
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+use std::collections::btree_map::{BTreeMap, Entry};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// the wait before redialing a peer, doubled for each attempt in a row that fails
+const FIRST_RETRY_DELAY: Duration = Duration::from_secs(1);
+// a connection that lasted this long was a success, one dropped sooner counts as a failed attempt
+// so a peer refusing us right after connecting is not redialed every second
+const STABLE_CONNECTION: Duration = Duration::from_secs(30);
+// addresses learned from peers are forgotten after failing this many times without ever connecting
+const FORGET_AFTER_FAILURES: u32 = 3;
+/// Most addresses kept, and sent in answer to a `GetAddr`
+pub const MAX_ADDRESSES: usize = 1000;
+/// Addr messages with at most this many addresses are advertisements, relayed when news to us
+pub const RELAY_ADDRESSES: usize = 10;
+/// Number of peers new addresses are relayed to
+pub const RELAY_FANOUT: usize = 2;
+
+/// `addr` with an IPv4-mapped IPv6 address, which is how a dual-stack listener sees IPv4 peers,
+/// turned back into the plain IPv4 address, so a node has one address whichever way it is reached
+pub fn canonical(addr: SocketAddr) -> SocketAddr {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port())
+}
+
+/// Whether `addr` reaches the node listening at `listen_addr`. A node listening on every
+/// interface cannot know all its addresses, but its loopback ones at least are its own.
+pub fn is_own_address(addr: &SocketAddr, listen_addr: &SocketAddr) -> bool {
+    let addr = canonical(*addr);
+    if addr == canonical(*listen_addr) {
+        return true;
+    }
+    listen_addr.ip().is_unspecified() && addr.port() == listen_addr.port() && addr.ip().is_loopback()
+}
+
+/// Whether `addr` could be a node to dial, rather than a placeholder or a group address
+pub fn is_dialable(addr: &SocketAddr) -> bool {
+    let ip = addr.ip().to_canonical();
+    let unroutable = match ip {
+        IpAddr::V4(ip) => ip.is_broadcast(),
+        // a link-local address needs the scope of the interface it is on, which only its owner knows
+        IpAddr::V6(ip) => ip.is_unicast_link_local(),
+    };
+    addr.port() != 0 && !ip.is_unspecified() && !ip.is_multicast() && !unroutable
+}
+
+/// What we know of a peer we dial, kept across restarts.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct PeerRecord {
+    /// when we were last connected to it, in ms since the unix epoch, 0 if never
+    pub last_seen: u64,
+    /// connections that were established
+    pub successes: u32,
+    /// attempts that failed, and connections dropped before they were stable
+    pub failures: u32,
+    /// whether a peer told us about it, rather than it being given with -c or --seed
+    #[serde(default)]
+    pub learned: bool,
+    #[serde(skip)]
+    connected_at: Option<Instant>,
+    #[serde(skip)]
+    failures_in_a_row: u32,
+    #[serde(skip)]
+    retry_at: Option<Instant>,
+}
+
+/// The addresses of the peers this node dials, with their connection stats, saved in the data
+/// directory so a restarted node reconnects to them. Incoming peers are only recorded once they
+/// advertise their own address, the one they connect from is an ephemeral port we could not dial.
+pub struct AddressBook {
+    path: Option<PathBuf>,
+    peers: BTreeMap<SocketAddr, PeerRecord>,
+    max_backoff: Duration,
+    changed: bool,
+}
+
+impl AddressBook {
+    /// An empty address book kept in memory, redialing at most every `max_backoff`
+    pub fn new(max_backoff: Duration) -> Self {
+        AddressBook { path: None, peers: BTreeMap::new(), max_backoff, changed: false }
+    }
+
+    /// Read the address book saved at `path`, or start an empty one there if there is none
+    pub fn load<P: AsRef<Path>>(path: P, max_backoff: Duration) -> std::io::Result<Self> {
+        let mut book = AddressBook::new(max_backoff);
+        book.path = Some(path.as_ref().to_path_buf());
+        match std::fs::read_to_string(&path) {
+            Ok(json) => {
+                book.peers = serde_json::from_str(&json).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+                Ok(book)
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(book),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Write the address book back to its file if it changed since the last save
+    pub fn save(&mut self) -> std::io::Result<()> {
+        if let (Some(path), true) = (&self.path, self.changed) {
+            std::fs::write(path, serde_json::to_string_pretty(&self.peers).unwrap())?;
+        }
+        self.changed = false;
+        Ok(())
+    }
+
+    /// Remember a peer to dial, right away if it is new
+    pub fn add(&mut self, addr: SocketAddr) {
+        let addr = canonical(addr);
+        if let Entry::Vacant(entry) = self.peers.entry(addr) {
+            entry.insert(PeerRecord::default());
+            self.changed = true;
+        }
+    }
+
+    /// Remember a peer some other peer told us about, unless the address book is full
+    pub fn learn(&mut self, addr: SocketAddr) -> bool {
+        let addr = canonical(addr);
+        if self.peers.len() >= MAX_ADDRESSES || !is_dialable(&addr) {
+            return false;
+        }
+        match self.peers.entry(addr) {
+            Entry::Vacant(entry) => {
+                entry.insert(PeerRecord { learned: true, ..Default::default() });
+                self.changed = true;
+                true
+            }
+            Entry::Occupied(_) => false,
+        }
+    }
+
+    /// Known addresses to tell a peer about, those we connected to most often first
+    pub fn addresses(&self, limit: usize) -> Vec<SocketAddr> {
+        let mut peers: Vec<(&SocketAddr, &PeerRecord)> = self.peers.iter().collect();
+        peers.sort_by_key(|(_, record)| std::cmp::Reverse(record.successes));
+        peers.into_iter().take(limit).map(|(addr, _)| *addr).collect()
+    }
+
+    /// Number of peers of the address book we are connected to
+    pub fn outbound(&self) -> usize {
+        self.peers.values().filter(|record| record.connected_at.is_some()).count()
+    }
+
+    /// Peers of the address book we are connected to
+    pub fn connected_peers(&self) -> Vec<SocketAddr> {
+        self.peers
+            .iter()
+            .filter(|(_, record)| record.connected_at.is_some())
+            .map(|(addr, _)| *addr)
+            .collect()
+    }
+
+    pub fn get(&self, addr: &SocketAddr) -> Option<&PeerRecord> {
+        self.peers.get(addr)
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+
+    /// Record that we connected to `addr`, adding it if it is new
+    pub fn connected(&mut self, addr: SocketAddr, now: Instant) {
+        let record = self.peers.entry(addr).or_default();
+        record.last_seen = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+        record.successes += 1;
+        record.connected_at = Some(now);
+        record.retry_at = None;
+        self.changed = true;
+    }
+
+    /// Record that dialing `addr` failed, returns how long until it is dialed again
+    pub fn failed(&mut self, addr: SocketAddr, now: Instant) -> Duration {
+        let max_backoff = self.max_backoff;
+        let record = self.peers.entry(addr).or_default();
+        record.failures += 1;
+        self.changed = true;
+        let delay = Self::back_off(record, now, max_backoff);
+        // an address we were told about that never worked is likely stale
+        if record.learned && record.successes == 0 && record.failures >= FORGET_AFTER_FAILURES {
+            debug!("Forgetting peer {}, never reached it", addr);
+            self.peers.remove(&addr);
+        }
+        delay
+    }
+
+    /// Record that the connection to `addr` was dropped, returns how long until it is dialed
+    /// again, or None if it is not a peer we dial
+    pub fn disconnected(&mut self, addr: SocketAddr, now: Instant) -> Option<Duration> {
+        let max_backoff = self.max_backoff;
+        let record = self.peers.get_mut(&addr)?;
+        let connected_at = record.connected_at.take()?;
+        if now.saturating_duration_since(connected_at) >= STABLE_CONNECTION {
+            record.failures_in_a_row = 0;
+        } else {
+            record.failures += 1;
+            self.changed = true;
+        }
+        Some(Self::back_off(record, now, max_backoff))
+    }
+
+    fn back_off(record: &mut PeerRecord, now: Instant, max_backoff: Duration) -> Duration {
+        let delay = FIRST_RETRY_DELAY
+            .checked_mul(2u32.saturating_pow(record.failures_in_a_row))
+            .unwrap_or(max_backoff)
+            .min(max_backoff);
+        record.failures_in_a_row += 1;
+        record.retry_at = Some(now + delay);
+        delay
+    }
+
+    /// Peers that are not connected and whose backoff is over at `now`, the ones given with -c
+    /// or --seed first, then those we connected to most often
+    pub fn due(&self, now: Instant) -> Vec<SocketAddr> {
+        let mut due: Vec<(&SocketAddr, &PeerRecord)> = self
+            .peers
+            .iter()
+            .filter(|(_, record)| record.connected_at.is_none() && record.retry_at.is_none_or(|at| at <= now))
+            .collect();
+        due.sort_by_key(|(_, record)| (record.learned, std::cmp::Reverse(record.successes)));
+        due.into_iter().map(|(addr, _)| *addr).collect()
+    }
+}
+
+/// Keeps this node connected to up to `max_outbound` peers of its address book: dials the ones
+/// that are due every `interval` and saves the address book when their stats changed.
+pub struct Dialer {
+    book: Arc<Mutex<AddressBook>>,
+    server: ServerHandle,
+    interval: Duration,
+    max_outbound: usize,
+}
+
+impl Dialer {
+    pub fn new(book: &Arc<Mutex<AddressBook>>, server: &ServerHandle, interval: Duration, max_outbound: usize) -> Self {
+        Dialer { book: Arc::clone(book), server: server.clone(), interval, max_outbound }
+    }
+
+    pub fn start(self) {
+        thread::Builder::new()
+            .name("peer-dialer".to_string())
+            .spawn(move || loop {
+                let (due, outbound) = {
+                    let book = self.book.lock().unwrap();
+                    (book.due(Instant::now()), book.outbound())
+                };
+                for addr in due.into_iter().take(self.max_outbound.saturating_sub(outbound)) {
+                    debug!("Dialing peer {}", addr);
+                    // the server records the outcome in the address book
+                    match self.server.connect(addr) {
+                        Ok(_) => info!("Connected to outgoing peer {}", addr),
+                        Err(e) => {
+                            let retry_at = {self.book.lock().unwrap().get(&addr).and_then(|record| record.retry_at)};
+                            let delay = retry_at.map(|at| at.saturating_duration_since(Instant::now())).unwrap_or_default();
+                            warn!("Error connecting to peer {}, retrying in {:?}: {}", addr, delay, e);
+                        }
+                    }
+                }
+                if let Err(e) = self.book.lock().unwrap().save() {
+                    warn!("Error saving the address book: {}", e);
+                }
+                thread::sleep(self.interval);
+            })
+            .unwrap();
+    }
+}
+
+/// Every `interval`, ask one of the peers we dial that supports peer exchange for the addresses it
+/// knows, so nodes keep learning about each other after they connected.
+pub fn start_exchange(
+    interval: Duration,
+    server: &ServerHandle,
+    book: &Arc<Mutex<AddressBook>>,
+    peer_features: &Arc<Mutex<PeerFeatures>>,
+) {
+    let server = server.clone();
+    let book = Arc::clone(book);
+    let peer_features = Arc::clone(peer_features);
+    thread::Builder::new()
+        .name("addr-exchange".to_string())
+        .spawn(move || loop {
+            thread::sleep(interval);
+            let connected = {book.lock().unwrap().connected_peers()};
+            let candidates: Vec<SocketAddr> = {
+                let peer_features = peer_features.lock().unwrap();
+                connected.into_iter().filter(|peer| peer_features.supports(peer, Features::PEER_EXCHANGE)).collect()
+            };
+            if let Some(peer) = candidates.choose(&mut rand::thread_rng()) {
+                debug!("Asking peer {} for the addresses it knows", peer);
+                server.send_to(*peer, Message::GetAddr);
+            }
+        })
+        .unwrap();
+    info!("Exchanging peer addresses every {:?}", interval);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles_until_a_stable_connection() {
+        let addr: SocketAddr = "127.0.0.1:6000".parse().unwrap();
+        let mut book = AddressBook::new(Duration::from_secs(5));
+        let start = Instant::now();
+        book.add(addr);
+        assert_eq!(book.due(start), vec![addr]);
+        assert_eq!(book.failed(addr, start), Duration::from_secs(1));
+        assert!(book.due(start).is_empty());
+        assert_eq!(book.due(start + Duration::from_secs(1)), vec![addr]);
+        assert_eq!(book.failed(addr, start), Duration::from_secs(2));
+        assert_eq!(book.failed(addr, start), Duration::from_secs(4));
+        assert_eq!(book.failed(addr, start), Duration::from_secs(5));
+        // dropped right after connecting, as by a peer on another chain, it keeps backing off
+        book.connected(addr, start);
+        assert!(book.due(start + Duration::from_secs(60)).is_empty());
+        assert_eq!(book.disconnected(addr, start + Duration::from_secs(1)), Some(Duration::from_secs(5)));
+        // a stable connection starts over
+        book.connected(addr, start);
+        assert_eq!(book.disconnected(addr, start + STABLE_CONNECTION), Some(Duration::from_secs(1)));
+        let record = book.get(&addr).unwrap();
+        assert_eq!((record.successes, record.failures), (2, 5));
+        // incoming peers are not in the book
+        assert_eq!(book.disconnected("127.0.0.1:50000".parse().unwrap(), start), None);
+    }
+
+    #[test]
+    fn ipv6_addresses() {
+        let mut book = AddressBook::new(Duration::from_secs(60));
+        assert!(book.learn("[2001:db8::1]:6000".parse().unwrap()));
+        assert!(book.learn("[::1]:6001".parse().unwrap()));
+        // an IPv4 peer seen through a dual-stack listener is the same peer
+        assert!(book.learn("[::ffff:127.0.0.1]:6002".parse().unwrap()));
+        assert!(!book.learn("127.0.0.1:6002".parse().unwrap()));
+        assert!(book.get(&"127.0.0.1:6002".parse().unwrap()).is_some());
+        assert!(!book.learn("[::]:6003".parse().unwrap()));
+        assert!(!book.learn("[::ffff:0.0.0.0]:6003".parse().unwrap()));
+        assert!(!book.learn("[ff02::1]:6003".parse().unwrap()));
+        assert!(!book.learn("[fe80::1]:6003".parse().unwrap()));
+        assert_eq!(book.len(), 3);
+
+        let dual_stack: SocketAddr = "[::]:6000".parse().unwrap();
+        assert!(is_own_address(&"127.0.0.1:6000".parse().unwrap(), &dual_stack));
+        assert!(is_own_address(&"[::1]:6000".parse().unwrap(), &dual_stack));
+        assert!(!is_own_address(&"[::1]:6001".parse().unwrap(), &dual_stack));
+        assert!(!is_own_address(&"[::1]:6000".parse().unwrap(), &"127.0.0.1:6000".parse().unwrap()));
+    }
+
+    #[test]
+    fn learned_addresses_are_forgotten_when_stale() {
+        let seed: SocketAddr = "127.0.0.1:6000".parse().unwrap();
+        let learned: SocketAddr = "127.0.0.1:6001".parse().unwrap();
+        let mut book = AddressBook::new(Duration::from_secs(60));
+        let start = Instant::now();
+        book.add(seed);
+        assert!(book.learn(learned));
+        assert!(!book.learn(seed));
+        assert!(!book.learn("0.0.0.0:6002".parse().unwrap()));
+        assert!(!book.learn("224.0.0.1:6002".parse().unwrap()));
+        assert!(!book.learn("127.0.0.1:0".parse().unwrap()));
+        // peers given on the command line are dialed first
+        assert_eq!(book.due(start), vec![seed, learned]);
+        book.connected(seed, start);
+        assert_eq!(book.outbound(), 1);
+        assert_eq!(book.connected_peers(), vec![seed]);
+        assert_eq!(book.addresses(1), vec![seed]);
+        for _ in 0..FORGET_AFTER_FAILURES {
+            book.failed(learned, start);
+        }
+        assert!(book.get(&learned).is_none());
+        // the ones given on the command line are kept trying
+        for _ in 0..FORGET_AFTER_FAILURES {
+            book.failed(seed, start);
+        }
+        assert!(book.get(&seed).is_some());
+    }
+
+    #[test]
+    fn save_and_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("peers.json");
+        let addr: SocketAddr = "127.0.0.1:6001".parse().unwrap();
+        let mut book = AddressBook::load(&path, Duration::from_secs(60)).unwrap();
+        assert!(book.is_empty());
+        book.connected(addr, Instant::now());
+        book.save().unwrap();
+        let loaded = AddressBook::load(&path, Duration::from_secs(60)).unwrap();
+        assert_eq!(loaded.get(&addr).unwrap().successes, 1);
+        // a restarted node dials its peers right away
+        assert_eq!(loaded.due(Instant::now()), vec![addr]);
+    }
+}
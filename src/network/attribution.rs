@@ -0,0 +1,113 @@
+use super::message::InvItem;
+
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+
+/// Most blocks and transactions whose first deliverer is remembered, the oldest are forgotten first
+pub const MAX_FIRST_SEEN: usize = 100_000;
+
+/// What one peer delivered before any other peer did, counting only what we accepted.
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PeerContribution {
+    pub blocks: u64,
+    pub transactions: u64,
+}
+
+struct Delivery {
+    peer: SocketAddr,
+    accepted: bool,
+}
+
+/// Which peer first delivered each block and transaction. A delivery is credited to the peer
+/// only once the item is accepted, so an orphan is credited when its parent connects it, and
+/// invalid items are never credited.
+pub struct FirstSeen {
+    capacity: usize,
+    deliveries: HashMap<InvItem, Delivery>,
+    order: VecDeque<InvItem>, // oldest first
+    contributions: HashMap<SocketAddr, PeerContribution>,
+}
+
+impl FirstSeen {
+    pub fn new(capacity: usize) -> Self {
+        FirstSeen {
+            capacity,
+            deliveries: HashMap::new(),
+            order: VecDeque::new(),
+            contributions: HashMap::new(),
+        }
+    }
+
+    /// `peer` delivered `item`, which only counts if no peer delivered it before
+    pub fn on_delivered(&mut self, item: InvItem, peer: SocketAddr) {
+        if self.deliveries.contains_key(&item) {
+            return;
+        }
+        self.deliveries.insert(item, Delivery { peer, accepted: false });
+        self.order.push_back(item);
+        while self.order.len() > self.capacity {
+            let oldest = self.order.pop_front().unwrap();
+            self.deliveries.remove(&oldest);
+        }
+    }
+
+    /// `item` was accepted, credit the peer that first delivered it, once
+    pub fn on_accepted(&mut self, item: InvItem) {
+        let delivery = match self.deliveries.get_mut(&item) {
+            Some(delivery) if !delivery.accepted => delivery,
+            _ => return,
+        };
+        delivery.accepted = true;
+        let contribution = self.contributions.entry(delivery.peer).or_default();
+        match item {
+            InvItem::Block(_) => contribution.blocks += 1,
+            InvItem::Transaction(_) => contribution.transactions += 1,
+        }
+    }
+
+    /// The peer that first delivered `item`, None if it was not accepted from a peer or is too old
+    pub fn source(&self, item: &InvItem) -> Option<SocketAddr> {
+        self.deliveries.get(item).filter(|delivery| delivery.accepted).map(|delivery| delivery.peer)
+    }
+
+    /// Contributions of every peer that was credited, disconnected peers included
+    pub fn contributions(&self) -> &HashMap<SocketAddr, PeerContribution> {
+        &self.contributions
+    }
+}
+
+impl Default for FirstSeen {
+    fn default() -> Self {
+        Self::new(MAX_FIRST_SEEN)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::hash::H256;
+
+    #[test]
+    fn first_accepted_delivery_is_credited() {
+        let mut first_seen = FirstSeen::new(2);
+        let (a, b): (SocketAddr, SocketAddr) = ("127.0.0.1:6001".parse().unwrap(), "127.0.0.1:6002".parse().unwrap());
+        let block = InvItem::Block(H256::from([1; 32]));
+        let tx = InvItem::Transaction(H256::from([2; 32]));
+        first_seen.on_delivered(block, a);
+        first_seen.on_delivered(block, b);
+        first_seen.on_delivered(tx, b);
+        // not accepted yet
+        assert_eq!(first_seen.source(&block), None);
+        first_seen.on_accepted(block);
+        first_seen.on_accepted(block);
+        first_seen.on_accepted(tx);
+        assert_eq!(first_seen.source(&block), Some(a));
+        assert_eq!(first_seen.contributions()[&a], PeerContribution { blocks: 1, transactions: 0 });
+        assert_eq!(first_seen.contributions()[&b], PeerContribution { blocks: 0, transactions: 1 });
+        // the oldest delivery is forgotten, its credit stays
+        first_seen.on_delivered(InvItem::Block(H256::from([3; 32])), b);
+        assert_eq!(first_seen.source(&block), None);
+        assert_eq!(first_seen.contributions()[&a].blocks, 1);
+    }
+}
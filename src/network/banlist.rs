@@ -0,0 +1,120 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Why and until when the peers at an address are refused.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Ban {
+    pub reason: String,
+    /// when the ban ends, in ms since the unix epoch
+    pub until: u64,
+}
+
+/// The time bans are checked against, in ms since the unix epoch
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64
+}
+
+/// The addresses this node refuses to accept connections from or dial, saved in the data
+/// directory so a restart does not lift them. A ban covers every port of an address, as the
+/// peers connecting from it do so from ephemeral ports, and ends by itself once it expires.
+pub struct BanList {
+    path: Option<PathBuf>,
+    bans: BTreeMap<IpAddr, Ban>,
+}
+
+impl BanList {
+    /// An empty ban list kept in memory
+    pub fn new() -> Self {
+        BanList { path: None, bans: BTreeMap::new() }
+    }
+
+    /// Read the ban list saved at `path` without the bans expired at `now`, or start an empty
+    /// one there if there is none
+    pub fn load<P: AsRef<Path>>(path: P, now: u64) -> io::Result<Self> {
+        let mut list = BanList::new();
+        list.path = Some(path.as_ref().to_path_buf());
+        match std::fs::read_to_string(&path) {
+            Ok(json) => {
+                list.bans = serde_json::from_str(&json).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                list.expire(now);
+                Ok(list)
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(list),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn save(&self) -> io::Result<()> {
+        match &self.path {
+            Some(path) => std::fs::write(path, serde_json::to_string_pretty(&self.bans).unwrap()),
+            None => Ok(()),
+        }
+    }
+
+    // forget the bans that ended by `now`
+    fn expire(&mut self, now: u64) {
+        self.bans.retain(|_, ban| ban.until > now);
+    }
+
+    /// Ban `ip` until `until`, replacing a ban it is under, and save the list
+    pub fn ban(&mut self, ip: IpAddr, reason: &str, until: u64) -> io::Result<()> {
+        self.bans.insert(ip.to_canonical(), Ban { reason: reason.to_string(), until });
+        self.save()
+    }
+
+    /// Lift the ban of `ip` and save the list. False if it was not banned.
+    pub fn unban(&mut self, ip: &IpAddr) -> io::Result<bool> {
+        if self.bans.remove(&ip.to_canonical()).is_none() {
+            return Ok(false);
+        }
+        self.save()?;
+        Ok(true)
+    }
+
+    /// The ban `ip` is under at `now`, if any
+    pub fn check(&mut self, ip: &IpAddr, now: u64) -> Option<Ban> {
+        self.expire(now);
+        self.bans.get(&ip.to_canonical()).cloned()
+    }
+
+    /// Every ban still in force at `now`
+    pub fn bans(&mut self, now: u64) -> Vec<(IpAddr, Ban)> {
+        self.expire(now);
+        self.bans.iter().map(|(ip, ban)| (*ip, ban.clone())).collect()
+    }
+}
+
+impl Default for BanList {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bans_outlive_restarts_until_they_expire() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bans.json");
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let mut list = BanList::load(&path, 0).unwrap();
+        list.ban(ip, "sent invalid blocks", 1000).unwrap();
+        list.ban("10.0.0.2".parse().unwrap(), "spam", 5000).unwrap();
+        // a peer reaching a dual-stack listener over IPv4 is the same address
+        let mapped: IpAddr = "::ffff:10.0.0.1".parse().unwrap();
+        assert_eq!(list.check(&mapped, 500).unwrap().reason, "sent invalid blocks");
+
+        let mut loaded = BanList::load(&path, 500).unwrap();
+        assert_eq!(loaded.bans(500).len(), 2);
+        assert_eq!(loaded.check(&ip, 1000), None);
+        assert!(loaded.unban(&"10.0.0.2".parse().unwrap()).unwrap());
+        assert!(!loaded.unban(&ip).unwrap());
+        assert!(BanList::load(&path, 0).unwrap().bans(0).is_empty());
+    }
+}
@@ -0,0 +1,77 @@
+/// Blocks behind the best tip of our peers from which transaction gossip is ignored by default
+pub const DEFAULT_PAUSE_BEHIND: u32 = 10;
+
+/// Change of whether transaction gossip is ignored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transition {
+    Paused,
+    Resumed,
+}
+
+/// Whether to ignore the transactions peers relay because we are catching up on blocks. More
+/// than `behind` blocks below the best tip of our peers, which several of them must have
+/// reported (see `PeerTips::best`), the mempool built from gossip
+/// would mostly be spent by the blocks still to come, and verifying floods of transactions
+/// slows down the validation of those blocks.
+pub struct TxGossipPause {
+    behind: u32,
+    paused: bool,
+    skipped: u64,
+}
+
+impl TxGossipPause {
+    pub fn new(behind: u32) -> Self {
+        TxGossipPause {
+            behind,
+            paused: false,
+            skipped: 0,
+        }
+    }
+
+    /// Check our `height` against the best tip our peers reported, returns whether transaction
+    /// gossip is ignored and how that changed
+    pub fn update(&mut self, height: u32, peer_height: Option<u32>) -> (bool, Option<Transition>) {
+        let paused = peer_height.is_some_and(|peer_height| peer_height > height.saturating_add(self.behind));
+        let transition = match (self.paused, paused) {
+            (false, true) => Some(Transition::Paused),
+            (true, false) => Some(Transition::Resumed),
+            _ => None,
+        };
+        self.paused = paused;
+        (paused, transition)
+    }
+
+    /// Count a message of transaction gossip that was ignored
+    pub fn skip(&mut self) {
+        self.skipped += 1;
+    }
+
+    /// Messages of transaction gossip ignored so far
+    pub fn skipped(&self) -> u64 {
+        self.skipped
+    }
+}
+
+impl Default for TxGossipPause {
+    fn default() -> Self {
+        Self::new(DEFAULT_PAUSE_BEHIND)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paused_while_far_behind() {
+        let mut pause = TxGossipPause::new(10);
+        // no peer told us its tip
+        assert_eq!(pause.update(0, None), (false, None));
+        assert_eq!(pause.update(0, Some(10)), (false, None));
+        assert_eq!(pause.update(0, Some(11)), (true, Some(Transition::Paused)));
+        pause.skip();
+        assert_eq!(pause.update(5, Some(20)), (true, None));
+        assert_eq!(pause.update(10, Some(20)), (false, Some(Transition::Resumed)));
+        assert_eq!(pause.skipped(), 1);
+    }
+}
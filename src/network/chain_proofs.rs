@@ -0,0 +1,68 @@
+use crate::types::chain_proof::ChainProofSummary;
+use crate::types::hash::H256;
+
+use rand::Rng;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+/// Chain proofs this node asked its peers for, as a light client would, and the outcome of
+/// checking the answers. The verifier picks the seed the samples are drawn from, so a peer
+/// cannot pick the headers it shows.
+#[derive(Debug, Default)]
+pub struct ChainProofRequests {
+    pending: HashMap<SocketAddr, (H256, u32)>,
+    results: HashMap<SocketAddr, Result<ChainProofSummary, String>>,
+}
+
+impl ChainProofRequests {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Ask `peer` for a proof with `draws` samples, replacing an earlier request. Returns the
+    /// seed to send.
+    pub fn request(&mut self, peer: SocketAddr, draws: u32) -> H256 {
+        let seed: [u8; 32] = rand::thread_rng().gen();
+        let seed = H256::from(seed);
+        self.pending.insert(peer, (seed, draws));
+        self.results.remove(&peer);
+        seed
+    }
+
+    /// The seed and draws of the request the proof of `peer` answers, None if we did not ask
+    pub fn answered(&mut self, peer: &SocketAddr) -> Option<(H256, u32)> {
+        self.pending.remove(peer)
+    }
+
+    /// Record the outcome of checking the proof of `peer`
+    pub fn on_result(&mut self, peer: SocketAddr, result: Result<ChainProofSummary, String>) {
+        self.results.insert(peer, result);
+    }
+
+    /// Take the outcome of the last request to `peer`, None while it is not answered
+    pub fn result(&mut self, peer: &SocketAddr) -> Option<Result<ChainProofSummary, String>> {
+        self.results.remove(peer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn answers_match_requests() {
+        let peer: SocketAddr = "127.0.0.1:6001".parse().unwrap();
+        let mut requests = ChainProofRequests::new();
+        assert_eq!(requests.answered(&peer), None);
+        let first = requests.request(peer, 16);
+        let second = requests.request(peer, 32);
+        assert_ne!(first, second);
+        assert_eq!(requests.answered(&peer), Some((second, 32)));
+        // each request is answered once
+        assert_eq!(requests.answered(&peer), None);
+        assert_eq!(requests.result(&peer), None);
+        requests.on_result(peer, Err("bad proof".to_string()));
+        assert_eq!(requests.result(&peer), Some(Err("bad proof".to_string())));
+        assert_eq!(requests.result(&peer), None);
+    }
+}
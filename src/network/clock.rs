@@ -0,0 +1,229 @@
+use log::{debug, info, warn};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// a peer's offset is forgotten once it has not been measured for this long, e.g. after it left
+const SAMPLE_LIFETIME: Duration = Duration::from_secs(600);
+
+/// Largest offset in ms a peer's clock is believed to have; a peer further off is broken or lying
+/// and its samples are discarded. This also bounds how far the peers can move our time, well
+/// within the `MAX_FUTURE_BLOCK_TIME_MS` blocks may be ahead.
+pub const MAX_OFFSET_MS: i64 = 70 * 60 * 1000;
+
+/// Peers that must have been measured before their median offset moves our time, so one or
+/// two peers cannot shift it on their own
+pub const MIN_ADJUST_SAMPLES: usize = 5;
+
+/// Offset of our clock to the clocks of our peers, measured on each keepalive ping: a peer
+/// supporting `Features::CLOCK_SYNC` answers with its time, and the offset is its time minus
+/// ours halfway through the round trip.
+pub struct NetworkClock {
+    offsets: HashMap<SocketAddr, (i64, Instant)>,
+    threshold: Duration,
+    adjust: bool,
+    // whether the median offset was above the threshold at the last sample, to warn once
+    skewed: bool,
+}
+
+/// Measured offsets, for the API.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ClockReport {
+    /// median offset of the peers' clocks to ours in ms, positive if ours is behind
+    pub offset_ms: Option<i64>,
+    pub peers: HashMap<String, i64>,
+    pub threshold_ms: u128,
+    /// whether `NetworkClock::now` follows the median of the peers
+    pub adjusted: bool,
+}
+
+fn local_now() -> u128 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis()
+}
+
+// the peer's time minus ours halfway through the round trip, None if implausible
+fn sample_offset(sent: u128, peer_time: u128, received: u128) -> Option<i64> {
+    if received < sent {
+        return None;
+    }
+    let midpoint = sent.checked_add(received)? / 2;
+    let offset = i128::try_from(peer_time).ok()?.checked_sub(i128::try_from(midpoint).ok()?)?;
+    let offset = i64::try_from(offset).ok()?;
+    if offset.unsigned_abs() > MAX_OFFSET_MS as u64 {
+        return None;
+    }
+    Some(offset)
+}
+
+impl NetworkClock {
+    /// Warn when the median offset goes over `threshold`; with `adjust`, `now` is corrected by it
+    pub fn new(threshold: Duration, adjust: bool) -> Self {
+        NetworkClock { offsets: HashMap::new(), threshold, adjust, skewed: false }
+    }
+
+    /// Record that `peer` answered a ping we sent at `sent` with its time `peer_time`, and we got
+    /// the answer at `received`, all in ms since the unix epoch by the clock that measured them.
+    /// A sample answered before it was sent or off by more than `MAX_OFFSET_MS` is discarded,
+    /// returning false.
+    pub fn on_sample(&mut self, peer: SocketAddr, sent: u128, peer_time: u128, received: u128) -> bool {
+        let offset = match sample_offset(sent, peer_time, received) {
+            Some(offset) => offset,
+            None => {
+                debug!("Discarding clock sample of peer {}: sent {}, answered {}, received {}", peer, sent, peer_time, received);
+                return false;
+            }
+        };
+        self.offsets.insert(peer, (offset, Instant::now()));
+
+        let median = match self.offset() {
+            Some(median) => median,
+            None => return true,
+        };
+        let skewed = median.unsigned_abs() as u128 > self.threshold.as_millis();
+        if skewed && !self.skewed {
+            warn!(
+                "Local clock is {} ms {} the median of {} peers, check the system clock{}",
+                median.abs(),
+                if median > 0 { "behind" } else { "ahead of" },
+                self.offsets.len(),
+                if self.adjust { ", block timestamps follow the peers" } else { "" }
+            );
+        } else if !skewed && self.skewed {
+            info!("Local clock is back within {:?} of the peers", self.threshold);
+        }
+        self.skewed = skewed;
+        true
+    }
+
+    /// Median offset of the recently measured peers' clocks to ours in ms, None without samples
+    pub fn offset(&mut self) -> Option<i64> {
+        self.offsets.retain(|_, (_, measured)| measured.elapsed() < SAMPLE_LIFETIME);
+        let mut offsets: Vec<i64> = self.offsets.values().map(|(offset, _)| *offset).collect();
+        if offsets.is_empty() {
+            return None;
+        }
+        offsets.sort_unstable();
+        let middle = offsets.len() / 2;
+        if offsets.len() % 2 == 1 {
+            Some(offsets[middle])
+        } else {
+            Some(((offsets[middle - 1] as i128 + offsets[middle] as i128) / 2) as i64)
+        }
+    }
+
+    // the median offset, once enough peers were measured to move our time by it
+    fn trusted_offset(&mut self) -> Option<i64> {
+        let offset = self.offset()?;
+        if self.offsets.len() < MIN_ADJUST_SAMPLES {
+            return None;
+        }
+        Some(offset)
+    }
+
+    /// Current time in ms since the unix epoch, corrected by the median offset of the peers if
+    /// adjusting is enabled and at least `MIN_ADJUST_SAMPLES` peers were measured
+    pub fn now(&mut self) -> u128 {
+        if !self.adjust {
+            return local_now();
+        }
+        self.validation_time()
+    }
+
+    /// Time in ms since the unix epoch the timestamps of received blocks are checked at. Our
+    /// clock running behind the peers' makes their fresh blocks look like they come from the
+    /// future, so the tolerance grows by the median offset once `MIN_ADJUST_SAMPLES` peers were
+    /// measured, even without adjusting. Only adjusting also follows peers whose clocks are
+    /// behind ours.
+    pub fn validation_time(&mut self) -> u128 {
+        let now = local_now();
+        match self.trusted_offset() {
+            Some(offset) if self.adjust || offset > 0 => (now as i128).saturating_add(offset as i128).max(0) as u128,
+            _ => now,
+        }
+    }
+
+    pub fn report(&mut self) -> ClockReport {
+        ClockReport {
+            offset_ms: self.offset(),
+            peers: self.offsets.iter().map(|(peer, (offset, _))| (peer.to_string(), *offset)).collect(),
+            threshold_ms: self.threshold.as_millis(),
+            adjusted: self.adjust,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn median_offset() {
+        let mut clock = NetworkClock::new(Duration::from_secs(5), false);
+        assert_eq!(clock.offset(), None);
+        // a round trip from 1000 to 1100 puts the peer's answer at our 1050
+        clock.on_sample(peer(1), 1000, 1050, 1100);
+        assert_eq!(clock.offset(), Some(0));
+        clock.on_sample(peer(2), 1000, 9050, 1100);
+        clock.on_sample(peer(3), 1000, 8050, 1100);
+        assert_eq!(clock.offset(), Some(7000));
+        assert!(clock.skewed);
+        // a peer measured again replaces its previous offset
+        clock.on_sample(peer(2), 2000, 2050, 2100);
+        assert_eq!(clock.offset(), Some(0));
+        assert!(!clock.skewed);
+    }
+
+    #[test]
+    fn implausible_samples_are_discarded() {
+        let mut clock = NetworkClock::new(Duration::from_secs(5), false);
+        assert!(!clock.on_sample(peer(1), 1100, 1050, 1000));
+        assert!(!clock.on_sample(peer(1), u128::MAX, u128::MAX, u128::MAX));
+        assert!(!clock.on_sample(peer(1), 1000, u128::MAX, 1100));
+        assert!(!clock.on_sample(peer(1), 1000, 1050 + MAX_OFFSET_MS as u128 + 1, 1100));
+        assert_eq!(clock.offset(), None);
+        // the most a peer can be off by still counts, and its median does not overflow
+        assert!(clock.on_sample(peer(1), MAX_OFFSET_MS as u128, 0, MAX_OFFSET_MS as u128));
+        assert!(clock.on_sample(peer(2), 0, MAX_OFFSET_MS as u128, 0));
+        assert_eq!(clock.offset(), Some(0));
+    }
+
+    #[test]
+    fn adjusted_now_follows_enough_peers() {
+        let mut clock = NetworkClock::new(Duration::from_secs(5), true);
+        let now = local_now();
+        // too few peers to follow
+        for port in 1..MIN_ADJUST_SAMPLES as u16 {
+            clock.on_sample(peer(port), now, now + 60_000, now);
+        }
+        assert!(clock.now() < now + 60_000);
+        clock.on_sample(peer(MIN_ADJUST_SAMPLES as u16), now, now + 60_000, now);
+        assert!(clock.now() >= now + 60_000);
+        assert!(clock.validation_time() >= now + 60_000);
+        let mut unadjusted = NetworkClock::new(Duration::from_secs(5), false);
+        for port in 1..=MIN_ADJUST_SAMPLES as u16 {
+            unadjusted.on_sample(peer(port), now, now + 60_000, now);
+        }
+        assert!(unadjusted.now() < now + 60_000);
+        // blocks of peers ahead of us are tolerated all the same
+        assert!(unadjusted.validation_time() >= now + 60_000);
+    }
+
+    #[test]
+    fn validation_time_only_follows_peers_behind_when_adjusting() {
+        let now = local_now();
+        let mut unadjusted = NetworkClock::new(Duration::from_secs(5), false);
+        let mut adjusted = NetworkClock::new(Duration::from_secs(5), true);
+        for port in 1..=MIN_ADJUST_SAMPLES as u16 {
+            unadjusted.on_sample(peer(port), now + 60_000, now, now + 60_000);
+            adjusted.on_sample(peer(port), now + 60_000, now, now + 60_000);
+        }
+        assert!(unadjusted.validation_time() >= now);
+        assert!(adjusted.validation_time() < now + 60_000);
+    }
+}
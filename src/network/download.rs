@@ -0,0 +1,277 @@
+use super::message::{InvItem, Message};
+use super::peer::PeerStates;
+use super::routing::RequestRouter;
+use super::server::Handle as ServerHandle;
+use crate::types::hash::H256;
+
+use log::{debug, info, warn};
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Spreads the blocks missing during the initial sync over every ready peer instead of asking
+/// the one that announced them for all of them. Each peer has at most `max_in_flight` blocks
+/// requested at a time, the peers with the lowest round trip time are given work first; a request unanswered after `stall_timeout` goes back to the queue for
+/// another peer, and the peer that stalled gets no new work for the same time.
+pub struct DownloadScheduler {
+    max_in_flight: usize,
+    stall_timeout: Duration,
+    pending: VecDeque<H256>, // oldest first
+    sources: HashMap<H256, Vec<SocketAddr>>, // peers known to have a block, any peer if none
+    in_flight: HashMap<H256, (SocketAddr, Instant)>,
+    stalled: HashMap<SocketAddr, Instant>, // until when a peer gets no work
+    reassigned: u64,
+}
+
+/// Blocks being downloaded, for the API.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct DownloadStatus {
+    pub pending: usize,
+    pub in_flight: HashMap<SocketAddr, usize>,
+    pub stalled: Vec<SocketAddr>,
+    /// requests moved to another peer so far
+    pub reassigned: u64,
+}
+
+impl DownloadScheduler {
+    pub fn new(max_in_flight: usize, stall_timeout: Duration) -> Self {
+        DownloadScheduler {
+            max_in_flight,
+            stall_timeout,
+            pending: VecDeque::new(),
+            sources: HashMap::new(),
+            in_flight: HashMap::new(),
+            stalled: HashMap::new(),
+            reassigned: 0,
+        }
+    }
+
+    /// Queue a missing block, `source` being a peer known to have it
+    pub fn want(&mut self, hash: H256, source: Option<SocketAddr>) {
+        if let Some(source) = source {
+            let sources = self.sources.entry(hash).or_default();
+            if !sources.contains(&source) {
+                sources.push(source);
+            }
+        }
+        if !self.in_flight.contains_key(&hash) && !self.pending.contains(&hash) {
+            self.pending.push_back(hash);
+        }
+    }
+
+    /// Forget a block once it arrived, from whichever peer
+    pub fn on_received(&mut self, hash: &H256) {
+        self.in_flight.remove(hash);
+        self.sources.remove(hash);
+        self.pending.retain(|pending| pending != hash);
+    }
+
+    /// The peer a block was requested from and when, None if it is not in flight
+    pub fn requested_from(&self, hash: &H256) -> Option<(SocketAddr, Instant)> {
+        self.in_flight.get(hash).cloned()
+    }
+
+    /// `peer` does not have the block after all, queue it again for another peer
+    pub fn on_not_found(&mut self, peer: SocketAddr, hash: &H256) {
+        if let Some(sources) = self.sources.get_mut(hash) {
+            sources.retain(|source| *source != peer);
+        }
+        if matches!(self.in_flight.get(hash), Some((asked, _)) if *asked == peer) {
+            self.in_flight.remove(hash);
+            self.pending.push_front(*hash);
+        }
+    }
+
+    /// Decide which blocks to request from which of the `ready` peers at `now`, preferring the
+    /// peers with the lowest round trip time in `rtt`, unmeasured peers last. Requests to peers
+    /// that are gone or stalled are queued again first.
+    pub fn schedule(&mut self, ready: &[SocketAddr], rtt: &HashMap<SocketAddr, Duration>, now: Instant) -> HashMap<SocketAddr, Vec<H256>> {
+        self.stalled.retain(|_, until| *until > now);
+        let mut requeue = Vec::new();
+        for (hash, (peer, since)) in self.in_flight.iter() {
+            if !ready.contains(peer) {
+                debug!("Peer {} left with block {} in flight", peer, hash);
+                requeue.push(*hash);
+            } else if now.saturating_duration_since(*since) >= self.stall_timeout {
+                warn!("Peer {} stalled on block {}, asking another peer", peer, hash);
+                self.stalled.insert(*peer, now + self.stall_timeout);
+                requeue.push(*hash);
+            }
+        }
+        for hash in requeue.into_iter().rev() {
+            self.in_flight.remove(&hash);
+            self.pending.push_front(hash);
+            self.reassigned += 1;
+        }
+
+        let mut load: HashMap<SocketAddr, usize> = ready.iter().map(|peer| (*peer, 0)).collect();
+        for (peer, _) in self.in_flight.values() {
+            if let Some(count) = load.get_mut(peer) {
+                *count += 1;
+            }
+        }
+        let mut routes: HashMap<SocketAddr, Vec<H256>> = HashMap::new();
+        let mut waiting = VecDeque::new();
+        while let Some(hash) = self.pending.pop_front() {
+            // a block whose known sources are all gone can be asked of anyone
+            let sources: Vec<SocketAddr> = self
+                .sources
+                .get(&hash)
+                .map(|sources| sources.iter().filter(|peer| load.contains_key(*peer)).cloned().collect())
+                .unwrap_or_default();
+            let best = load
+                .iter()
+                .filter(|(peer, count)| **count < self.max_in_flight && !self.stalled.contains_key(*peer))
+                .filter(|(peer, _)| sources.is_empty() || sources.contains(*peer))
+                .min_by_key(|(peer, count)| (rtt.get(*peer).cloned().unwrap_or(Duration::MAX), **count, **peer))
+                .map(|(peer, _)| *peer);
+            match best {
+                Some(peer) => {
+                    *load.get_mut(&peer).unwrap() += 1;
+                    self.in_flight.insert(hash, (peer, now));
+                    routes.entry(peer).or_default().push(hash);
+                }
+                None => waiting.push_back(hash),
+            }
+        }
+        self.pending = waiting;
+        routes
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.pending.is_empty() && self.in_flight.is_empty()
+    }
+
+    pub fn status(&self) -> DownloadStatus {
+        let mut in_flight = HashMap::new();
+        for (peer, _) in self.in_flight.values() {
+            *in_flight.entry(*peer).or_insert(0) += 1;
+        }
+        DownloadStatus {
+            pending: self.pending.len(),
+            in_flight,
+            stalled: self.stalled.keys().cloned().collect(),
+            reassigned: self.reassigned,
+        }
+    }
+}
+
+/// Request the blocks `scheduler` assigns to the ready peers, by the round trip times `router`
+/// measured
+pub fn dispatch(scheduler: &Arc<Mutex<DownloadScheduler>>, peer_states: &Arc<Mutex<PeerStates>>, router: &Arc<Mutex<RequestRouter>>, server: &ServerHandle) {
+    let ready = {peer_states.lock().unwrap().ready()};
+    let rtt = {router.lock().unwrap().rtts()};
+    let routes = {scheduler.lock().unwrap().schedule(&ready, &rtt, Instant::now())};
+    for (peer, hashes) in routes {
+        debug!("Requesting {} blocks from peer {}", hashes.len(), peer);
+        server.send_to(peer, Message::GetData(hashes.into_iter().map(InvItem::Block).collect()));
+    }
+}
+
+/// Every `interval`, move the requests of stalled or disconnected peers to other peers
+pub fn start(
+    interval: Duration,
+    scheduler: &Arc<Mutex<DownloadScheduler>>,
+    peer_states: &Arc<Mutex<PeerStates>>,
+    router: &Arc<Mutex<RequestRouter>>,
+    server: &ServerHandle,
+) {
+    let scheduler = Arc::clone(scheduler);
+    let peer_states = Arc::clone(peer_states);
+    let router = Arc::clone(router);
+    let server = server.clone();
+    thread::Builder::new()
+        .name("block-download".to_string())
+        .spawn(move || loop {
+            thread::sleep(interval);
+            if !{scheduler.lock().unwrap().is_idle()} {
+                dispatch(&scheduler, &peer_states, &router, &server);
+            }
+        })
+        .unwrap();
+    info!("Checking block downloads for stalled peers every {:?}", interval);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(i: u8) -> H256 {
+        H256::from([i; 32])
+    }
+
+    #[test]
+    fn work_is_split_across_peers() {
+        let mut scheduler = DownloadScheduler::new(2, Duration::from_secs(5));
+        let rtt = HashMap::new();
+        let a: SocketAddr = "127.0.0.1:6001".parse().unwrap();
+        let b: SocketAddr = "127.0.0.1:6002".parse().unwrap();
+        for i in 0..5 {
+            scheduler.want(hash(i), Some(a));
+        }
+        scheduler.want(hash(5), None);
+        let now = Instant::now();
+        // blocks announced by a go to a only, the one without a source can go to b
+        let routes = scheduler.schedule(&[a, b], &rtt, now);
+        assert_eq!(routes[&a], vec![hash(0), hash(1)]);
+        assert_eq!(routes[&b], vec![hash(5)]);
+        assert_eq!(scheduler.status().pending, 3);
+
+        // once b is known to have them too, the rest is split
+        for i in 2..5 {
+            scheduler.want(hash(i), Some(b));
+        }
+        scheduler.on_received(&hash(0));
+        let routes = scheduler.schedule(&[a, b], &rtt, now);
+        assert_eq!(routes[&a], vec![hash(2)]);
+        assert_eq!(routes[&b], vec![hash(3)]);
+        assert_eq!(scheduler.status().pending, 1);
+    }
+
+    #[test]
+    fn stalled_requests_are_reassigned() {
+        let mut scheduler = DownloadScheduler::new(4, Duration::from_secs(5));
+        let rtt = HashMap::new();
+        let a: SocketAddr = "127.0.0.1:6001".parse().unwrap();
+        let b: SocketAddr = "127.0.0.1:6002".parse().unwrap();
+        let now = Instant::now();
+        scheduler.want(hash(1), None);
+        assert_eq!(scheduler.schedule(&[a], &rtt, now)[&a], vec![hash(1)]);
+        // b connects, nothing moves before the timeout
+        assert!(scheduler.schedule(&[a, b], &rtt, now + Duration::from_secs(1)).is_empty());
+        let routes = scheduler.schedule(&[a, b], &rtt, now + Duration::from_secs(5));
+        assert_eq!(routes[&b], vec![hash(1)]);
+        assert_eq!(scheduler.status().stalled, vec![a]);
+        assert_eq!(scheduler.status().reassigned, 1);
+
+        // b drops, the block goes back to a once it is no longer held back
+        let routes = scheduler.schedule(&[a], &rtt, now + Duration::from_secs(10));
+        assert_eq!(routes[&a], vec![hash(1)]);
+        scheduler.on_not_found(a, &hash(1));
+        assert_eq!(scheduler.status().pending, 1);
+        scheduler.on_received(&hash(1));
+        assert!(scheduler.is_idle());
+    }
+
+    #[test]
+    fn fastest_peers_get_work_first() {
+        let mut scheduler = DownloadScheduler::new(2, Duration::from_secs(5));
+        let a: SocketAddr = "127.0.0.1:6001".parse().unwrap();
+        let b: SocketAddr = "127.0.0.1:6002".parse().unwrap();
+        let c: SocketAddr = "127.0.0.1:6003".parse().unwrap();
+        let rtt = vec![(a, Duration::from_millis(300)), (b, Duration::from_millis(20))].into_iter().collect();
+        for i in 0..5 {
+            scheduler.want(hash(i), None);
+        }
+        let now = Instant::now();
+        // the fastest peer is filled up first, the unmeasured one gets what is left
+        let routes = scheduler.schedule(&[a, b, c], &rtt, now);
+        assert_eq!(routes[&b], vec![hash(0), hash(1)]);
+        assert_eq!(routes[&a], vec![hash(2), hash(3)]);
+        assert_eq!(routes[&c], vec![hash(4)]);
+        assert_eq!(scheduler.requested_from(&hash(0)), Some((b, now)));
+    }
+}
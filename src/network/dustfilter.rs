@@ -0,0 +1,88 @@
+use super::message::InvItem;
+use crate::blockchain::Mempool;
+use crate::types::amount::Amount;
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+/// The dust thresholds peers sent us with `DustFilter`. Transactions pay no fee on this chain,
+/// so their value is what a relay policy refuses them on: announcing a transaction below the
+/// threshold of a peer only makes it fetch the transaction to drop it.
+pub struct DustFilters {
+    thresholds: HashMap<SocketAddr, Amount>,
+}
+
+impl DustFilters {
+    pub fn new() -> Self {
+        DustFilters { thresholds: HashMap::new() }
+    }
+
+    /// Record the threshold `peer` sent, replacing the one it sent before
+    pub fn set(&mut self, peer: SocketAddr, threshold: Amount) {
+        self.thresholds.insert(peer, threshold);
+    }
+
+    pub fn forget(&mut self, peer: &SocketAddr) {
+        self.thresholds.remove(peer);
+    }
+
+    /// Threshold of `peer`, zero if it sent none
+    pub fn threshold(&self, peer: &SocketAddr) -> Amount {
+        self.thresholds.get(peer).copied().unwrap_or(Amount::ZERO)
+    }
+
+    /// The items of `items` to announce to `peer`, without the transactions of `mempool` below
+    /// its threshold. Blocks, and transactions no longer in the mempool, are kept.
+    pub fn filter(&self, peer: &SocketAddr, items: &[InvItem], mempool: &Mempool) -> Vec<InvItem> {
+        let threshold = self.threshold(peer);
+        items
+            .iter()
+            .filter(|item| match item {
+                InvItem::Block(_) => true,
+                InvItem::Transaction(hash) => match mempool.hash_map.get(hash) {
+                    Some(transaction) => transaction.t.value >= threshold,
+                    None => true,
+                },
+            })
+            .cloned()
+            .collect()
+    }
+}
+
+impl Default for DustFilters {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::hash::{H256, Hashable};
+    use crate::types::transaction::{SignedTransaction, Transaction};
+
+    #[test]
+    fn dust_is_not_announced() {
+        let peer: SocketAddr = "127.0.0.1:6001".parse().unwrap();
+        let other: SocketAddr = "127.0.0.1:6002".parse().unwrap();
+        let mut mempool = Mempool::new();
+        let mut items = vec![InvItem::Block(H256::default())];
+        for value in [3, 10] {
+            let transaction = SignedTransaction {
+                t: Transaction { value: Amount::from_sub_units(value), ..Default::default() },
+                ..Default::default()
+            };
+            items.push(InvItem::Transaction(transaction.hash()));
+            mempool.hash_map.insert(transaction.hash(), transaction);
+        }
+        // gone from the mempool, its value is unknown
+        items.push(InvItem::Transaction(H256::from([1; 32])));
+
+        let mut filters = DustFilters::new();
+        filters.set(peer, Amount::from_sub_units(10));
+        assert_eq!(filters.filter(&peer, &items, &mempool), vec![items[0], items[2], items[3]]);
+        assert_eq!(filters.filter(&other, &items, &mempool), items);
+        filters.forget(&peer);
+        assert_eq!(filters.threshold(&peer), Amount::ZERO);
+    }
+}
@@ -0,0 +1,435 @@
+use super::message::Message;
+use super::peer::Direction;
+
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use ring::signature::{self, Ed25519KeyPair, KeyPair, ED25519};
+use smol::Timer;
+use snow::StatelessTransportState;
+use std::collections::HashSet;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+// the handshake and transport encryption are Noise, as implemented by snow
+const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+// mixed into the handshake, so a Noise handshake of another protocol does not complete as ours
+const PROLOGUE: &[u8] = b"bitcoin-p2p";
+// signed by the node key together with the Noise static key, binding one to the other
+const IDENTITY_CONTEXT: &[u8] = b"bitcoin-p2p-static-key:";
+// the initiator's first message is a ping legacy peers answer, carrying this and its Noise `e`
+const HELLO_PREFIX: &str = "encrypt:";
+// starts the responder's reply, no bincode encoded message starts with it
+const REPLY_MAGIC: &[u8; 8] = b"\xffENCRYPT";
+// the first frame of a legacy peer is its Version, this is plenty
+const MAX_HANDSHAKE_FRAME: usize = 64 * 1024;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// a public key and a signature of the Noise static key
+const IDENTITY_LEN: usize = 32 + 64;
+// a Noise message holds at most 65535 bytes, a longer frame is encrypted in several
+const MAX_NOISE_MESSAGE: usize = 65535;
+const TAG_LEN: usize = 16;
+
+/// Whether this node encrypts its connections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// plaintext only, as legacy nodes
+    Off,
+    /// encrypt with peers that support it, plaintext with legacy peers
+    Prefer,
+    /// encrypt, and disconnect legacy peers
+    Require,
+}
+
+impl Mode {
+    pub fn parse(mode: &str) -> Result<Self, String> {
+        match mode {
+            "off" => Ok(Mode::Off),
+            "prefer" => Ok(Mode::Prefer),
+            "require" => Ok(Mode::Require),
+            _ => Err(format!("unknown encryption mode {}, expected off, prefer or require", mode)),
+        }
+    }
+}
+
+/// One direction of a Noise transport, the nonce counts the Noise messages. The reader and the
+/// writer of a connection each hold one, sharing the transport keys.
+pub struct CipherState {
+    transport: Arc<StatelessTransportState>,
+    nonce: u64,
+}
+
+impl CipherState {
+    fn new(transport: &Arc<StatelessTransportState>) -> Self {
+        CipherState { transport: Arc::clone(transport), nonce: 0 }
+    }
+
+    pub fn encrypt(&mut self, frame: Vec<u8>) -> Vec<u8> {
+        let mut encrypted = Vec::with_capacity(frame.len() + TAG_LEN);
+        let mut message = vec![0; MAX_NOISE_MESSAGE];
+        for chunk in chunks(&frame, MAX_NOISE_MESSAGE - TAG_LEN) {
+            let len = self.transport.write_message(self.nonce, chunk, &mut message).unwrap();
+            self.nonce += 1;
+            encrypted.extend_from_slice(&message[..len]);
+        }
+        encrypted
+    }
+
+    /// Decrypt the next frame, fails if it was tampered with, reordered or replayed
+    pub fn decrypt(&mut self, frame: Vec<u8>) -> io::Result<Vec<u8>> {
+        let mut decrypted = Vec::with_capacity(frame.len());
+        let mut payload = vec![0; MAX_NOISE_MESSAGE];
+        for chunk in chunks(&frame, MAX_NOISE_MESSAGE) {
+            let nonce = self.nonce;
+            self.nonce += 1;
+            let len = self
+                .transport
+                .read_message(nonce, chunk, &mut payload)
+                .map_err(|_| invalid("frame failed authentication"))?;
+            decrypted.extend_from_slice(&payload[..len]);
+        }
+        Ok(decrypted)
+    }
+}
+
+// the Noise messages a frame is cut into, one even for an empty frame
+fn chunks(frame: &[u8], size: usize) -> Vec<&[u8]> {
+    if frame.is_empty() {
+        return vec![frame];
+    }
+    frame.chunks(size).collect()
+}
+
+/// An encrypted connection with a peer that proved it holds `remote_key`.
+pub struct Session {
+    pub send: CipherState,
+    pub recv: CipherState,
+    /// the peer's Ed25519 node key
+    pub remote_key: Vec<u8>,
+}
+
+/// What the start of a connection settled on.
+pub enum Transport {
+    Encrypted(Box<Session>),
+    /// plaintext, with the first frame of the peer if it was read while negotiating
+    Plaintext(Option<Vec<u8>>),
+}
+
+/// Negotiate the transport at the start of a connection, the side that connected initiates.
+///
+/// The handshake is Noise XX, `-> e`, `<- e, ee, s, es`, `-> s, se`, with a fresh static key
+/// for each connection. The payloads of the last two messages carry each side's Ed25519 node key
+/// and its signature of that side's Noise static key, so completing the handshake proves the
+/// peer holds the node key it shows. The initiator's `e` travels in a ping, so a legacy peer
+/// answers it with its own messages and is recognized by them. `KnownKeys` decides whether the
+/// proved key is one we accept.
+pub async fn handshake<S>(stream: &mut S, key: &Ed25519KeyPair, mode: Mode, direction: Direction) -> io::Result<Transport>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    if mode == Mode::Off {
+        return Ok(Transport::Plaintext(None));
+    }
+    let negotiate = async {
+        match direction {
+            Direction::Outgoing => initiate(stream, key, mode).await,
+            Direction::Incoming => respond(stream, key, mode).await,
+        }
+    };
+    let timeout = async {
+        Timer::after(HANDSHAKE_TIMEOUT).await;
+        Err(io::Error::new(io::ErrorKind::TimedOut, "handshake timed out"))
+    };
+    smol::future::or(negotiate, timeout).await
+}
+
+async fn initiate<S>(stream: &mut S, key: &Ed25519KeyPair, mode: Mode) -> io::Result<Transport>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut noise, local_static) = noise_state(Direction::Outgoing)?;
+    let mut message = vec![0; MAX_NOISE_MESSAGE];
+    let len = noise.write_message(&[], &mut message).map_err(noise_error)?;
+    let hello = Message::Ping(format!("{}{}", HELLO_PREFIX, hex::encode(&message[..len])));
+    write_frame(stream, &bincode::serialize(&hello).unwrap()).await?;
+
+    let reply = read_frame(stream).await?;
+    if !reply.starts_with(REPLY_MAGIC) {
+        return fall_back(mode, reply);
+    }
+    let mut payload = vec![0; MAX_NOISE_MESSAGE];
+    let len = noise.read_message(&reply[REPLY_MAGIC.len()..], &mut payload).map_err(noise_error)?;
+    let remote_key = open_identity(&payload[..len], noise.get_remote_static())?;
+    let len = noise.write_message(&prove_identity(key, &local_static), &mut message).map_err(noise_error)?;
+    write_frame(stream, &message[..len]).await?;
+    session(noise, remote_key)
+}
+
+async fn respond<S>(stream: &mut S, key: &Ed25519KeyPair, mode: Mode) -> io::Result<Transport>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let hello = read_frame(stream).await?;
+    let remote = match bincode::deserialize::<Message>(&hello) {
+        Ok(Message::Ping(nonce)) if nonce.starts_with(HELLO_PREFIX) => hex::decode(&nonce[HELLO_PREFIX.len()..]).ok(),
+        _ => None,
+    };
+    let remote = match remote {
+        Some(remote) => remote,
+        None => return fall_back(mode, hello),
+    };
+    let (mut noise, local_static) = noise_state(Direction::Incoming)?;
+    let mut payload = vec![0; MAX_NOISE_MESSAGE];
+    noise.read_message(&remote, &mut payload).map_err(noise_error)?;
+    let mut message = vec![0; MAX_NOISE_MESSAGE];
+    let len = noise.write_message(&prove_identity(key, &local_static), &mut message).map_err(noise_error)?;
+    let mut reply = REPLY_MAGIC.to_vec();
+    reply.extend_from_slice(&message[..len]);
+    write_frame(stream, &reply).await?;
+
+    let identity = read_frame(stream).await?;
+    let len = noise.read_message(&identity, &mut payload).map_err(noise_error)?;
+    let remote_key = open_identity(&payload[..len], noise.get_remote_static())?;
+    session(noise, remote_key)
+}
+
+/// Node keys the peers we connect to must prove, and the peers that encrypted before. With keys
+/// pinned on the command line, a peer we dial must prove one of them, wherever it is reached.
+/// A peer that encrypted once may not fall back to plaintext, which would let anyone on the path
+/// strip the encryption from a connection in `Mode::Prefer`; that is only remembered while the
+/// node runs.
+#[derive(Default)]
+pub struct KnownKeys {
+    pinned: HashSet<Vec<u8>>,
+    encrypted: HashSet<SocketAddr>,
+}
+
+impl KnownKeys {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Accept the peers we dial only if they prove `key` or another pinned key
+    pub fn pin(&mut self, key: Vec<u8>) {
+        self.pinned.insert(key);
+    }
+
+    /// Check the transport negotiated with `peer`, which we connected to, against the pinned
+    /// keys, and remember that it encrypted
+    pub fn check(&mut self, peer: SocketAddr, transport: &Transport) -> io::Result<()> {
+        match transport {
+            Transport::Encrypted(session) if !self.pinned.is_empty() && !self.pinned.contains(&session.remote_key) => {
+                Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("peer {} proved node key {}, which is not pinned", peer, hex::encode(&session.remote_key)),
+                ))
+            }
+            Transport::Encrypted(_) => {
+                self.encrypted.insert(peer);
+                Ok(())
+            }
+            Transport::Plaintext(_) if !self.pinned.is_empty() => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("peer {} cannot prove a pinned key in plaintext", peer),
+            )),
+            Transport::Plaintext(_) if self.encrypted.contains(&peer) => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("peer {} is known to encrypt, refusing plaintext", peer),
+            )),
+            Transport::Plaintext(_) => Ok(()),
+        }
+    }
+}
+
+/// Parse a pinned node key, 64 hex digits
+pub fn parse_pin(spec: &str) -> Result<Vec<u8>, String> {
+    let key = hex::decode(spec).map_err(|e| format!("bad key {}: {}", spec, e))?;
+    if key.len() != 32 {
+        return Err(format!("expected a 32 byte key, got {}", key.len()));
+    }
+    Ok(key)
+}
+
+// a Noise handshake with a fresh static key, and the public half of that key
+fn noise_state(direction: Direction) -> io::Result<(snow::HandshakeState, Vec<u8>)> {
+    let builder = snow::Builder::new(NOISE_PARAMS.parse().unwrap());
+    let keypair = builder.generate_keypair().map_err(noise_error)?;
+    let builder = builder.local_private_key(&keypair.private).prologue(PROLOGUE);
+    let noise = match direction {
+        Direction::Outgoing => builder.build_initiator(),
+        Direction::Incoming => builder.build_responder(),
+    };
+    Ok((noise.map_err(noise_error)?, keypair.public))
+}
+
+fn session(noise: snow::HandshakeState, remote_key: Vec<u8>) -> io::Result<Transport> {
+    let transport = Arc::new(noise.into_stateless_transport_mode().map_err(noise_error)?);
+    Ok(Transport::Encrypted(Box::new(Session {
+        send: CipherState::new(&transport),
+        recv: CipherState::new(&transport),
+        remote_key,
+    })))
+}
+
+fn fall_back(mode: Mode, first_frame: Vec<u8>) -> io::Result<Transport> {
+    match mode {
+        Mode::Require => Err(io::Error::new(io::ErrorKind::PermissionDenied, "peer does not support encryption")),
+        _ => Ok(Transport::Plaintext(Some(first_frame))),
+    }
+}
+
+fn prove_identity(key: &Ed25519KeyPair, noise_static: &[u8]) -> Vec<u8> {
+    let mut identity = key.public_key().as_ref().to_vec();
+    identity.extend_from_slice(key.sign(&[IDENTITY_CONTEXT, noise_static].concat()).as_ref());
+    identity
+}
+
+// the peer's node key, if it signed the Noise static key the peer proved with it
+fn open_identity(identity: &[u8], noise_static: Option<&[u8]>) -> io::Result<Vec<u8>> {
+    let noise_static = noise_static.ok_or_else(|| invalid("no static key"))?;
+    if identity.len() != IDENTITY_LEN {
+        return Err(invalid("bad identity length"));
+    }
+    let (remote_key, proof) = identity.split_at(32);
+    signature::UnparsedPublicKey::new(&ED25519, remote_key)
+        .verify(&[IDENTITY_CONTEXT, noise_static].concat(), proof)
+        .map_err(|_| invalid("bad identity signature"))?;
+    Ok(remote_key.to_vec())
+}
+
+async fn read_frame<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Vec<u8>> {
+    let mut size = [0; 4];
+    stream.read_exact(&mut size).await?;
+    let size = u32::from_be_bytes(size) as usize;
+    if size > MAX_HANDSHAKE_FRAME {
+        return Err(invalid("handshake frame too large"));
+    }
+    let mut frame = vec![0; size];
+    stream.read_exact(&mut frame).await?;
+    Ok(frame)
+}
+
+async fn write_frame<S: AsyncWrite + Unpin>(stream: &mut S, frame: &[u8]) -> io::Result<()> {
+    stream.write_all(&(frame.len() as u32).to_be_bytes()).await?;
+    stream.write_all(frame).await?;
+    stream.flush().await
+}
+
+fn invalid(error: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error.to_string())
+}
+
+fn noise_error(error: snow::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("handshake failed: {}", error))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::key_pair;
+    use smol::Async;
+    use std::net::{TcpListener, TcpStream};
+
+    // a legacy node sends its first message right away
+    async fn legacy_hello(stream: &mut Async<TcpStream>, mode: Mode) {
+        if mode == Mode::Off {
+            write_frame(stream, &bincode::serialize(&Message::Ping("legacy".to_string())).unwrap()).await.unwrap();
+        }
+    }
+
+    // run the handshake between a connecting and an accepting node in the given modes
+    fn connect(initiator: Mode, responder: Mode) -> (io::Result<Transport>, io::Result<Transport>, Vec<u8>, Vec<u8>) {
+        let (initiator_key, responder_key) = (key_pair::random(), key_pair::random());
+        let keys = (initiator_key.public_key().as_ref().to_vec(), responder_key.public_key().as_ref().to_vec());
+        smol::block_on(async {
+            let listener = Async::<TcpListener>::bind(([127, 0, 0, 1], 0)).unwrap();
+            let addr = listener.get_ref().local_addr().unwrap();
+            let accept = async {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let transport = handshake(&mut stream, &responder_key, responder, Direction::Incoming).await;
+                legacy_hello(&mut stream, responder).await;
+                (transport, stream)
+            };
+            let connect = async {
+                let mut stream = Async::<TcpStream>::connect(addr).await.unwrap();
+                let transport = handshake(&mut stream, &initiator_key, initiator, Direction::Outgoing).await;
+                legacy_hello(&mut stream, initiator).await;
+                (transport, stream)
+            };
+            let ((outgoing, _a), (incoming, _b)) = futures::join!(connect, accept);
+            (outgoing, incoming, keys.0, keys.1)
+        })
+    }
+
+    #[test]
+    fn handshake_authenticates_and_encrypts() {
+        let (outgoing, incoming, initiator_key, responder_key) = connect(Mode::Prefer, Mode::Require);
+        let (mut outgoing, mut incoming) = match (outgoing.unwrap(), incoming.unwrap()) {
+            (Transport::Encrypted(o), Transport::Encrypted(i)) => (o, i),
+            _ => panic!("expected an encrypted connection"),
+        };
+        assert_eq!(outgoing.remote_key, responder_key);
+        assert_eq!(incoming.remote_key, initiator_key);
+
+        // frames longer than a Noise message and empty ones go through as well
+        let large: Vec<u8> = (0..200_000).map(|i| i as u8).collect();
+        let frame = outgoing.send.encrypt(large.clone());
+        assert_eq!(incoming.recv.decrypt(frame).unwrap(), large);
+        let frame = outgoing.send.encrypt(Vec::new());
+        assert_eq!(incoming.recv.decrypt(frame).unwrap(), Vec::<u8>::new());
+        let frame = outgoing.send.encrypt(b"tip".to_vec());
+        assert_ne!(&frame[..3], b"tip");
+        assert_eq!(incoming.recv.decrypt(frame.clone()).unwrap(), b"tip");
+        // a replayed or tampered frame is rejected
+        assert!(incoming.recv.decrypt(frame).is_err());
+        let mut frame = incoming.send.encrypt(b"blocks".to_vec());
+        frame[0] ^= 1;
+        assert!(outgoing.recv.decrypt(frame).is_err());
+    }
+
+    #[test]
+    fn legacy_peers_fall_back_unless_required() {
+        // the legacy responder's first message is handed on
+        let (outgoing, _, _, _) = connect(Mode::Prefer, Mode::Off);
+        match outgoing.unwrap() {
+            Transport::Plaintext(Some(frame)) => match bincode::deserialize(&frame).unwrap() {
+                Message::Ping(nonce) => assert_eq!(nonce, "legacy"),
+                msg => panic!("unexpected {}", msg.name()),
+            },
+            _ => panic!("expected a plaintext connection"),
+        }
+        let (outgoing, _, _, _) = connect(Mode::Require, Mode::Off);
+        assert_eq!(outgoing.err().unwrap().kind(), io::ErrorKind::PermissionDenied);
+        // a legacy initiator's first message is its Version, not a hello
+        let (outgoing, incoming, _, _) = connect(Mode::Off, Mode::Prefer);
+        assert!(matches!(outgoing.unwrap(), Transport::Plaintext(None)));
+        assert!(matches!(incoming.unwrap(), Transport::Plaintext(Some(_))));
+    }
+
+    #[test]
+    fn known_keys_are_kept_to() {
+        let peer: SocketAddr = "127.0.0.1:6001".parse().unwrap();
+        let moved: SocketAddr = "127.0.0.1:6002".parse().unwrap();
+        let (outgoing, _, _, responder_key) = connect(Mode::Prefer, Mode::Prefer);
+        let encrypted = outgoing.unwrap();
+        let (impostor, _, _, _) = connect(Mode::Prefer, Mode::Prefer);
+        let impostor = impostor.unwrap();
+
+        let mut keys = KnownKeys::new();
+        assert!(keys.check(peer, &Transport::Plaintext(None)).is_ok());
+        assert!(keys.check(peer, &encrypted).is_ok());
+        assert!(keys.check(peer, &impostor).is_ok());
+        // no encryption at all is refused once the peer encrypted
+        assert_eq!(keys.check(peer, &Transport::Plaintext(None)).unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+
+        // a pinned key holds from the first connection on, at any address
+        let mut keys = KnownKeys::new();
+        keys.pin(parse_pin(&hex::encode(&responder_key)).unwrap());
+        assert_eq!(keys.check(peer, &impostor).unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        assert!(keys.check(peer, &Transport::Plaintext(None)).is_err());
+        assert!(keys.check(peer, &encrypted).is_ok());
+        assert!(keys.check(moved, &encrypted).is_ok());
+        assert!(parse_pin("00").is_err());
+        assert!(parse_pin("127.0.0.1:6001").is_err());
+    }
+}
@@ -0,0 +1,116 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Incoming peers kept out of eviction for their round trip time, and as many for their usefulness
+pub const PROTECTED: usize = 4;
+
+/// Most connections the server keeps in each direction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionLimits {
+    pub max_inbound: usize,
+    pub max_outbound: usize,
+}
+
+/// An incoming peer that could make room for a new one.
+#[derive(Debug, Clone, Copy)]
+pub struct Candidate {
+    pub addr: SocketAddr,
+    pub rtt: Option<Duration>,
+    pub last_useful: Option<Instant>,
+    pub connected_at: Instant,
+}
+
+/// When each peer last delivered a block or transaction we accepted before any other peer did.
+#[derive(Default)]
+pub struct LastUseful {
+    peers: HashMap<SocketAddr, Instant>,
+}
+
+impl LastUseful {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn on_useful(&mut self, peer: SocketAddr, now: Instant) {
+        self.peers.insert(peer, now);
+    }
+
+    pub fn get(&self, peer: &SocketAddr) -> Option<Instant> {
+        self.peers.get(peer).copied()
+    }
+
+    pub fn forget(&mut self, peer: &SocketAddr) {
+        self.peers.remove(peer);
+    }
+}
+
+/// The candidate of the lowest value, to disconnect so a new peer can connect, None without
+/// candidates. The `protected` peers with the lowest round trip time and the `protected` ones
+/// useful most recently are kept, so a flood of new connections cannot push out the peers that
+/// serve us best. Of the others the one useful the longest ago goes, peers never useful first,
+/// then the slowest, unmeasured peers counting as slowest, then the newest connection.
+pub fn pick_eviction(candidates: &[Candidate], protected: usize) -> Option<SocketAddr> {
+    let mut candidates: Vec<&Candidate> = candidates.iter().collect();
+    candidates.sort_by_key(|candidate| (candidate.rtt.unwrap_or(Duration::MAX), candidate.addr));
+    let fastest: Vec<SocketAddr> = candidates
+        .iter()
+        .filter(|candidate| candidate.rtt.is_some())
+        .take(protected)
+        .map(|candidate| candidate.addr)
+        .collect();
+    candidates.sort_by_key(|candidate| (Reverse(candidate.last_useful), candidate.addr));
+    let useful: Vec<SocketAddr> = candidates
+        .iter()
+        .filter(|candidate| candidate.last_useful.is_some())
+        .take(protected)
+        .map(|candidate| candidate.addr)
+        .collect();
+    let unprotected: Vec<&Candidate> = candidates
+        .iter()
+        .filter(|candidate| !fastest.contains(&candidate.addr) && !useful.contains(&candidate.addr))
+        .cloned()
+        .collect();
+    // with few peers they may all be protected, the lowest value still goes
+    let pool = if unprotected.is_empty() { candidates } else { unprotected };
+    pool.into_iter()
+        .min_by_key(|candidate| {
+            (candidate.last_useful, Reverse(candidate.rtt.unwrap_or(Duration::MAX)), Reverse(candidate.connected_at))
+        })
+        .map(|candidate| candidate.addr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lowest_value_peer_is_evicted() {
+        let now = Instant::now();
+        let peer = |port: u16, rtt: Option<u64>, useful: Option<u64>| Candidate {
+            addr: SocketAddr::from(([127, 0, 0, 1], port)),
+            rtt: rtt.map(Duration::from_millis),
+            last_useful: useful.map(|secs| now - Duration::from_secs(secs)),
+            connected_at: now - Duration::from_secs(port as u64),
+        };
+        let addr = |port: u16| Some(SocketAddr::from(([127, 0, 0, 1], port)));
+        assert_eq!(pick_eviction(&[], PROTECTED), None);
+        // useful long ago loses to useful recently, never useful loses to both
+        let peers = vec![peer(1, Some(50), Some(10)), peer(2, Some(50), Some(100)), peer(3, Some(50), None)];
+        assert_eq!(pick_eviction(&peers, 0), addr(3));
+        assert_eq!(pick_eviction(&peers[..2], 0), addr(2));
+        // of the peers never useful the slowest goes, unmeasured counting as slowest
+        let peers = vec![peer(1, Some(500), None), peer(2, Some(20), None), peer(3, None, None)];
+        assert_eq!(pick_eviction(&peers, 0), addr(3));
+        assert_eq!(pick_eviction(&peers[..2], 0), addr(1));
+        // the fastest and the most recently useful are protected
+        let peers = vec![peer(1, Some(5), None), peer(2, Some(500), Some(1)), peer(3, Some(50), Some(10))];
+        assert_eq!(pick_eviction(&peers, 1), addr(3));
+        // when every peer is protected the lowest value still goes
+        assert_eq!(pick_eviction(&peers, 3), addr(1));
+        // between equals the newest connection goes
+        let peers = vec![peer(1, None, None), peer(2, None, None)];
+        assert_eq!(pick_eviction(&peers, 0), addr(1));
+    }
+}
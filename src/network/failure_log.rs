@@ -0,0 +1,209 @@
+use crate::types::hash::H256;
+
+use log::warn;
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Why a block or transaction received from a peer was rejected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ValidationFailure {
+    /// the block hash is above its difficulty
+    BadPow,
+    BadSignature,
+    /// the sender cannot afford the transaction or its nonce is out of order
+    InvalidTransaction,
+    /// the block length is not its parent's length plus one
+    BadLength,
+    /// the block declares a different difficulty than the chain parameters require
+    BadDifficulty,
+    /// the block timestamp is not after the median time past, or too far ahead of our time
+    BadTimestamp,
+    /// a transaction moves less than the dust threshold the chain parameters make a consensus rule
+    Dust,
+    /// an extra rule registered in `validators::network_rules` was broken, named by its validator
+    Rule(&'static str),
+    /// the block at the height of the trusted checkpoint is another block, or leads to another state
+    Checkpoint,
+}
+
+impl std::fmt::Display for ValidationFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ValidationFailure::BadPow => write!(f, "proof of work above difficulty"),
+            ValidationFailure::BadSignature => write!(f, "invalid signature"),
+            ValidationFailure::InvalidTransaction => write!(f, "transaction inconsistent with state"),
+            ValidationFailure::BadLength => write!(f, "length does not follow parent"),
+            ValidationFailure::BadDifficulty => write!(f, "unexpected difficulty"),
+            ValidationFailure::BadTimestamp => write!(f, "timestamp out of range"),
+            ValidationFailure::Dust => write!(f, "dust transaction"),
+            ValidationFailure::Rule(rule) => write!(f, "breaks rule {}", rule),
+            ValidationFailure::Checkpoint => write!(f, "does not match the checkpoint"),
+        }
+    }
+}
+
+/// Reason code sent back to a peer in a `Reject`, one per `ValidationFailure`, so nodes written
+/// by other groups can tell why an object was refused instead of seeing it silently dropped.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum RejectCode {
+    BadPow,
+    BadSignature,
+    InvalidTransaction,
+    BadLength,
+    BadDifficulty,
+    Dust,
+    /// an extra network rule was broken, named by its validator
+    Rule(String),
+}
+
+impl From<ValidationFailure> for RejectCode {
+    fn from(failure: ValidationFailure) -> Self {
+        match failure {
+            ValidationFailure::BadPow => RejectCode::BadPow,
+            ValidationFailure::BadSignature => RejectCode::BadSignature,
+            ValidationFailure::InvalidTransaction => RejectCode::InvalidTransaction,
+            ValidationFailure::BadLength => RejectCode::BadLength,
+            ValidationFailure::BadDifficulty => RejectCode::BadDifficulty,
+            ValidationFailure::Dust => RejectCode::Dust,
+            ValidationFailure::Rule(rule) => RejectCode::Rule(rule.to_string()),
+            // told as rules so peers need no new code
+            ValidationFailure::BadTimestamp => RejectCode::Rule("timestamp".to_string()),
+            // a local setting
+            ValidationFailure::Checkpoint => RejectCode::Rule("checkpoint".to_string()),
+        }
+    }
+}
+
+impl std::fmt::Display for RejectCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            RejectCode::BadPow => write!(f, "proof of work above difficulty"),
+            RejectCode::BadSignature => write!(f, "invalid signature"),
+            RejectCode::InvalidTransaction => write!(f, "transaction inconsistent with state"),
+            RejectCode::BadLength => write!(f, "length does not follow parent"),
+            RejectCode::BadDifficulty => write!(f, "unexpected difficulty"),
+            RejectCode::Dust => write!(f, "dust transaction"),
+            RejectCode::Rule(rule) => write!(f, "breaks rule {}", rule),
+        }
+    }
+}
+
+type FailureKey = (SocketAddr, H256, ValidationFailure);
+
+/// Log of validation failures that stays readable when a peer keeps re-sending the same invalid
+/// object. The first failure for a (peer, hash, reason) in a window is logged right away; repeats
+/// are only counted and rolled up into one summary line per key when the window ends.
+pub struct FailureLog {
+    window: Duration,
+    window_start: Instant,
+    repeats: HashMap<FailureKey, usize>,
+}
+
+impl FailureLog {
+    pub fn new(window: Duration) -> Self {
+        FailureLog {
+            window,
+            window_start: Instant::now(),
+            repeats: HashMap::new(),
+        }
+    }
+
+    /// Record a failure, returns whether it was logged immediately rather than counted as a repeat
+    pub fn report(&mut self, peer: SocketAddr, hash: H256, reason: ValidationFailure) -> bool {
+        self.flush();
+        match self.repeats.get_mut(&(peer, hash, reason)) {
+            Some(count) => {
+                *count += 1;
+                false
+            }
+            None => {
+                warn!("Rejected {} from peer {}: {}", hash, peer, reason);
+                self.repeats.insert((peer, hash, reason), 0);
+                true
+            }
+        }
+    }
+
+    /// Log the summaries of the window if it is over and start a new one, returns whether it was over
+    pub fn flush(&mut self) -> bool {
+        if self.window_start.elapsed() < self.window {
+            return false;
+        }
+        for ((peer, hash, reason), count) in self.end_window() {
+            warn!("Peer {} re-sent {} {} more times in the last {:?}: {}", peer, hash, count, self.window, reason);
+        }
+        true
+    }
+
+    // time left until the window is over
+    fn remaining(&self) -> Duration {
+        self.window.saturating_sub(self.window_start.elapsed())
+    }
+
+    // start a new window, returning the keys that repeated in the old one with their repeat counts
+    fn end_window(&mut self) -> Vec<(FailureKey, usize)> {
+        self.window_start = Instant::now();
+        self.repeats.drain().filter(|(_, count)| *count > 0).collect()
+    }
+}
+
+/// Flush `log` whenever its window is over, so the repeats of the last window are summarized
+/// even if no failure follows them
+pub fn start_flusher(log: &Arc<Mutex<FailureLog>>) {
+    let log = Arc::clone(log);
+    thread::Builder::new()
+        .name("failure-log".to_string())
+        .spawn(move || loop {
+            let remaining = {log.lock().unwrap().remaining()};
+            thread::sleep(remaining);
+            log.lock().unwrap().flush();
+        })
+        .unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repeats_are_rolled_up() {
+        let mut log = FailureLog::new(Duration::from_secs(3600));
+        let peer = "127.0.0.1:6001".parse().unwrap();
+        let hash = H256::from([7; 32]);
+        assert!(log.report(peer, hash, ValidationFailure::BadPow));
+        assert!(!log.report(peer, hash, ValidationFailure::BadPow));
+        assert!(!log.report(peer, hash, ValidationFailure::BadPow));
+        // a different reason for the same hash is a different failure
+        assert!(log.report(peer, hash, ValidationFailure::BadSignature));
+        assert_eq!(log.end_window(), vec![((peer, hash, ValidationFailure::BadPow), 2)]);
+        // a new window logs the failure again
+        assert!(log.report(peer, hash, ValidationFailure::BadPow));
+        assert!(!log.flush());
+    }
+
+    #[test]
+    fn flush_ends_the_window_without_another_report() {
+        let mut log = FailureLog::new(Duration::ZERO);
+        let peer = "127.0.0.1:6001".parse().unwrap();
+        let hash = H256::from([7; 32]);
+        assert!(log.report(peer, hash, ValidationFailure::BadPow));
+        log.repeats.insert((peer, hash, ValidationFailure::BadPow), 2);
+        assert!(log.flush());
+        assert!(log.repeats.is_empty());
+        assert_eq!(log.remaining(), Duration::ZERO);
+    }
+
+    #[test]
+    fn reject_codes_keep_the_rule() {
+        let code = RejectCode::from(ValidationFailure::Rule("max-value"));
+        assert_eq!(code, RejectCode::Rule("max-value".to_string()));
+        assert_eq!(code.to_string(), ValidationFailure::Rule("max-value").to_string());
+        let decoded: RejectCode = bincode::deserialize(&bincode::serialize(&code).unwrap()).unwrap();
+        assert_eq!(decoded, code);
+        assert_eq!(RejectCode::from(ValidationFailure::BadPow), RejectCode::BadPow);
+    }
+}
@@ -0,0 +1,213 @@
+use crate::types::hash::H256;
+
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+/// Version of the P2P protocol spoken by this node, announced in the `Version` handshake. Any
+/// change to the message types or their tags takes a new version:
+/// - 2: height and genesis in `Version`, `Verack`, and the `GetTip`/`Tip` exchange
+/// - 3: nodes skip message types they do not know instead of disconnecting, so a new message
+///   type can be sent to them without a feature bit
+/// - 4: every message but `Version` travels in an envelope naming the protocol version, the
+///   message type and the payload length, see `Message::encode_for`
+pub const PROTOCOL_VERSION: u32 = 4;
+
+/// Oldest protocol version this node talks to. Version 1 nodes announce neither their genesis
+/// nor their height, so their `Version` cannot even be decoded.
+pub const MIN_PROTOCOL_VERSION: u32 = 2;
+
+/// Why the `Version` of a peer was refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Incompatible {
+    /// the peer speaks a protocol older than `MIN_PROTOCOL_VERSION`
+    Version(u32),
+    /// the peer's chain starts from another genesis block
+    Genesis(H256),
+}
+
+impl std::fmt::Display for Incompatible {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Incompatible::Version(version) => {
+                write!(f, "protocol version {} is older than {}", version, MIN_PROTOCOL_VERSION)
+            }
+            Incompatible::Genesis(genesis) => write!(f, "genesis {} is not ours", genesis),
+        }
+    }
+}
+
+/// Check the protocol version and genesis a peer announced against ours
+pub fn check_version(version: u32, genesis: &H256, our_genesis: &H256) -> Result<(), Incompatible> {
+    if version < MIN_PROTOCOL_VERSION {
+        return Err(Incompatible::Version(version));
+    }
+    if genesis != our_genesis {
+        return Err(Incompatible::Genesis(*genesis));
+    }
+    Ok(())
+}
+
+/// Bitfield of optional protocol features. A feature may only be used with a peer once both
+/// sides announced it, so nodes that do not know a feature keep working with nodes that do.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Features(u32);
+
+impl Features {
+    pub const NONE: Features = Features(0);
+    pub const COMPACT_BLOCKS: Features = Features(1 << 0);
+    pub const COMPRESSION: Features = Features(1 << 1);
+    pub const FILTERS: Features = Features(1 << 2);
+    pub const UDP_RELAY: Features = Features(1 << 3);
+    pub const TX_RECONCILIATION: Features = Features(1 << 4);
+    pub const CLOCK_SYNC: Features = Features(1 << 5);
+    pub const PEER_EXCHANGE: Features = Features(1 << 6);
+    pub const MARKERS: Features = Features(1 << 7);
+    pub const BLOCK_LOCATOR: Features = Features(1 << 8);
+    pub const MEMPOOL_SYNC: Features = Features(1 << 9);
+    pub const DUST_FILTER: Features = Features(1 << 10);
+    pub const TOPOLOGY: Features = Features(1 << 11);
+    pub const CHAIN_PROOFS: Features = Features(1 << 12);
+    pub const REJECT: Features = Features(1 << 13);
+
+    /// Whether every feature in `other` is also in `self`
+    pub fn contains(&self, other: Features) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Features present in both sets
+    pub fn intersection(&self, other: Features) -> Features {
+        Features(self.0 & other.0)
+    }
+
+    /// Features present in either set
+    pub const fn union(&self, other: Features) -> Features {
+        Features(self.0 | other.0)
+    }
+
+    /// Features of `self` not in `other`
+    pub fn difference(&self, other: Features) -> Features {
+        Features(self.0 & !other.0)
+    }
+}
+
+/// Features implemented by this node. Add a feature here once the code using it is gated on
+/// `PeerFeatures::supports`.
+pub const SUPPORTED_FEATURES: Features = Features::TX_RECONCILIATION
+    .union(Features::CLOCK_SYNC)
+    .union(Features::PEER_EXCHANGE)
+    .union(Features::MARKERS)
+    .union(Features::BLOCK_LOCATOR)
+    .union(Features::MEMPOOL_SYNC)
+    .union(Features::DUST_FILTER)
+    .union(Features::TOPOLOGY)
+    .union(Features::CHAIN_PROOFS)
+    .union(Features::REJECT)
+    .union(Features::COMPRESSION);
+
+/// Features and protocol version negotiated with each peer during the `Version` handshake.
+pub struct PeerFeatures {
+    negotiated: HashMap<SocketAddr, Features>,
+    versions: HashMap<SocketAddr, u32>,
+}
+
+impl PeerFeatures {
+    pub fn new() -> Self {
+        PeerFeatures { negotiated: HashMap::new(), versions: HashMap::new() }
+    }
+
+    /// Record the features a peer announced, keeping only those we support as well
+    pub fn negotiate(&mut self, peer: SocketAddr, announced: Features) -> Features {
+        let negotiated = SUPPORTED_FEATURES.intersection(announced);
+        self.negotiated.insert(peer, negotiated);
+        negotiated
+    }
+
+    /// Record the protocol version a peer announced, returns the one to write to it in: the
+    /// older of ours and the peer's
+    pub fn negotiate_version(&mut self, peer: SocketAddr, announced: u32) -> u32 {
+        let version = announced.min(PROTOCOL_VERSION);
+        self.versions.insert(peer, version);
+        version
+    }
+
+    /// Protocol version negotiated with `peer`, None until it completed the handshake
+    pub fn version(&self, peer: &SocketAddr) -> Option<u32> {
+        self.versions.get(peer).copied()
+    }
+
+    /// Peers that negotiated `feature`, including ones that disconnected since
+    pub fn peers_supporting(&self, feature: Features) -> Vec<SocketAddr> {
+        self.negotiated
+            .iter()
+            .filter(|(_, features)| features.contains(feature))
+            .map(|(peer, _)| *peer)
+            .collect()
+    }
+
+    /// Features negotiated with `peer`, None until it completed the handshake
+    pub fn negotiated(&self, peer: &SocketAddr) -> Option<Features> {
+        self.negotiated.get(peer).copied()
+    }
+
+    /// Whether a feature may be used with a peer; false until the peer completed the handshake
+    pub fn supports(&self, peer: &SocketAddr, feature: Features) -> bool {
+        match self.negotiated.get(peer) {
+            Some(features) => features.contains(feature),
+            None => false,
+        }
+    }
+}
+
+impl Default for PeerFeatures {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn feature_set_operations() {
+        let both = Features::COMPACT_BLOCKS.union(Features::FILTERS);
+        assert!(both.contains(Features::COMPACT_BLOCKS));
+        assert!(both.contains(Features::FILTERS));
+        assert!(!both.contains(Features::COMPRESSION));
+        assert!(both.contains(Features::NONE));
+        assert_eq!(both.intersection(Features::FILTERS.union(Features::UDP_RELAY)), Features::FILTERS);
+    }
+
+    #[test]
+    fn other_chains_and_old_versions_are_refused() {
+        let genesis = H256::default();
+        let other = H256::from([1; 32]);
+        assert_eq!(check_version(PROTOCOL_VERSION, &genesis, &genesis), Ok(()));
+        // newer peers are expected to talk down to us
+        assert_eq!(check_version(PROTOCOL_VERSION + 1, &genesis, &genesis), Ok(()));
+        assert_eq!(check_version(1, &genesis, &genesis), Err(Incompatible::Version(1)));
+        assert_eq!(check_version(PROTOCOL_VERSION, &other, &genesis), Err(Incompatible::Genesis(other)));
+    }
+
+    #[test]
+    fn unknown_features_are_not_negotiated() {
+        let mut peers = PeerFeatures::new();
+        let peer = "127.0.0.1:6001".parse().unwrap();
+        assert!(!peers.supports(&peer, Features::NONE));
+        // bits we do not implement, including ones from newer nodes, are dropped
+        let negotiated = peers.negotiate(peer, Features::UDP_RELAY.union(Features(1 << 31)));
+        assert_eq!(negotiated, SUPPORTED_FEATURES.intersection(Features::UDP_RELAY));
+        assert!(peers.supports(&peer, Features::NONE));
+        assert_eq!(peers.supports(&peer, Features::UDP_RELAY), SUPPORTED_FEATURES.contains(Features::UDP_RELAY));
+        // a peer without a feature is not told about it
+        let legacy = "127.0.0.1:6002".parse().unwrap();
+        peers.negotiate(legacy, Features::NONE);
+        peers.negotiate(peer, Features::PEER_EXCHANGE);
+        assert_eq!(peers.peers_supporting(Features::PEER_EXCHANGE), vec![peer]);
+        // newer peers talk down to us, we talk down to older ones
+        assert_eq!(peers.negotiate_version(peer, PROTOCOL_VERSION + 1), PROTOCOL_VERSION);
+        assert_eq!(peers.negotiate_version(legacy, 2), 2);
+        assert_eq!(peers.version(&legacy), Some(2));
+    }
+}
@@ -0,0 +1,280 @@
+use super::message::InvItem;
+use super::server::Handle as ServerHandle;
+use crate::memory;
+use crate::types::hash::H256;
+
+use log::info;
+use rand::seq::SliceRandom;
+use rand::Rng;
+use std::collections::{HashMap, HashSet};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Transaction and block hashes each peer is known to have seen recently, either because the
+/// peer announced or sent them to us or because we announced them to it. Announcements of those
+/// transactions to that peer are suppressed until `window` has passed, and those blocks are not
+/// pushed to it.
+pub struct RecentlyAnnounced {
+    window: Duration,
+    peers: HashMap<SocketAddr, HashMap<H256, Instant>>,
+    suppressed: u64,
+}
+
+impl RecentlyAnnounced {
+    pub fn new(window: Duration) -> Self {
+        RecentlyAnnounced {
+            window,
+            peers: HashMap::new(),
+            suppressed: 0,
+        }
+    }
+
+    /// Record that `peer` has seen the transaction or block `hash` at `now`
+    pub fn mark(&mut self, peer: SocketAddr, hash: H256, now: Instant) {
+        self.peers.entry(peer).or_default().insert(hash, now);
+    }
+
+    /// Keep the items worth announcing to `peer` at `now` and record them as announced. Blocks
+    /// are always announced, transactions only if the peer has not seen them within the window.
+    pub fn filter(&mut self, peer: SocketAddr, items: &[InvItem], now: Instant) -> Vec<InvItem> {
+        let window = self.window;
+        let seen = self.peers.entry(peer).or_default();
+        seen.retain(|_, at| now.saturating_duration_since(*at) < window);
+        let mut announce = Vec::new();
+        for item in items {
+            match item {
+                InvItem::Block(_) => announce.push(*item),
+                InvItem::Transaction(hash) => {
+                    if seen.contains_key(hash) {
+                        self.suppressed += 1;
+                    } else {
+                        seen.insert(*hash, now);
+                        announce.push(*item);
+                    }
+                }
+            }
+        }
+        announce
+    }
+
+    /// Whether `peer` has seen `hash` within the window before `now`
+    pub fn seen(&self, peer: &SocketAddr, hash: &H256, now: Instant) -> bool {
+        self.peers
+            .get(peer)
+            .and_then(|seen| seen.get(hash))
+            .is_some_and(|at| now.saturating_duration_since(*at) < self.window)
+    }
+
+    /// Drop what we know about a disconnected peer
+    pub fn forget(&mut self, peer: &SocketAddr) {
+        self.peers.remove(peer);
+    }
+
+    /// Number of transaction announcements suppressed so far
+    pub fn suppressed(&self) -> u64 {
+        self.suppressed
+    }
+
+    /// Approximate bytes of memory taken by the recorded transactions
+    pub fn memory_usage(&self) -> usize {
+        self.peers.values().map(|seen| seen.len()).sum::<usize>() * memory::map_entry_size::<H256, Instant>()
+    }
+
+    /// Forget the oldest records until the remaining ones take at most `max_bytes`, returns how
+    /// many were forgotten
+    pub fn evict(&mut self, max_bytes: usize) -> usize {
+        let entry_size = memory::map_entry_size::<H256, Instant>();
+        let entries: usize = self.peers.values().map(|seen| seen.len()).sum();
+        let excess = entries.saturating_sub(max_bytes / entry_size);
+        if excess == 0 {
+            return 0;
+        }
+        let mut by_age: Vec<(Instant, SocketAddr, H256)> = self
+            .peers
+            .iter()
+            .flat_map(|(peer, seen)| seen.iter().map(move |(hash, at)| (*at, *peer, *hash)))
+            .collect();
+        by_age.sort_by_key(|(at, _, _)| *at);
+        for (_, peer, hash) in by_age.into_iter().take(excess) {
+            self.peers.get_mut(&peer).unwrap().remove(&hash);
+        }
+        excess
+    }
+}
+
+/// How many peers each broadcast announces new blocks and transactions to, every peer if None.
+/// Announcing to fewer peers saves bandwidth, the others learn of the object from their own
+/// peers a hop later.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Fanout {
+    pub blocks: Option<usize>,
+    pub transactions: Option<usize>,
+}
+
+impl Fanout {
+    /// Draw the peers among `peers` one broadcast announces blocks and transactions to
+    pub fn pick<R: Rng>(&self, peers: &[SocketAddr], rng: &mut R) -> Picked {
+        let blocks = self.blocks.map(|fanout| peers.choose_multiple(rng, fanout).cloned().collect());
+        let transactions = self.transactions.map(|fanout| peers.choose_multiple(rng, fanout).cloned().collect());
+        Picked { blocks, transactions }
+    }
+}
+
+/// The peers one broadcast announces blocks and transactions to, None for every peer.
+pub struct Picked {
+    blocks: Option<HashSet<SocketAddr>>,
+    transactions: Option<HashSet<SocketAddr>>,
+}
+
+impl Picked {
+    /// Whether `peer` is told about `item`
+    pub fn admits(&self, peer: &SocketAddr, item: &InvItem) -> bool {
+        let picked = match item {
+            InvItem::Block(_) => &self.blocks,
+            InvItem::Transaction(_) => &self.transactions,
+        };
+        picked.as_ref().is_none_or(|picked| picked.contains(peer))
+    }
+}
+
+/// Holds new transactions back for a random time of up to `max` before they are announced,
+/// each for its own time, so the peers first told about a transaction cannot tell as easily
+/// which node it comes from. Blocks are never held.
+pub struct RelayDelay {
+    max: Duration,
+    pending: Vec<(Instant, InvItem)>,
+}
+
+impl RelayDelay {
+    pub fn new(max: Duration) -> Self {
+        RelayDelay { max, pending: Vec::new() }
+    }
+
+    /// The items of a broadcast at `now` to announce right away; the transactions are kept
+    /// until their delay is over
+    pub fn hold<R: Rng>(&mut self, items: Vec<InvItem>, now: Instant, rng: &mut R) -> Vec<InvItem> {
+        if self.max.is_zero() {
+            return items;
+        }
+        let mut announce = Vec::new();
+        for item in items {
+            match item {
+                InvItem::Block(_) => announce.push(item),
+                InvItem::Transaction(_) => self.pending.push((now + self.max.mul_f64(rng.gen::<f64>()), item)),
+            }
+        }
+        announce
+    }
+
+    /// The transactions whose delay is over at `now`
+    pub fn due(&mut self, now: Instant) -> Vec<InvItem> {
+        let (due, pending) = self.pending.drain(..).partition(|(at, _)| *at <= now);
+        self.pending = pending;
+        due.into_iter().map(|(_, item)| item).collect()
+    }
+
+    /// Number of transactions waiting for their delay to be over
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+}
+
+/// Every `interval`, announce the transactions of `delay` whose delay is over
+pub fn start_relay(interval: Duration, delay: &Arc<Mutex<RelayDelay>>, server: &ServerHandle) {
+    let max = {delay.lock().unwrap().max};
+    let delay = Arc::clone(delay);
+    let server = server.clone();
+    thread::Builder::new()
+        .name("relay-delay".to_string())
+        .spawn(move || loop {
+            thread::sleep(interval);
+            let due = {delay.lock().unwrap().due(Instant::now())};
+            if !due.is_empty() {
+                server.release(due);
+            }
+        })
+        .unwrap();
+    info!("Delaying the relay of transactions by up to {:?}", max);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    #[test]
+    fn sender_is_not_told_again() {
+        let mut recent = RecentlyAnnounced::new(Duration::from_secs(60));
+        let sender = "127.0.0.1:6001".parse().unwrap();
+        let other = "127.0.0.1:6002".parse().unwrap();
+        let now = Instant::now();
+        let tx = InvItem::Transaction(H256::from([1; 32]));
+        let block = InvItem::Block(H256::from([2; 32]));
+        recent.mark(sender, H256::from([1; 32]), now);
+        assert_eq!(recent.filter(sender, &[tx, block], now), vec![block]);
+        assert_eq!(recent.filter(other, &[tx, block], now), vec![tx, block]);
+        // told once is enough
+        assert_eq!(recent.filter(other, &[tx], now), vec![]);
+        assert_eq!(recent.suppressed(), 2);
+    }
+
+    #[test]
+    fn window_expires() {
+        let mut recent = RecentlyAnnounced::new(Duration::from_secs(60));
+        let peer = "127.0.0.1:6001".parse().unwrap();
+        let now = Instant::now();
+        let tx = InvItem::Transaction(H256::from([1; 32]));
+        assert_eq!(recent.filter(peer, &[tx], now), vec![tx]);
+        assert_eq!(recent.filter(peer, &[tx], now + Duration::from_secs(30)), vec![]);
+        assert_eq!(recent.filter(peer, &[tx], now + Duration::from_secs(60)), vec![tx]);
+        // a block the peer sent us is not pushed back to it within the window
+        let block = H256::from([2; 32]);
+        recent.mark(peer, block, now);
+        assert!(recent.seen(&peer, &block, now + Duration::from_secs(30)));
+        assert!(!recent.seen(&peer, &block, now + Duration::from_secs(60)));
+        assert!(!recent.seen(&"127.0.0.1:6002".parse().unwrap(), &block, now));
+    }
+
+    #[test]
+    fn fanout_and_delay() {
+        let mut rng = StdRng::seed_from_u64(1);
+        let peers: Vec<SocketAddr> = (0..5).map(|i| format!("127.0.0.1:600{}", i).parse().unwrap()).collect();
+        let block = InvItem::Block(H256::from([1; 32]));
+        let tx = InvItem::Transaction(H256::from([2; 32]));
+        let fanout = Fanout { blocks: None, transactions: Some(2) };
+        let picked = fanout.pick(&peers, &mut rng);
+        assert!(peers.iter().all(|peer| picked.admits(peer, &block)));
+        assert_eq!(peers.iter().filter(|peer| picked.admits(peer, &tx)).count(), 2);
+        // fewer peers than the fanout are all told
+        let picked = fanout.pick(&peers[..1], &mut rng);
+        assert!(picked.admits(&peers[0], &tx));
+
+        let mut delay = RelayDelay::new(Duration::from_secs(2));
+        let now = Instant::now();
+        assert_eq!(delay.hold(vec![block, tx], now, &mut rng), vec![block]);
+        assert_eq!(delay.pending(), 1);
+        assert_eq!(delay.due(now + Duration::from_secs(2)), vec![tx]);
+        assert_eq!(delay.pending(), 0);
+        // without a delay nothing is held
+        assert_eq!(RelayDelay::new(Duration::ZERO).hold(vec![tx], now, &mut rng), vec![tx]);
+    }
+
+    #[test]
+    fn oldest_are_evicted() {
+        let mut recent = RecentlyAnnounced::new(Duration::from_secs(60));
+        let peer = "127.0.0.1:6001".parse().unwrap();
+        let now = Instant::now();
+        for i in 0..4u8 {
+            recent.mark(peer, H256::from([i; 32]), now + Duration::from_secs(i as u64));
+        }
+        let entry = memory::map_entry_size::<H256, Instant>();
+        assert_eq!(recent.memory_usage(), 4 * entry);
+        assert_eq!(recent.evict(2 * entry), 2);
+        let tx = |i: u8| InvItem::Transaction(H256::from([i; 32]));
+        // the two oldest may be announced again, the newest are still suppressed
+        assert_eq!(recent.filter(peer, &[tx(0), tx(1), tx(2), tx(3)], now), vec![tx(0), tx(1)]);
+    }
+}
@@ -0,0 +1,67 @@
+use crate::types::hash::H256;
+
+use log::debug;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Blocks requested outside of the announcement routing, by the tip a peer reports or as the
+/// parent of an orphan, with when they were requested. When several peers report the same new
+/// tip, or send children of the same missing parent, only the first is asked; the others are
+/// asked only once `timeout` has passed without the block arriving.
+pub struct InFlightBlocks {
+    timeout: Duration,
+    requested: HashMap<H256, Instant>,
+    skipped: u64,
+}
+
+impl InFlightBlocks {
+    pub fn new(timeout: Duration) -> Self {
+        InFlightBlocks {
+            timeout,
+            requested: HashMap::new(),
+            skipped: 0,
+        }
+    }
+
+    /// Whether `hash` should be requested at `now`, in which case it is recorded as requested
+    pub fn request(&mut self, hash: H256, now: Instant) -> bool {
+        let timeout = self.timeout;
+        self.requested.retain(|_, at| now.saturating_duration_since(*at) < timeout);
+        if self.requested.contains_key(&hash) {
+            debug!("Block {} already requested, not asking again", hash);
+            self.skipped += 1;
+            return false;
+        }
+        self.requested.insert(hash, now);
+        true
+    }
+
+    /// Forget a block once it arrived, from whichever peer
+    pub fn on_received(&mut self, hash: &H256) {
+        self.requested.remove(hash);
+    }
+
+    /// Number of requests left out so far because the block was already requested
+    pub fn skipped(&self) -> u64 {
+        self.skipped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requested_once_until_timeout() {
+        let mut in_flight = InFlightBlocks::new(Duration::from_secs(5));
+        let block = H256::from([1; 32]);
+        let now = Instant::now();
+        assert!(in_flight.request(block, now));
+        assert!(!in_flight.request(block, now + Duration::from_secs(1)));
+        assert!(in_flight.request(block, now + Duration::from_secs(5)));
+        assert_eq!(in_flight.skipped(), 1);
+        // once received it may be asked for again, e.g. after a reorg dropped it
+        in_flight.on_received(&block);
+        assert!(in_flight.request(block, now + Duration::from_secs(6)));
+    }
+}
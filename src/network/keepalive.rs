@@ -0,0 +1,131 @@
+use super::message::Message;
+use super::peer::{PeerStates, State};
+use super::server::Handle as ServerHandle;
+
+use log::{info, warn};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{self, Duration};
+
+// the ping of a peer that was not answered yet, and how many before it went unanswered
+#[derive(Debug, Default)]
+struct Liveness {
+    outstanding: Option<u128>,
+    missed: u32,
+}
+
+/// Pings every peer that completed the handshake and notices those that stop answering. The
+/// nonce of a ping is the time it was sent in ms, so its answer also times the round trip and
+/// samples the clock of the peer. A peer that misses `max_missed` pings in a row, unless 0, is
+/// taken for dead, as a half-open connection never fails on its own.
+#[derive(Debug)]
+pub struct KeepAlive {
+    max_missed: u32,
+    peers: HashMap<SocketAddr, Liveness>,
+}
+
+impl KeepAlive {
+    pub fn new(max_missed: u32) -> Self {
+        KeepAlive { max_missed, peers: HashMap::new() }
+    }
+
+    /// Ping the `connected` peers at `now`, in ms since the unix epoch. Returns the peers to ping
+    /// with `now` as the nonce, and the peers to disconnect as they missed too many pings.
+    /// Peers no longer connected are forgotten.
+    pub fn tick(&mut self, connected: &[SocketAddr], now: u128) -> (Vec<SocketAddr>, Vec<SocketAddr>) {
+        self.peers.retain(|peer, _| connected.contains(peer));
+        let mut ping = vec![];
+        let mut dead = vec![];
+        for peer in connected {
+            let liveness = self.peers.entry(*peer).or_default();
+            if liveness.outstanding.is_some() {
+                liveness.missed += 1;
+            }
+            if self.max_missed > 0 && liveness.missed >= self.max_missed {
+                dead.push(*peer);
+            } else {
+                liveness.outstanding = Some(now);
+                ping.push(*peer);
+            }
+        }
+        for peer in dead.iter() {
+            self.peers.remove(peer);
+        }
+        (ping, dead)
+    }
+
+    /// `peer` answered the ping with `nonce`. Any answer, even to an older ping, shows the peer
+    /// is alive.
+    pub fn on_answer(&mut self, peer: SocketAddr, nonce: u128) {
+        if let Some(liveness) = self.peers.get_mut(&peer) {
+            liveness.missed = 0;
+            if liveness.outstanding == Some(nonce) {
+                liveness.outstanding = None;
+            }
+        }
+    }
+
+    /// Consecutive pings `peer` left unanswered, not counting the one in flight
+    pub fn missed(&self, peer: &SocketAddr) -> u32 {
+        self.peers.get(peer).map_or(0, |liveness| liveness.missed)
+    }
+}
+
+/// Every `interval`, ping the peers past the handshake and disconnect the ones `keepalive`
+/// found dead
+pub fn start(interval: Duration, keepalive: &Arc<Mutex<KeepAlive>>, peer_states: &Arc<Mutex<PeerStates>>, server: &ServerHandle) {
+    let keepalive = Arc::clone(keepalive);
+    let peer_states = Arc::clone(peer_states);
+    let server = server.clone();
+    thread::Builder::new()
+        .name("keepalive".to_string())
+        .spawn(move || loop {
+            // peers still handshaking would drop a ping as out of order
+            let connected: Vec<SocketAddr> = {
+                let peer_states = peer_states.lock().unwrap();
+                peer_states.connected().into_iter().filter(|(_, state)| *state != State::Handshaking).map(|(peer, _)| peer).collect()
+            };
+            let now = time::SystemTime::now().duration_since(time::UNIX_EPOCH).unwrap().as_millis();
+            let (ping, dead) = {keepalive.lock().unwrap().tick(&connected, now)};
+            for peer in dead {
+                warn!("Disconnecting peer {}, it stopped answering pings", peer);
+                server.disconnect(peer);
+            }
+            for peer in ping {
+                server.send_to(peer, Message::Ping(now.to_string()));
+            }
+            thread::sleep(interval);
+        })
+        .unwrap();
+    info!("Pinging peers every {:?}", interval);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn silent_peers_are_found_dead() {
+        let (a, b): (SocketAddr, SocketAddr) = ("127.0.0.1:6001".parse().unwrap(), "127.0.0.1:6002".parse().unwrap());
+        let mut keepalive = KeepAlive::new(3);
+        assert_eq!(keepalive.tick(&[a, b], 0), (vec![a, b], vec![]));
+        // a answers every ping, b answers one late, then nothing
+        keepalive.on_answer(a, 0);
+        assert_eq!(keepalive.tick(&[a, b], 10), (vec![a, b], vec![]));
+        keepalive.on_answer(a, 10);
+        keepalive.on_answer(b, 0);
+        assert_eq!(keepalive.missed(&b), 0);
+        assert_eq!(keepalive.tick(&[a, b], 20), (vec![a, b], vec![]));
+        assert_eq!(keepalive.missed(&b), 1);
+        keepalive.on_answer(a, 20);
+        assert_eq!(keepalive.tick(&[a, b], 30), (vec![a, b], vec![]));
+        keepalive.on_answer(a, 30);
+        assert_eq!(keepalive.tick(&[a, b], 40), (vec![a], vec![b]));
+        assert_eq!(keepalive.missed(&a), 0);
+        // a peer that reconnects starts over
+        assert_eq!(keepalive.tick(&[a, b], 50), (vec![a, b], vec![]));
+        assert_eq!(keepalive.missed(&b), 0);
+    }
+}
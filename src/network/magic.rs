@@ -0,0 +1,82 @@
+use std::convert::TryInto;
+
+/// Four bytes starting every frame a node sends once connected, telling apart networks whose
+/// nodes would otherwise accept each other, e.g. a devnet and an experiment started with the
+/// same chain parameters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NetworkMagic([u8; 4]);
+
+/// A frame that does not start with our magic.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MagicError {
+    /// shorter than the magic
+    Truncated,
+    /// starts with the magic of another network, or none at all
+    Wrong([u8; 4]),
+}
+
+impl std::fmt::Display for MagicError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            MagicError::Truncated => write!(f, "frame too short for the network magic"),
+            MagicError::Wrong(magic) => write!(f, "frame of another network, it starts with {}", hex::encode(magic)),
+        }
+    }
+}
+
+impl std::str::FromStr for NetworkMagic {
+    type Err = String;
+
+    /// Parse the magic from its 8 hex digits
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = hex::decode(s).map_err(|e| e.to_string())?;
+        let bytes: [u8; 4] = bytes.as_slice().try_into().map_err(|_| format!("expected 4 bytes, got {}", bytes.len()))?;
+        Ok(NetworkMagic(bytes))
+    }
+}
+
+impl std::fmt::Display for NetworkMagic {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+impl NetworkMagic {
+    /// `frame` as sent on our network
+    pub fn seal(&self, frame: Vec<u8>) -> Vec<u8> {
+        let mut sealed = Vec::with_capacity(self.0.len() + frame.len());
+        sealed.extend_from_slice(&self.0);
+        sealed.extend(frame);
+        sealed
+    }
+
+    /// The frame `sealed` carries, if it was sent on our network
+    pub fn open(&self, mut sealed: Vec<u8>) -> Result<Vec<u8>, MagicError> {
+        let magic: [u8; 4] = sealed.get(..4).ok_or(MagicError::Truncated)?.try_into().unwrap();
+        if magic != self.0 {
+            return Err(MagicError::Wrong(magic));
+        }
+        sealed.drain(..4);
+        Ok(sealed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::message::Message;
+
+    #[test]
+    fn frames_of_other_networks_are_refused() {
+        let devnet: NetworkMagic = "d0d0cafe".parse().unwrap();
+        let experiment: NetworkMagic = "e1e1beef".parse().unwrap();
+        let frame = Message::GetTip.encode();
+        assert_eq!(devnet.open(devnet.seal(frame.clone())).unwrap(), frame);
+        assert_eq!(experiment.open(devnet.seal(frame.clone())), Err(MagicError::Wrong([0xd0, 0xd0, 0xca, 0xfe])));
+        // a node without a magic sends bare frames
+        assert!(matches!(devnet.open(frame), Err(MagicError::Wrong(_))));
+        assert_eq!(devnet.open(vec![0xd0]), Err(MagicError::Truncated));
+        assert!("d0d0".parse::<NetworkMagic>().is_err());
+        assert_eq!(devnet.to_string(), "d0d0cafe");
+    }
+}
@@ -0,0 +1,32 @@
+use super::features::{Features, PeerFeatures};
+use super::message::Message;
+use super::server::Handle as ServerHandle;
+use crate::metrics::{Marker, MetricsHistory};
+
+use log::{info, warn};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+/// Record `marker` in `metrics` and pass it on to every peer supporting `Features::MARKERS`
+/// except `from`, the peer that sent it to us. A marker we recorded before is dropped, so it
+/// stops spreading once every node has it. Markers are annotations for experiments only, they
+/// have no effect on the chain.
+pub fn record_and_relay(
+    marker: &Marker,
+    from: Option<SocketAddr>,
+    metrics: &Arc<Mutex<MetricsHistory>>,
+    server: &ServerHandle,
+    peer_features: &Arc<Mutex<PeerFeatures>>,
+) {
+    let recorded = {metrics.lock().unwrap().mark(marker)};
+    match recorded {
+        Ok(true) => info!("Marker {:?} at {}", marker.name, marker.timestamp),
+        Ok(false) => return,
+        // peers still get it, their exports should not miss it because our disk failed
+        Err(e) => warn!("Error recording marker {:?}: {}", marker.name, e),
+    }
+    let peers = {peer_features.lock().unwrap().peers_supporting(Features::MARKERS)};
+    for peer in peers.into_iter().filter(|peer| Some(*peer) != from) {
+        server.send_to(peer, Message::Marker(marker.clone()));
+    }
+}
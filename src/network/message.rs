@@ -1,15 +1,532 @@
+use bincode::Options;
 use serde::{Serialize, Deserialize};
+use std::net::SocketAddr;
 
-use crate::types::{hash::H256, block::Block, transaction::SignedTransaction};
+use crate::types::{address::Address, amount::Amount, hash::H256, block::Block, transaction::SignedTransaction};
+use crate::types::chain_proof::ChainProof;
+use crate::types::state_proof::StateProof;
+use crate::metrics::Marker;
+use super::failure_log::RejectCode;
+use super::features::{Features, PROTOCOL_VERSION};
+use super::reconcile::Sketch;
+use super::topology::Link;
+
+/// A typed reference to an object a node can announce, request or report missing.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum InvItem {
+    Block(H256),
+    Transaction(H256),
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum Message {
     Ping(String),
     Pong(String),
-    NewBlockHashes(Vec<H256>),
-    GetBlocks(Vec<H256>),
+    Clock(String, u128), // answer to a ping with the responder's time in ms since the unix epoch
+    Version(u32, Features, u32, H256), // protocol version, optional features, height and genesis hash of the sender, sent on connect
+    Inv(Vec<InvItem>), // announce objects we have
+    GetData(Vec<InvItem>), // request announced objects
+    NotFound(Vec<InvItem>), // requested objects we do not have
     Blocks(Vec<Block>),
-    NewTransactionHashes(Vec<H256>),
-    GetTransactions(Vec<H256>),
     Transactions(Vec<SignedTransaction>),
+    TxPackage(Vec<SignedTransaction>), // dependent transactions in dependency order, admitted all or none
+    GetTip,
+    Tip(u32, H256), // height and hash of the sender's tip
+    GetStateProof(Address, H256), // account and the block whose state it is looked up in
+    StateProof(Option<StateProof>), // None if the block or the account is unknown
+    ReconcileRequest(u32), // ask for a sketch of the transactions held for us, with the size of our set
+    ReconcileSketch(Sketch), // sketch of the transactions held for the requester
+    ReconcileDiff(Vec<u64>), // short ids of the sketched transactions the requester lacks
+    ReconcileFailed, // the sketch did not decode, announce everything in it
+    Verack, // the sender accepted our Version
+    GetAddr, // ask for the addresses of peers the receiver knows
+    Addr(Vec<SocketAddr>), // P2P addresses of nodes, the first one sent on connect being the sender's own
+    Marker(Marker), // experiment marker placed on some node, recorded with the metrics and relayed
+    GetBlocksAfter(Vec<H256>), // locator of the sender's longest chain, ask for the blocks after the fork point
+    MoreBlocks(H256), // the Blocks answer to a GetBlocksAfter was capped, ask again to continue after this block
+    GetMempool, // ask for the transactions in the receiver's mempool, announced with Inv
+    DustFilter(Amount), // the smallest transaction value the sender relays, do not announce smaller ones to it
+    GetLinks, // ask for the connections of the receiver, to map the network
+    Links(Option<SocketAddr>, Vec<Link>), // the address the sender listens at, if it can tell, and its connections
+    GetChainProof(H256, u32), // ask for a proof of the receiver's longest chain sampled from a seed with that many draws
+    ChainProof(Option<ChainProof>), // None if more draws were asked for than the sender serves
+    Reject(InvItem, RejectCode), // a block or transaction we received from the receiver failed validation
+    Compressed(Vec<u8>), // the frame of another message, snappy compressed, sent once both sides negotiated compression
+    NewBlockHashes(Vec<H256>), // announce blocks as the course protocol does, answered with a GetBlocks
+    GetBlocks(Vec<H256>), // request blocks as the course protocol does, answered with Blocks
+}
+
+/// Most blocks in one `Blocks` message
+pub const MAX_BLOCKS_PER_MESSAGE: usize = 100;
+
+/// Most bytes of blocks in one `Blocks` message, a larger block is still sent on its own
+pub const MAX_BLOCKS_BYTES: u64 = 1 << 20;
+
+/// Longest locator answered, enough for a chain of 2^50 blocks
+pub const MAX_LOCATOR_HASHES: usize = 64;
+
+/// Most transactions announced in one `Inv` answering a `GetMempool`
+pub const MEMPOOL_INV_CHUNK: usize = 1000;
+
+/// Frames shorter than this are sent as they are, compressing them saves next to nothing
+pub const COMPRESSION_THRESHOLD: usize = 512;
+
+/// Largest frame a `Compressed` may expand to, so a small frame cannot make us allocate gigabytes
+pub const MAX_DECOMPRESSED_BYTES: usize = 64 << 20;
+
+/// First protocol version whose frames are envelopes, see `Message::encode_for`
+pub const ENVELOPE_VERSION: u32 = 4;
+
+// starts every envelope; a frame of an older protocol starts with the u32 id of its message
+// type, whose first byte is below `NAMES.len()`, so the two cannot be mistaken for each other
+const ENVELOPE_MARKER: u8 = 0xf4;
+
+// the marker, then the protocol version, the message type id and the payload length, as u32
+const ENVELOPE_HEADER: usize = 1 + 4 + 4 + 4;
+
+/// Why a frame did not decode as a message.
+#[derive(Debug)]
+pub enum DecodeError {
+    /// too short for its header, or for the payload length it announces
+    Truncated,
+    /// longer than the payload length its envelope announces
+    TrailingBytes,
+    /// a message type added after this node's protocol version, with this id
+    Unknown(u32),
+    /// a message type we know, whose payload does not decode
+    Malformed(&'static str, bincode::Error),
+    /// a `Compressed` frame that does not decompress to a message
+    Decompression(String),
+    /// a JSON message that does not parse as one of ours
+    Json(String),
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            DecodeError::Truncated => write!(f, "frame too short for its header or payload"),
+            DecodeError::TrailingBytes => write!(f, "frame longer than its payload"),
+            DecodeError::Unknown(id) => write!(f, "unknown message type {}", id),
+            DecodeError::Malformed(name, e) => write!(f, "{} does not decode: {}", name, e),
+            DecodeError::Decompression(e) => write!(f, "compressed frame does not decompress: {}", e),
+            DecodeError::Json(e) => write!(f, "JSON message does not parse: {}", e),
+        }
+    }
+}
+
+/// Names of the message types in declaration order, which is the order of their bincode tags
+pub const NAMES: [&str; 34] = [
+    "Ping", "Pong", "Clock", "Version", "Inv", "GetData", "NotFound", "Blocks", "Transactions", "TxPackage",
+    "GetTip", "Tip", "GetStateProof", "StateProof", "ReconcileRequest", "ReconcileSketch", "ReconcileDiff",
+    "ReconcileFailed", "Verack", "GetAddr", "Addr", "Marker", "GetBlocksAfter",
+    "MoreBlocks", "GetMempool", "DustFilter", "GetLinks", "Links", "GetChainProof", "ChainProof", "Reject",
+    "Compressed", "NewBlockHashes", "GetBlocks",
+];
+
+/// Number of message types protocol version 2 knows, the first ones of `NAMES`. A version 2
+/// node disconnects a peer sending it any other type.
+pub const V2_MESSAGE_TYPES: usize = 26;
+
+impl Message {
+    /// The frame of this message in our protocol version, see `encode_for`
+    pub fn encode(&self) -> Vec<u8> {
+        self.encode_for(PROTOCOL_VERSION)
+    }
+
+    /// The frame of this message for a peer speaking protocol `version`. From
+    /// `ENVELOPE_VERSION` on it is an envelope: a marker byte, then `version`, the id of the
+    /// message type indexing `NAMES` and the length of the payload, each a little endian u32,
+    /// then the payload. Older versions read the bare bincode of the message, the id followed
+    /// by the payload, and so does every version for a `Version`, which is sent before the peer
+    /// knows ours. New message types are only ever appended and new fields only appended to a
+    /// payload, so an older node can still tell a message apart.
+    pub fn encode_for(&self, version: u32) -> Vec<u8> {
+        let bare = bincode::serialize(self).unwrap();
+        if version < ENVELOPE_VERSION || matches!(self, Message::Version(..)) {
+            return bare;
+        }
+        // bincode writes the variant first, which is the position of the type in `NAMES`
+        let id = self.id();
+        debug_assert_eq!(bare[..4], id.to_le_bytes());
+        let payload = &bare[4..];
+        let mut frame = Vec::with_capacity(ENVELOPE_HEADER + payload.len());
+        frame.push(ENVELOPE_MARKER);
+        frame.extend_from_slice(&version.to_le_bytes());
+        frame.extend_from_slice(&id.to_le_bytes());
+        frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    /// Decode a frame, an envelope or the bare message of an older protocol. A message type
+    /// added after our protocol version is told apart from a frame that is broken, so it can be
+    /// skipped. A payload must be decoded whole, unless the envelope comes from a newer protocol
+    /// that may have appended fields to it.
+    pub fn decode(bytes: &[u8]) -> Result<Message, DecodeError> {
+        let (version, id, payload) = open(bytes)?;
+        let name = NAMES.get(id as usize).ok_or(DecodeError::Unknown(id))?;
+        let mut bare = id.to_le_bytes().to_vec();
+        bare.extend_from_slice(payload);
+        let options = bincode::DefaultOptions::new().with_fixint_encoding();
+        let message = if version > PROTOCOL_VERSION {
+            options.allow_trailing_bytes().deserialize(&bare)
+        } else {
+            options.reject_trailing_bytes().deserialize(&bare)
+        };
+        message.map_err(|e| DecodeError::Malformed(name, e))
+    }
+
+    /// Name of the type of the message in `bytes`, from its header without decoding the
+    /// payload. None if the header is incomplete or the type unknown.
+    pub fn peek_name(bytes: &[u8]) -> Option<&'static str> {
+        let (_, id, _) = open(bytes).ok()?;
+        NAMES.get(id as usize).copied()
+    }
+
+    // id of the message type, its position in `NAMES`
+    fn id(&self) -> u32 {
+        NAMES.iter().position(|name| *name == self.name()).unwrap() as u32
+    }
+
+    /// Oldest protocol version that knows this message type. Every type is listed by name, so
+    /// adding one without deciding its version does not compile. `GetTip` and `Tip` shifted the
+    /// tags of the types after them while the protocol was still at version 1; version 2 is the
+    /// first to include them, and older peers are refused by `MIN_PROTOCOL_VERSION`.
+    pub fn introduced_in(&self) -> u32 {
+        match self {
+            Message::Ping(_) | Message::Pong(_) | Message::Clock(..) | Message::Version(..) => 2,
+            Message::Inv(_) | Message::GetData(_) | Message::NotFound(_) => 2,
+            Message::Blocks(_) | Message::Transactions(_) | Message::TxPackage(_) => 2,
+            Message::GetTip | Message::Tip(..) => 2,
+            Message::GetStateProof(..) | Message::StateProof(_) => 2,
+            Message::ReconcileRequest(_) | Message::ReconcileSketch(_) | Message::ReconcileDiff(_) => 2,
+            Message::ReconcileFailed | Message::Verack | Message::GetAddr | Message::Addr(_) => 2,
+            Message::Marker(_) | Message::GetBlocksAfter(_) | Message::MoreBlocks(_) => 2,
+            Message::GetMempool | Message::DustFilter(_) => 2,
+            Message::GetLinks | Message::Links(..) => 3,
+            Message::GetChainProof(..) | Message::ChainProof(_) => 3,
+            Message::Reject(..) | Message::Compressed(_) => 3,
+            Message::NewBlockHashes(_) | Message::GetBlocks(_) => 4,
+        }
+    }
+
+    /// This message as a peer speaking protocol `version` can read it, None if it has no
+    /// equivalent there. The types added since version 2 only carry optional information, a
+    /// version 2 peer is not sent them at all rather than disconnecting us over them.
+    pub fn for_version(self, version: u32) -> Option<Message> {
+        if self.introduced_in() <= version {
+            return Some(self);
+        }
+        match self {
+            // the listen address is all of the links a peer exchanging addresses can use
+            Message::Links(Some(listen), _) => Some(Message::Addr(vec![listen])),
+            _ => None,
+        }
+    }
+
+    /// Name of the message type, for logs
+    pub fn name(&self) -> &'static str {
+        match self {
+            Message::Ping(_) => "Ping",
+            Message::Pong(_) => "Pong",
+            Message::Clock(..) => "Clock",
+            Message::Version(..) => "Version",
+            Message::Inv(_) => "Inv",
+            Message::GetData(_) => "GetData",
+            Message::NotFound(_) => "NotFound",
+            Message::Blocks(_) => "Blocks",
+            Message::Transactions(_) => "Transactions",
+            Message::TxPackage(_) => "TxPackage",
+            Message::GetTip => "GetTip",
+            Message::Tip(..) => "Tip",
+            Message::GetStateProof(..) => "GetStateProof",
+            Message::StateProof(_) => "StateProof",
+            Message::ReconcileRequest(_) => "ReconcileRequest",
+            Message::ReconcileSketch(_) => "ReconcileSketch",
+            Message::ReconcileDiff(_) => "ReconcileDiff",
+            Message::ReconcileFailed => "ReconcileFailed",
+            Message::Verack => "Verack",
+            Message::GetAddr => "GetAddr",
+            Message::Addr(_) => "Addr",
+            Message::Marker(_) => "Marker",
+            Message::GetBlocksAfter(_) => "GetBlocksAfter",
+            Message::MoreBlocks(_) => "MoreBlocks",
+            Message::GetMempool => "GetMempool",
+            Message::DustFilter(_) => "DustFilter",
+            Message::GetLinks => "GetLinks",
+            Message::Links(..) => "Links",
+            Message::GetChainProof(..) => "GetChainProof",
+            Message::ChainProof(_) => "ChainProof",
+            Message::Reject(..) => "Reject",
+            Message::Compressed(_) => "Compressed",
+            Message::NewBlockHashes(_) => "NewBlockHashes",
+            Message::GetBlocks(_) => "GetBlocks",
+        }
+    }
+}
+
+// the protocol version, the message type id and the payload of a frame; a frame of a protocol
+// before `ENVELOPE_VERSION` is the id followed by the payload
+fn open(bytes: &[u8]) -> Result<(u32, u32, &[u8]), DecodeError> {
+    if bytes.first() != Some(&ENVELOPE_MARKER) {
+        let id = bytes.get(..4).ok_or(DecodeError::Truncated)?;
+        return Ok((ENVELOPE_VERSION - 1, u32::from_le_bytes([id[0], id[1], id[2], id[3]]), &bytes[4..]));
+    }
+    let header = bytes.get(..ENVELOPE_HEADER).ok_or(DecodeError::Truncated)?;
+    let field = |at: usize| u32::from_le_bytes([header[at], header[at + 1], header[at + 2], header[at + 3]]);
+    let (version, id, len) = (field(1), field(5), field(9) as usize);
+    let payload = &bytes[ENVELOPE_HEADER..];
+    if payload.len() < len {
+        return Err(DecodeError::Truncated);
+    }
+    if payload.len() > len {
+        return Err(DecodeError::TrailingBytes);
+    }
+    Ok((version, id, payload))
+}
+
+/// The frame to send for `frame` to a peer speaking protocol `version` that negotiated
+/// compression: a `Compressed` wrapping it, or the frame itself if it is short or does not get
+/// any shorter
+pub fn compress(frame: Vec<u8>, version: u32) -> Vec<u8> {
+    if frame.len() < COMPRESSION_THRESHOLD {
+        return frame;
+    }
+    let compressed = match snap::raw::Encoder::new().compress_vec(&frame) {
+        Ok(compressed) => Message::Compressed(compressed).encode_for(version),
+        Err(_) => return frame,
+    };
+    if compressed.len() < frame.len() {
+        compressed
+    } else {
+        frame
+    }
+}
+
+/// The frame a `Compressed` frame wraps, other frames as they are. Done before rate limiting,
+/// so a message counts against the limit of its own type however it was sent.
+pub fn decompress(frame: Vec<u8>) -> Result<Vec<u8>, DecodeError> {
+    if Message::peek_name(&frame) != Some("Compressed") {
+        return Ok(frame);
+    }
+    let compressed = match Message::decode(&frame)? {
+        Message::Compressed(compressed) => compressed,
+        _ => unreachable!("peeked a Compressed"),
+    };
+    let len = snap::raw::decompress_len(&compressed).map_err(|e| DecodeError::Decompression(e.to_string()))?;
+    if len > MAX_DECOMPRESSED_BYTES {
+        return Err(DecodeError::Decompression(format!("{} bytes is over the limit of {}", len, MAX_DECOMPRESSED_BYTES)));
+    }
+    let inner = snap::raw::Decoder::new()
+        .decompress_vec(&compressed)
+        .map_err(|e| DecodeError::Decompression(e.to_string()))?;
+    // compressing twice gains nothing, a peer doing so is broken
+    if Message::peek_name(&inner) == Some("Compressed") {
+        return Err(DecodeError::Decompression("nested compressed frame".to_string()));
+    }
+    Ok(inner)
+}
+
+/// The message of `frame` as JSON, for peers that do not speak bincode: the name of its type
+/// keying its payload, e.g. `{"Ping":"nonce"}`, or only the name for a type without one, e.g.
+/// `"GetTip"`. Hashes and addresses are arrays of their bytes.
+pub fn to_json(frame: Vec<u8>) -> Result<String, DecodeError> {
+    let message = Message::decode(&decompress(frame)?)?;
+    Ok(serde_json::to_string(&message).unwrap())
+}
+
+/// The frame of a message a peer sent as JSON, see `to_json`
+pub fn from_json(text: &str) -> Result<Vec<u8>, DecodeError> {
+    let message: Message = serde_json::from_str(text).map_err(|e| DecodeError::Json(e.to_string()))?;
+    Ok(message.encode())
+}
+
+/// Split `blocks` into the contents of `Blocks` messages, in order, each within
+/// `MAX_BLOCKS_PER_MESSAGE` and `MAX_BLOCKS_BYTES`
+pub fn block_pages(blocks: Vec<Block>) -> Vec<Vec<Block>> {
+    let mut pages: Vec<Vec<Block>> = Vec::new();
+    let mut bytes = 0;
+    for block in blocks {
+        let size = bincode::serialized_size(&block).unwrap();
+        match pages.last_mut() {
+            Some(page) if page.len() < MAX_BLOCKS_PER_MESSAGE && bytes + size <= MAX_BLOCKS_BYTES => {
+                bytes += size;
+                page.push(block);
+            }
+            _ => {
+                bytes = size;
+                pages.push(vec![block]);
+            }
+        }
+    }
+    pages
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::block::{generate_random_block, TestBlockBuilder};
+    use crate::types::hash::Hashable;
+
+    #[test]
+    fn peek_name_matches_name() {
+        let messages = vec![
+            Message::Ping("1".to_string()),
+            Message::Clock("1".to_string(), 2),
+            Message::Inv(vec![InvItem::Block(H256::default())]),
+            Message::Blocks(vec![]),
+            Message::GetTip,
+            Message::Tip(3, H256::default()),
+            Message::ReconcileDiff(vec![1, 2]),
+            Message::ReconcileFailed,
+            Message::Verack,
+            Message::Addr(vec!["127.0.0.1:6000".parse().unwrap()]),
+            Message::Marker(Marker::new("partition start", 1)),
+            Message::GetBlocksAfter(vec![H256::default()]),
+            Message::MoreBlocks(H256::default()),
+            Message::GetMempool,
+            Message::DustFilter(Amount::from_sub_units(10)),
+            Message::GetLinks,
+            Message::Links(None, vec![]),
+            Message::GetChainProof(H256::default(), 16),
+            Message::ChainProof(None),
+            Message::Reject(InvItem::Block(H256::default()), RejectCode::BadPow),
+            Message::Compressed(vec![]),
+            Message::NewBlockHashes(vec![H256::default()]),
+            Message::GetBlocks(vec![H256::default()]),
+        ];
+        for message in messages {
+            assert_eq!(Message::peek_name(&message.encode()), Some(message.name()));
+            assert_eq!(Message::peek_name(&message.encode_for(3)), Some(message.name()));
+            assert!(matches!(Message::decode(&message.encode_for(3)), Ok(decoded) if decoded.name() == message.name()));
+        }
+        assert_eq!(Message::peek_name(&[1, 2]), None);
+        assert_eq!(Message::peek_name(&[200, 0, 0, 0]), None);
+        assert_eq!(Message::peek_name(&[ENVELOPE_MARKER, 4, 0, 0, 0]), None);
+    }
+
+    #[test]
+    fn older_peers_get_what_they_know() {
+        let tip = Message::Tip(3, H256::default());
+        assert!(matches!(tip.for_version(2), Some(Message::Tip(3, _))));
+        let reject = Message::Reject(InvItem::Block(H256::default()), RejectCode::BadPow);
+        assert_eq!(reject.introduced_in(), 3);
+        assert!(reject.clone().for_version(2).is_none());
+        assert!(reject.for_version(3).is_some());
+        let listen: SocketAddr = "127.0.0.1:6000".parse().unwrap();
+        assert!(matches!(Message::Links(Some(listen), vec![]).for_version(2), Some(Message::Addr(addrs)) if addrs == vec![listen]));
+        assert!(Message::Links(None, vec![]).for_version(2).is_none());
+        // what a version 2 node decodes is exactly the types it knows
+        assert_eq!(NAMES[V2_MESSAGE_TYPES - 1], "DustFilter");
+        assert_eq!(Message::DustFilter(Amount::default()).introduced_in(), 2);
+        assert_eq!(Message::GetLinks.introduced_in(), 3);
+        // the tags of the tip messages are part of version 2 and must not move again
+        assert_eq!(Message::GetTip.introduced_in(), 2);
+        assert_eq!(Message::GetTip.encode_for(3), 10u32.to_le_bytes().to_vec());
+        assert_eq!(Message::peek_name(&Message::Tip(3, H256::default()).encode()), Some("Tip"));
+    }
+
+    // an envelope as a node of protocol `version` would write it
+    fn envelope(version: u32, id: u32, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![ENVELOPE_MARKER];
+        frame.extend_from_slice(&version.to_le_bytes());
+        frame.extend_from_slice(&id.to_le_bytes());
+        frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    #[test]
+    fn frames_are_enveloped_from_version_4() {
+        let tip = Message::Tip(3, H256::default());
+        let bare = bincode::serialize(&tip).unwrap();
+        assert_eq!(tip.encode_for(3), bare);
+        assert_eq!(tip.encode_for(4), envelope(4, 11, &bare[4..]));
+        // a Version is read before the peer knows our protocol
+        let version = Message::Version(4, Features::NONE, 0, H256::default());
+        assert_eq!(version.encode(), bincode::serialize(&version).unwrap());
+        assert!(matches!(Message::decode(&tip.encode()), Ok(Message::Tip(3, _))));
+        assert!(matches!(Message::decode(&bare), Ok(Message::Tip(3, _))));
+    }
+
+    #[test]
+    fn newer_messages_are_told_apart() {
+        let payload = bincode::serialize(&(3u32, H256::default())).unwrap();
+        // a payload with fields added by a newer protocol
+        let mut longer = payload.clone();
+        longer.extend(&[1, 2, 3]);
+        assert!(matches!(Message::decode(&envelope(PROTOCOL_VERSION + 1, 11, &longer)), Ok(Message::Tip(3, _))));
+        // but not in our own, nor in a bare frame
+        assert!(matches!(Message::decode(&envelope(PROTOCOL_VERSION, 11, &longer)), Err(DecodeError::Malformed("Tip", _))));
+        let mut bare = Message::Tip(3, H256::default()).encode_for(3);
+        bare.extend(&[1, 2, 3]);
+        assert!(matches!(Message::decode(&bare), Err(DecodeError::Malformed("Tip", _))));
+        // bytes after the payload the envelope announces, or missing from it
+        let mut frame = envelope(PROTOCOL_VERSION, 11, &payload);
+        frame.push(0);
+        assert!(matches!(Message::decode(&frame), Err(DecodeError::TrailingBytes)));
+        assert!(matches!(Message::decode(&frame[..frame.len() - 2]), Err(DecodeError::Truncated)));
+        assert!(matches!(Message::decode(&frame[..ENVELOPE_HEADER - 1]), Err(DecodeError::Truncated)));
+        let frame = envelope(PROTOCOL_VERSION + 1, NAMES.len() as u32, &[1, 2, 3]);
+        assert!(matches!(Message::decode(&frame), Err(DecodeError::Unknown(id)) if id as usize == NAMES.len()));
+        assert!(matches!(Message::decode(&[1, 0]), Err(DecodeError::Truncated)));
+        let frame = envelope(PROTOCOL_VERSION, 11, &payload[..6]);
+        assert!(matches!(Message::decode(&frame), Err(DecodeError::Malformed("Tip", _))));
+    }
+
+    #[test]
+    fn block_pages_are_capped() {
+        let small: Vec<Block> = (0..250).map(|_| generate_random_block(&H256::default())).collect();
+        let pages = block_pages(small);
+        assert_eq!(pages.iter().map(Vec::len).collect::<Vec<_>>(), vec![100, 100, 50]);
+
+        // blocks of about 400 KiB, two fit in a page
+        let transactions = vec![SignedTransaction::default(); 8000];
+        let large: Vec<Block> = (0..3)
+            .map(|_| TestBlockBuilder::new(&H256::default()).transactions(transactions.clone()).build())
+            .collect();
+        assert!(bincode::serialized_size(&large[0]).unwrap() * 2 < MAX_BLOCKS_BYTES);
+        assert!(bincode::serialized_size(&large[0]).unwrap() * 3 > MAX_BLOCKS_BYTES);
+        let hashes: Vec<H256> = large.iter().map(|block| block.hash()).collect();
+        let pages = block_pages(large);
+        assert_eq!(pages.iter().map(Vec::len).collect::<Vec<_>>(), vec![2, 1]);
+        assert_eq!(pages.concat().iter().map(|block| block.hash()).collect::<Vec<_>>(), hashes);
+    }
+
+    #[test]
+    fn compressed_frames_round_trip() {
+        let block = generate_random_block(&H256::default());
+        let frame = Message::Blocks(vec![block.clone(); 20]).encode();
+        let compressed = compress(frame.clone(), PROTOCOL_VERSION);
+        assert_eq!(Message::peek_name(&compressed), Some("Compressed"));
+        assert_eq!(compressed[0], ENVELOPE_MARKER);
+        // an older peer gets it bare
+        let older = Message::Blocks(vec![block.clone(); 20]).encode_for(3);
+        let compressed_older = compress(older.clone(), 3);
+        assert_eq!(compressed_older[..4], 31u32.to_le_bytes());
+        assert_eq!(decompress(compressed_older).unwrap(), older);
+        assert!(compressed.len() < frame.len());
+        assert_eq!(decompress(compressed.clone()).unwrap(), frame);
+        // short frames and frames of other types pass through
+        let ping = Message::Ping("1".to_string()).encode();
+        assert_eq!(compress(ping.clone(), PROTOCOL_VERSION), ping);
+        assert_eq!(decompress(ping.clone()).unwrap(), ping);
+        // a frame compressed twice is refused
+        let twice = Message::Compressed(snap::raw::Encoder::new().compress_vec(&compressed).unwrap()).encode();
+        assert!(matches!(decompress(twice), Err(DecodeError::Decompression(_))));
+        assert!(matches!(decompress(Message::Compressed(vec![0xff; 8]).encode()), Err(DecodeError::Decompression(_))));
+    }
+
+    #[test]
+    fn json_messages_round_trip() {
+        assert_eq!(to_json(Message::Ping("1".to_string()).encode()).unwrap(), r#"{"Ping":"1"}"#);
+        assert_eq!(to_json(Message::GetTip.encode()).unwrap(), r#""GetTip""#);
+        assert!(matches!(Message::decode(&from_json(r#"{"Tip":[3,[0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,7]]}"#).unwrap()),
+            Ok(Message::Tip(3, hash)) if hash == H256::from({ let mut raw = [0; 32]; raw[31] = 7; raw })));
+        // frames sent compressed go out as the message they wrap
+        let block = generate_random_block(&H256::default());
+        let frame = Message::Blocks(vec![block; 20]).encode();
+        assert_eq!(from_json(&to_json(compress(frame.clone(), PROTOCOL_VERSION)).unwrap()).unwrap(), frame);
+        assert!(matches!(from_json(r#"{"Pong":3}"#), Err(DecodeError::Json(_))));
+    }
 }
@@ -1,4 +1,33 @@
+pub mod addrbook;
+pub mod attribution;
+pub mod banlist;
+pub mod catchup;
+pub mod chain_proofs;
+pub mod clock;
+pub mod download;
+pub mod dustfilter;
+pub mod encryption;
+pub mod eviction;
+pub mod failure_log;
+pub mod features;
+pub mod gossip;
+pub mod inflight;
+pub mod keepalive;
+pub mod magic;
+pub mod markers;
 pub mod message;
+pub mod orphan;
+pub mod outbound;
 pub mod peer;
+pub mod ratelimit;
+pub mod reconcile;
+pub mod routing;
 pub mod server;
+pub mod shared;
+pub mod sync;
+pub mod topology;
+pub mod trace;
+pub mod verifier;
+pub mod vnet;
+pub mod websocket;
 pub mod worker;
@@ -0,0 +1,327 @@
+use crate::memory;
+use crate::types::block::Block;
+use crate::types::hash::{H256, Hashable};
+
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+
+/// Whom to ask for the missing parent of an orphan block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OrphanStrategy {
+    /// only the peer that sent the orphan
+    SamePeer,
+    /// every connected peer
+    AllPeers,
+}
+
+impl std::str::FromStr for OrphanStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "same-peer" => Ok(OrphanStrategy::SamePeer),
+            "all-peers" => Ok(OrphanStrategy::AllPeers),
+            _ => Err(format!("unknown orphan resolution strategy {}, expected same-peer or all-peers", s)),
+        }
+    }
+}
+
+/// How orphan resolution went for the orphans one peer sent us.
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct PeerOrphanStats {
+    /// orphan blocks received from this peer
+    pub orphans: usize,
+    /// of those, orphans whose missing parent arrived
+    pub resolved: usize,
+    /// missing parents this peer delivered, for orphans from any peer
+    pub delivered: usize,
+    /// orphans from this peer currently waiting in the buffer
+    pub buffered: usize,
+    /// orphans from this peer dropped to stay within the buffer limits
+    pub evicted: usize,
+}
+
+/// Limits of the orphan buffer. Over `max_count` orphans or `max_bytes` the oldest orphans are
+/// dropped; a peer with `max_per_peer` orphans waiting has its own oldest dropped instead, so one
+/// peer cannot push out the orphans of the others.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OrphanLimits {
+    pub max_count: usize,
+    pub max_per_peer: usize,
+    pub max_bytes: Option<usize>,
+}
+
+impl Default for OrphanLimits {
+    fn default() -> Self {
+        OrphanLimits { max_count: 1000, max_per_peer: 200, max_bytes: None }
+    }
+}
+
+// a buffered orphan with the peer that sent it and its approximate size in memory
+struct BufferedOrphan {
+    block: Block,
+    peer: SocketAddr,
+    bytes: usize,
+}
+
+/// Decides how missing ancestors of orphan blocks are requested and keeps per-peer statistics.
+/// Ancestors are walked back one parent at a time; a chain of orphans longer than `max_depth`
+/// is given up on, so a peer cannot make us download an arbitrarily long bogus chain.
+pub struct OrphanResolver {
+    strategy: OrphanStrategy,
+    max_depth: u32,
+    // missing parent hash -> (depth of the orphan chain waiting on it, peer that sent the orphan)
+    waiting: HashMap<H256, (u32, SocketAddr)>,
+    stats: HashMap<SocketAddr, PeerOrphanStats>,
+    abandoned: usize,
+    limits: OrphanLimits,
+    buffer: HashMap<H256, BufferedOrphan>, // orphans waiting for their parent, by their hash
+    children: HashMap<H256, Vec<H256>>, // missing parent hash -> orphans waiting on it
+    arrival: VecDeque<H256>, // buffered orphans, oldest first
+    buffered: usize, // approximate bytes of the buffered orphans
+    evicted: usize,
+}
+
+impl OrphanResolver {
+    pub fn new(strategy: OrphanStrategy, max_depth: u32) -> Self {
+        OrphanResolver {
+            strategy,
+            max_depth,
+            waiting: HashMap::new(),
+            stats: HashMap::new(),
+            abandoned: 0,
+            limits: OrphanLimits::default(),
+            buffer: HashMap::new(),
+            children: HashMap::new(),
+            arrival: VecDeque::new(),
+            buffered: 0,
+            evicted: 0,
+        }
+    }
+
+    pub fn strategy(&self) -> OrphanStrategy {
+        self.strategy
+    }
+
+    /// Record an orphan from `peer` whose `parent` is unknown, returns whether to request the parent
+    pub fn on_orphan(&mut self, peer: SocketAddr, orphan: H256, parent: H256) -> bool {
+        self.stats.entry(peer).or_default().orphans += 1;
+        // an orphan that is itself the missing parent of another orphan extends that chain
+        let depth = match self.waiting.get(&orphan) {
+            Some((depth, _)) => depth + 1,
+            None => 1,
+        };
+        if depth > self.max_depth {
+            self.abandoned += 1;
+            return false;
+        }
+        self.waiting.insert(parent, (depth, peer));
+        true
+    }
+
+    /// Record a block from `peer`, crediting it if an orphan was waiting on this block
+    pub fn on_block(&mut self, peer: SocketAddr, hash: H256) {
+        if let Some((_, origin)) = self.waiting.remove(&hash) {
+            self.stats.entry(origin).or_default().resolved += 1;
+            self.stats.entry(peer).or_default().delivered += 1;
+        }
+    }
+
+    /// Missing parents requested and not received yet
+    pub fn pending(&self) -> Vec<H256> {
+        self.waiting.keys().cloned().collect()
+    }
+
+    pub fn stats(&self) -> &HashMap<SocketAddr, PeerOrphanStats> {
+        &self.stats
+    }
+
+    /// Number of orphan chains given up on for exceeding the depth limit
+    pub fn abandoned(&self) -> usize {
+        self.abandoned
+    }
+
+    pub fn limits(&self) -> OrphanLimits {
+        self.limits
+    }
+
+    pub fn set_limits(&mut self, limits: OrphanLimits) {
+        self.limits = limits;
+        self.enforce_limits();
+    }
+
+    /// Cap the bytes of the buffered orphans
+    pub fn set_max_buffered(&mut self, max_bytes: usize) {
+        self.limits.max_bytes = Some(max_bytes);
+        self.enforce_limits();
+    }
+
+    /// Keep the orphan `block` from `peer` until its parent arrives, returns how many older
+    /// orphans were dropped to make room for it
+    pub fn buffer(&mut self, peer: SocketAddr, block: Block) -> usize {
+        let hash = block.hash();
+        if self.buffer.contains_key(&hash) {
+            return 0;
+        }
+        let mut evicted = 0;
+        // a peer at its quota makes room among its own orphans
+        while self.stats.get(&peer).map_or(0, |stats| stats.buffered) >= self.limits.max_per_peer {
+            match self.arrival.iter().find(|h| self.buffer[*h].peer == peer).copied() {
+                Some(oldest) => {
+                    self.evict(&oldest);
+                    evicted += 1;
+                }
+                None => break,
+            }
+        }
+        if self.limits.max_per_peer == 0 {
+            return evicted;
+        }
+        let bytes = memory::block_size(&block);
+        self.children.entry(block.get_parent()).or_default().push(hash);
+        self.buffer.insert(hash, BufferedOrphan { block, peer, bytes });
+        self.arrival.push_back(hash);
+        self.buffered += bytes;
+        self.stats.entry(peer).or_default().buffered += 1;
+        evicted + self.enforce_limits()
+    }
+
+    /// Take the orphans waiting for `parent`, which just arrived, with the peers that sent them
+    pub fn take_children(&mut self, parent: &H256) -> Vec<(Block, SocketAddr)> {
+        let children = self.children.remove(parent).unwrap_or_default();
+        let blocks: Vec<(Block, SocketAddr)> =
+            children.iter().filter_map(|hash| self.release(hash)).map(|orphan| (orphan.block, orphan.peer)).collect();
+        if !blocks.is_empty() {
+            let buffer = &self.buffer;
+            self.arrival.retain(|hash| buffer.contains_key(hash));
+        }
+        blocks
+    }
+
+    // drop the oldest orphans while over the count or byte limit, returns how many were dropped
+    fn enforce_limits(&mut self) -> usize {
+        let mut evicted = 0;
+        while self.buffer.len() > self.limits.max_count || self.limits.max_bytes.is_some_and(|max| self.buffered > max) {
+            let oldest = match self.arrival.front() {
+                Some(oldest) => *oldest,
+                None => break,
+            };
+            self.evict(&oldest);
+            evicted += 1;
+        }
+        evicted
+    }
+
+    fn evict(&mut self, hash: &H256) {
+        if let Some(orphan) = self.release(hash) {
+            self.arrival.retain(|h| h != hash);
+            let parent = orphan.block.get_parent();
+            if let Some(children) = self.children.get_mut(&parent) {
+                children.retain(|h| h != hash);
+                if children.is_empty() {
+                    self.children.remove(&parent);
+                }
+            }
+            self.stats.entry(orphan.peer).or_default().evicted += 1;
+            self.evicted += 1;
+        }
+    }
+
+    // remove an orphan from the buffer and its accounting, but not from the arrival order
+    fn release(&mut self, hash: &H256) -> Option<BufferedOrphan> {
+        let orphan = self.buffer.remove(hash)?;
+        self.buffered -= orphan.bytes;
+        if let Some(stats) = self.stats.get_mut(&orphan.peer) {
+            stats.buffered -= 1;
+        }
+        Some(orphan)
+    }
+
+    /// Number of buffered orphans
+    pub fn buffered_count(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Approximate bytes of the buffered orphans
+    pub fn buffered(&self) -> usize {
+        self.buffered
+    }
+
+    /// Number of orphans dropped to stay within the limits
+    pub fn evicted(&self) -> usize {
+        self.evicted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::block::TestBlockBuilder;
+
+    #[test]
+    fn resolution_is_credited() {
+        let mut resolver = OrphanResolver::new(OrphanStrategy::AllPeers, 10);
+        let origin = "127.0.0.1:6001".parse().unwrap();
+        let helper = "127.0.0.1:6002".parse().unwrap();
+        assert!(resolver.on_orphan(origin, H256::from([2; 32]), H256::from([1; 32])));
+        resolver.on_block(helper, H256::from([1; 32]));
+        assert_eq!(resolver.stats()[&origin], PeerOrphanStats { orphans: 1, resolved: 1, delivered: 0, ..Default::default() });
+        assert_eq!(resolver.stats()[&helper], PeerOrphanStats { orphans: 0, resolved: 0, delivered: 1, ..Default::default() });
+    }
+
+    #[test]
+    fn walk_back_stops_at_max_depth() {
+        let mut resolver = OrphanResolver::new(OrphanStrategy::SamePeer, 2);
+        let peer = "127.0.0.1:6001".parse().unwrap();
+        // block 3 misses 2, which arrives and misses 1, which arrives and misses 0
+        assert!(resolver.on_orphan(peer, H256::from([3; 32]), H256::from([2; 32])));
+        assert!(resolver.on_orphan(peer, H256::from([2; 32]), H256::from([1; 32])));
+        assert!(!resolver.on_orphan(peer, H256::from([1; 32]), H256::from([0; 32])));
+        assert_eq!(resolver.abandoned(), 1);
+    }
+
+    fn orphans(parent: &H256, count: usize) -> Vec<Block> {
+        (0..count).map(|_| TestBlockBuilder::new(parent).length(2).build()).collect()
+    }
+
+    #[test]
+    fn buffer_evicts_oldest_and_per_peer() {
+        let mut resolver = OrphanResolver::new(OrphanStrategy::SamePeer, 10);
+        resolver.set_limits(OrphanLimits { max_count: 4, max_per_peer: 3, max_bytes: None });
+        let flooder = "127.0.0.1:6001".parse().unwrap();
+        let honest = "127.0.0.1:6002".parse().unwrap();
+        let parent = H256::from([1; 32]);
+        let honest_orphan = orphans(&parent, 1).pop().unwrap();
+        assert_eq!(resolver.buffer(honest, honest_orphan.clone()), 0);
+        let flood = orphans(&parent, 5);
+        let evicted: usize = flood.iter().map(|block| resolver.buffer(flooder, block.clone())).sum();
+        // the flooder only ever pushes out its own orphans
+        assert_eq!(evicted, 2);
+        assert_eq!(resolver.stats()[&flooder].buffered, 3);
+        assert_eq!(resolver.stats()[&flooder].evicted, 2);
+        assert_eq!(resolver.buffered_count(), 4);
+        // resending a buffered orphan changes nothing
+        assert_eq!(resolver.buffer(flooder, flood[4].clone()), 0);
+
+        // the oldest go first once the buffer is full
+        let other_parent = H256::from([2; 32]);
+        let late = orphans(&other_parent, 1).pop().unwrap();
+        assert_eq!(resolver.buffer("127.0.0.1:6003".parse().unwrap(), late.clone()), 1);
+        assert_eq!(resolver.stats()[&honest].evicted, 1);
+
+        let children: Vec<H256> = resolver.take_children(&parent).iter().map(|(b, _)| b.hash()).collect();
+        assert_eq!(children, vec![flood[2].hash(), flood[3].hash(), flood[4].hash()]);
+        assert_eq!(resolver.buffered_count(), 1);
+        assert_eq!(resolver.buffered(), memory::block_size(&late));
+        assert_eq!(resolver.take_children(&other_parent).len(), 1);
+        assert_eq!(resolver.buffered(), 0);
+    }
+
+    #[test]
+    fn strategy_from_str() {
+        assert_eq!("same-peer".parse(), Ok(OrphanStrategy::SamePeer));
+        assert_eq!("all-peers".parse(), Ok(OrphanStrategy::AllPeers));
+        assert!("some-peers".parse::<OrphanStrategy>().is_err());
+    }
+}
@@ -0,0 +1,148 @@
+use super::message::Message;
+
+use smol::channel::{self, Receiver, Sender, TrySendError};
+use smol::Timer;
+use std::time::Duration;
+
+/// Sync messages queued for a peer before their senders wait
+pub const SYNC_CAPACITY: usize = 256;
+/// Gossip messages queued for a peer before the oldest are dropped
+pub const GOSSIP_CAPACITY: usize = 1024;
+/// Longest a sender waits for room in the sync queue of a peer before giving up on it
+pub const SYNC_SEND_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The queue a message to a peer waits in. Gossip is what a peer can do without: it is
+/// written once no sync message is waiting, and dropped when the peer falls too far behind,
+/// as it learns the same objects from its other peers or the next sync. Everything else
+/// answers the peer or keeps its sync going, so a sender waits for room rather than lose it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lane {
+    Sync,
+    Gossip,
+}
+
+impl Lane {
+    pub fn of(msg: &Message) -> Lane {
+        match msg {
+            Message::Inv(_) | Message::Transactions(_) | Message::TxPackage(_) | Message::Addr(_) | Message::Marker(_) => Lane::Gossip,
+            _ => Lane::Sync,
+        }
+    }
+}
+
+/// What became of a queued message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Queued {
+    Sent,
+    /// queued in place of the oldest gossip, which was dropped
+    Evicted,
+    /// the sync queue stayed full for the whole timeout, the message was dropped
+    TimedOut,
+    /// the peer is disconnected
+    Closed,
+}
+
+/// The sending end of the queues of a peer, shared by the clones of its handle.
+#[derive(Clone, Debug)]
+pub struct OutboundSender {
+    sync: Sender<Vec<u8>>,
+    gossip: Sender<Vec<u8>>,
+    // to drop the oldest gossip when the queue is full
+    gossip_oldest: Receiver<Vec<u8>>,
+    timeout: Duration,
+}
+
+/// The receiving end of the queues of a peer, read by its writer.
+pub struct OutboundReceiver {
+    sync: Receiver<Vec<u8>>,
+    gossip: Receiver<Vec<u8>>,
+}
+
+/// Queues holding up to `sync_capacity` sync and `gossip_capacity` gossip messages, senders
+/// waiting up to `timeout` for room in the sync queue
+pub fn channel(sync_capacity: usize, gossip_capacity: usize, timeout: Duration) -> (OutboundSender, OutboundReceiver) {
+    let (sync, sync_receiver) = channel::bounded(sync_capacity);
+    let (gossip, gossip_receiver) = channel::bounded(gossip_capacity);
+    let sender = OutboundSender { sync, gossip, gossip_oldest: gossip_receiver.clone(), timeout };
+    (sender, OutboundReceiver { sync: sync_receiver, gossip: gossip_receiver })
+}
+
+impl OutboundSender {
+    /// Queue the encoded message `buffer` in `lane`, waiting for room if it is a sync message
+    pub fn send(&self, lane: Lane, buffer: Vec<u8>) -> Queued {
+        match lane {
+            Lane::Gossip => match self.gossip.try_send(buffer) {
+                Ok(()) => Queued::Sent,
+                Err(TrySendError::Closed(_)) => Queued::Closed,
+                Err(TrySendError::Full(buffer)) => {
+                    let _ = self.gossip_oldest.try_recv();
+                    // another sender may have taken the room, then this message is the one dropped
+                    let _ = self.gossip.try_send(buffer);
+                    Queued::Evicted
+                }
+            },
+            Lane::Sync => {
+                if self.sync.is_closed() {
+                    return Queued::Closed;
+                }
+                smol::block_on(smol::future::or(
+                    async {
+                        match self.sync.send(buffer).await {
+                            Ok(()) => Queued::Sent,
+                            Err(_) => Queued::Closed,
+                        }
+                    },
+                    async {
+                        Timer::after(self.timeout).await;
+                        Queued::TimedOut
+                    },
+                ))
+            }
+        }
+    }
+}
+
+impl OutboundReceiver {
+    /// The next message to write, sync messages first. None once the peer is dropped.
+    pub async fn next(&self) -> Option<Vec<u8>> {
+        loop {
+            // both queues close once every handle is dropped, what was queued before is still written
+            let closed = self.sync.is_closed();
+            if let Ok(buffer) = self.sync.try_recv() {
+                return Some(buffer);
+            }
+            if let Ok(buffer) = self.gossip.try_recv() {
+                return Some(buffer);
+            }
+            if closed {
+                return None;
+            }
+            if let Ok(buffer) = smol::future::or(self.sync.recv(), self.gossip.recv()).await {
+                return Some(buffer);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::hash::H256;
+
+    #[test]
+    fn gossip_gives_way() {
+        let (sender, receiver) = channel(1, 2, Duration::from_millis(10));
+        for byte in 1..=3 {
+            assert_eq!(sender.send(Lane::Gossip, vec![byte]), if byte < 3 { Queued::Sent } else { Queued::Evicted });
+        }
+        assert_eq!(sender.send(Lane::Sync, vec![10]), Queued::Sent);
+        // a peer that does not read its sync messages holds the sender only so long
+        assert_eq!(sender.send(Lane::Sync, vec![11]), Queued::TimedOut);
+        drop(sender);
+        let written: Vec<Vec<u8>> = std::iter::from_fn(|| smol::block_on(receiver.next())).collect();
+        assert_eq!(written, vec![vec![10], vec![2], vec![3]]);
+
+        assert_eq!(Lane::of(&Message::Inv(vec![])), Lane::Gossip);
+        assert_eq!(Lane::of(&Message::Tip(0, H256::default())), Lane::Sync);
+    }
+}
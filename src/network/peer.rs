@@ -1,16 +1,30 @@
-use super::message::Message;
-use futures::{channel::mpsc, sink::SinkExt};
-use log::trace;
+use super::addrbook::canonical;
+use super::features::PROTOCOL_VERSION;
+use super::message::{self, Message};
+use super::outbound::{self, Lane, OutboundReceiver, OutboundSender, Queued};
+use log::{trace, warn};
 use smol::Async;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 
 pub fn new(
     stream: &Async<std::net::TcpStream>,
-) -> std::io::Result<(mpsc::UnboundedReceiver<Vec<u8>>, Handle)> {
-    let (write_sender, write_receiver) = mpsc::unbounded();
-    let addr = stream.get_ref().peer_addr()?;
+) -> std::io::Result<(OutboundReceiver, Handle)> {
+    with_socket(canonical(stream.get_ref().peer_addr()?), stream.get_ref())
+}
+
+/// Queues and handle of the peer at `addr` connected through `socket`, which carries another
+/// framing than ours, such as WebSocket
+pub fn with_socket(addr: SocketAddr, socket: &std::net::TcpStream) -> std::io::Result<(OutboundReceiver, Handle)> {
+    let (write_sender, write_receiver) = outbound::channel(outbound::SYNC_CAPACITY, outbound::GOSSIP_CAPACITY, outbound::SYNC_SEND_TIMEOUT);
     let handle = Handle {
         write_queue: write_sender,
         addr,
+        socket: Some(Arc::new(socket.try_clone()?)),
+        version: Arc::new(AtomicU32::new(PROTOCOL_VERSION)),
+        compression: Arc::new(AtomicBool::new(false)),
     };
     Ok((write_receiver, handle))
 }
@@ -21,37 +35,221 @@ pub enum Direction {
     Outgoing,
 }
 
+/// Messages of a peer kept until its `Version` is handled, more are dropped.
+pub const MAX_HELD: usize = 32;
+
+/// Where a peer is in the protocol. A connection starts out handshaking, syncs once its
+/// `Version` is handled and is ready once it told us its tip.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum State {
+    /// waiting for the peer's `Version`
+    Handshaking,
+    /// a worker is handling the peer's `Version`
+    Negotiating,
+    /// waiting for the peer's `Tip`, only handshake and sync traffic is accepted
+    Syncing,
+    /// every message is accepted, another `Version` restarts the handshake
+    Ready,
+    /// the connection is closing, messages still queued from the peer are dropped
+    Draining,
+}
+
+impl State {
+    /// Whether `msg` is in order for a peer in this state
+    pub fn admits(&self, msg: &Message) -> bool {
+        match (self, msg) {
+            (State::Draining, _) => false,
+            (_, Message::Ping(_)) | (_, Message::Pong(_)) | (_, Message::Clock(..)) => true,
+            (State::Handshaking, Message::Version(..)) => true,
+            (State::Ready, Message::Version(..)) => true,
+            (_, Message::Version(..)) => false,
+            (State::Handshaking, _) | (State::Negotiating, _) => false,
+            (State::Ready, _) => true,
+            // relayed objects and announcements wait until the peer has synced
+            (State::Syncing, Message::Inv(_))
+            | (State::Syncing, Message::Transactions(_))
+            | (State::Syncing, Message::TxPackage(_))
+            | (State::Syncing, Message::ReconcileRequest(_))
+            | (State::Syncing, Message::ReconcileSketch(_))
+            | (State::Syncing, Message::ReconcileDiff(_))
+            | (State::Syncing, Message::ReconcileFailed) => false,
+            (State::Syncing, _) => true,
+        }
+    }
+}
+
+/// Protocol state of every peer, shared by the server, which sees connections close, and the
+/// workers, which see the messages. Several workers handle the messages of a peer, so one may
+/// take a message the peer sent after its `Version` while another still handles the `Version`;
+/// such messages are held and handed back once the `Version` is handled.
+#[derive(Debug, Default)]
+pub struct PeerStates {
+    states: HashMap<SocketAddr, State>,
+    held: HashMap<SocketAddr, Vec<Message>>,
+}
+
+impl PeerStates {
+    pub fn new() -> Self {
+        PeerStates { states: HashMap::new(), held: HashMap::new() }
+    }
+
+    pub fn state(&self, peer: &SocketAddr) -> State {
+        self.states.get(peer).copied().unwrap_or(State::Handshaking)
+    }
+
+    /// Check `msg` against the state of `peer` and apply the transition it causes. Returns the
+    /// new state if the message is in order, or the unchanged state if it must be dropped.
+    pub fn on_message(&mut self, peer: SocketAddr, msg: &Message) -> Result<State, State> {
+        let state = self.state(&peer);
+        if !state.admits(msg) {
+            return Err(state);
+        }
+        let next = match (state, msg) {
+            (State::Handshaking, Message::Version(..)) | (State::Ready, Message::Version(..)) => State::Negotiating,
+            (State::Syncing, Message::Tip(..)) => State::Ready,
+            _ => state,
+        };
+        self.states.insert(peer, next);
+        Ok(next)
+    }
+
+    /// Keep `msg`, which `peer` sent while its `Version` is missing or being handled, for
+    /// `negotiated` to hand back. Returns whether it was kept, messages in other states and
+    /// those over `MAX_HELD` are not.
+    pub fn hold(&mut self, peer: SocketAddr, msg: &Message) -> bool {
+        if !matches!(self.state(&peer), State::Handshaking | State::Negotiating) || matches!(msg, Message::Version(..)) {
+            return false;
+        }
+        let held = self.held.entry(peer).or_default();
+        if held.len() >= MAX_HELD {
+            return false;
+        }
+        held.push(msg.clone());
+        true
+    }
+
+    /// The `Version` of `peer` is handled, it syncs from now on. Returns the messages held for it,
+    /// in the order they arrived.
+    pub fn negotiated(&mut self, peer: SocketAddr) -> Vec<Message> {
+        if self.state(&peer) == State::Negotiating {
+            self.states.insert(peer, State::Syncing);
+        }
+        self.held.remove(&peer).unwrap_or_default()
+    }
+
+    /// Peers that completed the handshake and told us their tip
+    pub fn ready(&self) -> Vec<SocketAddr> {
+        self.states.iter().filter(|(_, state)| **state == State::Ready).map(|(peer, _)| *peer).collect()
+    }
+
+    /// Peers whose connection is not closing, with their state
+    pub fn connected(&self) -> Vec<(SocketAddr, State)> {
+        self.states.iter().filter(|(_, state)| **state != State::Draining).map(|(peer, state)| (*peer, *state)).collect()
+    }
+
+    /// Start tracking a new connection to `peer`
+    pub fn connect(&mut self, peer: SocketAddr) {
+        self.states.insert(peer, State::Handshaking);
+        self.held.remove(&peer);
+    }
+
+    /// Mark the connection to `peer` as closing
+    pub fn drain(&mut self, peer: SocketAddr) {
+        self.states.insert(peer, State::Draining);
+        self.held.remove(&peer);
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Handle {
     addr: std::net::SocketAddr,
-    write_queue: mpsc::UnboundedSender<Vec<u8>>,
+    write_queue: OutboundSender,
+    socket: Option<Arc<std::net::TcpStream>>, // to close the connection, None if there is none
+    version: Arc<AtomicU32>, // protocol version messages are written in, ours until the peer's Version
+    compression: Arc<AtomicBool>, // whether large frames are compressed, once both sides negotiated it
 }
 
 #[cfg(any(test,test_utilities))]
 pub struct TestReceiver {
-    r: mpsc::UnboundedReceiver<Vec<u8>>
+    r: OutboundReceiver,
 }
 
 impl Handle {
+    /// Queue `msg` for the peer. Gossip is dropped if the peer is too far behind, other
+    /// messages wait for room, and a peer that leaves them waiting too long is disconnected.
     pub fn write(&mut self, msg: Message) {
-        let buffer = bincode::serialize(&msg).unwrap();
-        smol::block_on(async move {
-            if self.write_queue.send(buffer).await.is_err() {
-                trace!("Trying to send to disconnected peer");
+        let version = self.protocol_version();
+        let name = msg.name();
+        let msg = match msg.for_version(version) {
+            Some(msg) => msg,
+            None => {
+                trace!("Not sending {} to peer {}, protocol version {} does not know it", name, self.addr, version);
+                return;
+            }
+        };
+        let lane = Lane::of(&msg);
+        let buffer = msg.encode_for(version);
+        let buffer = if self.compression.load(Ordering::Relaxed) { message::compress(buffer, version) } else { buffer };
+        match self.write_queue.send(lane, buffer) {
+            Queued::Sent => (),
+            Queued::Evicted => trace!("Dropping the oldest gossip queued for slow peer {}", self.addr),
+            Queued::TimedOut => {
+                warn!("Disconnecting peer {}, it did not read its messages for {:?}", self.addr, outbound::SYNC_SEND_TIMEOUT);
+                self.disconnect();
             }
-        });
+            Queued::Closed => trace!("Trying to send to disconnected peer"),
+        }
+    }
+
+    /// Write messages in protocol `version` from now on, the one negotiated with the peer
+    pub fn set_protocol_version(&self, version: u32) {
+        self.version.store(version, Ordering::Relaxed);
+    }
+
+    pub fn protocol_version(&self) -> u32 {
+        self.version.load(Ordering::Relaxed)
+    }
+
+    /// Compress the large frames written from now on, once compression was negotiated with the peer
+    pub fn set_compression(&self, compression: bool) {
+        self.compression.store(compression, Ordering::Relaxed);
     }
 
     pub fn addr(&self) -> &std::net::SocketAddr {
         &self.addr
     }
 
+    /// Close the connection, the server then drops the peer as if it disconnected
+    pub fn disconnect(&self) {
+        if let Some(socket) = &self.socket {
+            if let Err(e) = socket.shutdown(std::net::Shutdown::Both) {
+                trace!("Error closing the connection to {}: {}", self.addr, e);
+            }
+        }
+    }
+
+    /// Handle of a peer we are not connected to, whatever is written to it is dropped. Used to
+    /// replay the messages of a captured trace.
+    pub fn detached(addr: std::net::SocketAddr) -> Handle {
+        let (write_queue, _) = outbound::channel(1, 1, outbound::SYNC_SEND_TIMEOUT);
+        Handle {
+            addr,
+            write_queue,
+            socket: None,
+            version: Arc::new(AtomicU32::new(PROTOCOL_VERSION)),
+            compression: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
     #[cfg(any(test,test_utilities))]
     pub fn test_handle() -> (Handle, TestReceiver) {
-        let (s,r) = mpsc::unbounded();
+        let (s,r) = outbound::channel(outbound::SYNC_CAPACITY, outbound::GOSSIP_CAPACITY, outbound::SYNC_SEND_TIMEOUT);
         (Handle {
             addr: std::net::SocketAddr::new(std::net::IpAddr::V4(std::net::Ipv4Addr::new(127, 0, 0, 1)), 12321),
             write_queue: s,
+            socket: None,
+            version: Arc::new(AtomicU32::new(PROTOCOL_VERSION)),
+            compression: Arc::new(AtomicBool::new(false)),
         },
         TestReceiver {
             r
@@ -62,8 +260,82 @@ impl Handle {
 #[cfg(any(test,test_utilities))]
 impl TestReceiver {
     pub fn recv(&mut self) -> Message {
-        let bytes = smol::block_on(futures::stream::StreamExt::next(&mut self.r)).unwrap();
-        let msg: Message = bincode::deserialize(&bytes).unwrap();
-        msg
+        let bytes = smol::block_on(self.r.next()).unwrap();
+        let bytes = message::decompress(bytes).unwrap();
+        Message::decode(&bytes).unwrap()
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::features::{PROTOCOL_VERSION, SUPPORTED_FEATURES};
+    use crate::types::hash::H256;
+
+    #[test]
+    fn handshake_then_sync() {
+        let mut states = PeerStates::new();
+        let peer = "127.0.0.1:6001".parse().unwrap();
+        let version = Message::Version(PROTOCOL_VERSION, SUPPORTED_FEATURES, 0, H256::default());
+        assert_eq!(states.on_message(peer, &Message::Blocks(vec![])), Err(State::Handshaking));
+        assert_eq!(states.on_message(peer, &Message::Verack), Err(State::Handshaking));
+        assert_eq!(states.on_message(peer, &version), Ok(State::Negotiating));
+        assert_eq!(states.on_message(peer, &Message::Verack), Err(State::Negotiating));
+        assert!(states.negotiated(peer).is_empty());
+        assert_eq!(states.on_message(peer, &Message::Verack), Ok(State::Syncing));
+        assert_eq!(states.on_message(peer, &Message::Inv(vec![])), Err(State::Syncing));
+        assert_eq!(states.on_message(peer, &Message::Blocks(vec![])), Ok(State::Syncing));
+        assert_eq!(states.on_message(peer, &Message::Tip(0, H256::default())), Ok(State::Ready));
+        assert_eq!(states.on_message(peer, &Message::Inv(vec![])), Ok(State::Ready));
+        // a peer resyncing with us handshakes again
+        assert_eq!(states.on_message(peer, &version), Ok(State::Negotiating));
+        assert!(states.negotiated(peer).is_empty());
+        assert_eq!(states.on_message(peer, &version), Err(State::Syncing));
+        assert_eq!(states.on_message(peer, &Message::Tip(0, H256::default())), Ok(State::Ready));
+    }
+
+    #[test]
+    fn messages_before_the_version_is_handled_are_held() {
+        let mut states = PeerStates::new();
+        let peer = "127.0.0.1:6001".parse().unwrap();
+        let version = Message::Version(PROTOCOL_VERSION, SUPPORTED_FEATURES, 0, H256::default());
+        // another worker took the tip before the version
+        assert!(states.hold(peer, &Message::GetTip));
+        assert_eq!(states.on_message(peer, &version), Ok(State::Negotiating));
+        assert!(!states.hold(peer, &version));
+        assert!(states.hold(peer, &Message::Tip(1, H256::default())));
+        let held = states.negotiated(peer);
+        assert!(matches!(held.as_slice(), [Message::GetTip, Message::Tip(1, _)]));
+        assert_eq!(states.state(&peer), State::Syncing);
+        assert!(!states.hold(peer, &Message::GetTip));
+        // a peer flooding before its version gets only so much kept
+        let other = "127.0.0.1:6002".parse().unwrap();
+        assert_eq!((0..MAX_HELD + 1).filter(|_| states.hold(other, &Message::GetTip)).count(), MAX_HELD);
+        states.drain(other);
+        states.connect(other);
+        assert!(states.negotiated(other).is_empty());
+    }
+
+    #[test]
+    fn writes_follow_the_negotiated_version() {
+        use super::super::failure_log::RejectCode;
+        use super::super::message::InvItem;
+        let (mut handle, mut receiver) = Handle::test_handle();
+        handle.clone().set_protocol_version(2);
+        assert_eq!(handle.protocol_version(), 2);
+        handle.write(Message::Reject(InvItem::Block(H256::default()), RejectCode::BadPow));
+        handle.write(Message::Tip(1, H256::default()));
+        assert!(matches!(receiver.recv(), Message::Tip(1, _)));
+    }
+
+    #[test]
+    fn draining_drops_everything() {
+        let mut states = PeerStates::new();
+        let peer = "127.0.0.1:6001".parse().unwrap();
+        states.drain(peer);
+        assert_eq!(states.on_message(peer, &Message::Ping(String::new())), Err(State::Draining));
+        assert_eq!(states.state(&peer), State::Draining);
+        states.connect(peer);
+        assert_eq!(states.state(&peer), State::Handshaking);
+    }
+}
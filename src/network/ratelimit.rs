@@ -0,0 +1,203 @@
+use super::message::NAMES;
+
+use serde::Serialize;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Instant;
+
+/// Token bucket of one message type: a peer may send `burst` messages at once, and `rate` more
+/// per second after that.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    pub rate: f64,
+    pub burst: f64,
+}
+
+impl RateLimit {
+    pub fn new(rate: f64, burst: f64) -> Self {
+        RateLimit { rate, burst }
+    }
+}
+
+// tokens left in the bucket of a peer and message type, as of `updated`
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Limits the messages each peer may send us, per message type, so a single peer flooding us
+/// cannot fill the worker channel and starve the others. Messages over the limit are dropped
+/// before they reach the workers. Types without a limit, e.g. `Blocks` needed to sync, are
+/// never dropped.
+pub struct RateLimiter {
+    limits: HashMap<String, RateLimit>,
+    buckets: HashMap<(SocketAddr, &'static str), Bucket>,
+    dropped: HashMap<SocketAddr, usize>,
+}
+
+/// Limits and dropped messages, for the API.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct RateLimitReport {
+    pub limits: HashMap<String, RateLimit>,
+    pub dropped: HashMap<String, usize>,
+}
+
+impl RateLimiter {
+    /// A limiter with no limits
+    pub fn new() -> Self {
+        RateLimiter { limits: HashMap::new(), buckets: HashMap::new(), dropped: HashMap::new() }
+    }
+
+    /// A limiter with limits well above what an honest peer sends, on the requests and
+    /// announcements that are cheap to send and costly to answer
+    pub fn with_default_limits() -> Self {
+        let mut limiter = Self::new();
+        limiter.set_limit("Ping", Some(RateLimit::new(2.0, 10.0)));
+        limiter.set_limit("GetTip", Some(RateLimit::new(2.0, 10.0)));
+        limiter.set_limit("Inv", Some(RateLimit::new(50.0, 200.0)));
+        limiter.set_limit("GetData", Some(RateLimit::new(50.0, 200.0)));
+        limiter.set_limit("Transactions", Some(RateLimit::new(100.0, 500.0)));
+        limiter.set_limit("GetStateProof", Some(RateLimit::new(20.0, 50.0)));
+        limiter.set_limit("ReconcileRequest", Some(RateLimit::new(2.0, 10.0)));
+        limiter.set_limit("GetAddr", Some(RateLimit::new(0.1, 2.0)));
+        limiter.set_limit("Addr", Some(RateLimit::new(1.0, 5.0)));
+        limiter.set_limit("Marker", Some(RateLimit::new(1.0, 10.0)));
+        limiter.set_limit("GetBlocksAfter", Some(RateLimit::new(5.0, 20.0)));
+        limiter.set_limit("NewBlockHashes", Some(RateLimit::new(50.0, 200.0)));
+        limiter.set_limit("GetBlocks", Some(RateLimit::new(50.0, 200.0)));
+        limiter.set_limit("MoreBlocks", Some(RateLimit::new(5.0, 20.0)));
+        limiter.set_limit("GetMempool", Some(RateLimit::new(0.1, 2.0)));
+        limiter.set_limit("DustFilter", Some(RateLimit::new(0.1, 2.0)));
+        limiter.set_limit("GetLinks", Some(RateLimit::new(0.2, 5.0)));
+        limiter.set_limit("Links", Some(RateLimit::new(0.2, 5.0)));
+        limiter.set_limit("GetChainProof", Some(RateLimit::new(0.5, 5.0)));
+        limiter.set_limit("ChainProof", Some(RateLimit::new(0.5, 5.0)));
+        limiter.set_limit("Reject", Some(RateLimit::new(5.0, 20.0)));
+        limiter.set_limit("Unknown", Some(RateLimit::new(10.0, 50.0)));
+        limiter
+    }
+
+    /// Limit the messages of type `message`, as named by `Message::name`, or lift its limit
+    pub fn set_limit(&mut self, message: &str, limit: Option<RateLimit>) {
+        match limit {
+            Some(limit) => self.limits.insert(message.to_string(), limit),
+            None => self.limits.remove(message),
+        };
+        self.buckets.retain(|(_, name), _| *name != message);
+    }
+
+    /// Whether `peer` may send a message of type `message` at `now`, takes a token if so
+    pub fn admit(&mut self, peer: SocketAddr, message: &'static str, now: Instant) -> bool {
+        let limit = match self.limits.get(message) {
+            Some(limit) => *limit,
+            None => return true,
+        };
+        let bucket = self.buckets.entry((peer, message)).or_insert(Bucket { tokens: limit.burst, updated: now });
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * limit.rate).min(limit.burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            *self.dropped.entry(peer).or_default() += 1;
+            false
+        }
+    }
+
+    /// Drop the buckets of a disconnected peer, it starts with full buckets if it reconnects
+    pub fn forget(&mut self, peer: &SocketAddr) {
+        self.buckets.retain(|(addr, _), _| addr != peer);
+    }
+
+    /// Number of messages dropped from each peer so far
+    pub fn dropped(&self) -> &HashMap<SocketAddr, usize> {
+        &self.dropped
+    }
+
+    pub fn report(&self) -> RateLimitReport {
+        RateLimitReport {
+            limits: self.limits.clone(),
+            dropped: self.dropped.iter().map(|(peer, count)| (peer.to_string(), *count)).collect(),
+        }
+    }
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Parse a limit given on the command line as `<message>=<rate>[/<burst>]`, or `<message>=none`
+/// to lift the limit of that message type. The burst defaults to the rate.
+pub fn parse_limit(spec: &str) -> Result<(String, Option<RateLimit>), String> {
+    let (message, limit) = spec.split_once('=').ok_or_else(|| format!("expected <message>=<rate>[/<burst>], got {}", spec))?;
+    if !NAMES.contains(&message) {
+        return Err(format!("unknown message type {}, expected one of {}", message, NAMES.join(", ")));
+    }
+    Ok((message.to_string(), parse_rate(limit)?))
+}
+
+/// Parse a limit given as `<rate>[/<burst>]`, or none for no limit. The burst defaults to the rate.
+pub fn parse_rate(limit: &str) -> Result<Option<RateLimit>, String> {
+    if limit == "none" {
+        return Ok(None);
+    }
+    let (rate, burst) = match limit.split_once('/') {
+        Some((rate, burst)) => (rate, Some(burst)),
+        None => (limit, None),
+    };
+    let rate = rate.parse::<f64>().map_err(|e| format!("error parsing rate {}: {}", rate, e))?;
+    let burst = match burst {
+        Some(burst) => burst.parse::<f64>().map_err(|e| format!("error parsing burst {}: {}", burst, e))?,
+        None => rate,
+    };
+    if !(rate >= 0.0 && burst >= 1.0) {
+        return Err(format!("rate must not be negative and burst must be at least 1 in {}", limit));
+    }
+    Ok(Some(RateLimit::new(rate, burst)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn bucket_refills_at_rate() {
+        let mut limiter = RateLimiter::new();
+        limiter.set_limit("Inv", Some(RateLimit::new(10.0, 3.0)));
+        let flooder = "127.0.0.1:6001".parse().unwrap();
+        let other = "127.0.0.1:6002".parse().unwrap();
+        let start = Instant::now();
+        for _ in 0..3 {
+            assert!(limiter.admit(flooder, "Inv", start));
+        }
+        assert!(!limiter.admit(flooder, "Inv", start));
+        // other peers and message types are not affected
+        assert!(limiter.admit(other, "Inv", start));
+        assert!(limiter.admit(flooder, "Blocks", start));
+        // a token every 100 ms
+        assert!(limiter.admit(flooder, "Inv", start + Duration::from_millis(100)));
+        assert!(!limiter.admit(flooder, "Inv", start + Duration::from_millis(150)));
+        // refilled up to the burst only
+        let later = start + Duration::from_secs(10);
+        assert_eq!((0..5).filter(|_| limiter.admit(flooder, "Inv", later)).count(), 3);
+        assert_eq!(limiter.dropped()[&flooder], 4);
+
+        limiter.set_limit("Inv", None);
+        assert!(limiter.admit(flooder, "Inv", later));
+    }
+
+    #[test]
+    fn parse_limits() {
+        assert_eq!(parse_limit("Inv=10/20"), Ok(("Inv".to_string(), Some(RateLimit::new(10.0, 20.0)))));
+        assert_eq!(parse_limit("GetTip=5"), Ok(("GetTip".to_string(), Some(RateLimit::new(5.0, 5.0)))));
+        assert_eq!(parse_limit("Ping=none"), Ok(("Ping".to_string(), None)));
+        assert!(parse_limit("Inv").is_err());
+        assert!(parse_limit("NewTransactionHashes=10").is_err());
+        assert!(parse_limit("Inv=fast").is_err());
+        assert!(parse_limit("Inv=10/0").is_err());
+    }
+}
@@ -0,0 +1,467 @@
+use super::message::{InvItem, Message};
+use super::server::Handle as ServerHandle;
+use crate::types::hash::H256;
+
+use log::{debug, info};
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+// cells each id is added to, one in each third of the sketch
+const HASHES: usize = 3;
+// cells per expected difference, enough for peeling to succeed with high probability
+const CELLS_PER_DIFFERENCE: usize = 2;
+/// Most differences a sketch is made for, whatever set sizes the peers claim; larger
+/// differences fail to decode and fall back to flooding
+pub const MAX_CAPACITY: usize = 1000;
+
+/// Short id of a transaction in sketches, the first 8 bytes of its hash
+pub fn short_id(hash: &H256) -> u64 {
+    u64::from_be_bytes(hash.as_ref()[..8].try_into().unwrap())
+}
+
+fn mix(id: u64, seed: u64) -> u64 {
+    // splitmix64 finalizer
+    let mut z = id.wrapping_add(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15));
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Cell {
+    count: i64,
+    id_sum: u64,
+    check_sum: u64,
+}
+
+impl Cell {
+    fn is_empty(&self) -> bool {
+        self.count == 0 && self.id_sum == 0 && self.check_sum == 0
+    }
+
+    // holds a single id, added (1) or subtracted (-1)
+    fn is_pure(&self) -> bool {
+        (self.count == 1 || self.count == -1) && self.check_sum == mix(self.id_sum, HASHES as u64)
+    }
+}
+
+// cells of a sketch made for `capacity` differences
+fn cells_for(capacity: usize) -> usize {
+    (capacity * CELLS_PER_DIFFERENCE).div_ceil(HASHES).max(1) * HASHES
+}
+
+/// Invertible Bloom lookup table of short ids. Subtracting the sketch of another set leaves a
+/// sketch of the symmetric difference, which decodes as long as the difference is not much
+/// larger than the capacity the sketches were made with, whatever the size of the sets.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Sketch {
+    cells: Vec<Cell>,
+}
+
+impl Sketch {
+    /// Create an empty sketch able to decode about `capacity` differences
+    pub fn new(capacity: usize) -> Self {
+        Sketch { cells: vec![Cell::default(); cells_for(capacity.min(MAX_CAPACITY))] }
+    }
+
+    /// Whether a peer could have made the sketch with `Sketch::new`: some cells, in equal
+    /// parts for each hash, no more than `MAX_CAPACITY` needs
+    pub fn is_well_formed(&self) -> bool {
+        !self.cells.is_empty() && self.cells.len().is_multiple_of(HASHES) && self.cells.len() <= cells_for(MAX_CAPACITY)
+    }
+
+    // an empty sketch that can be subtracted from `other`
+    fn empty_like(other: &Sketch) -> Self {
+        Sketch { cells: vec![Cell::default(); other.cells.len()] }
+    }
+
+    pub fn insert(&mut self, id: u64) {
+        self.update(id, 1);
+    }
+
+    // index of the cell `id` is added to for hash `k`
+    fn position(&self, id: u64, k: usize) -> usize {
+        let per_hash = self.cells.len() / HASHES;
+        k * per_hash + (mix(id, k as u64) % per_hash as u64) as usize
+    }
+
+    fn update(&mut self, id: u64, count: i64) {
+        let check = mix(id, HASHES as u64);
+        for k in 0..HASHES {
+            let position = self.position(id, k);
+            let cell = &mut self.cells[position];
+            // the counts of a peer's sketch are whatever it sent
+            cell.count = cell.count.wrapping_add(count);
+            cell.id_sum ^= id;
+            cell.check_sum ^= check;
+        }
+    }
+
+    /// Sketch of the ids in `self` but not in `other` (count 1) and in `other` but not in
+    /// `self` (count -1). None if the sketches are of different sizes.
+    pub fn subtract(&self, other: &Sketch) -> Option<Sketch> {
+        if self.cells.len() != other.cells.len() {
+            return None;
+        }
+        let cells = self
+            .cells
+            .iter()
+            .zip(other.cells.iter())
+            .map(|(a, b)| Cell {
+                count: a.count.wrapping_sub(b.count),
+                id_sum: a.id_sum ^ b.id_sum,
+                check_sum: a.check_sum ^ b.check_sum,
+            })
+            .collect();
+        Some(Sketch { cells })
+    }
+
+    /// Recover the ids of a difference sketch as (only in the first set, only in the second),
+    /// None if there are too many to decode. Each peeled id empties a cell, so a sketch decodes
+    /// in at most as many steps as it has cells.
+    pub fn decode(mut self) -> Option<(Vec<u64>, Vec<u64>)> {
+        if !self.is_well_formed() {
+            return None;
+        }
+        let mut ours = Vec::new();
+        let mut theirs = Vec::new();
+        for _ in 0..self.cells.len() {
+            // a pure cell must also be one its id is added to, or peeling it corrupts others
+            let pure = (0..self.cells.len()).find(|&i| {
+                let cell = &self.cells[i];
+                cell.is_pure() && self.position(cell.id_sum, i / (self.cells.len() / HASHES)) == i
+            });
+            let pure = match pure {
+                Some(i) => self.cells[i],
+                None => break,
+            };
+            if pure.count == 1 {
+                ours.push(pure.id_sum);
+            } else {
+                theirs.push(pure.id_sum);
+            }
+            self.update(pure.id_sum, -pure.count);
+        }
+        if self.cells.iter().all(|cell| cell.is_empty()) {
+            Some((ours, theirs))
+        } else {
+            None
+        }
+    }
+}
+
+// transaction hashes by short id
+type Set = HashMap<u64, H256>;
+
+#[derive(Default)]
+struct PeerSet {
+    // we made the connection, so we start the reconciliations with this peer
+    initiator: bool,
+    // we asked the peer for a sketch and it has not answered yet
+    awaiting_sketch: bool,
+    // both sides announced the feature, transactions are reconciled instead of flooded
+    enabled: bool,
+    // transactions we would have announced to the peer since the last reconciliation
+    pending: Set,
+    // our pending set as of the sketch we sent, until the peer tells us what it lacks
+    sketched: Set,
+}
+
+/// Result of a reconciliation we started.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Outcome {
+    /// announce `announce` to the peer and ask it for the transactions with the `request` short ids
+    Reconciled { announce: Vec<H256>, request: Vec<u64> },
+    /// the difference was too large to decode, announce `announce`, our whole set, by flooding
+    Failed { announce: Vec<H256> },
+}
+
+/// Transaction set reconciliation in the style of Erlay. Instead of announcing every transaction
+/// to a peer supporting it, we collect what we would have announced; periodically the side that
+/// made the connection asks the other for a sketch of its collected set, subtracts the sketch of
+/// its own and so learns which transactions only one side has, at a cost proportional to the
+/// difference rather than to the number of transactions. Peers without the feature keep
+/// getting every announcement.
+pub struct Reconciler {
+    peers: HashMap<SocketAddr, PeerSet>,
+    active: bool,
+}
+
+impl Reconciler {
+    pub fn new() -> Self {
+        Reconciler { peers: HashMap::new(), active: true }
+    }
+
+    /// A reconciler that reconciles with no peer, so every transaction is flooded as before
+    pub fn disabled() -> Self {
+        Reconciler { peers: HashMap::new(), active: false }
+    }
+
+    /// Whether we offer reconciliation to peers at all
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    /// Record a new connection, `outgoing` if we made it
+    pub fn connect(&mut self, peer: SocketAddr, outgoing: bool) {
+        self.peers.insert(peer, PeerSet { initiator: outgoing, ..Default::default() });
+    }
+
+    /// Reconcile with `peer` from now on, once both sides announced the feature
+    pub fn enable(&mut self, peer: SocketAddr) {
+        if !self.active {
+            return;
+        }
+        if let Some(set) = self.peers.get_mut(&peer) {
+            set.enabled = true;
+        }
+    }
+
+    pub fn forget(&mut self, peer: &SocketAddr) {
+        self.peers.remove(peer);
+    }
+
+    /// Keep the items to announce to `peer` by flooding; the transactions are held for the next
+    /// reconciliation instead if the peer reconciles.
+    pub fn hold(&mut self, peer: SocketAddr, items: Vec<InvItem>) -> Vec<InvItem> {
+        let set = match self.peers.get_mut(&peer) {
+            Some(set) if set.enabled => set,
+            _ => return items,
+        };
+        items
+            .into_iter()
+            .filter(|item| match item {
+                InvItem::Block(_) => true,
+                InvItem::Transaction(hash) => {
+                    set.pending.insert(short_id(hash), *hash);
+                    false
+                }
+            })
+            .collect()
+    }
+
+    /// Peers we start a reconciliation with now, and the size of our set for each
+    pub fn initiations(&mut self) -> Vec<(SocketAddr, u32)> {
+        self.peers
+            .iter_mut()
+            .filter(|(_, set)| set.enabled && set.initiator)
+            .map(|(peer, set)| {
+                set.awaiting_sketch = true;
+                (*peer, set.pending.len() as u32)
+            })
+            .collect()
+    }
+
+    // the set of `peer` if we reconcile with it
+    fn enabled(&mut self, peer: &SocketAddr) -> Option<&mut PeerSet> {
+        self.peers.get_mut(peer).filter(|set| set.enabled)
+    }
+
+    /// Answer a reconciliation request of `peer`, whose set has `their_size` transactions, with
+    /// a sketch of our set. None if we do not reconcile with the peer.
+    pub fn sketch(&mut self, peer: SocketAddr, their_size: u32) -> Option<Sketch> {
+        let set = self.enabled(&peer)?;
+        // a previous request was never followed up, its transactions are still to be reconciled
+        let sketched: Vec<(u64, H256)> = set.sketched.drain().collect();
+        set.pending.extend(sketched);
+        let ours = set.pending.len();
+        let theirs = (their_size as usize).min(MAX_CAPACITY);
+        // the sets mostly overlap, the difference is estimated from their sizes
+        let capacity = ours.max(theirs) - ours.min(theirs) + ours.min(theirs) / 4 + 1;
+        let mut sketch = Sketch::new(capacity);
+        for id in set.pending.keys() {
+            sketch.insert(*id);
+        }
+        set.sketched = std::mem::take(&mut set.pending);
+        Some(sketch)
+    }
+
+    /// Compare the sketch `peer` answered our request with to our set, which is then cleared.
+    /// None if we did not ask the peer for a sketch or it is not one `Sketch::new` makes.
+    pub fn reconcile(&mut self, peer: SocketAddr, theirs: &Sketch) -> Option<Outcome> {
+        let set = self.enabled(&peer).filter(|set| set.awaiting_sketch)?;
+        if !theirs.is_well_formed() {
+            return None;
+        }
+        set.awaiting_sketch = false;
+        let pending = std::mem::take(&mut set.pending);
+        let mut ours = Sketch::empty_like(theirs);
+        for id in pending.keys() {
+            ours.insert(*id);
+        }
+        let decoded = ours.subtract(theirs).and_then(|difference| difference.decode());
+        Some(match decoded {
+            Some((only_ours, only_theirs)) => Outcome::Reconciled {
+                announce: only_ours.iter().filter_map(|id| pending.get(id)).cloned().collect(),
+                request: only_theirs,
+            },
+            None => Outcome::Failed { announce: pending.into_values().collect() },
+        })
+    }
+
+    /// The transactions `peer` asked for after reconciling with our sketch; the rest it has.
+    /// None if we do not reconcile with the peer.
+    pub fn requested(&mut self, peer: SocketAddr, ids: &[u64]) -> Option<Vec<H256>> {
+        let set = self.enabled(&peer)?;
+        let sketched = std::mem::take(&mut set.sketched);
+        Some(ids.iter().filter_map(|id| sketched.get(id)).cloned().collect())
+    }
+
+    /// `peer` could not decode our sketch, every transaction in it has to be announced. None if
+    /// we do not reconcile with the peer.
+    pub fn failed(&mut self, peer: SocketAddr) -> Option<Vec<H256>> {
+        let set = self.enabled(&peer)?;
+        Some(std::mem::take(&mut set.sketched).into_values().collect())
+    }
+}
+
+impl Default for Reconciler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Every `interval`, ask each peer we reconcile with and connected to for a sketch
+pub fn start_initiator(interval: Duration, server: &ServerHandle, reconciler: &Arc<Mutex<Reconciler>>) {
+    let server = server.clone();
+    let reconciler = Arc::clone(reconciler);
+    thread::Builder::new()
+        .name("reconcile-initiator".to_string())
+        .spawn(move || loop {
+            thread::sleep(interval);
+            let initiations = {reconciler.lock().unwrap().initiations()};
+            for (peer, size) in initiations {
+                debug!("Requesting reconciliation with peer {}, {} transactions on our side", peer, size);
+                server.send_to(peer, Message::ReconcileRequest(size));
+            }
+        })
+        .unwrap();
+    info!("Reconciling transactions with peers every {:?}", interval);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(i: u8) -> H256 {
+        let mut bytes = [0; 32];
+        bytes[0] = i;
+        bytes[31] = i;
+        H256::from(bytes)
+    }
+
+    #[test]
+    fn sketch_difference_decodes() {
+        let mut a = Sketch::new(6);
+        let mut b = Sketch::new(6);
+        for id in 0..1000u64 {
+            a.insert(id);
+            b.insert(id);
+        }
+        a.insert(5000);
+        a.insert(5001);
+        b.insert(6000);
+        let (mut ours, theirs) = a.subtract(&b).unwrap().decode().unwrap();
+        ours.sort();
+        assert_eq!(ours, vec![5000, 5001]);
+        assert_eq!(theirs, vec![6000]);
+
+        // far beyond the capacity
+        let mut c = Sketch::new(2);
+        for id in 0..100u64 {
+            c.insert(id);
+        }
+        assert!(c.subtract(&Sketch::new(2)).unwrap().decode().is_none());
+    }
+
+    #[test]
+    fn peers_learn_what_they_lack() {
+        let alice_addr: SocketAddr = "127.0.0.1:6001".parse().unwrap();
+        let bob_addr: SocketAddr = "127.0.0.1:6002".parse().unwrap();
+        let mut alice = Reconciler::new();
+        let mut bob = Reconciler::new();
+        alice.connect(bob_addr, true);
+        bob.connect(alice_addr, false);
+        // before the feature is negotiated, transactions are flooded
+        let tx = InvItem::Transaction(hash(1));
+        assert_eq!(alice.hold(bob_addr, vec![tx]), vec![tx]);
+        alice.enable(bob_addr);
+        bob.enable(alice_addr);
+
+        let shared: Vec<InvItem> = (10..50).map(|i| InvItem::Transaction(hash(i))).collect();
+        let block = InvItem::Block(hash(99));
+        assert_eq!(alice.hold(bob_addr, [shared.clone(), vec![block, InvItem::Transaction(hash(1))]].concat()), vec![block]);
+        assert!(bob.hold(alice_addr, [shared, vec![InvItem::Transaction(hash(2))]].concat()).is_empty());
+        // only the side that connected starts
+        assert_eq!(bob.initiations(), vec![]);
+        assert_eq!(alice.initiations(), vec![(bob_addr, 41)]);
+
+        let sketch = bob.sketch(alice_addr, 41).unwrap();
+        let (announce, request) = match alice.reconcile(bob_addr, &sketch) {
+            Some(Outcome::Reconciled { announce, request }) => (announce, request),
+            outcome => panic!("unexpected {:?}", outcome),
+        };
+        assert_eq!(announce, vec![hash(1)]);
+        assert_eq!(bob.requested(alice_addr, &request), Some(vec![hash(2)]));
+        // a sketch we did not ask for is ignored
+        assert_eq!(alice.reconcile(bob_addr, &sketch), None);
+        assert_eq!(alice.initiations(), vec![(bob_addr, 0)]);
+    }
+
+    #[test]
+    fn sketches_of_peers_are_bounded() {
+        let bob_addr: SocketAddr = "127.0.0.1:6002".parse().unwrap();
+        let mut alice = Reconciler::new();
+        alice.connect(bob_addr, false);
+        alice.enable(bob_addr);
+        // the size a peer claims does not make our sketch any larger
+        let sketch = alice.sketch(bob_addr, u32::MAX).unwrap();
+        assert_eq!(sketch, Sketch::new(MAX_CAPACITY));
+        assert!(sketch.is_well_formed());
+
+        alice.connect(bob_addr, true);
+        alice.enable(bob_addr);
+        for cells in [0, 4, cells_for(MAX_CAPACITY) + HASHES] {
+            assert_eq!(alice.initiations().len(), 1);
+            let malformed = Sketch { cells: vec![Cell::default(); cells] };
+            assert_eq!(alice.reconcile(bob_addr, &malformed), None);
+        }
+
+        // a pure looking cell its id is not added to is not peeled
+        let mut forged = Sketch::new(10);
+        let id = (0..).find(|&id| forged.position(id, 0) != 0).unwrap();
+        forged.cells[0] = Cell { count: 1, id_sum: id, check_sum: mix(id, HASHES as u64) };
+        assert_eq!(forged.decode(), None);
+        // nor do counts overflow
+        let mut extreme = Sketch::new(10);
+        extreme.cells[0].count = i64::MIN;
+        let ones = Sketch { cells: vec![Cell { count: 1, ..Cell::default() }; extreme.cells.len()] };
+        assert_eq!(extreme.subtract(&ones).unwrap().decode(), None);
+    }
+
+    #[test]
+    fn disabled_reconciler_floods() {
+        let bob_addr: SocketAddr = "127.0.0.1:6002".parse().unwrap();
+        let mut alice = Reconciler::disabled();
+        alice.connect(bob_addr, true);
+        // even if the peer negotiated it
+        alice.enable(bob_addr);
+        let tx = InvItem::Transaction(hash(1));
+        assert_eq!(alice.hold(bob_addr, vec![tx]), vec![tx]);
+        assert_eq!(alice.initiations(), vec![]);
+        // and reconciliation messages of the peer are ignored
+        assert_eq!(alice.sketch(bob_addr, 10), None);
+        assert_eq!(alice.reconcile(bob_addr, &Sketch::new(10)), None);
+        assert_eq!(alice.requested(bob_addr, &[1]), None);
+        assert_eq!(alice.failed(bob_addr), None);
+        // as are those of a peer that never negotiated the feature
+        let mut bob = Reconciler::new();
+        bob.connect("127.0.0.1:6001".parse().unwrap(), false);
+        assert_eq!(bob.sketch("127.0.0.1:6001".parse().unwrap(), 10), None);
+    }
+}
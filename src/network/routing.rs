@@ -0,0 +1,293 @@
+use super::message::{InvItem, Message};
+use super::peer::PeerStates;
+use super::server::Handle as ServerHandle;
+
+use log::{debug, info, warn};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// weight of a new round trip time in the moving average
+const RTT_WEIGHT: f64 = 0.25;
+// a request is given this many round trips of its peer before it goes to another peer
+const TIMEOUT_RTTS: u32 = 4;
+// shortest wait before a request is moved, so jitter on a fast link does not trigger it
+const MIN_TIMEOUT: Duration = Duration::from_secs(1);
+// a peer that let a request expire ranks behind every other peer for this long
+const PENALTY: Duration = Duration::from_secs(30);
+
+/// Routes requests for announced objects to the fastest peer that can serve them. Every peer
+/// announcing an object is a source for it, the request goes to the source with the lowest
+/// round trip time, measured by pings and by the requests the peer answers, and a request its peer does not answer in time is moved to
+/// the next fastest source, shifting load away from slow peers.
+pub struct RequestRouter {
+    rtt: HashMap<SocketAddr, Duration>,
+    sources: HashMap<InvItem, Vec<SocketAddr>>,
+    in_flight: HashMap<InvItem, (SocketAddr, Instant)>,
+    penalized: HashMap<SocketAddr, Instant>, // until when a peer ranks last
+    failovers: u64,
+}
+
+impl RequestRouter {
+    pub fn new() -> Self {
+        RequestRouter {
+            rtt: HashMap::new(),
+            sources: HashMap::new(),
+            in_flight: HashMap::new(),
+            penalized: HashMap::new(),
+            failovers: 0,
+        }
+    }
+
+    /// Record a round trip time of `peer`, of a ping or of a request it answered
+    pub fn on_pong(&mut self, peer: SocketAddr, rtt: Duration) {
+        let average = match self.rtt.get(&peer) {
+            Some(average) => average.mul_f64(1.0 - RTT_WEIGHT) + rtt.mul_f64(RTT_WEIGHT),
+            None => rtt,
+        };
+        self.rtt.insert(peer, average);
+    }
+
+    pub fn rtt(&self, peer: &SocketAddr) -> Option<Duration> {
+        self.rtt.get(peer).cloned()
+    }
+
+    /// Smoothed round trip times of every peer measured so far
+    pub fn rtts(&self) -> HashMap<SocketAddr, Duration> {
+        self.rtt.clone()
+    }
+
+    // peers never pinged yet rank behind every measured peer
+    fn rank(&self, peer: &SocketAddr) -> Duration {
+        self.rtt(peer).unwrap_or(Duration::MAX)
+    }
+
+    fn is_penalized(&self, peer: &SocketAddr, now: Instant) -> bool {
+        self.penalized.get(peer).is_some_and(|until| *until > now)
+    }
+
+    fn timeout(&self, peer: &SocketAddr) -> Duration {
+        self.rtt(peer).map_or(MIN_TIMEOUT, |rtt| (rtt * TIMEOUT_RTTS).max(MIN_TIMEOUT))
+    }
+
+    /// Decide where to request the `items` `announcer` announced at `now`, among the sources
+    /// `is_ready` accepts. Returns the items to request from each peer; items already requested
+    /// and not overdue are left out.
+    pub fn route<F: Fn(&SocketAddr) -> bool>(
+        &mut self,
+        announcer: SocketAddr,
+        items: &[InvItem],
+        now: Instant,
+        is_ready: F,
+    ) -> HashMap<SocketAddr, Vec<InvItem>> {
+        let mut routes: HashMap<SocketAddr, Vec<InvItem>> = HashMap::new();
+        for item in items {
+            let sources = self.sources.entry(*item).or_default();
+            if !sources.contains(&announcer) {
+                sources.push(announcer);
+            }
+            let slow = match self.in_flight.get(item) {
+                Some((peer, since)) => {
+                    if now.saturating_duration_since(*since) < self.timeout(peer) {
+                        continue;
+                    }
+                    Some(*peer)
+                }
+                None => None,
+            };
+            // the overdue peer is the last resort
+            let best = self.sources[item]
+                .iter()
+                .filter(|peer| is_ready(peer))
+                .min_by_key(|peer| (Some(**peer) == slow, self.is_penalized(peer, now), self.rank(peer)))
+                .cloned();
+            let peer = match best {
+                Some(peer) => peer,
+                None => continue,
+            };
+            debug!(
+                "Routing request for {:?} to peer {} (rtt {:?}), announced by {}{}",
+                item,
+                peer,
+                self.rtt(&peer),
+                announcer,
+                slow.map_or(String::new(), |slow| format!(", {} was too slow", slow))
+            );
+            self.in_flight.insert(*item, (peer, now));
+            routes.entry(peer).or_default().push(*item);
+        }
+        routes
+    }
+
+    /// Move the requests that are overdue at `now`, or whose peer is no longer among the
+    /// `ready` ones, to another ready peer, a source of the object if there is one. A peer that
+    /// let a request expire ranks behind every other peer for a while. Returns the items to
+    /// request from each peer; a request nobody else can take waits for the next announcement.
+    pub fn expire(&mut self, now: Instant, ready: &[SocketAddr]) -> HashMap<SocketAddr, Vec<InvItem>> {
+        self.penalized.retain(|_, until| *until > now);
+        let expired: Vec<(InvItem, SocketAddr)> = self
+            .in_flight
+            .iter()
+            .filter(|(_, (peer, since))| !ready.contains(peer) || now.saturating_duration_since(*since) >= self.timeout(peer))
+            .map(|(item, (peer, _))| (*item, *peer))
+            .collect();
+        let mut routes: HashMap<SocketAddr, Vec<InvItem>> = HashMap::new();
+        for (item, slow) in expired {
+            if ready.contains(&slow) {
+                warn!("Peer {} did not answer the request for {:?} in time", slow, item);
+                self.penalized.insert(slow, now + PENALTY);
+            }
+            let sources = self.sources.get(&item).cloned().unwrap_or_default();
+            let best = ready
+                .iter()
+                .filter(|peer| **peer != slow)
+                .min_by_key(|peer| (!sources.contains(peer), self.is_penalized(peer, now), self.rank(peer)))
+                .cloned();
+            match best {
+                Some(peer) => {
+                    debug!("Requesting {:?} from peer {} instead of {}", item, peer, slow);
+                    self.in_flight.insert(item, (peer, now));
+                    routes.entry(peer).or_default().push(item);
+                    self.failovers += 1;
+                }
+                None => {
+                    self.in_flight.remove(&item);
+                }
+            }
+        }
+        routes
+    }
+
+    /// Number of requests moved to another peer after a timeout so far
+    pub fn failovers(&self) -> u64 {
+        self.failovers
+    }
+
+    /// `peer` does not have the object after all, the next announcement asks another source
+    pub fn on_not_found(&mut self, peer: SocketAddr, item: &InvItem) {
+        if let Some(sources) = self.sources.get_mut(item) {
+            sources.retain(|source| *source != peer);
+        }
+        if matches!(self.in_flight.get(item), Some((asked, _)) if *asked == peer) {
+            self.in_flight.remove(item);
+        }
+    }
+
+    /// Forget about an object once it arrived
+    pub fn on_received(&mut self, item: &InvItem) {
+        self.sources.remove(item);
+        self.in_flight.remove(item);
+    }
+
+    /// Forget about an object that arrived from `peer` at `now`, timing the round trip if it
+    /// answers our request to that peer
+    pub fn on_received_from(&mut self, peer: SocketAddr, item: &InvItem, now: Instant) {
+        if let Some((asked, since)) = self.in_flight.get(item) {
+            if *asked == peer {
+                let rtt = now.saturating_duration_since(*since);
+                self.on_pong(peer, rtt);
+            }
+        }
+        self.on_received(item);
+    }
+}
+
+impl Default for RequestRouter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Every `interval`, move the requests unanswered in time to other peers
+pub fn start(interval: Duration, router: &Arc<Mutex<RequestRouter>>, peer_states: &Arc<Mutex<PeerStates>>, server: &ServerHandle) {
+    let router = Arc::clone(router);
+    let peer_states = Arc::clone(peer_states);
+    let server = server.clone();
+    thread::Builder::new()
+        .name("request-timeouts".to_string())
+        .spawn(move || loop {
+            thread::sleep(interval);
+            let ready = {peer_states.lock().unwrap().ready()};
+            let routes = {router.lock().unwrap().expire(Instant::now(), &ready)};
+            for (peer, items) in routes {
+                server.send_to(peer, Message::GetData(items));
+            }
+        })
+        .unwrap();
+    info!("Checking requests for unresponsive peers every {:?}", interval);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::hash::H256;
+
+    #[test]
+    fn fastest_source_is_asked() {
+        let mut router = RequestRouter::new();
+        let slow = "127.0.0.1:6001".parse().unwrap();
+        let fast = "127.0.0.1:6002".parse().unwrap();
+        router.on_pong(slow, Duration::from_millis(300));
+        router.on_pong(fast, Duration::from_millis(20));
+        let item = InvItem::Block(H256::from([1; 32]));
+        let now = Instant::now();
+
+        // only the slow peer announced it so far
+        let routes = router.route(slow, &[item], now, |_| true);
+        assert_eq!(routes[&slow], vec![item]);
+        // in flight, the fast peer announcing it too does not trigger a second request
+        assert!(router.route(fast, &[item], now, |_| true).is_empty());
+        // once overdue, the request moves to the fast peer
+        let routes = router.route(fast, &[item], now + Duration::from_secs(2), |_| true);
+        assert_eq!(routes[&fast], vec![item]);
+
+        // the answer of the fast peer times its round trip
+        router.on_received_from(fast, &item, now + Duration::from_millis(2100));
+        assert_eq!(router.rtt(&fast), Some(Duration::from_millis(40)));
+        let routes = router.route(slow, &[item], now, |peer| *peer != slow);
+        assert!(routes.is_empty());
+    }
+
+    #[test]
+    fn unanswered_requests_fail_over() {
+        let mut router = RequestRouter::new();
+        let slow = "127.0.0.1:6001".parse().unwrap();
+        let other = "127.0.0.1:6002".parse().unwrap();
+        let third = "127.0.0.1:6003".parse().unwrap();
+        let block = InvItem::Block(H256::from([1; 32]));
+        let tx = InvItem::Transaction(H256::from([2; 32]));
+        let now = Instant::now();
+        router.route(slow, &[block, tx], now, |_| true);
+        router.route(third, &[tx], now, |_| true);
+        let ready = [slow, other, third];
+        assert!(router.expire(now, &ready).is_empty());
+
+        // the block goes to any other peer, the transaction to the other peer that announced it
+        let later = now + MIN_TIMEOUT;
+        let routes = router.expire(later, &ready);
+        assert_eq!(routes[&other], vec![block]);
+        assert_eq!(routes[&third], vec![tx]);
+        assert_eq!(router.failovers(), 2);
+        // the unresponsive peer is asked last for a while, even when it is the fastest
+        router.on_pong(slow, Duration::from_millis(1));
+        let item = InvItem::Block(H256::from([3; 32]));
+        assert!(router.route(slow, &[item], later, |_| false).is_empty());
+        assert_eq!(router.route(other, &[item], later, |_| true)[&other], vec![item]);
+
+        // the requests to a peer that left move right away, to the sources if they can take them
+        let routes = router.expire(later, &[slow, third]);
+        assert_eq!(routes[&slow].len(), 2);
+        assert!(routes[&slow].contains(&item) && routes[&slow].contains(&block));
+    }
+
+    #[test]
+    fn rtt_is_averaged() {
+        let mut router = RequestRouter::new();
+        let peer = "127.0.0.1:6001".parse().unwrap();
+        router.on_pong(peer, Duration::from_millis(100));
+        router.on_pong(peer, Duration::from_millis(500));
+        assert_eq!(router.rtt(&peer), Some(Duration::from_millis(200)));
+    }
+}
@@ -1,21 +1,64 @@
+use crate::blockchain::{Blockchain, Mempool};
 use crate::types::address::Address;
+use crate::events::{Event, EventBus};
+use super::addrbook::{self, AddressBook};
+use super::banlist::{self, BanList};
+use super::dustfilter::DustFilters;
+use super::eviction::{self, Candidate, ConnectionLimits, LastUseful};
+use super::magic::NetworkMagic;
 use super::peer;
 use super::message;
+use super::features::{Features, PROTOCOL_VERSION, SUPPORTED_FEATURES};
+use super::gossip::{Fanout, RecentlyAnnounced, RelayDelay};
+use super::encryption::{self, KnownKeys, Transport};
+use super::ratelimit::RateLimiter;
+use super::reconcile::Reconciler;
+use super::routing::RequestRouter;
+use super::shared::Shared;
+use super::sync::PeerTips;
+use super::vnet;
+use super::websocket;
+use crate::types::block::Block;
+use crate::types::hash::{H256, Hashable};
 
 use async_dup::Arc as AsyncArc;
 use futures::io::{AsyncReadExt, AsyncWriteExt};
 use futures::io::{BufReader, BufWriter};
-use futures::{channel::oneshot, stream::StreamExt};
+use futures::channel::oneshot;
 use smol::{Async, Executor};
-use log::{debug, info, trace};
+use log::{debug, info, trace, warn};
+use ring::signature::Ed25519KeyPair;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::{HashMap, HashSet};
 use std::net;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::thread;
+use tungstenite::WebSocket;
 
+// a listener on the unspecified IPv6 address takes IPv4 connections too
+fn dual_stack(addr: &net::SocketAddr) -> bool {
+    addr.is_ipv6() && addr.ip().is_unspecified()
+}
+
+// bind the P2P listener, explicitly dual-stack on [::] as systems differ in their default
+fn bind(addr: net::SocketAddr) -> std::io::Result<net::TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    if addr.is_ipv6() {
+        socket.set_only_v6(!dual_stack(&addr))?;
+    }
+    // as std does, so a restarted node can listen again while old connections linger in TIME_WAIT
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(128)?;
+    Ok(socket.into())
+}
 
 pub fn new(
     addr: std::net::SocketAddr,
     msg_sink: smol::channel::Sender<(Vec<u8>, peer::Handle)>,
+    shared: &Shared,
+    events: &EventBus,
 ) -> std::io::Result<(Context, Handle)> {
     let (control_signal_sender, control_signal_receiver) = smol::channel::bounded(10000);
     let handle = Handle {
@@ -27,25 +70,201 @@ pub fn new(
         control_chan: control_signal_receiver,
         control_sender: control_signal_sender,
         new_msg_chan: msg_sink,
+        blockchain: Arc::clone(&shared.blockchain),
+        events: events.clone(),
+        peer_states: Arc::clone(&shared.peer_states),
+        peer_tips: Arc::clone(&shared.peer_tips),
+        gossip: Arc::clone(&shared.gossip),
+        reconciler: Arc::clone(&shared.reconciler),
+        rate_limiter: Arc::clone(&shared.rate_limiter),
+        encryption: None,
+        known_keys: KnownKeys::new(),
+        address_book: None,
+        ban_list: None,
+        dust_filters: None,
+        mempool: None,
+        routes: HashMap::new(),
+        websocket: None,
+        websocket_json: false,
+        push_blocks: HashSet::new(),
+        fanout: Fanout::default(),
+        relay_delay: None,
+        compression: true,
+        magic: None,
+        limits: None,
+        connections: HashMap::new(),
     };
     Ok((ctx, handle))
 }
 
+// the connection limits, with the round trip times and usefulness of the peers to pick which one to evict
+struct Limits {
+    limits: ConnectionLimits,
+    router: Arc<Mutex<RequestRouter>>,
+    last_useful: Arc<Mutex<LastUseful>>,
+}
+
 pub struct Context {
     peers: std::collections::HashMap<std::net::SocketAddr, peer::Handle>,
     addr: std::net::SocketAddr,
     control_chan: smol::channel::Receiver<ControlSignal>,
     control_sender: smol::channel::Sender<ControlSignal>,
     new_msg_chan: smol::channel::Sender<(Vec<u8>, peer::Handle)>,
+    blockchain: Arc<Mutex<Blockchain>>,
+    events: EventBus,
+    peer_states: Arc<Mutex<peer::PeerStates>>,
+    peer_tips: Arc<Mutex<PeerTips>>, // tips of disconnected peers are forgotten
+    gossip: Arc<Mutex<RecentlyAnnounced>>,
+    reconciler: Arc<Mutex<Reconciler>>,
+    rate_limiter: Arc<Mutex<RateLimiter>>,
+    encryption: Option<(encryption::Mode, Arc<Ed25519KeyPair>)>,
+    known_keys: KnownKeys, // node keys the peers we dial have to prove
+    address_book: Option<Arc<Mutex<AddressBook>>>,
+    ban_list: Option<Arc<Mutex<BanList>>>,
+    dust_filters: Option<Arc<Mutex<DustFilters>>>,
+    mempool: Option<Arc<Mutex<Mempool>>>, // to value the transactions the dust filters apply to
+    routes: HashMap<H256, Handle>, // servers of the other networks of this process, by genesis
+    websocket: Option<net::SocketAddr>,
+    websocket_json: bool, // whether WebSocket peers may exchange messages as JSON
+    push_blocks: HashSet<net::SocketAddr>, // peers sent new blocks whole rather than announced
+    fanout: Fanout,
+    relay_delay: Option<Arc<Mutex<RelayDelay>>>,
+    compression: bool, // whether compression is offered to peers
+    magic: Option<NetworkMagic>,
+    limits: Option<Limits>,
+    connections: HashMap<net::SocketAddr, (peer::Direction, std::time::Instant)>,
 }
 
 impl Context {
+    /// Negotiate encrypted connections in `mode`, proving our identity with `key`
+    pub fn with_encryption(mut self, mode: encryption::Mode, key: Ed25519KeyPair) -> Self {
+        if mode != encryption::Mode::Off {
+            self.encryption = Some((mode, Arc::new(key)));
+        }
+        self
+    }
+
+    /// Only accept the peers we dial if they prove one of the node keys in `pins`
+    pub fn with_key_pins(mut self, pins: Vec<Vec<u8>>) -> Self {
+        for key in pins {
+            self.known_keys.pin(key);
+        }
+        self
+    }
+
+    /// Record the outcome of the connections we dial in `book`, so dropped ones are redialed
+    pub fn with_address_book(mut self, book: &Arc<Mutex<AddressBook>>) -> Self {
+        self.address_book = Some(Arc::clone(book));
+        self
+    }
+
+    /// Refuse the connections from and to the addresses banned in `bans`
+    pub fn with_ban_list(mut self, bans: &Arc<Mutex<BanList>>) -> Self {
+        self.ban_list = Some(Arc::clone(bans));
+        self
+    }
+
+    /// Leave the transactions below the dust threshold a peer sent out of the announcements to
+    /// it, reading their values from `mempool`
+    pub fn with_dust_filters(mut self, filters: &Arc<Mutex<DustFilters>>, mempool: &Arc<Mutex<Mempool>>) -> Self {
+        self.dust_filters = Some(Arc::clone(filters));
+        self.mempool = Some(Arc::clone(mempool));
+        self
+    }
+
+    /// Hand the incoming connections tagged with `genesis` to `server`, the server of another
+    /// network hosted by this process
+    pub fn with_route(mut self, genesis: H256, server: &Handle) -> Self {
+        self.routes.insert(genesis, server.clone());
+        self
+    }
+
+    /// Accept peers over WebSocket at `addr` as well, see `websocket::listen`
+    pub fn with_websocket(mut self, addr: net::SocketAddr) -> Self {
+        self.websocket = Some(addr);
+        self
+    }
+
+    /// Let WebSocket peers connecting to `websocket::JSON_PATH` exchange messages as JSON
+    pub fn with_websocket_json(mut self, json: bool) -> Self {
+        self.websocket_json = json;
+        self
+    }
+
+    /// Send the new blocks we accept whole to the peers connected at `peers`, instead of
+    /// announcing them and waiting for a GetData. Costs bandwidth when a peer already has the
+    /// block from elsewhere, but saves a round trip on the path blocks race along.
+    pub fn with_block_push(mut self, peers: Vec<net::SocketAddr>) -> Self {
+        self.push_blocks = peers.into_iter().map(addrbook::canonical).collect();
+        self
+    }
+
+    /// Announce each new block and transaction to as many peers as `fanout` says, drawn at
+    /// random for each broadcast
+    pub fn with_fanout(mut self, fanout: Fanout) -> Self {
+        self.fanout = fanout;
+        self
+    }
+
+    /// Hold new transactions in `delay` before announcing them, see `gossip::start_relay`
+    pub fn with_relay_delay(mut self, delay: &Arc<Mutex<RelayDelay>>) -> Self {
+        self.relay_delay = Some(Arc::clone(delay));
+        self
+    }
+
+    /// Whether to offer peers compression of the frames sent to each other, on by default
+    pub fn with_compression(mut self, compression: bool) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Start every frame sent to TCP peers with `magic` and disconnect peers whose frames do not,
+    /// frames are bare without a magic
+    pub fn with_magic(mut self, magic: Option<NetworkMagic>) -> Self {
+        self.magic = magic;
+        self
+    }
+
+    /// Keep at most `limits` connections in each direction. A new incoming peer over the limit
+    /// makes room by evicting the incoming peer of the lowest value, by its round trip time in
+    /// `router` and by when it was last useful in `last_useful`
+    pub fn with_connection_limits(
+        mut self,
+        limits: ConnectionLimits,
+        router: &Arc<Mutex<RequestRouter>>,
+        last_useful: &Arc<Mutex<LastUseful>>,
+    ) -> Self {
+        self.limits = Some(Limits { limits, router: Arc::clone(router), last_useful: Arc::clone(last_useful) });
+        self
+    }
+
     /// Start a new server context.
     pub fn start(self) -> std::io::Result<()> {
         // initialize the server socket
-        let listener = Async::<net::TcpListener>::bind(self.addr)?;
-        info!("P2P server listening at {}", self.addr);
-        let control_chan = self.control_sender.clone();
+        let listener = Async::new(bind(self.addr)?)?;
+        if dual_stack(&self.addr) {
+            info!("P2P server listening at {}, for IPv4 peers as well", self.addr);
+        } else {
+            info!("P2P server listening at {}", self.addr);
+        }
+        let genesis = {self.blockchain.lock().unwrap().genesis()};
+        let mut routes = self.routes.clone();
+        routes.insert(genesis, Handle { control_chan: self.control_sender.clone() });
+        let events = self.events.clone();
+        let encryption = self.encryption.clone();
+        let ban_list = self.ban_list.clone();
+        if let Some(addr) = self.websocket {
+            if matches!(&encryption, Some((encryption::Mode::Require, _))) {
+                // WebSocket peers do not negotiate encryption
+                warn!("Not accepting WebSocket peers at {}, as P2P encryption is required", addr);
+            } else {
+                let control_chan = self.control_sender.clone();
+                websocket::listen(bind(addr)?, ban_list.clone(), self.websocket_json, move |socket, encoding| {
+                    let _ = smol::block_on(control_chan.send(ControlSignal::GetNewWebSocketPeer(socket, encoding)));
+                });
+                info!("P2P server listening for WebSocket peers at {}", addr);
+            }
+        }
         let ex = Executor::new();
         let ex = Arc::new(ex);
         let ex_clone = ex.clone();
@@ -53,26 +272,153 @@ impl Context {
             self.dispatch_control(ex_clone).await.unwrap();
         })
             .detach();
+        let ex_clone = ex.clone();
         ex.spawn(async move {
-            Self::listener_loop(listener, control_chan).await.unwrap();
+            Self::listener_loop(listener, genesis, Arc::new(routes), events, encryption, ban_list, ex_clone).await.unwrap();
         })
             .detach();
         thread::spawn(move || smol::block_on(ex.run(futures::future::pending::<()>())));
         return Ok(());
     }
 
-    /// the loop that endlessly accept incoming peers
+    /// Start a server context without a listener of its own, the server of another network
+    /// routes the incoming connections for this one to it
+    pub fn start_without_listener(self) {
+        let ex = Arc::new(Executor::new());
+        let ex_clone = ex.clone();
+        ex.spawn(async move {
+            self.dispatch_control(ex_clone).await.unwrap();
+        })
+            .detach();
+        thread::spawn(move || smol::block_on(ex.run(futures::future::pending::<()>())));
+    }
+
+    /// the loop that endlessly accept incoming peers, for the network with `genesis` and the
+    /// others in `routes`
     async fn listener_loop(
         listener: Async<net::TcpListener>,
-        control_chan: smol::channel::Sender<ControlSignal>,
+        genesis: H256,
+        routes: Arc<HashMap<H256, Handle>>,
+        events: EventBus,
+        encryption: Option<(encryption::Mode, Arc<Ed25519KeyPair>)>,
+        ban_list: Option<Arc<Mutex<BanList>>>,
+        ex: Arc<Executor<'_>>,
     ) -> std::io::Result<()> {
         loop {
             let (stream, addr) = listener.accept().await?;
-            control_chan
-                .send(ControlSignal::GetNewPeer(stream))
-                .await
-                .unwrap();
+            let addr = addrbook::canonical(addr);
+            // a banned peer is dropped before it costs a handshake, the bans hold for every network
+            let ban = ban_list.as_ref().and_then(|bans| bans.lock().unwrap().check(&addr.ip(), banlist::now()));
+            if let Some(ban) = ban {
+                debug!("Refusing incoming peer {}, banned: {}", addr, ban.reason);
+                continue;
+            }
             info!("Incoming peer from {}", addr);
+            // pick the network and negotiate apart from this loop, so a peer that never answers
+            // holds up nobody else
+            let routes = Arc::clone(&routes);
+            let events = events.clone();
+            let encryption = encryption.clone();
+            ex.spawn(async move {
+                let negotiated: std::io::Result<_> = async {
+                    // connections of nodes that predate tags are for the network of our flags
+                    let tag = vnet::read_chain_tag(&mut &stream).await?.unwrap_or(genesis);
+                    let server = routes.get(&tag).ok_or_else(|| {
+                        std::io::Error::new(std::io::ErrorKind::NotFound, format!("no network with genesis {} here", tag))
+                    })?;
+                    let transport = match &encryption {
+                        Some((mode, key)) => encryption::handshake(&mut &stream, key, *mode, peer::Direction::Incoming).await?,
+                        None => Transport::Plaintext(None),
+                    };
+                    Ok((server.control_chan.clone(), transport))
+                }
+                .await;
+                match negotiated {
+                    Ok((control_chan, transport)) => control_chan
+                        .send(ControlSignal::GetNewPeer(stream, transport))
+                        .await
+                        .unwrap(),
+                    Err(e) => {
+                        warn!("Handshake with incoming peer {} failed: {}", addr, e);
+                        events.publish(Event::HandshakeFailed { addr, error: e.to_string() });
+                    }
+                }
+            })
+                .detach();
+        }
+    }
+
+    // announce `items` to the peers, those the fanout picks, but for what a peer has seen or
+    // would refuse; blocks go whole to the push peers
+    fn announce(&mut self, items: Vec<message::InvItem>) {
+        // each peer is only told about transactions it has not seen recently
+        let now = std::time::Instant::now();
+        // nor about the transactions it would refuse as dust
+        let dust_filtered: HashMap<net::SocketAddr, Vec<message::InvItem>> = match (&self.dust_filters, &self.mempool) {
+            (Some(filters), Some(mempool)) => {
+                let filters = filters.lock().unwrap();
+                let mempool = mempool.lock().unwrap();
+                self.peers.keys().map(|addr| (*addr, filters.filter(addr, &items, &mempool))).collect()
+            }
+            _ => HashMap::new(),
+        };
+        // the blocks to push, looked up before the gossip records are locked
+        let pushed: HashMap<H256, Block> = if self.peers.keys().any(|addr| self.push_blocks.contains(addr)) {
+            let blockchain = self.blockchain.lock().unwrap();
+            items
+                .iter()
+                .filter_map(|item| match item {
+                    message::InvItem::Block(hash) => blockchain.block(hash).map(|block| (*hash, block)),
+                    message::InvItem::Transaction(_) => None,
+                })
+                .collect()
+        } else {
+            HashMap::new()
+        };
+        // the peers told about each kind of object this time, the push peers get every block
+        let peers: Vec<net::SocketAddr> = self.peers.keys().cloned().collect();
+        let picked = self.fanout.pick(&peers, &mut rand::thread_rng());
+        let mut gossip = self.gossip.lock().unwrap();
+        let mut reconciler = self.reconciler.lock().unwrap();
+        let suppressed = gossip.suppressed();
+        for (addr, hd) in self.peers.iter_mut() {
+            let push = self.push_blocks.contains(addr);
+            let items: Vec<message::InvItem> = dust_filtered
+                .get(addr)
+                .unwrap_or(&items)
+                .iter()
+                .filter(|item| picked.admits(addr, item) || (push && matches!(item, message::InvItem::Block(_))))
+                .cloned()
+                .collect();
+            // peers we reconcile with learn about transactions on the next reconciliation
+            let mut items = reconciler.hold(*addr, gossip.filter(*addr, &items, now));
+            if push {
+                // but for the ones it sent or announced to us, blocks go to a push peer whole
+                let mut blocks = Vec::new();
+                items.retain(|item| match item {
+                    message::InvItem::Block(hash) if !gossip.seen(addr, hash, now) => match pushed.get(hash) {
+                        Some(block) => {
+                            blocks.push(block.clone());
+                            false
+                        }
+                        None => true,
+                    },
+                    _ => true,
+                });
+                if !blocks.is_empty() {
+                    trace!("Pushing {} blocks to peer {}", blocks.len(), addr);
+                    for block in blocks.iter() {
+                        gossip.mark(*addr, block.hash(), now);
+                    }
+                    hd.write(message::Message::Blocks(blocks));
+                }
+            }
+            if !items.is_empty() {
+                hd.write(message::Message::Inv(items));
+            }
+        }
+        if gossip.suppressed() > suppressed {
+            debug!("Suppressed {} redundant announcements, {} in total", gossip.suppressed() - suppressed, gossip.suppressed());
         }
     }
 
@@ -83,26 +429,110 @@ impl Context {
                 ControlSignal::ConnectNewPeer(addr, result_chan) => {
                     trace!("Processing ConnectNewPeer command");
                     let handle = self.connect(&addr, ex.clone()).await;
+                    if let Err(e) = &handle {
+                        self.events.publish(Event::HandshakeFailed { addr, error: e.to_string() });
+                    }
+                    if let Some(book) = &self.address_book {
+                        let mut book = book.lock().unwrap();
+                        match &handle {
+                            Ok(_) => book.connected(addr, std::time::Instant::now()),
+                            Err(_) => {
+                                book.failed(addr, std::time::Instant::now());
+                            }
+                        }
+                    }
                     result_chan.send(handle).unwrap();
                 }
+                ControlSignal::BroadcastMessage(message::Message::Inv(items)) => {
+                    trace!("Processing BroadcastMessage command");
+                    // transactions wait for their relay delay, if there is one
+                    let items = match &self.relay_delay {
+                        Some(delay) => delay.lock().unwrap().hold(items, std::time::Instant::now(), &mut rand::thread_rng()),
+                        None => items,
+                    };
+                    if !items.is_empty() {
+                        self.announce(items);
+                    }
+                }
+                ControlSignal::Release(items) => {
+                    trace!("Processing Release command");
+                    self.announce(items);
+                }
                 ControlSignal::BroadcastMessage(msg) => {
                     trace!("Processing BroadcastMessage command");
                     for (_, hd) in self.peers.iter_mut() {
                         hd.write(msg.clone());
                     }
                 }
-                ControlSignal::GetNewPeer(stream) => {
+                ControlSignal::GetNewPeer(stream, transport) => {
                     trace!("Processing GetNewPeer command");
-                    self.accept(stream, ex.clone()).await?;
+                    self.accept(stream, transport, ex.clone()).await?;
+                }
+                ControlSignal::GetNewWebSocketPeer(socket, encoding) => {
+                    trace!("Processing GetNewWebSocketPeer command");
+                    if let Err(e) = self.register_websocket(socket, encoding) {
+                        warn!("Error registering WebSocket peer: {}", e);
+                    }
                 }
                 ControlSignal::DroppedPeer(addr) => {
                     trace!("Processing DroppedPeer({})", addr);
-                    self.peers.remove(&addr);
-                    info!("Peer {} disconnected", addr);
+                    // both the reader and the writer of a peer report its disconnection
+                    if self.peers.remove(&addr).is_some() {
+                        info!("Peer {} disconnected", addr);
+                        {self.peer_states.lock().unwrap().drain(addr)};
+                        self.peer_tips.lock().unwrap().forget(&addr);
+                        {self.gossip.lock().unwrap().forget(&addr)};
+                        {self.reconciler.lock().unwrap().forget(&addr)};
+                        if let Some(filters) = &self.dust_filters {
+                            filters.lock().unwrap().forget(&addr);
+                        }
+                        {self.rate_limiter.lock().unwrap().forget(&addr)};
+                        self.connections.remove(&addr);
+                        if let Some(limits) = &self.limits {
+                            limits.last_useful.lock().unwrap().forget(&addr);
+                        }
+                        self.events.publish(Event::PeerDisconnected { addr, peers: self.peers.len() });
+                        if let Some(book) = &self.address_book {
+                            if let Some(delay) = book.lock().unwrap().disconnected(addr, std::time::Instant::now()) {
+                                info!("Redialing peer {} in {:?}", addr, delay);
+                            }
+                        }
+                    }
                 }
                 ControlSignal::SendToPeer((_receiver, _msg)) => {
                     unimplemented!()
                 }
+                ControlSignal::SendToAddr(addr, msg) => {
+                    trace!("Processing SendToAddr({}) command", addr);
+                    match self.peers.get_mut(&addr) {
+                        Some(hd) => hd.write(msg),
+                        None => debug!("Dropping message to disconnected peer {}", addr),
+                    }
+                }
+                ControlSignal::GetPeerCount(result_chan) => {
+                    trace!("Processing GetPeerCount command");
+                    result_chan.send(self.peers.len()).unwrap();
+                }
+                ControlSignal::DisconnectIp(ip) => {
+                    trace!("Processing DisconnectIp({}) command", ip);
+                    for (addr, hd) in self.peers.iter().filter(|(addr, _)| addr.ip() == ip.to_canonical()) {
+                        info!("Disconnecting peer {}", addr);
+                        hd.disconnect();
+                    }
+                }
+                ControlSignal::Disconnect(addr) => {
+                    trace!("Processing Disconnect({}) command", addr);
+                    if let Some(hd) = self.peers.get(&addr) {
+                        hd.disconnect();
+                    }
+                }
+                ControlSignal::Rehandshake => {
+                    trace!("Processing Rehandshake command");
+                    let version = self.version();
+                    for (_, hd) in self.peers.iter_mut() {
+                        hd.write(version.clone());
+                    }
+                }
             }
         }
         return Ok(());
@@ -114,35 +544,69 @@ impl Context {
         addr: &std::net::SocketAddr,
         ex: Arc<Executor<'_>>,
     ) -> std::io::Result<peer::Handle> {
+        let ban = self.ban_list.as_ref().and_then(|bans| bans.lock().unwrap().check(&addr.ip(), banlist::now()));
+        if let Some(ban) = ban {
+            return Err(std::io::Error::new(std::io::ErrorKind::PermissionDenied, format!("{} is banned: {}", addr, ban.reason)));
+        }
+        self.admit(peer::Direction::Outgoing)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::ConnectionRefused, e))?;
         debug!("Establishing connection to peer {}", addr);
         let stream = Async::<std::net::TcpStream>::connect(addr.clone()).await?;
+        // tell a peer hosting several networks which one this connection is for
+        let genesis = {self.blockchain.lock().unwrap().genesis()};
+        vnet::write_chain_tag(&mut &stream, &genesis).await?;
+        let transport = match &self.encryption {
+            Some((mode, key)) => encryption::handshake(&mut &stream, key, *mode, peer::Direction::Outgoing).await?,
+            None => Transport::Plaintext(None),
+        };
+        if self.encryption.is_some() {
+            self.known_keys.check(*addr, &transport)?;
+        }
 
         // register the new peer
-        self.register(stream, peer::Direction::Outgoing, ex).await
+        self.register(stream, peer::Direction::Outgoing, transport, ex).await
     }
 
     async fn accept(
         &mut self,
         stream: Async<net::TcpStream>,
+        transport: Transport,
         ex: Arc<Executor<'_>>,
     ) -> std::io::Result<()> {
-        self.register(stream, peer::Direction::Incoming, ex).await?;
+        if let Err(e) = self.admit(peer::Direction::Incoming) {
+            info!("Refusing incoming peer {}: {}", stream.get_ref().peer_addr()?, e);
+            return Ok(());
+        }
+        self.register(stream, peer::Direction::Incoming, transport, ex).await?;
         Ok(())
     }
 
     async fn register(
         &mut self,
         stream: Async<net::TcpStream>,
-        _direction: peer::Direction,
+        direction: peer::Direction,
+        transport: Transport,
         ex: Arc<Executor<'_>>,
     ) -> std::io::Result<peer::Handle> {
-        let (mut write_queue, handle) = peer::new(&stream)?;
+        let (write_queue, handle) = peer::new(&stream)?;
 
         let stream = AsyncArc::new(stream);
         let new_msg_chan = self.new_msg_chan.clone();
         let handle_copy = handle.clone();
         let control_chan = self.control_sender.clone();
-        let addr = stream.get_ref().peer_addr()?;
+        let reader_control_chan = self.control_sender.clone();
+        let rate_limiter = Arc::clone(&self.rate_limiter);
+        let addr = addrbook::canonical(stream.get_ref().peer_addr()?);
+        let magic = self.magic;
+        self.track(addr, direction);
+        // the reader decrypts the frames of an encrypted connection and the writer encrypts them
+        let (mut first_frame, mut recv_cipher, mut send_cipher) = match transport {
+            Transport::Encrypted(session) => {
+                info!("Encrypted connection with peer {}, its node key is {}", addr, hex::encode(&session.remote_key));
+                (None, Some(session.recv), Some(session.send))
+            }
+            Transport::Plaintext(first_frame) => (first_frame, None, None),
+        };
 
         // start the reactor for this peer
         // first, start a task that keeps reading from this guy
@@ -153,34 +617,76 @@ impl Context {
             // the buffer to store the message content
             let mut msg_buffer: Vec<u8> = vec![];
             loop {
-                // first, read exactly 4 bytes to get the frame header
-                let msg_size = match reader.read_exact(&mut size_buffer).await {
-                    Ok(_) => u32::from_be_bytes(size_buffer),
-                    Err(_) => {
-                        break;
-                    }
-                };
-                // then, read exactly msg_size bytes to get the whole message
-                if msg_buffer.len() < msg_size as usize {
-                    msg_buffer.resize(msg_size as usize, 0);
-                }
-                match reader
-                    .read_exact(&mut msg_buffer[0..msg_size as usize])
-                    .await
-                {
-                    Ok(_) => {
-                        let new_payload: Vec<u8> = msg_buffer[0..msg_size as usize].to_vec();
-                        new_msg_chan
-                            .send((new_payload, handle_copy.clone()))
+                // a legacy peer's first message was already read while negotiating
+                let new_payload = match first_frame.take() {
+                    Some(frame) => frame,
+                    None => {
+                        // first, read exactly 4 bytes to get the frame header
+                        let msg_size = match reader.read_exact(&mut size_buffer).await {
+                            Ok(_) => u32::from_be_bytes(size_buffer),
+                            Err(_) => {
+                                break;
+                            }
+                        };
+                        // then, read exactly msg_size bytes to get the whole message
+                        if msg_buffer.len() < msg_size as usize {
+                            msg_buffer.resize(msg_size as usize, 0);
+                        }
+                        match reader
+                            .read_exact(&mut msg_buffer[0..msg_size as usize])
                             .await
-                            .unwrap();
+                        {
+                            Ok(_) => msg_buffer[0..msg_size as usize].to_vec(),
+                            Err(_) => {
+                                break;
+                            }
+                        }
                     }
-                    Err(_) => {
+                };
+                let new_payload = match recv_cipher.as_mut() {
+                    Some(cipher) => match cipher.decrypt(new_payload) {
+                        Ok(payload) => payload,
+                        Err(e) => {
+                            warn!("Disconnecting peer {}: {}", addr, e);
+                            break;
+                        }
+                    },
+                    None => new_payload,
+                };
+                let new_payload = match magic.as_ref() {
+                    Some(magic) => match magic.open(new_payload) {
+                        Ok(payload) => payload,
+                        Err(e) => {
+                            warn!("Disconnecting peer {}: {}", addr, e);
+                            break;
+                        }
+                    },
+                    None => new_payload,
+                };
+                let new_payload = match message::decompress(new_payload) {
+                    Ok(payload) => payload,
+                    Err(e) => {
+                        warn!("Disconnecting peer {}: {}", addr, e);
                         break;
                     }
+                };
+                // drop what is over the peer's limit here, before it takes a place in the worker channel
+                // message types we do not know share one limit, the worker skips them anyway
+                let name = message::Message::peek_name(&new_payload).unwrap_or("Unknown");
+                if !rate_limiter.lock().unwrap().admit(addr, name, std::time::Instant::now()) {
+                    trace!("Dropping {} from peer {} over its rate limit", name, addr);
+                    continue;
                 }
+                new_msg_chan
+                    .send((new_payload, handle_copy.clone()))
+                    .await
+                    .unwrap();
             }
             // the peer is disconnected
+            reader_control_chan
+                .send(ControlSignal::DroppedPeer(addr))
+                .await
+                .unwrap();
         })
             .detach();
 
@@ -188,8 +694,19 @@ impl Context {
         let mut writer = BufWriter::new(stream.clone());
         ex.spawn(async move {
             loop {
-                // first, get a message to write from the queue
-                let new_msg = write_queue.next().await.unwrap();
+                // first, get a message to write from the queue, it ends once the peer is dropped
+                let new_msg = match write_queue.next().await {
+                    Some(new_msg) => new_msg,
+                    None => break,
+                };
+                let new_msg = match magic.as_ref() {
+                    Some(magic) => magic.seal(new_msg),
+                    None => new_msg,
+                };
+                let new_msg = match send_cipher.as_mut() {
+                    Some(cipher) => cipher.encrypt(new_msg),
+                    None => new_msg,
+                };
 
                 // second, encode the length of the message
                 let size_buffer = (new_msg.len() as u32).to_be_bytes();
@@ -222,9 +739,112 @@ impl Context {
         })
             .detach();
 
+        self.add(addr, &handle, direction);
+        Ok(handle)
+    }
+
+    // a reconnecting peer starts over, before any of its messages can reach the workers
+    fn track(&self, addr: net::SocketAddr, direction: peer::Direction) {
+        {self.peer_states.lock().unwrap().connect(addr)};
+        {self.reconciler.lock().unwrap().connect(addr, matches!(direction, peer::Direction::Outgoing))};
+    }
+
+    // whether a new peer may connect in `direction`, over the limit an incoming peer is let in
+    // by evicting the incoming peer of the lowest value
+    fn admit(&mut self, direction: peer::Direction) -> Result<(), String> {
+        let Limits { limits, router, last_useful } = match &self.limits {
+            Some(limits) => limits,
+            None => return Ok(()),
+        };
+        let incoming = matches!(direction, peer::Direction::Incoming);
+        let (max, kind) = if incoming { (limits.max_inbound, "inbound") } else { (limits.max_outbound, "outbound") };
+        let count = self.connections.values().filter(|(other, _)| matches!(other, peer::Direction::Incoming) == incoming).count();
+        if count < max {
+            return Ok(());
+        }
+        if !incoming {
+            return Err(format!("{} connection limit of {} reached", kind, max));
+        }
+        let rtts = {router.lock().unwrap().rtts()};
+        let candidates: Vec<Candidate> = {
+            let last_useful = last_useful.lock().unwrap();
+            self.connections
+                .iter()
+                .filter(|(_, (direction, _))| matches!(direction, peer::Direction::Incoming))
+                .map(|(addr, (_, connected_at))| Candidate {
+                    addr: *addr,
+                    rtt: rtts.get(addr).cloned(),
+                    last_useful: last_useful.get(addr),
+                    connected_at: *connected_at,
+                })
+                .collect()
+        };
+        let evicted = match eviction::pick_eviction(&candidates, eviction::PROTECTED) {
+            Some(evicted) => evicted,
+            None => return Err(format!("{} connection limit of {} reached", kind, max)),
+        };
+        info!("Evicting incoming peer {} to make room for a new one", evicted);
+        // it no longer counts, though it is only dropped once its connection closes
+        self.connections.remove(&evicted);
+        if let Some(hd) = self.peers.get(&evicted) {
+            hd.disconnect();
+        }
+        Ok(())
+    }
+
+    /// Register a peer that connected over WebSocket, its messages are relayed by a thread of its own
+    fn register_websocket(&mut self, socket: WebSocket<net::TcpStream>, encoding: websocket::Encoding) -> std::io::Result<()> {
+        let addr = addrbook::canonical(socket.get_ref().peer_addr()?);
+        if let Err(e) = self.admit(peer::Direction::Incoming) {
+            info!("Refusing WebSocket peer {}: {}", addr, e);
+            return Ok(());
+        }
+        let (write_queue, handle) = peer::with_socket(addr, socket.get_ref())?;
+        self.track(addr, peer::Direction::Incoming);
+        let relay_handle = handle.clone();
+        let new_msg_chan = self.new_msg_chan.clone();
+        let rate_limiter = Arc::clone(&self.rate_limiter);
+        let control_chan = self.control_sender.clone();
+        thread::spawn(move || {
+            websocket::relay(socket, encoding, relay_handle, write_queue, &new_msg_chan, &rate_limiter);
+            // the peer is disconnected
+            let _ = smol::block_on(control_chan.send(ControlSignal::DroppedPeer(addr)));
+        });
+        self.add(addr, &handle, peer::Direction::Incoming);
+        Ok(())
+    }
+
+    // our protocol version, features and chain
+    fn version(&self) -> message::Message {
+        let (height, genesis) = {
+            let blockchain = self.blockchain.lock().unwrap();
+            (blockchain.height(), blockchain.genesis())
+        };
+        // peers of a node that floods must not hold transactions back for it
+        let reconciling = {self.reconciler.lock().unwrap().is_active()};
+        let features = if reconciling {
+            SUPPORTED_FEATURES
+        } else {
+            SUPPORTED_FEATURES.difference(Features::TX_RECONCILIATION)
+        };
+        let features = if self.compression { features } else { features.difference(Features::COMPRESSION) };
+        message::Message::Version(PROTOCOL_VERSION, features, height, genesis)
+    }
+
+    // greet a peer whose reader and writer are running and start broadcasting to it
+    fn add(&mut self, addr: net::SocketAddr, handle: &peer::Handle, direction: peer::Direction) {
+        // announce our protocol version, features and chain, both sides do so right after connecting
+        let mut version_handle = handle.clone();
+        version_handle.write(self.version());
+
         // insert the peer handle so that we can broadcast to this guy later
         self.peers.insert(addr, handle.clone());
-        Ok(handle)
+        self.connections.insert(addr, (direction, std::time::Instant::now()));
+        let direction = match direction {
+            peer::Direction::Incoming => "incoming",
+            peer::Direction::Outgoing => "outgoing",
+        };
+        self.events.publish(Event::PeerConnected { addr, direction: direction.to_string() });
     }
 }
 
@@ -249,6 +869,32 @@ impl TestReceiver {
 }
 
 impl Handle {
+    /// Handle of a server that is not listening: messages are dropped, it has no peers and
+    /// connecting fails. Used to replay the messages of a captured trace.
+    pub fn detached() -> Handle {
+        let (control_chan, receiver) = smol::channel::unbounded();
+        thread::Builder::new()
+            .name("detached-server".to_string())
+            .spawn(move || {
+                // ends once every handle is dropped
+                while let Ok(signal) = smol::block_on(receiver.recv()) {
+                    match signal {
+                        ControlSignal::ConnectNewPeer(addr, result) => {
+                            let e = std::io::Error::new(std::io::ErrorKind::NotConnected, format!("not connecting to {} while detached", addr));
+                            let _ = result.send(Err(e));
+                        }
+                        ControlSignal::GetPeerCount(result) => {
+                            let _ = result.send(0);
+                        }
+                        ControlSignal::BroadcastMessage(msg) => trace!("Dropping broadcast {}", msg.name()),
+                        _ => (),
+                    }
+                }
+            })
+            .unwrap();
+        Handle { control_chan }
+    }
+
     pub fn connect(&self, addr: std::net::SocketAddr) -> std::io::Result<peer::Handle> {
         let (sender, receiver) = oneshot::channel();
         smol::block_on(
@@ -267,6 +913,39 @@ impl Handle {
         smol::block_on(self.control_chan.send(ControlSignal::SendToPeer((receiver, msg)))).unwrap();
     }
 
+    /// Announce the transactions whose relay delay is over, without delaying them again
+    pub fn release(&self, items: Vec<message::InvItem>) {
+        smol::block_on(self.control_chan.send(ControlSignal::Release(items))).unwrap();
+    }
+
+    /// Send a message to the connected peer at `addr`
+    pub fn send_to(&self, addr: std::net::SocketAddr, msg: message::Message) {
+        smol::block_on(self.control_chan.send(ControlSignal::SendToAddr(addr, msg))).unwrap();
+    }
+
+    /// Get the number of peers currently connected to this node
+    pub fn peer_count(&self) -> usize {
+        let (sender, receiver) = oneshot::channel();
+        smol::block_on(self.control_chan.send(ControlSignal::GetPeerCount(sender))).unwrap();
+        smol::block_on(receiver).unwrap()
+    }
+
+    /// Close the connections of every peer at `ip`, such as a peer just banned
+    pub fn disconnect_ip(&self, ip: net::IpAddr) {
+        smol::block_on(self.control_chan.send(ControlSignal::DisconnectIp(ip))).unwrap();
+    }
+
+    /// Close the connection of the peer at `addr`
+    pub fn disconnect(&self, addr: std::net::SocketAddr) {
+        smol::block_on(self.control_chan.send(ControlSignal::Disconnect(addr))).unwrap();
+    }
+
+    /// Send our `Version` to every peer again, restarting the handshake: the peers renegotiate
+    /// and ask for our tip, as after connecting
+    pub fn rehandshake(&self) {
+        smol::block_on(self.control_chan.send(ControlSignal::Rehandshake)).unwrap();
+    }
+
     #[cfg(any(test,test_utilities))]
     pub fn new_for_test() -> (Handle, TestReceiver) {
         let (s,r) = smol::channel::unbounded();
@@ -282,7 +961,35 @@ enum ControlSignal {
         oneshot::Sender<std::io::Result<peer::Handle>>,
     ),
     BroadcastMessage(message::Message),
-    GetNewPeer(Async<net::TcpStream>),
+    Release(Vec<message::InvItem>), // announce transactions whose relay delay is over
+    GetNewPeer(Async<net::TcpStream>, Transport),
+    GetNewWebSocketPeer(WebSocket<net::TcpStream>, websocket::Encoding),
     DroppedPeer(std::net::SocketAddr),
     SendToPeer((Address,message::Message)),
+    SendToAddr(std::net::SocketAddr, message::Message),
+    GetPeerCount(oneshot::Sender<usize>),
+    DisconnectIp(net::IpAddr),
+    Disconnect(std::net::SocketAddr),
+    Rehandshake,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dual_stack_listener_takes_both_families() {
+        let listener = bind("[::]:0".parse().unwrap()).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        for peer in ["127.0.0.1", "::1"] {
+            let peer: net::IpAddr = peer.parse().unwrap();
+            let _stream = net::TcpStream::connect((peer, port)).unwrap();
+            let (_, addr) = listener.accept().unwrap();
+            assert_eq!(addrbook::canonical(addr).ip(), peer);
+        }
+        // a listener on a specific IPv6 address does not
+        let listener = bind("[::1]:0".parse().unwrap()).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        assert!(net::TcpStream::connect(("127.0.0.1", port)).is_err());
+    }
 }
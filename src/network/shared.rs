@@ -0,0 +1,46 @@
+use super::clock::NetworkClock;
+use super::features::PeerFeatures;
+use super::gossip::RecentlyAnnounced;
+use super::orphan::{OrphanResolver, OrphanStrategy};
+use super::peer::PeerStates;
+use super::ratelimit::RateLimiter;
+use super::reconcile::Reconciler;
+use super::sync::PeerTips;
+use crate::blockchain::{Blockchain, Mempool};
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// The node state the P2P server, its workers and the API server share.
+#[derive(Clone)]
+pub struct Shared {
+    pub blockchain: Arc<Mutex<Blockchain>>,
+    pub mempool: Arc<Mutex<Mempool>>,
+    pub peer_states: Arc<Mutex<PeerStates>>,
+    pub peer_tips: Arc<Mutex<PeerTips>>,
+    pub peer_features: Arc<Mutex<PeerFeatures>>,
+    pub orphans: Arc<Mutex<OrphanResolver>>,
+    pub gossip: Arc<Mutex<RecentlyAnnounced>>,
+    pub reconciler: Arc<Mutex<Reconciler>>,
+    pub clock: Arc<Mutex<NetworkClock>>,
+    pub rate_limiter: Arc<Mutex<RateLimiter>>,
+}
+
+impl Shared {
+    /// The state around `blockchain` and `mempool` of a node without peers yet, with the
+    /// default settings of the command line
+    pub fn new(blockchain: &Arc<Mutex<Blockchain>>, mempool: &Arc<Mutex<Mempool>>) -> Self {
+        Shared {
+            blockchain: Arc::clone(blockchain),
+            mempool: Arc::clone(mempool),
+            peer_states: Arc::new(Mutex::new(PeerStates::new())),
+            peer_tips: Arc::new(Mutex::new(PeerTips::new())),
+            peer_features: Arc::new(Mutex::new(PeerFeatures::new())),
+            orphans: Arc::new(Mutex::new(OrphanResolver::new(OrphanStrategy::SamePeer, 100))),
+            gossip: Arc::new(Mutex::new(RecentlyAnnounced::new(Duration::from_secs(60)))),
+            reconciler: Arc::new(Mutex::new(Reconciler::new())),
+            clock: Arc::new(Mutex::new(NetworkClock::new(Duration::from_secs(5), false))),
+            rate_limiter: Arc::new(Mutex::new(RateLimiter::with_default_limits())),
+        }
+    }
+}
@@ -0,0 +1,509 @@
+use super::message::{InvItem, Message};
+use super::orphan::OrphanResolver;
+use super::server::Handle as ServerHandle;
+use crate::blockchain::Blockchain;
+use crate::events::{Event, EventBus};
+use crate::types::hash::H256;
+
+use log::{debug, info, warn};
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time;
+
+/// Peers that must report a tip at least as high before it counts as the best tip
+pub const TIP_QUORUM: usize = 2;
+
+/// Tips not reported again for this long are ignored; the sync monitor asks for fresh ones every
+/// block interval, so only a peer that stopped answering keeps a stale one
+pub const TIP_LIFETIME: time::Duration = time::Duration::from_secs(600);
+
+/// The latest tip each peer reported to us through a `Tip` message. A `Tip` is only the peer's
+/// word, so the best tip is the highest one `quorum` peers reached: a single peer lying about a
+/// far higher tip cannot make us believe we are behind.
+pub struct PeerTips {
+    tips: HashMap<SocketAddr, (u32, H256, time::Instant)>,
+    quorum: usize,
+    lifetime: time::Duration,
+}
+
+impl PeerTips {
+    pub fn new() -> Self {
+        Self::with_limits(TIP_QUORUM, TIP_LIFETIME)
+    }
+
+    /// Count a tip once `quorum` peers reached it, and ignore tips older than `lifetime`
+    pub fn with_limits(quorum: usize, lifetime: time::Duration) -> Self {
+        PeerTips { tips: HashMap::new(), quorum: quorum.max(1), lifetime }
+    }
+
+    /// Remember the tip a peer reported, replacing whatever it reported before
+    pub fn update(&mut self, peer: SocketAddr, height: u32, hash: H256) {
+        self.tips.insert(peer, (height, hash, time::Instant::now()));
+    }
+
+    /// Forget the tip of a peer that disconnected
+    pub fn forget(&mut self, peer: &SocketAddr) {
+        self.tips.remove(peer);
+    }
+
+    /// Get the tip a peer last reported, as (height, hash), unless it is stale
+    pub fn get(&self, peer: &SocketAddr) -> Option<(u32, H256)> {
+        self.tips
+            .get(peer)
+            .filter(|(_, _, at)| at.elapsed() < self.lifetime)
+            .map(|(height, hash, _)| (*height, *hash))
+    }
+
+    /// Get the highest tip `quorum` peers reached, as (height, hash)
+    pub fn best(&self) -> Option<(u32, H256)> {
+        self.best_peer().map(|(_, height, hash)| (height, hash))
+    }
+
+    /// Get the highest tip `quorum` peers reached, as (peer, height, hash) of the peer that
+    /// reported the lowest of them
+    pub fn best_peer(&self) -> Option<(SocketAddr, u32, H256)> {
+        let mut fresh: Vec<(SocketAddr, u32, H256)> = self
+            .tips
+            .iter()
+            .filter(|(_, (_, _, at))| at.elapsed() < self.lifetime)
+            .map(|(peer, (height, hash, _))| (*peer, *height, *hash))
+            .collect();
+        fresh.sort_by_key(|(peer, height, _)| (std::cmp::Reverse(*height), *peer));
+        fresh.get(self.quorum - 1).copied()
+    }
+}
+
+impl Default for PeerTips {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// How long the gate waits for the best tip of our peers once we have the minimum chain work
+pub const SYNC_TIMEOUT: time::Duration = time::Duration::from_secs(600);
+
+/// Whether the initial sync is complete: our longest chain has at least `min_chain_work` and is
+/// not behind the best tip of our peers. Until then the node only has part of the chain, so it
+/// answers requests for blocks with `NotFound` and its API reports that it is syncing. Peers
+/// that keep claiming a higher tip without ever sending its blocks cannot hold the gate closed:
+/// it opens anyway `timeout` after we reached the minimum chain work. Once complete the gate
+/// stays open, a node falling behind later still serves what it has.
+pub struct SyncGate {
+    min_chain_work: f64,
+    complete: bool,
+    timeout: time::Duration,
+    // since when we have the minimum chain work but are behind our peers
+    waiting_since: Option<time::Instant>,
+}
+
+/// Sync progress, for the API.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct SyncStatus {
+    pub syncing: bool,
+    pub height: u32,
+    pub chain_work: f64,
+    pub min_chain_work: f64,
+    /// best tip of our peers, see `PeerTips::best`
+    pub peer_height: Option<u32>,
+}
+
+impl SyncGate {
+    /// A gate with no minimum work, i.e. `min_chain_work` of 0, is open from the start
+    pub fn new(min_chain_work: f64) -> Self {
+        SyncGate { min_chain_work, complete: min_chain_work <= 0.0, timeout: SYNC_TIMEOUT, waiting_since: None }
+    }
+
+    /// Open the gate `timeout` after we reached the minimum chain work, even if peers are ahead
+    pub fn with_timeout(mut self, timeout: time::Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.complete
+    }
+
+    pub fn min_chain_work(&self) -> f64 {
+        self.min_chain_work
+    }
+
+    /// Check the gate against our chain and the best peer tip at `now`, returns whether this
+    /// completed it
+    pub fn update(&mut self, chain_work: f64, height: u32, peer_height: Option<u32>, now: time::Instant) -> bool {
+        if self.complete || chain_work < self.min_chain_work {
+            return false;
+        }
+        if peer_height.is_some_and(|peer_height| peer_height > height) {
+            let waiting_since = *self.waiting_since.get_or_insert(now);
+            if now.duration_since(waiting_since) < self.timeout {
+                return false;
+            }
+            warn!("Peers still report a tip above height {} after {:?}, serving blocks anyway", height, self.timeout);
+        }
+        self.complete = true;
+        true
+    }
+}
+
+impl Default for SyncGate {
+    fn default() -> Self {
+        Self::new(0.0)
+    }
+}
+
+/// Longest a peer is left alone after failed resyncs, as a power of two of the base delay
+pub const MAX_RESYNC_BACKOFF_EXPONENT: u32 = 6;
+
+/// When the sync monitor may resync from a peer again. Each resync from a peer that did not
+/// get our tip moving doubles the wait before the next one from it, starting at `base` and up
+/// to `base * 2^MAX_RESYNC_BACKOFF_EXPONENT`, so a peer whose tip we cannot reach is not asked
+/// again every few block intervals forever.
+pub struct ResyncBackoff {
+    base: time::Duration,
+    // failed resyncs and when the next one may start, per peer
+    peers: HashMap<SocketAddr, (u32, time::Instant)>,
+}
+
+impl ResyncBackoff {
+    pub fn new(base: time::Duration) -> Self {
+        ResyncBackoff { base, peers: HashMap::new() }
+    }
+
+    /// Whether a resync from `peer` may start at `now`
+    pub fn ready(&self, peer: &SocketAddr, now: time::Instant) -> bool {
+        self.peers.get(peer).is_none_or(|(_, until)| now >= *until)
+    }
+
+    /// Record a resync from `peer` at `now` that has not helped yet
+    pub fn failed(&mut self, peer: SocketAddr, now: time::Instant) {
+        let failures = self.peers.get(&peer).map_or(0, |(failures, _)| *failures);
+        let delay = self.base * 2u32.pow(failures.min(MAX_RESYNC_BACKOFF_EXPONENT));
+        self.peers.insert(peer, (failures + 1, now + delay));
+    }
+
+    /// Our tip advanced, every peer may be resynced from right away again
+    pub fn clear(&mut self) {
+        self.peers.clear();
+    }
+}
+
+/// Progress of syncing, saved every block interval so a restarted node can pick up where it
+/// stopped: the last validated block and the window of blocks requested but not received yet.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct SyncCheckpoint {
+    pub height: u32,
+    pub tip: H256,
+    pub window: Vec<H256>,
+}
+
+impl SyncCheckpoint {
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let json = std::fs::read_to_string(path)?;
+        serde_json::from_str(&json).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        // write and rename, so a crash never leaves a torn checkpoint
+        let tmp = path.as_ref().with_extension("tmp");
+        std::fs::write(&tmp, serde_json::to_string(self).unwrap())?;
+        std::fs::rename(tmp, path)
+    }
+}
+
+/// Watches the tip of our blockchain. When it has not advanced for `stall_intervals` expected
+/// block intervals while a quorum of peers reports a higher tip, we assume we are cut off from
+/// the rest of the network: raise a health warning and try to resync from one of those peers
+/// instead of staying behind silently. Resyncs from a peer that do not help back off, see
+/// `ResyncBackoff`.
+pub struct Monitor {
+    block_interval: time::Duration,
+    stall_intervals: u32,
+    server: ServerHandle,
+    blockchain: Arc<Mutex<Blockchain>>,
+    peer_tips: Arc<Mutex<PeerTips>>,
+    orphans: Arc<Mutex<OrphanResolver>>,
+    events: EventBus,
+    checkpoint: Option<PathBuf>,
+    sync_gate: Arc<Mutex<SyncGate>>,
+}
+
+impl Monitor {
+    pub fn new(
+        block_interval: time::Duration,
+        stall_intervals: u32,
+        server: &ServerHandle,
+        blockchain: &Arc<Mutex<Blockchain>>,
+        peer_tips: &Arc<Mutex<PeerTips>>,
+        orphans: &Arc<Mutex<OrphanResolver>>,
+        events: &EventBus,
+    ) -> Self {
+        Self {
+            block_interval,
+            stall_intervals,
+            server: server.clone(),
+            blockchain: Arc::clone(blockchain),
+            peer_tips: Arc::clone(peer_tips),
+            orphans: Arc::clone(orphans),
+            events: events.clone(),
+            checkpoint: None,
+            sync_gate: Arc::new(Mutex::new(SyncGate::default())),
+        }
+    }
+
+    /// Save the sync progress to `path` every block interval, and resume from it on start
+    pub fn with_checkpoint<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.checkpoint = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Open `sync_gate` once the initial sync is complete
+    pub fn with_sync_gate(mut self, sync_gate: &Arc<Mutex<SyncGate>>) -> Self {
+        self.sync_gate = Arc::clone(sync_gate);
+        self
+    }
+
+    /// Sync progress of `blockchain` against the tips of our peers, as `sync_gate` sees it
+    pub fn status(blockchain: &Blockchain, peer_tips: &PeerTips, sync_gate: &SyncGate) -> SyncStatus {
+        let height = blockchain.height();
+        SyncStatus {
+            syncing: !sync_gate.is_complete(),
+            height,
+            chain_work: blockchain.chain_work(height, height).pop().map_or(0.0, |work| work.cumulative_work),
+            min_chain_work: sync_gate.min_chain_work(),
+            peer_height: peer_tips.best().map(|(peer_height, _)| peer_height),
+        }
+    }
+
+    fn update_sync_gate(&self) {
+        if self.sync_gate.lock().unwrap().is_complete() {
+            return;
+        }
+        let status = {
+            let blockchain = self.blockchain.lock().unwrap();
+            let peer_tips = self.peer_tips.lock().unwrap();
+            let sync_gate = self.sync_gate.lock().unwrap();
+            Self::status(&blockchain, &peer_tips, &sync_gate)
+        };
+        let completed = {self.sync_gate.lock().unwrap().update(status.chain_work, status.height, status.peer_height, time::Instant::now())};
+        if completed {
+            info!("Initial sync complete at height {} with chain work {:.0}, serving blocks", status.height, status.chain_work);
+            self.events.publish(Event::SyncComplete { height: status.height });
+        }
+    }
+
+    // blocks of the saved window we still miss, after the chain was restored from disk
+    fn resume_window(&self) -> Vec<H256> {
+        let path = match &self.checkpoint {
+            Some(path) if path.exists() => path,
+            _ => return Vec::new(),
+        };
+        let checkpoint = match SyncCheckpoint::load(path) {
+            Ok(checkpoint) => checkpoint,
+            Err(e) => {
+                warn!("Error reading sync checkpoint {}: {}, syncing from scratch", path.display(), e);
+                return Vec::new();
+            }
+        };
+        let blockchain = self.blockchain.lock().unwrap();
+        if !blockchain.contains(&checkpoint.tip) {
+            warn!("Last validated block {} at height {} is not in the restored chain", checkpoint.tip, checkpoint.height);
+        }
+        info!(
+            "Resuming sync from height {} with {} blocks of the last window outstanding",
+            blockchain.height(), checkpoint.window.len()
+        );
+        checkpoint
+            .window
+            .into_iter()
+            .filter(|hash| !blockchain.contains(hash))
+            .collect()
+    }
+
+    fn save_checkpoint(&self, requested: &[H256]) {
+        let path = match &self.checkpoint {
+            Some(path) => path,
+            None => return,
+        };
+        let (height, tip) = {
+            let blockchain = self.blockchain.lock().unwrap();
+            (blockchain.height(), blockchain.tip())
+        };
+        let mut window = {self.orphans.lock().unwrap().pending()};
+        for hash in requested {
+            if !window.contains(hash) {
+                window.push(*hash);
+            }
+        }
+        if let Err(e) = (SyncCheckpoint { height, tip, window }).save(path) {
+            warn!("Error writing sync checkpoint {}: {}", path.display(), e);
+        }
+    }
+
+    pub fn start(self) {
+        thread::Builder::new()
+            .name("sync-monitor".to_string())
+            .spawn(move || {
+                self.monitor_loop();
+            })
+            .unwrap();
+        info!("Sync monitor initialized");
+    }
+
+    fn monitor_loop(&self) {
+        let mut last_height = {self.blockchain.lock().unwrap().height()};
+        let mut stalled = 0;
+        // blocks we asked for ourselves, kept in the checkpoint until they arrive
+        let mut requested = self.resume_window();
+        let mut resume = !requested.is_empty();
+        let mut backoff = ResyncBackoff::new(self.block_interval * self.stall_intervals);
+        loop {
+            // keep the tips reported by our peers fresh
+            self.server.broadcast(Message::GetTip);
+            thread::sleep(self.block_interval);
+            self.update_sync_gate();
+
+            // peers had an interval to connect, ask them for what we were fetching before the restart
+            if resume {
+                self.server.broadcast(Message::GetData(requested.iter().cloned().map(InvItem::Block).collect()));
+                resume = false;
+            }
+            {
+                let blockchain = self.blockchain.lock().unwrap();
+                requested.retain(|hash| !blockchain.contains(hash));
+            }
+            self.save_checkpoint(&requested);
+
+            let height = {self.blockchain.lock().unwrap().height()};
+            if height > last_height {
+                last_height = height;
+                stalled = 0;
+                backoff.clear();
+                continue;
+            }
+            stalled += 1;
+            if stalled < self.stall_intervals {
+                continue;
+            }
+
+            // only a tip a quorum of peers reached, a single peer cannot make us resync
+            let best = {self.peer_tips.lock().unwrap().best_peer()};
+            if let Some((peer, peer_height, peer_tip)) = best {
+                if peer_height > height {
+                    let now = time::Instant::now();
+                    if !backoff.ready(&peer, now) {
+                        debug!("Not resyncing from {} yet, the last resync from it did not help", peer);
+                        continue;
+                    }
+                    warn!(
+                        "Tip stuck at height {} for {} block intervals while peers are at height {}, resyncing from {}",
+                        height, stalled, peer_height, peer
+                    );
+                    self.events.publish(Event::SyncStalled { height, peer_height });
+                    // handshake again, so peers renegotiate and report their tips afresh
+                    self.server.rehandshake();
+                    // asking for the peer tip walks back through the missing parents until we reconnect
+                    self.server.send_to(peer, Message::GetData(vec![InvItem::Block(peer_tip)]));
+                    if !requested.contains(&peer_tip) {
+                        requested.push(peer_tip);
+                    }
+                    backoff.failed(peer, now);
+                    stalled = 0;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn best_is_highest_tip_a_quorum_reached() {
+        let mut tips = PeerTips::with_limits(2, TIP_LIFETIME);
+        assert_eq!(tips.best(), None);
+        let first = "127.0.0.1:6001".parse().unwrap();
+        let second = "127.0.0.1:6002".parse().unwrap();
+        let third = "127.0.0.1:6003".parse().unwrap();
+        // a single peer's word is not enough
+        tips.update(first, 5, H256::from([1; 32]));
+        assert_eq!(tips.best(), None);
+        assert_eq!(tips.get(&first), Some((5, H256::from([1; 32]))));
+        tips.update(second, 3, H256::from([2; 32]));
+        assert_eq!(tips.best(), Some((3, H256::from([2; 32]))));
+        // a peer lying about a far higher tip changes nothing
+        tips.update(third, u32::MAX, H256::from([9; 32]));
+        assert_eq!(tips.best(), Some((5, H256::from([1; 32]))));
+        assert_eq!(tips.best_peer(), Some((first, 5, H256::from([1; 32]))));
+        // a peer's new report replaces its old one
+        tips.update(first, 2, H256::from([3; 32]));
+        assert_eq!(tips.best(), Some((3, H256::from([2; 32]))));
+        // a disconnected peer no longer counts
+        tips.forget(&second);
+        assert_eq!(tips.best(), Some((2, H256::from([3; 32]))));
+        assert_eq!(tips.get(&second), None);
+        // neither do tips not reported again in time
+        let mut stale = PeerTips::with_limits(1, time::Duration::ZERO);
+        stale.update(first, 5, H256::from([1; 32]));
+        assert_eq!(stale.best(), None);
+        assert_eq!(stale.get(&first), None);
+    }
+
+    #[test]
+    fn resyncs_from_a_peer_back_off() {
+        let now = time::Instant::now();
+        let base = time::Duration::from_secs(10);
+        let peer = "127.0.0.1:6001".parse().unwrap();
+        let other = "127.0.0.1:6002".parse().unwrap();
+        let mut backoff = ResyncBackoff::new(base);
+        assert!(backoff.ready(&peer, now));
+        backoff.failed(peer, now);
+        assert!(!backoff.ready(&peer, now + base - time::Duration::from_secs(1)));
+        assert!(backoff.ready(&peer, now + base));
+        assert!(backoff.ready(&other, now));
+        // the wait doubles with every failure, up to the cap
+        backoff.failed(peer, now);
+        assert!(!backoff.ready(&peer, now + base));
+        assert!(backoff.ready(&peer, now + base * 2));
+        for _ in 0..10 {
+            backoff.failed(peer, now);
+        }
+        assert!(backoff.ready(&peer, now + base * 2u32.pow(MAX_RESYNC_BACKOFF_EXPONENT)));
+        backoff.clear();
+        assert!(backoff.ready(&peer, now));
+    }
+
+    #[test]
+    fn gate_opens_with_enough_work_and_peers_reached() {
+        let now = time::Instant::now();
+        assert!(SyncGate::new(0.0).is_complete());
+        let mut gate = SyncGate::new(100.0);
+        assert!(!gate.update(50.0, 5, None, now));
+        // enough work, but peers are further ahead
+        assert!(!gate.update(150.0, 5, Some(8), now));
+        assert!(!gate.is_complete());
+        assert!(gate.update(200.0, 8, Some(8), now));
+        assert!(gate.is_complete());
+        // it stays open when we fall behind again, and only completes once
+        assert!(!gate.update(200.0, 8, Some(20), now));
+        assert!(gate.is_complete());
+    }
+
+    #[test]
+    fn gate_opens_when_peers_never_deliver() {
+        let now = time::Instant::now();
+        let timeout = time::Duration::from_secs(60);
+        let mut gate = SyncGate::new(100.0).with_timeout(timeout);
+        // the wait only starts once we have the minimum work
+        assert!(!gate.update(50.0, 5, Some(u32::MAX), now));
+        assert!(!gate.update(150.0, 5, Some(u32::MAX), now + timeout));
+        assert!(!gate.update(150.0, 6, Some(u32::MAX), now + timeout * 2 - time::Duration::from_secs(1)));
+        assert!(gate.update(150.0, 6, Some(u32::MAX), now + timeout * 2));
+        assert!(gate.is_complete());
+    }
+}
@@ -0,0 +1,206 @@
+use super::addrbook::AddressBook;
+use super::routing::RequestRouter;
+
+use serde::{Deserialize, Serialize};
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Most links accepted in one `Links` report
+pub const MAX_LINKS: usize = 1000;
+/// Reports of peers older than this are left out of the graph
+pub const REPORT_MAX_AGE: Duration = Duration::from_secs(300);
+
+/// A connection of a node, as the node reports it in `Links`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Link {
+    /// the address the peer listens at if the node knows it, else that of the connection
+    pub addr: SocketAddr,
+    /// whether the node dialed the peer
+    pub outgoing: bool,
+    /// moving average of the ping round trip time to the peer, in ms
+    pub rtt_ms: Option<u64>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct TopologyNode {
+    pub addr: SocketAddr,
+    /// connections between this node and the node asked for the graph, 0 for itself
+    pub hops: u32,
+    /// whether the links of the node are known, the others are only seen as the peers of one
+    pub reported: bool,
+}
+
+/// A connection, from the node that dialed it if known.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct TopologyEdge {
+    pub from: SocketAddr,
+    pub to: SocketAddr,
+    pub rtt_ms: Option<u64>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct TopologyGraph {
+    pub nodes: Vec<TopologyNode>,
+    pub edges: Vec<TopologyEdge>,
+}
+
+/// The links of the `connected` peers of this node, by the addresses of the connections, the
+/// ones in `book` being those it dialed, with the round trip times `router` measured
+pub fn connections(connected: Vec<SocketAddr>, book: Option<&AddressBook>, router: &RequestRouter) -> Vec<Link> {
+    connected
+        .into_iter()
+        .map(|addr| Link {
+            addr,
+            outgoing: book.is_some_and(|book| book.get(&addr).is_some()),
+            rtt_ms: router.rtt(&addr).map(|rtt| rtt.as_millis() as u64),
+        })
+        .collect()
+}
+
+/// The network as seen from this node: its own connections and the ones its peers report. A
+/// node is named by the address it listens at, which its peers learn from its reports, so a
+/// node that only accepted a connection can tell whom it leads to.
+pub struct Topology {
+    own: SocketAddr,
+    // the address each peer listens at, by the address of its connection
+    listen_addrs: HashMap<SocketAddr, SocketAddr>,
+    // the last links each peer reported and when, by the address of its connection
+    reports: HashMap<SocketAddr, (Instant, Vec<Link>)>,
+}
+
+impl Topology {
+    /// The topology around the node listening at `own`
+    pub fn new(own: SocketAddr) -> Self {
+        Topology { own, listen_addrs: HashMap::new(), reports: HashMap::new() }
+    }
+
+    /// The address this node listens at, None if it is bound to every interface and cannot tell
+    pub fn own(&self) -> Option<SocketAddr> {
+        if self.own.ip().is_unspecified() {
+            None
+        } else {
+            Some(self.own)
+        }
+    }
+
+    /// Record the `links` the peer connected from `peer` reported at `now`, and the address
+    /// `listen` it listens at
+    pub fn on_links(&mut self, peer: SocketAddr, listen: Option<SocketAddr>, links: Vec<Link>, now: Instant) {
+        if let Some(listen) = listen {
+            self.listen_addrs.insert(peer, listen);
+        }
+        self.reports.insert(peer, (now, links));
+    }
+
+    // the name of the node connected from `peer` in the graph
+    fn identify(&self, peer: &SocketAddr) -> SocketAddr {
+        self.listen_addrs.get(peer).copied().unwrap_or(*peer)
+    }
+
+    /// Our `connections` with the peers named by their listen addresses, as reported to the
+    /// peers. Peers that are no longer connected are forgotten.
+    pub fn local_links(&mut self, connections: Vec<Link>) -> Vec<Link> {
+        self.listen_addrs.retain(|peer, _| connections.iter().any(|link| link.addr == *peer));
+        self.reports.retain(|peer, _| connections.iter().any(|link| link.addr == *peer));
+        connections.into_iter().map(|link| Link { addr: self.identify(&link.addr), ..link }).collect()
+    }
+
+    /// The graph of `own_links` from `local_links` and the links the peers reported less than
+    /// `max_age` before `now`
+    pub fn graph(&self, own_links: &[Link], now: Instant, max_age: Duration) -> TopologyGraph {
+        // each connection once, whichever end reported it
+        let mut edges: BTreeMap<(SocketAddr, SocketAddr), TopologyEdge> = BTreeMap::new();
+        let mut add = |node: SocketAddr, link: &Link| {
+            if node == link.addr {
+                return;
+            }
+            let (from, to) = if link.outgoing { (node, link.addr) } else { (link.addr, node) };
+            let edge = edges.entry((node.min(link.addr), node.max(link.addr))).or_insert(TopologyEdge { from, to, rtt_ms: None });
+            edge.rtt_ms = edge.rtt_ms.or(link.rtt_ms);
+        };
+        let mut reported = BTreeSet::new();
+        reported.insert(self.own);
+        for link in own_links {
+            add(self.own, link);
+        }
+        for (peer, (at, links)) in self.reports.iter() {
+            if now.saturating_duration_since(*at) >= max_age {
+                continue;
+            }
+            let node = self.identify(peer);
+            reported.insert(node);
+            for link in links {
+                add(node, link);
+            }
+        }
+        let edges: Vec<TopologyEdge> = edges.into_values().collect();
+
+        let mut hops: BTreeMap<SocketAddr, u32> = BTreeMap::new();
+        hops.insert(self.own, 0);
+        let mut queue = VecDeque::from(vec![self.own]);
+        while let Some(node) = queue.pop_front() {
+            let next = hops[&node] + 1;
+            for edge in edges.iter() {
+                let other = match (edge.from == node, edge.to == node) {
+                    (true, _) => edge.to,
+                    (_, true) => edge.from,
+                    _ => continue,
+                };
+                if let Entry::Vacant(entry) = hops.entry(other) {
+                    entry.insert(next);
+                    queue.push_back(other);
+                }
+            }
+        }
+        let nodes = hops
+            .into_iter()
+            .map(|(addr, hops)| TopologyNode { addr, hops, reported: reported.contains(&addr) })
+            .collect();
+        TopologyGraph { nodes, edges }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn link(addr: &str, outgoing: bool, rtt_ms: Option<u64>) -> Link {
+        Link { addr: addr.parse().unwrap(), outgoing, rtt_ms }
+    }
+
+    #[test]
+    fn reports_join_into_one_graph() {
+        let own: SocketAddr = "127.0.0.1:6000".parse().unwrap();
+        let mut topology = Topology::new(own);
+        let now = Instant::now();
+        // we dialed 6001, 6002 dialed us from an ephemeral port
+        let own_links = vec![link("127.0.0.1:6001", true, Some(4)), link("127.0.0.1:50000", false, None)];
+        topology.on_links("127.0.0.1:6001".parse().unwrap(), Some("127.0.0.1:6001".parse().unwrap()), vec![
+            link("127.0.0.1:6000", false, Some(6)),
+            link("127.0.0.1:6003", true, Some(20)),
+        ], now);
+        topology.on_links("127.0.0.1:50000".parse().unwrap(), Some("127.0.0.1:6002".parse().unwrap()), vec![
+            link("127.0.0.1:6000", true, Some(9)),
+        ], now);
+        // a peer that disconnected is forgotten
+        topology.on_links("127.0.0.1:6009".parse().unwrap(), None, vec![link("127.0.0.1:6010", true, None)], now);
+        let own_links = topology.local_links(own_links);
+        assert_eq!(own_links[1].addr, "127.0.0.1:6002".parse().unwrap());
+
+        let graph = topology.graph(&own_links, now, REPORT_MAX_AGE);
+        let edges: Vec<(String, String, Option<u64>)> =
+            graph.edges.iter().map(|edge| (edge.from.to_string(), edge.to.to_string(), edge.rtt_ms)).collect();
+        assert_eq!(edges, vec![
+            ("127.0.0.1:6000".to_string(), "127.0.0.1:6001".to_string(), Some(4)),
+            ("127.0.0.1:6002".to_string(), "127.0.0.1:6000".to_string(), Some(9)),
+            ("127.0.0.1:6001".to_string(), "127.0.0.1:6003".to_string(), Some(20)),
+        ]);
+        let nodes: Vec<(u16, u32, bool)> = graph.nodes.iter().map(|node| (node.addr.port(), node.hops, node.reported)).collect();
+        assert_eq!(nodes, vec![(6000, 0, true), (6001, 1, true), (6002, 1, true), (6003, 2, false)]);
+        // stale reports are left out
+        let graph = topology.graph(&own_links, now + REPORT_MAX_AGE, REPORT_MAX_AGE);
+        assert_eq!(graph.edges.len(), 2);
+    }
+}
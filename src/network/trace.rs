@@ -0,0 +1,148 @@
+use super::peer;
+use super::worker::Worker;
+
+use log::{debug, error};
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// One inbound P2P message of a captured trace.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TraceRecord {
+    /// ms since the unix epoch when the message was received
+    pub timestamp: u64,
+    pub peer: SocketAddr,
+    /// the message as bincode, as it came off the wire
+    pub message: Vec<u8>,
+}
+
+/// File the inbound messages of a node are appended to, each record stored as bincode prefixed
+/// by its length as a big-endian u32.
+pub struct TraceWriter {
+    file: File,
+}
+
+impl TraceWriter {
+    /// Start a trace at `path`, replacing any previous one
+    pub fn create<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).truncate(true).write(true).open(path)?;
+        Ok(TraceWriter { file })
+    }
+
+    pub fn record(&mut self, peer: SocketAddr, message: &[u8]) -> io::Result<()> {
+        let record = TraceRecord {
+            timestamp: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64,
+            peer,
+            message: message.to_vec(),
+        };
+        let bytes = bincode::serialize(&record).unwrap();
+        let mut buffer = (bytes.len() as u32).to_be_bytes().to_vec();
+        buffer.extend_from_slice(&bytes);
+        self.file.write_all(&buffer)
+    }
+}
+
+/// Read the records of the trace at `path` in the order they were received. A record cut short
+/// because the node stopped while writing it is ignored.
+pub fn read<P: AsRef<Path>>(path: P) -> io::Result<Vec<TraceRecord>> {
+    let mut bytes = Vec::new();
+    File::open(path)?.read_to_end(&mut bytes)?;
+    let mut records = Vec::new();
+    let mut rest = bytes.as_slice();
+    while rest.len() >= 4 {
+        let length = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+        let record = match rest.get(4..4 + length) {
+            Some(record) => record,
+            None => break,
+        };
+        records.push(bincode::deserialize(record).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?);
+        rest = &rest[4 + length..];
+    }
+    Ok(records)
+}
+
+/// Record every message coming from `inbound` with `writer` before passing it on to the returned
+/// receiver, which the workers then read from instead.
+pub fn capture(
+    mut writer: TraceWriter,
+    inbound: smol::channel::Receiver<(Vec<u8>, peer::Handle)>,
+) -> smol::channel::Receiver<(Vec<u8>, peer::Handle)> {
+    let (sender, receiver) = smol::channel::bounded(inbound.capacity().unwrap_or(10000));
+    thread::Builder::new()
+        .name("trace-capture".to_string())
+        .spawn(move || {
+            while let Ok((msg, peer)) = smol::block_on(inbound.recv()) {
+                if let Err(e) = writer.record(*peer.addr(), &msg) {
+                    error!("Error capturing message from peer {}: {}", peer.addr(), e);
+                }
+                if smol::block_on(sender.send((msg, peer))).is_err() {
+                    break;
+                }
+            }
+        })
+        .unwrap();
+    receiver
+}
+
+/// Feed `records` through `worker` one at a time, in the order they were captured. Replies to
+/// peers and broadcasts are dropped, so replaying the same trace on the same chain always ends
+/// in the same state.
+pub fn replay(records: &[TraceRecord], worker: &Worker) {
+    let mut peers = HashMap::new();
+    for (i, record) in records.iter().enumerate() {
+        let peer = peers.entry(record.peer).or_insert_with(|| peer::Handle::detached(record.peer));
+        debug!("Replaying message {} from peer {} received at {}", i, record.peer, record.timestamp);
+        worker.handle_message(record.message.clone(), peer.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::{Blockchain, Mempool};
+    use crate::network::features::{PROTOCOL_VERSION, SUPPORTED_FEATURES};
+    use crate::network::message::Message;
+    use crate::types::block::TestBlockBuilder;
+    use crate::types::hash::Hashable;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn replay_captured_blocks() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("trace.bin");
+        let peer: SocketAddr = "127.0.0.1:6001".parse().unwrap();
+        let genesis = Blockchain::new(0).tip();
+        let difficulty = Blockchain::new(0).next_difficulty(&genesis);
+        let first = TestBlockBuilder::new(&genesis).length(1).difficulty(difficulty).solve_pow().build();
+        let second = TestBlockBuilder::new(&first.hash()).length(2).difficulty(difficulty).solve_pow().build();
+        let messages = [
+            Message::Version(PROTOCOL_VERSION, SUPPORTED_FEATURES, 0, genesis),
+            Message::Tip(2, second.hash()),
+            // the child arrives first and waits for its parent
+            Message::Blocks(vec![second.clone()]),
+            Message::Blocks(vec![first]),
+        ];
+        {
+            let mut writer = TraceWriter::create(&path).unwrap();
+            for message in messages.iter() {
+                writer.record(peer, &bincode::serialize(message).unwrap()).unwrap();
+            }
+            // the node stopped in the middle of a record
+            writer.file.write_all(&[0, 0, 0, 9, 1]).unwrap();
+        }
+        let records = read(&path).unwrap();
+        assert_eq!(records.len(), 4);
+        assert_eq!(records[0].peer, peer);
+
+        let blockchain = Arc::new(Mutex::new(Blockchain::new(0)));
+        let worker = Worker::offline(&blockchain, &Arc::new(Mutex::new(Mempool::new())));
+        replay(&records, &worker);
+        assert_eq!(blockchain.lock().unwrap().tip(), second.hash());
+    }
+}
@@ -0,0 +1,138 @@
+use crate::threads::Pool;
+use crate::types::hash::{H256, Hashable};
+use crate::types::transaction::{verify, SignedTransaction};
+
+use crossbeam::channel::{bounded, unbounded, Receiver, Sender};
+use log::{debug, info};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// The transactions of one message from `peer`, split by whether their signature is valid.
+#[derive(Debug, Clone)]
+pub struct Verified {
+    pub peer: SocketAddr,
+    pub valid: Vec<SignedTransaction>,
+    pub invalid: Vec<H256>,
+}
+
+/// Check the signature of every transaction `peer` sent
+pub fn verify_all(peer: SocketAddr, transactions: Vec<SignedTransaction>) -> Verified {
+    let (valid, invalid): (Vec<_>, Vec<_>) = transactions
+        .into_iter()
+        .partition(|tx| verify(&tx.t, &tx.signer_public_key, &tx.signature_vector));
+    Verified { peer, valid, invalid: invalid.iter().map(|tx| tx.hash()).collect() }
+}
+
+/// Verifies the signatures of gossiped transactions on threads of its own, so a burst of
+/// transactions does not hold up the message workers handling blocks. Messages are queued up to
+/// a capacity and dropped past it; their results come back on `results` in no particular order.
+#[derive(Clone)]
+pub struct VerifierPool {
+    jobs: Sender<(SocketAddr, Vec<SignedTransaction>)>,
+    results: Receiver<Verified>,
+    dropped: Arc<AtomicUsize>,
+    threads: Arc<Mutex<Pool>>,
+}
+
+impl VerifierPool {
+    /// Start `threads` verification threads, with room for `capacity` queued messages
+    pub fn start(threads: usize, capacity: usize) -> Self {
+        let (job_sender, job_receiver) = bounded::<(SocketAddr, Vec<SignedTransaction>)>(capacity);
+        let (result_sender, result_receiver) = unbounded();
+        let mut pool = Pool::new("tx-verifier", move |stop| {
+            while !stop.load(Ordering::Relaxed) {
+                let (peer, transactions) = match job_receiver.recv() {
+                    Ok(job) => job,
+                    Err(_) => break,
+                };
+                if result_sender.send(verify_all(peer, transactions)).is_err() {
+                    break;
+                }
+            }
+        });
+        pool.resize(threads);
+        info!("Verifying transaction signatures on {} threads", threads);
+        VerifierPool {
+            jobs: job_sender,
+            results: result_receiver,
+            dropped: Arc::new(AtomicUsize::new(0)),
+            threads: Arc::new(Mutex::new(pool)),
+        }
+    }
+
+    /// Queue the transactions of a message from `peer`, returns false if the queue is full and
+    /// they were dropped
+    pub fn submit(&self, peer: SocketAddr, transactions: Vec<SignedTransaction>) -> bool {
+        match self.jobs.try_send((peer, transactions)) {
+            Ok(()) => true,
+            Err(_) => {
+                debug!("Verification queue full, dropping transactions from peer {}", peer);
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                false
+            }
+        }
+    }
+
+    /// Verified messages, to be admitted to the mempool
+    pub fn results(&self) -> &Receiver<Verified> {
+        &self.results
+    }
+
+    /// Number of messages dropped because the queue was full
+    pub fn dropped(&self) -> usize {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// The verification threads, to resize while the node runs
+    pub fn thread_pool(&self) -> Arc<Mutex<Pool>> {
+        Arc::clone(&self.threads)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::key_pair;
+    use crate::types::transaction::{generate_random_transaction, sign};
+    use ring::signature::KeyPair;
+    use std::time::Duration;
+
+    fn signed(valid: bool) -> SignedTransaction {
+        let key = key_pair::random();
+        let t = generate_random_transaction();
+        let mut signature_vector = sign(&t, &key).as_ref().to_vec();
+        if !valid {
+            signature_vector[0] ^= 1;
+        }
+        SignedTransaction { t, signature_vector, signer_public_key: key.public_key().as_ref().to_vec() }
+    }
+
+    #[test]
+    fn pool_splits_valid_and_invalid() {
+        let pool = VerifierPool::start(2, 16);
+        let peer = "127.0.0.1:6001".parse().unwrap();
+        let bad = signed(false);
+        for _ in 0..4 {
+            assert!(pool.submit(peer, vec![signed(true), bad.clone()]));
+        }
+        for _ in 0..4 {
+            let verified = pool.results().recv_timeout(Duration::from_secs(5)).unwrap();
+            assert_eq!(verified.peer, peer);
+            assert_eq!(verified.valid.len(), 1);
+            assert_eq!(verified.invalid, vec![bad.hash()]);
+        }
+    }
+
+    #[test]
+    fn full_queue_drops() {
+        // nobody takes jobs from the queue, so it fills up
+        let (jobs, _queue) = bounded(1);
+        let threads = Arc::new(Mutex::new(Pool::new("tx-verifier", |_| ())));
+        let pool = VerifierPool { jobs, results: unbounded().1, dropped: Arc::new(AtomicUsize::new(0)), threads };
+        let peer = "127.0.0.1:6001".parse().unwrap();
+        assert!(pool.submit(peer, vec![signed(true)]));
+        assert!(!pool.submit(peer, vec![signed(true)]));
+        assert_eq!(pool.dropped(), 1);
+    }
+}
@@ -0,0 +1,170 @@
+use super::banlist::BanList;
+use super::clock::NetworkClock;
+use super::dustfilter::DustFilters;
+use super::encryption;
+use super::message::Message;
+use super::orphan::{OrphanResolver, OrphanStrategy};
+use super::server::{self, Handle as ServerHandle};
+use super::shared::Shared;
+use super::worker::Worker;
+use crate::blockchain::params::ChainParams;
+use crate::blockchain::{Blockchain, Mempool};
+use crate::events::EventBus;
+use crate::policy::RelayPolicy;
+use crate::types::hash::H256;
+
+use futures::io::{AsyncReadExt, AsyncWriteExt};
+use log::info;
+use ring::signature::Ed25519KeyPair;
+use smol::{Async, Timer};
+use std::io;
+use std::net::{SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+// the first frame of a tagged connection is a ping carrying this and the genesis hash in hex,
+// legacy peers answer it as any other ping
+const CHAIN_TAG_PREFIX: &str = "chain:";
+// how long the start of a connection may take to show whether it is tagged
+const TAG_TIMEOUT: Duration = Duration::from_secs(10);
+// time between looks at a connection whose first frame arrived only in part
+const TAG_POLL: Duration = Duration::from_millis(10);
+
+/// The frame a node starts its outgoing connections with, telling a peer hosting several
+/// networks on one port which of them the connection is for. Works like the server name of TLS:
+/// it is sent in plaintext, before the encryption handshake, and only picks the network.
+pub fn chain_tag(genesis: &H256) -> Vec<u8> {
+    let ping = bincode::serialize(&Message::Ping(format!("{}{}", CHAIN_TAG_PREFIX, genesis))).unwrap();
+    let mut frame = (ping.len() as u32).to_be_bytes().to_vec();
+    frame.extend(ping);
+    frame
+}
+
+/// Write the tag of the network with `genesis` at the start of an outgoing connection
+pub async fn write_chain_tag(stream: &mut &Async<TcpStream>, genesis: &H256) -> io::Result<()> {
+    stream.write_all(&chain_tag(genesis)).await?;
+    stream.flush().await
+}
+
+/// The genesis an incoming connection was tagged with, reading the tag off the connection. An
+/// untagged connection, from a node that predates tags, is left as it was and gives None.
+pub async fn read_chain_tag(stream: &mut &Async<TcpStream>) -> io::Result<Option<H256>> {
+    let timeout = async {
+        Timer::after(TAG_TIMEOUT).await;
+        Err(io::Error::new(io::ErrorKind::TimedOut, "chain tag timed out"))
+    };
+    smol::future::or(peek_chain_tag(stream), timeout).await
+}
+
+async fn peek_chain_tag(stream: &mut &Async<TcpStream>) -> io::Result<Option<H256>> {
+    // every tag has the same length, and the same bytes up to the hex digits of the genesis
+    let template = chain_tag(&H256::default());
+    let hex_start = template.len() - 64;
+    let mut peeked = vec![0; template.len()];
+    loop {
+        let len = stream.peek(&mut peeked).await?;
+        if len == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed before its first frame"));
+        }
+        let fixed = len.min(hex_start);
+        let hex_digits = peeked[fixed..len].iter().all(|byte| byte.is_ascii_hexdigit());
+        if peeked[..fixed] != template[..fixed] || !hex_digits {
+            return Ok(None);
+        }
+        if len == template.len() {
+            break;
+        }
+        // the start of a tag so far, the rest of it is on its way
+        Timer::after(TAG_POLL).await;
+    }
+    stream.read_exact(&mut peeked).await?;
+    let genesis = std::str::from_utf8(&peeked[hex_start..]).unwrap().parse::<H256>().unwrap();
+    Ok(Some(genesis))
+}
+
+/// A further network hosted by this process next to the one of its command line flags, e.g. one
+/// course testnet of several sharing a lab machine. It validates and relays blocks and
+/// transactions of its own chain with its own blockchain, mempool and P2P workers, but has no
+/// listener: the P2P server of the process hands it the connections tagged with its genesis.
+/// Mining, the wallet and the API stay with the main network.
+pub struct VirtualNetwork {
+    pub genesis: H256,
+    pub server: ServerHandle,
+    pub blockchain: Arc<Mutex<Blockchain>>,
+}
+
+impl VirtualNetwork {
+    /// Start the network described by `params`, with `num_worker` P2P workers. `p2p_addr` is the
+    /// address of the shared listener and `encryption` that of its connections. The bans of
+    /// `bans` hold for this network too.
+    pub fn start(
+        params: &ChainParams,
+        p2p_addr: SocketAddr,
+        num_worker: usize,
+        policy: &RelayPolicy,
+        clock: &Arc<Mutex<NetworkClock>>,
+        encryption: Option<(encryption::Mode, Ed25519KeyPair)>,
+        bans: &Arc<Mutex<BanList>>,
+    ) -> io::Result<Self> {
+        let blockchain = Arc::new(Mutex::new(Blockchain::with_params(0, params)));
+        let genesis = {blockchain.lock().unwrap().genesis()};
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        let shared = Shared {
+            orphans: Arc::new(Mutex::new(OrphanResolver::new(OrphanStrategy::AllPeers, 100))),
+            clock: Arc::clone(clock),
+            ..Shared::new(&blockchain, &mempool)
+        };
+        let (msg_tx, msg_rx) = smol::channel::bounded(10000);
+        let (server_ctx, server) = server::new(p2p_addr, msg_tx, &shared, &EventBus::new())?;
+        let server_ctx = match encryption {
+            Some((mode, key)) => server_ctx.with_encryption(mode, key),
+            None => server_ctx,
+        };
+        let dust_filters = Arc::new(Mutex::new(DustFilters::new()));
+        let server_ctx = server_ctx.with_dust_filters(&dust_filters, &mempool).with_ban_list(bans);
+        server_ctx.start_without_listener();
+        Worker::new(num_worker, msg_rx, &server, &shared, policy)
+        .with_dust_filters(&dust_filters)
+        .start();
+        info!("Hosting the network with genesis {} at {}", genesis, p2p_addr);
+        Ok(VirtualNetwork { genesis, server, blockchain })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    fn tag_of(first_bytes: &[u8]) -> Option<H256> {
+        let listener = Async::<TcpListener>::bind(([127, 0, 0, 1], 0)).unwrap();
+        let addr = listener.get_ref().local_addr().unwrap();
+        smol::block_on(async {
+            let mut dialer = Async::<TcpStream>::connect(addr).await.unwrap();
+            dialer.write_all(first_bytes).await.unwrap();
+            let (stream, _) = listener.accept().await.unwrap();
+            let tag = read_chain_tag(&mut &stream).await.unwrap();
+            // whatever follows the tag, or an untagged start, is still there to read
+            let mut rest = vec![0; first_bytes.len() - tag.map_or(0, |_| chain_tag(&H256::default()).len())];
+            (&stream).read_exact(&mut rest).await.unwrap();
+            assert!(first_bytes.ends_with(&rest));
+            tag
+        })
+    }
+
+    #[test]
+    fn connections_are_routed_by_chain_tag() {
+        let genesis = Blockchain::new(0).genesis();
+        let frame = |message: &Message| {
+            let bytes = bincode::serialize(message).unwrap();
+            [(bytes.len() as u32).to_be_bytes().to_vec(), bytes].concat()
+        };
+        let version = frame(&Message::Version(2, Default::default(), 0, genesis));
+        let mut tagged = chain_tag(&genesis);
+        tagged.extend(&version);
+        assert_eq!(tag_of(&tagged), Some(genesis));
+        // a legacy node starts with its Version, or with a ping that is not a tag
+        assert_eq!(tag_of(&version), None);
+        assert_eq!(tag_of(&frame(&Message::Ping("encrypt:00".to_string()))), None);
+    }
+}
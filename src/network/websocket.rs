@@ -0,0 +1,246 @@
+use super::addrbook;
+use super::banlist::{self, BanList};
+use super::message::{self, Message};
+use super::outbound::OutboundReceiver;
+use super::peer;
+use super::ratelimit::RateLimiter;
+
+use futures::FutureExt;
+use log::{debug, info, trace, warn};
+use std::io;
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use tungstenite::handshake::server::{Callback, ErrorResponse, Request, Response};
+use tungstenite::http::StatusCode;
+use tungstenite::WebSocket;
+
+/// Longest a client may take to complete the WebSocket handshake
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// how long a relay waits for a message from its peer before writing what was queued for it
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+// messages written to a peer between two reads from it
+const WRITE_BATCH: usize = 64;
+
+/// Path WebSocket peers exchanging JSON connect to, e.g. ws://127.0.0.1:8000/json
+pub const JSON_PATH: &str = "/json";
+
+/// How the messages of a WebSocket peer are encoded, picked by the path it connects to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    /// binary messages holding the bincode frame of a `Message`, like the TCP frames
+    Binary,
+    /// text messages holding a `Message` as JSON, for nodes written in other languages, see
+    /// `message::to_json`
+    Json,
+}
+
+/// Accept peers connecting over WebSocket on `listener`, for clients that cannot open raw TCP
+/// connections, such as browsers or nodes behind a proxy letting only HTTP out. Every binary
+/// WebSocket message is one encoded `Message`, without the length prefix of the TCP frames; with
+/// `json`, peers connecting to `JSON_PATH` exchange text messages of JSON instead. The
+/// connections of banned addresses are dropped, the others are handed to `accepted` once the
+/// handshake completes.
+pub fn listen<F>(listener: TcpListener, ban_list: Option<Arc<Mutex<BanList>>>, json: bool, accepted: F)
+where
+    F: Fn(WebSocket<TcpStream>, Encoding) + Clone + Send + 'static,
+{
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("Error accepting WebSocket peer: {}", e);
+                    continue;
+                }
+            };
+            let addr = match stream.peer_addr() {
+                Ok(addr) => addrbook::canonical(addr),
+                Err(_) => continue,
+            };
+            let ban = ban_list.as_ref().and_then(|bans| bans.lock().unwrap().check(&addr.ip(), banlist::now()));
+            if let Some(ban) = ban {
+                debug!("Refusing WebSocket peer {}, banned: {}", addr, ban.reason);
+                continue;
+            }
+            info!("Incoming WebSocket peer from {}", addr);
+            let accepted = accepted.clone();
+            // a client that never completes the handshake holds up nobody else
+            thread::spawn(move || match handshake(stream, json) {
+                Ok((socket, encoding)) => accepted(socket, encoding),
+                Err(e) => warn!("WebSocket handshake with peer {} failed: {}", addr, e),
+            });
+        }
+    });
+}
+
+// picks the encoding a connecting peer asked for by the path of its handshake request
+struct PickEncoding<'a> {
+    json: bool,
+    encoding: &'a mut Encoding,
+}
+
+impl Callback for PickEncoding<'_> {
+    fn on_request(self, request: &Request, response: Response) -> Result<Response, ErrorResponse> {
+        if request.uri().path() != JSON_PATH {
+            return Ok(response);
+        }
+        if !self.json {
+            let mut refusal = ErrorResponse::new(Some("JSON messages are not enabled on this node".to_string()));
+            *refusal.status_mut() = StatusCode::NOT_FOUND;
+            return Err(refusal);
+        }
+        *self.encoding = Encoding::Json;
+        Ok(response)
+    }
+}
+
+// upgrade the connection of a peer to WebSocket, giving up after HANDSHAKE_TIMEOUT, and tell
+// the encoding it asked for; JSON is refused unless `json`
+fn handshake(stream: TcpStream, json: bool) -> io::Result<(WebSocket<TcpStream>, Encoding)> {
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT))?;
+    let mut encoding = Encoding::Binary;
+    let pick = PickEncoding { json, encoding: &mut encoding };
+    let socket = tungstenite::accept_hdr(stream, pick).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+    socket.get_ref().set_read_timeout(None)?;
+    Ok((socket, encoding))
+}
+
+/// Pass the messages of the peer on `socket` to `new_msg_chan`, as the reader of a TCP peer
+/// does, and write what is queued for it in `write_queue`. Returns once the connection closes.
+pub fn relay(
+    mut socket: WebSocket<TcpStream>,
+    encoding: Encoding,
+    handle: peer::Handle,
+    write_queue: OutboundReceiver,
+    new_msg_chan: &smol::channel::Sender<(Vec<u8>, peer::Handle)>,
+    rate_limiter: &Arc<Mutex<RateLimiter>>,
+) {
+    let addr = *handle.addr();
+    // one thread reads and writes, reads time out so queued messages are not held up
+    if let Err(e) = socket.get_ref().set_read_timeout(Some(POLL_INTERVAL)) {
+        warn!("Disconnecting WebSocket peer {}: {}", addr, e);
+        return;
+    }
+    loop {
+        for _ in 0..WRITE_BATCH {
+            match write_queue.next().now_or_never() {
+                Some(Some(buffer)) => {
+                    let message = match encoding {
+                        Encoding::Binary => tungstenite::Message::Binary(buffer),
+                        Encoding::Json => match message::to_json(buffer) {
+                            Ok(text) => tungstenite::Message::Text(text),
+                            Err(e) => {
+                                warn!("Not sending a message to WebSocket peer {}: {}", addr, e);
+                                continue;
+                            }
+                        },
+                    };
+                    if let Err(e) = socket.write_message(message) {
+                        debug!("WebSocket peer {} went away: {}", addr, e);
+                        return;
+                    }
+                }
+                // the peer was dropped
+                Some(None) => return,
+                None => break,
+            }
+        }
+        let payload = match socket.read_message() {
+            Ok(tungstenite::Message::Binary(payload)) => payload,
+            Ok(tungstenite::Message::Text(text)) if encoding == Encoding::Json => match message::from_json(&text) {
+                Ok(payload) => payload,
+                Err(e) => {
+                    warn!("Disconnecting WebSocket peer {}: {}", addr, e);
+                    return;
+                }
+            },
+            Ok(tungstenite::Message::Close(_)) => return,
+            // pings are answered by tungstenite, text is only part of the protocol with JSON
+            Ok(_) => continue,
+            Err(tungstenite::Error::Io(e)) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => continue,
+            Err(e) => {
+                debug!("WebSocket peer {} went away: {}", addr, e);
+                return;
+            }
+        };
+        let payload = match message::decompress(payload) {
+            Ok(payload) => payload,
+            Err(e) => {
+                warn!("Disconnecting WebSocket peer {}: {}", addr, e);
+                return;
+            }
+        };
+        // message types we do not know share one limit, the worker skips them anyway
+        let name = Message::peek_name(&payload).unwrap_or("Unknown");
+        if !rate_limiter.lock().unwrap().admit(addr, name, Instant::now()) {
+            trace!("Dropping {} from peer {} over its rate limit", name, addr);
+            continue;
+        }
+        if smol::block_on(new_msg_chan.send((payload, handle.clone()))).is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_travel_as_binary_frames() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (sockets, accepted) = smol::channel::unbounded();
+        listen(listener, None, false, move |socket, _| smol::block_on(sockets.send(socket)).unwrap());
+        let (mut client, _) = tungstenite::connect(format!("ws://127.0.0.1:{}", port)).unwrap();
+
+        let socket = smol::block_on(accepted.recv()).unwrap();
+        let (write_queue, mut handle) = peer::with_socket(addrbook::canonical(socket.get_ref().peer_addr().unwrap()), socket.get_ref()).unwrap();
+        let (msg_sink, messages) = smol::channel::unbounded();
+        let rate_limiter = Arc::new(Mutex::new(RateLimiter::new()));
+        let relay_handle = handle.clone();
+        let relay = thread::spawn(move || relay(socket, Encoding::Binary, relay_handle, write_queue, &msg_sink, &rate_limiter));
+
+        client.write_message(tungstenite::Message::Binary(Message::Ping("hello".to_string()).encode())).unwrap();
+        let (payload, _) = smol::block_on(messages.recv()).unwrap();
+        assert!(matches!(Message::decode(&payload), Ok(Message::Ping(nonce)) if nonce == "hello"));
+        handle.write(Message::Pong("hello".to_string()));
+        match client.read_message().unwrap() {
+            tungstenite::Message::Binary(payload) => assert!(matches!(Message::decode(&payload), Ok(Message::Pong(nonce)) if nonce == "hello")),
+            other => panic!("expected a binary message, got {:?}", other),
+        }
+        // the relay ends with the connection, the server then drops the peer
+        client.close(None).unwrap();
+        relay.join().unwrap();
+    }
+
+    #[test]
+    fn json_peers_exchange_text() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (sockets, accepted) = smol::channel::unbounded();
+        listen(listener, None, true, move |socket, encoding| smol::block_on(sockets.send((socket, encoding))).unwrap());
+        let (mut client, _) = tungstenite::connect(format!("ws://127.0.0.1:{}{}", port, JSON_PATH)).unwrap();
+        let (socket, encoding) = smol::block_on(accepted.recv()).unwrap();
+        assert_eq!(encoding, Encoding::Json);
+        let (write_queue, mut handle) = peer::with_socket(addrbook::canonical(socket.get_ref().peer_addr().unwrap()), socket.get_ref()).unwrap();
+        let (msg_sink, messages) = smol::channel::unbounded();
+        let rate_limiter = Arc::new(Mutex::new(RateLimiter::new()));
+        let relay_handle = handle.clone();
+        thread::spawn(move || relay(socket, encoding, relay_handle, write_queue, &msg_sink, &rate_limiter));
+
+        client.write_message(tungstenite::Message::Text(r#"{"Ping":"hello"}"#.to_string())).unwrap();
+        let (payload, _) = smol::block_on(messages.recv()).unwrap();
+        assert!(matches!(Message::decode(&payload), Ok(Message::Ping(nonce)) if nonce == "hello"));
+        handle.write(Message::GetTip);
+        assert_eq!(client.read_message().unwrap(), tungstenite::Message::Text(r#""GetTip""#.to_string()));
+
+        // JSON is refused by a node that did not enable it
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        listen(listener, None, false, |_, _| panic!("JSON peer accepted"));
+        assert!(tungstenite::connect(format!("ws://127.0.0.1:{}{}", port, JSON_PATH)).is_err());
+    }
+}
@@ -1,19 +1,57 @@
-use super::message::Message;
+use super::addrbook::{canonical, is_own_address, AddressBook, MAX_ADDRESSES, RELAY_ADDRESSES, RELAY_FANOUT};
+use super::message::{self, DecodeError, InvItem, Message, MAX_BLOCKS_PER_MESSAGE, MAX_LOCATOR_HASHES, MEMPOOL_INV_CHUNK};
 use super::peer;
 use super::server::Handle as ServerHandle;
+use super::shared::Shared;
+use super::catchup::{Transition, TxGossipPause};
+use super::sync::{PeerTips, SyncGate};
+use super::verifier::{self, Verified, VerifierPool};
+use super::clock::NetworkClock;
+use super::download::{self, DownloadScheduler};
+use super::dustfilter::DustFilters;
+use super::markers;
+use super::features::PeerFeatures;
+use super::gossip::RecentlyAnnounced;
+use super::keepalive::KeepAlive;
+use super::chain_proofs::ChainProofRequests;
+use super::routing::RequestRouter;
+use super::inflight::InFlightBlocks;
+use super::attribution::FirstSeen;
+use super::eviction::LastUseful;
+use super::reconcile::{Outcome, Reconciler};
+use super::topology::{self, Topology, MAX_LINKS};
+use super::features::{check_version, Features, Incompatible};
+#[cfg(any(test,test_utilities))]
+use super::features::{PROTOCOL_VERSION, SUPPORTED_FEATURES};
+use super::orphan::{OrphanResolver, OrphanStrategy};
+use super::failure_log::{self, FailureLog, ValidationFailure};
+use crate::threads::Pool;
 use crate::types::address::Address;
+use crate::types::amount::Amount;
 use crate::types::block::Block;
 use crate::types::hash::{H256, Hashable};
+use crate::types::pow::PowHash;
+use crate::types::chain_proof::MAX_CHAIN_PROOF_DRAWS;
 use crate::blockchain::{Blockchain, Mempool, State};
+use crate::blockchain::validators::Validators;
+use crate::blockchain::invariants;
+use crate::policy::RelayPolicy;
+use crate::metrics::{Marker, MetricsHistory};
+use crate::metrics::validation::{BlockMetrics, BlockMetricsLog};
 use crate::types::transaction::{Transaction, SignedTransaction, sign};
-use std::collections::HashMap;
 use std::convert::{TryInto, TryFrom};
 use std::io::{self, Write};
 use std::thread::{self, current};
+use std::collections::HashSet;
+use std::collections::hash_map::Entry;
+use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{self, Duration};
 use ring::signature::{Ed25519KeyPair, Signature, self};
+use rand::seq::SliceRandom;
 
-use log::{debug, warn, error};
+use log::{debug, info, trace, warn, error};
 
 #[cfg(any(test,test_utilities))]
 use super::peer::TestReceiver as PeerTestReceiver;
@@ -26,11 +64,31 @@ pub struct Worker {
     server: ServerHandle,
     wrapped_blockchain: Arc<Mutex<Blockchain>>, 
     wrapped_mempool: Arc<Mutex<Mempool>>,
-}
-
-#[derive(Clone)]
-pub struct OrphanBuffer {
-    pub hash_map: HashMap<H256, Block>,
+    policy: RelayPolicy,
+    peer_tips: Arc<Mutex<PeerTips>>,
+    peer_features: Arc<Mutex<PeerFeatures>>,
+    orphans: Arc<Mutex<OrphanResolver>>,
+    peer_states: Arc<Mutex<peer::PeerStates>>,
+    gossip: Arc<Mutex<RecentlyAnnounced>>,
+    reconciler: Arc<Mutex<Reconciler>>,
+    clock: Arc<Mutex<NetworkClock>>,
+    router: Arc<Mutex<RequestRouter>>,
+    block_requests: Arc<Mutex<InFlightBlocks>>,
+    failure_log: Arc<Mutex<FailureLog>>,
+    sync_gate: Arc<Mutex<SyncGate>>,
+    verifier: Option<VerifierPool>,
+    address_book: Option<(Arc<Mutex<AddressBook>>, SocketAddr)>,
+    metrics: Option<Arc<Mutex<MetricsHistory>>>,
+    block_metrics: Option<Arc<Mutex<BlockMetricsLog>>>,
+    first_seen: Option<Arc<Mutex<FirstSeen>>>,
+    last_useful: Option<Arc<Mutex<LastUseful>>>,
+    downloads: Option<Arc<Mutex<DownloadScheduler>>>,
+    dust_filters: Option<Arc<Mutex<DustFilters>>>,
+    topology: Option<Arc<Mutex<Topology>>>,
+    keepalive: Option<Arc<Mutex<KeepAlive>>>,
+    chain_proofs: Option<Arc<Mutex<ChainProofRequests>>>,
+    tx_pause: Option<Arc<Mutex<TxGossipPause>>>,
+    compression: bool,
 }
 
 impl Worker {
@@ -38,35 +96,323 @@ impl Worker {
         num_worker: usize,
         msg_src: smol::channel::Receiver<(Vec<u8>, peer::Handle)>,
         server: &ServerHandle,
-        wrapped_blockchain: &Arc<Mutex<Blockchain>>, 
-        wrapped_mempool: &Arc<Mutex<Mempool>>, 
+        shared: &Shared,
+        policy: &RelayPolicy,
     ) -> Self {
         Self {
             msg_chan: msg_src,
             num_worker,
             server: server.clone(),
-            wrapped_blockchain: wrapped_blockchain.clone(),
-            wrapped_mempool: wrapped_mempool.clone()
+            wrapped_blockchain: Arc::clone(&shared.blockchain),
+            wrapped_mempool: Arc::clone(&shared.mempool),
+            policy: policy.clone(),
+            peer_tips: Arc::clone(&shared.peer_tips),
+            peer_features: Arc::clone(&shared.peer_features),
+            orphans: Arc::clone(&shared.orphans),
+            peer_states: Arc::clone(&shared.peer_states),
+            gossip: Arc::clone(&shared.gossip),
+            reconciler: Arc::clone(&shared.reconciler),
+            clock: Arc::clone(&shared.clock),
+            router: Arc::new(Mutex::new(RequestRouter::new())),
+            block_requests: Arc::new(Mutex::new(InFlightBlocks::new(Duration::from_secs(5)))),
+            // repeated validation failures are summarized once a minute
+            failure_log: Arc::new(Mutex::new(FailureLog::new(Duration::from_secs(60)))),
+            sync_gate: Arc::new(Mutex::new(SyncGate::default())),
+            verifier: None,
+            address_book: None,
+            metrics: None,
+            block_metrics: None,
+            first_seen: None,
+            last_useful: None,
+            downloads: None,
+            dust_filters: None,
+            topology: None,
+            keepalive: None,
+            chain_proofs: None,
+            tx_pause: None,
+            compression: true,
+        }
+    }
+
+    /// A worker without a network, its messages are handed to `handle_message` on the calling
+    /// thread and whatever it sends is dropped. Used to replay a captured trace and by the self-test.
+    pub fn offline(blockchain: &Arc<Mutex<Blockchain>>, mempool: &Arc<Mutex<Mempool>>) -> Self {
+        let (_, msg_rx) = smol::channel::unbounded();
+        Worker::new(1, msg_rx, &ServerHandle::detached(), &Shared::new(blockchain, mempool), &RelayPolicy::default())
+    }
+
+    /// Measure the round trip times of the peers in `router`, shared with the topology report
+    pub fn with_router(mut self, router: &Arc<Mutex<RequestRouter>>) -> Self {
+        self.router = Arc::clone(router);
+        self
+    }
+
+    /// Ask only one peer at a time for a block several peers lead us to, as tracked by
+    /// `block_requests`
+    pub fn with_block_requests(mut self, block_requests: &Arc<Mutex<InFlightBlocks>>) -> Self {
+        self.block_requests = Arc::clone(block_requests);
+        self
+    }
+
+    /// Tell the peers supporting it whom this node is connected to, and record what they tell
+    /// about themselves in `topology`
+    pub fn with_topology(mut self, topology: &Arc<Mutex<Topology>>) -> Self {
+        self.topology = Some(Arc::clone(topology));
+        self
+    }
+
+    /// Report the answers to pings to `keepalive`, which disconnects peers that stop answering
+    pub fn with_keepalive(mut self, keepalive: &Arc<Mutex<KeepAlive>>) -> Self {
+        self.keepalive = Some(Arc::clone(keepalive));
+        self
+    }
+
+    /// Check the chain proofs our peers send for the requests in `chain_proofs`
+    pub fn with_chain_proofs(mut self, chain_proofs: &Arc<Mutex<ChainProofRequests>>) -> Self {
+        self.chain_proofs = Some(Arc::clone(chain_proofs));
+        self
+    }
+
+    /// Serve blocks only once `sync_gate` reports the initial sync complete
+    pub fn with_sync_gate(mut self, sync_gate: &Arc<Mutex<SyncGate>>) -> Self {
+        self.sync_gate = Arc::clone(sync_gate);
+        self
+    }
+
+    /// Verify the signatures of gossiped transactions on `verifier` instead of the worker
+    /// threads. Without one they are verified inline, as when replaying a trace.
+    pub fn with_verifier(mut self, verifier: &VerifierPool) -> Self {
+        self.verifier = Some(verifier.clone());
+        self
+    }
+
+    /// Exchange peer addresses with the peers supporting it: tell them this node is reachable at
+    /// `listen_addr`, ask the ones we dial whom they know and add what they answer to `book`
+    pub fn with_address_book(mut self, book: &Arc<Mutex<AddressBook>>, listen_addr: SocketAddr) -> Self {
+        self.address_book = Some((Arc::clone(book), listen_addr));
+        self
+    }
+
+    /// Record the experiment markers peers send in `metrics` and relay them
+    pub fn with_metrics(mut self, metrics: &Arc<Mutex<MetricsHistory>>) -> Self {
+        self.metrics = Some(Arc::clone(metrics));
+        self
+    }
+
+    /// Record in `block_metrics` the size of each block received from a peer and what it took
+    /// to validate it
+    pub fn with_block_metrics(mut self, block_metrics: &Arc<Mutex<BlockMetricsLog>>) -> Self {
+        self.block_metrics = Some(Arc::clone(block_metrics));
+        self
+    }
+
+    /// Record in `first_seen` which peer first delivered each block and transaction we accept
+    pub fn with_first_seen(mut self, first_seen: &Arc<Mutex<FirstSeen>>) -> Self {
+        self.first_seen = Some(Arc::clone(first_seen));
+        self
+    }
+
+    /// Record in `last_useful` when each peer was last credited with a block or transaction,
+    /// along with `first_seen`
+    pub fn with_last_useful(mut self, last_useful: &Arc<Mutex<LastUseful>>) -> Self {
+        self.last_useful = Some(Arc::clone(last_useful));
+        self
+    }
+
+    /// During the initial sync, spread the requests for missing blocks over the ready peers
+    /// with `downloads` instead of asking the peer that announced them
+    pub fn with_downloads(mut self, downloads: &Arc<Mutex<DownloadScheduler>>) -> Self {
+        self.downloads = Some(Arc::clone(downloads));
+        self
+    }
+
+    /// Record the dust thresholds peers send in `filters`, and leave the transactions below a
+    /// peer's threshold out of the mempool we announce to it
+    pub fn with_dust_filters(mut self, filters: &Arc<Mutex<DustFilters>>) -> Self {
+        self.dust_filters = Some(Arc::clone(filters));
+        self
+    }
+
+    /// Ignore the transactions peers relay while `pause` says we are far behind, and ask the
+    /// ready peers for their mempool once we caught up
+    pub fn with_tx_pause(mut self, pause: &Arc<Mutex<TxGossipPause>>) -> Self {
+        self.tx_pause = Some(Arc::clone(pause));
+        self
+    }
+
+    /// Compress what is written to the peers that negotiated compression, unless the server
+    /// does not offer it
+    pub fn with_compression(mut self, compression: bool) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    // whether transaction gossip is ignored, as we are catching up on blocks; once caught up,
+    // the transactions relayed in the meantime are fetched from the mempools of the ready peers
+    fn tx_gossip_paused(&self) -> bool {
+        let pause = match &self.tx_pause {
+            Some(pause) => pause,
+            None => return false,
+        };
+        let height = {self.wrapped_blockchain.lock().unwrap().height()};
+        let peer_height = {self.peer_tips.lock().unwrap().best().map(|(height, _)| height)};
+        let (paused, transition) = {pause.lock().unwrap().update(height, peer_height)};
+        match transition {
+            Some(Transition::Paused) => info!("Ignoring transaction gossip while catching up from height {} to {}", height, peer_height.unwrap()),
+            Some(Transition::Resumed) => {
+                let skipped = {pause.lock().unwrap().skipped()};
+                info!("Caught up at height {}, resuming transaction gossip after ignoring {} messages", height, skipped);
+                let ready = {self.peer_states.lock().unwrap().ready()};
+                let syncing: Vec<SocketAddr> = {
+                    let features = self.peer_features.lock().unwrap();
+                    ready.into_iter().filter(|addr| features.supports(addr, Features::MEMPOOL_SYNC)).collect()
+                };
+                for addr in syncing {
+                    self.server.send_to(addr, Message::GetMempool);
+                }
+            }
+            None => {}
         }
+        paused
     }
 
-    pub fn start(self) {
-        let num_worker = self.num_worker;
-        for i in 0..num_worker {
-            let cloned = self.clone();
-            thread::spawn(move || {
-                cloned.worker_loop();
-                warn!("Worker thread {} exited", i);
-            });
+    // count a message of transaction gossip ignored while catching up
+    fn skip_tx_gossip(&self, peer: &peer::Handle, what: &str) {
+        debug!("Ignoring {} from peer {} while catching up", what, peer.addr());
+        if let Some(pause) = &self.tx_pause {
+            pause.lock().unwrap().skip();
         }
     }
 
-    fn worker_loop(&self) {
-        let mut orphanbuffer = OrphanBuffer {
-            hash_map: HashMap::new(),
+    // the scheduler to fetch missing blocks with, while the initial sync is running
+    fn sync_downloads(&self) -> Option<&Arc<Mutex<DownloadScheduler>>> {
+        match &self.downloads {
+            Some(downloads) if !self.sync_gate.lock().unwrap().is_complete() => Some(downloads),
+            _ => None,
+        }
+    }
+
+    /// Start the worker threads, returns their pool to resize it while the node runs
+    pub fn start(self) -> Arc<Mutex<Pool>> {
+        let worker = self.clone();
+        let pool = Arc::new(Mutex::new(Pool::new("p2p-worker", move |stop| worker.worker_loop(stop))));
+        {pool.lock().unwrap().resize(self.num_worker)};
+        failure_log::start_flusher(&self.failure_log);
+        if let Some(verifier) = self.verifier.clone() {
+            thread::Builder::new()
+                .name("tx-admission".to_string())
+                .spawn(move || {
+                    for verified in verifier.results().iter() {
+                        self.admit_transactions(verified);
+                    }
+                })
+                .unwrap();
+        }
+        pool
+    }
+
+    // the peer has these transactions, do not announce them back to it
+    fn mark_seen<I: Iterator<Item = H256>>(&self, peer: &peer::Handle, hashes: I) {
+        let now = std::time::Instant::now();
+        let mut gossip = self.gossip.lock().unwrap();
+        for hash in hashes {
+            gossip.mark(*peer.addr(), hash, now);
+        }
+    }
+
+    // requests for these transactions are answered, by `peer` if they were asked of it
+    fn mark_received<I: Iterator<Item = H256>>(&self, peer: &peer::Handle, hashes: I) {
+        let now = time::Instant::now();
+        let mut router = self.router.lock().unwrap();
+        for hash in hashes {
+            router.on_received_from(*peer.addr(), &InvItem::Transaction(hash), now);
+        }
+    }
+
+    // `peer` delivered these, it is credited with those no peer delivered before once accepted
+    fn mark_delivered<I: Iterator<Item = InvItem>>(&self, peer: &peer::Handle, items: I) {
+        if let Some(first_seen) = &self.first_seen {
+            let mut first_seen = first_seen.lock().unwrap();
+            for item in items {
+                first_seen.on_delivered(item, *peer.addr());
+            }
+        }
+    }
+
+    // these were accepted, credit the peers that first delivered them
+    fn mark_accepted<'a, I: Iterator<Item = &'a InvItem>>(&self, items: I) {
+        if let Some(first_seen) = &self.first_seen {
+            let mut credited = Vec::new();
+            {
+                let mut first_seen = first_seen.lock().unwrap();
+                for item in items {
+                    first_seen.on_accepted(*item);
+                    credited.extend(first_seen.source(item));
+                }
+            }
+            if let Some(last_useful) = &self.last_useful {
+                let now = time::Instant::now();
+                let mut last_useful = last_useful.lock().unwrap();
+                for peer in credited {
+                    last_useful.on_useful(peer, now);
+                }
+            }
+        }
+    }
+
+    // ask a peer whose tip is ahead of ours for the blocks after the point our chains fork at
+    fn request_blocks_if_behind(&self, peer: &mut peer::Handle) {
+        let peer_tip = {self.peer_tips.lock().unwrap().get(peer.addr())};
+        let (height, tip) = match peer_tip {
+            Some(peer_tip) => peer_tip,
+            None => return,
+        };
+        if !{self.peer_features.lock().unwrap().supports(peer.addr(), Features::BLOCK_LOCATOR)} {
+            return;
+        }
+        let locator = {
+            let blockchain = self.wrapped_blockchain.lock().unwrap();
+            if height <= blockchain.height() || blockchain.contains(&tip) {
+                return;
+            }
+            blockchain.locator()
         };
-        
-        loop {
+        // every peer reporting the same new tip would otherwise send us the same blocks
+        if !{self.block_requests.lock().unwrap().request(tip, time::Instant::now())} {
+            return;
+        }
+        debug!("Asking peer {} at height {} for the blocks after our locator", peer.addr(), height);
+        peer.write(Message::GetBlocksAfter(locator));
+    }
+
+    // stop exchanging anything with a peer on another chain or protocol, messages of it still
+    // queued are dropped and the server forgets it once the connection is closed
+    fn refuse(&self, peer: &peer::Handle, reason: Incompatible) {
+        warn!("Disconnecting peer {}: {}", peer.addr(), reason);
+        {self.peer_states.lock().unwrap().drain(*peer.addr())};
+        peer.disconnect();
+    }
+
+    // keepalive pings carry their send time in ms since the unix epoch, answers to them give the
+    // round trip time to the peer and, with its time, the offset of its clock to ours
+    fn on_keepalive_answer(&self, peer: &peer::Handle, nonce: &str, peer_time: Option<u128>) {
+        let sent = match nonce.parse::<u128>() {
+            Ok(sent) => sent,
+            Err(_) => return,
+        };
+        let now = time::SystemTime::now().duration_since(time::UNIX_EPOCH).unwrap().as_millis();
+        if let Some(keepalive) = &self.keepalive {
+            keepalive.lock().unwrap().on_answer(*peer.addr(), sent);
+        }
+        let rtt = Duration::from_millis(now.saturating_sub(sent) as u64);
+        {self.router.lock().unwrap().on_pong(*peer.addr(), rtt)};
+        if let Some(peer_time) = peer_time {
+            {self.clock.lock().unwrap().on_sample(*peer.addr(), sent, peer_time, now)};
+        }
+    }
+
+    fn worker_loop(&self, stop: &AtomicBool) {
+        while !stop.load(Ordering::Relaxed) {
             let result = smol::block_on(self.msg_chan.recv());
             if let Err(e) = result {
                 error!("network worker terminated {}", e);
@@ -74,230 +420,784 @@ impl Worker {
             }
 
             let msg = result.unwrap();
-            let (msg, mut peer) = msg;
-            let msg: Message = bincode::deserialize(&msg).unwrap();
-            match msg {
-                Message::Ping(nonce) => {
-                    debug!("Ping: {}", nonce);
-                    peer.write(Message::Pong(nonce.to_string()));
-                }
-                Message::Pong(nonce) => {
-                    debug!("Pong: {}", nonce);
-                }
-                Message::NewBlockHashes(hashvec) => {
-                    let mut new_hashes = Vec::<H256>::new();
-                    {
-                        let blockchain = self.wrapped_blockchain.lock().unwrap();
-                        for hash in hashvec {
-                            // println!(" does blockchain contain this hash: {}", {self.wrapped_blockchain.lock().unwrap().hash_map.contains_key(&hash)});
-                            if !blockchain.hash_map.contains_key(&hash) {
-                                new_hashes.push(hash);
+            let (msg, peer) = msg;
+            self.handle_message(msg, peer);
+        }
+    }
+
+    /// Handle the message `msg`, as bincode, received from `peer`. Messages are handled one at a
+    /// time by each worker thread, the replay of a captured trace calls this directly.
+    pub fn handle_message(&self, msg: Vec<u8>, peer: peer::Handle) {
+        let msg = match Message::decode(&msg) {
+            Ok(msg) => msg,
+            // a newer peer may send message types we do not know yet, the rest of what it sends still counts
+            Err(DecodeError::Unknown(id)) => {
+                debug!("Skipping a message of unknown type {} from peer {}", id, peer.addr());
+                return;
+            }
+            // the Version of an older protocol no longer decodes, its version number still does
+            Err(DecodeError::Malformed("Version", _)) if msg.len() >= 8 => {
+                let version = bincode::deserialize(&msg[4..8]).unwrap();
+                self.refuse(&peer, Incompatible::Version(version));
+                return;
+            }
+            Err(e) => {
+                warn!("Disconnecting peer {}: {}", peer.addr(), e);
+                {self.peer_states.lock().unwrap().drain(*peer.addr())};
+                peer.disconnect();
+                return;
+            }
+        };
+        self.handle_decoded(msg, peer);
+    }
+
+    // handle `msg` once decoded, also called for the messages held until the Version of `peer` was handled
+    fn handle_decoded(&self, msg: Message, mut peer: peer::Handle) {
+        // handlers below can assume the peer went through the handshake it needs
+        let (was_ready, transition) = {
+            let mut states = self.peer_states.lock().unwrap();
+            let was_ready = states.state(peer.addr()) == peer::State::Ready;
+            let transition = states.on_message(*peer.addr(), &msg);
+            // taken by this worker before another one handled the peer's Version
+            if transition.is_err() && states.hold(*peer.addr(), &msg) {
+                debug!("Holding {} from peer {} until its Version is handled", msg.name(), peer.addr());
+                return;
+            }
+            (was_ready, transition)
+        };
+        if let Err(state) = transition {
+            warn!("Dropping out-of-order {} from peer {} in state {:?}", msg.name(), peer.addr(), state);
+            return;
+        }
+        match msg {
+            Message::Ping(nonce) => {
+                debug!("Ping: {}", nonce);
+                if self.peer_features.lock().unwrap().supports(peer.addr(), Features::CLOCK_SYNC) {
+                    let now = time::SystemTime::now().duration_since(time::UNIX_EPOCH).unwrap().as_millis();
+                    peer.write(Message::Clock(nonce, now));
+                } else {
+                    peer.write(Message::Pong(nonce));
+                }
+            }
+            Message::Pong(nonce) => {
+                debug!("Pong: {}", nonce);
+                self.on_keepalive_answer(&peer, &nonce, None);
+            }
+            Message::Clock(nonce, peer_time) => {
+                debug!("Clock: {} at {}", nonce, peer_time);
+                self.on_keepalive_answer(&peer, &nonce, Some(peer_time));
+            }
+            Message::Version(version, features, height, genesis) => {
+                let our_genesis = {self.wrapped_blockchain.lock().unwrap().genesis()};
+                if let Err(e) = check_version(version, &genesis, &our_genesis) {
+                    self.refuse(&peer, e);
+                    return;
+                }
+                let (negotiated, written_version) = {
+                    let mut peer_features = self.peer_features.lock().unwrap();
+                    (peer_features.negotiate(*peer.addr(), features), peer_features.negotiate_version(*peer.addr(), version))
+                };
+                // every message written to the peer from now on is one its version knows
+                peer.set_protocol_version(written_version);
+                peer.set_compression(self.compression && negotiated.contains(Features::COMPRESSION));
+                debug!("Peer {} at height {} speaks version {}, negotiated features {:?}", peer.addr(), height, version, negotiated);
+                if negotiated.contains(Features::TX_RECONCILIATION) {
+                    {self.reconciler.lock().unwrap().enable(*peer.addr())};
+                }
+                peer.write(Message::Verack);
+                if negotiated.contains(Features::DUST_FILTER) && !self.policy.dust_threshold.is_zero() {
+                    peer.write(Message::DustFilter(self.policy.dust_threshold));
+                }
+                // the peer is ready once we know its tip
+                peer.write(Message::GetTip);
+                if let (true, Some((book, listen_addr))) = (negotiated.contains(Features::PEER_EXCHANGE), &self.address_book) {
+                    // a node bound to every interface cannot tell which address reaches it
+                    if !listen_addr.ip().is_unspecified() {
+                        peer.write(Message::Addr(vec![*listen_addr]));
+                    }
+                    let dialed = book.lock().unwrap().get(peer.addr()).is_some();
+                    if dialed {
+                        peer.write(Message::GetAddr);
+                    }
+                }
+                // the answer tells the address an incoming peer listens at
+                if negotiated.contains(Features::TOPOLOGY) && self.topology.is_some() {
+                    peer.write(Message::GetLinks);
+                }
+                let held = {self.peer_states.lock().unwrap().negotiated(*peer.addr())};
+                for msg in held {
+                    self.handle_decoded(msg, peer.clone());
+                }
+            }
+            Message::Verack => {
+                debug!("Peer {} accepted our version", peer.addr());
+            }
+            Message::GetAddr => {
+                if let Some((book, _)) = &self.address_book {
+                    let addresses = {book.lock().unwrap().addresses(MAX_ADDRESSES)};
+                    peer.write(Message::Addr(addresses));
+                }
+            }
+            Message::Addr(addresses) => {
+                let (book, listen_addr) = match &self.address_book {
+                    Some(address_book) => address_book,
+                    None => return,
+                };
+                if addresses.len() > MAX_ADDRESSES {
+                    warn!("Ignoring {} addresses from peer {}, more than {}", addresses.len(), peer.addr(), MAX_ADDRESSES);
+                    return;
+                }
+                let relayed = addresses.len() <= RELAY_ADDRESSES;
+                let unique: HashSet<SocketAddr> = addresses.into_iter().map(canonical).collect();
+                let learned: Vec<SocketAddr> = {
+                    let mut book = book.lock().unwrap();
+                    unique.into_iter().filter(|addr| !is_own_address(addr, listen_addr) && book.learn(*addr)).collect()
+                };
+                if learned.is_empty() {
+                    return;
+                }
+                info!("Learned {} peer addresses from {}", learned.len(), peer.addr());
+                // pass fresh advertisements on, not answers to GetAddr. Only addresses new to us
+                // are relayed, so an advertisement stops spreading once every node knows it.
+                if relayed {
+                    let peers = {self.peer_features.lock().unwrap().peers_supporting(Features::PEER_EXCHANGE)};
+                    let others: Vec<SocketAddr> = peers.into_iter().filter(|other| other != peer.addr()).collect();
+                    for other in others.choose_multiple(&mut rand::thread_rng(), RELAY_FANOUT) {
+                        self.server.send_to(*other, Message::Addr(learned.clone()));
+                    }
+                }
+            }
+            Message::Marker(marker) => {
+                let metrics = match &self.metrics {
+                    Some(metrics) => metrics,
+                    None => return,
+                };
+                if let Err(e) = Marker::check_name(&marker.name) {
+                    warn!("Ignoring marker from peer {}: {}", peer.addr(), e);
+                    return;
+                }
+                markers::record_and_relay(&marker, Some(*peer.addr()), metrics, &self.server, &self.peer_features);
+            }
+            Message::ReconcileRequest(their_size) => {
+                let sketch = {self.reconciler.lock().unwrap().sketch(*peer.addr(), their_size)};
+                match sketch {
+                    Some(sketch) => peer.write(Message::ReconcileSketch(sketch)),
+                    None => debug!("Ignoring reconciliation request of peer {}, we do not reconcile with it", peer.addr()),
+                }
+            }
+            Message::ReconcileSketch(sketch) => {
+                let outcome = {self.reconciler.lock().unwrap().reconcile(*peer.addr(), &sketch)};
+                match outcome {
+                    Some(Outcome::Reconciled { announce, request }) => {
+                        debug!("Reconciled with peer {}: announcing {}, requesting {}", peer.addr(), announce.len(), request.len());
+                        if !announce.is_empty() {
+                            peer.write(Message::Inv(announce.into_iter().map(InvItem::Transaction).collect()));
+                        }
+                        peer.write(Message::ReconcileDiff(request));
+                    }
+                    Some(Outcome::Failed { announce }) => {
+                        // fall back to flooding both sets
+                        debug!("Reconciliation with peer {} failed, flooding {} transactions", peer.addr(), announce.len());
+                        if !announce.is_empty() {
+                            peer.write(Message::Inv(announce.into_iter().map(InvItem::Transaction).collect()));
+                        }
+                        peer.write(Message::ReconcileFailed);
+                    }
+                    None => debug!("Ignoring sketch of peer {}, not one we asked for", peer.addr()),
+                }
+            }
+            Message::ReconcileDiff(ids) => {
+                let announce = {self.reconciler.lock().unwrap().requested(*peer.addr(), &ids)};
+                if let Some(announce) = announce.filter(|announce| !announce.is_empty()) {
+                    peer.write(Message::Inv(announce.into_iter().map(InvItem::Transaction).collect()));
+                }
+            }
+            Message::ReconcileFailed => {
+                let announce = {self.reconciler.lock().unwrap().failed(*peer.addr())};
+                if let Some(announce) = announce.filter(|announce| !announce.is_empty()) {
+                    peer.write(Message::Inv(announce.into_iter().map(InvItem::Transaction).collect()));
+                }
+            }
+            Message::GetTip => {
+                let (height, tip) = {
+                    let blockchain = self.wrapped_blockchain.lock().unwrap();
+                    (blockchain.height(), blockchain.tip())
+                };
+                peer.write(Message::Tip(height, tip));
+            }
+            Message::GetStateProof(address, block_hash) => {
+                let proof = {self.wrapped_blockchain.lock().unwrap().state_proof(&address, &block_hash)};
+                peer.write(Message::StateProof(proof));
+            }
+            Message::StateProof(_) => {
+                // full nodes never ask for state proofs, only light wallets consume them
+                debug!("Unsolicited state proof from peer {}", peer.addr());
+            }
+            Message::Tip(height, tip) => {
+                debug!("Peer {} at height {}", peer.addr(), height);
+                {self.peer_tips.lock().unwrap().update(*peer.addr(), height, tip)};
+                self.request_blocks_if_behind(&mut peer);
+                // the peer now takes our announcements, catch up on the transactions it has
+                // that were relayed before we connected
+                // while catching up it is asked once we caught up instead
+                let paused = self.tx_gossip_paused();
+                if !was_ready && !paused && {self.peer_features.lock().unwrap().supports(peer.addr(), Features::MEMPOOL_SYNC)} {
+                    peer.write(Message::GetMempool);
+                }
+            }
+            Message::GetMempool => {
+                let threshold = match &self.dust_filters {
+                    Some(filters) => filters.lock().unwrap().threshold(peer.addr()),
+                    None => Amount::ZERO,
+                };
+                // lowest account nonces first, so transactions come before the ones spending after them
+                let mut hashes: Vec<(usize, H256)> = {
+                    let mempool = self.wrapped_mempool.lock().unwrap();
+                    mempool
+                        .hash_map
+                        .iter()
+                        .filter(|(_, transaction)| self.policy.accepts(transaction) && transaction.t.value >= threshold)
+                        .map(|(hash, transaction)| (transaction.t.account_nonce, *hash))
+                        .collect()
+                };
+                hashes.sort();
+                debug!("Announcing the {} transactions of our mempool to peer {}", hashes.len(), peer.addr());
+                self.mark_seen(&peer, hashes.iter().map(|(_, hash)| *hash));
+                for chunk in hashes.chunks(MEMPOOL_INV_CHUNK) {
+                    peer.write(Message::Inv(chunk.iter().map(|(_, hash)| InvItem::Transaction(*hash)).collect()));
+                }
+            }
+            Message::DustFilter(threshold) => {
+                if let Some(filters) = &self.dust_filters {
+                    debug!("Peer {} filters transactions below {}", peer.addr(), threshold);
+                    filters.lock().unwrap().set(*peer.addr(), threshold);
+                }
+            }
+            Message::GetLinks => {
+                let topology = match &self.topology {
+                    Some(topology) => topology,
+                    None => return,
+                };
+                let connected: Vec<SocketAddr> = {self.peer_states.lock().unwrap().connected()}.into_iter().map(|(addr, _)| addr).collect();
+                let connections = {
+                    let book = self.address_book.as_ref().map(|(book, _)| book.lock().unwrap());
+                    let router = self.router.lock().unwrap();
+                    topology::connections(connected, book.as_deref(), &router)
+                };
+                let (own, mut links) = {
+                    let mut topology = topology.lock().unwrap();
+                    (topology.own(), topology.local_links(connections))
+                };
+                links.truncate(MAX_LINKS);
+                peer.write(Message::Links(own, links));
+            }
+            Message::Links(listen, links) => {
+                let topology = match &self.topology {
+                    Some(topology) => topology,
+                    None => return,
+                };
+                if links.len() > MAX_LINKS {
+                    warn!("Ignoring {} links from peer {}, more than {}", links.len(), peer.addr(), MAX_LINKS);
+                    return;
+                }
+                debug!("Peer {} listening at {:?} reports {} links", peer.addr(), listen, links.len());
+                topology.lock().unwrap().on_links(*peer.addr(), listen, links, time::Instant::now());
+            }
+            Message::GetChainProof(seed, draws) => {
+                if draws > MAX_CHAIN_PROOF_DRAWS {
+                    debug!("Peer {} asked for a chain proof of {} draws, more than {}", peer.addr(), draws, MAX_CHAIN_PROOF_DRAWS);
+                    peer.write(Message::ChainProof(None));
+                    return;
+                }
+                let proof = {self.wrapped_blockchain.lock().unwrap().chain_proof(&seed, draws)};
+                peer.write(Message::ChainProof(Some(proof)));
+            }
+            Message::Reject(item, code) => {
+                warn!("Peer {} rejected our {:?}: {}", peer.addr(), item, code);
+            }
+            Message::Compressed(_) => {
+                // the readers decompress frames before they get here, this one came from a trace
+                debug!("Skipping a compressed frame from peer {}", peer.addr());
+            }
+            Message::NewBlockHashes(hashes) => {
+                // the announcement of the course protocol, answered in kind
+                self.mark_seen(&peer, hashes.iter().cloned());
+                let wanted: Vec<H256> = {
+                    let blockchain = self.wrapped_blockchain.lock().unwrap();
+                    hashes.into_iter().filter(|hash| !blockchain.contains(hash)).collect()
+                };
+                if !wanted.is_empty() {
+                    peer.write(Message::GetBlocks(wanted));
+                }
+            }
+            Message::GetBlocks(hashes) => {
+                // while syncing we only have part of the chain, the peer should ask a synced node
+                if !self.sync_gate.lock().unwrap().is_complete() {
+                    return;
+                }
+                let blocks: Vec<Block> = {
+                    let blockchain = self.wrapped_blockchain.lock().unwrap();
+                    hashes.iter().filter_map(|hash| blockchain.block(hash)).collect()
+                };
+                for page in message::block_pages(blocks) {
+                    peer.write(Message::Blocks(page));
+                }
+            }
+            Message::ChainProof(proof) => {
+                let chain_proofs = match &self.chain_proofs {
+                    Some(chain_proofs) => chain_proofs,
+                    None => return,
+                };
+                let request = chain_proofs.lock().unwrap().answered(peer.addr());
+                let (seed, draws) = match request {
+                    Some(request) => request,
+                    None => {
+                        debug!("Unsolicited chain proof from peer {}", peer.addr());
+                        return;
+                    }
+                };
+                let (genesis, pow) = {
+                    let blockchain = self.wrapped_blockchain.lock().unwrap();
+                    (blockchain.genesis(), blockchain.pow())
+                };
+                let result = match proof {
+                    Some(proof) => proof.verify(&seed, draws, &genesis, &pow).map_err(|e| e.to_string()),
+                    None => Err("the peer refused to prove its chain".to_string()),
+                };
+                match &result {
+                    Ok(summary) => info!("Peer {} proved a chain of height {} with about {:.0} work", peer.addr(), summary.height, summary.estimated_work),
+                    Err(e) => warn!("Invalid chain proof from peer {}: {}", peer.addr(), e),
+                }
+                chain_proofs.lock().unwrap().on_result(*peer.addr(), result);
+            }
+            Message::GetBlocksAfter(locator) => {
+                if locator.len() > MAX_LOCATOR_HASHES {
+                    debug!("Locator of {} hashes from peer {} is too long", locator.len(), peer.addr());
+                    return;
+                }
+                // while syncing we only have part of the chain, the peer should ask a synced node
+                if !self.sync_gate.lock().unwrap().is_complete() {
+                    return;
+                }
+                // one block past a page shows whether the peer has to ask for more
+                let blocks = {self.wrapped_blockchain.lock().unwrap().blocks_after(&locator, MAX_BLOCKS_PER_MESSAGE + 1)};
+                let mut pages = message::block_pages(blocks).into_iter();
+                if let Some(page) = pages.next() {
+                    let last = page.last().unwrap().hash();
+                    peer.write(Message::Blocks(page));
+                    if pages.next().is_some() {
+                        peer.write(Message::MoreBlocks(last));
+                    }
+                }
+            }
+            Message::MoreBlocks(last) => {
+                // continue after the last block of the page, or from our own fork point if the
+                // peer switched chains since
+                let mut locator = {self.wrapped_blockchain.lock().unwrap().locator()};
+                locator.insert(0, last);
+                locator.truncate(MAX_LOCATOR_HASHES);
+                debug!("Asking peer {} for the blocks after {}", peer.addr(), last);
+                peer.write(Message::GetBlocksAfter(locator));
+            }
+            Message::Inv(items) => {
+                self.mark_seen(&peer, items.iter().map(|item| match item {
+                    InvItem::Transaction(hash) | InvItem::Block(hash) => *hash,
+                }));
+                // ask for every announced object we do not have yet
+                let mut wanted = Vec::new();
+                for item in items {
+                    let known = match item {
+                        InvItem::Block(hash) => self.wrapped_blockchain.lock().unwrap().contains(&hash),
+                        InvItem::Transaction(hash) => self.wrapped_mempool.lock().unwrap().hash_map.contains_key(&hash),
+                    };
+                    if !known {
+                        wanted.push(item);
+                    }
+                }
+                if wanted.iter().any(|item| matches!(item, InvItem::Transaction(_))) && self.tx_gossip_paused() {
+                    self.skip_tx_gossip(&peer, "transaction announcements");
+                    wanted.retain(|item| matches!(item, InvItem::Block(_)));
+                }
+                if let Some(downloads) = self.sync_downloads() {
+                    let mut scheduled = false;
+                    wanted.retain(|item| match item {
+                        InvItem::Block(hash) => {
+                            downloads.lock().unwrap().want(*hash, Some(*peer.addr()));
+                            scheduled = true;
+                            false
+                        }
+                        InvItem::Transaction(_) => true,
+                    });
+                    if scheduled {
+                        download::dispatch(downloads, &self.peer_states, &self.router, &self.server);
+                    }
+                }
+                // ask the fastest peer that announced each object, not necessarily this one
+                let routes = {
+                    let states = self.peer_states.lock().unwrap();
+                    let mut router = self.router.lock().unwrap();
+                    router.route(*peer.addr(), &wanted, time::Instant::now(), |addr| states.state(addr) == peer::State::Ready)
+                };
+                for (addr, items) in routes {
+                    if addr == *peer.addr() {
+                        peer.write(Message::GetData(items));
+                    } else {
+                        self.server.send_to(addr, Message::GetData(items));
+                    }
+                }
+            }
+            Message::GetData(items) => {
+                let mut blocks = Vec::new();
+                let mut transactions = Vec::new();
+                let mut not_found = Vec::new();
+                // while syncing we only have part of the chain, the peer should ask a synced node
+                let syncing = !self.sync_gate.lock().unwrap().is_complete();
+                for item in items {
+                    match item {
+                        InvItem::Block(_) if syncing => not_found.push(item),
+                        InvItem::Block(hash) => {
+                            let blockchain = self.wrapped_blockchain.lock().unwrap();
+                            // a block whose body was pruned cannot be served
+                            match blockchain.block(&hash) {
+                                Some(block) => blocks.push(block),
+                                None => not_found.push(item),
+                            }
+                        }
+                        InvItem::Transaction(hash) => {
+                            let mempool = self.wrapped_mempool.lock().unwrap();
+                            match mempool.hash_map.get(&hash) {
+                                // only relay transactions our policy would admit
+                                Some(transaction) if self.policy.accepts(transaction) => transactions.push(transaction.clone()),
+                                _ => not_found.push(item),
                             }
                         }
                     }
-                    if new_hashes.len() > 0 {
-                        peer.write(Message::GetBlocks(new_hashes));
+                }
+                for page in message::block_pages(blocks) {
+                    peer.write(Message::Blocks(page));
+                }
+                if transactions.len() > 0 {
+                    peer.write(Message::Transactions(transactions));
+                }
+                if not_found.len() > 0 {
+                    peer.write(Message::NotFound(not_found));
+                }
+            }
+            Message::NotFound(items) => {
+                debug!("Peer {} does not have {:?}", peer.addr(), items);
+                {
+                    let mut router = self.router.lock().unwrap();
+                    for item in items.iter() {
+                        router.on_not_found(*peer.addr(), item);
                     }
                 }
-                Message::GetBlocks(hashvec) => {
-                    let mut blocks = Vec::new();
-                    {
-                        let blockchain = self.wrapped_blockchain.lock().unwrap();
-                        for hash in hashvec {
-                            if blockchain.hash_map.contains_key(&hash){ 
-                                let block_response = blockchain.hash_map.get(&hash).unwrap().clone();
-                                blocks.push(block_response.clone());
-                            } 
+                if let Some(downloads) = &self.downloads {
+                    let mut downloads = downloads.lock().unwrap();
+                    for item in items.iter() {
+                        if let InvItem::Block(hash) = item {
+                            downloads.on_not_found(*peer.addr(), hash);
+                        }
+                    }
+                }
+            }
+
+            Message::Blocks(blockvec) => {
+                // the peer needs none of these pushed back
+                self.mark_seen(&peer, blockvec.iter().map(|block| block.hash()));
+                self.mark_delivered(&peer, blockvec.iter().map(|block| InvItem::Block(block.hash())));
+                let now = time::Instant::now();
+                // the blocks the download scheduler asked this peer for time its round trip too
+                let mut download_rtts = Vec::new();
+                if let Some(downloads) = &self.downloads {
+                    let mut downloads = downloads.lock().unwrap();
+                    for block in blockvec.iter() {
+                        if let Some((asked, since)) = downloads.requested_from(&block.hash()) {
+                            if asked == *peer.addr() {
+                                download_rtts.push(now.saturating_duration_since(since));
+                            }
                         }
+                        downloads.on_received(&block.hash());
                     }
-                    if blocks.len() > 0 {
-                        peer.write(Message::Blocks(blocks));
+                }
+                {
+                    let mut router = self.router.lock().unwrap();
+                    let mut block_requests = self.block_requests.lock().unwrap();
+                    for block in blockvec.iter() {
+                        router.on_received_from(*peer.addr(), &InvItem::Block(block.hash()), now);
+                        block_requests.on_received(&block.hash());
+                    }
+                    for rtt in download_rtts {
+                        router.on_pong(*peer.addr(), rtt);
                     }
                 }
+                let mut new_hashes = Vec::<H256>::new();
+                let mut parent_vec = Vec::new();
+                // Check the block before inserting the block into blockchain
+                let pow = {self.wrapped_blockchain.lock().unwrap().pow()};
+                let (received_at, validation_time) = {
+                    let mut clock = self.clock.lock().unwrap();
+                    (clock.now() as u64, clock.validation_time())
+                };
+                for block in blockvec {
+                    let validation_start = time::Instant::now();
+                    // Check if the block passed POW difficulty check
+                    let pow_passed = pow.meets_target(&block.header);
+                    if !pow_passed {
+                        self.reject(*peer.addr(), InvItem::Block(block.hash()), ValidationFailure::BadPow);
+                    }
 
-                Message::Blocks(blockvec) => {
-                    let mut new_hashes = Vec::<H256>::new();
-                    let mut parent_vec = Vec::new();
-                    // Check the block before inserting the block into blockchain
-                    for block in blockvec {
-                        // Check if the block passed POW difficulty check
-                        let pow_passed = block.hash() <= block.get_difficulty();
-                        
-                        // Check if transactions in a block are valid
-                        let block_clone = block.clone(); 
-                        let signed_transactions = block_clone.content.transactions;
-
-                        // After updating the mempool, proceed to insert the block
-                        // If the blockchain does not already contain the block
-                        {
-                            let mut blockchain = self.wrapped_blockchain.lock().unwrap();
-                            if !blockchain.hash_map.contains_key(&block.hash()) && pow_passed {
-                                
-                                // But contains the block's parent, add the block to the blockchain and remove the block's transactions from the mempool
-                                if blockchain.hash_map.contains_key(&block.get_parent()) {
-                                    // get the state of the blockchain tip based on the block's parent
-                                    let parent = block.get_parent();
-                                    let state_copy = blockchain.state_map.get(&parent).unwrap().clone();
-                                    
-                                    let mut all_transactions_valid = true;
-                                    // Check the block's transactions - if any transaction if invalid, skip the entire block
-                                    for signed_transaction in signed_transactions {
-                                        // by first checking if transaction signature is valid
-                                        if !verify(&signed_transaction.t, &signed_transaction.signer_public_key, &signed_transaction.signature_vector) {
-                                            all_transactions_valid = false;
-                                            break;
-                                        }
+                    // After updating the mempool, proceed to insert the block
+                    // If the blockchain does not already contain the block
+                    {
+                        let mut blockchain = self.wrapped_blockchain.lock().unwrap();
+                        if !blockchain.contains(&block.hash()) && pow_passed {
+                            
+                            // But contains the block's parent, add the block to the blockchain and remove the block's transactions from the mempool
+                            if blockchain.contains(&block.get_parent()) {
+                                let signature_ops = match validate_block(&blockchain, &block, validation_time) {
+                                    Ok(signature_ops) => signature_ops,
+                                    Err(reason) => {
+                                        self.reject(*peer.addr(), InvItem::Block(block.hash()), reason);
+                                        continue;
+                                    }
+                                };
+                                blockchain.insert(&block.clone());
+                                new_hashes.push(block.hash()); 
+                                if let Some(block_metrics) = &self.block_metrics {
+                                    let metrics = BlockMetrics {
+                                        size: bincode::serialized_size(&block).unwrap(),
+                                        transactions: block.content.transactions.len(),
+                                        signature_ops,
+                                        validation_us: validation_start.elapsed().as_micros() as u64,
+                                        received_at,
+                                    };
+                                    block_metrics.lock().unwrap().record(block.hash(), metrics);
+                                }
 
-                                        let sender = Address::from_public_key_bytes(signed_transaction.signer_public_key.as_slice());
-                                        let amount = signed_transaction.t.value;
-                                        let nonce = signed_transaction.t.account_nonce;
-                                        
-                                        // check if the state agrees with the validity of the transaction
-                                        if state_copy.state.contains_key(&sender) {
-                                            // spending check
-                                            if amount > state_copy.state.get(&sender).unwrap().1 || nonce != state_copy.state.get(&sender).unwrap().0 + 1{
-                                                all_transactions_valid = false;
-                                                break;
-                                            }
-                                        }
-                                        else {
-                                            all_transactions_valid = false;
-                                            break;
+                                // remove the block's transactions from the mempool after inserting the block to the blockchain
+                                let transactions = block.clone().content.transactions;
+                                {
+                                    let mut mempool = self.wrapped_mempool.lock().unwrap();
+                                    for signed_transaction in transactions {
+                                        if mempool.hash_map.contains_key(&signed_transaction.hash()) {
+                                        mempool.hash_map.remove(&signed_transaction.hash());
                                         }
                                     }
-
-                                    if all_transactions_valid {
-                                        blockchain.insert(&block.clone());
-                                        new_hashes.push(block.hash()); 
-
-                                        // remove the block's transactions from the mempool after inserting the block to the blockchain
-                                        let transactions = block.clone().content.transactions;
-                                        {
-                                            let mut mempool = self.wrapped_mempool.lock().unwrap();
-                                            for signed_transaction in transactions {
-                                                if mempool.hash_map.contains_key(&signed_transaction.hash()) {
-                                                mempool.hash_map.remove(&signed_transaction.hash());
-                                                }
-                                            }
-                                        
-                                            // After inserting the block, update the mempool based on the new tip (Transaction Mempool Update)
-                                            let tip = blockchain.tip();
-                                            let new_state_copy = blockchain.state_map.get(&tip).unwrap().clone();
-                                            for (hash, signed_transaction) in mempool.hash_map.clone() {
-                                                let sender = Address::from_public_key_bytes(signed_transaction.signer_public_key.as_slice());
-                                                let tx_nonce = signed_transaction.t.account_nonce;
-                                                if new_state_copy.state.contains_key(&sender) {
-                                                    let nonce = new_state_copy.state.get(&sender).unwrap().0;
-                                                    if tx_nonce < nonce {
-                                                        mempool.hash_map.remove(&hash);
-                                                    }
-                                                }
+                                
+                                    // After inserting the block, update the mempool based on the new tip (Transaction Mempool Update)
+                                    let tip = blockchain.tip();
+                                    let new_state_copy = blockchain.state_map.get(&tip).unwrap().clone();
+                                    for (hash, signed_transaction) in mempool.hash_map.clone() {
+                                        let sender = Address::from_public_key_bytes(signed_transaction.signer_public_key.as_slice());
+                                        let tx_nonce = signed_transaction.t.account_nonce;
+                                        if new_state_copy.state.contains_key(&sender) {
+                                            let nonce = new_state_copy.state.get(&sender).unwrap().0;
+                                            if tx_nonce < nonce {
+                                                mempool.hash_map.remove(&hash);
                                             }
                                         }
                                     }
-                                    // if a block contains at least one invalid transaction, skip the entire block
-                                    else {
-                                        continue;
-                                    }
-                                }
-
-                                // if the new block is the parent of any block in the buffer
-                                let mut parent_hash = block.hash();
-                                while orphanbuffer.hash_map.contains_key(&parent_hash) {
-                                    
-                                    let removed_hash = parent_hash; // the hash to be removed from the buffer
-                                    let selected_block = orphanbuffer.hash_map.get(&parent_hash);
-                                    let selected_block_option = Option::expect(selected_block, "block not found");
-                                    blockchain.insert(&selected_block_option.clone()); // add the block to your blockchain
-                                    new_hashes.push(selected_block_option.clone().hash());
-
-                                    parent_hash = selected_block_option.clone().hash(); // update the hash for next round
-                                    orphanbuffer.hash_map.remove(&removed_hash); // remove the block from the buffer
                                 }
                             }
-                            // if the blockchain already contains the block, add the repeated block to the orphan buffer
-                            else if pow_passed {
+                            // if the parent does not exist, add the block to the buffer and look for the parent
+                            else {
                                 let parent_hash = block.get_parent();
-                                orphanbuffer.hash_map.insert(parent_hash, block); // if the parent does not exist, add the block to the buffer
-                                parent_vec.push(parent_hash);
+                                let mut orphans = self.orphans.lock().unwrap();
+                                if orphans.on_orphan(*peer.addr(), block.hash(), parent_hash) {
+                                    parent_vec.push(parent_hash);
+                                }
+                                orphans.on_block(*peer.addr(), block.hash());
+                                let evicted = orphans.buffer(*peer.addr(), block);
+                                if evicted > 0 {
+                                    debug!("Evicted {} orphan blocks to stay within the orphan buffer limits", evicted);
+                                }
+                                continue;
                             }
-                        }   
-                    }
+                            {self.orphans.lock().unwrap().on_block(*peer.addr(), block.hash())};
 
-                    if parent_vec.len() > 0 {
-                        peer.write(Message::GetBlocks(parent_vec));
-                    }
-                    else {
-                        print!(" there is no parent vector to get blocks ");
-                    }
-                    if new_hashes.len() > 0 {
-                        self.server.broadcast(Message::NewBlockHashes(new_hashes));
-                    }
-                    else {
-                        print!(" there is no new block hashes to send ");
-                    }
+                            // connect every buffered descendant of the new block, forks included
+                            let mut parents = vec![block.hash()];
+                            while let Some(parent_hash) = parents.pop() {
+                                let children = {self.orphans.lock().unwrap().take_children(&parent_hash)};
+                                for (child, sender) in children {
+                                    if blockchain.contains(&child.hash()) {
+                                        continue;
+                                    }
+                                    // held back only for its parent, it is checked like any other block
+                                    if let Err(reason) = validate_block(&blockchain, &child, validation_time) {
+                                        self.reject(sender, InvItem::Block(child.hash()), reason);
+                                        continue;
+                                    }
+                                    blockchain.insert(&child); // add the block to your blockchain
+                                    if blockchain.contains(&child.hash()) {
+                                        let mut mempool = self.wrapped_mempool.lock().unwrap();
+                                        for signed_transaction in child.content.transactions.iter() {
+                                            mempool.hash_map.remove(&signed_transaction.hash());
+                                        }
+                                        new_hashes.push(child.hash());
+                                        parents.push(child.hash());
+                                    }
+                                }
+                            }
+                            let change = format!("block {} from peer {}", block.hash(), peer.addr());
+                            invariants::enforce(&blockchain, Some(&self.wrapped_mempool.lock().unwrap()), &change);
+                        }
+                    }   
                 }
-                
-                Message::NewTransactionHashes(trans_hashes) => {
-                    let mut get_hashes = Vec::<H256>::new();
-                    // for all the transaction hashes in the message
+
+                if let (false, Some(downloads)) = (parent_vec.is_empty(), self.sync_downloads()) {
+                    // the peer that sent the orphans has their parents, but so may others
                     {
-                        let mempool = self.wrapped_mempool.lock().unwrap();
-                        for hash in trans_hashes {
-                            // if the transaction is not in the mempool, ask for it using GetTransactions
-                            if !mempool.hash_map.contains_key(&hash) {
-                                get_hashes.push(hash);
-                            }
+                        let mut downloads = downloads.lock().unwrap();
+                        for parent in parent_vec.drain(..) {
+                            downloads.want(parent, None);
                         }
                     }
-                    if get_hashes.len() > 0 {
-                        peer.write(Message::GetTransactions(get_hashes));
+                    download::dispatch(downloads, &self.peer_states, &self.router, &self.server);
+                }
+                // children of the same missing parent may come from several peers
+                {
+                    let now = time::Instant::now();
+                    let mut block_requests = self.block_requests.lock().unwrap();
+                    parent_vec.retain(|parent| block_requests.request(*parent, now));
+                }
+                if parent_vec.len() > 0 {
+                    let parent_items = parent_vec.into_iter().map(InvItem::Block).collect();
+                    let strategy = {self.orphans.lock().unwrap().strategy()};
+                    match strategy {
+                        OrphanStrategy::SamePeer => peer.write(Message::GetData(parent_items)),
+                        OrphanStrategy::AllPeers => self.server.broadcast(Message::GetData(parent_items)),
                     }
                 }
-                Message::GetTransactions(trans_vec) => {
-                    let mut transactions = Vec::new();
-                    {
-                        let mempool = self.wrapped_mempool.lock().unwrap();
-                        for hash in trans_vec {
-                            if mempool.hash_map.contains_key(&hash){ 
-                                let transaction = mempool.hash_map.get(&hash).unwrap().clone();
-                                transactions.push(transaction);
-                            } 
-                        }
+                if new_hashes.len() > 0 {
+                    let new_items: Vec<InvItem> = new_hashes.into_iter().map(InvItem::Block).collect();
+                    self.mark_accepted(new_items.iter());
+                    self.server.broadcast(Message::Inv(new_items));
+                }
+                // resume transaction gossip as soon as the blocks caught us up
+                self.tx_gossip_paused();
+            }
+            
+            Message::TxPackage(package) => {
+                if self.tx_gossip_paused() {
+                    self.skip_tx_gossip(&peer, "a transaction package");
+                    return;
+                }
+                self.mark_seen(&peer, package.iter().map(|tx| tx.hash()));
+                self.mark_received(&peer, package.iter().map(|tx| tx.hash()));
+                self.mark_delivered(&peer, package.iter().map(|tx| InvItem::Transaction(tx.hash())));
+                // the package is judged as a whole, a child may carry a parent our policy refuses alone
+                if let Err(violation) = self.policy.check_package(&package) {
+                    debug!("Package from peer {} refused by relay policy: {}", peer.addr(), violation);
+                    return;
+                }
+                // admit the whole package at once, and only relay it the first time we see it; the
+                // blockchain stays locked from the check on, so no block confirms it in between
+                let is_new = {
+                    let blockchain = self.wrapped_blockchain.lock().unwrap();
+                    let result = validate_package(&package, blockchain.state_map.get(&blockchain.tip()).unwrap(), &blockchain.validators());
+                    if let Err((hash, reason)) = result {
+                        self.reject(*peer.addr(), InvItem::Transaction(hash), reason);
+                        return;
+                    }
+                    let mut mempool = self.wrapped_mempool.lock().unwrap();
+                    let new_items: Vec<InvItem> = package
+                        .iter()
+                        .map(|tx| tx.hash())
+                        .filter(|hash| !mempool.hash_map.contains_key(hash))
+                        .map(InvItem::Transaction)
+                        .collect();
+                    for tx in package.iter() {
+                        mempool.hash_map.insert(tx.hash(), tx.clone());
                     }
-                    if transactions.len() > 0 {
-                        peer.write(Message::Transactions(transactions));
+                    self.mark_accepted(new_items.iter());
+                    !new_items.is_empty()
+                };
+                if is_new {
+                    self.server.broadcast(Message::TxPackage(package));
+                }
+            }
+            Message::Transactions(signed_transactions) => {
+                if self.tx_gossip_paused() {
+                    self.skip_tx_gossip(&peer, "transactions");
+                    return;
+                }
+                self.mark_seen(&peer, signed_transactions.iter().map(|tx| tx.hash()));
+                self.mark_received(&peer, signed_transactions.iter().map(|tx| tx.hash()));
+                self.mark_delivered(&peer, signed_transactions.iter().map(|tx| InvItem::Transaction(tx.hash())));
+                // signatures are checked off the worker threads if there is a pool for it
+                match &self.verifier {
+                    Some(verifier) => {
+                        verifier.submit(*peer.addr(), signed_transactions);
                     }
+                    None => self.admit_transactions(verifier::verify_all(*peer.addr(), signed_transactions)),
                 }
-                Message::Transactions(signed_transactions) => {
-                    let mut new_hashes = Vec::<H256>::new();
+            }
+        }
+    }
 
-                    // retrive the trasnactions of the hashes from the mempool, and check their validity
-                    for signed_transaction in signed_transactions {
-                        let mut signature_is_valid = true;
-                        // first, check transaction signature validity
-                        if !verify(&signed_transaction.t, &signed_transaction.signer_public_key, &signed_transaction.signature_vector) {
-                            signature_is_valid = false;
-                        }
+    // log a block or transaction of `peer` that failed validation, and tell the peer why the
+    // first time, repeats within the window of the failure log are not answered again
+    fn reject(&self, peer: SocketAddr, item: InvItem, reason: ValidationFailure) {
+        let hash = match item {
+            InvItem::Block(hash) | InvItem::Transaction(hash) => hash,
+        };
+        let first = {self.failure_log.lock().unwrap().report(peer, hash, reason)};
+        if first && {self.peer_features.lock().unwrap().supports(&peer, Features::REJECT)} {
+            self.server.send_to(peer, Message::Reject(item, reason.into()));
+        }
+    }
 
-                        // if the transaction is not in the mempool, add it to the mempool
-                        {
-                            let mut mempool = self.wrapped_mempool.lock().unwrap();
-                            if !mempool.hash_map.contains_key(&signed_transaction.hash()) && signature_is_valid {
-                                new_hashes.push(signed_transaction.hash());
-                                mempool.hash_map.insert(signed_transaction.hash(), signed_transaction);
-                            }
-                            else {
-                                println!("transaction already exists in the mempool!");
-                            }
-                        }
-                    }
-                    if new_hashes.len() > 0 {
-                        self.server.broadcast(Message::NewTransactionHashes(new_hashes));  
-                    }
+    // admit the transactions of a message whose signatures were checked, and announce the new ones
+    fn admit_transactions(&self, verified: Verified) {
+        for hash in verified.invalid {
+            self.reject(verified.peer, InvItem::Transaction(hash), ValidationFailure::BadSignature);
+        }
+        let mut new_hashes = Vec::<H256>::new();
+        let validators = {self.wrapped_blockchain.lock().unwrap().validators()};
+        for signed_transaction in verified.valid {
+            // check the transaction against our local relay policy
+            if let Err(violation) = self.policy.check(&signed_transaction) {
+                debug!("Transaction {} refused by relay policy: {}", signed_transaction.hash(), violation);
+                continue;
+            }
+            // and against the extra rules of the network, a transaction breaking them could never be mined
+            if !validators.is_empty() {
+                let result = {
+                    let blockchain = self.wrapped_blockchain.lock().unwrap();
+                    validators.check_transaction(&signed_transaction, &blockchain.state_map[&blockchain.tip()])
+                };
+                if let Err(violation) = result {
+                    debug!("Transaction {} breaks {}", signed_transaction.hash(), violation);
+                    self.reject(verified.peer, InvItem::Transaction(signed_transaction.hash()), ValidationFailure::Rule(violation.rule));
+                    continue;
+                }
+            }
+
+            // a nonce the sender already used at our tip belongs to a confirmed transaction, or
+            // one conflicting with it, that can never be mined; the blockchain stays locked from
+            // the check on, so no block confirms the transaction in between
+            let blockchain = self.wrapped_blockchain.lock().unwrap();
+            if blockchain.state_map[&blockchain.tip()].is_stale(&signed_transaction) {
+                debug!("Transaction {} reuses a nonce its sender already used", signed_transaction.hash());
+                continue;
+            }
+
+            // if the transaction is not in the mempool, add it to the mempool
+            let mut mempool = self.wrapped_mempool.lock().unwrap();
+            match mempool.hash_map.entry(signed_transaction.hash()) {
+                Entry::Vacant(entry) => {
+                    new_hashes.push(signed_transaction.hash());
+                    entry.insert(signed_transaction);
                 }
+                Entry::Occupied(_) => trace!("Transaction {} is already in the mempool", signed_transaction.hash()),
             }
         }
+        if new_hashes.len() > 0 {
+            let new_items: Vec<InvItem> = new_hashes.into_iter().map(InvItem::Transaction).collect();
+            self.mark_accepted(new_items.iter());
+            self.server.broadcast(Message::Inv(new_items));
+        }
     }
 }
 
@@ -310,6 +1210,97 @@ pub fn verify(t: &Transaction, public_key: &[u8], signature: &[u8]) -> bool {
     peer_public_key.verify(trans, signature).is_ok() // verify the mesage
 }
 
+/// Check `block`, whose parent `blockchain` holds, against every consensus rule but its proof of
+/// work: its length and difficulty follow the parent, its timestamp is after the median time past
+/// and not too far ahead of `now`, see `NetworkClock::validation_time`, the network's extra rules hold, every
+/// transaction is signed, is not dust and can be sent in the parent's state, and a block at the
+/// height of the trusted checkpoint is the checkpointed one. Returns the number of signatures
+/// checked, none up to the checkpoint, whose signatures are taken on trust.
+pub fn validate_block(blockchain: &Blockchain, block: &Block, now: u128) -> Result<usize, ValidationFailure> {
+    let parent = block.get_parent();
+    if block.header.length != blockchain.header(&parent).unwrap().length + 1 {
+        return Err(ValidationFailure::BadLength);
+    }
+    if block.get_difficulty() != blockchain.next_difficulty(&parent) {
+        return Err(ValidationFailure::BadDifficulty);
+    }
+    if !blockchain.timestamp_is_valid(&parent, block.header.timestamp, now) {
+        return Err(ValidationFailure::BadTimestamp);
+    }
+    let state = &blockchain.state_map[&parent];
+    let validators = blockchain.validators();
+    let checkpoint = blockchain.checkpoint();
+    // the extra rules of the network look at the block as a whole first
+    if let Err(violation) = validators.check_block(block, state) {
+        debug!("Block {} breaks {}", block.hash(), violation);
+        return Err(ValidationFailure::Rule(violation.rule));
+    }
+    let assumed_valid = checkpoint.is_some_and(|checkpoint| checkpoint.covers(block.header.length));
+    let mut signature_ops = 0;
+    // each transaction is checked on top of the ones before it, so two spending the same nonce
+    // or together more than the sender holds make the block invalid
+    let mut state_after = state.clone();
+    state_after.start_block();
+    for signed_transaction in block.content.transactions.iter() {
+        if !assumed_valid {
+            signature_ops += 1;
+            if !verify(&signed_transaction.t, &signed_transaction.signer_public_key, &signed_transaction.signature_vector) {
+                return Err(ValidationFailure::BadSignature);
+            }
+        }
+        if signed_transaction.t.value < blockchain.min_tx_value() {
+            return Err(ValidationFailure::Dust);
+        }
+        let sender = Address::from_public_key_bytes(signed_transaction.signer_public_key.as_slice());
+        if !state_after.can_send(&sender, signed_transaction.t.value, signed_transaction.t.account_nonce) {
+            return Err(ValidationFailure::InvalidTransaction);
+        }
+        if let Err(violation) = validators.check_transaction(signed_transaction, &state_after) {
+            debug!("Transaction {} of block {} breaks {}", signed_transaction.hash(), block.hash(), violation);
+            return Err(ValidationFailure::Rule(violation.rule));
+        }
+        if let Err(e) = state_after.apply_transaction(signed_transaction) {
+            debug!("Block {}: {}", block.hash(), e);
+            return Err(ValidationFailure::InvalidTransaction);
+        }
+    }
+    state_after.finish_block();
+    // the chain has to pass through the checkpoint, with the state it vouches for
+    if let Some(checkpoint) = checkpoint.filter(|checkpoint| checkpoint.height == block.header.length) {
+        if !checkpoint.admits(block.header.length, &block.hash(), &state_after) {
+            return Err(ValidationFailure::Checkpoint);
+        }
+    }
+    Ok(signature_ops)
+}
+
+/// Check that a package of transactions can be applied in order on top of `state`: every
+/// signature is valid, every nonce follows the previous one of its sender, and every sender can
+/// afford its transaction, counting what earlier transactions of the package paid it, and
+/// every transaction follows the extra rules of `validators`.
+pub fn validate_package(package: &[SignedTransaction], state: &State, validators: &Validators) -> Result<(), (H256, ValidationFailure)> {
+    // the package goes into the block after `state`
+    let mut state = state.clone();
+    state.start_block();
+    for tx in package {
+        if !verify(&tx.t, &tx.signer_public_key, &tx.signature_vector) {
+            return Err((tx.hash(), ValidationFailure::BadSignature));
+        }
+        let sender = Address::from_public_key_bytes(tx.signer_public_key.as_slice());
+        if !state.can_send(&sender, tx.t.value, tx.t.account_nonce) {
+            return Err((tx.hash(), ValidationFailure::InvalidTransaction));
+        }
+        if let Err(violation) = validators.check_transaction(tx, &state) {
+            debug!("Transaction {} breaks {}", tx.hash(), violation);
+            return Err((tx.hash(), ValidationFailure::Rule(violation.rule)));
+        }
+        if state.apply_transaction(tx).is_err() {
+            return Err((tx.hash(), ValidationFailure::InvalidTransaction));
+        }
+    }
+    Ok(())
+}
+
 #[cfg(any(test,test_utilities))]
 struct TestMsgSender {
     s: smol::channel::Sender<(Vec<u8>, peer::Handle)>
@@ -322,7 +1313,10 @@ impl TestMsgSender {
     }
 
     fn send(&self, msg: Message) -> PeerTestReceiver {
-        let bytes = bincode::serialize(&msg).unwrap();
+        self.send_frame(msg.encode())
+    }
+
+    fn send_frame(&self, bytes: Vec<u8>) -> PeerTestReceiver {
         let (handle, r) = peer::Handle::test_handle();
         smol::block_on(self.s.send((bytes, handle))).unwrap();
         r
@@ -336,7 +1330,16 @@ fn generate_test_worker_and_start() -> (TestMsgSender, ServerTestReceiver, Vec<H
     let (test_msg_sender, msg_chan) = TestMsgSender::new();
     let new_blockchain= &Arc::new(Mutex::new(Blockchain::new(0)));
     let new_mempool = &Arc::new(Mutex::new(Mempool::new()));
-    let worker = Worker::new(1, msg_chan, &server, new_blockchain, new_mempool);
+    let shared = Shared::new(new_blockchain, new_mempool);
+    // the test peer has completed the handshake
+    {
+        let mut states = shared.peer_states.lock().unwrap();
+        let addr = *peer::Handle::test_handle().0.addr();
+        states.on_message(addr, &Message::Version(PROTOCOL_VERSION, SUPPORTED_FEATURES, 0, H256::default())).unwrap();
+        states.negotiated(addr);
+        states.on_message(addr, &Message::Tip(0, H256::default())).unwrap();
+    }
+    let worker = Worker::new(1, msg_chan, &server, &shared, &RelayPolicy::default());
     worker.start(); 
     // generate and append the hash of the genesis block
     let blockchain_vector = {new_blockchain.lock().unwrap().all_blocks_in_longest_chain()};
@@ -398,4 +1401,288 @@ mod test {
     }
 }
 
-// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
\ No newline at end of file
+// DO NOT CHANGE THIS COMMENT, IT IS FOR AUTOGRADER. AFTER TEST
+
+#[cfg(test)]
+mod tests {
+    use ntest::timeout;
+    use crate::types::block::{generate_random_block, TestBlockBuilder};
+    use crate::types::hash::H256;
+    use crate::types::hash::Hashable;
+
+    use super::super::message::{InvItem, Message};
+    use crate::types::address::Address;
+    use crate::types::amount::Amount;
+    use crate::types::transaction::{sign, SignedTransaction, Transaction};
+    use crate::blockchain::validators::Validators;
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+    use super::generate_test_worker_and_start;
+
+    #[test]
+    #[timeout(60000)]
+    fn reply_inv_with_get_data() {
+        let (test_msg_sender, _server_receiver, v) = generate_test_worker_and_start();
+        let random_block = generate_random_block(v.last().unwrap());
+        let mut peer_receiver = test_msg_sender.send(Message::Inv(vec![InvItem::Block(random_block.hash())]));
+        let reply = peer_receiver.recv();
+        if let Message::GetData(v) = reply {
+            assert_eq!(v, vec![InvItem::Block(random_block.hash())]);
+        } else {
+            panic!();
+        }
+    }
+    #[test]
+    #[timeout(60000)]
+    fn reply_blocks_after_locator() {
+        let (test_msg_sender, _server_receiver, v) = generate_test_worker_and_start();
+        let genesis = v[0];
+        let difficulty = super::Blockchain::new(0).next_difficulty(&genesis);
+        let block = TestBlockBuilder::new(&genesis).length(1).difficulty(difficulty).solve_pow().build();
+        let _peer_receiver = test_msg_sender.send(Message::Blocks(vec![block.clone()]));
+        // a peer that only has genesis, and one that is on a chain we do not know
+        for locator in [vec![genesis], vec![H256::default(), genesis]] {
+            let mut peer_receiver = test_msg_sender.send(Message::GetBlocksAfter(locator));
+            if let Message::Blocks(blocks) = peer_receiver.recv() {
+                assert_eq!(blocks.iter().map(|block| block.hash()).collect::<Vec<_>>(), vec![block.hash()]);
+            } else {
+                panic!();
+            }
+        }
+    }
+    #[test]
+    #[timeout(60000)]
+    fn continue_after_capped_page() {
+        let (test_msg_sender, _server_receiver, v) = generate_test_worker_and_start();
+        let last = H256::from([7; 32]);
+        let mut peer_receiver = test_msg_sender.send(Message::MoreBlocks(last));
+        if let Message::GetBlocksAfter(locator) = peer_receiver.recv() {
+            assert_eq!(locator[0], last);
+            assert_eq!(locator.last(), v.first());
+        } else {
+            panic!();
+        }
+    }
+    #[test]
+    #[timeout(60000)]
+    fn announce_mempool_on_request() {
+        let (test_msg_sender, server_receiver, _v) = generate_test_worker_and_start();
+        let payer = Ed25519KeyPair::from_seed_unchecked(&[0; 32]).unwrap();
+        let child_key = Ed25519KeyPair::from_seed_unchecked(&[9; 32]).unwrap();
+        let child_address = Address::from_public_key_bytes(child_key.public_key().as_ref());
+        let parent = signed(&payer, child_address, 50, 1);
+        let child = signed(&child_key, Address::from([1; 20]), 20, 1);
+        let _peer_receiver = test_msg_sender.send(Message::TxPackage(vec![parent.clone(), child.clone()]));
+        // relayed once it is in the mempool
+        assert!(matches!(server_receiver.recv().unwrap(), Message::TxPackage(_)));
+
+        let mut peer_receiver = test_msg_sender.send(Message::GetMempool);
+        if let Message::Inv(mut items) = peer_receiver.recv() {
+            let mut expected = vec![InvItem::Transaction(parent.hash()), InvItem::Transaction(child.hash())];
+            items.sort_by_key(|item| format!("{:?}", item));
+            expected.sort_by_key(|item| format!("{:?}", item));
+            assert_eq!(items, expected);
+        } else {
+            panic!();
+        }
+    }
+    #[test]
+    #[timeout(60000)]
+    fn reply_get_data_with_blocks() {
+        let (test_msg_sender, _server_receiver, v) = generate_test_worker_and_start();
+        let h = *v.last().unwrap();
+        let mut peer_receiver = test_msg_sender.send(Message::GetData(vec![InvItem::Block(h)]));
+        let reply = peer_receiver.recv();
+        if let Message::Blocks(v) = reply {
+            assert_eq!(1, v.len());
+            assert_eq!(h, v[0].hash())
+        } else {
+            panic!();
+        }
+    }
+    #[test]
+    #[timeout(60000)]
+    fn reply_not_found() {
+        let (test_msg_sender, _server_receiver, v) = generate_test_worker_and_start();
+        let random_block = generate_random_block(v.last().unwrap());
+        let missing = vec![InvItem::Block(random_block.hash()), InvItem::Transaction(random_block.hash())];
+        let mut peer_receiver = test_msg_sender.send(Message::GetData(missing.clone()));
+        let reply = peer_receiver.recv();
+        if let Message::NotFound(v) = reply {
+            assert_eq!(v, missing);
+        } else {
+            panic!();
+        }
+    }
+    #[test]
+    #[timeout(60000)]
+    fn reply_get_state_proof() {
+        let (test_msg_sender, _server_receiver, v) = generate_test_worker_and_start();
+        let genesis = *v.last().unwrap();
+        let key = Ed25519KeyPair::from_seed_unchecked(&[0; 32]).unwrap();
+        let address = Address::from_public_key_bytes(key.public_key().as_ref());
+        let mut peer_receiver = test_msg_sender.send(Message::GetStateProof(address, genesis));
+        let reply = peer_receiver.recv();
+        if let Message::StateProof(Some(proof)) = reply {
+            assert_eq!(proof.block_hash, genesis);
+            assert_eq!(proof.entry.balance, Amount::from_sub_units(100));
+            assert!(proof.verify(&proof.state_root));
+        } else {
+            panic!();
+        }
+    }
+    #[test]
+    #[timeout(60000)]
+    fn reply_orphan_block() {
+        let (test_msg_sender, _server_receiver, _v) = generate_test_worker_and_start();
+        let missing_parent = H256::from([7; 32]);
+        let easy = H256::from_leading_zeros(4);
+        let orphan = TestBlockBuilder::new(&missing_parent).difficulty(easy).length(2).solve_pow().build();
+        let mut peer_receiver = test_msg_sender.send(Message::Blocks(vec![orphan]));
+        let reply = peer_receiver.recv();
+        if let Message::GetData(v) = reply {
+            assert_eq!(v, vec![InvItem::Block(missing_parent)]);
+        } else {
+            panic!();
+        }
+    }
+    #[test]
+    #[timeout(60000)]
+    fn connect_all_orphans_of_parent() {
+        let (test_msg_sender, server_receiver, v) = generate_test_worker_and_start();
+        let genesis = *v.last().unwrap();
+        let difficulty = super::Blockchain::new(0).next_difficulty(&genesis);
+        let parent = TestBlockBuilder::new(&genesis).length(1).difficulty(difficulty).solve_pow().build();
+        // two children of the same missing parent, a fork
+        let first = TestBlockBuilder::new(&parent.hash()).length(2).difficulty(difficulty).solve_pow().build();
+        let second = TestBlockBuilder::new(&parent.hash()).length(2).difficulty(difficulty).solve_pow().build();
+        let grandchild = TestBlockBuilder::new(&second.hash()).length(3).difficulty(difficulty).solve_pow().build();
+        let mut peer_receiver = test_msg_sender.send(Message::Blocks(vec![first.clone()]));
+        assert!(matches!(peer_receiver.recv(), Message::GetData(items) if items == vec![InvItem::Block(parent.hash())]));
+        // the parent is already requested, the sibling only waits for it
+        let _peer_receiver = test_msg_sender.send(Message::Blocks(vec![second.clone()]));
+        // an orphan breaking the rules is still refused once its parent arrives
+        let skipping = TestBlockBuilder::new(&parent.hash()).length(5).difficulty(difficulty).solve_pow().build();
+        let _peer_receiver = test_msg_sender.send(Message::Blocks(vec![skipping.clone()]));
+        let mut peer_receiver = test_msg_sender.send(Message::Blocks(vec![grandchild.clone()]));
+        assert!(matches!(peer_receiver.recv(), Message::GetData(items) if items == vec![InvItem::Block(second.hash())]));
+        let _peer_receiver = test_msg_sender.send(Message::Blocks(vec![parent.clone()]));
+        let reply = server_receiver.recv().unwrap();
+        if let Message::Inv(items) = reply {
+            assert_eq!(items.len(), 4);
+            for block in [&parent, &first, &second, &grandchild] {
+                assert!(items.contains(&InvItem::Block(block.hash())));
+            }
+            assert!(!items.contains(&InvItem::Block(skipping.hash())));
+        } else {
+            panic!();
+        }
+    }
+    #[test]
+    #[timeout(60000)]
+    fn relay_tx_package() {
+        let (test_msg_sender, server_receiver, _v) = generate_test_worker_and_start();
+        // the genesis account pays a fresh account, which spends part of it right away
+        let payer = Ed25519KeyPair::from_seed_unchecked(&[0; 32]).unwrap();
+        let child_key = Ed25519KeyPair::from_seed_unchecked(&[9; 32]).unwrap();
+        let child_address = Address::from_public_key_bytes(child_key.public_key().as_ref());
+        let parent = signed(&payer, child_address, 50, 1);
+        let child = signed(&child_key, Address::from([1; 20]), 20, 1);
+        let package = vec![parent.clone(), child.clone()];
+        let blockchain = super::Blockchain::new(0);
+        let genesis_state = blockchain.state_map.get(&blockchain.tip()).unwrap();
+        assert!(super::validate_package(&package, genesis_state, &Validators::new()).is_ok());
+        // out of dependency order the child cannot pay
+        assert!(super::validate_package(&[child.clone(), parent.clone()], genesis_state, &Validators::new()).is_err());
+
+        let mut _peer_receiver = test_msg_sender.send(Message::TxPackage(package));
+        let reply = server_receiver.recv().unwrap();
+        if let Message::TxPackage(v) = reply {
+            assert_eq!(v.len(), 2);
+            assert_eq!(v[0].hash(), parent.hash());
+            assert_eq!(v[1].hash(), child.hash());
+        } else {
+            panic!();
+        }
+    }
+
+    #[test]
+    fn conflicting_transactions_invalidate_the_block() {
+        let blockchain = super::Blockchain::new(0);
+        let genesis = blockchain.tip();
+        let payer = Ed25519KeyPair::from_seed_unchecked(&[0; 32]).unwrap();
+        let block = |transactions| {
+            TestBlockBuilder::new(&genesis).length(1).difficulty(blockchain.next_difficulty(&genesis)).transactions(transactions).build()
+        };
+        let valid = vec![signed(&payer, Address::from([1; 20]), 60, 1), signed(&payer, Address::from([2; 20]), 40, 2)];
+        assert_eq!(super::validate_block(&blockchain, &block(valid), u128::MAX), Ok(2));
+        // each transaction alone is fine on the parent state, together they are not
+        let same_nonce = vec![signed(&payer, Address::from([1; 20]), 10, 1), signed(&payer, Address::from([2; 20]), 10, 1)];
+        let overspend = vec![signed(&payer, Address::from([1; 20]), 60, 1), signed(&payer, Address::from([2; 20]), 60, 2)];
+        for transactions in [same_nonce, overspend] {
+            assert_eq!(super::validate_block(&blockchain, &block(transactions), u128::MAX), Err(super::ValidationFailure::InvalidTransaction));
+        }
+    }
+
+    #[test]
+    fn block_timestamps_are_checked() {
+        let mut blockchain = super::Blockchain::new(0);
+        let now = 1_000_000;
+        let genesis = blockchain.tip();
+        // without retargets every block has the difficulty of the genesis block
+        let difficulty = blockchain.next_difficulty(&genesis);
+        let block = |parent: H256, length: u32, timestamp: u128| {
+            TestBlockBuilder::new(&parent).length(length).difficulty(difficulty).timestamp(timestamp).build()
+        };
+        // not after the genesis timestamp of 0, or too far ahead of our time
+        assert_eq!(super::validate_block(&blockchain, &block(genesis, 1, 0), now), Err(super::ValidationFailure::BadTimestamp));
+        let ahead = now + crate::blockchain::MAX_FUTURE_BLOCK_TIME_MS;
+        assert_eq!(super::validate_block(&blockchain, &block(genesis, 1, ahead + 1), now), Err(super::ValidationFailure::BadTimestamp));
+        assert!(super::validate_block(&blockchain, &block(genesis, 1, ahead), now).is_ok());
+        // a chain of blocks 1000 ms apart, one of them backdated
+        let mut parent = genesis;
+        for length in 1..=11 {
+            let timestamp = if length == 6 { 1 } else { length as u128 * 1000 };
+            let next = block(parent, length, timestamp);
+            blockchain.insert(&next);
+            parent = next.hash();
+        }
+        // the median of the last 11 timestamps moves by one block for the backdated one
+        assert_eq!(blockchain.median_time_past(&parent), 5000);
+        assert_eq!(super::validate_block(&blockchain, &block(parent, 12, 5000), now), Err(super::ValidationFailure::BadTimestamp));
+        assert!(super::validate_block(&blockchain, &block(parent, 12, 5001), now).is_ok());
+    }
+
+    fn signed(key: &Ed25519KeyPair, receiver: Address, value: u64, account_nonce: usize) -> SignedTransaction {
+        let t = Transaction { receiver, value: Amount::from_sub_units(value), account_nonce };
+        let signature = sign(&t, key);
+        SignedTransaction {
+            t,
+            signature_vector: signature.as_ref().to_vec(),
+            signer_public_key: key.public_key().as_ref().to_vec(),
+        }
+    }
+    #[test]
+    #[timeout(60000)]
+    fn reply_get_tip() {
+        let (test_msg_sender, _server_receiver, v) = generate_test_worker_and_start();
+        let mut peer_receiver = test_msg_sender.send(Message::GetTip);
+        let reply = peer_receiver.recv();
+        if let Message::Tip(height, tip) = reply {
+            assert_eq!(height as usize, v.len() - 1);
+            assert_eq!(&tip, v.last().unwrap());
+        } else {
+            panic!();
+        }
+    }
+    #[test]
+    #[timeout(60000)]
+    fn skip_unknown_message_type() {
+        let (test_msg_sender, _server_receiver, v) = generate_test_worker_and_start();
+        let mut frame = (super::message::NAMES.len() as u32).to_le_bytes().to_vec();
+        frame.extend(&[1, 2, 3]);
+        test_msg_sender.send_frame(frame);
+        // the peer is still served
+        let mut peer_receiver = test_msg_sender.send(Message::GetTip);
+        assert!(matches!(peer_receiver.recv(), Message::Tip(_, tip) if &tip == v.last().unwrap()));
+    }
+}
@@ -0,0 +1,133 @@
+use crate::types::amount::Amount;
+use crate::types::transaction::SignedTransaction;
+
+/// Relay policy of this node. These are local preferences deciding which transactions we admit
+/// into our mempool and announce to peers; they are NOT consensus rules, so a block containing a
+/// transaction our policy rejects is still valid. Nodes with different policies can share a network.
+#[derive(Debug, Clone)]
+pub struct RelayPolicy {
+    /// transactions moving less than this value are considered dust and not relayed
+    pub dust_threshold: Amount,
+    /// maximum serialized size of a transaction in bytes
+    pub max_tx_size: usize,
+}
+
+/// Reason a transaction was refused by the relay policy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PolicyViolation {
+    Dust { value: Amount, threshold: Amount },
+    Oversized { size: usize, limit: usize },
+}
+
+impl std::fmt::Display for PolicyViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PolicyViolation::Dust { value, threshold } => {
+                write!(f, "dust transaction: value {} below threshold {}", value, threshold)
+            }
+            PolicyViolation::Oversized { size, limit } => {
+                write!(f, "transaction too large: {} bytes, limit {}", size, limit)
+            }
+        }
+    }
+}
+
+impl Default for RelayPolicy {
+    fn default() -> Self {
+        RelayPolicy {
+            dust_threshold: Amount::ZERO,
+            max_tx_size: 100_000,
+        }
+    }
+}
+
+impl RelayPolicy {
+    /// Check whether a transaction may enter the mempool and be relayed to peers
+    pub fn check(&self, tx: &SignedTransaction) -> Result<(), PolicyViolation> {
+        if tx.t.value < self.dust_threshold {
+            return Err(PolicyViolation::Dust {
+                value: tx.t.value,
+                threshold: self.dust_threshold,
+            });
+        }
+        let size = bincode::serialized_size(tx).unwrap() as usize;
+        if size > self.max_tx_size {
+            return Err(PolicyViolation::Oversized {
+                size,
+                limit: self.max_tx_size,
+            });
+        }
+        Ok(())
+    }
+
+    /// Check a package of dependent transactions as a whole. Each transaction must still fit the
+    /// size limit, but the dust threshold applies to the total value, so a child can carry a
+    /// parent that would be refused on its own.
+    pub fn check_package(&self, txs: &[SignedTransaction]) -> Result<(), PolicyViolation> {
+        for tx in txs {
+            let size = bincode::serialized_size(tx).unwrap() as usize;
+            if size > self.max_tx_size {
+                return Err(PolicyViolation::Oversized {
+                    size,
+                    limit: self.max_tx_size,
+                });
+            }
+        }
+        // a total too large to count is certainly not dust
+        if let Some(value) = Amount::checked_sum(txs.iter().map(|tx| tx.t.value)) {
+            if value < self.dust_threshold {
+                return Err(PolicyViolation::Dust {
+                    value,
+                    threshold: self.dust_threshold,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether a transaction should be admitted and relayed under this policy
+    pub fn accepts(&self, tx: &SignedTransaction) -> bool {
+        self.check(tx).is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::transaction::Transaction;
+
+    fn tx_with_value(value: u64) -> SignedTransaction {
+        SignedTransaction {
+            t: Transaction { value: Amount::from_sub_units(value), ..Default::default() },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn dust_is_rejected() {
+        let policy = RelayPolicy { dust_threshold: Amount::from_sub_units(10), ..Default::default() };
+        assert_eq!(
+            policy.check(&tx_with_value(3)),
+            Err(PolicyViolation::Dust { value: Amount::from_sub_units(3), threshold: Amount::from_sub_units(10) })
+        );
+        assert!(policy.accepts(&tx_with_value(10)));
+    }
+
+    #[test]
+    fn package_carries_dust_parent() {
+        let policy = RelayPolicy { dust_threshold: Amount::from_sub_units(10), ..Default::default() };
+        let package = vec![tx_with_value(3), tx_with_value(8)];
+        assert!(!policy.accepts(&package[0]));
+        assert_eq!(policy.check_package(&package), Ok(()));
+        assert_eq!(
+            policy.check_package(&package[..1]),
+            Err(PolicyViolation::Dust { value: Amount::from_sub_units(3), threshold: Amount::from_sub_units(10) })
+        );
+    }
+
+    #[test]
+    fn oversized_is_rejected() {
+        let policy = RelayPolicy { max_tx_size: 8, ..Default::default() };
+        assert!(!policy.accepts(&tx_with_value(1)));
+    }
+}
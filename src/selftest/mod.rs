@@ -0,0 +1,291 @@
+use crate::blockchain::params::ChainParams;
+use crate::blockchain::store::BlockStore;
+use crate::blockchain::{validators, Blockchain, Mempool};
+use crate::network::features::{PROTOCOL_VERSION, SUPPORTED_FEATURES};
+use crate::network::message::Message;
+use crate::network::peer;
+use crate::network::worker::Worker;
+use crate::types::address::Address;
+use crate::types::amount::Amount;
+use crate::types::block::{Block, Content, Header};
+use crate::types::hash::{H256, Hashable};
+use crate::types::key_pair;
+use crate::types::merkle::{self, MerkleTree};
+use crate::types::pow::PowHash;
+use crate::types::transaction::{self, SignedTransaction, Transaction};
+
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use serde::Serialize;
+use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// Outcome of one check of the self-test.
+#[derive(Serialize, Debug, Clone)]
+pub struct CheckResult {
+    pub name: &'static str,
+    pub passed: bool,
+    /// what was checked, or why it failed
+    pub detail: String,
+    pub millis: u128,
+}
+
+/// Outcome of the self-test, one result per check in the order they ran.
+#[derive(Serialize, Debug, Clone)]
+pub struct Report {
+    pub checks: Vec<CheckResult>,
+}
+
+impl Report {
+    pub fn passed(&self) -> bool {
+        self.checks.iter().all(|check| check.passed)
+    }
+}
+
+impl std::fmt::Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for check in self.checks.iter() {
+            let outcome = if check.passed { "PASS" } else { "FAIL" };
+            writeln!(f, "{}  {:<10} {} ({} ms)", outcome, check.name, check.detail, check.millis)?;
+        }
+        let passed = self.checks.iter().filter(|check| check.passed).count();
+        write!(f, "{} of {} checks passed", passed, self.checks.len())
+    }
+}
+
+type Check = fn(&Path) -> Result<String, String>;
+
+/// Run every check in-process, the ones touching the disk in `dir`. A check that panics fails
+/// instead of ending the run, so one broken piece does not hide the others.
+pub fn run(dir: &Path) -> Report {
+    let checks: Vec<(&'static str, Check)> = vec![
+        ("keys", check_keys),
+        ("signatures", check_signatures),
+        ("merkle", check_merkle),
+        ("blocks", check_blocks),
+        ("storage", check_storage),
+        #[cfg(feature = "api")]
+        ("api", check_api),
+    ];
+    let checks = checks
+        .into_iter()
+        .map(|(name, check)| {
+            let start = Instant::now();
+            let result = panic::catch_unwind(AssertUnwindSafe(|| check(dir)))
+                .unwrap_or_else(|e| Err(format!("panicked: {}", panic_message(&e))));
+            let (passed, detail) = match result {
+                Ok(detail) => (true, detail),
+                Err(detail) => (false, detail),
+            };
+            CheckResult { name, passed, detail, millis: start.elapsed().as_millis() }
+        })
+        .collect();
+    Report { checks }
+}
+
+fn panic_message(payload: &Box<dyn std::any::Any + Send>) -> String {
+    match (payload.downcast_ref::<&str>(), payload.downcast_ref::<String>()) {
+        (Some(message), _) => message.to_string(),
+        (_, Some(message)) => message.clone(),
+        _ => "unknown panic".to_string(),
+    }
+}
+
+// fail with `message` unless `condition` holds
+fn ensure(condition: bool, message: &str) -> Result<(), String> {
+    if condition {
+        Ok(())
+    } else {
+        Err(message.to_string())
+    }
+}
+
+fn check_keys(dir: &Path) -> Result<String, String> {
+    let path = dir.join("selftest.key");
+    let generated = key_pair::load_or_generate(&path).map_err(|e| format!("generating {}: {}", path.display(), e))?;
+    let loaded = key_pair::load_or_generate(&path).map_err(|e| format!("loading {}: {}", path.display(), e))?;
+    ensure(generated.public_key().as_ref() == loaded.public_key().as_ref(), "the saved key loads back as another key")?;
+    ensure(key_pair::random().public_key().as_ref() != generated.public_key().as_ref(), "two random keys are equal")?;
+    Ok("Ed25519 node key generated, saved and loaded back".to_string())
+}
+
+// a transaction of `value` from the account the genesis state funds, with its first nonce
+fn funded_transaction(value: u64) -> SignedTransaction {
+    let key = Ed25519KeyPair::from_seed_unchecked(&[0; 32]).unwrap();
+    let receiver = Address::from_public_key_bytes(Ed25519KeyPair::from_seed_unchecked(&[1; 32]).unwrap().public_key().as_ref());
+    let t = Transaction { receiver, value: Amount::from_sub_units(value), account_nonce: 1 };
+    let signature = transaction::sign(&t, &key);
+    SignedTransaction { t, signature_vector: signature.as_ref().to_vec(), signer_public_key: key.public_key().as_ref().to_vec() }
+}
+
+fn check_signatures(_dir: &Path) -> Result<String, String> {
+    let signed = funded_transaction(10);
+    ensure(transaction::verify(&signed.t, &signed.signer_public_key, &signed.signature_vector), "a valid signature does not verify")?;
+    let mut tampered = signed.t.clone();
+    tampered.value = Amount::from_sub_units(11);
+    ensure(!transaction::verify(&tampered, &signed.signer_public_key, &signed.signature_vector), "a signature verifies a changed transaction")?;
+    let other = key_pair::random();
+    ensure(!transaction::verify(&signed.t, other.public_key().as_ref(), &signed.signature_vector), "a signature verifies under another key")?;
+    Ok("transaction signed and verified, forgeries refused".to_string())
+}
+
+fn check_merkle(_dir: &Path) -> Result<String, String> {
+    let leaves: Vec<H256> = (0..7u8).map(|i| H256::from([i; 32])).collect();
+    let tree = MerkleTree::new(&leaves);
+    let root = tree.root();
+    for (index, leaf) in leaves.iter().enumerate() {
+        let proof = tree.proof(index);
+        ensure(merkle::verify(&root, &leaf.hash(), &proof, index, leaves.len()), "a valid proof does not verify")?;
+        ensure(!merkle::verify(&root, &leaf.hash(), &proof, (index + 1) % leaves.len(), leaves.len()), "a proof verifies at another index")?;
+    }
+    Ok(format!("proofs of {} leaves verified", leaves.len()))
+}
+
+// a block on `parent` at `length` carrying `transactions`, mined at `difficulty`
+fn mine(parent: H256, length: u32, difficulty: H256, transactions: Vec<SignedTransaction>, params: &ChainParams) -> Block {
+    let mut header = Header {
+        parent,
+        nonce: 0,
+        difficulty,
+        // deterministic, and after the median time past of the fixture chain
+        timestamp: length as u128,
+        merkle_root: MerkleTree::new(&transactions).root(),
+        length,
+    };
+    while !params.pow.meets_target(&header) {
+        header.nonce = header.nonce.wrapping_add(1);
+    }
+    Block { header, content: Content { transactions } }
+}
+
+// the fixture chain: the genesis of `params`, then a block paying from the funded account, then
+// an empty block
+fn fixture_chain(params: &ChainParams) -> Vec<Block> {
+    let genesis = Blockchain::with_params(0, params).genesis();
+    let first = mine(genesis, 1, params.initial_difficulty, vec![funded_transaction(10)], params);
+    let second = mine(first.hash(), 2, params.initial_difficulty, vec![], params);
+    vec![first, second]
+}
+
+fn fixture_params() -> ChainParams {
+    ChainParams { initial_difficulty: H256::from_leading_zeros(8), ..ChainParams::default() }
+}
+
+fn check_blocks(_dir: &Path) -> Result<String, String> {
+    let params = fixture_params();
+    let mut blockchain = Blockchain::with_params(0, &params);
+    blockchain.set_validators(validators::network_rules());
+    let genesis = blockchain.genesis();
+    let blockchain = Arc::new(Mutex::new(blockchain));
+    let mempool = Arc::new(Mutex::new(Mempool::new()));
+    // the blocks go through the P2P worker as if a peer had sent them
+    let worker = Worker::offline(&blockchain, &mempool);
+    let peer = peer::Handle::detached("127.0.0.1:1".parse().unwrap());
+    let send = |message: Message| worker.handle_message(message.encode(), peer.clone());
+    send(Message::Version(PROTOCOL_VERSION, SUPPORTED_FEATURES, 0, genesis));
+    send(Message::Tip(0, genesis));
+    let tip = || {blockchain.lock().unwrap().tip()};
+
+    let chain = fixture_chain(&params);
+    let (first, second) = (&chain[0], &chain[1]);
+    let mut no_work = first.clone();
+    while params.pow.meets_target(&no_work.header) {
+        no_work.header.nonce = no_work.header.nonce.wrapping_add(1);
+    }
+    send(Message::Blocks(vec![no_work]));
+    ensure(tip() == genesis, "a block without proof of work was accepted")?;
+    send(Message::Blocks(vec![mine(genesis, 1, params.initial_difficulty, vec![funded_transaction(1000)], &params)]));
+    ensure(tip() == genesis, "a block spending more than the balance was accepted")?;
+    send(Message::Blocks(vec![mine(genesis, 2, params.initial_difficulty, vec![], &params)]));
+    ensure(tip() == genesis, "a block with the wrong length was accepted")?;
+
+    send(Message::Blocks(vec![first.clone(), second.clone()]));
+    ensure(tip() == second.hash(), "valid blocks were not accepted")?;
+    let blockchain = blockchain.lock().unwrap();
+    let receiver = funded_transaction(10).t.receiver;
+    ensure(blockchain.state_map[&blockchain.tip()].account(&receiver).1 == Amount::from_sub_units(10), "the state does not reflect the payment")?;
+    Ok("fixture chain accepted, invalid blocks refused".to_string())
+}
+
+fn check_storage(dir: &Path) -> Result<String, String> {
+    let (headers, bodies) = (dir.join("selftest-headers.dat"), dir.join("selftest-bodies.dat"));
+    let chain = fixture_chain(&fixture_params());
+    {
+        let (mut store, existing) = BlockStore::open(&headers, &bodies).map_err(|e| format!("opening {}: {}", headers.display(), e))?;
+        ensure(existing.is_empty(), "a new store holds blocks")?;
+        for block in chain.iter() {
+            store.append(block).map_err(|e| format!("writing {}: {}", bodies.display(), e))?;
+        }
+        store.sync().map_err(|e| format!("syncing {}: {}", bodies.display(), e))?;
+    }
+    let (store, blocks) = BlockStore::open(&headers, &bodies).map_err(|e| format!("reopening {}: {}", headers.display(), e))?;
+    ensure(blocks.iter().map(|b| b.hash()).eq(chain.iter().map(|b| b.hash())), "the blocks read back differ from those written")?;
+    let body = store.read_body(&chain[0].hash()).map_err(|e| format!("reading {}: {}", bodies.display(), e))?;
+    ensure(body.map(|content| content.transactions.len()) == Some(1), "a block body read back differs from the one written")?;
+    Ok(format!("{} blocks written, synced and read back", chain.len()))
+}
+
+#[cfg(feature = "api")]
+fn check_api(dir: &Path) -> Result<String, String> {
+    use crate::api::{Reports, Server as ApiServer};
+    use crate::diagnostics;
+    use crate::memory::MemoryReport;
+    use crate::metrics::MetricsHistory;
+    use crate::network::banlist::BanList;
+    use crate::network::download::DownloadScheduler;
+    use crate::network::server::Handle as ServerHandle;
+    use crate::network::shared::Shared;
+    use crate::network::sync::SyncGate;
+    use crate::policy::RelayPolicy;
+    use crate::txgen;
+    use crate::watchdog::{AlarmThresholds, BlockWatch};
+    use std::time::Duration;
+
+    // a port the system just handed out is very likely still free
+    let addr = std::net::TcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .map_err(|e| format!("finding a free port: {}", e))?;
+    let blockchain = Arc::new(Mutex::new(Blockchain::new(0)));
+    let genesis = {blockchain.lock().unwrap().genesis()};
+    let mempool = Arc::new(Mutex::new(Mempool::new()));
+    let (_, tx, _) = txgen::new(&blockchain, &mempool, 0, &RelayPolicy::default());
+    let metrics_path = dir.join("selftest-metrics.ring");
+    let metrics = MetricsHistory::open(&metrics_path, 16).map_err(|e| format!("opening {}: {}", metrics_path.display(), e))?;
+    let block_watch = BlockWatch::new(Duration::from_secs(10), AlarmThresholds { warning: 3, critical: 6 }, genesis, 0, Instant::now());
+    let reports = Reports {
+        metrics: Arc::new(Mutex::new(metrics)),
+        memory: Arc::new(Mutex::new(MemoryReport::default())),
+        sync_gate: Arc::new(Mutex::new(SyncGate::new(0.0))),
+        downloads: Arc::new(Mutex::new(DownloadScheduler::new(16, Duration::from_secs(5)))),
+        block_watch: Arc::new(Mutex::new(block_watch)),
+        ban_list: Arc::new(Mutex::new(BanList::new())),
+    };
+    ApiServer::new(addr, &ServerHandle::detached(), &Shared::new(&blockchain, &mempool), &tx, reports, false).start();
+    let summary = diagnostics::fetch_summary(addr, 1).map_err(|e| format!("asking the API server for its chain: {}", e))?;
+    ensure(summary.tip == genesis.to_string(), "the API server serves another chain")?;
+    Ok(format!("API server started at {} and served the chain", addr))
+}
+
+/// Run the self-test in a fresh directory under `parent`, removed afterwards
+pub fn run_in(parent: &Path) -> std::io::Result<Report> {
+    let dir = parent.join(format!("selftest-{}", std::process::id()));
+    fs::create_dir_all(&dir)?;
+    let report = run(&dir);
+    fs::remove_dir_all(&dir)?;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_check_passes() {
+        let temp = tempfile::tempdir().unwrap();
+        let report = run_in(temp.path()).unwrap();
+        assert!(report.passed(), "{}", report);
+        assert!(report.to_string().ends_with(&format!("{} of {} checks passed", report.checks.len(), report.checks.len())));
+    }
+}
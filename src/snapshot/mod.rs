@@ -0,0 +1,280 @@
+pub mod worker;
+
+use crate::blockchain::Blockchain;
+use crate::types::block::Block;
+use crate::types::hash::{H256, Hashable};
+use crate::types::pow::PowHash;
+use crate::types::state_proof;
+
+use ring::signature::{self, Ed25519KeyPair, KeyPair};
+use serde::{Serialize, Deserialize};
+use std::convert::TryInto;
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+/// Description of a published snapshot, signed by the publishing node so a node bootstrapping
+/// from it knows whose chain it adopts.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Manifest {
+    /// hash of the genesis block
+    pub chain_id: String,
+    pub height: u32,
+    pub tip: String,
+    /// root of the account state after the tip, see `state_proof::state_root`
+    pub state_root: String,
+    /// file in the snapshot directory holding the blocks from height 1 to the tip
+    pub blocks_file: String,
+    pub blocks_sha256: String,
+    /// ms since epoch
+    pub created: u128,
+    /// public key of the publishing node
+    pub publisher: String,
+    /// signature of the manifest with an empty signature field
+    pub signature: String,
+}
+
+impl Manifest {
+    fn signed_bytes(&self) -> Vec<u8> {
+        let mut unsigned = self.clone();
+        unsigned.signature = String::new();
+        serde_json::to_vec(&unsigned).unwrap()
+    }
+}
+
+/// Reason a snapshot could not be used to bootstrap.
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    BadManifest(String),
+    BadSignature,
+    /// the manifest is signed by another node than the one we trust
+    UntrustedPublisher(String),
+    /// a block of the snapshot is not valid on our chain
+    InvalidBlock(H256),
+    /// the snapshot does not match its manifest, or is for another network
+    Mismatch(&'static str),
+}
+
+impl std::fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SnapshotError::Io(e) => write!(f, "{}", e),
+            SnapshotError::BadManifest(e) => write!(f, "malformed manifest: {}", e),
+            SnapshotError::BadSignature => write!(f, "manifest signature does not verify"),
+            SnapshotError::UntrustedPublisher(key) => write!(f, "manifest published by untrusted key {}", key),
+            SnapshotError::InvalidBlock(hash) => write!(f, "invalid block {}", hash),
+            SnapshotError::Mismatch(what) => write!(f, "{} does not match the manifest", what),
+        }
+    }
+}
+
+impl From<io::Error> for SnapshotError {
+    fn from(e: io::Error) -> Self {
+        SnapshotError::Io(e)
+    }
+}
+
+/// The longest chain and the state after its tip, taken from the blockchain.
+pub struct Snapshot {
+    chain_id: H256,
+    tip: H256,
+    state_root: H256,
+    blocks: Vec<Block>,
+}
+
+impl Snapshot {
+    /// Copy the longest chain of `blockchain`. Fails if the body of one of its blocks was pruned.
+    pub fn take(blockchain: &Blockchain) -> io::Result<Self> {
+        let chain = blockchain.all_blocks_in_longest_chain();
+        let mut blocks = Vec::new();
+        for hash in chain.iter().skip(1) {
+            match blockchain.block(hash) {
+                Some(block) => blocks.push(block),
+                None => return Err(io::Error::other(format!("body of block {} was pruned", hash))),
+            }
+        }
+        let tip = blockchain.tip();
+        Ok(Snapshot {
+            chain_id: chain[0],
+            tip,
+            state_root: state_proof::state_root(&blockchain.state_map[&tip].state),
+            blocks,
+        })
+    }
+
+    pub fn height(&self) -> u32 {
+        self.blocks.len() as u32
+    }
+
+    /// Write the blocks and a manifest signed with `key` to `dir`, replacing the previous snapshot
+    pub fn publish<P: AsRef<Path>>(&self, dir: P, key: &Ed25519KeyPair) -> io::Result<Manifest> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+        let mut bytes = Vec::new();
+        for block in self.blocks.iter() {
+            let record = bincode::serialize(block).unwrap();
+            bytes.extend_from_slice(&(record.len() as u32).to_be_bytes());
+            bytes.extend_from_slice(&record);
+        }
+        let blocks_file = format!("blocks-{}.bin", self.height());
+        write_atomically(&dir.join(&blocks_file), &bytes)?;
+
+        let mut manifest = Manifest {
+            chain_id: self.chain_id.to_string(),
+            height: self.height(),
+            tip: self.tip.to_string(),
+            state_root: self.state_root.to_string(),
+            blocks_file: blocks_file.clone(),
+            blocks_sha256: hex::encode(ring::digest::digest(&ring::digest::SHA256, &bytes)),
+            created: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis(),
+            publisher: hex::encode(key.public_key()),
+            signature: String::new(),
+        };
+        manifest.signature = hex::encode(key.sign(&manifest.signed_bytes()));
+        write_atomically(&dir.join(MANIFEST_FILE), serde_json::to_string_pretty(&manifest).unwrap().as_bytes())?;
+
+        // the manifest now points at the new blocks, older ones are of no use
+        for entry in fs::read_dir(dir)? {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            if name.starts_with("blocks-") && name.ends_with(".bin") && name != blocks_file {
+                fs::remove_file(dir.join(name))?;
+            }
+        }
+        Ok(manifest)
+    }
+}
+
+// write through a temporary file, so a reader never sees a partly written file
+fn write_atomically(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, bytes)?;
+    fs::rename(&tmp, path)
+}
+
+/// Read the snapshot in `dir`, checking its manifest is signed by its publisher, or by
+/// `trusted` (a hex public key) if given, and that the blocks are the ones it describes.
+pub fn load<P: AsRef<Path>>(dir: P, trusted: Option<&str>) -> Result<(Manifest, Vec<Block>), SnapshotError> {
+    let dir = dir.as_ref();
+    let json = fs::read_to_string(dir.join(MANIFEST_FILE))?;
+    let manifest: Manifest = serde_json::from_str(&json).map_err(|e| SnapshotError::BadManifest(e.to_string()))?;
+    if let Some(trusted) = trusted {
+        if !manifest.publisher.eq_ignore_ascii_case(trusted) {
+            return Err(SnapshotError::UntrustedPublisher(manifest.publisher));
+        }
+    }
+    let publisher = hex::decode(&manifest.publisher).map_err(|e| SnapshotError::BadManifest(e.to_string()))?;
+    let signature = hex::decode(&manifest.signature).map_err(|e| SnapshotError::BadManifest(e.to_string()))?;
+    signature::UnparsedPublicKey::new(&signature::ED25519, publisher)
+        .verify(&manifest.signed_bytes(), &signature)
+        .map_err(|_| SnapshotError::BadSignature)?;
+
+    // the manifest is trusted from here on, the blocks file is checked against it
+    // a bare file name: no directories, no `..`, no root or prefix of any platform
+    if Path::new(&manifest.blocks_file).file_name() != Some(OsStr::new(&manifest.blocks_file)) {
+        return Err(SnapshotError::BadManifest(format!("blocks file {} outside the snapshot", manifest.blocks_file)));
+    }
+    let bytes = fs::read(dir.join(&manifest.blocks_file))?;
+    if hex::encode(ring::digest::digest(&ring::digest::SHA256, &bytes)) != manifest.blocks_sha256 {
+        return Err(SnapshotError::Mismatch("blocks file hash"));
+    }
+    let mut blocks = Vec::new();
+    let mut rest = bytes.as_slice();
+    while !rest.is_empty() {
+        let record = rest
+            .get(..4)
+            .map(|length| u32::from_be_bytes(length.try_into().unwrap()) as usize)
+            .and_then(|length| rest.get(4..4 + length));
+        let record = match record {
+            Some(record) => record,
+            None => return Err(SnapshotError::Mismatch("blocks file length")),
+        };
+        blocks.push(bincode::deserialize(record).map_err(|_| SnapshotError::Mismatch("blocks file content"))?);
+        rest = &rest[4 + record.len()..];
+    }
+    Ok((manifest, blocks))
+}
+
+/// Insert the blocks of a loaded snapshot into `blockchain`, checking the proof of work and
+/// difficulty of each, and that the result has the tip and state the manifest promises.
+pub fn restore(blockchain: &mut Blockchain, manifest: &Manifest, blocks: &[Block]) -> Result<(), SnapshotError> {
+    let genesis = blockchain.all_blocks_in_longest_chain()[0];
+    if genesis.to_string() != manifest.chain_id {
+        return Err(SnapshotError::Mismatch("chain id"));
+    }
+    for block in blocks {
+        let hash = block.hash();
+        // blocks restored from our own store are already in
+        if blockchain.contains(&hash) {
+            continue;
+        }
+        let valid = blockchain.contains(&block.get_parent())
+            && block.get_difficulty() == blockchain.next_difficulty(&block.get_parent())
+            && blockchain.pow().meets_target(&block.header);
+        if !valid {
+            return Err(SnapshotError::InvalidBlock(hash));
+        }
+        blockchain.insert(block);
+    }
+    let tip = blockchain.tip();
+    if tip.to_string() != manifest.tip {
+        return Err(SnapshotError::Mismatch("tip"));
+    }
+    if state_proof::state_root(&blockchain.state_map[&tip].state).to_string() != manifest.state_root {
+        return Err(SnapshotError::Mismatch("state root"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::block::TestBlockBuilder;
+    use crate::types::key_pair;
+
+    fn test_chain(length: u32) -> Blockchain {
+        let mut blockchain = Blockchain::new(0);
+        for i in 1..=length {
+            let difficulty = blockchain.next_difficulty(&blockchain.tip());
+            let block = TestBlockBuilder::new(&blockchain.tip()).length(i).difficulty(difficulty).solve_pow().build();
+            blockchain.insert(&block);
+        }
+        blockchain
+    }
+
+    #[test]
+    fn bootstrap_from_published_snapshot() {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path().join("snapshot");
+        let source = test_chain(3);
+        let key = key_pair::random();
+        let manifest = Snapshot::take(&source).unwrap().publish(&dir, &key).unwrap();
+        assert_eq!(manifest.height, 3);
+
+        let publisher = hex::encode(key.public_key());
+        let (loaded, blocks) = load(&dir, Some(&publisher)).unwrap();
+        assert_eq!(loaded, manifest);
+        let mut blockchain = Blockchain::new(0);
+        restore(&mut blockchain, &loaded, &blocks).unwrap();
+        assert_eq!(blockchain.tip(), source.tip());
+
+        // another publisher is refused, and so is a tampered manifest
+        assert!(matches!(load(&dir, Some(&"00".repeat(32))), Err(SnapshotError::UntrustedPublisher(_))));
+        let mut tampered = manifest.clone();
+        tampered.height = 4;
+        fs::write(dir.join(MANIFEST_FILE), serde_json::to_string(&tampered).unwrap()).unwrap();
+        assert!(matches!(load(&dir, None), Err(SnapshotError::BadSignature)));
+        // even a signed manifest only names a file inside the snapshot
+        for blocks_file in &["../blocks-3.bin", "sub/blocks-3.bin", "/blocks-3.bin", "..", ""] {
+            let mut escaping = manifest.clone();
+            escaping.blocks_file = blocks_file.to_string();
+            escaping.signature = hex::encode(key.sign(&escaping.signed_bytes()));
+            fs::write(dir.join(MANIFEST_FILE), serde_json::to_string(&escaping).unwrap()).unwrap();
+            assert!(matches!(load(&dir, None), Err(SnapshotError::BadManifest(_))), "{}", blocks_file);
+        }
+    }
+}
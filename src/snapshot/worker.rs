@@ -0,0 +1,70 @@
+use log::{error, info};
+use crate::blockchain::Blockchain;
+use super::Snapshot;
+use ring::signature::Ed25519KeyPair;
+use std::path::PathBuf;
+use std::thread;
+use std::sync::{Arc, Mutex};
+use std::time;
+
+pub struct Worker {
+    dir: PathBuf,
+    interval: time::Duration,
+    key: Ed25519KeyPair,
+    blockchain: Arc<Mutex<Blockchain>>,
+}
+
+impl Worker {
+    pub fn new(
+        dir: PathBuf,
+        interval: time::Duration,
+        key: Ed25519KeyPair,
+        blockchain: &Arc<Mutex<Blockchain>>,
+    ) -> Self {
+        Self {
+            dir,
+            interval,
+            key,
+            blockchain: Arc::clone(blockchain),
+        }
+    }
+
+    pub fn start(self) {
+        let interval = self.interval;
+        let dir = self.dir.display().to_string();
+        thread::Builder::new()
+            .name("snapshot-worker".to_string())
+            .spawn(move || {
+                self.publish_loop();
+            })
+            .unwrap();
+        info!("Snapshot publisher writing to {} every {:?}", dir, interval);
+    }
+
+    fn publish_loop(&self) {
+        let mut published = None;
+        loop {
+            thread::sleep(self.interval);
+
+            // nothing new to publish while the tip stays the same
+            let tip = {self.blockchain.lock().unwrap().tip()};
+            if published == Some(tip) {
+                continue;
+            }
+            let snapshot = match Snapshot::take(&self.blockchain.lock().unwrap()) {
+                Ok(snapshot) => snapshot,
+                Err(e) => {
+                    error!("Error taking snapshot: {}", e);
+                    continue;
+                }
+            };
+            match snapshot.publish(&self.dir, &self.key) {
+                Ok(manifest) => {
+                    info!("Published snapshot at height {} to {}", manifest.height, self.dir.display());
+                    published = Some(snapshot.tip);
+                }
+                Err(e) => error!("Error publishing snapshot: {}", e),
+            }
+        }
+    }
+}
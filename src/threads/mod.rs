@@ -0,0 +1,253 @@
+use log::info;
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// How many threads a pool runs: a fixed number, or sized from the cores of the machine.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadCount {
+    Auto,
+    Fixed(usize),
+}
+
+impl std::str::FromStr for ThreadCount {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "auto" {
+            return Ok(ThreadCount::Auto);
+        }
+        match s.parse::<usize>() {
+            Ok(0) => Err("at least one thread is needed".to_string()),
+            Ok(threads) => Ok(ThreadCount::Fixed(threads)),
+            Err(e) => Err(format!("expected auto or a number of threads: {}", e)),
+        }
+    }
+}
+
+impl std::fmt::Display for ThreadCount {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ThreadCount::Auto => write!(f, "auto"),
+            ThreadCount::Fixed(threads) => write!(f, "{}", threads),
+        }
+    }
+}
+
+/// The thread pools of a node that can be sized.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PoolKind {
+    /// threads handling P2P messages
+    P2pWorkers,
+    /// threads verifying the signatures of gossiped transactions
+    Verifiers,
+    /// threads trying nonces
+    Miner,
+}
+
+impl PoolKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            PoolKind::P2pWorkers => "p2p",
+            PoolKind::Verifiers => "verify",
+            PoolKind::Miner => "miner",
+        }
+    }
+
+    /// Threads of this pool in auto mode on a machine with `cores` cores. Message handling
+    /// mostly waits for locks, so it gets half the cores; verification and mining are pure CPU.
+    /// Mining gets half of them as well, the verifiers would otherwise compete with it for every
+    /// core during a burst of transactions.
+    pub fn auto_threads(&self, cores: usize) -> usize {
+        match self {
+            PoolKind::P2pWorkers => (cores / 2).max(2),
+            PoolKind::Verifiers => cores.max(1),
+            PoolKind::Miner => (cores / 2).max(1),
+        }
+    }
+}
+
+impl std::str::FromStr for PoolKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "p2p" => Ok(PoolKind::P2pWorkers),
+            "verify" => Ok(PoolKind::Verifiers),
+            "miner" => Ok(PoolKind::Miner),
+            _ => Err(format!("unknown pool {}, expected p2p, verify or miner", s)),
+        }
+    }
+}
+
+/// Number of cores of this machine, 1 if it cannot tell
+pub fn cores() -> usize {
+    thread::available_parallelism().map_or(1, |cores| cores.get())
+}
+
+type Body = Arc<dyn Fn(&AtomicBool) + Send + Sync>;
+
+/// Threads running the same loop, that can be added or stopped while the node runs. Each
+/// thread gets a stop flag to check between two units of work, so a stopped thread first
+/// finishes the unit it is in; one blocked waiting for work exits after the next one.
+pub struct Pool {
+    name: String,
+    body: Body,
+    stops: Vec<Arc<AtomicBool>>,
+    spawned: usize,
+}
+
+impl Pool {
+    /// A pool of threads named after `name` running `body` until their stop flag is set. No
+    /// thread runs until the pool is resized.
+    pub fn new<F>(name: &str, body: F) -> Self
+    where
+        F: Fn(&AtomicBool) + Send + Sync + 'static,
+    {
+        Pool { name: name.to_string(), body: Arc::new(body), stops: Vec::new(), spawned: 0 }
+    }
+
+    /// Start or stop threads until `threads` run
+    pub fn resize(&mut self, threads: usize) {
+        while self.stops.len() > threads {
+            self.stops.pop().unwrap().store(true, Ordering::Relaxed);
+        }
+        while self.stops.len() < threads {
+            let stop = Arc::new(AtomicBool::new(false));
+            let body = Arc::clone(&self.body);
+            let thread_stop = Arc::clone(&stop);
+            thread::Builder::new()
+                .name(format!("{}-{}", self.name, self.spawned))
+                .spawn(move || body(&thread_stop))
+                .unwrap();
+            self.spawned += 1;
+            self.stops.push(stop);
+        }
+    }
+
+    /// Number of threads running, not counting stopped ones finishing their work
+    pub fn size(&self) -> usize {
+        self.stops.len()
+    }
+}
+
+/// Size of one pool, as served by the API.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct PoolStatus {
+    pub pool: &'static str,
+    /// auto or the number of threads asked for
+    pub mode: String,
+    pub threads: usize,
+}
+
+/// Sizes of the thread pools of the node, as served by the API.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct ThreadsStatus {
+    pub cores: usize,
+    pub pools: Vec<PoolStatus>,
+}
+
+/// The sizable thread pools of the node, with how each was asked to be sized.
+pub struct ThreadPools {
+    cores: usize,
+    pools: Vec<(PoolKind, ThreadCount, Arc<Mutex<Pool>>)>,
+}
+
+impl ThreadPools {
+    pub fn new(cores: usize) -> Self {
+        ThreadPools { cores, pools: Vec::new() }
+    }
+
+    /// Number of threads `count` stands for in pool `kind`
+    pub fn threads(&self, kind: PoolKind, count: ThreadCount) -> usize {
+        match count {
+            ThreadCount::Auto => kind.auto_threads(self.cores),
+            ThreadCount::Fixed(threads) => threads,
+        }
+    }
+
+    /// Size `pool` as `count` and keep it to resize later as `kind`
+    pub fn add(&mut self, kind: PoolKind, count: ThreadCount, pool: &Arc<Mutex<Pool>>) {
+        let threads = self.threads(kind, count);
+        {pool.lock().unwrap().resize(threads)};
+        info!("Running {} {} threads{}", threads, kind.name(), if count == ThreadCount::Auto { " (auto)" } else { "" });
+        self.pools.push((kind, count, Arc::clone(pool)));
+    }
+
+    /// Resize the pool `kind` as `count`, returns its new number of threads
+    pub fn resize(&mut self, kind: PoolKind, count: ThreadCount) -> Result<usize, String> {
+        let threads = self.threads(kind, count);
+        let (_, current, pool) = self
+            .pools
+            .iter_mut()
+            .find(|(k, _, _)| *k == kind)
+            .ok_or_else(|| format!("this node runs no {} pool", kind.name()))?;
+        {pool.lock().unwrap().resize(threads)};
+        *current = count;
+        info!("Resized the {} pool to {} threads{}", kind.name(), threads, if count == ThreadCount::Auto { " (auto)" } else { "" });
+        Ok(threads)
+    }
+
+    pub fn status(&self) -> ThreadsStatus {
+        ThreadsStatus {
+            cores: self.cores,
+            pools: self
+                .pools
+                .iter()
+                .map(|(kind, count, pool)| PoolStatus {
+                    pool: kind.name(),
+                    mode: count.to_string(),
+                    threads: pool.lock().unwrap().size(),
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::time::{Duration, Instant};
+
+    // wait up to a few seconds for `running` to reach `expected`
+    fn settles(running: &AtomicUsize, expected: usize) -> bool {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(5) {
+            if running.load(Ordering::SeqCst) == expected {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        false
+    }
+
+    #[test]
+    fn pools_grow_and_shrink() {
+        let running = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&running);
+        let pool = Arc::new(Mutex::new(Pool::new("test", move |stop| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            while !stop.load(Ordering::Relaxed) {
+                thread::sleep(Duration::from_millis(1));
+            }
+            counter.fetch_sub(1, Ordering::SeqCst);
+        })));
+        let mut pools = ThreadPools::new(8);
+        pools.add(PoolKind::Verifiers, ThreadCount::Fixed(3), &pool);
+        assert!(settles(&running, 3));
+        assert_eq!(pools.resize(PoolKind::Verifiers, ThreadCount::Fixed(1)), Ok(1));
+        assert!(settles(&running, 1));
+        // auto sizes from the cores
+        assert_eq!(pools.resize(PoolKind::Verifiers, ThreadCount::Auto), Ok(8));
+        assert!(settles(&running, 8));
+        assert_eq!(pools.status().pools, vec![PoolStatus { pool: "verify", mode: "auto".to_string(), threads: 8 }]);
+        assert!(pools.resize(PoolKind::Miner, ThreadCount::Fixed(1)).is_err());
+
+        assert_eq!("auto".parse::<ThreadCount>(), Ok(ThreadCount::Auto));
+        assert_eq!("4".parse::<ThreadCount>(), Ok(ThreadCount::Fixed(4)));
+        assert!("0".parse::<ThreadCount>().is_err());
+        assert_eq!(PoolKind::P2pWorkers.auto_threads(1), 2);
+    }
+}
@@ -1,6 +1,6 @@
 pub mod worker;
 
-use log::info;
+use log::{debug, info};
 
 use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
 use rand::Rng;
@@ -10,8 +10,10 @@ use std::thread;
 
 use crate::network::{self, server};
 use crate::types::address::Address;
+use crate::types::amount::Amount;
 use crate::types::block::{Block, Header, Content};
 use crate::blockchain::{Blockchain, Mempool};
+use crate::policy::RelayPolicy;
 use crate::types::hash::Hashable;
 use crate::types::key_pair;
 use crate::types::transaction::{SignedTransaction, sign, Transaction};
@@ -39,6 +41,7 @@ pub struct Context {
     finished_tx_chan: Sender<SignedTransaction>,
     mempool: Arc<Mutex<Mempool>>,
     key_pairs: Vec<Ed25519KeyPair>,
+    policy: RelayPolicy,
 }
 
 #[derive(Clone)]
@@ -47,7 +50,7 @@ pub struct Handle {
     control_chan: Sender<ControlSignal>,
 }
 
-pub fn new(blockchain: &Arc<Mutex<Blockchain>>, mempool: &Arc<Mutex<Mempool>>, seed: u8) -> (Context, Handle, Receiver<SignedTransaction>) {
+pub fn new(blockchain: &Arc<Mutex<Blockchain>>, mempool: &Arc<Mutex<Mempool>>, seed: u8, policy: &RelayPolicy) -> (Context, Handle, Receiver<SignedTransaction>) {
     let (signal_chan_sender, signal_chan_receiver) = unbounded();
     let (finished_tx_sender, finished_tx_receiver) = unbounded();
     let mut key_pairs = Vec::new();
@@ -61,6 +64,7 @@ pub fn new(blockchain: &Arc<Mutex<Blockchain>>, mempool: &Arc<Mutex<Mempool>>, s
         finished_tx_chan: finished_tx_sender,
         mempool: Arc::clone(mempool),
         key_pairs,
+        policy: policy.clone(),
     };
 
     let txgen_handle = Handle {
@@ -74,7 +78,7 @@ pub fn new(blockchain: &Arc<Mutex<Blockchain>>, mempool: &Arc<Mutex<Mempool>>, s
 fn test_new() -> (Context, Handle, Receiver<SignedTransaction>) {
     let new_blockchain= &Arc::new(Mutex::new(Blockchain::new(0)));
     let new_mempool = &Arc::new(Mutex::new(Mempool::new()));
-    new(new_blockchain, new_mempool, 0)
+    new(new_blockchain, new_mempool, 0, &RelayPolicy::default())
 }
 
 impl Handle {
@@ -177,12 +181,14 @@ impl Context {
                 sender_index = rand::thread_rng().gen_range(0..self.key_pairs.len()-1);
             }
 
-            // only pick a sender that has a balance greater than 0 and is contained in the state
+            // only pick a sender that is contained in the state and can afford more than dust, so
+            // the generated transactions do not bloat the state with values nobody relays
             let sender = Address::from_public_key_bytes(self.key_pairs[sender_index].public_key().as_ref().to_vec().as_slice()).clone();
-            if all_accounts.state.contains_key(&sender) && all_accounts.state.get(&sender).unwrap().1 > 0{
+            let min_value = self.policy.dust_threshold;
+            if all_accounts.state.contains_key(&sender) && all_accounts.state.get(&sender).unwrap().1 > min_value {
 
                 let account_nonce = {all_accounts.state.get(&sender).unwrap().0 + 1};
-                let value = {rand::thread_rng().gen_range(0..all_accounts.state.get(&sender).unwrap().1)};
+                let value = Amount::from_sub_units(rand::thread_rng().gen_range(min_value.sub_units()..all_accounts.state.get(&sender).unwrap().1.sub_units()));
                 let new_transaction = Transaction {
                     receiver,
                     value,
@@ -196,12 +202,28 @@ impl Context {
                     signer_public_key: self.key_pairs[sender_index].public_key().as_ref().to_vec(),
                 };
                 let signed_hash = signed_transaction.hash();
-    
-                let signed_transaction_clone = signed_transaction.clone();
-    
-                // add the transaction to mempool and pass it on to the worker for broadcasting
-                {self.mempool.lock().unwrap().hash_map.insert(signed_hash, signed_transaction)};
-                self.finished_tx_chan.send(signed_transaction_clone).unwrap();
+
+                // our own transactions go through the same relay policy as the ones from peers
+                if let Err(violation) = self.policy.check(&signed_transaction) {
+                    debug!("Generated transaction {} refused by relay policy: {}", signed_hash, violation);
+                }
+                else {
+                    let signed_transaction_clone = signed_transaction.clone();
+
+                    // add the transaction to mempool and pass it on to the worker for broadcasting,
+                    // unless a block used its nonce since the state was read
+                    let admitted = {
+                        let blockchain = self.arc_mutex.lock().unwrap();
+                        let admitted = !blockchain.state_map[&blockchain.tip()].is_stale(&signed_transaction);
+                        if admitted {
+                            self.mempool.lock().unwrap().hash_map.insert(signed_hash, signed_transaction);
+                        }
+                        admitted
+                    };
+                    if admitted {
+                        self.finished_tx_chan.send(signed_transaction_clone).unwrap();
+                    }
+                }
             }
    
 
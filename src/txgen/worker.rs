@@ -1,6 +1,6 @@
 use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
 use log::{debug, info};
-use crate::network::message::Message::{NewBlockHashes, self};
+use crate::network::message::{InvItem, Message};
 use crate::types::block::Block;
 use crate::network::server::Handle as ServerHandle;
 use crate::blockchain::{Blockchain, Mempool};
@@ -46,12 +46,11 @@ impl Worker {
         loop {
             let _transaction = self.finished_tx_chan.recv().expect("Receive finished transaction error");
             let _transaction_hash = _transaction.hash();
-            {self.mempool.lock().unwrap().hash_map.insert(_transaction.hash(), _transaction)};
 
-            // broadcast the hashes of the transactions after insert the transaction
-            let tx_vector = vec![_transaction_hash];
+            // broadcast the hashes of the transactions, the generator already put them in the mempool
+            let tx_vector = vec![InvItem::Transaction(_transaction_hash)];
             if tx_vector.len() > 0 {
-                self.server.broadcast(Message::NewTransactionHashes(tx_vector));
+                self.server.broadcast(Message::Inv(tx_vector));
             }
         }
     }
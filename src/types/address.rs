@@ -3,7 +3,7 @@ use ring::digest;
 use std::convert::TryInto;
 
 // 20-byte address
-#[derive(Eq, PartialEq, Serialize, Deserialize, Clone, Hash, Default, Copy)]
+#[derive(Eq, PartialEq, Ord, PartialOrd, Serialize, Deserialize, Clone, Hash, Default, Copy)]
 pub struct Address([u8; 20]);
 
 impl std::convert::From<&[u8; 20]> for Address {
@@ -48,6 +48,17 @@ impl std::fmt::Debug for Address {
     }
 }
 
+impl std::str::FromStr for Address {
+    type Err = String;
+
+    /// Parse an address from its 40 hex digits, as printed by `Display`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = hex::decode(s).map_err(|e| e.to_string())?;
+        let bytes: [u8; 20] = bytes.as_slice().try_into().map_err(|_| format!("expected 20 bytes, got {}", bytes.len()))?;
+        Ok(Address(bytes))
+    }
+}
+
 impl Address {
     pub fn from_public_key_bytes(bytes: &[u8]) -> Address {
         let actual = digest::digest(&digest::SHA256,bytes);
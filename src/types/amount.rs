@@ -0,0 +1,98 @@
+use serde::{Serialize, Deserialize};
+
+/// Sub-units, the unit balances and transaction values are counted in, that make up one coin
+pub const SUB_UNITS_PER_COIN: u64 = 100;
+
+/// An amount of money, counted in sub-units. Arithmetic on it is checked, so an overflow or an
+/// overdraft shows up as `None` instead of wrapping around or panicking in release builds.
+/// Serialized as the bare number of sub-units, the same as the plain integers it replaces, so
+/// transaction signatures, state roots and the API did not change.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(transparent)]
+pub struct Amount(u64);
+
+impl Amount {
+    pub const ZERO: Amount = Amount(0);
+
+    pub const fn from_sub_units(sub_units: u64) -> Self {
+        Amount(sub_units)
+    }
+
+    /// None if `coins` is too many to count in sub-units
+    pub fn from_coins(coins: u64) -> Option<Self> {
+        coins.checked_mul(SUB_UNITS_PER_COIN).map(Amount)
+    }
+
+    pub const fn sub_units(self) -> u64 {
+        self.0
+    }
+
+    pub fn is_zero(self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn checked_add(self, other: Amount) -> Option<Amount> {
+        self.0.checked_add(other.0).map(Amount)
+    }
+
+    pub fn checked_sub(self, other: Amount) -> Option<Amount> {
+        self.0.checked_sub(other.0).map(Amount)
+    }
+
+    /// Sum of `amounts`, None if it overflows
+    pub fn checked_sum<I: IntoIterator<Item = Amount>>(amounts: I) -> Option<Amount> {
+        amounts.into_iter().try_fold(Amount::ZERO, Amount::checked_add)
+    }
+}
+
+impl std::fmt::Display for Amount {
+    /// The number of sub-units, as parsed by `FromStr`, or with `{:#}` whole coins and the
+    /// sub-units as decimals, e.g. `1.25 coins`
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if f.alternate() {
+            let decimals = (SUB_UNITS_PER_COIN - 1).to_string().len();
+            write!(f, "{}.{:0width$} coins", self.0 / SUB_UNITS_PER_COIN, self.0 % SUB_UNITS_PER_COIN, width = decimals)
+        } else {
+            write!(f, "{}", self.0)
+        }
+    }
+}
+
+impl std::str::FromStr for Amount {
+    type Err = std::num::ParseIntError;
+
+    /// Parse a number of sub-units, as printed by `Display`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse::<u64>().map(Amount)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn arithmetic_is_checked() {
+        let a = Amount::from_sub_units(70);
+        let b = Amount::from_sub_units(30);
+        assert_eq!(a.checked_add(b), Some(Amount::from_sub_units(100)));
+        assert_eq!(a.checked_sub(b), Some(Amount::from_sub_units(40)));
+        assert_eq!(b.checked_sub(a), None);
+        assert_eq!(Amount::from_sub_units(u64::MAX).checked_add(b), None);
+        assert_eq!(Amount::checked_sum(vec![a, b, b]), Some(Amount::from_sub_units(130)));
+        assert_eq!(Amount::checked_sum(vec![a, Amount::from_sub_units(u64::MAX)]), None);
+        assert_eq!(Amount::from_coins(u64::MAX), None);
+    }
+
+    #[test]
+    fn display_and_serialization() {
+        let amount = Amount::from_coins(3).unwrap().checked_add(Amount::from_sub_units(5)).unwrap();
+        assert_eq!(amount.to_string(), "305");
+        assert_eq!(format!("{:#}", amount), "3.05 coins");
+        assert_eq!("305".parse::<Amount>(), Ok(amount));
+        assert!("-1".parse::<Amount>().is_err());
+        // the same bytes and JSON as the integers amounts used to be
+        assert_eq!(bincode::serialize(&amount).unwrap(), bincode::serialize(&305usize).unwrap());
+        assert_eq!(serde_json::to_string(&amount).unwrap(), "305");
+    }
+}
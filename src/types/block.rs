@@ -7,7 +7,8 @@ use crate::types::hash::{H256, Hashable};
 use crate::types::transaction::SignedTransaction;
 use rand::{thread_rng, Rng};
 use crate::types::merkle::MerkleTree;
-use hex_literal::hex;
+#[cfg(any(test, test_utilities))]
+use crate::types::pow::{PowAlgorithm, PowHash};
 
 use super::address::Address;
 
@@ -33,13 +34,7 @@ pub struct Block {
 }
 
 
-impl Hashable for Header {
-    fn hash(&self) -> H256 {
-        let header = bincode::serialize(self).unwrap();
-        let header_bytes = header.as_slice();
-        ring::digest::digest(&ring::digest::SHA256, header_bytes).into()
-    }
-}
+crate::hashable!(Header, SignedTransaction);
 
 impl Hashable for Block {
     fn hash(&self) -> H256 {
@@ -47,14 +42,6 @@ impl Hashable for Block {
     }
 }
 
-impl Hashable for SignedTransaction {
-    fn hash(&self) -> H256 {
-        let transac = bincode::serialize(self).unwrap();
-        let transac_bytes = transac.as_slice();
-        ring::digest::digest(&ring::digest::SHA256, &transac_bytes).into()
-    }
-}
-
 impl Block {
     pub fn get_parent(&self) -> H256 {
         self.header.parent
@@ -63,38 +50,121 @@ impl Block {
     pub fn get_difficulty(&self) -> H256 {
         self.header.difficulty
     }
+
+    /// Expected number of hashes needed to mine this block
+    pub fn work(&self) -> f64 {
+        self.header.difficulty.work()
+    }
 }
 
-// #[cfg(any(test, test_utilities))]
+#[cfg(any(test, test_utilities))]
 pub fn generate_random_block(parent: &H256) -> Block {
-    // generate a random integer for nounce
-    let mut rng = rand::thread_rng();
+    TestBlockBuilder::new(parent).build()
+}
 
-    // generate a merkle tree
-    let data: [H256; 0] = [];
-    let merkle_tree = MerkleTree::new(&data);
-    let merkle_root = merkle_tree.root();
-    let difficulty = hex!("00000effffffffffffffffffffffffffffffffffffffffffffffffffffffffff").into(); // set difficulty
+// the current time in ms, but always after the last one handed out, so blocks built one after
+// the other pass the median time past of their parents
+#[cfg(any(test, test_utilities))]
+fn next_test_timestamp() -> u128 {
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::time::{SystemTime, UNIX_EPOCH};
+    static LAST: AtomicU64 = AtomicU64::new(0);
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+    let last = LAST.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |last| Some((last + 1).max(now))).unwrap();
+    (last + 1).max(now) as u128
+}
 
-    let header = Header {
-        parent: *parent,
-        nonce: rng.gen(),
-        difficulty,
-        timestamp: rng.gen(),
-        merkle_root,
-        length: 0,
-    };
-    
-    let content = Content {transactions: Vec::new()};
-    Block {header, content}
+/// Builds blocks for tests. By default the block has a random nonce, the current time as its
+/// timestamp, no transactions, the matching merkle root and a difficulty of 20 leading zero bits,
+/// and its proof of work is not solved.
+#[cfg(any(test, test_utilities))]
+pub struct TestBlockBuilder {
+    header: Header,
+    transactions: Vec<SignedTransaction>,
+    merkle_root: Option<H256>,
+    solve_pow: Option<PowAlgorithm>,
 }
 
-pub fn generate_genesis_block(parent: &H256) -> Block {
+#[cfg(any(test, test_utilities))]
+impl TestBlockBuilder {
+    pub fn new(parent: &H256) -> Self {
+        let mut rng = rand::thread_rng();
+        let header = Header {
+            parent: *parent,
+            nonce: rng.gen(),
+            difficulty: H256::from_leading_zeros(20),
+            timestamp: next_test_timestamp(),
+            merkle_root: H256::default(),
+            length: 0,
+        };
+        TestBlockBuilder { header, transactions: Vec::new(), merkle_root: None, solve_pow: None }
+    }
+
+    pub fn parent(mut self, parent: &H256) -> Self {
+        self.header.parent = *parent;
+        self
+    }
+
+    pub fn difficulty(mut self, difficulty: H256) -> Self {
+        self.header.difficulty = difficulty;
+        self
+    }
+
+    pub fn timestamp(mut self, timestamp: u128) -> Self {
+        self.header.timestamp = timestamp;
+        self
+    }
+
+    pub fn length(mut self, length: u32) -> Self {
+        self.header.length = length;
+        self
+    }
+
+    pub fn transactions(mut self, transactions: Vec<SignedTransaction>) -> Self {
+        self.transactions = transactions;
+        self
+    }
+
+    /// Use this merkle root instead of the one computed from the transactions
+    pub fn merkle_root(mut self, merkle_root: H256) -> Self {
+        self.merkle_root = Some(merkle_root);
+        self
+    }
+
+    /// Search for a nonce so that the block hash meets its difficulty
+    pub fn solve_pow(mut self) -> Self {
+        self.solve_pow = Some(PowAlgorithm::Sha256);
+        self
+    }
+
+    /// Search for a nonce so that the proof of work hash `pow` meets the difficulty
+    pub fn solve_pow_with(mut self, pow: PowAlgorithm) -> Self {
+        self.solve_pow = Some(pow);
+        self
+    }
+
+    pub fn build(self) -> Block {
+        let mut header = self.header;
+        header.merkle_root = match self.merkle_root {
+            Some(root) => root,
+            None => MerkleTree::new(&self.transactions).root(),
+        };
+        if let Some(pow) = self.solve_pow {
+            while !pow.meets_target(&header) {
+                header.nonce = header.nonce.wrapping_add(1);
+            }
+        }
+        let content = Content {transactions: self.transactions};
+        Block {header, content}
+    }
+}
+
+pub fn generate_genesis_block(parent: &H256, difficulty: &H256) -> Block {
     // generate the genesis block determinstically
     let data: [H256; 0] = [];
     let merkle_tree = MerkleTree::new(&data);
     let merkle_root = merkle_tree.root();
-    let difficulty = hex!("000effffffffffffffffffffffffffffffffffffffffffffffffffffffffffff").into(); // set difficulty
+    let difficulty = *difficulty;
     let timestamp: u128 = 0;
     let nonce: usize = 0;
   
@@ -110,4 +180,36 @@ pub fn generate_genesis_block(parent: &H256) -> Block {
     let content = Content {transactions: Vec::new()};
     Block {header, content}
     
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builder_solves_pow() {
+        let easy = H256::from_leading_zeros(4);
+        let block = TestBlockBuilder::new(&H256::default()).difficulty(easy).solve_pow().build();
+        assert!(block.hash().meets_target(&easy));
+        assert_eq!(block.get_difficulty(), easy);
+    }
+
+    #[test]
+    fn builder_overrides_merkle_root() {
+        let parent = H256::from([1; 32]);
+        let transactions = vec![SignedTransaction::default(), SignedTransaction::default()];
+        let block = TestBlockBuilder::new(&H256::default())
+            .parent(&parent)
+            .timestamp(42)
+            .length(3)
+            .transactions(transactions.clone())
+            .build();
+        assert_eq!(block.get_parent(), parent);
+        assert_eq!(block.header.timestamp, 42);
+        assert_eq!(block.header.length, 3);
+        assert_eq!(block.header.merkle_root, MerkleTree::new(&transactions).root());
+
+        let bad_root = H256::from([9; 32]);
+        let block = TestBlockBuilder::new(&parent).transactions(transactions).merkle_root(bad_root).build();
+        assert_eq!(block.header.merkle_root, bad_root);
+    }
+}
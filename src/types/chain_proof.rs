@@ -0,0 +1,263 @@
+use ring::digest;
+use serde::{Serialize, Deserialize};
+use std::convert::TryInto;
+
+use super::block::Header;
+use super::hash::{H256, Hashable};
+use super::mmr::{self, MerkleMountainRange, MmrProof};
+use super::pow::PowHash;
+
+/// Most draws a proof may be asked for
+pub const MAX_CHAIN_PROOF_DRAWS: u32 = 256;
+
+/// A header of the chain with the proof that it is the leaf at its height in the header MMR.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SampledHeader {
+    pub header: Header,
+    pub proof: MmrProof,
+}
+
+/// Probabilistic proof of the work of a chain in the style of FlyClient: instead of every header,
+/// a verifier checks headers sampled at random from the header MMR, more densely towards the tip
+/// where a fork would branch off. A chain with much less work than claimed only passes if every
+/// sample lands on one of its few valid headers.
+///
+/// Headers here do not commit to the MMR over their ancestors as in FlyClient, so the samples
+/// are bound to their heights and to the root but not chained to each other, except for samples
+/// at consecutive heights. The proof shows that work was done at the sampled heights of a chain
+/// that long from our genesis, not that it was all done on one branch.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChainProof {
+    /// root of the header MMR of the chain, with one leaf per block from the genesis to the tip
+    pub root: H256,
+    pub leaf_count: usize,
+    /// the sampled headers by height, the genesis and the tip always among them
+    pub headers: Vec<SampledHeader>,
+}
+
+/// What a valid proof shows about the chain.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChainProofSummary {
+    pub height: u32,
+    pub tip: H256,
+    pub root: H256,
+    /// total work of the chain, estimated from the work of the samples
+    pub estimated_work: f64,
+    pub sampled_headers: usize,
+}
+
+/// Why a chain proof was refused.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChainProofError {
+    /// the proof covers no header
+    Empty,
+    /// the headers are not those the seed samples
+    Samples,
+    /// the genesis of the chain is not ours
+    Genesis(H256),
+    /// the header at this height is not the leaf at that height of the MMR
+    Inclusion(usize),
+    /// the header at this height does not meet the difficulty it declares
+    Work(usize),
+    /// the header at this height does not extend the one below it
+    Link(usize),
+}
+
+impl std::fmt::Display for ChainProofError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ChainProofError::Empty => write!(f, "the proof covers no header"),
+            ChainProofError::Samples => write!(f, "the headers are not the ones sampled"),
+            ChainProofError::Genesis(genesis) => write!(f, "genesis {} is not ours", genesis),
+            ChainProofError::Inclusion(height) => write!(f, "header at height {} is not in the header MMR", height),
+            ChainProofError::Work(height) => write!(f, "header at height {} does not meet its difficulty", height),
+            ChainProofError::Link(height) => write!(f, "header at height {} does not extend its parent", height),
+        }
+    }
+}
+
+// windows a draw can fall in: the last 1, 2, 4, ... leaves, the last covering the whole chain
+fn windows(leaf_count: usize) -> u32 {
+    usize::BITS - (leaf_count - 1).leading_zeros() + 1
+}
+
+/// Heights drawn for a proof against `root` over `leaf_count` leaves, from the verifier's `seed`.
+/// Each draw picks one of the windows of the last 1, 2, 4, ... leaves evenly, then a height in
+/// it evenly, so a height `d` below the tip is drawn with a probability close to FlyClient's
+/// 1 / ((d + 1) ln n). Heights may repeat. Integers only, so prover and verifier agree.
+pub fn draw_heights(seed: &H256, root: &H256, leaf_count: usize, draws: u32) -> Vec<usize> {
+    let windows = windows(leaf_count);
+    (0..draws)
+        .map(|draw| {
+            let mut ctx = digest::Context::new(&digest::SHA256);
+            ctx.update(seed.as_ref());
+            ctx.update(root.as_ref());
+            ctx.update(&draw.to_be_bytes());
+            let random = ctx.finish();
+            let window = u64::from_be_bytes(random.as_ref()[..8].try_into().unwrap()) % windows as u64;
+            let size = (1usize << window).min(leaf_count);
+            let depth = u64::from_be_bytes(random.as_ref()[8..16].try_into().unwrap()) % size as u64;
+            leaf_count - 1 - depth as usize
+        })
+        .collect()
+}
+
+// probability that one draw picks `height`, for weighting its work
+fn draw_probability(height: usize, leaf_count: usize) -> f64 {
+    let depth = leaf_count - 1 - height;
+    let windows = windows(leaf_count);
+    (0..windows)
+        .map(|window| (1usize << window).min(leaf_count))
+        .filter(|size| *size > depth)
+        .map(|size| 1.0 / size as f64)
+        .sum::<f64>()
+        / windows as f64
+}
+
+// the heights a proof holds headers at: the drawn ones, the genesis and the tip, in order
+fn sampled_heights(draws: &[usize], leaf_count: usize) -> Vec<usize> {
+    let mut heights = draws.to_vec();
+    heights.push(0);
+    heights.push(leaf_count - 1);
+    heights.sort_unstable();
+    heights.dedup();
+    heights
+}
+
+/// Prove the chain whose headers, genesis first, are the leaves of `mmr`, with `draws` heights
+/// drawn from `seed`. `header` looks up a header by hash.
+pub fn prove<F>(mmr: &MerkleMountainRange, header: F, seed: &H256, draws: u32) -> ChainProof
+where
+    F: Fn(&H256) -> Header,
+{
+    let root = mmr.root();
+    let leaf_count = mmr.len();
+    let heights = sampled_heights(&draw_heights(seed, &root, leaf_count, draws), leaf_count);
+    let headers = heights
+        .into_iter()
+        .map(|height| SampledHeader {
+            header: header(&mmr.leaf(height).unwrap()),
+            proof: mmr.prove(height, leaf_count).unwrap(),
+        })
+        .collect();
+    ChainProof { root, leaf_count, headers }
+}
+
+impl ChainProof {
+    /// Check the proof the prover built for `seed` and `draws` against our `genesis`, the proof of
+    /// work of every header being checked with `pow`, and estimate the work of the chain
+    pub fn verify<P: PowHash>(&self, seed: &H256, draws: u32, genesis: &H256, pow: &P) -> Result<ChainProofSummary, ChainProofError> {
+        if self.leaf_count == 0 {
+            return Err(ChainProofError::Empty);
+        }
+        let drawn = draw_heights(seed, &self.root, self.leaf_count, draws);
+        let heights = sampled_heights(&drawn, self.leaf_count);
+        if heights.len() != self.headers.len() || heights.iter().zip(self.headers.iter()).any(|(height, sample)| sample.proof.leaf_index != *height) {
+            return Err(ChainProofError::Samples);
+        }
+        let mut previous: Option<(usize, H256)> = None;
+        for (height, sample) in heights.iter().copied().zip(self.headers.iter()) {
+            let hash = sample.header.hash();
+            if sample.proof.leaf_count != self.leaf_count
+                || sample.header.length as usize != height
+                || !mmr::verify(&self.root, &hash, &sample.proof)
+            {
+                return Err(ChainProofError::Inclusion(height));
+            }
+            if height == 0 {
+                // the genesis is not mined, it is known
+                if hash != *genesis {
+                    return Err(ChainProofError::Genesis(hash));
+                }
+            } else if !pow.meets_target(&sample.header) {
+                return Err(ChainProofError::Work(height));
+            }
+            if let Some((below, parent)) = previous {
+                if below + 1 == height && sample.header.parent != parent {
+                    return Err(ChainProofError::Link(height));
+                }
+            }
+            previous = Some((height, hash));
+        }
+
+        // each draw weighs the work of its header by how likely it was drawn
+        let work = |height: usize| {
+            let index = heights.binary_search(&height).unwrap();
+            self.headers[index].header.difficulty.work() / draw_probability(height, self.leaf_count)
+        };
+        let estimated_work = if drawn.is_empty() {
+            0.0
+        } else {
+            drawn.iter().map(|height| work(*height)).sum::<f64>() / drawn.len() as f64
+        };
+        let tip = self.headers.last().unwrap().header.hash();
+        Ok(ChainProofSummary {
+            height: (self.leaf_count - 1) as u32,
+            tip,
+            root: self.root,
+            estimated_work,
+            sampled_headers: self.headers.len(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::block::TestBlockBuilder;
+    use crate::types::pow::PowAlgorithm;
+    use std::collections::HashMap;
+
+    #[test]
+    fn sampled_headers_prove_the_work() {
+        let easy = H256::from_leading_zeros(4);
+        let genesis = TestBlockBuilder::new(&H256::default()).difficulty(easy).build().header;
+        let mut headers = HashMap::new();
+        let mut mmr = MerkleMountainRange::new();
+        let mut parent = genesis.hash();
+        headers.insert(parent, genesis);
+        mmr.push(parent);
+        for length in 1..100 {
+            let header = TestBlockBuilder::new(&parent).difficulty(easy).length(length).solve_pow().build().header;
+            parent = header.hash();
+            headers.insert(parent, header);
+            mmr.push(parent);
+        }
+        let lookup = |hash: &H256| headers[hash].clone();
+        let seed = H256::from([7; 32]);
+        let proof = prove(&mmr, lookup, &seed, 64);
+        assert!(proof.headers.len() < 66);
+        let genesis_hash = mmr.leaf(0).unwrap();
+        let summary = proof.verify(&seed, 64, &genesis_hash, &PowAlgorithm::Sha256).unwrap();
+        assert_eq!((summary.height, summary.tip, summary.root), (99, parent, mmr.root()));
+        // 100 blocks of equal work
+        let total = 100.0 * easy.work();
+        assert!(summary.estimated_work > total / 2.0 && summary.estimated_work < total * 2.0, "{}", summary.estimated_work);
+        // draws far back are rare
+        let drawn = draw_heights(&seed, &mmr.root(), 100, 1000);
+        assert!(drawn.iter().filter(|height| **height >= 50).count() > 800);
+
+        // the headers were sampled for another seed
+        assert_eq!(proof.verify(&H256::from([8; 32]), 64, &genesis_hash, &PowAlgorithm::Sha256).unwrap_err(), ChainProofError::Samples);
+        // another network
+        assert_eq!(proof.verify(&seed, 64, &H256::default(), &PowAlgorithm::Sha256).unwrap_err(), ChainProofError::Genesis(genesis_hash));
+        // a header changed after the fact is not the leaf at its height
+        let mut forged = proof.clone();
+        let height = forged.headers[1].header.length as usize;
+        forged.headers[1].header.nonce = forged.headers[1].header.nonce.wrapping_add(1);
+        assert_eq!(forged.verify(&seed, 64, &genesis_hash, &PowAlgorithm::Sha256).unwrap_err(), ChainProofError::Inclusion(height));
+        // a chain of headers nobody mined, in an MMR built around them
+        let hard = H256::from_leading_zeros(64);
+        let mut fake = MerkleMountainRange::new();
+        fake.push(genesis_hash);
+        let mut parent = genesis_hash;
+        for length in 1..100 {
+            let header = TestBlockBuilder::new(&parent).difficulty(hard).length(length).build().header;
+            parent = header.hash();
+            headers.insert(parent, header);
+            fake.push(parent);
+        }
+        let forged = prove(&fake, |hash: &H256| headers[hash].clone(), &seed, 64);
+        assert!(matches!(forged.verify(&seed, 64, &genesis_hash, &PowAlgorithm::Sha256), Err(ChainProofError::Work(_))));
+    }
+}
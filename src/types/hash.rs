@@ -11,6 +11,60 @@ pub trait Hashable {
     fn hash(&self) -> H256;
 }
 
+/// SHA256 context that bytes can be written to, so a value is hashed as it is serialized
+/// instead of serialized to a buffer first.
+pub struct DigestWriter(ring::digest::Context);
+
+impl DigestWriter {
+    pub fn new() -> Self {
+        DigestWriter(ring::digest::Context::new(&ring::digest::SHA256))
+    }
+
+    pub fn finish(self) -> H256 {
+        self.0.finish().into()
+    }
+}
+
+impl Default for DigestWriter {
+    fn default() -> Self {
+        DigestWriter::new()
+    }
+}
+
+impl std::io::Write for DigestWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// SHA256 of the bincode serialization of `value`, fed to the digest field by field
+pub fn digest_serialized<T: Serialize + ?Sized>(value: &T) -> H256 {
+    let mut writer = DigestWriter::new();
+    // writing to the digest cannot fail, nor can serializing the consensus types
+    bincode::serialize_into(&mut writer, value).unwrap();
+    writer.finish()
+}
+
+/// Implement `Hashable` for consensus types as the SHA256 of their bincode serialization,
+/// streamed into the digest by `digest_serialized`
+#[macro_export]
+macro_rules! hashable {
+    ($($t:ty),+ $(,)?) => {
+        $(
+            impl $crate::types::hash::Hashable for $t {
+                fn hash(&self) -> $crate::types::hash::H256 {
+                    $crate::types::hash::digest_serialized(self)
+                }
+            }
+        )+
+    };
+}
+
 /// A SHA256 hash.
 #[derive(Eq, PartialEq, Serialize, Deserialize, Clone, Hash, Default, Copy)]
 pub struct H256([u8; 32]); // big endian u256
@@ -91,6 +145,151 @@ impl std::convert::From<ring::digest::Digest> for H256 {
     }
 }
 
+impl std::str::FromStr for H256 {
+    type Err = String;
+
+    /// Parse a hash from its 64 hex digits, as printed by `Display`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = hex::decode(s).map_err(|e| e.to_string())?;
+        let bytes: [u8; 32] = bytes.as_slice().try_into().map_err(|_| format!("expected 32 bytes, got {}", bytes.len()))?;
+        Ok(H256(bytes))
+    }
+}
+
+impl H256 {
+    /// The easiest target, met by every hash
+    pub const MAX: H256 = H256([0xff; 32]);
+
+    /// Target met by the hashes starting with at least `bits` zero bits
+    pub fn from_leading_zeros(bits: u32) -> H256 {
+        let mut raw = [0xffu8; 32];
+        for (i, byte) in raw.iter_mut().enumerate() {
+            let zeros = bits.saturating_sub(8 * i as u32).min(8);
+            *byte = if zeros == 8 { 0 } else { 0xff >> zeros };
+        }
+        H256(raw)
+    }
+
+    /// Number of leading zero bits, 256 for the zero hash
+    pub fn leading_zeros(&self) -> u32 {
+        let mut bits = 0;
+        for byte in self.0.iter() {
+            bits += byte.leading_zeros();
+            if *byte != 0 {
+                break;
+            }
+        }
+        bits
+    }
+
+    /// Target of a decimal difficulty, i.e. `difficulty` times harder to meet than `MAX`
+    pub fn from_difficulty(difficulty: u64) -> Result<H256, String> {
+        if difficulty == 0 {
+            return Err("difficulty must be at least 1".to_string());
+        }
+        Ok(H256::MAX.scale(1, difficulty))
+    }
+
+    /// Target from the compact encoding used by Bitcoin's nBits: the high byte is the length of
+    /// the target in bytes and the low three bytes its most significant bytes
+    pub fn from_compact(compact: u32) -> Result<H256, String> {
+        let size = (compact >> 24) as usize;
+        let mantissa = compact & 0x00ff_ffff;
+        if mantissa & 0x0080_0000 != 0 {
+            return Err(format!("compact target {:08x} is negative", compact));
+        }
+        let mut raw = [0u8; 32];
+        for (i, byte) in mantissa.to_be_bytes()[1..].iter().enumerate() {
+            // bytes shifted below the last one are dropped, as in Bitcoin
+            match (32 + i).checked_sub(size) {
+                Some(index) if index < 32 => raw[index] = *byte,
+                Some(_) => {}
+                None if *byte == 0 => {}
+                None => return Err(format!("compact target {:08x} overflows 256 bits", compact)),
+            }
+        }
+        Ok(H256(raw))
+    }
+
+    /// Compact encoding of this target, rounded down to its three most significant bytes
+    pub fn to_compact(&self) -> u32 {
+        let first = match self.0.iter().position(|b| *b != 0) {
+            Some(first) => first,
+            None => return 0,
+        };
+        let mut size = 32 - first;
+        let mut mantissa = self.0[first..].iter().take(3).fold(0u32, |acc, b| (acc << 8) | *b as u32);
+        // pad a target shorter than three bytes
+        mantissa <<= 8 * (3 - size.min(3)) as u32;
+        // the high bit of the mantissa is a sign bit, keep it clear
+        if mantissa & 0x0080_0000 != 0 {
+            mantissa >>= 8;
+            size += 1;
+        }
+        ((size as u32) << 24) | mantissa
+    }
+
+    /// Parse a target written as 64 hex digits, `zeros:<bits>`, `compact:<8 hex digits>` or a
+    /// decimal difficulty
+    pub fn parse_target(s: &str) -> Result<H256, String> {
+        if let Some(bits) = s.strip_prefix("zeros:") {
+            let bits = bits.parse::<u32>().map_err(|e| format!("error parsing leading zero bits: {}", e))?;
+            return Ok(H256::from_leading_zeros(bits));
+        }
+        if let Some(compact) = s.strip_prefix("compact:") {
+            let compact = u32::from_str_radix(compact.trim_start_matches("0x"), 16)
+                .map_err(|e| format!("error parsing compact target: {}", e))?;
+            return H256::from_compact(compact);
+        }
+        if s.len() == 64 {
+            return s.parse();
+        }
+        let difficulty = s.parse::<u64>().map_err(|e| format!("error parsing difficulty: {}", e))?;
+        H256::from_difficulty(difficulty)
+    }
+
+    /// Whether this hash, read as a number, is at most `target`
+    pub fn meets_target(&self, target: &H256) -> bool {
+        self <= target
+    }
+
+    /// Expected number of hashes to meet this target, i.e. 2^256 / (target + 1)
+    pub fn work(&self) -> f64 {
+        let target = self.0.iter().fold(0.0, |acc, byte| acc * 256.0 + *byte as f64);
+        2f64.powi(256) / (target + 1.0)
+    }
+
+    /// Multiply by `num / den` as a 256-bit integer, saturating at the largest value
+    pub fn scale(&self, num: u64, den: u64) -> H256 {
+        assert!(den > 0, "cannot scale by a zero denominator");
+        // the product needs up to 8 more bytes than the hash itself
+        let mut product = [0u8; 40];
+        let mut carry: u128 = 0;
+        for i in (0..32).rev() {
+            let acc = self.0[i] as u128 * num as u128 + carry;
+            product[i + 8] = acc as u8;
+            carry = acc >> 8;
+        }
+        for i in (0..8).rev() {
+            product[i] = carry as u8;
+            carry >>= 8;
+        }
+        let mut quotient = [0u8; 40];
+        let mut rem: u128 = 0;
+        for i in 0..40 {
+            let cur = (rem << 8) | product[i] as u128;
+            quotient[i] = (cur / den as u128) as u8;
+            rem = cur % den as u128;
+        }
+        if quotient[0..8].iter().any(|b| *b != 0) {
+            return H256::MAX;
+        }
+        let mut raw = [0u8; 32];
+        raw.copy_from_slice(&quotient[8..40]);
+        H256(raw)
+    }
+}
+
 impl Ord for H256 {
     fn cmp(&self, other: &H256) -> std::cmp::Ordering {
         let self_higher = u128::from_be_bytes(self.0[0..16].try_into().unwrap());
@@ -118,4 +317,64 @@ pub fn generate_random_hash() -> H256 {
     let mut raw_bytes = [0; 32];
     raw_bytes.copy_from_slice(&random_bytes);
     (&raw_bytes).into()
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hex_literal::hex;
+
+    #[test]
+    fn streamed_digest_matches_serialized() {
+        use crate::types::block::TestBlockBuilder;
+        use crate::types::transaction::{generate_random_transaction, SignedTransaction};
+        let tx = SignedTransaction {
+            t: generate_random_transaction(),
+            signature_vector: vec![7; 64],
+            signer_public_key: vec![9; 32],
+        };
+        let block = TestBlockBuilder::new(&H256::default()).length(3).transactions(vec![tx.clone()]).build();
+        // the hashes identifying blocks and transactions must not change with the streaming
+        let header: H256 = ring::digest::digest(&ring::digest::SHA256, &bincode::serialize(&block.header).unwrap()).into();
+        assert_eq!(block.hash(), header);
+        let transaction: H256 = ring::digest::digest(&ring::digest::SHA256, &bincode::serialize(&tx).unwrap()).into();
+        assert_eq!(tx.hash(), transaction);
+    }
+
+    #[test]
+    fn leading_zeros_round_trip() {
+        let target = H256::from_leading_zeros(12);
+        assert_eq!(target, hex!("000fffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff").into());
+        assert_eq!(target.leading_zeros(), 12);
+        assert_eq!(H256::from_leading_zeros(0), H256::MAX);
+        assert_eq!(H256::default().leading_zeros(), 256);
+    }
+
+    #[test]
+    fn compact_targets() {
+        // the difficulty 1 target of Bitcoin
+        let target = H256::from_compact(0x1d00ffff).unwrap();
+        assert_eq!(target, hex!("00000000ffff0000000000000000000000000000000000000000000000000000").into());
+        assert_eq!(target.to_compact(), 0x1d00ffff);
+        // the sign bit moves the mantissa one byte down
+        assert_eq!(H256::MAX.to_compact(), 0x2100ffff);
+        assert!(H256::from_compact(0x1d800000).is_err());
+        assert!(H256::from_compact(0x2201ffff).is_err());
+    }
+
+    #[test]
+    fn parse_targets() {
+        assert_eq!(H256::parse_target("zeros:12"), Ok(H256::from_leading_zeros(12)));
+        assert_eq!(H256::parse_target("compact:1d00ffff"), H256::from_compact(0x1d00ffff));
+        assert_eq!(H256::parse_target("16").unwrap(), H256::from_leading_zeros(4));
+        assert_eq!(H256::parse_target(&H256::MAX.to_string()), Ok(H256::MAX));
+        assert!(H256::parse_target("0").is_err());
+    }
+
+    #[test]
+    fn work_of_targets() {
+        assert!(H256::default().meets_target(&H256::from_leading_zeros(8)));
+        assert!(!H256::MAX.meets_target(&H256::from_leading_zeros(8)));
+        assert_eq!(H256::from_leading_zeros(8).work(), 256.0);
+    }
+}
@@ -1,5 +1,8 @@
 use ring::rand;
 use ring::signature::Ed25519KeyPair;
+use std::fs;
+use std::io;
+use std::path::Path;
 
 /// Generate a random key pair.
 pub fn random() -> Ed25519KeyPair {
@@ -7,3 +10,19 @@ pub fn random() -> Ed25519KeyPair {
     let pkcs8_bytes = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
     Ed25519KeyPair::from_pkcs8(pkcs8_bytes.as_ref().into()).unwrap()
 }
+
+/// Load the key pair stored as PKCS#8 at `path`, generating and storing a new one the first
+/// time, so a node keeps the same identity across restarts.
+pub fn load_or_generate<P: AsRef<Path>>(path: P) -> io::Result<Ed25519KeyPair> {
+    let pkcs8_bytes = match fs::read(&path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let rng = rand::SystemRandom::new();
+            let bytes = Ed25519KeyPair::generate_pkcs8(&rng).unwrap().as_ref().to_vec();
+            fs::write(&path, &bytes)?;
+            bytes
+        }
+        Err(e) => return Err(e),
+    };
+    Ed25519KeyPair::from_pkcs8(&pkcs8_bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+}
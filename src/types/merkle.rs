@@ -1,4 +1,3 @@
-use crate::miner::Context;
 use super::hash::{Hashable, H256};
 use ring::digest;
 
@@ -70,10 +69,10 @@ impl MerkleTree {
         let mut new_index = index;
         let mut prooftree: Vec<H256> = Vec::new();
         for n in 0..self.merkle.len()-1{
-            if new_index % 2 == 0 {prooftree.push(self.merkle[n][new_index+1])
-            }
-            else {prooftree.push(self.merkle[n][index-1])
-            }
+            let layer = &self.merkle[n];
+            let sibling = if new_index % 2 == 0 {new_index + 1} else {new_index - 1};
+            // a layer of odd length pairs its last hash with itself
+            prooftree.push(*layer.get(sibling).unwrap_or(&layer[new_index]));
             new_index = new_index / 2;
         }
         prooftree
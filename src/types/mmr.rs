@@ -0,0 +1,205 @@
+use super::hash::H256;
+use ring::digest;
+use serde::{Serialize, Deserialize};
+
+/// A Merkle mountain range: an append-only list of leaves committed to by a forest of perfect
+/// Merkle trees, one per set bit of the number of leaves. Appending a leaf merges equal-height
+/// trees, so the range of every prefix of the leaves is a prefix of each level and old roots
+/// stay provable. `levels[0]` are the leaves and `levels[k][i]` the root of leaves
+/// `i * 2^k .. (i + 1) * 2^k`, for the complete subtrees only.
+#[derive(Debug, Clone)]
+pub struct MerkleMountainRange {
+    levels: Vec<Vec<H256>>,
+}
+
+/// Proof that a leaf is in the range of `leaf_count` leaves: the siblings up to the peak of its
+/// tree, and every peak to bag them into the root.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MmrProof {
+    pub leaf_index: usize,
+    pub leaf_count: usize,
+    pub siblings: Vec<H256>,
+    pub peaks: Vec<H256>,
+}
+
+fn hash_pair(left: &H256, right: &H256) -> H256 {
+    let mut ctx = digest::Context::new(&digest::SHA256);
+    ctx.update(left.as_ref());
+    ctx.update(right.as_ref());
+    ctx.finish().into()
+}
+
+// fold the peaks right to left, then commit to the number of leaves so ranges of different
+// sizes never share a root
+fn bag(peaks: &[H256], leaf_count: usize) -> H256 {
+    let bagged = match peaks.split_last() {
+        Some((last, rest)) => rest.iter().rev().fold(*last, |acc, peak| hash_pair(peak, &acc)),
+        None => H256::default(),
+    };
+    let mut ctx = digest::Context::new(&digest::SHA256);
+    ctx.update(&(leaf_count as u64).to_be_bytes());
+    ctx.update(bagged.as_ref());
+    ctx.finish().into()
+}
+
+impl Default for MerkleMountainRange {
+    fn default() -> Self {
+        MerkleMountainRange::new()
+    }
+}
+
+impl MerkleMountainRange {
+    pub fn new() -> Self {
+        MerkleMountainRange { levels: vec![Vec::new()] }
+    }
+
+    pub fn len(&self) -> usize {
+        self.levels[0].len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn leaf(&self, index: usize) -> Option<H256> {
+        self.levels[0].get(index).copied()
+    }
+
+    pub fn push(&mut self, leaf: H256) {
+        self.levels[0].push(leaf);
+        let mut level = 0;
+        // a level of even length just completed a pair, merge it one level up
+        while self.levels[level].len().is_multiple_of(2) {
+            let nodes = &self.levels[level];
+            let parent = hash_pair(&nodes[nodes.len() - 2], &nodes[nodes.len() - 1]);
+            if self.levels.len() == level + 1 {
+                self.levels.push(Vec::new());
+            }
+            self.levels[level + 1].push(parent);
+            level += 1;
+        }
+    }
+
+    /// Keep only the first `leaf_count` leaves, to follow a reorg
+    pub fn truncate(&mut self, leaf_count: usize) {
+        for (level, nodes) in self.levels.iter_mut().enumerate() {
+            nodes.truncate(leaf_count >> level);
+        }
+    }
+
+    // peaks of the range of the first `leaf_count` leaves, highest first
+    fn peaks(&self, leaf_count: usize) -> Vec<H256> {
+        (0..self.levels.len())
+            .rev()
+            .filter(|level| leaf_count >> level & 1 == 1)
+            .map(|level| self.levels[level][(leaf_count >> level) - 1])
+            .collect()
+    }
+
+    /// Root of the range of the first `leaf_count` leaves, None if there are fewer leaves
+    pub fn root_at(&self, leaf_count: usize) -> Option<H256> {
+        if leaf_count > self.len() {
+            return None;
+        }
+        Some(bag(&self.peaks(leaf_count), leaf_count))
+    }
+
+    pub fn root(&self) -> H256 {
+        self.root_at(self.len()).unwrap()
+    }
+
+    /// Prove that leaf `index` is in the range of the first `leaf_count` leaves
+    pub fn prove(&self, index: usize, leaf_count: usize) -> Option<MmrProof> {
+        if index >= leaf_count || leaf_count > self.len() {
+            return None;
+        }
+        let mut siblings = Vec::new();
+        let (mut level, mut position) = (0, index);
+        // climb while the parent is a complete subtree of the first `leaf_count` leaves
+        while level + 1 < self.levels.len() && position / 2 < leaf_count >> (level + 1) {
+            siblings.push(self.levels[level][position ^ 1]);
+            level += 1;
+            position /= 2;
+        }
+        Some(MmrProof { leaf_index: index, leaf_count, siblings, peaks: self.peaks(leaf_count) })
+    }
+}
+
+/// Verify that `leaf` is at `proof.leaf_index` in a range whose root is `root`
+pub fn verify(root: &H256, leaf: &H256, proof: &MmrProof) -> bool {
+    if proof.leaf_index >= proof.leaf_count || bag(&proof.peaks, proof.leaf_count) != *root {
+        return false;
+    }
+    // find the tree holding the leaf, trees are laid out highest first
+    let mut start = 0;
+    let mut peak = 0;
+    for level in (0..usize::BITS as usize).rev().filter(|level| proof.leaf_count >> level & 1 == 1) {
+        if proof.leaf_index < start + (1 << level) {
+            if proof.siblings.len() != level {
+                return false;
+            }
+            break;
+        }
+        start += 1 << level;
+        peak += 1;
+    }
+    let mut node = *leaf;
+    let mut position = proof.leaf_index - start;
+    for sibling in proof.siblings.iter() {
+        node = if position.is_multiple_of(2) { hash_pair(&node, sibling) } else { hash_pair(sibling, &node) };
+        position /= 2;
+    }
+    proof.peaks.get(peak) == Some(&node)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaf(i: usize) -> H256 {
+        let mut bytes = [0u8; 32];
+        bytes[..8].copy_from_slice(&(i as u64).to_be_bytes());
+        H256::from(bytes)
+    }
+
+    #[test]
+    fn every_leaf_is_provable_at_every_size() {
+        let mut mmr = MerkleMountainRange::new();
+        let mut roots = Vec::new();
+        for i in 0..19 {
+            mmr.push(leaf(i));
+            roots.push(mmr.root());
+        }
+        for size in 1..=19 {
+            // old roots are kept by the prefix of the range
+            assert_eq!(mmr.root_at(size), Some(roots[size - 1]));
+            for i in 0..size {
+                let proof = mmr.prove(i, size).unwrap();
+                assert!(verify(&roots[size - 1], &leaf(i), &proof), "leaf {} of {}", i, size);
+                assert!(!verify(&roots[size - 1], &leaf(i + 1), &proof));
+            }
+        }
+        assert!(mmr.prove(19, 19).is_none());
+        // a root of another size does not verify
+        let proof = mmr.prove(3, 8).unwrap();
+        assert!(!verify(&roots[8], &leaf(3), &proof));
+    }
+
+    #[test]
+    fn truncate_follows_reorg() {
+        let mut mmr = MerkleMountainRange::new();
+        for i in 0..11 {
+            mmr.push(leaf(i));
+        }
+        let mut other = MerkleMountainRange::new();
+        for i in 0..6 {
+            other.push(leaf(i));
+        }
+        mmr.truncate(6);
+        assert_eq!(mmr.root(), other.root());
+        mmr.push(leaf(100));
+        other.push(leaf(100));
+        assert_eq!(mmr.root(), other.root());
+        assert_eq!(mmr.len(), 7);
+    }
+}
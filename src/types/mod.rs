@@ -1,6 +1,11 @@
 pub mod address;
+pub mod amount;
 pub mod block;
+pub mod chain_proof;
 pub mod hash;
 pub mod merkle;
+pub mod mmr;
+pub mod pow;
+pub mod state_proof;
 pub mod key_pair;
 pub mod transaction;
\ No newline at end of file
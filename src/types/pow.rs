@@ -0,0 +1,147 @@
+use super::block::Header;
+use super::hash::{H256, Hashable};
+
+use serde::{Serialize, Deserialize};
+
+/// Hash function of the proof of work. The hash identifying a block is always SHA-256 of its
+/// header; the proof of work hash of the header is the one that must meet the difficulty.
+pub trait PowHash {
+    fn pow_hash(&self, header: &Header) -> H256;
+
+    /// Whether the proof of work of `header` meets the difficulty it declares
+    fn meets_target(&self, header: &Header) -> bool {
+        self.pow_hash(header).meets_target(&header.difficulty)
+    }
+}
+
+/// SHA-256 of the header, the block hash itself.
+pub struct Sha256Pow;
+
+impl PowHash for Sha256Pow {
+    fn pow_hash(&self, header: &Header) -> H256 {
+        header.hash()
+    }
+}
+
+/// Memory-hard Argon2id hash of the block hash, with a scratchpad of `memory_kib` KiB and
+/// `passes` passes over it, so computing one hash needs the whole scratchpad in memory.
+pub struct Argon2idPow {
+    pub memory_kib: u32,
+    pub passes: u32,
+}
+
+/// Smallest scratchpad Argon2 takes with one lane
+pub const MIN_MEMORY_KIB: u32 = 8;
+/// Largest scratchpad of a network, every node spends it on each header it checks
+pub const MAX_MEMORY_KIB: u32 = 256 * 1024;
+pub const MAX_PASSES: u32 = 16;
+
+// Argon2 wants a salt; the block hash is unique already, the salt only separates this use
+const SALT: &[u8] = b"ece470 proof of work";
+
+impl PowHash for Argon2idPow {
+    fn pow_hash(&self, header: &Header) -> H256 {
+        let params = argon2::Params::new(self.memory_kib, self.passes, 1, Some(32))
+            .expect("proof of work parameters are checked with the chain parameters");
+        let argon2 = argon2::Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params);
+        let mut hash = [0u8; 32];
+        argon2.hash_password_into(header.hash().as_ref(), SALT, &mut hash).unwrap();
+        hash.into()
+    }
+}
+
+/// Proof of work hash a network uses, part of its chain parameters.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(tag = "algorithm", rename_all = "kebab-case")]
+pub enum PowAlgorithm {
+    #[default]
+    Sha256,
+    Argon2id { memory_kib: u32, passes: u32 },
+}
+
+impl PowAlgorithm {
+    /// Whether every node can afford to compute the hash: a scratchpad of
+    /// `MIN_MEMORY_KIB..=MAX_MEMORY_KIB` and `1..=MAX_PASSES` passes
+    pub fn check(&self) -> Result<(), String> {
+        if let PowAlgorithm::Argon2id { memory_kib, passes } = *self {
+            if !(MIN_MEMORY_KIB..=MAX_MEMORY_KIB).contains(&memory_kib) {
+                return Err(format!("scratchpad of {} KiB, expected {} to {}", memory_kib, MIN_MEMORY_KIB, MAX_MEMORY_KIB));
+            }
+            if !(1..=MAX_PASSES).contains(&passes) {
+                return Err(format!("{} passes, expected 1 to {}", passes, MAX_PASSES));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl PowHash for PowAlgorithm {
+    fn pow_hash(&self, header: &Header) -> H256 {
+        match *self {
+            PowAlgorithm::Sha256 => Sha256Pow.pow_hash(header),
+            PowAlgorithm::Argon2id { memory_kib, passes } => Argon2idPow { memory_kib, passes }.pow_hash(header),
+        }
+    }
+}
+
+impl std::str::FromStr for PowAlgorithm {
+    type Err = String;
+
+    /// `sha256`, or `argon2id:<memory KiB>:<passes>`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split(':').collect();
+        let algorithm = match parts.as_slice() {
+            ["sha256"] => PowAlgorithm::Sha256,
+            ["argon2id", memory_kib, passes] => {
+                let memory_kib = memory_kib.parse::<u32>().map_err(|e| format!("bad scratchpad size: {}", e))?;
+                let passes = passes.parse::<u32>().map_err(|e| format!("bad number of passes: {}", e))?;
+                PowAlgorithm::Argon2id { memory_kib, passes }
+            }
+            _ => return Err(format!("unknown proof of work {}, expected sha256 or argon2id:<KiB>:<passes>", s)),
+        };
+        algorithm.check()?;
+        Ok(algorithm)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::block::TestBlockBuilder;
+
+    #[test]
+    fn argon2id_depends_on_parameters() {
+        let header = TestBlockBuilder::new(&H256::default()).build().header;
+        let small = PowAlgorithm::Argon2id { memory_kib: 8, passes: 1 };
+        assert_eq!(small.pow_hash(&header), small.pow_hash(&header));
+        assert_ne!(small.pow_hash(&header), header.hash());
+        assert_ne!(small.pow_hash(&header), PowAlgorithm::Argon2id { memory_kib: 16, passes: 1 }.pow_hash(&header));
+        assert_ne!(small.pow_hash(&header), PowAlgorithm::Argon2id { memory_kib: 8, passes: 2 }.pow_hash(&header));
+        assert_eq!(PowAlgorithm::Sha256.pow_hash(&header), header.hash());
+    }
+
+    #[test]
+    fn argon2id_blocks_are_mined_and_checked_alike() {
+        let pow = PowAlgorithm::Argon2id { memory_kib: 8, passes: 1 };
+        let block = TestBlockBuilder::new(&H256::default())
+            .difficulty(H256::from_leading_zeros(6))
+            .solve_pow_with(pow)
+            .build();
+        assert!(pow.meets_target(&block.header));
+    }
+
+    #[test]
+    fn parse_algorithm() {
+        assert_eq!("sha256".parse::<PowAlgorithm>(), Ok(PowAlgorithm::Sha256));
+        assert_eq!(
+            "argon2id:256:2".parse::<PowAlgorithm>(),
+            Ok(PowAlgorithm::Argon2id { memory_kib: 256, passes: 2 })
+        );
+        // a scratchpad too small for Argon2, or one every node must spend too much on
+        assert!("argon2id:4:1".parse::<PowAlgorithm>().is_err());
+        assert!(format!("argon2id:{}:1", MAX_MEMORY_KIB + 1).parse::<PowAlgorithm>().is_err());
+        assert!("argon2id:64:0".parse::<PowAlgorithm>().is_err());
+        assert!("scratchpad:256:2".parse::<PowAlgorithm>().is_err());
+        assert!("argon2".parse::<PowAlgorithm>().is_err());
+    }
+}
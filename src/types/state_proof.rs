@@ -0,0 +1,119 @@
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+
+use super::address::Address;
+use super::amount::Amount;
+use super::hash::{H256, Hashable};
+use super::merkle::{self, MerkleTree};
+
+/// One account of the state, a leaf of the state Merkle tree.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AccountEntry {
+    pub address: Address,
+    pub nonce: usize,
+    pub balance: Amount,
+}
+
+crate::hashable!(AccountEntry);
+
+/// Proof that an account has a given nonce and balance in the state committed to by `state_root`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StateProof {
+    /// the block whose resulting state is proven
+    pub block_hash: H256,
+    pub state_root: H256,
+    pub entry: AccountEntry,
+    pub index: usize,
+    pub leaf_size: usize,
+    pub proof: Vec<H256>,
+}
+
+impl StateProof {
+    /// Check the proof against a state root obtained from a source the caller trusts
+    pub fn verify(&self, state_root: &H256) -> bool {
+        self.state_root == *state_root
+            && merkle::verify(state_root, &self.entry.hash().hash(), &self.proof, self.index, self.leaf_size)
+    }
+}
+
+// the leaves of the state tree are the accounts sorted by address, so every node builds the same tree
+fn sorted_entries(state: &HashMap<Address, (usize, Amount)>) -> Vec<AccountEntry> {
+    let mut entries: Vec<AccountEntry> = state
+        .iter()
+        .map(|(address, (nonce, balance))| AccountEntry { address: *address, nonce: *nonce, balance: *balance })
+        .collect();
+    entries.sort_by_key(|e| e.address);
+    entries
+}
+
+/// Merkle root committing to every account of a state
+pub fn state_root(state: &HashMap<Address, (usize, Amount)>) -> H256 {
+    let leaves: Vec<H256> = sorted_entries(state).iter().map(|e| e.hash()).collect();
+    MerkleTree::new(&leaves).root()
+}
+
+/// Build the proof of an account in the state after `block_hash`, None if the account does not exist
+pub fn prove(state: &HashMap<Address, (usize, Amount)>, block_hash: H256, address: &Address) -> Option<StateProof> {
+    let entries = sorted_entries(state);
+    let index = entries.iter().position(|e| e.address == *address)?;
+    let leaves: Vec<H256> = entries.iter().map(|e| e.hash()).collect();
+    let tree = MerkleTree::new(&leaves);
+    Some(StateProof {
+        block_hash,
+        state_root: tree.root(),
+        entry: entries[index].clone(),
+        index,
+        leaf_size: leaves.len(),
+        proof: tree.proof(index),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_state(accounts: u8) -> HashMap<Address, (usize, Amount)> {
+        let mut state = HashMap::new();
+        for i in 0..accounts {
+            state.insert(Address::from([i; 20]), (i as usize + 1, Amount::from_sub_units(100 * i as u64)));
+        }
+        state
+    }
+
+    #[test]
+    fn every_account_is_provable() {
+        // an odd number of accounts exercises the padding of odd layers
+        let state = test_state(5);
+        let root = state_root(&state);
+        for i in 0..5 {
+            let proof = prove(&state, H256::default(), &Address::from([i; 20])).unwrap();
+            assert_eq!(proof.entry.balance, Amount::from_sub_units(100 * i as u64));
+            assert!(proof.verify(&root));
+        }
+        assert!(prove(&state, H256::default(), &Address::from([9; 20])).is_none());
+    }
+
+    #[test]
+    fn stored_empty_accounts_count() {
+        let mut state = test_state(3);
+        let root = state_root(&state);
+        // blocks of chains that keep every account store empty ones, the root must tell them apart
+        state.insert(Address::from([7; 20]), (0, Amount::ZERO));
+        assert_ne!(state_root(&state), root);
+        let proof = prove(&state, H256::default(), &Address::from([7; 20])).unwrap();
+        assert!(proof.verify(&state_root(&state)));
+        // a pruned account is simply missing again
+        state.remove(&Address::from([7; 20]));
+        assert_eq!(state_root(&state), root);
+    }
+
+    #[test]
+    fn tampered_proof_fails() {
+        let state = test_state(4);
+        let root = state_root(&state);
+        let mut proof = prove(&state, H256::default(), &Address::from([2; 20])).unwrap();
+        proof.entry.balance = proof.entry.balance.checked_add(Amount::from_sub_units(1)).unwrap();
+        assert!(!proof.verify(&root));
+        assert!(!prove(&state, H256::default(), &Address::from([2; 20])).unwrap().verify(&H256::default()));
+    }
+}
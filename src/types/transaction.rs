@@ -2,11 +2,12 @@ use serde::{Serialize,Deserialize}; // declare serde as (serialize, deserialize)
 use ring::signature::{Ed25519KeyPair, Signature, self};
 use rand::{Rng, RngCore}; // bind rand to Rng
 use crate::types::address::Address;
+use crate::types::amount::Amount;
 
 #[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub struct Transaction {
     pub receiver: Address,
-    pub value: usize,
+    pub value: Amount,
     pub account_nonce: usize,
 }
 
@@ -45,7 +46,7 @@ pub fn generate_random_transaction() -> Transaction {
 
     Transaction {
         receiver: receiver_add,
-        value: rng.gen(),
+        value: Amount::from_sub_units(rng.gen()),
         account_nonce: rng.gen(),
     }
 }
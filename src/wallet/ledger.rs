@@ -0,0 +1,210 @@
+use crate::blockchain::Blockchain;
+use crate::types::address::Address;
+use crate::types::hash::{H256, Hashable};
+
+use log::warn;
+
+pub const WALLET_ACCOUNT: &str = "Assets:Wallet";
+pub const GENESIS_ACCOUNT: &str = "Equity:Genesis";
+
+/// Amount booked to one account, positive for a debit and negative for a credit.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Posting {
+    pub account: String,
+    pub amount: i64,
+}
+
+/// One balanced entry of the wallet's ledger, its postings sum to zero.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LedgerEntry {
+    /// ms since the unix epoch, from the header of the block
+    pub timestamp: u128,
+    pub height: u32,
+    pub block: H256,
+    /// None for the allocation of the genesis state
+    pub transaction: Option<H256>,
+    pub postings: Vec<Posting>,
+}
+
+/// Book the activity of `addresses` on the longest chain of `blockchain`, oldest first: their
+/// allocation in the genesis state against `Equity:Genesis`, then every transfer they received
+/// against `Income:Received:<sender>` and every transfer they sent against
+/// `Expenses:Sent:<receiver>`. Transfers between two of the addresses leave the wallet as it
+/// was and are not booked. Blocks carry no coinbase and transactions pay no fee on this chain,
+/// so there is no mining income or fee expense to book. Blocks whose body was pruned are
+/// skipped.
+pub fn entries(blockchain: &Blockchain, addresses: &[Address]) -> Vec<LedgerEntry> {
+    let chain = blockchain.all_blocks_in_longest_chain();
+    let mut entries = Vec::new();
+    let genesis = chain[0];
+    let allocation: i64 = addresses
+        .iter()
+        .filter_map(|address| blockchain.state_map[&genesis].state.get(address))
+        .map(|(_, balance)| balance.sub_units() as i64)
+        .sum();
+    if allocation > 0 {
+        entries.push(LedgerEntry {
+            timestamp: blockchain.header(&genesis).unwrap().timestamp,
+            height: 0,
+            block: genesis,
+            transaction: None,
+            postings: vec![
+                Posting { account: WALLET_ACCOUNT.to_string(), amount: allocation },
+                Posting { account: GENESIS_ACCOUNT.to_string(), amount: -allocation },
+            ],
+        });
+    }
+    for hash in chain.iter().skip(1) {
+        let block = match blockchain.block(hash) {
+            Some(block) => block,
+            None => {
+                warn!("Body of block {} was pruned, leaving it out of the ledger", hash);
+                continue;
+            }
+        };
+        for tx in block.content.transactions.iter() {
+            let sender = Address::from_public_key_bytes(&tx.signer_public_key);
+            let receiver = tx.t.receiver;
+            let value = tx.t.value.sub_units() as i64;
+            let (sent, received) = (addresses.contains(&sender), addresses.contains(&receiver));
+            let mut postings = Vec::new();
+            if sent && !received {
+                postings.push(Posting { account: format!("Expenses:Sent:{}", receiver), amount: value });
+                postings.push(Posting { account: WALLET_ACCOUNT.to_string(), amount: -value });
+            }
+            if received && !sent {
+                postings.push(Posting { account: WALLET_ACCOUNT.to_string(), amount: value });
+                postings.push(Posting { account: format!("Income:Received:{}", sender), amount: -value });
+            }
+            if !postings.is_empty() {
+                entries.push(LedgerEntry {
+                    timestamp: block.header.timestamp,
+                    height: block.header.length,
+                    block: *hash,
+                    transaction: Some(tx.hash()),
+                    postings,
+                });
+            }
+        }
+    }
+    entries
+}
+
+// date of a timestamp in ms since the unix epoch, as yyyy/mm/dd in UTC
+fn date(timestamp: u128) -> String {
+    // days to civil date, from Howard Hinnant's chrono-compatible algorithms
+    let days = (timestamp / 86_400_000) as i64 + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}/{:02}/{:02}", year, month, day)
+}
+
+fn description(entry: &LedgerEntry) -> String {
+    match entry.transaction {
+        Some(tx) => format!("Block {} transaction {}", entry.height, tx),
+        None => format!("Genesis allocation in block {}", entry.block),
+    }
+}
+
+/// Write `entries` as a ledger-cli journal
+pub fn to_journal(entries: &[LedgerEntry]) -> String {
+    let mut journal = String::new();
+    for entry in entries {
+        journal.push_str(&format!("{} * {}\n", date(entry.timestamp), description(entry)));
+        for posting in entry.postings.iter() {
+            journal.push_str(&format!("    {:<60}  {:>12}\n", posting.account, posting.amount));
+        }
+        journal.push('\n');
+    }
+    journal
+}
+
+/// Write `entries` as CSV, one row per posting with its debit or credit
+pub fn to_csv(entries: &[LedgerEntry]) -> String {
+    let mut csv = String::from("date,timestamp,height,block,transaction,account,debit,credit\n");
+    for entry in entries {
+        let transaction = entry.transaction.map(|tx| tx.to_string()).unwrap_or_default();
+        for posting in entry.postings.iter() {
+            let (debit, credit) = if posting.amount >= 0 {
+                (posting.amount.to_string(), String::new())
+            } else {
+                (String::new(), (-posting.amount).to_string())
+            };
+            csv.push_str(&format!(
+                "{},{},{},{},{},{},{},{}\n",
+                date(entry.timestamp), entry.timestamp, entry.height, entry.block, transaction, posting.account, debit, credit
+            ));
+        }
+    }
+    csv
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::block::TestBlockBuilder;
+    use crate::types::amount::Amount;
+    use crate::types::key_pair;
+    use crate::types::transaction::{sign, SignedTransaction, Transaction};
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    fn signed(key: &Ed25519KeyPair, receiver: Address, value: u64, account_nonce: usize) -> SignedTransaction {
+        let t = Transaction { receiver, value: Amount::from_sub_units(value), account_nonce };
+        SignedTransaction {
+            signature_vector: sign(&t, key).as_ref().to_vec(),
+            signer_public_key: key.public_key().as_ref().to_vec(),
+            t,
+        }
+    }
+
+    #[test]
+    fn entries_balance() {
+        let mut blockchain = Blockchain::new(0);
+        let ours = Ed25519KeyPair::from_seed_unchecked(&[0; 32]).unwrap();
+        let address = Address::from_public_key_bytes(ours.public_key().as_ref());
+        let theirs = key_pair::random();
+        let other = Address::from_public_key_bytes(theirs.public_key().as_ref());
+        let first = TestBlockBuilder::new(&blockchain.tip())
+            .length(1)
+            .transactions(vec![signed(&ours, other, 30, 1)])
+            .build();
+        blockchain.insert(&first);
+        let second = TestBlockBuilder::new(&first.hash())
+            .length(2)
+            .transactions(vec![signed(&theirs, address, 5, 1)])
+            .build();
+        blockchain.insert(&second);
+
+        let entries = entries(&blockchain, &[address]);
+        assert_eq!(entries.len(), 3);
+        assert!(entries.iter().all(|e| e.postings.iter().map(|p| p.amount).sum::<i64>() == 0));
+        // the wallet account ends at the balance of the state
+        let balance: i64 = entries
+            .iter()
+            .flat_map(|e| e.postings.iter())
+            .filter(|p| p.account == WALLET_ACCOUNT)
+            .map(|p| p.amount)
+            .sum();
+        assert_eq!(balance, 75);
+        assert_eq!(entries[1].postings[0], Posting { account: format!("Expenses:Sent:{}", other), amount: 30 });
+
+        let csv = to_csv(&entries);
+        assert_eq!(csv.lines().count(), 1 + 6);
+        assert!(csv.lines().nth(1).unwrap().starts_with("1970/01/01,0,0,"));
+        assert!(to_journal(&entries).starts_with("1970/01/01 * Genesis allocation"));
+    }
+
+    #[test]
+    fn civil_dates() {
+        assert_eq!(date(0), "1970/01/01");
+        // 2022-10-17 12:00 UTC
+        assert_eq!(date(1_666_008_000_000), "2022/10/17");
+        assert_eq!(date(951_782_400_000), "2000/02/29");
+    }
+}
@@ -0,0 +1,483 @@
+pub mod ledger;
+pub mod worker;
+
+use crate::blockchain::{Blockchain, Mempool};
+use crate::network::message::{InvItem, Message};
+use crate::network::server::Handle as ServerHandle;
+use crate::policy::{PolicyViolation, RelayPolicy};
+use crate::types::address::Address;
+use crate::types::amount::Amount;
+use crate::types::hash::{H256, Hashable};
+use crate::types::transaction::{sign, SignedTransaction, Transaction};
+
+use ring::digest;
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use std::sync::{Arc, Mutex};
+
+/// Unused addresses derived past the last used one. Addresses handed out before a restart are
+/// found again by deriving until this many in a row were never used.
+pub const GAP_LIMIT: usize = 20;
+
+/// Earliest point at which a scheduled send may be broadcast.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendCondition {
+    /// once the longest chain reaches this height
+    Height(u32),
+    /// once the clock reaches this many milliseconds since the unix epoch
+    Timestamp(u128),
+}
+
+impl SendCondition {
+    pub fn is_met(&self, height: u32, now: u128) -> bool {
+        match self {
+            SendCondition::Height(h) => height >= *h,
+            SendCondition::Timestamp(t) => now >= *t,
+        }
+    }
+}
+
+/// A send held by the wallet until its condition is met.
+#[derive(Debug, Clone)]
+pub struct ScheduledSend {
+    pub id: usize,
+    pub receiver: Address,
+    pub value: Amount,
+    pub condition: SendCondition,
+    /// signed when scheduled, or None to be created and signed when it becomes due
+    pub signed: Option<SignedTransaction>,
+}
+
+/// Reason the wallet could not create or submit a transaction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WalletError {
+    /// our account is not in the state of the tip
+    UnknownAccount,
+    InsufficientBalance { value: Amount, balance: Amount },
+    /// the addresses together hold enough, but a transaction spends from a single one
+    Fragmented { value: Amount, largest: Amount },
+    Policy(PolicyViolation),
+}
+
+impl std::fmt::Display for WalletError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            WalletError::UnknownAccount => write!(f, "wallet account not found in state"),
+            WalletError::InsufficientBalance { value, balance } => {
+                write!(f, "insufficient balance: sending {}, balance {}", value, balance)
+            }
+            WalletError::Fragmented { value, largest } => {
+                write!(f, "no single address holds {}, the largest balance is {}", value, largest)
+            }
+            WalletError::Policy(violation) => write!(f, "refused by relay policy: {}", violation),
+        }
+    }
+}
+
+/// Balance of one address of the wallet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AddressBalance {
+    pub index: usize,
+    pub address: Address,
+    pub nonce: usize,
+    pub balance: Amount,
+}
+
+/// Balances of the addresses of the wallet handed out or used, and their sum.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Balances {
+    pub total: Amount,
+    pub addresses: Vec<AddressBalance>,
+}
+
+// seed of the key at `index`, the first being the node key itself and the others derived from it
+fn key_seed(seed: u8, index: usize) -> [u8; 32] {
+    if index == 0 {
+        return [seed; 32];
+    }
+    let mut ctx = digest::Context::new(&digest::SHA256);
+    ctx.update(b"receive");
+    ctx.update(&[seed; 32]);
+    ctx.update(&(index as u64).to_be_bytes());
+    let mut derived = [0; 32];
+    derived.copy_from_slice(ctx.finish().as_ref());
+    derived
+}
+
+/// Wallet of this node. Its first key pair is derived from the node seed and holds what the
+/// node was allocated; every receive address handed out after it has a key pair of its own,
+/// derived from the seed too, so payments are not linked by a reused address.
+pub struct Wallet {
+    seed: u8,
+    // derived so far, the first GAP_LIMIT past the last used one included
+    keys: Vec<Ed25519KeyPair>,
+    // number of keys whose address was handed out, the first one always is
+    issued: usize,
+    blockchain: Arc<Mutex<Blockchain>>,
+    mempool: Arc<Mutex<Mempool>>,
+    server: ServerHandle,
+    policy: RelayPolicy,
+    scheduled: Vec<ScheduledSend>,
+    next_id: usize,
+}
+
+impl Wallet {
+    pub fn new(
+        seed: u8,
+        blockchain: &Arc<Mutex<Blockchain>>,
+        mempool: &Arc<Mutex<Mempool>>,
+        server: &ServerHandle,
+        policy: &RelayPolicy,
+    ) -> Self {
+        let mut wallet = Wallet {
+            seed,
+            keys: Vec::new(),
+            issued: 1,
+            blockchain: Arc::clone(blockchain),
+            mempool: Arc::clone(mempool),
+            server: server.clone(),
+            policy: policy.clone(),
+            scheduled: Vec::new(),
+            next_id: 0,
+        };
+        wallet.discover();
+        wallet
+    }
+
+    /// The first address of the wallet, the one of the node seed
+    pub fn address(&self) -> Address {
+        self.address_at(0)
+    }
+
+    fn address_at(&self, index: usize) -> Address {
+        Address::from_public_key_bytes(self.keys[index].public_key().as_ref())
+    }
+
+    /// Every address derived so far
+    pub fn addresses(&self) -> Vec<Address> {
+        (0..self.keys.len()).map(|index| self.address_at(index)).collect()
+    }
+
+    // nonce and balance of each derived address in the state of the tip, None if it was never used
+    fn accounts(&self) -> Vec<Option<(usize, Amount)>> {
+        let blockchain = self.blockchain.lock().unwrap();
+        let state = &blockchain.state_map.get(&blockchain.tip()).unwrap().state;
+        self.addresses().iter().map(|address| state.get(address).copied()).collect()
+    }
+
+    // keys up to the last one whose address was used
+    fn used(&self) -> usize {
+        self.accounts().iter().rposition(|account| account.is_some()).map_or(0, |index| index + 1)
+    }
+
+    /// Derive addresses until GAP_LIMIT in a row past the last used one were never used, so the
+    /// addresses paid since they were handed out are tracked, even by a restarted node
+    pub fn discover(&mut self) {
+        // the addresses derived may be used too, then more are derived past them
+        loop {
+            let wanted = self.used().max(self.issued) + GAP_LIMIT;
+            if self.keys.len() >= wanted {
+                break;
+            }
+            while self.keys.len() < wanted {
+                let key = Ed25519KeyPair::from_seed_unchecked(&key_seed(self.seed, self.keys.len())).unwrap();
+                self.keys.push(key);
+            }
+        }
+    }
+
+    /// A receive address never handed out nor paid before
+    pub fn receive_address(&mut self) -> Address {
+        self.discover();
+        let index = self.used().max(self.issued);
+        self.issued = index + 1;
+        self.discover();
+        self.address_at(index)
+    }
+
+    /// Balances of the addresses handed out or used, and their sum
+    pub fn balances(&mut self) -> Balances {
+        self.discover();
+        let addresses: Vec<AddressBalance> = self
+            .accounts()
+            .into_iter()
+            .enumerate()
+            .filter(|(index, account)| *index < self.issued || account.is_some())
+            .map(|(index, account)| {
+                let (nonce, balance) = account.unwrap_or_default();
+                AddressBalance { index, address: self.address_at(index), nonce, balance }
+            })
+            .collect();
+        let total = Amount::checked_sum(addresses.iter().map(|address| address.balance)).expect("balances of the state overflow");
+        Balances { total, addresses }
+    }
+
+    /// Book the activity of our addresses on the longest chain as double-entry ledger entries
+    pub fn ledger(&self) -> Vec<ledger::LedgerEntry> {
+        ledger::entries(&self.blockchain.lock().unwrap(), &self.addresses())
+    }
+
+    /// Create and sign a transaction with the next unused nonce of the address paying it, the
+    /// one with the smallest balance covering `value` so the larger ones stay whole
+    pub fn create(&self, receiver: Address, value: Amount) -> Result<SignedTransaction, WalletError> {
+        let accounts = self.accounts();
+        let funded: Vec<(usize, usize, Amount)> =
+            accounts.iter().enumerate().filter_map(|(index, account)| account.map(|(nonce, balance)| (index, nonce, balance))).collect();
+        if funded.is_empty() {
+            return Err(WalletError::UnknownAccount);
+        }
+        let (index, nonce, _) = match funded.iter().filter(|(_, _, balance)| *balance >= value).min_by_key(|(_, _, balance)| *balance) {
+            Some(payer) => *payer,
+            None => {
+                let largest = funded.iter().map(|(_, _, balance)| *balance).max().unwrap();
+                let total = Amount::checked_sum(funded.iter().map(|(_, _, balance)| *balance)).expect("balances of the state overflow");
+                if total >= value {
+                    return Err(WalletError::Fragmented { value, largest });
+                }
+                return Err(WalletError::InsufficientBalance { value, balance: total });
+            }
+        };
+        let key_pair = &self.keys[index];
+        // skip the nonces taken by our transactions still in the mempool or signed in advance
+        let mut last_nonce = nonce;
+        for tx in self.mempool.lock().unwrap().hash_map.values() {
+            if tx.signer_public_key.as_slice() == key_pair.public_key().as_ref() {
+                last_nonce = last_nonce.max(tx.t.account_nonce);
+            }
+        }
+        for send in self.scheduled.iter() {
+            if let Some(tx) = &send.signed {
+                if tx.signer_public_key.as_slice() == key_pair.public_key().as_ref() {
+                    last_nonce = last_nonce.max(tx.t.account_nonce);
+                }
+            }
+        }
+        let t = Transaction {
+            receiver,
+            value,
+            account_nonce: last_nonce + 1,
+        };
+        let signature = sign(&t, key_pair);
+        Ok(SignedTransaction {
+            t,
+            signature_vector: signature.as_ref().to_vec(),
+            signer_public_key: key_pair.public_key().as_ref().to_vec(),
+        })
+    }
+
+    /// Create a transaction and broadcast it right away
+    pub fn send(&self, receiver: Address, value: Amount) -> Result<H256, WalletError> {
+        let tx = self.create(receiver, value)?;
+        self.submit(tx)
+    }
+
+    /// Hold a send until `condition` is met, signing it now if `sign_now` is set
+    pub fn schedule(
+        &mut self,
+        receiver: Address,
+        value: Amount,
+        condition: SendCondition,
+        sign_now: bool,
+    ) -> Result<usize, WalletError> {
+        let signed = if sign_now { Some(self.create(receiver, value)?) } else { None };
+        let id = self.next_id;
+        self.next_id += 1;
+        self.scheduled.push(ScheduledSend { id, receiver, value, condition, signed });
+        Ok(id)
+    }
+
+    /// Sends still waiting for their condition
+    pub fn scheduled(&self) -> &[ScheduledSend] {
+        &self.scheduled
+    }
+
+    /// Submit every scheduled send whose condition is met at `now` (ms since the unix epoch)
+    pub fn submit_due(&mut self, now: u128) -> Vec<(usize, Result<H256, WalletError>)> {
+        let height = {self.blockchain.lock().unwrap().height()};
+        let (due, waiting): (Vec<ScheduledSend>, Vec<ScheduledSend>) =
+            self.scheduled.drain(..).partition(|s| s.condition.is_met(height, now));
+        self.scheduled = waiting;
+        let mut results = Vec::new();
+        for send in due {
+            let result = match send.signed {
+                Some(tx) => self.submit(tx),
+                None => self.create(send.receiver, send.value).and_then(|tx| self.submit(tx)),
+            };
+            results.push((send.id, result));
+        }
+        results
+    }
+
+    // add our transaction to the mempool and announce it to peers
+    fn submit(&self, tx: SignedTransaction) -> Result<H256, WalletError> {
+        self.policy.check(&tx).map_err(WalletError::Policy)?;
+        let hash = tx.hash();
+        {self.mempool.lock().unwrap().hash_map.insert(hash, tx)};
+        self.server.broadcast(Message::Inv(vec![InvItem::Transaction(hash)]));
+        Ok(hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::server::TestReceiver as ServerTestReceiver;
+    use crate::types::block::{Block, TestBlockBuilder};
+
+    fn test_wallet() -> (Wallet, Arc<Mutex<Blockchain>>, Arc<Mutex<Mempool>>, ServerTestReceiver) {
+        let blockchain = Arc::new(Mutex::new(Blockchain::new(0)));
+        let mempool = Arc::new(Mutex::new(Mempool::new()));
+        let (server, server_receiver) = ServerHandle::new_for_test();
+        // seed 0 owns the genesis balance
+        let wallet = Wallet::new(0, &blockchain, &mempool, &server, &RelayPolicy::default());
+        (wallet, blockchain, mempool, server_receiver)
+    }
+
+    #[test]
+    fn send_uses_next_nonce() {
+        let (wallet, _blockchain, mempool, _server_receiver) = test_wallet();
+        let receiver = Address::from([1; 20]);
+        wallet.send(receiver, Amount::from_sub_units(10)).unwrap();
+        let second = wallet.send(receiver, Amount::from_sub_units(10)).unwrap();
+        assert_eq!(mempool.lock().unwrap().hash_map.get(&second).unwrap().t.account_nonce, 2);
+        assert_eq!(
+            wallet.send(receiver, Amount::from_sub_units(1000)),
+            Err(WalletError::InsufficientBalance { value: Amount::from_sub_units(1000), balance: Amount::from_sub_units(100) })
+        );
+    }
+
+    #[test]
+    fn scheduled_send_waits_for_height() {
+        let (mut wallet, blockchain, mempool, _server_receiver) = test_wallet();
+        let receiver = Address::from([1; 20]);
+        let id = wallet.schedule(receiver, Amount::from_sub_units(10), SendCondition::Height(1), true).unwrap();
+        wallet.schedule(receiver, Amount::from_sub_units(5), SendCondition::Timestamp(1000), false).unwrap();
+        assert!(wallet.submit_due(0).is_empty());
+        assert!(mempool.lock().unwrap().hash_map.is_empty());
+
+        let genesis = {blockchain.lock().unwrap().tip()};
+        let block = TestBlockBuilder::new(&genesis).length(1).build();
+        {blockchain.lock().unwrap().insert(&block)};
+        let submitted = wallet.submit_due(0);
+        assert_eq!(submitted.len(), 1);
+        assert_eq!(submitted[0].0, id);
+        assert!(submitted[0].1.is_ok());
+        assert_eq!(wallet.scheduled().len(), 1);
+
+        // the unsigned send is signed when due, after the pre-signed one
+        let submitted = wallet.submit_due(1000);
+        let hash = submitted[0].1.clone().unwrap();
+        assert_eq!(mempool.lock().unwrap().hash_map.get(&hash).unwrap().t.account_nonce, 2);
+        assert!(wallet.scheduled().is_empty());
+    }
+
+    #[test]
+    fn receive_addresses_rotate() {
+        let (mut wallet, blockchain, mempool, _server_receiver) = test_wallet();
+        let first = wallet.receive_address();
+        let second = wallet.receive_address();
+        assert!(first != wallet.address() && second != first);
+        wallet.send(first, Amount::from_sub_units(30)).unwrap();
+        let genesis = {blockchain.lock().unwrap().tip()};
+        let transactions: Vec<SignedTransaction> = mempool.lock().unwrap().hash_map.drain().map(|(_, tx)| tx).collect();
+        let block = TestBlockBuilder::new(&genesis).length(1).transactions(transactions).build();
+        {blockchain.lock().unwrap().insert(&block)};
+
+        let balances = wallet.balances();
+        assert_eq!(balances.total, Amount::from_sub_units(100));
+        let held: Vec<(usize, u64)> = balances.addresses.iter().map(|a| (a.index, a.balance.sub_units())).collect();
+        assert_eq!(held, vec![(0, 70), (1, 30), (2, 0)]);
+
+        // a restarted wallet finds the address paid, and hands out the ones after it
+        let (server, _restarted_receiver) = ServerHandle::new_for_test();
+        let mut restarted = Wallet::new(0, &blockchain, &mempool, &server, &RelayPolicy::default());
+        assert_eq!(restarted.balances().addresses.len(), 2);
+        assert_eq!(restarted.receive_address(), second);
+
+        // a send is paid by the smallest balance covering it
+        let hash = wallet.send(Address::from([1; 20]), Amount::from_sub_units(25)).unwrap();
+        let signer = mempool.lock().unwrap().hash_map[&hash].signer_public_key.clone();
+        assert_eq!(Address::from_public_key_bytes(&signer), first);
+        assert_eq!(
+            wallet.send(Address::from([1; 20]), Amount::from_sub_units(80)),
+            Err(WalletError::Fragmented { value: Amount::from_sub_units(80), largest: Amount::from_sub_units(70) })
+        );
+    }
+
+    // a node of an in-process cluster, blocks are delivered by hand instead of over the network
+    struct TestNode {
+        wallet: Wallet,
+        blockchain: Arc<Mutex<Blockchain>>,
+        mempool: Arc<Mutex<Mempool>>,
+        _server_receiver: ServerTestReceiver,
+    }
+
+    impl TestNode {
+        fn new(seed: u8) -> Self {
+            let blockchain = Arc::new(Mutex::new(Blockchain::new(seed)));
+            let mempool = Arc::new(Mutex::new(Mempool::new()));
+            let (server, server_receiver) = ServerHandle::new_for_test();
+            let wallet = Wallet::new(seed, &blockchain, &mempool, &server, &RelayPolicy::default());
+            TestNode { wallet, blockchain, mempool, _server_receiver: server_receiver }
+        }
+
+        // mine the mempool on top of our tip, the way the miner fills a block
+        fn mine(&self) -> Block {
+            let blockchain = self.blockchain.lock().unwrap();
+            let tip = blockchain.tip();
+            let transactions: Vec<SignedTransaction> = self.mempool.lock().unwrap().hash_map.drain().map(|(_, tx)| tx).collect();
+            TestBlockBuilder::new(&tip)
+                .length(blockchain.height() + 1)
+                .difficulty(blockchain.next_difficulty(&tip))
+                .transactions(transactions)
+                .build()
+        }
+
+        fn balance(&self, address: &Address) -> u64 {
+            let blockchain = self.blockchain.lock().unwrap();
+            blockchain.state_map[&blockchain.tip()].state.get(address).map_or(0, |(_, balance)| balance.sub_units())
+        }
+    }
+
+    fn deliver(block: &Block, nodes: &[&TestNode]) {
+        for node in nodes {
+            node.blockchain.lock().unwrap().insert(block);
+        }
+    }
+
+    #[test]
+    fn payments_survive_reorg() {
+        let nodes: Vec<TestNode> = (0..3).map(TestNode::new).collect();
+        let (a, b, c) = (&nodes[0], &nodes[1], &nodes[2]);
+        let all = [a, b, c];
+
+        a.wallet.send(b.wallet.address(), Amount::from_sub_units(30)).unwrap();
+        deliver(&a.mine(), &all);
+        for node in all.iter() {
+            assert_eq!(node.balance(&b.wallet.address()), 30);
+        }
+
+        // b pays c on a block only b sees, while c mines a longer chain without it
+        b.wallet.send(c.wallet.address(), Amount::from_sub_units(10)).unwrap();
+        deliver(&b.mine(), &[b]);
+        assert_eq!(b.balance(&c.wallet.address()), 10);
+        let first = c.mine();
+        deliver(&first, &[a, c]);
+        let second = c.mine();
+        deliver(&second, &[a, c]);
+        deliver(&first, &[b]);
+        deliver(&second, &[b]);
+        assert_eq!(b.blockchain.lock().unwrap().fork_events(), 1);
+        assert_eq!(b.balance(&c.wallet.address()), 0);
+
+        // the payment was dropped with the losing block, the wallet pays again from the new tip
+        b.wallet.send(c.wallet.address(), Amount::from_sub_units(10)).unwrap();
+        let last = b.mine();
+        deliver(&last, &all);
+        for node in all.iter() {
+            assert_eq!(node.blockchain.lock().unwrap().tip(), last.hash());
+            assert_eq!(node.balance(&a.wallet.address()), 70);
+            assert_eq!(node.balance(&b.wallet.address()), 20);
+            assert_eq!(node.balance(&c.wallet.address()), 10);
+        }
+    }
+}
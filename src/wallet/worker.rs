@@ -0,0 +1,50 @@
+use super::Wallet;
+
+use log::{info, warn};
+use std::thread;
+use std::sync::{Arc, Mutex};
+use std::time::{self, SystemTime, UNIX_EPOCH};
+
+/// Submits the scheduled sends of the wallet once they become due, and tracks the addresses
+/// derived past the ones paid meanwhile.
+pub struct Worker {
+    wallet: Arc<Mutex<Wallet>>,
+    interval: time::Duration,
+}
+
+impl Worker {
+    pub fn new(wallet: &Arc<Mutex<Wallet>>, interval: time::Duration) -> Self {
+        Self {
+            wallet: Arc::clone(wallet),
+            interval,
+        }
+    }
+
+    pub fn start(self) {
+        thread::Builder::new()
+            .name("wallet-worker".to_string())
+            .spawn(move || {
+                self.schedule_loop();
+            })
+            .unwrap();
+        info!("Wallet scheduler initialized");
+    }
+
+    fn schedule_loop(&self) {
+        loop {
+            thread::sleep(self.interval);
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+            let results = {
+                let mut wallet = self.wallet.lock().unwrap();
+                wallet.discover();
+                wallet.submit_due(now)
+            };
+            for (id, result) in results {
+                match result {
+                    Ok(hash) => info!("Scheduled send {} submitted as transaction {}", id, hash),
+                    Err(e) => warn!("Scheduled send {} dropped: {}", id, e),
+                }
+            }
+        }
+    }
+}
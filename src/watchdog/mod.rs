@@ -0,0 +1,138 @@
+pub mod worker;
+
+use crate::types::hash::H256;
+
+use serde::Serialize;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// How worried we are about block production, by increasing severity.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum AlarmLevel {
+    Ok,
+    /// no block for `AlarmThresholds::warning` target intervals, logged and reported as degraded
+    Warning,
+    /// no block for `AlarmThresholds::critical` target intervals, the node reports itself
+    /// unhealthy and calls the webhook if there is one
+    Critical,
+}
+
+/// After how many target block intervals without a new tip each alarm level is raised.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AlarmThresholds {
+    pub warning: u32,
+    pub critical: u32,
+}
+
+impl Default for AlarmThresholds {
+    fn default() -> Self {
+        AlarmThresholds { warning: 3, critical: 6 }
+    }
+}
+
+/// Watches the tip of the longest chain, timestamping it by our own clock when it changes, and
+/// raises an alarm when no block arrived for several target intervals, e.g. because every miner
+/// died or the node is cut off from them.
+pub struct BlockWatch {
+    target: Duration,
+    thresholds: AlarmThresholds,
+    tip: H256,
+    height: u32,
+    tip_seen: Instant,
+    tip_seen_ms: u128, // when `tip_seen` was, in ms since the unix epoch
+    level: AlarmLevel,
+}
+
+/// Health of block production, for the API.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct HealthStatus {
+    pub status: AlarmLevel,
+    pub height: u32,
+    pub tip: String,
+    /// when the tip was first seen, in ms since the unix epoch
+    pub tip_seen: u128,
+    pub since_last_block_ms: u128,
+    pub target_interval_ms: u128,
+    pub thresholds: AlarmThresholds,
+}
+
+impl BlockWatch {
+    /// Watch a chain currently at `tip` and `height`, first seen at `now`
+    pub fn new(target: Duration, thresholds: AlarmThresholds, tip: H256, height: u32, now: Instant) -> Self {
+        BlockWatch {
+            target,
+            thresholds,
+            tip,
+            height,
+            tip_seen: now,
+            tip_seen_ms: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis(),
+            level: AlarmLevel::Ok,
+        }
+    }
+
+    /// Record the tip at `now`, returns the new alarm level if it changed
+    pub fn observe(&mut self, tip: H256, height: u32, now: Instant) -> Option<AlarmLevel> {
+        if tip != self.tip {
+            self.tip = tip;
+            self.height = height;
+            self.tip_seen = now;
+            self.tip_seen_ms = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+        }
+        let silent = now.saturating_duration_since(self.tip_seen);
+        let level = if silent >= self.target * self.thresholds.critical {
+            AlarmLevel::Critical
+        } else if silent >= self.target * self.thresholds.warning {
+            AlarmLevel::Warning
+        } else {
+            AlarmLevel::Ok
+        };
+        if level == self.level {
+            return None;
+        }
+        self.level = level;
+        Some(level)
+    }
+
+    pub fn level(&self) -> AlarmLevel {
+        self.level
+    }
+
+    /// Time without a new tip as of `now`
+    pub fn silent(&self, now: Instant) -> Duration {
+        now.saturating_duration_since(self.tip_seen)
+    }
+
+    pub fn status(&self, now: Instant) -> HealthStatus {
+        HealthStatus {
+            status: self.level,
+            height: self.height,
+            tip: self.tip.to_string(),
+            tip_seen: self.tip_seen_ms,
+            since_last_block_ms: self.silent(now).as_millis(),
+            target_interval_ms: self.target.as_millis(),
+            thresholds: self.thresholds,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn alarm_escalates_and_clears() {
+        let start = Instant::now();
+        let target = Duration::from_secs(10);
+        let mut watch = BlockWatch::new(target, AlarmThresholds::default(), H256::default(), 0, start);
+        assert_eq!(watch.observe(H256::default(), 0, start + target * 2), None);
+        assert_eq!(watch.observe(H256::default(), 0, start + target * 3), Some(AlarmLevel::Warning));
+        assert_eq!(watch.observe(H256::default(), 0, start + target * 4), None);
+        assert_eq!(watch.observe(H256::default(), 0, start + target * 6), Some(AlarmLevel::Critical));
+        // a new tip clears the alarm, and the clock starts over from it
+        let tip = H256::from([1; 32]);
+        let later = start + target * 7;
+        assert_eq!(watch.observe(tip, 1, later), Some(AlarmLevel::Ok));
+        assert_eq!(watch.status(later + target).since_last_block_ms, 10_000);
+        assert_eq!(watch.status(later).height, 1);
+    }
+}
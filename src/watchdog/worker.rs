@@ -0,0 +1,85 @@
+use log::{error, info, warn};
+use crate::blockchain::Blockchain;
+use crate::events::{Event, EventBus};
+use crate::events::webhook::Webhook;
+use super::{AlarmLevel, BlockWatch};
+use std::thread;
+use std::sync::{Arc, Mutex};
+use std::time::{self, Instant};
+
+/// Checks the tip every `interval` and escalates the block interval alarm: a warning is logged,
+/// a critical alarm is logged as an error and posted to the webhook, as is its end.
+pub struct Worker {
+    watch: Arc<Mutex<BlockWatch>>,
+    interval: time::Duration,
+    blockchain: Arc<Mutex<Blockchain>>,
+    events: EventBus,
+    webhook: Option<Webhook>,
+}
+
+impl Worker {
+    pub fn new(
+        watch: &Arc<Mutex<BlockWatch>>,
+        interval: time::Duration,
+        blockchain: &Arc<Mutex<Blockchain>>,
+        events: &EventBus,
+        webhook: Option<Webhook>,
+    ) -> Self {
+        Self {
+            watch: Arc::clone(watch),
+            interval,
+            blockchain: Arc::clone(blockchain),
+            events: events.clone(),
+            webhook,
+        }
+    }
+
+    pub fn start(self) {
+        thread::Builder::new()
+            .name("block-watchdog".to_string())
+            .spawn(move || loop {
+                self.check();
+                thread::sleep(self.interval);
+            })
+            .unwrap();
+        info!("Block interval watchdog started");
+    }
+
+    fn check(&self) {
+        let (tip, height) = {
+            let blockchain = self.blockchain.lock().unwrap();
+            (blockchain.tip(), blockchain.height())
+        };
+        let now = Instant::now();
+        let (previous, level, status) = {
+            let mut watch = self.watch.lock().unwrap();
+            let previous = watch.level();
+            match watch.observe(tip, height, now) {
+                Some(level) => (previous, level, watch.status(now)),
+                None => return,
+            }
+        };
+        let silent = time::Duration::from_millis(status.since_last_block_ms as u64);
+        match level {
+            AlarmLevel::Ok => info!("Block production resumed at height {}", height),
+            AlarmLevel::Warning => warn!("No new block for {:?}, the tip is still at height {}", silent, height),
+            AlarmLevel::Critical => error!(
+                "No new block for {:?}, the tip is still at height {}: are the miners down or is this node cut off?",
+                silent, height
+            ),
+        }
+        self.events.publish(Event::BlockAlarm { level: status.status, since_last_block_ms: status.since_last_block_ms as u64 });
+        if level != AlarmLevel::Critical && previous != AlarmLevel::Critical {
+            return;
+        }
+        // a webhook that is slow to answer must not hold up the next check
+        if let Some(webhook) = self.webhook.clone() {
+            let body = serde_json::to_string(&status).unwrap();
+            thread::spawn(move || {
+                if let Err(e) = webhook.post("health", &body) {
+                    warn!("Error posting the block alarm to {}: {}", webhook.url, e);
+                }
+            });
+        }
+    }
+}